@@ -0,0 +1,208 @@
+#ifndef ELUSIVE_H
+#define ELUSIVE_H
+
+/* Generated with cbindgen, do not edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * The call succeeded.
+ */
+#define ELUSIVE_OK 0
+
+/**
+ * A required pointer argument is null.
+ */
+#define ELUSIVE_ERROR_NULL -1
+
+/**
+ * An argument is invalid, e.g. not valid UTF-8 or a relative path.
+ */
+#define ELUSIVE_ERROR_INVALID -2
+
+/**
+ * The builder failed to add an entry.
+ */
+#define ELUSIVE_ERROR_BUILD -3
+
+/**
+ * The archive could not be serialized, encoded or written.
+ */
+#define ELUSIVE_ERROR_WRITE -4
+
+/**
+ * The builder was already serialized.
+ */
+#define ELUSIVE_ERROR_CONSUMED -5
+
+/**
+ * Elusive panicked, which is a bug.
+ */
+#define ELUSIVE_ERROR_PANIC -6
+
+/**
+ * Opaque builder handle.
+ */
+typedef struct ElusiveBuilder ElusiveBuilder;
+
+/**
+ * Callback receiving the encoded archive in chunks, returning 0 on success.
+ */
+typedef int (*ElusiveWriteCallback)(void *userdata, const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a builder with the default skeleton (root directories and usr-merge
+ * symlinks), returning null on failure.
+ */
+ElusiveBuilder *elusive_builder_new(void);
+
+/**
+ * Create a builder for a bare archive, which only contains the root
+ * directory and does not move files under `/usr`.
+ */
+ElusiveBuilder *elusive_builder_new_bare(void);
+
+/**
+ * Free a builder handle, null is ignored.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a handle returned by one of the
+ * `elusive_builder_new` functions and not freed yet.
+ */
+void elusive_builder_free(ElusiveBuilder *builder);
+
+/**
+ * Get the message of the error returned by the last call on the handle, or
+ * null if it succeeded. The string is owned by the handle and valid until
+ * the next call on it.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle.
+ */
+const char *elusive_builder_last_error(const ElusiveBuilder *builder);
+
+/**
+ * Add a file holding `len` bytes of `data` with the provided permissions
+ * (e.g. `0644`) at `path` in the initramfs.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `path` null or a nul-terminated
+ * string and `data` valid for `len` bytes unless `len` is 0.
+ */
+int elusive_builder_add_file(ElusiveBuilder *builder,
+                             const char *path,
+                             const uint8_t *data,
+                             size_t len,
+                             uint32_t mode);
+
+/**
+ * Add a symlink at `path` in the initramfs pointing to `target` as is.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `path` and `target` null or
+ * nul-terminated strings.
+ */
+int elusive_builder_add_symlink(ElusiveBuilder *builder, const char *path, const char *target);
+
+/**
+ * Add an empty directory, along with its parents, at `path` in the
+ * initramfs.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `path` null or a nul-terminated
+ * string.
+ */
+int elusive_builder_add_directory(ElusiveBuilder *builder, const char *path);
+
+/**
+ * Add the ELF binary found at `path` on the host, or in `PATH` when it is
+ * relative, along with its libraries and program interpreter.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `path` null or a nul-terminated
+ * string.
+ */
+int elusive_builder_add_elf(ElusiveBuilder *builder, const char *path);
+
+/**
+ * Look kernel modules up in the provided module directory or kernel package
+ * instead of the modules of the running kernel.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `path` null or a nul-terminated
+ * string.
+ */
+int elusive_builder_set_kernel_module_path(ElusiveBuilder *builder, const char *path);
+
+/**
+ * Add the kernel module with the provided name or alias, along with its
+ * dependencies and the firmware it needs.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `name` null or a nul-terminated
+ * string.
+ */
+int elusive_builder_add_kernel_module(ElusiveBuilder *builder, const char *name);
+
+/**
+ * Set the compression of the archive: `none` (the default), `gzip` or
+ * `zstd`.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `name` null or a nul-terminated
+ * string.
+ */
+int elusive_builder_set_encoder(ElusiveBuilder *builder, const char *name);
+
+/**
+ * Serialize and encode the archive, passing it to `callback` in chunks along
+ * with `userdata`. This consumes the content of the builder.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `callback` must be safe to call
+ * with `userdata` and a buffer valid for the duration of the call.
+ */
+int elusive_builder_write(ElusiveBuilder *builder, ElusiveWriteCallback callback, void *userdata);
+
+/**
+ * Serialize and encode the archive into a buffer owned by the caller, to be
+ * freed with [`elusive_buffer_free`]. This consumes the content of the
+ * builder.
+ *
+ * # Safety
+ *
+ * `builder` must be null or a valid handle, `data` and `len` null or valid
+ * for writes.
+ */
+int elusive_builder_build(ElusiveBuilder *builder, uint8_t **data, size_t *len);
+
+/**
+ * Free a buffer returned by [`elusive_builder_build`], null is ignored.
+ *
+ * # Safety
+ *
+ * `data` must be null or a buffer returned by [`elusive_builder_build`] along
+ * with its `len`, and not freed yet.
+ */
+void elusive_buffer_free(uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ELUSIVE_H */
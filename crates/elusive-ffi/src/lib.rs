@@ -0,0 +1,755 @@
+//! C bindings for building initramfs archives with elusive.
+//!
+//! A builder handle wraps an [`Initramfs`] along with the [`Encoder`] used
+//! when it is serialized. Functions return [`ELUSIVE_OK`] or a negative
+//! error code, the message of the last error is kept on the handle and can be
+//! read with [`elusive_builder_last_error`].
+//!
+//! A panic inside elusive does not unwind into the caller: it is reported as
+//! [`ELUSIVE_ERROR_PANIC`] with the panic message as the last error, after
+//! which the content of the builder is unspecified.
+//!
+//! Serializing a builder, either into a callback or an owned buffer, consumes
+//! its content: further calls on the handle return
+//! [`ELUSIVE_ERROR_CONSUMED`]. The handle itself still has to be freed.
+//!
+//! The C header in `include/elusive.h` is generated with cbindgen:
+//!
+//! ```sh
+//! cbindgen --config cbindgen.toml --output include/elusive.h
+//! ```
+
+#![deny(clippy::all)]
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use elusive::config::Mode;
+use elusive::encoder::Encoder;
+use elusive::initramfs::Initramfs;
+use elusive::kmod::Kmod;
+use elusive::package;
+use elusive::paths::ImagePath;
+
+use std::any::Any;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::{ptr, slice};
+
+/// The call succeeded.
+pub const ELUSIVE_OK: c_int = 0;
+/// A required pointer argument is null.
+pub const ELUSIVE_ERROR_NULL: c_int = -1;
+/// An argument is invalid, e.g. not valid UTF-8 or a relative path.
+pub const ELUSIVE_ERROR_INVALID: c_int = -2;
+/// The builder failed to add an entry.
+pub const ELUSIVE_ERROR_BUILD: c_int = -3;
+/// The archive could not be serialized, encoded or written.
+pub const ELUSIVE_ERROR_WRITE: c_int = -4;
+/// The builder was already serialized.
+pub const ELUSIVE_ERROR_CONSUMED: c_int = -5;
+/// Elusive panicked, which is a bug.
+pub const ELUSIVE_ERROR_PANIC: c_int = -6;
+
+/// Callback receiving the encoded archive in chunks, returning 0 on success.
+pub type ElusiveWriteCallback =
+    Option<unsafe extern "C" fn(userdata: *mut c_void, data: *const u8, len: usize) -> c_int>;
+
+/// Opaque builder handle.
+pub struct ElusiveBuilder {
+    initramfs: Option<Initramfs>,
+    kmod: Option<Kmod>,
+    encoder: Encoder,
+    last_error: Option<CString>,
+}
+
+/// Error of a call, turned into a code and a message kept on the handle.
+struct Error {
+    code: c_int,
+    message: String,
+}
+
+impl Error {
+    fn new<E: Display>(code: c_int, err: E) -> Self {
+        Error {
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Run the body of an entry point, returning `fallback` if it panics so the
+/// panic does not unwind into the caller.
+fn guard<T, F>(fallback: T, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(fallback)
+}
+
+/// Get the message of a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => format!("panic: {message}"),
+        (_, Some(message)) => format!("panic: {message}"),
+        _ => "panic".to_string(),
+    }
+}
+
+impl ElusiveBuilder {
+    fn new(initramfs: Initramfs) -> *mut Self {
+        let builder = ElusiveBuilder {
+            initramfs: Some(initramfs),
+            kmod: None,
+            encoder: Encoder::None,
+            last_error: None,
+        };
+
+        Box::into_raw(Box::new(builder))
+    }
+
+    fn initramfs(&mut self) -> Result<&mut Initramfs, Error> {
+        self.initramfs
+            .as_mut()
+            .ok_or_else(|| Error::new(ELUSIVE_ERROR_CONSUMED, "builder was already serialized"))
+    }
+
+    // serialize the content of the builder and encode it into the writer
+    fn write_to<W: Write>(&mut self, out: W) -> Result<(), Error> {
+        self.initramfs()?;
+        let initramfs = self.initramfs.take().expect("builder is not consumed");
+
+        let data = initramfs
+            .into_archive()
+            .serialize()
+            .map_err(|err| Error::new(ELUSIVE_ERROR_WRITE, err))?;
+        self.encoder
+            .encode(&data, out)
+            .map_err(|err| Error::new(ELUSIVE_ERROR_WRITE, err))
+    }
+}
+
+/// Writes into a caller provided callback.
+struct CallbackWriter {
+    callback: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> c_int,
+    userdata: *mut c_void,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        // SAFETY: the caller of elusive_builder_write vouches for the callback
+        // and its userdata, the buffer is valid for the duration of the call
+        let ret = unsafe { (self.callback)(self.userdata, buf.as_ptr(), buf.len()) };
+        if ret != 0 {
+            return Err(io::Error::other(format!("write callback returned {ret}")));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Run a call on a builder handle, recording its error if any.
+///
+/// # Safety
+///
+/// `builder` must be null or a handle returned by one of the
+/// `elusive_builder_new` functions and not freed yet.
+unsafe fn with_builder<F>(builder: *mut ElusiveBuilder, f: F) -> c_int
+where
+    F: FnOnce(&mut ElusiveBuilder) -> Result<(), Error>,
+{
+    // SAFETY: see the requirements of this function
+    let Some(builder) = (unsafe { builder.as_mut() }) else {
+        return ELUSIVE_ERROR_NULL;
+    };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(builder)))
+        .unwrap_or_else(|payload| Err(Error::new(ELUSIVE_ERROR_PANIC, panic_message(&*payload))));
+
+    match result {
+        Ok(()) => {
+            builder.last_error = None;
+            ELUSIVE_OK
+        }
+        Err(err) => {
+            // messages cannot hold nul bytes, replace them rather than losing
+            // the message
+            let message = err.message.replace('\0', "\\0");
+            builder.last_error = Some(CString::new(message).expect("nul bytes are replaced"));
+            err.code
+        }
+    }
+}
+
+/// Read a nul-terminated UTF-8 string argument.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a nul-terminated string.
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(Error::new(ELUSIVE_ERROR_NULL, format!("{name} is null")));
+    }
+
+    // SAFETY: see the requirements of this function
+    let cstr = unsafe { CStr::from_ptr(ptr) };
+    cstr.to_str()
+        .map_err(|err| Error::new(ELUSIVE_ERROR_INVALID, format!("{name}: {err}")))
+}
+
+/// Read a path in the initramfs argument.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a nul-terminated string.
+unsafe fn image_path_arg(ptr: *const c_char, name: &str) -> Result<ImagePath, Error> {
+    // SAFETY: see the requirements of this function
+    let path = unsafe { str_arg(ptr, name) }?;
+    ImagePath::new(path).map_err(|err| Error::new(ELUSIVE_ERROR_INVALID, err))
+}
+
+/// Create a builder with the default skeleton (root directories and usr-merge
+/// symlinks), returning null on failure.
+#[no_mangle]
+pub extern "C" fn elusive_builder_new() -> *mut ElusiveBuilder {
+    guard(ptr::null_mut(), || match Initramfs::new() {
+        Ok(initramfs) => ElusiveBuilder::new(initramfs),
+        Err(_) => ptr::null_mut(),
+    })
+}
+
+/// Create a builder for a bare archive, which only contains the root
+/// directory and does not move files under `/usr`.
+#[no_mangle]
+pub extern "C" fn elusive_builder_new_bare() -> *mut ElusiveBuilder {
+    guard(ptr::null_mut(), || {
+        ElusiveBuilder::new(Initramfs::new_bare())
+    })
+}
+
+/// Free a builder handle, null is ignored.
+///
+/// # Safety
+///
+/// `builder` must be null or a handle returned by one of the
+/// `elusive_builder_new` functions and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_free(builder: *mut ElusiveBuilder) {
+    if !builder.is_null() {
+        // SAFETY: the handle was created by Box::into_raw
+        let builder = unsafe { Box::from_raw(builder) };
+        guard((), || drop(builder));
+    }
+}
+
+/// Get the message of the error returned by the last call on the handle, or
+/// null if it succeeded. The string is owned by the handle and valid until
+/// the next call on it.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_last_error(
+    builder: *const ElusiveBuilder,
+) -> *const c_char {
+    // SAFETY: see the requirements of this function
+    match unsafe { builder.as_ref() }.and_then(|builder| builder.last_error.as_ref()) {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Add a file holding `len` bytes of `data` with the provided permissions
+/// (e.g. `0644`) at `path` in the initramfs.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `path` null or a nul-terminated
+/// string and `data` valid for `len` bytes unless `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_add_file(
+    builder: *mut ElusiveBuilder,
+    path: *const c_char,
+    data: *const u8,
+    len: usize,
+    mode: u32,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let path = image_path_arg(path, "path")?;
+
+            let content = match (data.is_null(), len) {
+                (_, 0) => Vec::new(),
+                (true, _) => return Err(Error::new(ELUSIVE_ERROR_NULL, "data is null")),
+                (false, _) => slice::from_raw_parts(data, len).to_vec(),
+            };
+
+            if mode > 0o7777 {
+                return Err(Error::new(
+                    ELUSIVE_ERROR_INVALID,
+                    format!("invalid mode: {mode:o}"),
+                ));
+            }
+
+            builder
+                .initramfs()?
+                .add_content_file(&path, content, Mode(mode))
+                .map_err(|err| Error::new(ELUSIVE_ERROR_BUILD, err))
+        })
+    }
+}
+
+/// Add a symlink at `path` in the initramfs pointing to `target` as is.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `path` and `target` null or
+/// nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_add_symlink(
+    builder: *mut ElusiveBuilder,
+    path: *const c_char,
+    target: *const c_char,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let path = image_path_arg(path, "path")?;
+            let target = str_arg(target, "target")?;
+
+            builder
+                .initramfs()?
+                .add_link(&path, Path::new(target))
+                .map_err(|err| Error::new(ELUSIVE_ERROR_BUILD, err))
+        })
+    }
+}
+
+/// Add an empty directory, along with its parents, at `path` in the
+/// initramfs.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `path` null or a nul-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_add_directory(
+    builder: *mut ElusiveBuilder,
+    path: *const c_char,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let path = image_path_arg(path, "path")?;
+
+            builder
+                .initramfs()?
+                .add_directory(&path)
+                .map_err(|err| Error::new(ELUSIVE_ERROR_BUILD, err))
+        })
+    }
+}
+
+/// Add the ELF binary found at `path` on the host, or in `PATH` when it is
+/// relative, along with its libraries and program interpreter.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `path` null or a nul-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_add_elf(
+    builder: *mut ElusiveBuilder,
+    path: *const c_char,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let path = str_arg(path, "path")?;
+
+            builder
+                .initramfs()?
+                .add_elf(Path::new(path))
+                .map_err(|err| Error::new(ELUSIVE_ERROR_BUILD, err))
+        })
+    }
+}
+
+/// Look kernel modules up in the provided module directory or kernel package
+/// instead of the modules of the running kernel.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `path` null or a nul-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_set_kernel_module_path(
+    builder: *mut ElusiveBuilder,
+    path: *const c_char,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let path = Path::new(str_arg(path, "path")?);
+
+            let kmod = if package::is_package(path) {
+                Kmod::with_package(path)
+            } else {
+                Kmod::with_directory(path)
+            };
+
+            builder.kmod = Some(kmod.map_err(|err| Error::new(ELUSIVE_ERROR_INVALID, err))?);
+            Ok(())
+        })
+    }
+}
+
+/// Add the kernel module with the provided name or alias, along with its
+/// dependencies and the firmware it needs.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `name` null or a nul-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_add_kernel_module(
+    builder: *mut ElusiveBuilder,
+    name: *const c_char,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let name = str_arg(name, "name")?;
+
+            if builder.kmod.is_none() {
+                let kmod = Kmod::new().map_err(|err| Error::new(ELUSIVE_ERROR_BUILD, err))?;
+                builder.kmod = Some(kmod);
+            }
+
+            let ElusiveBuilder {
+                initramfs, kmod, ..
+            } = builder;
+            let initramfs = initramfs.as_mut().ok_or_else(|| {
+                Error::new(ELUSIVE_ERROR_CONSUMED, "builder was already serialized")
+            })?;
+            let kmod = kmod.as_mut().expect("kmod was created");
+
+            initramfs
+                .add_module_from_name(kmod, name)
+                .map_err(|err| Error::new(ELUSIVE_ERROR_BUILD, err))
+        })
+    }
+}
+
+/// Set the compression of the archive: `none` (the default), `gzip` or
+/// `zstd`.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `name` null or a nul-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_set_encoder(
+    builder: *mut ElusiveBuilder,
+    name: *const c_char,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let name = str_arg(name, "name")?;
+
+            builder.encoder = name
+                .parse()
+                .map_err(|err| Error::new(ELUSIVE_ERROR_INVALID, err))?;
+            Ok(())
+        })
+    }
+}
+
+/// Serialize and encode the archive, passing it to `callback` in chunks along
+/// with `userdata`. This consumes the content of the builder.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `callback` must be safe to call
+/// with `userdata` and a buffer valid for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_write(
+    builder: *mut ElusiveBuilder,
+    callback: ElusiveWriteCallback,
+    userdata: *mut c_void,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            let Some(callback) = callback else {
+                return Err(Error::new(ELUSIVE_ERROR_NULL, "callback is null"));
+            };
+
+            builder.write_to(CallbackWriter { callback, userdata })
+        })
+    }
+}
+
+/// Serialize and encode the archive into a buffer owned by the caller, to be
+/// freed with [`elusive_buffer_free`]. This consumes the content of the
+/// builder.
+///
+/// # Safety
+///
+/// `builder` must be null or a valid handle, `data` and `len` null or valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_builder_build(
+    builder: *mut ElusiveBuilder,
+    data: *mut *mut u8,
+    len: *mut usize,
+) -> c_int {
+    // SAFETY: see the requirements of this function
+    unsafe {
+        with_builder(builder, |builder| {
+            if data.is_null() || len.is_null() {
+                return Err(Error::new(ELUSIVE_ERROR_NULL, "output pointer is null"));
+            }
+
+            let mut buffer = Vec::new();
+            builder.write_to(&mut buffer)?;
+
+            let buffer = Box::into_raw(buffer.into_boxed_slice());
+            *len = buffer.len();
+            *data = buffer.cast::<u8>();
+
+            Ok(())
+        })
+    }
+}
+
+/// Free a buffer returned by [`elusive_builder_build`], null is ignored.
+///
+/// # Safety
+///
+/// `data` must be null or a buffer returned by [`elusive_builder_build`] along
+/// with its `len`, and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn elusive_buffer_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        // SAFETY: the buffer was created from a boxed slice of this length
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use elusive::newc::Archive;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    fn last_error(builder: *const ElusiveBuilder) -> Option<String> {
+        let message = unsafe { elusive_builder_last_error(builder) };
+        if message.is_null() {
+            return None;
+        }
+
+        Some(
+            unsafe { CStr::from_ptr(message) }
+                .to_str()
+                .unwrap()
+                .to_string(),
+        )
+    }
+
+    unsafe extern "C" fn collect(userdata: *mut c_void, data: *const u8, len: usize) -> c_int {
+        let out = unsafe { &mut *userdata.cast::<Vec<u8>>() };
+        out.extend_from_slice(unsafe { slice::from_raw_parts(data, len) });
+        0
+    }
+
+    unsafe extern "C" fn fail(_: *mut c_void, _: *const u8, _: usize) -> c_int {
+        5
+    }
+
+    // dynamic ELF whose DT_NEEDED entry points past its string table
+    fn malformed_elf() -> Vec<u8> {
+        let strtab = 64 + 56 * 2;
+        let dynamic_offset = strtab + 8;
+        let dynamic = [1, 0x1000, 5, strtab, 10, 8, 0, 0];
+        let size = dynamic_offset + 8 * dynamic.len() as u64;
+
+        let mut data = b"\x7fELF\x02\x01\x01".to_vec();
+        data.resize(16, 0);
+        data.extend(3u16.to_le_bytes());
+        data.extend(62u16.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend([0u64, 64, 0].iter().flat_map(|value| value.to_le_bytes()));
+        data.extend(0u32.to_le_bytes());
+        for value in [64u16, 56, 2, 64, 0, 0] {
+            data.extend(value.to_le_bytes());
+        }
+
+        for (kind, offset) in [(1u32, 0), (2, dynamic_offset)] {
+            data.extend(kind.to_le_bytes());
+            data.extend(4u32.to_le_bytes());
+            for value in [offset, offset, offset, size - offset, size - offset, 8] {
+                data.extend(value.to_le_bytes());
+            }
+        }
+
+        data.extend([0; 8]);
+        data.extend(dynamic.iter().flat_map(|value: &u64| value.to_le_bytes()));
+        data
+    }
+
+    #[test]
+    fn test_build() {
+        let builder = elusive_builder_new_bare();
+        let motd = b"hello";
+
+        unsafe {
+            let ret = elusive_builder_add_file(
+                builder,
+                c"/etc/motd".as_ptr(),
+                motd.as_ptr(),
+                motd.len(),
+                0o600,
+            );
+            assert_eq!(ret, ELUSIVE_OK);
+            assert_eq!(
+                elusive_builder_add_symlink(builder, c"/bin".as_ptr(), c"usr/bin".as_ptr()),
+                ELUSIVE_OK
+            );
+            assert_eq!(
+                elusive_builder_add_directory(builder, c"/run/lock".as_ptr()),
+                ELUSIVE_OK
+            );
+            assert_eq!(
+                elusive_builder_set_encoder(builder, c"gzip".as_ptr()),
+                ELUSIVE_OK
+            );
+
+            let mut data = ptr::null_mut();
+            let mut len = 0;
+            assert_eq!(
+                elusive_builder_build(builder, &mut data, &mut len),
+                ELUSIVE_OK
+            );
+            assert_eq!(last_error(builder), None);
+
+            let encoded = slice::from_raw_parts(data, len).to_vec();
+            elusive_buffer_free(data, len);
+
+            let decoded = elusive::encoder::decode(&encoded).unwrap();
+            assert_ne!(decoded, encoded);
+
+            let archive = Archive::deserialize(&decoded).unwrap();
+            let entries: BTreeMap<_, _> = archive.entries().iter().cloned().collect();
+            let motd = &entries[&PathBuf::from("/etc/motd")];
+            assert_eq!(motd.data.as_deref(), Some(&b"hello"[..]));
+            assert_eq!(motd.metadata.mode, 0o100_600);
+            assert!(entries[&PathBuf::from("/bin")].is_symlink());
+            assert!(entries[&PathBuf::from("/run/lock")].is_dir());
+
+            // the content is gone once serialized
+            let ret = elusive_builder_add_directory(builder, c"/tmp".as_ptr());
+            assert_eq!(ret, ELUSIVE_ERROR_CONSUMED);
+
+            elusive_builder_free(builder);
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let builder = elusive_builder_new();
+        let mut out: Vec<u8> = Vec::new();
+
+        unsafe {
+            let userdata = (&mut out as *mut Vec<u8>).cast::<c_void>();
+            assert_eq!(
+                elusive_builder_write(builder, Some(collect), userdata),
+                ELUSIVE_OK
+            );
+            elusive_builder_free(builder);
+        }
+
+        let archive = Archive::deserialize(&out).unwrap();
+        assert!(archive
+            .entries()
+            .iter()
+            .any(|(path, _)| path == Path::new("/usr/bin")));
+    }
+
+    #[test]
+    fn test_panic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("malformed");
+        std::fs::write(&path, malformed_elf()).unwrap();
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let builder = elusive_builder_new_bare();
+
+        unsafe {
+            let ret = elusive_builder_add_elf(builder, path.as_ptr());
+            assert_eq!(ret, ELUSIVE_ERROR_PANIC);
+            assert_eq!(
+                last_error(builder).as_deref(),
+                Some("panic: offset exists in string table: ()")
+            );
+
+            elusive_builder_free(builder);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let builder = elusive_builder_new_bare();
+
+        unsafe {
+            let ret = elusive_builder_add_directory(builder, c"etc".as_ptr());
+            assert_eq!(ret, ELUSIVE_ERROR_INVALID);
+            assert_eq!(
+                last_error(builder).as_deref(),
+                Some("path in the initramfs must be absolute: etc")
+            );
+
+            let ret = elusive_builder_add_file(builder, c"/etc/a".as_ptr(), ptr::null(), 4, 0o644);
+            assert_eq!(ret, ELUSIVE_ERROR_NULL);
+            assert_eq!(last_error(builder).as_deref(), Some("data is null"));
+
+            let ret = elusive_builder_set_encoder(builder, c"lz4".as_ptr());
+            assert_eq!(ret, ELUSIVE_ERROR_INVALID);
+            assert_eq!(last_error(builder).as_deref(), Some("unknown encoder: lz4"));
+
+            let ret = elusive_builder_add_elf(builder, c"/nonexistent/elusive".as_ptr());
+            assert_eq!(ret, ELUSIVE_ERROR_BUILD);
+            assert!(last_error(builder).is_some());
+
+            // errors are cleared by the next successful call
+            let ret = elusive_builder_add_directory(builder, c"/etc".as_ptr());
+            assert_eq!(ret, ELUSIVE_OK);
+            assert_eq!(last_error(builder), None);
+
+            let ret = elusive_builder_write(builder, Some(fail), ptr::null_mut());
+            assert_eq!(ret, ELUSIVE_ERROR_WRITE);
+            assert!(last_error(builder)
+                .unwrap()
+                .contains("write callback returned 5"));
+
+            elusive_builder_free(builder);
+        }
+
+        let ret = unsafe { elusive_builder_add_directory(ptr::null_mut(), c"/etc".as_ptr()) };
+        assert_eq!(ret, ELUSIVE_ERROR_NULL);
+        assert!(unsafe { elusive_builder_last_error(ptr::null()) }.is_null());
+    }
+}
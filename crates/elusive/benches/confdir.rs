@@ -0,0 +1,80 @@
+//! Time module loading from a large configuration directory.
+//!
+//! Run with `cargo bench --bench confdir`, the number of generated module
+//! files and of selected modules can be set with `ELUSIVE_BENCH_MODULES` and
+//! `ELUSIVE_BENCH_SELECTED`.
+
+use elusive::config::loader;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+const ITERATIONS: u32 = 20;
+
+fn var(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Write a module file similar to the ones generated per package.
+fn write_module(dir: &Path, index: usize) {
+    let mut module = format!("name: package-{index}\nbinaries:\n");
+
+    for binary in 0..10 {
+        module.push_str(&format!("  - /usr/bin/package-{index}-{binary}\n"));
+    }
+
+    module.push_str("files:\n");
+    for file in 0..10 {
+        module.push_str(&format!(
+            "  - sources: [/usr/share/package-{index}/{file}]\n    destination: /usr/share/package-{index}\n"
+        ));
+    }
+
+    module.push_str("kernel_modules:\n  - name: module-a\n    softdeps: false\n  - module-b\n");
+    fs::write(dir.join(format!("{index:03}-package-{index}.yaml")), module).unwrap();
+}
+
+fn time<F>(mut f: F) -> Duration
+where
+    F: FnMut(),
+{
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        f();
+    }
+
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let count = var("ELUSIVE_BENCH_MODULES", 200);
+    let selected = var("ELUSIVE_BENCH_SELECTED", 15).min(count);
+
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    for index in 0..count {
+        write_module(dir, index);
+    }
+
+    let confdirs = [dir.to_path_buf()];
+    let names: Vec<_> = (0..selected)
+        .map(|index| format!("package-{}", index * count / selected))
+        .collect();
+
+    let eager = time(|| {
+        loader::read_modules(&confdirs).unwrap();
+    });
+    let lazy = time(|| {
+        loader::load_modules(&confdirs, &names).unwrap();
+    });
+
+    println!("{count} module files, {selected} selected, {ITERATIONS} iterations");
+    println!("parse all modules:      {eager:?}");
+    println!("parse selected modules: {lazy:?}");
+}
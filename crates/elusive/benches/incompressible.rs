@@ -0,0 +1,107 @@
+//! Time the compression of a firmware heavy archive, whole and with
+//! incompressible files split off to an uncompressed segment.
+//!
+//! Run with `cargo bench --bench incompressible`, the number of firmware
+//! blobs and of text files can be set with `ELUSIVE_BENCH_FIRMWARE` and
+//! `ELUSIVE_BENCH_FILES`.
+
+use elusive::encoder::{self, Encoder};
+use elusive::newc::Archive;
+use elusive::vfs::Entry;
+
+use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 5;
+
+/// Size of each firmware blob.
+const FIRMWARE_SIZE: usize = 1 << 20;
+
+fn var(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Generate data that does not compress, like most firmware.
+fn noise(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+
+    (0..len)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+/// Build an archive of firmware blobs and small text files.
+fn fixture(firmware: usize, files: usize) -> Archive {
+    let blobs = (0..firmware).map(|index| {
+        let path = PathBuf::from(format!("/usr/lib/firmware/vendor/blob-{index}.bin"));
+        (path, Entry::file(noise(index as u64, FIRMWARE_SIZE)))
+    });
+
+    let texts = (0..files).map(|index| {
+        let path = PathBuf::from(format!("/usr/share/package/file-{index}.conf"));
+        let data = format!("# configuration file {index}\noption = value\n").repeat(64);
+        (path, Entry::file(data.into_bytes()))
+    });
+
+    Archive::from(blobs.chain(texts))
+}
+
+fn time<F>(mut f: F) -> Duration
+where
+    F: FnMut(),
+{
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        f();
+    }
+
+    start.elapsed() / ITERATIONS
+}
+
+fn compress(data: &[u8]) -> usize {
+    let mut out = Vec::new();
+    Encoder::Zstd.encode(data, &mut out).unwrap();
+
+    out.len()
+}
+
+fn main() {
+    let firmware = var("ELUSIVE_BENCH_FIRMWARE", 40);
+    let files = var("ELUSIVE_BENCH_FILES", 2000);
+
+    let whole = fixture(firmware, files).serialize().unwrap();
+
+    let mut archive = fixture(firmware, files);
+    let stored = archive.split_off(|path, entry| {
+        entry
+            .data
+            .as_deref()
+            .is_some_and(|data| encoder::is_incompressible(path, data))
+    });
+    let split_count = stored.entries().len();
+    let main = archive.serialize().unwrap();
+    let stored = stored.serialize().unwrap();
+
+    let mut whole_size = 0;
+    let mut split_size = 0;
+    let whole_time = time(|| whole_size = compress(&whole));
+    let split_time = time(|| split_size = compress(&main) + stored.len());
+
+    println!(
+        "{firmware} firmware blobs of {FIRMWARE_SIZE} bytes, {files} text files, \
+         {split_count} split off, {ITERATIONS} iterations"
+    );
+    println!("zstd whole archive: {whole_time:?}, {whole_size} bytes");
+    println!("zstd split archive: {split_time:?}, {split_size} bytes");
+}
@@ -0,0 +1,32 @@
+//! Parse arbitrary bytes as an archive. Parsing never panics, and archives
+//! that parse and serialize again keep their entries.
+
+#![no_main]
+
+use elusive::newc::Archive;
+use libfuzzer_sys::fuzz_target;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+fn paths(archive: &Archive) -> BTreeSet<PathBuf> {
+    archive
+        .entries()
+        .iter()
+        .map(|(path, _)| path.clone())
+        .filter(|path| path != Path::new("/"))
+        .collect()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(archive) = Archive::deserialize(data) else {
+        return;
+    };
+
+    let expected = paths(&archive);
+    let Ok(serialized) = archive.serialize() else {
+        return;
+    };
+
+    let parsed = Archive::deserialize(&serialized).expect("serialized archive should parse");
+    assert_eq!(paths(&parsed), expected);
+});
@@ -0,0 +1,72 @@
+//! Serialize archives built from arbitrary entries. Serializing may fail on
+//! entries the kernel would misread, but never panics, and what it produces
+//! parses back.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use elusive::newc::Archive;
+use elusive::vfs::Entry;
+use libfuzzer_sys::fuzz_target;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+#[derive(Arbitrary, Debug)]
+enum Kind {
+    Directory,
+    File(Vec<u8>),
+    Symlink(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct FuzzEntry {
+    path: Vec<u8>,
+    kind: Kind,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    rdev_major: u64,
+    rdev_minor: u64,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    entries: Vec<FuzzEntry>,
+    hardlinks: Vec<Vec<Vec<u8>>>,
+    canonical: bool,
+}
+
+fn path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+fuzz_target!(|input: Input| {
+    let entries = input.entries.into_iter().map(|fuzz| {
+        let mut entry = match fuzz.kind {
+            Kind::Directory => Entry::directory(),
+            Kind::File(data) => Entry::file(data),
+            Kind::Symlink(target) => Entry::symlink(path(&target)),
+        };
+
+        entry.metadata.mode = fuzz.mode;
+        entry.metadata.uid = fuzz.uid;
+        entry.metadata.gid = fuzz.gid;
+        entry.metadata.mtime = fuzz.mtime;
+        entry.metadata.rdev_major = fuzz.rdev_major;
+        entry.metadata.rdev_minor = fuzz.rdev_minor;
+
+        (path(&fuzz.path), entry)
+    });
+
+    let mut archive = Archive::from(entries.collect::<Vec<_>>());
+    for group in input.hardlinks {
+        archive.add_hardlinks(group.iter().map(|member| path(member)));
+    }
+    archive.set_canonical(input.canonical);
+
+    if let Ok(data) = archive.serialize() {
+        Archive::deserialize(&data).expect("serialized archive should parse");
+    }
+});
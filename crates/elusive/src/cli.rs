@@ -1,41 +1,237 @@
 use crate::config;
+pub use crate::config::loader::ConfigurationError;
+use crate::config::loader::{self, ConfigPaths};
+use crate::constraint::Platform;
 use crate::encoder::Encoder;
-use crate::initramfs::Initramfs;
-use crate::io::{Input, Output};
-use crate::microcode::MicrocodeBundle;
+use crate::encoder::{self, EncoderError};
+use crate::initramfs::{Failure, Initramfs, InitramfsError};
+use crate::io::{CountingWriter, Input, Output, TimedWriter};
+use crate::kmod;
+use crate::logger::LogFormat;
+use crate::measurement::{self, ImageDigest, Manifest};
+use crate::microcode::{MicrocodeBundle, MicrocodeError};
+use crate::newc::{self, NewcError};
+use crate::paths::escaped;
+use crate::plan::BuildPlan;
+use crate::report::{Attribution, ReportError, ReportFormat, SizeReport};
+use crate::signing::{self, DigestWriter, SigningError};
+use crate::size::Size;
+use crate::stats::BuildStats;
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
-use log::{debug, error, info};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint};
+use clap_complete::Shell;
+use ed25519_dalek::SigningKey;
+use glob::Pattern;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::{fs, io};
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::{env, fs, io, slice};
+use walkdir::WalkDir;
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/elusive.yaml";
-const DEFAULT_CONFDIR_PATHS: &[&str] = &["/etc/elusive.d", "/usr/share/elusive/elusive.d"];
+/// Default module directories, from lowest to highest priority.
+const DEFAULT_CONFDIR_PATHS: &[&str] = &["/usr/share/elusive/elusive.d", "/etc/elusive.d"];
+/// Default maximum size of trained dictionaries, same as the zstd command.
+const DEFAULT_DICTIONARY_SIZE: Size = Size(112_640);
+
+/// Placeholder replaced by the kernel release in output paths.
+const KERNEL_PLACEHOLDER: &str = "{kernel}";
+
+#[derive(thiserror::Error, Debug)]
+pub enum OutputError {
+    #[error(
+        "output size {actual} ({} bytes) exceeds the maximum size of {budget} ({} bytes)",
+        actual.bytes(),
+        budget.bytes()
+    )]
+    TooLarge { actual: Size, budget: Size },
+    #[error("not a cpio archive: {0}")]
+    InvalidSegment(PathBuf),
+    #[error("cannot write a detached signature for standard output")]
+    SignStdout,
+    #[error("unknown output format: {0}")]
+    UnknownFormat(String),
+    #[error(
+        "zstd dictionaries require --format raw-zstd, the kernel cannot decompress the result"
+    )]
+    DictionaryFormat,
+    #[error("--{0} cannot be used with --output-format dir, nothing is archived")]
+    DirOption(&'static str),
+    #[error("output directory is not empty, use --force to write into it: {0}")]
+    NotEmpty(PathBuf),
+    #[error("--uki-section requires --format initramfs, the section is booted by the kernel")]
+    UkiFormat,
+    #[error("cannot print the measurement when the image is written to standard output")]
+    MeasurementStdout,
+    #[error(
+        "cannot print the size report or timings when the image is written to standard output"
+    )]
+    ReportStdout,
+    #[error("{0} must contain {{kernel}} to write one output per kernel")]
+    KernelPlaceholder(PathBuf),
+    #[error("{{kernel}} in {0} requires --kernel or --all-kernels")]
+    UnusedKernelPlaceholder(PathBuf),
+    #[error("no kernel modules for release {0} in /usr/lib/modules")]
+    UnknownKernel(String),
+    #[error("no kernel found in /usr/lib/modules")]
+    NoKernels,
+    #[error("output truncated after {written} bytes")]
+    Truncated { written: u64 },
+    #[error("cannot sync output to disk: {0}")]
+    Sync(io::Error),
+    #[error("cannot move output into place: {0}")]
+    Commit(io::Error),
+    #[error("microcode archives cannot use the {0} encoder, the kernel only loads uncompressed early microcode")]
+    CompressedMicrocode(Encoder),
+}
+
+impl OutputError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            OutputError::TooLarge { .. } => "output_too_large",
+            OutputError::InvalidSegment(_) => "output_invalid_segment",
+            OutputError::SignStdout => "output_sign_stdout",
+            OutputError::UnknownFormat(_) => "output_unknown_format",
+            OutputError::DictionaryFormat => "output_dictionary_format",
+            OutputError::DirOption(_) => "output_dir_option",
+            OutputError::NotEmpty(_) => "output_not_empty",
+            OutputError::UkiFormat => "output_uki_format",
+            OutputError::MeasurementStdout => "output_measurement_stdout",
+            OutputError::ReportStdout => "output_report_stdout",
+            OutputError::KernelPlaceholder(_) => "output_kernel_placeholder",
+            OutputError::UnusedKernelPlaceholder(_) => "output_unused_kernel_placeholder",
+            OutputError::UnknownKernel(_) => "output_unknown_kernel",
+            OutputError::NoKernels => "output_no_kernels",
+            OutputError::Truncated { .. } => "output_truncated",
+            OutputError::Sync(_) => "output_sync",
+            OutputError::Commit(_) => "output_commit",
+            OutputError::CompressedMicrocode(_) => "output_compressed_microcode",
+        }
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
-pub enum ConfigurationError {
-    #[error("default paths skipped but no fallback paths specified")]
-    SkipWithoutParameter,
-    #[error("configuration requires a module named '{0}' but none was found")]
-    UnknownModule(String),
-    #[error("configuration file is not a file or does not exist: {0}")]
-    ExpectedFile(PathBuf),
-    #[error("configuration directory is not a directory or does not exist: {0}")]
-    ExpectedDirectory(PathBuf),
+pub enum ExplainError {
+    #[error("no entry in the initramfs matches: {0}")]
+    NoMatch(String),
+}
+
+impl ExplainError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ExplainError::NoMatch(_) => "explain_no_match",
+        }
+    }
+}
+
+/// Get a stable identifier for an error returned by [`elusive`], for tooling
+/// that needs to tell failures apart without parsing messages.
+pub fn error_code(err: &anyhow::Error) -> &'static str {
+    if let Some(err) = err.downcast_ref::<ConfigurationError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<InitramfsError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<MicrocodeError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<OutputError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<SigningError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<ExplainError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<ReportError>() {
+        return err.code();
+    }
+
+    if let Some(err) = err.downcast_ref::<NewcError>() {
+        return err.code();
+    }
+
+    if err.is::<EncoderError>() {
+        return "encoder";
+    }
+
+    if err.is::<serde_yaml::Error>() || err.is::<toml::de::Error>() {
+        return "config_parse";
+    }
+
+    if err.is::<io::Error>() {
+        return "io";
+    }
+
+    "unknown"
+}
+
+/// Default exit code of runs that left items out in best effort mode.
+pub const DEGRADED_EXIT_CODE: i32 = 3;
+
+/// Outcome of a successful run.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// Everything was generated.
+    Complete,
+    /// Items were left out in best effort mode, the process should exit with
+    /// this code.
+    Degraded(i32),
+}
+
+/// Represents the format of generated initramfs images.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub enum OutputFormat {
+    /// Archive loadable by the kernel, with external segments.
+    #[default]
+    Initramfs,
+    /// Archive alone as a single zstd frame, possibly using a dictionary. The
+    /// kernel cannot always load it, it is meant for build caches.
+    RawZstd,
+    /// Uncompressed tree written to a directory, for inspection or chroots.
+    Dir,
+}
+
+impl FromStr for OutputFormat {
+    type Err = OutputError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "initramfs" | "cpio" => Ok(OutputFormat::Initramfs),
+            "raw-zstd" => Ok(OutputFormat::RawZstd),
+            "dir" => Ok(OutputFormat::Dir),
+            other => Err(OutputError::UnknownFormat(other.to_string())),
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
     /// Path to the configuration file
-    #[clap(short, long)]
+    #[clap(short, long, value_hint = ValueHint::FilePath)]
     #[clap(global = true)]
     pub config: Option<PathBuf>,
-    /// Path to the configuration directory
-    #[clap(short = 'C', long)]
+    /// Path to a module directory, modules in later directories override those
+    /// of the same name in earlier ones and in default directories
+    #[clap(short = 'C', long, value_hint = ValueHint::DirPath)]
     #[clap(global = true)]
     pub confdir: Option<Vec<PathBuf>>,
     /// Do not read configuration from default paths
@@ -43,45 +239,283 @@ pub struct Args {
     #[clap(default_value_t = false)]
     #[clap(global = true)]
     pub skip_default_paths: bool,
-    /// Encoder to use for compression
+    /// Encoder to use for compression, overrides settings.encoder
     #[clap(short, long)]
     #[clap(global = true)]
     pub encoder: Option<Encoder>,
+    /// Format of log messages (human or json)
+    #[clap(long)]
+    #[clap(global = true)]
+    pub log_format: Option<LogFormat>,
+    /// Go through the whole generation without writing anything
+    #[clap(long)]
+    #[clap(default_value_t = false)]
+    #[clap(global = true)]
+    pub dry_run: bool,
+    /// Do not wait for written files to reach the disk before exiting
+    #[clap(long)]
+    #[clap(default_value_t = false)]
+    #[clap(global = true)]
+    pub no_sync: bool,
+    /// Show hidden subcommands in help
+    #[clap(long)]
+    #[clap(default_value_t = false)]
+    #[clap(global = true)]
+    pub verbose: bool,
     #[clap(subcommand)]
     pub command: Command,
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Generate a compressed cpio archive to use as initramfs
     Initramfs {
-        /// Microcode archive to include
-        #[clap(short, long)]
+        /// Microcode archive to include, same as prepending it first
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
         ucode: Option<PathBuf>,
-        /// Path to the kernel module source directory
-        #[clap(short, long)]
+        /// Init script or executable installed at /init, overrides the init of the configuration
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        init: Option<PathBuf>,
+        /// Uncompressed cpio archive to write before the initramfs, can be repeated
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        prepend: Vec<PathBuf>,
+        /// Cpio archive to write after the initramfs, can be repeated
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        append: Vec<PathBuf>,
+        /// Path to the kernel module source directory, or a kernel package (.tar, .tar.zst...)
+        #[clap(short, long, value_hint = ValueHint::AnyPath)]
+        modules: Option<PathBuf>,
+        /// Kernel release to generate an initramfs for, with its modules in /usr/lib/modules, can
+        /// be repeated; {kernel} in output paths is replaced by the release
+        #[clap(long = "kernel", value_name = "RELEASE", conflicts_with = "modules")]
+        kernels: Vec<String>,
+        /// Generate an initramfs for every kernel in /usr/lib/modules, like --kernel
+        #[clap(long, conflicts_with_all = ["modules", "kernels"])]
+        #[clap(default_value_t = false)]
+        all_kernels: bool,
+        /// Paths where the initramfs will be written, can be repeated
+        #[clap(short, long, required = true, value_delimiter = ',')]
+        #[clap(value_hint = ValueHint::FilePath)]
+        output: Vec<PathBuf>,
+        /// Check versioned symbols required by binaries are provided in the initramfs
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        verify_symbols: bool,
+        /// Path where kernel command line fragments from modules will be written
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        emit_cmdline: Option<PathBuf>,
+        /// Fail if the compressed initramfs is larger than this size (e.g. 80MiB)
+        #[clap(long)]
+        max_size: Option<Size>,
+        /// Fail if a file read from the host is larger than this size (e.g. 1GiB)
+        #[clap(long)]
+        max_file_size: Option<Size>,
+        /// Fail if the content of the initramfs adds up to more than this size before compression
+        #[clap(long)]
+        max_total_size: Option<Size>,
+        /// Add kernel modules needed by the filesystems, storage and keyboards of this host
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        host_only: bool,
+        /// Add every kernel module loaded on this host, except unused ones matching settings.lsmod_denylist
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        modules_from_lsmod: bool,
+        /// Add the kernel modules listed in a file, one name per line (e.g. a captured lsmod)
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        modules_from_file: Option<PathBuf>,
+        /// Directory where debug sections removed by settings.strip are written, as <path>.debug files
+        #[clap(long, value_hint = ValueHint::DirPath)]
+        debug_sidecar: Option<PathBuf>,
+        /// Remove libraries that no binary in the initramfs needs anymore
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        prune_unused_libs: bool,
+        /// Print the SHA-384 digest of the uncompressed archive, see settings.canonical
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        print_measurement: bool,
+        /// Print the size of each configuration module, compressed sizes are split in proportion
+        /// to uncompressed sizes or, with `modules`, to each module compressed on its own
+        #[clap(long, value_name = "ATTRIBUTION")]
+        #[clap(num_args = 0..=1, default_missing_value = "proportional")]
+        size_report: Option<Attribution>,
+        /// Format of the size report (table, json)
+        #[clap(long)]
+        #[clap(default_value = "table")]
+        size_report_format: ReportFormat,
+        /// Print the time spent in each phase of the build and in each configuration module
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        timings: bool,
+        /// Format of the timings (table, json)
+        #[clap(long)]
+        #[clap(default_value = "table")]
+        timings_format: ReportFormat,
+        /// Ed25519 private key (PEM or raw) used to write a detached signature next to each output
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        sign_key: Option<PathBuf>,
+        /// Output format (initramfs or cpio, raw-zstd, dir), raw-zstd is not meant to be booted
+        #[clap(long, alias = "output-format")]
+        #[clap(default_value = "initramfs")]
+        format: OutputFormat,
+        /// Write into a non-empty directory with --output-format dir
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        force: bool,
+        /// Zstd dictionary to compress with, only allowed with --format raw-zstd
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        zstd_dictionary: Option<PathBuf>,
+        /// Also write the image for the .initrd section of a unified kernel image, with its size in <path>.size
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        uki_section: Option<PathBuf>,
+        /// Path where a JSON manifest with the SHA-256 digests of the image and its entries will be written
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        measurement_manifest: Option<PathBuf>,
+        /// Leave out items of non critical modules that cannot be added, see settings.on_error
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        best_effort: bool,
+        /// Path where a JSON report of the items left out with --best-effort will be written
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        error_report: Option<PathBuf>,
+        /// Exit code when items were left out with --best-effort, 0 to treat it as a success
+        #[clap(long, value_name = "CODE")]
+        #[clap(default_value_t = DEGRADED_EXIT_CODE)]
+        degraded_exit_code: i32,
+    },
+    /// Generate the shutdown initramfs (exitrd) as a cpio archive or a directory
+    Exitrd {
+        /// Path to the kernel module source directory, or a kernel package (.tar, .tar.zst...)
+        #[clap(short, long, value_hint = ValueHint::AnyPath)]
         modules: Option<PathBuf>,
-        /// Path where the initramfs will be written
-        #[clap(short, long)]
+        /// Path where the exitrd will be written, existing directories are populated in place
+        #[clap(short, long, value_hint = ValueHint::AnyPath)]
         output: PathBuf,
     },
-    /// Generate a compressed cpio archive for CPU microcode
-    Microcode {
-        /// Path where the microcode archive will be written
-        #[clap(short, long)]
+    /// Verify the detached signature of a generated image
+    Verify {
+        /// Path to the image
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+        /// Path to the signature, defaults to the image path with a .sig extension
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        sig: Option<PathBuf>,
+        /// Ed25519 public key (PEM or raw)
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        pubkey: PathBuf,
+    },
+    /// Explain why paths are included in the initramfs
+    Explain {
+        /// Path to the kernel module source directory, or a kernel package (.tar, .tar.zst...)
+        #[clap(short, long, value_hint = ValueHint::AnyPath)]
+        modules: Option<PathBuf>,
+        /// Path or glob pattern (e.g. '*/libcrypto*') of entries in the initramfs
+        pattern: String,
+    },
+    /// List libraries that no binary in the initramfs needs, as removed by --prune-unused-libs
+    UnusedLibs {
+        /// Path to the kernel module source directory, or a kernel package (.tar, .tar.zst...)
+        #[clap(short, long, value_hint = ValueHint::AnyPath)]
+        modules: Option<PathBuf>,
+    },
+    /// Train a zstd dictionary from the entries of existing images or directory trees
+    TrainDictionary {
+        /// Images or directory trees to sample file contents from, can be repeated
+        #[clap(short, long, required = true)]
+        #[clap(value_hint = ValueHint::AnyPath)]
+        from: Vec<PathBuf>,
+        /// Path where the dictionary will be written
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
         output: PathBuf,
+        /// Maximum size of the dictionary
+        #[clap(long)]
+        #[clap(default_value_t = DEFAULT_DICTIONARY_SIZE)]
+        max_size: Size,
+    },
+    /// Convert a legacy TOML configuration to the current YAML format
+    MigrateConfig {
+        /// Path to the legacy TOML configuration
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+        /// Directory where the migrated configuration will be written
+        #[clap(long, value_hint = ValueHint::DirPath)]
+        output_dir: PathBuf,
+    },
+    /// List the modules found in the configuration directories or show one
+    Modules {
+        #[clap(subcommand)]
+        command: ModulesCommand,
+    },
+    /// Print the JSON Schema of configuration files
+    Schema {
+        /// Print the schema of module files instead of the top-level file
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        module: bool,
+    },
+    /// Generate an uncompressed cpio archive for CPU microcode
+    Microcode {
+        /// Paths where the microcode archive will be written, can be repeated
+        #[clap(short, long, required_unless_present = "split_output")]
+        #[clap(value_delimiter = ',', value_hint = ValueHint::FilePath)]
+        output: Vec<PathBuf>,
+        /// Directory where a standalone early archive is written per vendor
+        /// (amd-ucode.img, intel-ucode.img)
+        #[clap(long, value_hint = ValueHint::DirPath)]
+        split_output: Option<PathBuf>,
+    },
+    /// Generate shell completions or a man page
+    #[clap(hide = true)]
+    Generate {
+        /// Shell to generate completions for
+        #[clap(long, required_unless_present = "man", conflicts_with = "man")]
+        shell: Option<Shell>,
+        /// Generate a man page in roff format
+        #[clap(long)]
+        #[clap(default_value_t = false)]
+        man: bool,
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum ModulesCommand {
+    /// List every module with a summary of its content and the file defining it
+    List,
+    /// Print a module the way it is interpreted, as normalized YAML
+    Show {
+        /// Name of the module
+        name: String,
+    },
+}
+
+/// Parse command line arguments, hidden subcommands are only listed in help
+/// when `--verbose` is given.
+pub fn parse_args() -> Args {
+    let mut command = Args::command();
+
+    if env::args_os().any(|arg| arg == "--verbose") {
+        command = command.mut_subcommand("generate", |generate| generate.hide(false));
+    }
+
+    let matches = command.get_matches();
+    Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
 /// Entrypoint of the program
-pub fn elusive(args: Args) -> Result<()> {
+pub fn elusive(args: Args) -> Result<Status> {
     let Args {
         config,
         confdir: confdirs,
         encoder,
         command,
         skip_default_paths,
+        dry_run,
+        no_sync,
+        ..
     } = args;
+    let sync = !no_sync;
 
     let config_path = match (config, skip_default_paths) {
         (Some(path), _) => path,
@@ -89,116 +523,2132 @@ pub fn elusive(args: Args) -> Result<()> {
         (None, true) => bail!(ConfigurationError::SkipWithoutParameter),
     };
 
-    let default_confdirs = DEFAULT_CONFDIR_PATHS.iter().map(PathBuf::from).collect();
+    // lowest priority first, see loader::read_modules
+    let default_confdirs: Vec<_> = DEFAULT_CONFDIR_PATHS.iter().map(PathBuf::from).collect();
     let confdir_paths = match (confdirs, skip_default_paths) {
-        (Some(paths), false) => paths.into_iter().chain(default_confdirs).collect(),
+        (Some(paths), false) => default_confdirs.into_iter().chain(paths).collect(),
         (Some(paths), true) => paths,
         (None, false) => default_confdirs,
         (None, true) => bail!(ConfigurationError::SkipWithoutParameter),
     };
 
+    let mut status = Status::Complete;
+
     debug!("Config file path set to {:?}", config_path);
     debug!("Module directory paths set to {:?}", confdir_paths);
 
-    let encoder = encoder.unwrap_or(Encoder::Zstd);
+    let paths = ConfigPaths {
+        config: config_path,
+        confdirs: confdir_paths,
+    };
+
+    let custom_encoder = encoder.is_some();
 
     match command {
         Command::Initramfs {
             ucode,
+            init,
+            prepend,
+            append,
             modules,
+            kernels,
+            all_kernels,
             output,
+            verify_symbols,
+            emit_cmdline,
+            max_size,
+            max_file_size,
+            max_total_size,
+            host_only,
+            modules_from_lsmod,
+            modules_from_file,
+            debug_sidecar,
+            prune_unused_libs,
+            print_measurement,
+            size_report,
+            size_report_format,
+            timings,
+            timings_format,
+            sign_key,
+            format,
+            force,
+            zstd_dictionary,
+            uki_section,
+            measurement_manifest,
+            best_effort,
+            error_report,
+            degraded_exit_code,
         } => {
-            let mut config: config::Initramfs = {
-                if !config_path.exists() || !config_path.is_file() {
-                    bail!(ConfigurationError::ExpectedFile(config_path));
+            // options that only make sense for archives
+            if format == OutputFormat::Dir {
+                let rejected = [
+                    ("encoder", custom_encoder),
+                    ("ucode", ucode.is_some()),
+                    ("prepend", !prepend.is_empty()),
+                    ("append", !append.is_empty()),
+                    ("max-size", max_size.is_some()),
+                    ("sign-key", sign_key.is_some()),
+                    ("zstd-dictionary", zstd_dictionary.is_some()),
+                    ("uki-section", uki_section.is_some()),
+                    ("measurement-manifest", measurement_manifest.is_some()),
+                    ("print-measurement", print_measurement),
+                    ("size-report", size_report.is_some()),
+                ];
+
+                if let Some((option, _)) = rejected.iter().find(|(_, set)| *set) {
+                    bail!(OutputError::DirOption(option));
+                }
+            }
+
+            if format == OutputFormat::RawZstd && uki_section.is_some() {
+                bail!(OutputError::UkiFormat);
+            }
+
+            if print_measurement && output.iter().any(|path| path == Path::new("-")) {
+                bail!(OutputError::MeasurementStdout);
+            }
+
+            if (size_report.is_some() || timings)
+                && output.iter().any(|path| path == Path::new("-"))
+            {
+                bail!(OutputError::ReportStdout);
+            }
+
+            let kernels = if all_kernels {
+                let releases = kmod::installed_releases(Path::new(kmod::MODULES_DIR))?;
+                if releases.is_empty() {
+                    bail!(OutputError::NoKernels);
                 }
 
-                debug!("Parsing top-level config file: {:?}", config_path);
-                let data = fs::read(config_path)?;
-                serde_yaml::from_slice(&data)?
+                releases
+            } else {
+                kernels
             };
 
+            // every kernel needs its own outputs
+            let sidecars = emit_cmdline
+                .iter()
+                .chain(&uki_section)
+                .chain(&measurement_manifest);
+            for path in output.iter().chain(sidecars) {
+                let placeholder = has_kernel_placeholder(path);
+
+                if kernels.len() > 1 && !placeholder {
+                    bail!(OutputError::KernelPlaceholder(path.clone()));
+                }
+
+                if kernels.is_empty() && placeholder {
+                    bail!(OutputError::UnusedKernelPlaceholder(path.clone()));
+                }
+            }
+
+            let (mut config, selected) = loader::load_initramfs_config(&paths)?;
+
             // override kernel modules path
             if let Some(path) = modules {
                 debug!("Overriding kernel module path: {:?}", path);
                 config.settings.kernel_module_path = Some(path);
             }
 
-            // parse all available modules
-            let mut modules = BTreeMap::new();
-            for path in confdir_paths {
-                if !path.exists() {
-                    continue;
+            // the command line wins over the configuration
+            if let Some(path) = init {
+                match &config.init {
+                    Some(config::Init::Path(configured)) if *configured == path => (),
+                    Some(config::Init::Path(configured)) => info!(
+                        "Overriding init {} with {}",
+                        configured.display(),
+                        path.display()
+                    ),
+                    Some(config::Init::Wrapper(wrapper)) => info!(
+                        "Overriding init wrapper around {} with {}",
+                        wrapper.helper.display(),
+                        path.display()
+                    ),
+                    None => info!("Using init: {}", path.display()),
+                }
+
+                config.init = Some(config::Init::Path(path));
+            }
+
+            // fail early on unusable keys, before generating anything
+            let sign_key = sign_key
+                .map(|path| signing::read_signing_key(&path))
+                .transpose()?;
+
+            let encoder = output_encoder(
+                format,
+                resolve_encoder(encoder, config.settings.encoder.as_ref()),
+                zstd_dictionary.as_deref(),
+                config.settings.zstd_dictionary.as_deref(),
+            )?;
+
+            if verify_symbols {
+                config.settings.verify_symbols = true;
+            }
+
+            if max_size.is_some() {
+                config.settings.max_size = max_size;
+            }
+
+            if max_file_size.is_some() {
+                config.settings.max_file_size = max_file_size;
+            }
+
+            if max_total_size.is_some() {
+                config.settings.max_total_size = max_total_size;
+            }
+
+            if host_only {
+                config.settings.host_only = true;
+            }
+
+            if modules_from_lsmod {
+                config.settings.modules_from_lsmod = true;
+            }
+
+            if modules_from_file.is_some() {
+                config.settings.modules_from_file = modules_from_file;
+            }
+
+            if debug_sidecar.is_some() {
+                config.settings.debug_sidecar = debug_sidecar;
+            }
+
+            if config.settings.debug_sidecar.is_some() && !config.settings.strip {
+                warn!("Stripping is disabled, debug files are only written for binaries with strip set");
+            }
+
+            if best_effort {
+                config.settings.on_error = config::OnError::BestEffort;
+            }
+
+            if prune_unused_libs {
+                config.settings.prune_unused_libs = true;
+            }
+
+            let limits = SizeLimits {
+                max: config.settings.max_size,
+                warn: config.settings.warn_size,
+            };
+
+            // microcode has to come first for early loading
+            let segments = Segments {
+                prepend: ucode
+                    .into_iter()
+                    .chain(config.prepend.iter().cloned())
+                    .chain(prepend)
+                    .collect(),
+                append: config.append.iter().cloned().chain(append).collect(),
+                stored: Vec::new(),
+            };
+
+            // raw output only holds the generated archive
+            let segments = match format {
+                OutputFormat::Initramfs => segments,
+                OutputFormat::RawZstd | OutputFormat::Dir => {
+                    if !segments.prepend.is_empty() || !segments.append.is_empty() {
+                        warn!("Raw zstd output, not writing prepended or appended archives");
+                    }
+
+                    Segments::default()
+                }
+            };
+
+            // raw zstd output is a single compressed stream
+            let split_incompressible =
+                config.settings.split_incompressible && format == OutputFormat::Initramfs;
+            if config.settings.split_incompressible && format == OutputFormat::RawZstd {
+                warn!("Raw zstd output, not splitting incompressible files");
+            }
+
+            let debug_sidecar = config.settings.debug_sidecar.clone();
+
+            let write = |release: Option<&str>, initramfs: Initramfs| -> Result<()> {
+                let expand = |path: &PathBuf| expand_kernel_placeholder(path, release);
+                let output: Vec<_> = output.iter().map(expand).collect();
+                let emit_cmdline = emit_cmdline.as_ref().map(expand);
+                let uki_section = uki_section.as_ref().map(expand);
+                let measurement_manifest = measurement_manifest.as_ref().map(expand);
+
+                if let Some(path) = emit_cmdline {
+                    let cmdline = initramfs.kernel_cmdline().join(" ");
+
+                    if dry_run {
+                        info!(
+                            "Dry run, kernel command line for {}: {}",
+                            path.display(),
+                            cmdline
+                        );
+                    } else {
+                        info!("Writing kernel command line to: {}", path.display());
+
+                        let mut output = Output::from_path(path)?;
+                        writeln!(output, "{cmdline}")?;
+                    }
+                }
+
+                if let Some(dir) = &debug_sidecar {
+                    write_debug_files(&expand(dir), initramfs.debug_files(), dry_run)?;
+                }
+
+                if format == OutputFormat::Dir {
+                    for path in &output {
+                        write_tree(&initramfs, path, force, dry_run)?;
+                    }
+
+                    write_timings(initramfs.stats(), timings.then_some(timings_format))?;
+                    return Ok(());
+                }
+
+                // raw zstd output is not meant to be loaded by the kernel
+                if format == OutputFormat::Initramfs {
+                    initramfs.check_encoder(&encoder)?;
+                }
+
+                let owners = size_report.map(|_| initramfs.module_owners().clone());
+                let filtered_libraries = initramfs.filtered_libraries().to_vec();
+                let payloads = initramfs.payloads().to_vec();
+                let mut stats = initramfs.stats().clone();
+                let start = Instant::now();
+                let mut archive = initramfs.into_archive();
+                let entries = measurement_manifest
+                    .as_ref()
+                    .map(|_| measurement::entry_digests(&archive));
+                let report = match (size_report, &owners) {
+                    (Some(attribution), Some(owners)) => {
+                        Some(SizeReport::new(&archive, owners, attribution, &encoder)?)
+                    }
+                    _ => None,
+                };
+                let entry_count = archive.entries().len();
+                let segments = match split_incompressible {
+                    true => {
+                        let stored = archive.split_off(|path, entry| {
+                            entry
+                                .data
+                                .as_deref()
+                                .is_some_and(|data| encoder::is_incompressible(path, data))
+                        });
+
+                        debug!(
+                            "Writing {} incompressible files uncompressed",
+                            stored.entries().len()
+                        );
+
+                        let stored = match stored.entries().is_empty() {
+                            true => Vec::new(),
+                            false => stored.serialize()?,
+                        };
+
+                        Segments {
+                            stored,
+                            ..segments.clone()
+                        }
+                    }
+                    false => segments.clone(),
+                };
+                let serialized = archive.serialize()?;
+                let uncompressed = (serialized.len() + segments.stored.len()) as u64;
+                stats.add_phase("serialization", start.elapsed(), entry_count, uncompressed);
+
+                // the section is written along with the other outputs
+                let mut output = output;
+                output.extend(uki_section.iter().cloned());
+                let paths = display_paths(&output);
+
+                info!(
+                    path = paths.as_str(), bytes = serialized.len();
+                    "Writing initramfs to: {}", paths
+                );
+                let sign_key = sign_key.as_ref();
+                let written = write_archive(
+                    &output,
+                    &segments,
+                    &serialized,
+                    &encoder,
+                    WriteOptions {
+                        limits,
+                        sign_key,
+                        measure: entries.is_some(),
+                        dry_run,
+                        sync,
+                    },
+                )?;
+
+                stats.add_phase(
+                    "compression",
+                    written.compression,
+                    0,
+                    written.archive.bytes(),
+                );
+                stats.add_phase("write", written.write, 0, written.size.bytes());
+                write_timings(&stats, timings.then_some(timings_format))?;
+
+                if let Some(path) = uki_section {
+                    write_sidecar(
+                        &size_path(&path),
+                        &format!("{}\n", written.size.bytes()),
+                        dry_run,
+                    )?;
+                }
+
+                if let (Some(path), Some(entries), Some(sha256)) =
+                    (measurement_manifest, entries, written.sha256)
+                {
+                    let manifest = Manifest {
+                        image: ImageDigest {
+                            size: written.size.bytes(),
+                            sha256: measurement::hex(&sha256),
+                        },
+                        entries,
+                    };
+
+                    let json = serde_json::to_string_pretty(&manifest)? + "\n";
+                    write_sidecar(&path, &json, dry_run)?;
+                }
+
+                if print_measurement {
+                    if !config.settings.canonical {
+                        warn!("Archive is not canonical, its measurement depends on host metadata");
+                    }
+
+                    let measured = [serialized.as_slice(), &segments.stored].concat();
+                    writeln!(io::stdout(), "{}", measurement::archive_sha384(&measured))?;
+                }
+
+                if let Some(mut report) = report {
+                    report.filtered_libraries = filtered_libraries;
+                    report.payloads = payloads;
+                    report.set_totals(uncompressed, written.archive.bytes());
+                    report.write(size_report_format, io::stdout())?;
                 }
 
-                if !path.is_dir() {
-                    bail!(ConfigurationError::ExpectedDirectory(path));
+                Ok(())
+            };
+
+            let mut failures = Vec::new();
+            let generate = || -> Result<()> {
+                if kernels.is_empty() {
+                    let platform = Platform::detect(config.settings.kernel_module_path.as_deref())?;
+                    let selected = loader::filter_constraints(selected, &platform);
+
+                    if dry_run {
+                        let plan = BuildPlan::from_config(&config, &selected);
+                        info!("Dry run, build plan:\n{}", plan.to_string().trim_end());
+                    }
+
+                    info!("Generating initramfs");
+                    let initramfs = Initramfs::from_config(&config, &selected)?;
+                    failures.extend(kernel_failures(None, initramfs.failures()));
+                    return write(None, initramfs);
                 }
 
-                for entry in fs::read_dir(&path)? {
-                    let entry = entry?;
-                    let path = entry.path();
+                for (releases, modules) in kernel_groups(&paths, &config, selected, &kernels)? {
+                    info!(
+                        "Generating initramfs content shared by kernels: {}",
+                        releases.join(", ")
+                    );
+                    if dry_run {
+                        let shared = BuildPlan::shared(&config, &modules);
+                        let kernel = BuildPlan::for_kernel(&config, &modules);
+                        info!(
+                            "Dry run, build plan of the shared content:\n{}\nthen for each kernel:\n{}",
+                            shared.to_string().trim_end(),
+                            kernel.to_string().trim_end()
+                        );
+                    }
+
+                    let shared = Initramfs::shared_from_config(&config, &modules)?;
+                    failures.extend(kernel_failures(None, shared.failures()));
 
-                    if path.is_file() {
-                        debug!("Parsing module config file: {:?}", path);
-                        let data = fs::read(path)?;
-                        let module = serde_yaml::from_slice::<config::Module>(&data)?;
+                    for release in &releases {
+                        info!("Generating initramfs for kernel: {}", release);
+                        let path = Path::new(kmod::MODULES_DIR).join(release);
+                        let initramfs = shared.for_kernel(&config, &modules, &path)?;
 
-                        modules.insert(module.name.clone(), module);
+                        // failures of the shared content are reported once
+                        let own = &initramfs.failures()[shared.failures().len()..];
+                        failures.extend(kernel_failures(Some(release), own));
+
+                        write(Some(release), initramfs)?;
                     }
                 }
+
+                Ok(())
+            };
+
+            let result = generate();
+            if let Some(path) = &error_report {
+                let report = ErrorReport::new(&result, &failures);
+                let json = serde_json::to_string_pretty(&report)? + "\n";
+                write_sidecar(path, &json, dry_run)?;
             }
+            result?;
 
-            // check all selected modules are present
-            let mut selected: Vec<config::Module> = Vec::new();
-            for name in &config.modules {
-                let module = modules
-                    .remove(name.as_str())
-                    .context(ConfigurationError::UnknownModule(name.clone()))?;
+            if !failures.is_empty() {
+                warn!(
+                    "Initramfs generated without {} items that could not be added:",
+                    failures.len()
+                );
+                for failure in &failures {
+                    warn!(
+                        "  module {}: {}",
+                        failure.failure.module, failure.failure.item
+                    );
+                }
 
-                selected.push(module)
+                status = Status::Degraded(degraded_exit_code);
             }
+        }
+        Command::Exitrd { modules, output } => {
+            let mut config: config::Initramfs = loader::read_config(&paths.config)?;
 
-            info!("Generating initramfs");
-            let archive = Initramfs::from_config(&config, &selected)?.into_archive();
-            let serialized = archive.serialize()?;
+            // override kernel modules path
+            if let Some(path) = modules {
+                debug!("Overriding kernel module path: {:?}", path);
+                config.settings.kernel_module_path = Some(path);
+            }
 
-            info!("Writing initramfs to: {}", output.display());
-            let output = Output::from_path(output)?;
-            let mut output = BufWriter::new(output);
+            let selected = loader::load_modules(&paths.confdirs, &config.shutdown_modules)?;
+            let platform = Platform::detect(config.settings.kernel_module_path.as_deref())?;
+            let selected = loader::filter_constraints(selected, &platform);
 
-            if let Some(path) = ucode {
-                info!("Adding microcode bundle from: {}", path.display());
+            let encoder = resolve_encoder(encoder, config.settings.encoder.as_ref());
 
-                let read = Input::from_path(path)?;
-                let mut read = BufReader::new(read);
+            info!("Generating exitrd");
+            let exitrd = Initramfs::exitrd_from_config(&config, &selected)?;
 
-                io::copy(&mut read, &mut output)?;
+            if output.is_dir() {
+                info!("Writing exitrd tree to: {}", output.display());
+
+                if dry_run {
+                    info!("Dry run, not writing exitrd tree to: {}", output.display());
+                } else {
+                    exitrd.write_to_dir(&output)?;
+                }
+            } else {
+                let serialized = exitrd.into_archive().serialize()?;
+
+                info!(
+                    path:% = output.display(), bytes = serialized.len();
+                    "Writing exitrd to: {}", output.display()
+                );
+                let output = slice::from_ref(&output);
+                let limits = SizeLimits::default();
+                let segments = Segments::default();
+                write_archive(
+                    output,
+                    &segments,
+                    &serialized,
+                    &encoder,
+                    WriteOptions {
+                        limits,
+                        dry_run,
+                        sync,
+                        ..WriteOptions::default()
+                    },
+                )?;
             }
+        }
+        Command::TrainDictionary {
+            from,
+            output,
+            max_size,
+        } => {
+            let samples = dictionary_samples(&from)?;
+            let max_size = usize::try_from(max_size.bytes())?;
 
-            encoder.encode(&serialized, output)?;
+            info!(
+                samples = samples.len();
+                "Training zstd dictionary from {} samples", samples.len()
+            );
+            let dictionary = encoder::train_dictionary(&samples, max_size)?;
+
+            if dry_run {
+                info!(
+                    bytes = dictionary.len();
+                    "Dry run, not writing dictionary to: {}", output.display()
+                );
+            } else {
+                info!(
+                    bytes = dictionary.len();
+                    "Writing dictionary to: {}", output.display()
+                );
+                fs::write(&output, dictionary)?;
+            }
         }
-        Command::Microcode { output } => {
-            let config: config::Microcode = {
-                if !config_path.exists() || !config_path.is_file() {
-                    bail!(ConfigurationError::ExpectedFile(config_path));
+        Command::MigrateConfig { input, output_dir } => {
+            info!("Migrating legacy configuration: {}", input.display());
+
+            let data = fs::read_to_string(&input)?;
+            let legacy: config::legacy::Config = toml::from_str(&data)?;
+            let migration = legacy.migrate()?;
+
+            let config_path = output_dir.join("elusive.yaml");
+            let module_path = output_dir
+                .join("elusive.d")
+                .join(format!("{}.yaml", config::legacy::MIGRATED_MODULE));
+
+            if dry_run {
+                info!(
+                    "Dry run, not writing migrated configuration to: {}",
+                    output_dir.display()
+                );
+            } else {
+                fs::create_dir_all(&output_dir)?;
+                fs::write(&config_path, migration.config)?;
+                info!("Wrote configuration to: {}", config_path.display());
+
+                if let Some(module) = migration.module {
+                    fs::create_dir_all(module_path.parent().expect("path has a parent"))?;
+                    fs::write(&module_path, module)?;
+                    info!("Wrote module to: {}", module_path.display());
                 }
+            }
+        }
+        Command::Modules { command } => match command {
+            ModulesCommand::List => {
+                let listings = loader::list_modules(&paths.confdirs)?;
+                list_modules(&listings, &mut io::stdout())?;
+            }
+            ModulesCommand::Show { name } => {
+                let mut modules = loader::load_modules(&paths.confdirs, &[name])?;
+                let module = modules.remove(0);
 
-                let data = fs::read(config_path)?;
-                serde_yaml::from_slice(&data)?
+                write!(io::stdout(), "{}", serde_yaml::to_string(&module)?)?;
+            }
+        },
+        Command::Schema { module } => {
+            let schema = if module {
+                config::schema::module()
+            } else {
+                config::schema::config()
             };
 
-            info!("Generating microcode bundle");
-            let archive = MicrocodeBundle::from_config(&config)?.into_archive();
-            let serialized = archive.serialize()?;
+            writeln!(io::stdout(), "{}", serde_json::to_string_pretty(&schema)?)?;
+        }
+        Command::Generate { shell, .. } => {
+            generate(shell, &mut io::stdout())?;
+        }
+        Command::Microcode {
+            output,
+            split_output,
+        } => {
+            let config: config::Microcode = loader::read_config(&paths.config)?;
+
+            // early microcode is only found in an uncompressed archive
+            let encoder = encoder.unwrap_or(Encoder::None);
+            if encoder != Encoder::None {
+                bail!(OutputError::CompressedMicrocode(encoder));
+            }
+
+            if !output.is_empty() {
+                info!("Generating microcode bundle");
+                let archive = MicrocodeBundle::from_config(&config)?.into_archive();
+                write_microcode(&output, &archive.serialize()?, &encoder, dry_run, sync)?;
+            }
+
+            if let Some(dir) = split_output {
+                if !dry_run {
+                    fs::create_dir_all(&dir)?;
+                }
+
+                for (name, bundle) in MicrocodeBundle::split_from_config(&config)? {
+                    info!("Generating standalone microcode bundle: {}", name);
+                    let serialized = bundle.into_archive().serialize()?;
+                    write_microcode(&[dir.join(name)], &serialized, &encoder, dry_run, sync)?;
+                }
+            }
+        }
+        Command::Explain { modules, pattern } => {
+            let (mut config, selected) = loader::load_initramfs_config(&paths)?;
+
+            // override kernel modules path
+            if let Some(path) = modules {
+                debug!("Overriding kernel module path: {:?}", path);
+                config.settings.kernel_module_path = Some(path);
+            }
+
+            let platform = Platform::detect(config.settings.kernel_module_path.as_deref())?;
+            let selected = loader::filter_constraints(selected, &platform);
+
+            info!("Generating initramfs");
+            let initramfs = Initramfs::from_config(&config, &selected)?;
+
+            explain(&initramfs, &pattern, &mut io::stdout())?;
+        }
+        Command::UnusedLibs { modules } => {
+            let (mut config, selected) = loader::load_initramfs_config(&paths)?;
+
+            // override kernel modules path
+            if let Some(path) = modules {
+                debug!("Overriding kernel module path: {:?}", path);
+                config.settings.kernel_module_path = Some(path);
+            }
+
+            let platform = Platform::detect(config.settings.kernel_module_path.as_deref())?;
+            let selected = loader::filter_constraints(selected, &platform);
 
-            info!("Writing microcode cpio to: {}", output.display());
-            let output = Output::from_path(output)?;
-            let output = BufWriter::new(output);
+            info!("Generating initramfs");
+            let initramfs = Initramfs::from_config(&config, &selected)?;
+
+            let mut out = io::stdout();
+            for path in initramfs.unused_libraries() {
+                writeln!(out, "{}", escaped(&path))?;
+            }
+        }
+        Command::Verify { input, sig, pubkey } => {
+            let sig = sig.unwrap_or_else(|| signing::signature_path(&input));
+            let key = signing::read_verifying_key(&pubkey)?;
+            let signature = signing::read_signature(&sig)?;
 
-            encoder.encode(&serialized, output)?;
+            info!(
+                "Verifying {} with signature: {}",
+                input.display(),
+                sig.display()
+            );
+            signing::verify(&key, BufReader::new(Input::from_path(&input)?), &signature)?;
+            info!("Signature is valid: {}", input.display());
         }
     }
 
+    Ok(status)
+}
+
+/// Write shell completions for the given shell, or the man page when no
+/// shell is given.
+fn generate<W>(shell: Option<Shell>, out: &mut W) -> Result<()>
+where
+    W: Write,
+{
+    let mut command = Args::command();
+    let mut buffer = Vec::new();
+
+    // clap_complete panics on write errors, render in memory first
+    match shell {
+        Some(shell) => clap_complete::generate(shell, &mut command, "elusive", &mut buffer),
+        None => clap_mangen::Man::new(command).render(&mut buffer)?,
+    }
+
+    out.write_all(&buffer)?;
+
     Ok(())
 }
+
+/// Write a line per module with a summary of its content and the file defining
+/// it, followed by the definitions it replaces.
+fn list_modules<W>(listings: &[loader::Listing], out: &mut W) -> Result<()>
+where
+    W: Write,
+{
+    for listing in listings {
+        let summary = match &listing.module {
+            Ok(module) => module.summary(),
+            Err(err) => format!("invalid: {err}"),
+        };
+
+        writeln!(
+            out,
+            "{}\t{}\t{}",
+            listing.name,
+            summary,
+            listing.path.display()
+        )?;
+
+        for replaced in &listing.replaced {
+            writeln!(out, "  duplicate, replaced: {}", replaced.display())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the chains of reasons that caused entries matching the pattern to be
+/// included, failing when nothing matches.
+fn explain<W>(initramfs: &Initramfs, pattern: &str, out: &mut W) -> Result<()>
+where
+    W: Write,
+{
+    let explained = initramfs.explain(&Pattern::new(pattern)?);
+
+    if explained.is_empty() {
+        bail!(ExplainError::NoMatch(pattern.to_string()));
+    }
+
+    for (path, chains) in explained {
+        writeln!(out, "{}", escaped(&path))?;
+
+        if chains.is_empty() {
+            writeln!(out, "  parent directory of included entries")?;
+        }
+
+        for chain in chains {
+            writeln!(out, "  {chain}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the encoder to use: the `--encoder` flag takes precedence over the
+/// configured encoder, then zstd is used.
+fn resolve_encoder(flag: Option<Encoder>, configured: Option<&Encoder>) -> Encoder {
+    let encoder = flag
+        .or_else(|| configured.cloned())
+        .unwrap_or(Encoder::Zstd);
+    debug!("Using encoder: {}", encoder);
+
+    encoder
+}
+
+/// Get the encoder for the output format. Dictionaries are refused for
+/// initramfs output since the kernel cannot decompress frames using one, the
+/// configured dictionary is only used for raw zstd output.
+fn output_encoder(
+    format: OutputFormat,
+    encoder: Encoder,
+    dictionary: Option<&Path>,
+    configured: Option<&Path>,
+) -> Result<Encoder> {
+    match format {
+        OutputFormat::Initramfs | OutputFormat::Dir => {
+            if dictionary.is_some() {
+                bail!(OutputError::DictionaryFormat);
+            }
+
+            if let Some(path) = configured {
+                debug!("Ignoring zstd dictionary for initramfs output: {:?}", path);
+            }
+
+            Ok(encoder)
+        }
+        OutputFormat::RawZstd => match dictionary.or(configured) {
+            Some(path) => {
+                info!("Using zstd dictionary: {}", path.display());
+                Ok(Encoder::ZstdDictionary(fs::read(path)?))
+            }
+            None => Ok(Encoder::Zstd),
+        },
+    }
+}
+
+/// Write the tree of an initramfs to a directory, which has to be empty
+/// unless `force` is set.
+fn write_tree(initramfs: &Initramfs, path: &Path, force: bool, dry_run: bool) -> Result<()> {
+    let non_empty = match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(err.into()),
+    };
+
+    if non_empty && !force {
+        bail!(OutputError::NotEmpty(path.to_path_buf()));
+    }
+
+    if dry_run {
+        info!("Dry run, not writing initramfs tree to: {}", path.display());
+    } else {
+        info!("Writing initramfs tree to: {}", path.display());
+        initramfs.write_to_dir(path)?;
+    }
+
+    Ok(())
+}
+
+/// Write the debug files of stripped ELF files under a directory, mirroring
+/// their path in the initramfs, e.g. `<dir>/usr/bin/ls.debug`.
+fn write_debug_files(dir: &Path, files: &BTreeMap<PathBuf, Vec<u8>>, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!(
+            "Dry run, not writing {} debug files to: {}",
+            files.len(),
+            dir.display()
+        );
+        return Ok(());
+    }
+
+    info!("Writing {} debug files to: {}", files.len(), dir.display());
+
+    for (path, data) in files {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".debug");
+
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let path = dir.join(relative).with_file_name(name);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, data)?;
+    }
+
+    Ok(())
+}
+
+/// Collect the content of regular files to train a dictionary from, either
+/// from entries of images (possibly compressed) or from directory trees.
+fn dictionary_samples(paths: &[PathBuf]) -> Result<Vec<Vec<u8>>> {
+    let mut samples = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            debug!("Sampling files from directory: {:?}", path);
+
+            for entry in WalkDir::new(path) {
+                let entry = entry?;
+
+                if entry.file_type().is_file() {
+                    samples.push(fs::read(entry.path())?);
+                }
+            }
+
+            continue;
+        }
+
+        debug!("Sampling entries from image: {:?}", path);
+        let data = encoder::decode(&fs::read(path)?)?;
+        let archive = newc::Archive::deserialize(&data)
+            .with_context(|| format!("failed to read image: {}", path.display()))?;
+
+        let files = archive
+            .entries()
+            .iter()
+            .filter(|(_, entry)| entry.is_file())
+            .filter_map(|(_, entry)| entry.data.clone());
+        samples.extend(files);
+    }
+
+    samples.retain(|sample| !sample.is_empty());
+    Ok(samples)
+}
+
+/// Size thresholds checked against the final output.
+#[derive(Clone, Copy, Default, Debug)]
+struct SizeLimits {
+    /// Fail and remove written files above this size.
+    max: Option<Size>,
+    /// Log a warning above this size.
+    warn: Option<Size>,
+}
+
+/// External archives written around the main compressed archive.
+#[derive(Clone, Default, Debug)]
+struct Segments {
+    /// Uncompressed cpio archives (e.g. a microcode bundle) written first.
+    prepend: Vec<PathBuf>,
+    /// Serialized cpio archive written uncompressed right after the main
+    /// archive, see settings.split_incompressible.
+    stored: Vec<u8>,
+    /// Cpio archives, possibly compressed, written last.
+    append: Vec<PathBuf>,
+}
+
+/// How [`write_archive`] writes an image.
+#[derive(Clone, Copy, Default)]
+struct WriteOptions<'a> {
+    /// Size thresholds checked against the final output.
+    limits: SizeLimits,
+    /// Hash the output while written and write the same detached signature
+    /// next to every path.
+    sign_key: Option<&'a SigningKey>,
+    /// Compute the SHA-256 digest of the output in the same pass.
+    measure: bool,
+    /// Write everything to a sink that only counts bytes, and report the
+    /// would-be output.
+    dry_run: bool,
+    /// Sync written files to disk before returning.
+    sync: bool,
+}
+
+fn write_microcode(
+    output: &[PathBuf],
+    serialized: &[u8],
+    encoder: &Encoder,
+    dry_run: bool,
+    sync: bool,
+) -> Result<()> {
+    let paths = display_paths(output);
+
+    info!(
+        path = paths.as_str(), bytes = serialized.len();
+        "Writing microcode cpio to: {}", paths
+    );
+    let limits = SizeLimits::default();
+    let segments = Segments::default();
+    write_archive(
+        output,
+        &segments,
+        serialized,
+        encoder,
+        WriteOptions {
+            limits,
+            dry_run,
+            sync,
+            ..WriteOptions::default()
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Compress and write a serialized archive to the provided paths, along with
+/// external segments. Data is only compressed once and duplicated to every
+/// path. Segments are kept aligned to 4 bytes, as expected by the kernel.
+/// Files are removed when anything fails.
+fn write_archive(
+    paths: &[PathBuf],
+    segments: &Segments,
+    data: &[u8],
+    encoder: &Encoder,
+    options: WriteOptions,
+) -> Result<Written> {
+    let WriteOptions {
+        limits,
+        sign_key,
+        measure,
+        dry_run,
+        sync,
+    } = options;
+
+    if sign_key.is_some() && paths.iter().any(|path| path == Path::new("-")) {
+        bail!(OutputError::SignStdout);
+    }
+
+    let output = if dry_run {
+        Output::sink()
+    } else {
+        Output::multi(paths)?
+    };
+
+    let output = match sign_key {
+        Some(_) => DigestWriter::new(output),
+        None => DigestWriter::disabled(output),
+    };
+
+    let output = if measure {
+        DigestWriter::<_, Sha256>::new(output)
+    } else {
+        DigestWriter::disabled(output)
+    };
+
+    let mut output = CountingWriter::new(BufWriter::new(output));
+
+    let mut archive = 0;
+    let mut compression = Duration::ZERO;
+    let start = Instant::now();
+    let mut write = || -> Result<()> {
+        for path in &segments.prepend {
+            info!(path:% = path.display(); "Prepending archive from: {}", path.display());
+            write_segment(&mut output, path, false)?;
+        }
+
+        // time spent below the encoder is writing, not compressing
+        let offset = output.count();
+        let encoding = Instant::now();
+        let mut timed = TimedWriter::new(&mut output);
+        encoder.encode(data, &mut timed)?;
+        compression = encoding.elapsed().saturating_sub(timed.elapsed());
+
+        if !segments.stored.is_empty() {
+            align_segment(&mut output)?;
+            output.write_all(&segments.stored)?;
+        }
+
+        archive = output.count() - offset;
+
+        for path in &segments.append {
+            info!(path:% = path.display(); "Appending archive from: {}", path.display());
+            write_segment(&mut output, path, true)?;
+        }
+
+        Ok(())
+    };
+
+    // what is still buffered never reached the output
+    if let Err(err) = write() {
+        let written = output.count() - output.get_mut().buffer().len() as u64;
+        output.get_mut().get_mut().get_mut().get_mut().discard();
+
+        return match err.is::<OutputError>() {
+            true => Err(err),
+            false => Err(err.context(OutputError::Truncated { written })),
+        };
+    }
+
+    let size = Size(output.count());
+    let output = output.into_inner().into_inner().map_err(|err| {
+        let (source, mut output) = err.into_parts();
+        let written = size.bytes() - output.buffer().len() as u64;
+        output.get_mut().get_mut().get_mut().discard();
+
+        anyhow::Error::new(source).context(OutputError::Truncated { written })
+    })?;
+    let (output, sha256) = output.into_parts();
+    let (mut output, digest) = output.into_parts();
+    let writing = start.elapsed().saturating_sub(compression);
+
+    if let Some(budget) = limits.max.filter(|max| size > *max) {
+        output.discard();
+        bail!(OutputError::TooLarge {
+            actual: size,
+            budget,
+        });
+    }
+
+    // files are complete on disk before anything points at them
+    if sync {
+        output.sync().map_err(OutputError::Sync)?;
+    }
+
+    // previous files are only replaced by a complete output
+    output.commit().map_err(OutputError::Commit)?;
+
+    if let Some(threshold) = limits.warn.filter(|warn| size > *warn) {
+        warn!(
+            bytes = size.bytes(), threshold = threshold.bytes();
+            "Output size {} exceeds the warning threshold of {}", size, threshold
+        );
+    }
+
+    if let (Some(key), Some(digest)) = (sign_key, digest) {
+        let signature = signing::sign(key, digest);
+
+        for path in paths {
+            let path = signing::signature_path(path);
+
+            if dry_run {
+                info!("Dry run, not writing signature to: {}", path.display());
+            } else {
+                info!("Writing signature to: {}", path.display());
+                fs::write(&path, signature.to_bytes())?;
+            }
+        }
+    }
+
+    if let Some(bytes) = output.bytes_written() {
+        let paths = display_paths(paths);
+
+        info!(
+            path = paths.as_str(), bytes = bytes, uncompressed = data.len();
+            "Dry run, would write {} bytes ({} uncompressed) to: {}",
+            bytes, data.len(), paths
+        );
+    }
+
+    Ok(Written {
+        size,
+        archive: Size(archive),
+        sha256: sha256.map(|digest| digest.finalize().into()),
+        compression,
+        write: writing,
+    })
+}
+
+/// Result of [`write_archive`].
+struct Written {
+    /// Size of everything written.
+    size: Size,
+    /// Size of the compressed archive alone, with its uncompressed segment but
+    /// without prepended or appended segments.
+    archive: Size,
+    /// SHA-256 digest of everything written, if measured.
+    sha256: Option<[u8; 32]>,
+    /// Time spent compressing the archive.
+    compression: Duration,
+    /// Time spent writing, digests and segments included.
+    write: Duration,
+}
+
+/// An item left out in best effort mode, for the error report.
+#[derive(serde::Serialize, Debug)]
+struct KernelFailure {
+    /// Kernel release of the image, when generating for several kernels and
+    /// the item is not part of the content they share.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel: Option<String>,
+    #[serde(flatten)]
+    failure: Failure,
+}
+
+fn kernel_failures(release: Option<&str>, failures: &[Failure]) -> Vec<KernelFailure> {
+    failures
+        .iter()
+        .map(|failure| KernelFailure {
+            kernel: release.map(String::from),
+            failure: failure.clone(),
+        })
+        .collect()
+}
+
+/// Report written with --error-report.
+#[derive(serde::Serialize, Debug)]
+struct ErrorReport<'a> {
+    /// Either `complete`, `degraded` or `failed`.
+    status: &'static str,
+    /// Error that failed the generation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Items left out in best effort mode.
+    failures: &'a [KernelFailure],
+}
+
+impl<'a> ErrorReport<'a> {
+    fn new(result: &Result<()>, failures: &'a [KernelFailure]) -> Self {
+        let status = match result {
+            Err(_) => "failed",
+            Ok(()) if failures.is_empty() => "complete",
+            Ok(()) => "degraded",
+        };
+
+        ErrorReport {
+            status,
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+            failures,
+        }
+    }
+}
+
+/// Get the path of the size file written next to a UKI section.
+fn size_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".size");
+
+    PathBuf::from(path)
+}
+
+/// Write a small file describing the output, unless running dry.
+fn write_sidecar(path: &Path, content: &str, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("Dry run, not writing: {}", path.display());
+    } else {
+        info!("Writing: {}", path.display());
+        fs::write(path, content)?;
+    }
+
+    Ok(())
+}
+
+/// Copy an external cpio archive to the output, after padding the output to a
+/// 4 byte boundary. Compressed archives are only accepted when `compressed` is
+/// set.
+fn write_segment<W>(output: &mut CountingWriter<W>, path: &Path, compressed: bool) -> Result<()>
+where
+    W: Write,
+{
+    let mut read = BufReader::new(Input::from_path(path)?);
+    let head = read.fill_buf()?;
+
+    let is_cpio = head.starts_with(newc::MAGIC) || head.starts_with(newc::MAGIC_CRC);
+    let valid = is_cpio || (compressed && encoder::is_compressed(head));
+
+    if !valid {
+        bail!(OutputError::InvalidSegment(path.to_path_buf()));
+    }
+
+    align_segment(output)?;
+    io::copy(&mut read, output)?;
+    Ok(())
+}
+
+/// Pad the output to 4 bytes before the next segment.
+fn align_segment<W>(output: &mut CountingWriter<W>) -> Result<()>
+where
+    W: Write,
+{
+    // the kernel skips zero padding between segments
+    let padding = (4 - output.count() % 4) % 4;
+    output.write_all(&[0; 3][..padding as usize])?;
+
+    Ok(())
+}
+
+/// Print build timings in the requested format, or log them as a table at
+/// debug level.
+fn write_timings(stats: &BuildStats, format: Option<ReportFormat>) -> Result<()> {
+    if let Some(format) = format {
+        stats.write(format, io::stdout())?;
+    } else if log::log_enabled!(log::Level::Debug) {
+        let mut table = Vec::new();
+        stats.write(ReportFormat::Table, &mut table)?;
+
+        for line in String::from_utf8_lossy(&table).lines() {
+            debug!("{}", line);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check if a path contains the kernel release placeholder.
+fn has_kernel_placeholder(path: &Path) -> bool {
+    path.as_os_str()
+        .as_bytes()
+        .windows(KERNEL_PLACEHOLDER.len())
+        .any(|window| window == KERNEL_PLACEHOLDER.as_bytes())
+}
+
+/// Replace the kernel release placeholder in a path, if a release is given.
+fn expand_kernel_placeholder(path: &Path, release: Option<&str>) -> PathBuf {
+    let Some(release) = release else {
+        return path.to_path_buf();
+    };
+
+    let mut rest = path.as_os_str().as_bytes();
+    let mut expanded = Vec::with_capacity(rest.len());
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix(KERNEL_PLACEHOLDER.as_bytes()) {
+            expanded.extend_from_slice(release.as_bytes());
+            rest = after;
+        } else {
+            expanded.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+
+    PathBuf::from(OsString::from_vec(expanded))
+}
+
+/// Group kernels selecting the same configuration modules, given their
+/// constraints, with the modules selected for each group. Each group shares
+/// the content that does not depend on the kernel.
+fn kernel_groups(
+    paths: &ConfigPaths,
+    config: &config::Initramfs,
+    selected: Vec<config::Module>,
+    kernels: &[String],
+) -> Result<Vec<(Vec<String>, Vec<config::Module>)>> {
+    let mut groups: Vec<(Vec<String>, Vec<String>, Platform)> = Vec::new();
+
+    for release in kernels {
+        let dir = Path::new(kmod::MODULES_DIR).join(release);
+        if !dir.join("kernel").is_dir() {
+            bail!(OutputError::UnknownKernel(release.clone()));
+        }
+
+        let platform = Platform::detect(Some(&dir))?;
+        let names: Vec<_> = selected
+            .iter()
+            .filter(|module| module.constraints.check(&platform).is_ok())
+            .map(|module| module.name.clone())
+            .collect();
+
+        match groups.iter_mut().find(|(group, _, _)| *group == names) {
+            Some((_, releases, _)) => releases.push(release.clone()),
+            None => groups.push((names, vec![release.clone()], platform)),
+        }
+    }
+
+    // modules are moved into the first group, and parsed again for others
+    let mut selected = Some(selected);
+    groups
+        .into_iter()
+        .map(|(_, releases, platform)| {
+            let modules = match selected.take() {
+                Some(modules) => modules,
+                None => loader::load_modules(&paths.confdirs, &config.modules)?,
+            };
+
+            Ok((releases, loader::filter_constraints(modules, &platform)))
+        })
+        .collect()
+}
+
+/// Join paths for display in log messages.
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<_> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    paths.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::newc::Archive;
+    use crate::paths::ImagePath;
+    use crate::vfs::Entry;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_measure() {
+        let data = Archive::from([(PathBuf::from("/test"), Entry::file(b"data".to_vec()))])
+            .serialize()
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let paths = [dir.join("initramfs.img"), dir.join("initrd.section")];
+        let written = write_archive(
+            &paths,
+            &Segments::default(),
+            &data,
+            &Encoder::Zstd,
+            WriteOptions {
+                measure: true,
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+
+        let image = fs::read(&paths[0]).unwrap();
+        let section = fs::read(&paths[1]).unwrap();
+
+        assert_eq!(image, section);
+        assert_eq!(written.size.bytes(), section.len() as u64);
+        assert_eq!(written.sha256, Some(Sha256::digest(&section).into()));
+        assert_eq!(
+            size_path(&paths[1]),
+            dir.join("initrd.section.size"),
+            "size file is next to the section"
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let data = Archive::from([(PathBuf::from("/test"), Entry::file(b"data".to_vec()))])
+            .serialize()
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let dry = dir.join("dry.img");
+        let real = dir.join("real.img");
+
+        let limits = SizeLimits::default();
+        let output = write_archive(
+            slice::from_ref(&dry),
+            &Segments::default(),
+            &data,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                dry_run: true,
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+        write_archive(
+            slice::from_ref(&real),
+            &Segments::default(),
+            &data,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+
+        let dry_exists = dry.exists();
+        let real_len = fs::metadata(&real).unwrap().len();
+
+        assert!(!dry_exists);
+        assert_eq!(output.size.bytes(), real_len);
+    }
+
+    #[test]
+    fn test_short_write() {
+        let small = Archive::from([(PathBuf::from("/test"), Entry::file(b"data".to_vec()))])
+            .serialize()
+            .unwrap();
+        let large = Archive::from([(PathBuf::from("/test"), Entry::file(vec![0x55; 64 << 10]))])
+            .serialize()
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        // writes to /dev/full fail with ENOSPC
+        let paths = [dir.join("initramfs.img"), PathBuf::from("/dev/full")];
+        let write = |data: &[u8]| {
+            write_archive(
+                &paths,
+                &Segments::default(),
+                data,
+                &Encoder::None,
+                WriteOptions {
+                    sync: true,
+                    ..WriteOptions::default()
+                },
+            )
+            .err()
+            .expect("writing to /dev/full fails")
+        };
+
+        // buffered until the end of the archive
+        let flush = write(&small);
+        let flush_removed = !paths[0].exists();
+
+        // written while the archive is encoded
+        let encode = write(&large);
+        let encode_removed = !paths[0].exists();
+
+        assert_eq!(error_code(&flush), "output_truncated");
+        assert_eq!(
+            format!("{flush:#}"),
+            format!(
+                "output truncated after 0 bytes: \
+                 cannot finish encoding: {len} of {len} input bytes encoded, \
+                 {len} encoded bytes handed to the writer: \
+                 No space left on device (os error 28)",
+                len = small.len()
+            )
+        );
+        assert!(flush_removed);
+
+        let Some(OutputError::Truncated { written }) = encode.downcast_ref() else {
+            panic!("unexpected error: {encode:#}");
+        };
+        let Some(EncoderError::ShortWrite { consumed, .. }) = encode.downcast_ref() else {
+            panic!("unexpected error: {encode:#}");
+        };
+        assert_eq!(*written, 0);
+        assert!(*consumed < large.len() as u64);
+        assert!(encode_removed);
+    }
+
+    #[test]
+    fn test_sign() {
+        let data = Archive::from([(PathBuf::from("/test"), Entry::file(b"data".to_vec()))])
+            .serialize()
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let paths = [dir.join("first.img"), dir.join("second.img")];
+        let key = SigningKey::from_bytes(&[7; 32]);
+
+        let limits = SizeLimits::default();
+        write_archive(
+            &paths,
+            &Segments::default(),
+            &data,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                sign_key: Some(&key),
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+
+        let stdout = write_archive(
+            &[PathBuf::from("-")],
+            &Segments::default(),
+            &data,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                sign_key: Some(&key),
+                sync: true,
+                ..WriteOptions::default()
+            },
+        );
+
+        let verified: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let signature = signing::read_signature(signing::signature_path(path)).unwrap();
+                let image = fs::read(path).unwrap();
+                signing::verify(&key.verifying_key(), image.as_slice(), &signature).is_ok()
+            })
+            .collect();
+
+        assert_eq!(verified, [true, true]);
+
+        let Err(err) = stdout else {
+            panic!("signed standard output");
+        };
+        assert_eq!(error_code(&err), "output_sign_stdout");
+    }
+
+    #[test]
+    fn test_raw_zstd() {
+        let data = Archive::from([(PathBuf::from("/test"), Entry::file(b"data".to_vec()))])
+            .serialize()
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        // raw content dictionaries are accepted by zstd as well
+        let dictionary = dir.join("dict.bin");
+        fs::write(&dictionary, b"070701 /test data TRAILER!!!".repeat(16)).unwrap();
+
+        let refused = output_encoder(
+            OutputFormat::Initramfs,
+            Encoder::Zstd,
+            Some(&dictionary),
+            None,
+        );
+        let ignored = output_encoder(
+            OutputFormat::Initramfs,
+            Encoder::Gzip,
+            None,
+            Some(&dictionary),
+        );
+        let encoder = output_encoder(
+            OutputFormat::RawZstd,
+            Encoder::Gzip,
+            None,
+            Some(&dictionary),
+        );
+
+        let path = dir.join("cache.zst");
+        let encoder = encoder.unwrap();
+        write_archive(
+            slice::from_ref(&path),
+            &Segments::default(),
+            &data,
+            &encoder,
+            WriteOptions {
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+
+        let cached = fs::read(&path).unwrap();
+        let samples = dictionary_samples(&[dir.to_path_buf()]);
+
+        let Err(err) = refused else {
+            panic!("dictionary accepted for initramfs output");
+        };
+        assert_eq!(error_code(&err), "output_dictionary_format");
+        assert_eq!(ignored.unwrap(), Encoder::Gzip);
+
+        // the cache can only be read back with the dictionary
+        let Encoder::ZstdDictionary(dictionary) = encoder else {
+            panic!("dictionary not used for raw output");
+        };
+        let mut decoder = zstd::Decoder::with_dictionary(cached.as_slice(), &dictionary).unwrap();
+        let mut decoded = Vec::new();
+        io::Read::read_to_end(&mut decoder, &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        // samples from a directory tree include every file
+        assert_eq!(samples.unwrap().len(), 2);
+
+        let archive = dir.with_extension("img");
+        fs::write(&archive, &cached).unwrap();
+        let samples = dictionary_samples(slice::from_ref(&archive));
+        fs::remove_file(&archive).unwrap();
+        assert!(samples.is_err());
+
+        let plain = dir.with_extension("cpio");
+        fs::write(&plain, &data).unwrap();
+        let samples = dictionary_samples(slice::from_ref(&plain));
+        fs::remove_file(&plain).unwrap();
+        assert_eq!(samples.unwrap(), [b"data".to_vec()]);
+    }
+
+    #[test]
+    fn test_resolve_encoder() {
+        let configured = Some(&Encoder::Gzip);
+
+        assert_eq!(resolve_encoder(None, None), Encoder::Zstd);
+        assert_eq!(resolve_encoder(None, configured), Encoder::Gzip);
+        assert_eq!(resolve_encoder(Some(Encoder::None), None), Encoder::None);
+        assert_eq!(
+            resolve_encoder(Some(Encoder::Zstd), configured),
+            Encoder::Zstd
+        );
+
+        let settings: config::Settings = serde_yaml::from_str("encoder: gzip").unwrap();
+        assert_eq!(settings.encoder, Some(Encoder::Gzip));
+        assert!(serde_yaml::from_str::<config::Settings>("encoder: lz4").is_err());
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let tree = dir.join("tree");
+
+        let run = |extra: &[&str]| {
+            let mut args = vec!["elusive", "initramfs", "--output-format", "dir", "-o"];
+            args.push(tree.to_str().unwrap());
+            args.extend(extra);
+
+            elusive(Args::try_parse_from(args).unwrap()).unwrap_err()
+        };
+
+        let encoder = run(&["-e", "gzip"]);
+        let ucode = run(&["--ucode", "/tmp/ucode.img"]);
+
+        let initramfs = Initramfs::new().unwrap();
+        let first = write_tree(&initramfs, &tree, false, false);
+        let second = write_tree(&initramfs, &tree, false, false);
+        let forced = write_tree(&initramfs, &tree, true, false);
+        let bin = fs::read_link(tree.join("bin"));
+
+        assert_eq!(error_code(&encoder), "output_dir_option");
+        assert!(encoder.to_string().contains("--encoder"), "{encoder}");
+        assert!(ucode.to_string().contains("--ucode"), "{ucode}");
+
+        first.unwrap();
+        forced.unwrap();
+        assert_eq!(bin.unwrap(), Path::new("usr/bin"));
+
+        let Err(err) = second else {
+            panic!("wrote into a non-empty directory");
+        };
+        assert_eq!(error_code(&err), "output_not_empty");
+    }
+
+    #[test]
+    fn test_init_override() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let confdir = dir.join("elusive.d");
+        let tree = dir.join("tree");
+        fs::create_dir_all(&confdir).unwrap();
+
+        let configured = dir.join("configured-init");
+        let flag = dir.join("flag-init");
+        fs::write(&configured, "#!/bin/sh\necho configured\n").unwrap();
+        fs::write(&flag, "#!/bin/sh\necho flag\n").unwrap();
+
+        let config = dir.join("elusive.yaml");
+        fs::write(
+            &config,
+            format!("init: {}\nmodules: []\n", configured.display()),
+        )
+        .unwrap();
+
+        let args = [
+            "elusive",
+            "--skip-default-paths",
+            "-c",
+            config.to_str().unwrap(),
+            "-C",
+            confdir.to_str().unwrap(),
+            "initramfs",
+            "--output-format",
+            "dir",
+            "--init",
+            flag.to_str().unwrap(),
+            "-o",
+            tree.to_str().unwrap(),
+        ];
+        let result = elusive(Args::try_parse_from(args).unwrap());
+        let init = fs::read_to_string(tree.join("init"));
+
+        result.unwrap();
+        assert_eq!(init.unwrap(), "#!/bin/sh\necho flag\n");
+    }
+
+    #[test]
+    fn test_microcode_prepend() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let confdir = dir.join("elusive.d");
+        fs::create_dir_all(dir.join("amd-ucode")).unwrap();
+        fs::create_dir_all(&confdir).unwrap();
+        fs::write(dir.join("amd-ucode/family_17h.bin"), b"ucode").unwrap();
+
+        let init = dir.join("init");
+        fs::write(&init, "#!/bin/sh\n").unwrap();
+
+        let config = dir.join("elusive.yaml");
+        fs::write(
+            &config,
+            format!(
+                "amd_ucode: {}\ninit: {}\nmodules: []\n",
+                dir.join("amd-ucode").display(),
+                init.display()
+            ),
+        )
+        .unwrap();
+
+        let ucode = dir.join("ucode.img");
+        let image = dir.join("initramfs.img");
+        let run = |args: &[&str]| {
+            let mut all = vec![
+                "elusive",
+                "--skip-default-paths",
+                "-c",
+                config.to_str().unwrap(),
+                "-C",
+                confdir.to_str().unwrap(),
+            ];
+            all.extend(args);
+
+            elusive(Args::try_parse_from(all).unwrap())
+        };
+
+        let compressed = run(&["-e", "zstd", "microcode", "-o", ucode.to_str().unwrap()]);
+        let microcode = run(&["microcode", "-o", ucode.to_str().unwrap()]);
+        let initramfs = run(&[
+            "initramfs",
+            "--ucode",
+            ucode.to_str().unwrap(),
+            "-o",
+            image.to_str().unwrap(),
+        ]);
+        let data = fs::read(&image).unwrap();
+        let prepended = Archive::deserialize(&data).unwrap();
+
+        assert_eq!(
+            error_code(&compressed.unwrap_err()),
+            "output_compressed_microcode"
+        );
+        microcode.unwrap();
+        initramfs.unwrap();
+        assert!(data.starts_with(b"070701"));
+        let blob = prepended
+            .entries()
+            .iter()
+            .find(|(path, _)| path.ends_with("kernel/x86/microcode/AuthenticAMD.bin"));
+        assert_eq!(blob.unwrap().1.data.as_deref(), Some(&b"ucode"[..]));
+    }
+
+    #[test]
+    fn test_debug_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let files = BTreeMap::from([
+            (PathBuf::from("/usr/bin/ls"), b"ls".to_vec()),
+            (PathBuf::from("/usr/lib/libc.so.6"), b"libc".to_vec()),
+        ]);
+
+        write_debug_files(dir, &files, true).unwrap();
+        assert!(!dir.join("usr").exists());
+
+        write_debug_files(dir, &files, false).unwrap();
+        assert_eq!(fs::read(dir.join("usr/bin/ls.debug")).unwrap(), b"ls");
+        assert_eq!(
+            fs::read(dir.join("usr/lib/libc.so.6.debug")).unwrap(),
+            b"libc"
+        );
+    }
+
+    #[test]
+    fn test_max_size() {
+        let data = Archive::from([(PathBuf::from("/test"), Entry::file(b"data".to_vec()))])
+            .serialize()
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let path = dir.join("initramfs.img");
+        let limits = SizeLimits {
+            max: Some(Size(16)),
+            warn: None,
+        };
+
+        let result = write_archive(
+            slice::from_ref(&path),
+            &Segments::default(),
+            &data,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                sync: true,
+                ..WriteOptions::default()
+            },
+        );
+        let exists = path.exists();
+
+        assert!(!exists);
+
+        let Err(err) = result else {
+            panic!("output should exceed the maximum size");
+        };
+
+        let message = err.to_string();
+        let Ok(OutputError::TooLarge { actual, budget }) = err.downcast() else {
+            panic!("unexpected error: {message}");
+        };
+
+        assert_eq!(budget, Size(16));
+        assert!(actual > budget);
+        assert!(
+            message.contains(&format!("({} bytes)", actual.bytes())),
+            "{message}"
+        );
+        assert!(message.contains("(16 bytes)"), "{message}");
+    }
+
+    #[test]
+    fn test_segments() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let archive = |name: &str| {
+            let path = PathBuf::from("/").join(name);
+            Archive::from([(path, Entry::file(name.as_bytes().to_vec()))])
+        };
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let first = archive("first").serialize().unwrap();
+        let second = archive("second").serialize().unwrap();
+        let last = archive("last").serialize().unwrap();
+        let main = archive("main").serialize().unwrap();
+
+        fs::write(dir.join("first.cpio"), &first).unwrap();
+        fs::write(dir.join("second.cpio"), &second).unwrap();
+        fs::write(dir.join("last.cpio"), &last).unwrap();
+        fs::write(dir.join("garbage"), b"garbage").unwrap();
+
+        let output = dir.join("initramfs.img");
+        let segments = Segments {
+            prepend: vec![dir.join("first.cpio"), dir.join("second.cpio")],
+            append: vec![dir.join("last.cpio")],
+            stored: Vec::new(),
+        };
+
+        let limits = SizeLimits::default();
+        write_archive(
+            slice::from_ref(&output),
+            &segments,
+            &main,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+
+        let invalid = Segments {
+            prepend: vec![dir.join("garbage")],
+            ..Segments::default()
+        };
+
+        let partial = dir.join("partial.img");
+        let result = write_archive(
+            slice::from_ref(&partial),
+            &invalid,
+            &main,
+            &Encoder::Gzip,
+            WriteOptions {
+                limits,
+                sync: true,
+                ..WriteOptions::default()
+            },
+        );
+
+        let data = fs::read(&output).unwrap();
+        let partial_exists = partial.exists();
+
+        assert_eq!(Archive::deserialize(&data).unwrap(), archive("first"));
+
+        let offset = first.len();
+        assert_eq!(offset % 4, 0);
+        assert_eq!(
+            Archive::deserialize(&data[offset..]).unwrap(),
+            archive("second")
+        );
+
+        let offset = offset + second.len();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&data[offset..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(
+            Archive::deserialize(&decompressed).unwrap(),
+            archive("main")
+        );
+
+        let offset = data.len() - last.len();
+        assert_eq!(offset % 4, 0);
+        assert_eq!(
+            Archive::deserialize(&data[offset..]).unwrap(),
+            archive("last")
+        );
+
+        let Err(err) = result else {
+            panic!("garbage should not be accepted as a segment");
+        };
+
+        assert!(!partial_exists);
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(OutputError::InvalidSegment(path)) if path.ends_with("garbage")
+        ));
+    }
+
+    #[test]
+    fn test_stored_segment() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let firmware: Vec<u8> = (0..32u32 << 10)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut archive = Archive::from([
+            (
+                PathBuf::from("/etc/hostname"),
+                Entry::file(b"host".to_vec()),
+            ),
+            (
+                PathBuf::from("/lib/firmware/blob.bin"),
+                Entry::file(firmware),
+            ),
+        ]);
+
+        let stored = archive.split_off(|path, entry| {
+            entry
+                .data
+                .as_deref()
+                .is_some_and(|data| encoder::is_incompressible(path, data))
+        });
+        assert_eq!(stored.entries().len(), 1);
+        assert_eq!(archive.entries().len(), 1);
+
+        let main = archive.serialize().unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let output = dir.join("initramfs.img");
+        let segments = Segments {
+            stored: stored.serialize().unwrap(),
+            ..Segments::default()
+        };
+        let written = write_archive(
+            slice::from_ref(&output),
+            &segments,
+            &main,
+            &Encoder::Gzip,
+            WriteOptions {
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+
+        let data = fs::read(&output).unwrap();
+
+        assert_eq!(written.archive, written.size);
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(data.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, main);
+
+        let offset = data.len() - segments.stored.len();
+        assert_eq!(offset % 4, 0);
+        assert_eq!(data[offset..], segments.stored);
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut initramfs = Initramfs::new().unwrap();
+        initramfs
+            .add_template_file(
+                &ImagePath::new("/etc/hostname").unwrap(),
+                "host",
+                &BTreeMap::new(),
+            )
+            .unwrap();
+
+        let mut out = Vec::new();
+        explain(&initramfs, "/etc/host*", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "/etc/hostname\n  template\n"
+        );
+
+        let Err(err) = explain(&initramfs, "/etc/shadow", &mut Vec::new()) else {
+            panic!("explained a missing path");
+        };
+        assert_eq!(error_code(&err), "explain_no_match");
+    }
+
+    #[test]
+    fn test_generate() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut script = Vec::new();
+            generate(Some(shell), &mut script).unwrap();
+            assert!(!script.is_empty());
+        }
+
+        let mut man = Vec::new();
+        generate(None, &mut man).unwrap();
+        assert!(String::from_utf8(man).unwrap().starts_with(".ie"));
+
+        let mut bash = Vec::new();
+        generate(Some(Shell::Bash), &mut bash).unwrap();
+        let bash = String::from_utf8(bash).unwrap();
+
+        for arg in Args::command().get_arguments() {
+            if let Some(long) = arg.get_long() {
+                assert!(bash.contains(&format!("--{long}")), "missing --{long}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_report() {
+        let failures = [KernelFailure {
+            kernel: Some("6.1.0".to_string()),
+            failure: Failure {
+                module: "plymouth".to_string(),
+                item: "binary /usr/bin/plymouth".to_string(),
+                code: "initramfs_elf",
+                message: "missing".to_string(),
+            },
+        }];
+
+        let report = |result: Result<()>, failures| {
+            serde_json::to_value(ErrorReport::new(&result, failures)).unwrap()
+        };
+
+        assert_eq!(
+            report(Ok(()), &[]),
+            serde_json::json!({ "status": "complete", "failures": [] })
+        );
+        assert_eq!(
+            report(Ok(()), &failures),
+            serde_json::json!({
+                "status": "degraded",
+                "failures": [{
+                    "kernel": "6.1.0",
+                    "module": "plymouth",
+                    "item": "binary /usr/bin/plymouth",
+                    "code": "initramfs_elf",
+                    "message": "missing"
+                }]
+            })
+        );
+
+        let failed = report(Err(anyhow::anyhow!("init is missing")), &failures);
+        assert_eq!(failed["status"], "failed");
+        assert_eq!(failed["error"], "init is missing");
+    }
+
+    #[test]
+    fn test_kernel_placeholder() {
+        let path = Path::new("/boot/initramfs-{kernel}.img");
+        assert!(has_kernel_placeholder(path));
+        assert!(!has_kernel_placeholder(Path::new("/boot/initramfs.img")));
+
+        assert_eq!(expand_kernel_placeholder(path, None), path);
+        assert_eq!(
+            expand_kernel_placeholder(path, Some("6.1.0-arch1")),
+            Path::new("/boot/initramfs-6.1.0-arch1.img")
+        );
+        assert_eq!(
+            expand_kernel_placeholder(Path::new("/boot/{kernel}/{kernel}.img"), Some("6.1.0")),
+            Path::new("/boot/6.1.0/6.1.0.img")
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Paths of the final initramfs tree differing only by case.
+//!
+//! Some consumers of the archive extract it on case-insensitive filesystems
+//! (UEFI tools, inspection pipelines on Windows) where `/etc/Foo` and
+//! `/etc/foo` end up as the same file. Names are compared component by
+//! component within each directory, so a collision points at the component
+//! to rename rather than at every path below it.
+//!
+//! Folding is a simple ASCII lowercase. With the `unicode-casefold` feature,
+//! names that are valid UTF-8 are lowercased following Unicode instead, so
+//! `/etc/Ä` and `/etc/ä` collide as well.
+
+use crate::vfs::Vfs;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Entry taking part in a collision.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Colliding {
+    /// Path of the entry.
+    pub path: PathBuf,
+    /// Configuration module that added the entry, or the first entry below it
+    /// for directories created along the way.
+    pub module: Option<String>,
+}
+
+/// Names of a directory that are the same once folded.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Collision {
+    /// Directory containing the entries.
+    pub dir: PathBuf,
+    /// Entries sorted by path.
+    pub entries: Vec<Colliding>,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "entries of {} differ only by case:", self.dir.display())?;
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            write!(f, "{separator}{}", entry.path.display())?;
+
+            if let Some(module) = &entry.module {
+                write!(f, " (module {module})")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the names colliding once folded in every directory of the VFS, using
+/// the configuration module owning each entry (see
+/// [`Initramfs::module_owners`](crate::initramfs::Initramfs::module_owners)).
+pub fn collisions(vfs: &Vfs, owners: &BTreeMap<PathBuf, String>) -> Vec<Collision> {
+    let mut dirs: BTreeMap<&Path, BTreeMap<Vec<u8>, BTreeSet<&OsStr>>> = BTreeMap::new();
+
+    // every ancestor is looked at, in case a parent is not an entry itself
+    for path in vfs.iter().map(|(path, _)| path) {
+        for path in path.ancestors() {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+
+            dirs.entry(parent)
+                .or_default()
+                .entry(fold(name))
+                .or_default()
+                .insert(name);
+        }
+    }
+
+    let mut collisions = Vec::new();
+
+    for (dir, names) in dirs {
+        for names in names.into_values().filter(|names| names.len() > 1) {
+            let entries = names
+                .into_iter()
+                .map(|name| {
+                    let path = dir.join(name);
+                    let module = owner(&path, owners).map(str::to_string);
+
+                    Colliding { path, module }
+                })
+                .collect();
+
+            collisions.push(Collision {
+                dir: dir.to_path_buf(),
+                entries,
+            });
+        }
+    }
+
+    collisions
+}
+
+/// Fold the case of a name.
+pub fn fold(name: &OsStr) -> Vec<u8> {
+    #[cfg(feature = "unicode-casefold")]
+    if let Some(name) = name.to_str() {
+        return name.to_lowercase().into_bytes();
+    }
+
+    name.as_bytes().to_ascii_lowercase()
+}
+
+fn owner<'a>(path: &Path, owners: &'a BTreeMap<PathBuf, String>) -> Option<&'a str> {
+    if let Some(module) = owners.get(path) {
+        return Some(module);
+    }
+
+    owners
+        .range(path.to_path_buf()..)
+        .take_while(|(owned, _)| owned.starts_with(path))
+        .map(|(_, module)| module.as_str())
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::ImagePath;
+    use crate::vfs::Entry;
+
+    fn paths(collision: &Collision) -> Vec<&Path> {
+        collision
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_path())
+            .collect()
+    }
+
+    #[test]
+    fn test_collisions() {
+        let mut vfs = Vfs::new();
+
+        for path in [
+            "/etc/Foo/bar",
+            "/etc/foo/bar",
+            "/etc/foo/Baz",
+            "/etc/foo/baz",
+            "/usr/lib/firmware/ABC.bin",
+            "/usr/lib/firmware/abc.bin",
+            "/usr/lib/firmware/abd.bin",
+            "/usr/share/Ä",
+            "/usr/share/ä",
+        ] {
+            let path = ImagePath::new(path).unwrap();
+            vfs.create_dir_all(&path.parent().unwrap()).unwrap();
+            vfs.create_entry(&path, Entry::file(b"data".to_vec()))
+                .unwrap();
+        }
+
+        let owners = BTreeMap::from([
+            (PathBuf::from("/etc/Foo/bar"), "upper".to_string()),
+            (PathBuf::from("/etc/foo"), "lower".to_string()),
+            (
+                PathBuf::from("/usr/lib/firmware/ABC.bin"),
+                "firmware".to_string(),
+            ),
+        ]);
+
+        let collisions = collisions(&vfs, &owners);
+        let unicode = cfg!(feature = "unicode-casefold");
+        assert_eq!(collisions.len(), if unicode { 4 } else { 3 });
+
+        // nested collisions point at the directory component, bar is not reported
+        assert_eq!(collisions[0].dir, Path::new("/etc"));
+        assert_eq!(paths(&collisions[0]), ["/etc/Foo", "/etc/foo"]);
+        assert_eq!(collisions[0].entries[0].module.as_deref(), Some("upper"));
+        assert_eq!(collisions[0].entries[1].module.as_deref(), Some("lower"));
+
+        assert_eq!(paths(&collisions[1]), ["/etc/foo/Baz", "/etc/foo/baz"]);
+        assert_eq!(collisions[1].entries[0].module, None);
+
+        assert_eq!(
+            paths(&collisions[2]),
+            ["/usr/lib/firmware/ABC.bin", "/usr/lib/firmware/abc.bin"]
+        );
+        assert_eq!(collisions[2].entries[1].module, None);
+        assert_eq!(
+            collisions[2].to_string(),
+            "entries of /usr/lib/firmware differ only by case: \
+             /usr/lib/firmware/ABC.bin (module firmware), /usr/lib/firmware/abc.bin"
+        );
+
+        // non-ASCII letters only fold with Unicode case folding
+        if unicode {
+            assert_eq!(paths(&collisions[3]), ["/usr/share/Ä", "/usr/share/ä"]);
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(fold(OsStr::new("Foo.BIN")), b"foo.bin");
+        assert_eq!(fold(OsStr::from_bytes(b"A\xff")), b"a\xff");
+
+        let unicode = fold(OsStr::new("Ä"));
+        if cfg!(feature = "unicode-casefold") {
+            assert_eq!(unicode, "ä".as_bytes());
+        } else {
+            assert_eq!(unicode, "Ä".as_bytes());
+        }
+    }
+}
@@ -38,7 +38,11 @@
 //!   - crc32c
 //!   - crc32-generic
 //!   - crc32c-generic
+//!   - name: snd-hda-intel
+//!     softdeps: false
 //! binaries:
+//!   - directory: /usr/lib/systemd/system-generators
+//!     recursive: false
 //!   - blkid
 //!   - busybox
 //!   - lsblk
@@ -53,73 +57,646 @@
 //!       - contrib/files/etc/passwd
 //!       - contrib/files/etc/shadow
 //!     destination: /etc
+//!     install:
+//!       exceptions:
+//!         shadow: "0600"
+//!   - destination: /etc/vconsole.conf
+//!     content: |
+//!       KEYMAP=us
+//!     mode: "0644"
 //! symlinks:
 //!   - path: /usr/bin/sh
 //!     target: busybox
+//!   - path: /usr/sbin/init
+//!     target: /usr/lib/systemd/systemd
+//!     relative: true
+//! templates:
+//!   - destination: /etc/hostname
+//!     content: "{{hostname}}"
+//!     vars:
+//!       hostname: env:HOSTNAME
+//! constraints:
+//!   arch: [x86_64, aarch64]
+//!   kernel_version: ">=5.15"
+//! console:
+//!   keymap: de-latin1
+//!   font: lat9w-16
+//!   terminfo: [linux]
 //! ```
 //!
 //! For more examples, see the `contrib` directory in the repository.
 
-use serde::{Deserialize, Deserializer};
+pub mod legacy;
+pub mod loader;
+pub mod schema;
+
+use crate::constraint::Constraints;
+use crate::distro::Backend as PackageBackend;
+use crate::encoder::Encoder;
+use crate::kmod::ModuleCompression;
+use crate::newc::Format as ArchiveFormat;
+use crate::paths::{HostPath, ImagePath};
+use crate::payload::PayloadType;
+use crate::permissions;
+use crate::size::Size;
+
+use base64ct::{Base64, Encoding};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 /// Microcode generation configuration.
 #[derive(Deserialize, Debug)]
+#[serde(from = "RawMicrocode")]
 pub struct Microcode {
     /// The path to the AMD specific blobs.
     pub amd_ucode: Option<PathBuf>,
     /// The path to the Intel specific blobs.
     pub intel_ucode: Option<PathBuf>,
+    /// How blobs are laid out in the archive.
+    #[serde(default)]
+    pub layout: UcodeLayout,
+}
+
+/// Microcode configuration as written. The top-level configuration file also
+/// holds the initramfs configuration, its keys are accepted but not read.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct RawMicrocode {
+    amd_ucode: Option<PathBuf>,
+    intel_ucode: Option<PathBuf>,
+    #[serde(default)]
+    layout: UcodeLayout,
+    init: Option<IgnoredAny>,
+    shutdown: Option<IgnoredAny>,
+    settings: Option<IgnoredAny>,
+    modules: Option<IgnoredAny>,
+    shutdown_modules: Option<IgnoredAny>,
+    prepend: Option<IgnoredAny>,
+    append: Option<IgnoredAny>,
+    environment: Option<IgnoredAny>,
+}
+
+impl From<RawMicrocode> for Microcode {
+    fn from(raw: RawMicrocode) -> Self {
+        Microcode {
+            amd_ucode: raw.amd_ucode,
+            intel_ucode: raw.intel_ucode,
+            layout: raw.layout,
+        }
+    }
+}
+
+/// Layout of the microcode blobs in the archive.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum UcodeLayout {
+    /// A single blob per vendor under `/kernel/x86/microcode`, loaded by the
+    /// kernel before anything else.
+    #[default]
+    Early,
+    /// Individual blobs with their original names under
+    /// `/usr/lib/firmware/{amd,intel}-ucode`, loaded through the reload
+    /// interface once the system is up.
+    Late,
 }
 
 /// Initramfs generation configuration.
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "RawInitramfs")]
 pub struct Initramfs {
-    /// Where to find the init script for the initramfs.
-    pub init: PathBuf,
-    /// Where to find the optional shutdown script for the initramfs.
-    pub shutdown: Option<PathBuf>,
+    /// Where to find the init script for the initramfs, or a wrapper around an
+    /// init helper, only optional for bare archives.
+    pub init: Option<Init>,
+    /// Where to find the optional shutdown executables for the initramfs, see
+    /// [`ShutdownMode`] for where they are installed.
+    pub shutdown: Vec<PathBuf>,
     /// Various flags to tweak generation.
-    #[serde(default)]
     pub settings: Settings,
     /// Enabled modules.
     pub modules: Vec<String>,
+    /// Enabled modules for the shutdown initramfs (exitrd).
+    pub shutdown_modules: Vec<String>,
+    /// Uncompressed cpio archives written before the initramfs.
+    pub prepend: Vec<PathBuf>,
+    /// Cpio archives, possibly compressed, written after the initramfs.
+    pub append: Vec<PathBuf>,
+    /// Environment variables written for the init along with the ones of
+    /// modules.
+    pub environment: BTreeMap<String, EnvironmentValue>,
+}
+
+/// Initramfs configuration as written, validated when converted to
+/// [`Initramfs`]. Keys of the microcode configuration, held by the same file,
+/// are accepted but not read.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct RawInitramfs {
+    init: Option<Init>,
+    shutdown: Option<RawShutdown>,
+    #[serde(default)]
+    settings: Settings,
+    modules: Vec<String>,
+    #[serde(default = "Vec::new")]
+    shutdown_modules: Vec<String>,
+    #[serde(default = "Vec::new")]
+    prepend: Vec<PathBuf>,
+    #[serde(default = "Vec::new")]
+    append: Vec<PathBuf>,
+    #[serde(default)]
+    environment: BTreeMap<String, EnvironmentValue>,
+    amd_ucode: Option<IgnoredAny>,
+    intel_ucode: Option<IgnoredAny>,
+    layout: Option<IgnoredAny>,
+}
+
+/// Init of the initramfs, installed at `/init`.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum Init {
+    /// Path of the init script or executable.
+    Path(PathBuf),
+    /// Generated script that execs an init helper with the payload.
+    Wrapper(InitWrapper),
+}
+
+/// Init helper (e.g. `tini`, `catatonit`) started as PID 1 by a generated
+/// `/init` script, it reaps zombies and forwards signals to the payload.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InitWrapper {
+    /// Name of the helper, looked up in the binary search paths, or its path.
+    #[serde(rename = "use")]
+    pub helper: PathBuf,
+    /// Arguments passed to the helper before the payload (e.g. `--`).
+    pub args: Option<String>,
+    /// Payload executed by the helper.
+    pub exec: PathBuf,
+}
+
+/// Shutdown executables as written, a single path or a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawShutdown {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+impl TryFrom<RawInitramfs> for Initramfs {
+    type Error = String;
+
+    fn try_from(raw: RawInitramfs) -> Result<Self, Self::Error> {
+        if raw.init.is_none() && !raw.settings.bare {
+            return Err("init is required unless settings.bare is set".to_string());
+        }
+
+        Ok(Initramfs {
+            init: raw.init,
+            shutdown: match raw.shutdown {
+                None => Vec::new(),
+                Some(RawShutdown::One(path)) => vec![path],
+                Some(RawShutdown::Many(paths)) => paths,
+            },
+            settings: raw.settings,
+            modules: raw.modules,
+            shutdown_modules: raw.shutdown_modules,
+            prepend: raw.prepend,
+            append: raw.append,
+            environment: raw.environment,
+        })
+    }
 }
 
 /// Initramfs generation settings such as various flags.
 #[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Settings {
-    /// Override path where kernel module are searched.
+    /// Override path where kernel module are searched, either a module
+    /// directory or a kernel package (`.tar`, `.tar.gz`, `.tar.xz` or
+    /// `.tar.zst`) holding one.
     pub kernel_module_path: Option<PathBuf>,
+    /// Check that versioned symbols required by ELF files are provided by
+    /// libraries included in the initramfs.
+    #[serde(default)]
+    pub verify_symbols: bool,
+    /// How unresolved versioned symbols are reported.
+    #[serde(default)]
+    pub unresolved_symbols: Severity,
+    /// Strip debug sections and static symbols from ELF files.
+    #[serde(default)]
+    pub strip: bool,
+    /// Directory where what is stripped from ELF files is written, as
+    /// `<dir>/<path in the initramfs>.debug` files.
+    pub debug_sidecar: Option<PathBuf>,
+    /// What to do when copied files look like host secrets (e.g. `/etc/shadow`).
+    #[serde(default)]
+    pub secret_filter: SecretFilter,
+    /// Additional glob patterns of host paths considered secret.
+    #[serde(default = "Vec::new")]
+    pub secret_patterns: Vec<String>,
+    /// Allow template variables to be produced by shell commands (`cmd:`).
+    #[serde(default)]
+    pub allow_commands: bool,
+    /// Remove directories left empty, default root directories are kept.
+    #[serde(default)]
+    pub prune_empty_dirs: bool,
+    /// Remove libraries that were added as dependencies but that no ELF file
+    /// in the initramfs needs anymore.
+    #[serde(default)]
+    pub prune_unused_libs: bool,
+    /// Serialize the archive in canonical form for measured boot: timestamps,
+    /// owners (including configured ones) and link counts are forced to
+    /// constants and the archive is padded to a multiple of 512 bytes.
+    #[serde(default)]
+    pub canonical: bool,
+    /// Variant of the cpio format the archive is written in: `newc`, or `crc`
+    /// for the `070702` variant where each regular file carries a checksum of
+    /// its data that the kernel verifies when unpacking.
+    #[serde(default)]
+    pub archive_format: ArchiveFormat,
+    /// Write files that would not compress, such as firmware or already
+    /// compressed payloads, to a second uncompressed cpio segment after the
+    /// compressed archive instead of compressing them again.
+    #[serde(default)]
+    pub split_incompressible: bool,
+    /// Look kernel modules up by file name when the module directory has not
+    /// been indexed by depmod, e.g. in build containers.
+    #[serde(default)]
+    pub auto_depmod: bool,
+    /// Glob patterns of kernel module names never added to the initramfs, not
+    /// even as dependencies: modules depending on them are added without
+    /// them. Dashes and underscores are interchangeable in module names.
+    #[serde(default = "Vec::new")]
+    pub kernel_module_denylist: Vec<String>,
+    /// Glob patterns of the only kernel module names that may be added, other
+    /// modules are left out as if they matched `kernel_module_denylist`.
+    pub kernel_module_allowlist: Option<Vec<String>>,
+    /// Fail instead of warning when a kernel module left out by
+    /// `kernel_module_denylist` or `kernel_module_allowlist` is requested.
+    #[serde(default)]
+    pub strict_module_policy: bool,
+    /// Compression of the kernel modules in the initramfs (`none`, `gzip` or
+    /// `zstd`). Defaults to the compression the kernel decompresses by itself
+    /// according to its configuration, `none` without one.
+    pub module_compression: Option<ModuleCompression>,
+    /// How a `module_compression` or encoder the kernel configuration does not
+    /// support is reported.
+    #[serde(default)]
+    pub kernel_config_conflicts: Severity,
+    /// Glob patterns of library file names left out of the dependencies of
+    /// ELF files, along with the libraries only they need (e.g. `liblz4.so*`).
+    #[serde(default = "Vec::new")]
+    pub library_denylist: Vec<String>,
+    /// Fail instead of warning when an ELF file needs a library matching
+    /// `library_denylist`.
+    #[serde(default)]
+    pub strict_library_policy: bool,
+    /// Libraries added with their dependencies even though no ELF file needs
+    /// them (`DT_NEEDED`), by name in the library search paths or by path,
+    /// e.g. `libgcc_s.so.1` for `pthread_cancel`.
+    #[serde(default = "Vec::new")]
+    pub library_extra: Vec<String>,
+    /// Add kernel modules for the filesystems, storage and keyboards of the
+    /// host the initramfs is generated on.
+    #[serde(default)]
+    pub host_only: bool,
+    /// Add every kernel module loaded on the host (`/proc/modules`), except
+    /// unused ones matching `lsmod_denylist`.
+    #[serde(default)]
+    pub modules_from_lsmod: bool,
+    /// Add the kernel modules listed in a file, one name per line, e.g. a
+    /// snapshot of `lsmod` captured on the target machine.
+    pub modules_from_file: Option<PathBuf>,
+    /// Glob patterns of kernel modules left out by `modules_from_lsmod` when
+    /// their use count is zero. Defaults to modules not needed to boot such as
+    /// bluetooth, sound and wireless.
+    pub lsmod_denylist: Option<Vec<String>>,
+    /// Build a bare cpio payload instead of an initramfs: the archive starts
+    /// with only the root directory, the skeleton is not created, files are
+    /// not moved under `/usr` unless `usr_merge` is set, and `init` is
+    /// optional.
+    #[serde(default)]
+    pub bare: bool,
+    /// Move files destined to `/bin`, `/sbin`, `/lib`, `/lib64` and `/usr/sbin`
+    /// under `/usr`, where the top-level symlinks point. Defaults to true,
+    /// unless `bare` or `skeleton.disable_defaults` is set since nothing then
+    /// links the top-level directories to `/usr`.
+    pub usr_merge: Option<bool>,
+    /// Directories searched for systemd units, from highest to lowest
+    /// precedence. Defaults to the directories used by systemd, where
+    /// `/etc/systemd/system` overrides `/usr/lib/systemd/system`.
+    pub unit_search_paths: Option<Vec<PathBuf>>,
+    /// Keys of the `[Unit]` section of systemd units naming units added along
+    /// with them. Defaults to `Requires`, `Requisite` and `BindsTo`, `PartOf`
+    /// or `Wants` can be added to pull more units in.
+    pub unit_dependency_keys: Option<Vec<String>>,
+    /// Package manager queried for the files of `packages` (`pacman`, `dpkg`
+    /// or `rpm`). Detected from the commands available on the host by
+    /// default.
+    pub package_backend: Option<PackageBackend>,
+    /// Only files of `packages` under these directories are added. Defaults
+    /// to `/usr` and `/etc`.
+    pub package_prefixes: Option<Vec<PathBuf>>,
+    /// Directories and symlinks created before anything else.
+    #[serde(default)]
+    pub skeleton: Skeleton,
+    /// How volumes in `/etc/crypttab` that cannot be unlocked with the
+    /// content of the initramfs are reported.
+    #[serde(default)]
+    pub crypttab_problems: Severity,
+    /// How symlinks whose target is not in the initramfs are reported.
+    #[serde(default)]
+    pub dangling_symlinks: Severity,
+    /// How world-writable, setuid or otherwise dangerous permissions in the
+    /// initramfs are reported.
+    #[serde(default)]
+    pub permission_lints: Severity,
+    /// Permission lints to disable and paths they accept.
+    #[serde(default)]
+    pub permission_rules: permissions::Rules,
+    /// How entries of a directory whose names only differ by case (e.g.
+    /// `/etc/Foo` and `/etc/foo`) are reported, they collide when the archive
+    /// is extracted on a case-insensitive filesystem.
+    #[serde(default)]
+    pub case_collision: Severity,
+    /// Where shutdown executables are installed. Defaults to `systemd-hook`
+    /// when modules include systemd units, `legacy` otherwise.
+    pub shutdown_mode: Option<ShutdownMode>,
+    /// Whether an item of a configuration module that cannot be added fails
+    /// the generation.
+    #[serde(default)]
+    pub on_error: OnError,
+    /// Warn when the init does not look like it can run as PID 1, i.e. reap
+    /// zombies and handle signals.
+    #[serde(default)]
+    pub init_lint: bool,
+    /// How an `/init` the kernel cannot execute is reported, e.g. a script
+    /// whose interpreter does not resolve through the symlinks of the
+    /// initramfs.
+    #[serde(default)]
+    pub init_exec: Severity,
+    /// How kernel command line parameters repeating a key with another value
+    /// are reported. Both parameters are kept unless it is an error, some
+    /// parameters (e.g. `console=`) are meant to be repeated.
+    #[serde(default)]
+    pub cmdline_conflicts: Severity,
+    /// Add the fstab, gpt-auto and debug generators found on the host when
+    /// systemd units are included.
+    #[serde(default)]
+    pub auto_generators: bool,
+    /// Collect the extended attributes of files read from the host (file
+    /// capabilities, SELinux labels, IMA signatures, ...) into a script at
+    /// this path, restoring them once run by the init.
+    pub xattr_script: Option<ImagePath>,
+    /// Where environment variables of the configuration and its modules are
+    /// written, `/etc/initrd-env` by default.
+    pub environment_path: Option<ImagePath>,
+    /// Also pass environment variables to the services of systemd, with a
+    /// `DefaultEnvironment=` drop-in.
+    #[serde(default)]
+    pub systemd_environment: bool,
+    /// Write `/etc/ld.so.conf` listing the directories holding shared
+    /// libraries outside of the default loader paths. The dynamic loader only
+    /// reads `/etc/ld.so.cache`, so the init must run `ldconfig` unless
+    /// `run_ldconfig` is set.
+    #[serde(default)]
+    pub generate_ld_cache: bool,
+    /// Generate `/etc/ld.so.cache` at build time by running the host
+    /// `ldconfig` on a copy of the libraries, implies `generate_ld_cache`.
+    #[serde(default)]
+    pub run_ldconfig: bool,
+    /// Rescue path for when init fails, added after all modules.
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub emergency: Emergency,
+    /// Fail when a file read from the host is larger than this size, checked
+    /// before it is read. Defaults to 256 MiB.
+    pub max_file_size: Option<Size>,
+    /// Fail when the content of the initramfs, before compression, adds up to
+    /// more than this size. Unlimited by default.
+    pub max_total_size: Option<Size>,
+    /// Fail when the compressed initramfs is larger than this size.
+    pub max_size: Option<Size>,
+    /// Encoder compressing the archive (`none`, `gzip` or `zstd`), the
+    /// `--encoder` flag takes precedence. Defaults to `zstd`.
+    pub encoder: Option<Encoder>,
+    /// Log a warning when the compressed initramfs is larger than this size.
+    pub warn_size: Option<Size>,
+    /// Zstd dictionary used for raw zstd output (`--format raw-zstd`), it is
+    /// ignored for initramfs output since the kernel cannot use it.
+    pub zstd_dictionary: Option<PathBuf>,
+    /// Directory where remote sources are kept once downloaded. Defaults to
+    /// `$XDG_CACHE_HOME/elusive`.
+    pub cache_dir: Option<PathBuf>,
+    /// Timeout of remote source downloads, in seconds. Defaults to 60.
+    pub remote_timeout: Option<u64>,
+}
+
+/// Base layout of the initramfs, created before anything else.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Skeleton {
+    /// Do not create the default directories (`/dev`, `/etc`, ...) and
+    /// symlinks (`/bin -> usr/bin`, ...).
+    #[serde(default)]
+    pub disable_defaults: bool,
+    /// Additional directories to create.
+    #[serde(default = "Vec::new")]
+    pub extra_dirs: Vec<ImagePath>,
+    /// Additional symlinks to create.
+    #[serde(default = "Vec::new")]
+    pub extra_symlinks: Vec<Symlink>,
+}
+
+/// Severity used to report problems found while checking the initramfs.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Log a warning and continue.
+    #[default]
+    Warn,
+    /// Fail the generation.
+    Error,
+    /// Do not report anything.
+    #[serde(alias = "off")]
+    Ignore,
+}
+
+/// Behavior when a copied file matches a secret pattern.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretFilter {
+    /// Fail the generation.
+    #[default]
+    Error,
+    /// Log a warning and leave the file out.
+    Skip,
+    /// Include the file anyway.
+    Allow,
+}
+
+/// What to do when an item of a configuration module cannot be added.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnError {
+    /// Fail the generation.
+    #[default]
+    Abort,
+    /// Leave the item out and carry on, unless its module is critical. The
+    /// failures are reported once the image is generated.
+    BestEffort,
+}
+
+/// Placement of the shutdown executables.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShutdownMode {
+    /// A single script at `/shutdown`, run by a hand-rolled init.
+    Legacy,
+    /// Executables in `/usr/lib/systemd/system-shutdown/`, run by systemd
+    /// right before the system halts.
+    SystemdHook,
+    /// Left out of the initramfs, the script is the `/shutdown` of the
+    /// exitrd built by `elusive exitrd` that systemd pivots into.
+    Exitrd,
+}
+
+/// Rescue path added to the initramfs for when init fails.
+#[derive(Deserialize, Clone, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Emergency {
+    /// No emergency shell.
+    #[default]
+    None,
+    /// A busybox shell started by `/usr/bin/rescue`, which mounts the API
+    /// filesystems first.
+    Busybox {
+        /// Path of the busybox binary.
+        path: PathBuf,
+    },
+    /// The emergency and rescue targets of systemd, with sulogin.
+    Systemd,
 }
 
 /// Initramfs configuration module.
-#[derive(Deserialize, Debug)]
+///
+/// Modules serialize back to their normalized form, where shorthand strings
+/// are written as the equivalent map.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Module {
     /// Name to refer to this module.
     pub name: String,
     /// Binaries to add to the initramfs.
-    #[serde(default = "Vec::new")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub binaries: Vec<Binary>,
     /// Filesystem trees to copy into the initramfs.
-    #[serde(default = "Vec::new")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub files: Vec<File>,
     /// Symlinks to add to the initramfs.
-    #[serde(default = "Vec::new")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub symlinks: Vec<Symlink>,
+    /// File of symlinks to add to the initramfs, read when the module is
+    /// loaded: its symlinks are appended to `symlinks` and the map is
+    /// cleared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_map: Option<SymlinkMap>,
+    /// Filesystem images to add, along with the kernel modules mounting them.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub payloads: Vec<Payload>,
     /// Modules to include in the initramfs.
-    #[serde(default = "Vec::new")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub kernel_modules: Vec<KernelModule>,
     /// Units (systemd) to include in the initramfs.
-    #[serde(default = "Vec::new")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub units: Vec<Unit>,
+    /// Installed distribution packages whose files are added, as listed by
+    /// the package manager (see `settings.package_backend`).
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+    /// Systemd generators to include, by name in `/usr/lib/systemd/system-generators/`.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub generators: Vec<String>,
+    /// Kernel command line fragments required by this module.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub kernel_cmdline: Vec<String>,
+    /// Sysctl settings written to `/etc/sysctl.d/90-elusive.conf`, the same
+    /// key set to different values by two modules is an error.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sysctl: BTreeMap<String, SysctlValue>,
+    /// Lines written to `/etc/tmpfiles.d/elusive.conf`.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub tmpfiles: Vec<String>,
+    /// Kernel modules written to `/etc/modules-load.d/elusive.conf`, and
+    /// added to the initramfs like `kernel_modules`.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub modules_load: Vec<String>,
+    /// Environment variables written to `/etc/initrd-env` for the init, the
+    /// same variable set to different values by two modules is an error.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, EnvironmentValue>,
+    /// Small files rendered from templates.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub templates: Vec<Template>,
+    /// Keymap, font and terminfo entries needed by console prompts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub console: Option<Console>,
+    /// Architectures and kernel versions this module applies to.
+    #[serde(default, skip_serializing_if = "Constraints::is_empty")]
+    pub constraints: Constraints,
+    /// Fail the generation when anything in this module cannot be added, even
+    /// with `on_error: best-effort`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub critical: bool,
+}
+
+impl Module {
+    /// Summarize the content of the module on a single line.
+    pub fn summary(&self) -> String {
+        format!(
+            "binaries: {}, files: {}, kernel modules: {}, units: {}",
+            self.binaries.len(),
+            self.files.len() + self.templates.len(),
+            self.kernel_modules.len(),
+            self.units.len()
+        )
+    }
+}
+
+/// Console assets to include, located in the kbd and terminfo data directories.
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Console {
+    /// Keymap name (e.g. `de-latin1`), files it includes are added too.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keymap: Option<String>,
+    /// Console font name (e.g. `lat9w-16`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
+    /// Terminfo entry names (e.g. `linux`).
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub terminfo: Vec<String>,
 }
 
 /// Configuration for an ELF binary.
-#[derive(Debug)]
+#[derive(Serialize, Debug)]
 pub struct Binary {
-    /// The path where the binary can be found.
+    /// The path where the binary can be found, or a directory of binaries.
+    /// For remote binaries, the path of the binary in the initramfs.
     pub path: PathBuf,
+    /// Whether subdirectories are walked when `path` is a directory.
+    pub recursive: bool,
+    /// Override the global strip setting for this binary.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip: Option<bool>,
+    /// Whether linked libraries and the program interpreter are added too.
+    /// Defaults to true.
+    pub resolve_deps: bool,
+    /// Download the binary instead of finding it on the host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<Remote>,
 }
 
 impl<'de> Deserialize<'de> for Binary {
@@ -136,7 +713,10 @@ impl<'de> Deserialize<'de> for Binary {
             type Value = Binary;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                write!(formatter, "a string or a map with one of 'name' or 'path'")
+                write!(
+                    formatter,
+                    "a string or a map with one of 'path', 'directory' or 'remote'"
+                )
             }
 
             fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -145,6 +725,10 @@ impl<'de> Deserialize<'de> for Binary {
             {
                 Ok(Binary {
                     path: PathBuf::from(v),
+                    recursive: false,
+                    strip: None,
+                    resolve_deps: true,
+                    remote: None,
                 })
             }
 
@@ -152,12 +736,57 @@ impl<'de> Deserialize<'de> for Binary {
             where
                 M: MapAccess<'de>,
             {
-                match map.next_key::<String>()? {
-                    Some(ref key) if key == "path" => Ok(Binary {
-                        path: map.next_value()?,
-                    }),
-                    _ => Err(Error::custom("missing key 'path'".to_string())),
+                let mut path = None;
+                let mut recursive = false;
+                let mut strip = None;
+                let mut resolve_deps = true;
+                let mut remote: Option<Remote> = None;
+
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "path" | "directory" => path = Some(map.next_value()?),
+                        "recursive" => recursive = map.next_value()?,
+                        "strip" => strip = Some(map.next_value()?),
+                        "resolve_deps" => resolve_deps = map.next_value()?,
+                        "remote" => remote = Some(map.next_value()?),
+                        other => {
+                            return Err(Error::unknown_field(
+                                other,
+                                &[
+                                    "path",
+                                    "directory",
+                                    "recursive",
+                                    "strip",
+                                    "resolve_deps",
+                                    "remote",
+                                ],
+                            ))
+                        }
+                    }
+                }
+
+                let Some(path) = path else {
+                    return Err(Error::custom(
+                        "missing one of 'path' or 'directory'".to_string(),
+                    ));
+                };
+
+                // remote binaries are placed at their path in the initramfs
+                if remote.is_some() {
+                    if recursive {
+                        return Err(Error::custom("remote binaries cannot be recursive"));
+                    }
+
+                    ImagePath::new(&path).map_err(Error::custom)?;
                 }
+
+                Ok(Binary {
+                    path,
+                    recursive,
+                    strip,
+                    resolve_deps,
+                    remote,
+                })
             }
         }
 
@@ -165,33 +794,608 @@ impl<'de> Deserialize<'de> for Binary {
     }
 }
 
-/// Configuration for a filesystem tree.
+/// Configuration for a filesystem tree, or a single file with inline or
+/// remote content.
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "RawFile")]
 pub struct File {
-    /// The list of files and directories to copy.
-    pub sources: Vec<PathBuf>,
-    /// The destination in the initramfs.
-    pub destination: PathBuf,
+    /// The list of files and directories to copy, empty for inline and remote
+    /// content.
+    pub sources: Vec<HostPath>,
+    /// Regular files copied into the destination under a different name.
+    pub renamed: Vec<RenamedSource>,
+    /// The destination in the initramfs: the directory sources are copied
+    /// into, or the path of the file holding inline or remote content.
+    pub destination: ImagePath,
+    /// Inline content, from `content` or decoded from `content_base64`.
+    pub content: Option<Vec<u8>>,
+    /// Remote content, downloaded when the initramfs is built.
+    pub remote: Option<Remote>,
+    /// Permissions of the inline or remote content file, defaults to 0644.
+    pub mode: Option<Mode>,
+    /// Override the global secret filter for these files.
+    pub secret_filter: Option<SecretFilter>,
+    /// Normalize ownership and modes instead of using host metadata.
+    pub install: Option<Install>,
+    /// Leave out editor backups and version control files found in
+    /// directories, defaults to true.
+    pub ignore_defaults: bool,
+    /// Additional glob patterns of files left out of directories.
+    pub exclude: Vec<String>,
+}
+
+impl Serialize for File {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(None)?;
+
+        if let Some(remote) = &self.remote {
+            map.serialize_entry("destination", &self.destination)?;
+            map.serialize_entry("remote", remote)?;
+            if let Some(mode) = &self.mode {
+                map.serialize_entry("mode", mode)?;
+            }
+
+            return map.end();
+        }
+
+        let Some(content) = &self.content else {
+            let sources: Vec<_> = self
+                .sources
+                .iter()
+                .map(SourceRef::Path)
+                .chain(self.renamed.iter().map(SourceRef::Renamed))
+                .collect();
+
+            map.serialize_entry("sources", &sources)?;
+            map.serialize_entry("destination", &self.destination)?;
+
+            if let Some(secret_filter) = &self.secret_filter {
+                map.serialize_entry("secret_filter", secret_filter)?;
+            }
+            if let Some(install) = &self.install {
+                map.serialize_entry("install", install)?;
+            }
+            map.serialize_entry("ignore_defaults", &self.ignore_defaults)?;
+            if !self.exclude.is_empty() {
+                map.serialize_entry("exclude", &self.exclude)?;
+            }
+
+            return map.end();
+        };
+
+        map.serialize_entry("destination", &self.destination)?;
+        match std::str::from_utf8(content) {
+            Ok(content) => map.serialize_entry("content", content)?,
+            Err(_) => map.serialize_entry("content_base64", &Base64::encode_string(content))?,
+        }
+        if let Some(mode) = &self.mode {
+            map.serialize_entry("mode", mode)?;
+        }
+
+        map.end()
+    }
+}
+
+/// A regular file copied under a different name, the destination of the
+/// [`File`] being the directory it is copied into.
+#[derive(Serialize, Debug)]
+pub struct RenamedSource {
+    /// The regular file to copy.
+    pub path: HostPath,
+    /// Name of the copy in the destination directory.
+    pub rename: String,
+}
+
+/// Entry of `sources`, either a path or a path with a new name.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum RawSource {
+    Path(HostPath),
+    Renamed { path: HostPath, rename: String },
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SourceRef<'a> {
+    Path(&'a HostPath),
+    Renamed(&'a RenamedSource),
+}
+
+/// Filesystem tree entry as written, validated when converted to [`File`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFile {
+    sources: Option<Vec<RawSource>>,
+    destination: Option<ImagePath>,
+    destination_file: Option<ImagePath>,
+    content: Option<String>,
+    content_base64: Option<String>,
+    remote: Option<Remote>,
+    mode: Option<Mode>,
+    secret_filter: Option<SecretFilter>,
+    install: Option<Install>,
+    ignore_defaults: Option<bool>,
+    #[serde(default = "Vec::new")]
+    exclude: Vec<String>,
+}
+
+impl TryFrom<RawFile> for File {
+    type Error = String;
+
+    fn try_from(raw: RawFile) -> Result<Self, Self::Error> {
+        let content = match (raw.content, raw.content_base64) {
+            (Some(_), Some(_)) => {
+                return Err("content and content_base64 are mutually exclusive".to_string())
+            }
+            (Some(content), None) => Some(content.into_bytes()),
+            (None, Some(encoded)) => {
+                // block scalars may wrap the payload over several lines
+                let encoded: String = encoded.split_whitespace().collect();
+                let decoded = Base64::decode_vec(&encoded)
+                    .map_err(|err| format!("invalid content_base64: {err}"))?;
+
+                Some(decoded)
+            }
+            (None, None) => None,
+        };
+
+        if raw.remote.is_some() && (raw.sources.is_some() || content.is_some()) {
+            return Err("remote is mutually exclusive with sources and inline content".to_string());
+        }
+
+        let sources = match (raw.sources, &content) {
+            (Some(_), Some(_)) => {
+                return Err("sources and inline content are mutually exclusive".to_string())
+            }
+            (None, None) if raw.remote.is_none() => {
+                return Err(
+                    "one of sources, content, content_base64 or remote is required".to_string(),
+                )
+            }
+            (Some(sources), None) => {
+                if raw.mode.is_some() {
+                    return Err(
+                        "mode only applies to inline and remote content, use install".to_string(),
+                    );
+                }
+
+                sources
+            }
+            (None, _) => {
+                let tree_options = raw.secret_filter.is_some()
+                    || raw.install.is_some()
+                    || raw.ignore_defaults.is_some()
+                    || !raw.exclude.is_empty();
+
+                if tree_options {
+                    return Err(
+                        "secret_filter, install, ignore_defaults and exclude only apply to sources"
+                            .to_string(),
+                    );
+                }
+
+                Vec::new()
+            }
+        };
+
+        let mut paths = Vec::new();
+        let mut renamed = Vec::new();
+        for source in sources {
+            match source {
+                RawSource::Path(path) => paths.push(path),
+                RawSource::Renamed { path, rename } => {
+                    let invalid = rename.is_empty() || rename == "." || rename == "..";
+                    if invalid || rename.contains('/') {
+                        return Err(format!(
+                            "invalid rename '{rename}' for {}, expected a file name",
+                            path.display()
+                        ));
+                    }
+
+                    renamed.push(RenamedSource { path, rename });
+                }
+            }
+        }
+
+        let destination = match (raw.destination, raw.destination_file) {
+            (Some(_), Some(_)) => {
+                return Err("destination and destination_file are mutually exclusive".to_string())
+            }
+            (None, None) => {
+                return Err("one of destination or destination_file is required".to_string())
+            }
+            (Some(destination), None) => destination,
+            (None, Some(destination_file)) => {
+                if content.is_some() || raw.remote.is_some() {
+                    return Err(
+                        "destination_file only applies to sources, inline and remote content use destination as the file path".to_string(),
+                    );
+                }
+
+                let (Some(parent), Some(name)) =
+                    (destination_file.parent(), destination_file.file_name())
+                else {
+                    return Err("destination_file must be the path of a file".to_string());
+                };
+
+                // the single source is copied into the parent under a new name
+                let path = match (paths.pop(), renamed.is_empty(), paths.is_empty()) {
+                    (Some(path), true, true) => path,
+                    _ => {
+                        return Err(
+                            "destination_file requires exactly one source, without rename"
+                                .to_string(),
+                        )
+                    }
+                };
+                let rename = name
+                    .to_str()
+                    .ok_or("destination_file must be valid UTF-8")?
+                    .to_string();
+
+                renamed.push(RenamedSource { path, rename });
+                parent
+            }
+        };
+
+        Ok(File {
+            sources: paths,
+            renamed,
+            destination,
+            content,
+            remote: raw.remote,
+            mode: raw.mode,
+            secret_filter: raw.secret_filter,
+            install: raw.install,
+            ignore_defaults: raw.ignore_defaults.unwrap_or(true),
+            exclude: raw.exclude,
+        })
+    }
+}
+
+/// Install-like ownership and modes for copied files.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Install {
+    /// Permissions of directories, defaults to 0755.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir_mode: Option<Mode>,
+    /// Permissions of other files, defaults to 0644.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<Mode>,
+    /// Numeric owner, defaults to root.
+    #[serde(default)]
+    pub owner: u64,
+    /// Numeric group, defaults to root.
+    #[serde(default)]
+    pub group: u64,
+    /// Permissions for specific paths, relative to the destination.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exceptions: BTreeMap<PathBuf, Mode>,
+}
+
+/// Value of a sysctl setting, numbers and booleans are written as is.
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct SysctlValue(pub String);
+
+impl<'de> Deserialize<'de> for SysctlValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // the kernel expects 0 or 1
+        let visitor = ScalarVisitor {
+            numeric_bools: true,
+        };
+        deserializer.deserialize_any(visitor).map(SysctlValue)
+    }
+}
+
+/// Value of an environment variable, numbers and booleans are written as is.
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct EnvironmentValue(pub String);
+
+impl<'de> Deserialize<'de> for EnvironmentValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let visitor = ScalarVisitor {
+            numeric_bools: false,
+        };
+        deserializer.deserialize_any(visitor).map(EnvironmentValue)
+    }
+}
+
+/// Visitor reading a string, number or boolean as text.
+struct ScalarVisitor {
+    /// Write booleans as 0 or 1 instead of true or false.
+    numeric_bools: bool,
+}
+
+impl serde::de::Visitor<'_> for ScalarVisitor {
+    type Value = String;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "a string, number or boolean")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        match self.numeric_bools {
+            true => Ok(u8::from(v).to_string()),
+            false => Ok(v.to_string()),
+        }
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v.to_string())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v.to_string())
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v.to_string())
+    }
+}
+
+/// Permission bits written in octal, e.g. `"0644"`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mode(pub u32);
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::{Error, Visitor};
+        use std::fmt;
+
+        struct ModeVisitor;
+
+        impl Visitor<'_> for ModeVisitor {
+            type Value = Mode;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "an octal mode such as \"0644\"")
+            }
+
+            // unquoted 0644 is read as the decimal 644, use its digits
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                let digits = v.strip_prefix("0o").unwrap_or(v);
+
+                match u32::from_str_radix(digits, 8) {
+                    Ok(mode) if mode <= 0o7777 => Ok(Mode(mode)),
+                    _ => Err(Error::custom(format!("invalid mode: {v}"))),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(ModeVisitor)
+    }
+}
+
+impl Serialize for Mode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+/// File downloaded when the initramfs is built, pinned by the SHA-256 digest
+/// of its content.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "RawRemote")]
+pub struct Remote {
+    /// Where the file is downloaded from, `https://`, `http://` or `file://`.
+    pub url: String,
+    /// Hex encoded SHA-256 digest of the file, in lowercase.
+    pub sha256: String,
+}
+
+/// Remote source as written, validated when converted to [`Remote`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRemote {
+    url: String,
+    sha256: Option<String>,
+}
+
+impl TryFrom<RawRemote> for Remote {
+    type Error = String;
+
+    fn try_from(raw: RawRemote) -> Result<Self, Self::Error> {
+        let Some(sha256) = raw.sha256 else {
+            return Err(format!(
+                "remote source {} must be pinned with sha256",
+                raw.url
+            ));
+        };
+
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "invalid sha256 for remote source {}: {sha256}",
+                raw.url
+            ));
+        }
+
+        Ok(Remote {
+            url: raw.url,
+            sha256: sha256.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Configuration for a file rendered from a template.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    /// The path of the rendered file in the initramfs.
+    pub destination: ImagePath,
+    /// The template, where `{{name}}` is replaced by the value of `name`.
+    pub content: String,
+    /// Variables available to the template. Values prefixed with `env:` are
+    /// read from the environment and values prefixed with `cmd:` are the
+    /// output of a shell command.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
 }
 
 /// Configuration for a symbolic link.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Symlink {
     /// The path where the symlink will be placed.
-    pub path: PathBuf,
+    pub path: ImagePath,
     /// The file the symlink points to.
     pub target: PathBuf,
+    /// Point to an absolute target relative to the directory of the symlink
+    /// instead, e.g. `../lib/libc.so.6` for `/usr/lib/libc.so.6` from
+    /// `/usr/bin/ldd`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub relative: bool,
+}
+
+impl Symlink {
+    /// Get the target the symlink is created with.
+    pub fn resolved_target(&self) -> PathBuf {
+        match self.relative {
+            true => self.path.relative_target(&self.target),
+            false => self.target.clone(),
+        }
+    }
+}
+
+/// Text file of symlinks, one `path -> target` per line. Blank lines and
+/// lines starting with `#` are skipped.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(from = "RawSymlinkMap")]
+pub struct SymlinkMap {
+    /// Path of the file, relative to the directory of the module file.
+    pub path: PathBuf,
+    /// Whether absolute targets are made relative, see [`Symlink::relative`].
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub relative: bool,
+}
+
+/// `symlink_map` as written, either a path or a path with options.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum RawSymlinkMap {
+    Path(PathBuf),
+    Options {
+        path: PathBuf,
+        #[serde(default)]
+        relative: bool,
+    },
+}
+
+impl From<RawSymlinkMap> for SymlinkMap {
+    fn from(raw: RawSymlinkMap) -> Self {
+        match raw {
+            RawSymlinkMap::Path(path) => SymlinkMap {
+                path,
+                relative: false,
+            },
+            RawSymlinkMap::Options { path, relative } => SymlinkMap { path, relative },
+        }
+    }
+}
+
+/// Filesystem image mounted from the initramfs, e.g. the squashfs root of a
+/// live system. See [`crate::payload`].
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Payload {
+    /// Path of the image on the host.
+    pub path: HostPath,
+    /// Path of the image in the initramfs.
+    pub destination: ImagePath,
+    /// Filesystem of the image, detected from its magic by default.
+    #[serde(default, rename = "type")]
+    pub filesystem: PayloadType,
 }
 
 /// Configuration for a kernel module.
 #[derive(Debug)]
-pub enum KernelModule {
+pub struct KernelModule {
+    /// The kernel module to include.
+    pub source: ModuleSource,
+    /// Whether hard dependencies are added too. Defaults to true.
+    pub deps: bool,
+    /// Whether soft dependencies are added too. Defaults to true.
+    pub softdeps: bool,
+}
+
+/// How a kernel module is found.
+#[derive(Debug)]
+pub enum ModuleSource {
     /// Name of the kernel module to include.
     Name(String),
-    /// Path to the kernel module, useful for out of tree modules.
+    /// Path to the kernel module, useful for out of tree modules. Modules
+    /// outside of a `kernel` directory are installed in `updates`.
     Path(PathBuf),
 }
 
+impl From<ModuleSource> for KernelModule {
+    fn from(source: ModuleSource) -> Self {
+        KernelModule {
+            source,
+            deps: true,
+            softdeps: true,
+        }
+    }
+}
+
+impl Serialize for KernelModule {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(3))?;
+        match &self.source {
+            ModuleSource::Name(name) => map.serialize_entry("name", name)?,
+            ModuleSource::Path(path) => map.serialize_entry("path", path)?,
+        }
+        map.serialize_entry("deps", &self.deps)?;
+        map.serialize_entry("softdeps", &self.softdeps)?;
+
+        map.end()
+    }
+}
+
 impl<'de> Deserialize<'de> for KernelModule {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -213,18 +1417,51 @@ impl<'de> Deserialize<'de> for KernelModule {
             where
                 E: Error,
             {
-                Ok(KernelModule::Name(v.to_string()))
+                Ok(ModuleSource::Name(v.to_string()).into())
             }
 
             fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
             where
                 M: MapAccess<'de>,
             {
-                match map.next_key::<String>()? {
-                    Some(ref key) if key == "name" => Ok(KernelModule::Name(map.next_value()?)),
-                    Some(ref key) if key == "path" => Ok(KernelModule::Path(map.next_value()?)),
-                    _ => Err(Error::custom("missing one of 'name' or 'path'".to_string())),
+                let mut source = None;
+                let mut deps = true;
+                let mut softdeps = true;
+
+                while let Some(key) = map.next_key::<String>()? {
+                    let value = match key.as_str() {
+                        "name" => ModuleSource::Name(map.next_value()?),
+                        "path" => ModuleSource::Path(map.next_value()?),
+                        "deps" => {
+                            deps = map.next_value()?;
+                            continue;
+                        }
+                        "softdeps" => {
+                            softdeps = map.next_value()?;
+                            continue;
+                        }
+                        other => {
+                            return Err(Error::unknown_field(
+                                other,
+                                &["name", "path", "deps", "softdeps"],
+                            ))
+                        }
+                    };
+
+                    if source.replace(value).is_some() {
+                        return Err(Error::custom("only one of 'name' or 'path' is allowed"));
+                    }
                 }
+
+                let Some(source) = source else {
+                    return Err(Error::custom("missing one of 'name' or 'path'".to_string()));
+                };
+
+                Ok(KernelModule {
+                    source,
+                    deps,
+                    softdeps,
+                })
             }
         }
 
@@ -233,10 +1470,30 @@ impl<'de> Deserialize<'de> for KernelModule {
 }
 
 /// Configuration for a systemd unit.
-#[derive(Debug)]
+#[derive(Serialize, Debug)]
 pub struct Unit {
-    /// Name of the unit to include.
-    pub name: String,
+    /// Name of the unit to include, or a glob pattern matched against the unit
+    /// files of every search path (e.g. `systemd-udevd*.{service,socket}`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Target whose `.wants` and `.requires` directories list the units to
+    /// include.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wants_of: Option<String>,
+    /// Do not fail when the unit is missing, or when the pattern or target
+    /// matches no unit.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+impl std::fmt::Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.name, &self.wants_of) {
+            (Some(name), _) => write!(f, "{name}"),
+            (None, Some(target)) => write!(f, "wants_of: {target}"),
+            (None, None) => Ok(()),
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for Unit {
@@ -253,7 +1510,10 @@ impl<'de> Deserialize<'de> for Unit {
             type Value = Unit;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                write!(formatter, "a string or a map with one of 'name' or 'path'")
+                write!(
+                    formatter,
+                    "a string or a map with one of 'name' or 'wants_of'"
+                )
             }
 
             fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -261,7 +1521,9 @@ impl<'de> Deserialize<'de> for Unit {
                 E: Error,
             {
                 Ok(Unit {
-                    name: v.to_string(),
+                    name: Some(v.to_string()),
+                    wants_of: None,
+                    optional: false,
                 })
             }
 
@@ -269,11 +1531,29 @@ impl<'de> Deserialize<'de> for Unit {
             where
                 M: MapAccess<'de>,
             {
-                match map.next_key::<String>()? {
-                    Some(ref key) if key == "name" => Ok(Unit {
-                        name: map.next_value()?,
-                    }),
-                    _ => Err(Error::custom("missing key 'name'".to_string())),
+                let mut unit = Unit {
+                    name: None,
+                    wants_of: None,
+                    optional: false,
+                };
+
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "name" => unit.name = Some(map.next_value()?),
+                        "wants_of" => unit.wants_of = Some(map.next_value()?),
+                        "optional" => unit.optional = map.next_value()?,
+                        other => {
+                            return Err(Error::unknown_field(
+                                other,
+                                &["name", "wants_of", "optional"],
+                            ))
+                        }
+                    }
+                }
+
+                match (&unit.name, &unit.wants_of) {
+                    (Some(_), None) | (None, Some(_)) => Ok(unit),
+                    _ => Err(Error::custom("expected one of 'name' or 'wants_of'")),
                 }
             }
         }
@@ -281,3 +1561,367 @@ impl<'de> Deserialize<'de> for Unit {
         deserializer.deserialize_any(UnitVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(document: &str) -> Result<File, String> {
+        serde_yaml::from_str(document).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_emergency() {
+        let settings: Settings = serde_yaml::from_str("emergency: systemd").unwrap();
+        assert_eq!(settings.emergency, Emergency::Systemd);
+
+        let settings: Settings =
+            serde_yaml::from_str("emergency:\n  busybox:\n    path: /usr/bin/busybox").unwrap();
+        assert_eq!(
+            settings.emergency,
+            Emergency::Busybox {
+                path: PathBuf::from("/usr/bin/busybox")
+            }
+        );
+
+        let settings: Settings = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(settings.emergency, Emergency::None);
+    }
+
+    #[test]
+    fn test_bare_init() {
+        let config: Initramfs =
+            serde_yaml::from_str("settings:\n  bare: true\nmodules: []\n").unwrap();
+        assert!(config.settings.bare);
+        assert_eq!(config.init, None);
+
+        let err = serde_yaml::from_str::<Initramfs>("modules: [base]\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("init is required unless settings.bare is set"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_top_level_keys() {
+        let document = "amd_ucode: /lib/firmware/amd-ucode\ninit: /init\nmodules: []\n";
+        let initramfs: Initramfs = serde_yaml::from_str(document).unwrap();
+        let microcode: Microcode = serde_yaml::from_str(document).unwrap();
+        assert_eq!(initramfs.init, Some(Init::Path(PathBuf::from("/init"))));
+        assert_eq!(
+            microcode.amd_ucode,
+            Some(PathBuf::from("/lib/firmware/amd-ucode"))
+        );
+
+        // a typo would otherwise silently drop every setting
+        let typo = "init: /init\nmodules: []\nsetings:\n  strip: true\n";
+        let err = serde_yaml::from_str::<Initramfs>(typo).unwrap_err();
+        assert!(err.to_string().contains("unknown field `setings`"), "{err}");
+
+        let err = serde_yaml::from_str::<Microcode>("amd_ucod: /ucode\n").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `amd_ucod`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_init_wrapper() {
+        let config: Initramfs = serde_yaml::from_str(
+            "init: { use: tini, args: '--', exec: /usr/bin/myapp }\nmodules: []\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.init,
+            Some(Init::Wrapper(InitWrapper {
+                helper: PathBuf::from("tini"),
+                args: Some("--".to_string()),
+                exec: PathBuf::from("/usr/bin/myapp"),
+            }))
+        );
+
+        let config: Initramfs = serde_yaml::from_str("init: /sbin/init\nmodules: []\n").unwrap();
+        assert_eq!(config.init, Some(Init::Path(PathBuf::from("/sbin/init"))));
+    }
+
+    #[test]
+    fn test_module_round_trip() {
+        let module: Module = serde_yaml::from_str(
+            r#"
+name: base
+binaries:
+  - ls
+  - directory: /usr/lib/systemd/system-generators
+    recursive: true
+    strip: false
+files:
+  - sources: [/etc/passwd]
+    destination: /etc
+    install: { file_mode: "0600" }
+  - destination: /etc/hostname
+    content: initrd
+  - destination: /etc/blob
+    content_base64: AP8=
+    mode: 0600
+kernel_modules:
+  - ext4
+  - { path: /opt/zfs.ko, softdeps: false }
+units: [systemd-udevd.service]
+constraints: { arch: [x86_64], kernel_version: ">=6.1" }
+"#,
+        )
+        .unwrap();
+
+        let normalized = serde_yaml::to_string(&module).unwrap();
+        let parsed: Module = serde_yaml::from_str(&normalized).unwrap();
+
+        // shorthand strings are written as the equivalent map
+        assert_eq!(serde_yaml::to_string(&parsed).unwrap(), normalized);
+        assert!(normalized.contains("- path: ls\n  recursive: false\n  resolve_deps: true\n"));
+        assert!(normalized.contains("- name: ext4\n  deps: true\n  softdeps: true\n"));
+        assert!(normalized.contains("- name: systemd-udevd.service\n"));
+        assert!(normalized.contains("file_mode: '0600'"));
+        assert!(normalized.contains("content_base64: AP8="));
+        assert!(normalized.contains("kernel_version: '>=6.1'"));
+
+        assert_eq!(parsed.binaries[0].path, PathBuf::from("ls"));
+        assert!(parsed.binaries[0].resolve_deps);
+        assert!(parsed.binaries[1].recursive);
+        assert_eq!(parsed.files[1].content.as_deref(), Some(&b"initrd"[..]));
+        assert_eq!(parsed.files[2].content.as_deref(), Some(&[0, 0xff][..]));
+        assert_eq!(parsed.files[2].mode, Some(Mode(0o600)));
+        assert!(!parsed.kernel_modules[1].softdeps);
+        assert_eq!(
+            module.summary(),
+            "binaries: 2, files: 3, kernel modules: 2, units: 1"
+        );
+    }
+
+    #[test]
+    fn test_inline_content() {
+        let file = parse("destination: /etc/vconsole.conf\ncontent: |\n  KEYMAP=us\n  FONT=lat9w-16\nmode: \"0600\"\n").unwrap();
+        assert_eq!(
+            file.content.as_deref(),
+            Some(&b"KEYMAP=us\nFONT=lat9w-16\n"[..])
+        );
+        assert_eq!(file.mode, Some(Mode(0o600)));
+        assert!(file.sources.is_empty());
+
+        let file = parse("destination: /etc/key\ncontent_base64: |\n  AAEC\n  /w==\n").unwrap();
+        assert_eq!(file.content.as_deref(), Some(&[0, 1, 2, 255][..]));
+
+        let errors = [
+            (
+                "destination: /etc\n",
+                "one of sources, content, content_base64 or remote is required",
+            ),
+            (
+                "sources: [/etc/passwd]\ndestination: /etc\ncontent: x\n",
+                "sources and inline content are mutually exclusive",
+            ),
+            (
+                "destination: /etc/a\ncontent: x\ncontent_base64: eA==\n",
+                "content and content_base64 are mutually exclusive",
+            ),
+            (
+                "destination: /etc/a\ncontent_base64: \"e@==\"\n",
+                "invalid content_base64",
+            ),
+            (
+                "destination: /etc/a\ncontent_base64: eA=\n",
+                "invalid content_base64",
+            ),
+            (
+                "sources: [/etc/passwd]\ndestination: /etc\nmode: \"0600\"\n",
+                "mode only applies to inline and remote content",
+            ),
+            (
+                "destination: /etc/a\ncontent: x\nsecret_filter: allow\n",
+                "only apply to sources",
+            ),
+            (
+                "sources: [/etc/passwd]\ndestination: etc\n",
+                "path in the initramfs must be absolute",
+            ),
+            (
+                "destination: /etc/../a\ncontent: x\n",
+                "path in the initramfs cannot contain '..'",
+            ),
+        ];
+
+        for (document, expected) in errors {
+            let message = parse(document).unwrap_err();
+            assert!(message.contains(expected), "{document:?}: {message}");
+        }
+    }
+    #[test]
+    fn test_rename() {
+        let file =
+            parse("sources: [contrib/files/init-single.sh]\ndestination_file: /etc/rc.local\n")
+                .unwrap();
+        assert!(file.sources.is_empty());
+        assert_eq!(file.destination, ImagePath::new("/etc").unwrap());
+        assert_eq!(file.renamed.len(), 1);
+        assert_eq!(
+            file.renamed[0].path,
+            HostPath::from("contrib/files/init-single.sh")
+        );
+        assert_eq!(file.renamed[0].rename, "rc.local");
+
+        let file = parse(
+            "sources:\n  - /etc/passwd\n  - { path: contrib/hosts.initrd, rename: hosts }\ndestination: /etc\n",
+        )
+        .unwrap();
+        assert_eq!(file.sources, vec![HostPath::from("/etc/passwd")]);
+        assert_eq!(file.renamed[0].rename, "hosts");
+
+        // normalized to the per-source form
+        let normalized = serde_yaml::to_string(&file).unwrap();
+        assert!(
+            normalized.contains("- /etc/passwd\n- path: contrib/hosts.initrd\n  rename: hosts\n")
+        );
+        assert_eq!(parse(&normalized).unwrap().renamed.len(), 1);
+
+        let errors = [
+            (
+                "sources: [/a]\ndestination: /etc\ndestination_file: /etc/a\n",
+                "destination and destination_file are mutually exclusive",
+            ),
+            (
+                "sources: [/a]\n",
+                "one of destination or destination_file is required",
+            ),
+            (
+                "sources: [/a, /b]\ndestination_file: /etc/a\n",
+                "destination_file requires exactly one source",
+            ),
+            (
+                "sources: [{ path: /a, rename: b }]\ndestination_file: /etc/a\n",
+                "destination_file requires exactly one source",
+            ),
+            (
+                "content: x\ndestination_file: /etc/a\n",
+                "destination_file only applies to sources",
+            ),
+            (
+                "sources: [/a]\ndestination_file: /\n",
+                "destination_file must be the path of a file",
+            ),
+            (
+                "sources: [{ path: /a, rename: b/c }]\ndestination: /etc\n",
+                "invalid rename 'b/c' for /a",
+            ),
+            (
+                "sources: [{ path: /a, rename: .. }]\ndestination: /etc\n",
+                "invalid rename '..' for /a",
+            ),
+        ];
+
+        for (document, expected) in errors {
+            let message = parse(document).unwrap_err();
+            assert!(message.contains(expected), "{document:?}: {message}");
+        }
+    }
+
+    #[test]
+    fn test_remote() {
+        let digest = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+
+        let file = parse(&format!(
+            "destination: /usr/bin/rescue\nremote:\n  url: https://example.com/rescue\n  sha256: {digest}\nmode: \"0755\"\n"
+        ))
+        .unwrap();
+        let remote = file.remote.unwrap();
+        assert_eq!(remote.url, "https://example.com/rescue");
+        assert_eq!(remote.sha256, digest.to_ascii_lowercase());
+        assert!(file.sources.is_empty());
+
+        let binary: Binary = serde_yaml::from_str(&format!(
+            "path: /usr/bin/rescue\nremote: {{ url: 'file:///srv/rescue', sha256: {digest} }}\n"
+        ))
+        .unwrap();
+        assert!(binary.remote.is_some());
+
+        let errors = [
+            (
+                "destination: /a\nremote: { url: 'https://example.com/a' }\n".to_string(),
+                "must be pinned with sha256",
+            ),
+            (
+                "destination: /a\nremote: { url: 'https://example.com/a', sha256: abc }\n"
+                    .to_string(),
+                "invalid sha256",
+            ),
+            (
+                format!("destination: /a\ncontent: x\nremote: {{ url: 'file:///a', sha256: {digest} }}\n"),
+                "remote is mutually exclusive",
+            ),
+        ];
+
+        for (document, expected) in errors {
+            let message = parse(&document).unwrap_err();
+            assert!(message.contains(expected), "{document:?}: {message}");
+        }
+
+        let binary = serde_yaml::from_str::<Binary>(&format!(
+            "path: rescue\nremote: {{ url: 'file:///a', sha256: {digest} }}\n"
+        ));
+        assert!(binary.is_err());
+    }
+
+    #[test]
+    fn test_image_paths() {
+        let symlink: Result<Symlink, _> = serde_yaml::from_str(
+            "path: bin/sh
+target: busybox
+",
+        );
+        assert!(symlink.is_err());
+
+        let template: Result<Template, _> = serde_yaml::from_str(
+            "destination: etc/hostname
+content: host
+",
+        );
+        assert!(template.is_err());
+
+        let skeleton: Skeleton = serde_yaml::from_str(
+            "extra_dirs: [/sysroot/./]
+",
+        )
+        .unwrap();
+        assert_eq!(skeleton.extra_dirs, [PathBuf::from("/sysroot")]);
+    }
+
+    #[test]
+    fn test_units() {
+        let units: Vec<Unit> = serde_yaml::from_str(
+            "- systemd-udevd*.{service,socket}
+- { wants_of: sysinit.target, optional: true }
+- { name: plymouth-start.service }
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            units[0].name.as_deref(),
+            Some("systemd-udevd*.{service,socket}")
+        );
+        assert_eq!(units[1].wants_of.as_deref(), Some("sysinit.target"));
+        assert!(units[1].optional);
+        assert_eq!(units[1].to_string(), "wants_of: sysinit.target");
+        assert!(!units[2].optional);
+
+        for document in [
+            "{ name: a.service, wants_of: sysinit.target }",
+            "{ optional: true }",
+            "{ name: a.service, path: /a }",
+        ] {
+            assert!(
+                serde_yaml::from_str::<Unit>(document).is_err(),
+                "{document}"
+            );
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Legacy TOML configuration and migration to the current format.
+//!
+//! Older versions of elusive were configured with a single TOML file, where
+//! everything included in the initramfs was listed in the top-level file:
+//!
+//! ```toml
+//! [initramfs]
+//! init = "/usr/share/elusive/init"
+//! module_path = "/lib/modules/6.1.0"
+//! uncompress_modules = true
+//!
+//! [[initramfs.bin]]
+//! path = "/bin/busybox"
+//!
+//! [[initramfs.lib]]
+//! path = "/usr/lib/libgcc_s.so.1"
+//!
+//! [[initramfs.tree]]
+//! path = "/etc"
+//! copy = ["/etc/passwd", "/etc/group"]
+//!
+//! [[initramfs.module]]
+//! name = "ext4"
+//!
+//! [[initramfs.symlink]]
+//! path = "/bin/sh"
+//! target = "busybox"
+//!
+//! [microcode]
+//! amd_ucode = "/lib/firmware/amd-ucode"
+//! ```
+//!
+//! Migration produces a top-level YAML file and a single module containing
+//! everything that used to be listed in the initramfs section.
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::path::{Path, PathBuf};
+
+/// Name of the module produced by migration.
+pub const MIGRATED_MODULE: &str = "migrated";
+
+/// Legacy top-level configuration.
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    /// Initramfs section.
+    pub initramfs: Option<Initramfs>,
+    /// Microcode section.
+    pub microcode: Option<Microcode>,
+}
+
+/// Legacy initramfs configuration.
+#[derive(Deserialize, Debug)]
+pub struct Initramfs {
+    /// Path to the init script.
+    pub init: PathBuf,
+    /// Override path where kernel modules are searched.
+    pub module_path: Option<PathBuf>,
+    /// Decompress kernel modules before adding them.
+    pub uncompress_modules: Option<bool>,
+    /// Binaries to include.
+    #[serde(default = "Vec::new")]
+    pub bin: Vec<Binary>,
+    /// Libraries to include.
+    #[serde(default = "Vec::new")]
+    pub lib: Vec<Library>,
+    /// Filesystem trees to copy.
+    #[serde(default = "Vec::new")]
+    pub tree: Vec<Tree>,
+    /// Kernel modules to include.
+    #[serde(default = "Vec::new")]
+    pub module: Vec<Module>,
+    /// Symlinks to create.
+    #[serde(default = "Vec::new")]
+    pub symlink: Vec<Symlink>,
+}
+
+/// Legacy microcode configuration, identical to the current one.
+#[derive(Deserialize, Debug)]
+pub struct Microcode {
+    /// The path to the AMD specific blobs.
+    pub amd_ucode: Option<PathBuf>,
+    /// The path to the Intel specific blobs.
+    pub intel_ucode: Option<PathBuf>,
+}
+
+/// Legacy binary entry.
+#[derive(Deserialize, Debug)]
+pub struct Binary {
+    /// Path to the binary.
+    pub path: PathBuf,
+}
+
+/// Legacy library entry.
+#[derive(Deserialize, Debug)]
+pub struct Library {
+    /// Path to the library.
+    pub path: PathBuf,
+}
+
+/// Legacy filesystem tree entry.
+#[derive(Deserialize, Debug)]
+pub struct Tree {
+    /// The destination in the initramfs.
+    pub path: PathBuf,
+    /// The files and directories to copy.
+    pub copy: Vec<PathBuf>,
+}
+
+/// Legacy kernel module entry.
+#[derive(Deserialize, Debug)]
+pub struct Module {
+    /// Name of the kernel module.
+    pub name: Option<String>,
+    /// Path to the kernel module.
+    pub path: Option<PathBuf>,
+}
+
+/// Legacy symlink entry.
+#[derive(Deserialize, Debug)]
+pub struct Symlink {
+    /// The path where the symlink will be placed.
+    pub path: PathBuf,
+    /// The file the symlink points to.
+    pub target: PathBuf,
+}
+
+/// Result of a migration, as YAML documents ready to be written.
+#[derive(Debug)]
+pub struct Migration {
+    /// Top-level configuration.
+    pub config: String,
+    /// Module configuration, if the legacy file had an initramfs section.
+    pub module: Option<String>,
+}
+
+impl Config {
+    /// Convert this configuration to the current format. Settings that cannot
+    /// be translated are written as comments at the top of the documents.
+    pub fn migrate(&self) -> Result<Migration, serde_yaml::Error> {
+        let mut notes = Vec::new();
+        let mut config = Mapping::new();
+
+        if let Some(microcode) = &self.microcode {
+            insert_path(&mut config, "amd_ucode", microcode.amd_ucode.as_deref());
+            insert_path(&mut config, "intel_ucode", microcode.intel_ucode.as_deref());
+        }
+
+        let module = match &self.initramfs {
+            Some(initramfs) => {
+                insert_path(&mut config, "init", Some(&initramfs.init));
+
+                let mut settings = Mapping::new();
+                let module_path = initramfs.module_path.as_deref();
+                insert_path(&mut settings, "kernel_module_path", module_path);
+
+                // otherwise modules are compressed the way the kernel expects
+                if initramfs.uncompress_modules == Some(true) {
+                    settings.insert("module_compression".into(), "none".into());
+                }
+
+                if !settings.is_empty() {
+                    config.insert("settings".into(), settings.into());
+                }
+
+                let modules = vec![Value::from(MIGRATED_MODULE)];
+                config.insert("modules".into(), modules.into());
+
+                let module = initramfs.migrate_module(&mut notes);
+                Some(document(&[], &module)?)
+            }
+            None => None,
+        };
+
+        Ok(Migration {
+            config: document(&notes, &config)?,
+            module,
+        })
+    }
+}
+
+impl Initramfs {
+    fn migrate_module(&self, notes: &mut Vec<String>) -> Mapping {
+        let mut module = Mapping::new();
+        module.insert("name".into(), MIGRATED_MODULE.into());
+
+        // libraries are ELF files too, they are handled the same way
+        let binaries: Vec<Value> = self
+            .bin
+            .iter()
+            .map(|bin| bin.path.as_path())
+            .chain(self.lib.iter().map(|lib| lib.path.as_path()))
+            .map(path_value)
+            .collect();
+
+        let files: Vec<Value> = self
+            .tree
+            .iter()
+            .map(|tree| {
+                let mut file = Mapping::new();
+                let sources: Vec<Value> = tree.copy.iter().map(|path| path_value(path)).collect();
+
+                file.insert("sources".into(), sources.into());
+                file.insert("destination".into(), path_value(&tree.path));
+                file.into()
+            })
+            .collect();
+
+        let symlinks: Vec<Value> = self
+            .symlink
+            .iter()
+            .map(|symlink| {
+                let mut entry = Mapping::new();
+                entry.insert("path".into(), path_value(&symlink.path));
+                entry.insert("target".into(), path_value(&symlink.target));
+                entry.into()
+            })
+            .collect();
+
+        let mut kernel_modules = Vec::new();
+        for module in &self.module {
+            match (&module.name, &module.path) {
+                (Some(name), _) => kernel_modules.push(Value::from(name.as_str())),
+                (None, Some(path)) => {
+                    let mut entry = Mapping::new();
+                    entry.insert("path".into(), path_value(path));
+                    kernel_modules.push(entry.into());
+                }
+                (None, None) => notes.push("kernel module without name or path skipped".into()),
+            }
+        }
+
+        for (key, values) in [
+            ("binaries", binaries),
+            ("files", files),
+            ("symlinks", symlinks),
+            ("kernel_modules", kernel_modules),
+        ] {
+            if !values.is_empty() {
+                module.insert(key.into(), values.into());
+            }
+        }
+
+        module
+    }
+}
+
+fn path_value(path: &Path) -> Value {
+    path.to_string_lossy().into_owned().into()
+}
+
+fn insert_path(map: &mut Mapping, key: &str, path: Option<&Path>) {
+    if let Some(path) = path {
+        map.insert(key.into(), path_value(path));
+    }
+}
+
+// serialize a mapping with a header of comments
+fn document(notes: &[String], map: &Mapping) -> Result<String, serde_yaml::Error> {
+    let mut document = String::from("# Migrated from a legacy elusive TOML configuration\n");
+
+    for note in notes {
+        document.push_str(&format!("# NOTE: {note}\n"));
+    }
+
+    document.push_str(&serde_yaml::to_string(map)?);
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config;
+
+    const LEGACY: &str = r#"
+[initramfs]
+init = "/usr/share/elusive/init"
+module_path = "/lib/modules/6.1.0"
+uncompress_modules = true
+
+[[initramfs.bin]]
+path = "/bin/busybox"
+
+[[initramfs.lib]]
+path = "/usr/lib/libgcc_s.so.1"
+
+[[initramfs.tree]]
+path = "/etc"
+copy = ["/etc/passwd", "/etc/group"]
+
+[[initramfs.module]]
+name = "ext4"
+
+[[initramfs.module]]
+path = "/opt/modules/zfs.ko"
+
+[[initramfs.symlink]]
+path = "/bin/sh"
+target = "busybox"
+
+[microcode]
+amd_ucode = "/lib/firmware/amd-ucode"
+"#;
+
+    #[test]
+    fn test_migrate() {
+        let legacy: Config = toml::from_str(LEGACY).unwrap();
+        let migration = legacy.migrate().unwrap();
+
+        let initramfs: config::Initramfs = serde_yaml::from_str(&migration.config).unwrap();
+        let microcode: config::Microcode = serde_yaml::from_str(&migration.config).unwrap();
+        let module: config::Module = serde_yaml::from_str(&migration.module.unwrap()).unwrap();
+
+        assert_eq!(
+            initramfs.init,
+            Some(config::Init::Path(PathBuf::from("/usr/share/elusive/init")))
+        );
+        assert_eq!(initramfs.modules, [MIGRATED_MODULE]);
+        assert_eq!(
+            initramfs.settings.kernel_module_path,
+            Some(PathBuf::from("/lib/modules/6.1.0"))
+        );
+        assert_eq!(
+            initramfs.settings.module_compression,
+            Some(crate::kmod::ModuleCompression::None)
+        );
+        assert_eq!(
+            microcode.amd_ucode,
+            Some(PathBuf::from("/lib/firmware/amd-ucode"))
+        );
+        assert_eq!(microcode.intel_ucode, None);
+
+        let binaries: Vec<_> = module.binaries.iter().map(|bin| &bin.path).collect();
+        assert_eq!(binaries, ["/bin/busybox", "/usr/lib/libgcc_s.so.1"]);
+
+        assert_eq!(
+            module.files[0].sources,
+            [Path::new("/etc/passwd"), Path::new("/etc/group")]
+        );
+        assert_eq!(module.files[0].destination, PathBuf::from("/etc"));
+        assert_eq!(module.symlinks[0].path, PathBuf::from("/bin/sh"));
+        assert_eq!(module.symlinks[0].target, PathBuf::from("busybox"));
+
+        assert!(matches!(
+            &module.kernel_modules[0].source,
+            config::ModuleSource::Name(name) if name == "ext4"
+        ));
+        assert!(matches!(
+            &module.kernel_modules[1].source,
+            config::ModuleSource::Path(path) if path == Path::new("/opt/modules/zfs.ko")
+        ));
+    }
+
+    #[test]
+    fn test_migrate_microcode_only() {
+        let legacy: Config = toml::from_str("[microcode]\nintel_ucode = \"/ucode\"\n").unwrap();
+        let migration = legacy.migrate().unwrap();
+
+        let microcode: config::Microcode = serde_yaml::from_str(&migration.config).unwrap();
+
+        assert!(migration.module.is_none());
+        assert_eq!(microcode.intel_ucode, Some(PathBuf::from("/ucode")));
+    }
+}
@@ -0,0 +1,988 @@
+//! Loading of the configuration from explicit paths.
+//!
+//! The top-level file is parsed first, then every module file found in the
+//! configuration directories, and finally the modules listed by the top-level
+//! file are resolved by name:
+//!
+//! ```no_run
+//! use elusive::config::loader::{self, ConfigPaths};
+//! use std::path::PathBuf;
+//!
+//! let paths = ConfigPaths {
+//!     config: PathBuf::from("/etc/elusive.yaml"),
+//!     confdirs: vec![PathBuf::from("/etc/elusive.d")],
+//! };
+//!
+//! let (config, modules) = loader::load_initramfs_config(&paths)?;
+//! # Ok::<(), loader::ConfigurationError>(())
+//! ```
+//!
+//! Nothing is read from default locations, callers provide every path.
+//!
+//! Module files are first scanned for the `name` of each of their documents,
+//! only the modules that are selected are then fully parsed.
+//!
+//! Directories are listed from lowest to highest priority: a module found in
+//! a directory replaces the module of the same name found in an earlier one.
+//! Within a directory, files are read in the order given by their numeric
+//! prefix (`10-base.yaml` before `20-udev.yaml`), then by name. The prefix
+//! only orders log messages, modules are always selected by their name.
+
+use super::{Initramfs, Module, Symlink};
+use crate::constraint::Platform;
+use crate::paths::ImagePath;
+
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigurationError {
+    #[error("default paths skipped but no fallback paths specified")]
+    SkipWithoutParameter,
+    #[error("configuration requires a module named '{0}' but none was found")]
+    UnknownModule(String),
+    #[error("configuration file is not a file or does not exist: {0}")]
+    ExpectedFile(PathBuf),
+    #[error("configuration directory is not a directory or does not exist: {0}")]
+    ExpectedDirectory(PathBuf),
+    #[error(
+        "invalid module configuration {}:{line}:{column}: {message}{}",
+        path.display(),
+        suggestion.as_ref().map(|s| format!(", did you mean '{s}'?")).unwrap_or_default()
+    )]
+    ModuleParse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+        suggestion: Option<String>,
+    },
+    #[error("failed to read symlink map {}: {source}", path.display())]
+    SymlinkMapRead { path: PathBuf, source: io::Error },
+    #[error("invalid symlink map {}:{line}: {message}", path.display())]
+    SymlinkMap {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    #[error(transparent)]
+    Parse(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl ConfigurationError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConfigurationError::SkipWithoutParameter => "config_skip_without_parameter",
+            ConfigurationError::UnknownModule(_) => "config_unknown_module",
+            ConfigurationError::ExpectedFile(_) => "config_expected_file",
+            ConfigurationError::ExpectedDirectory(_) => "config_expected_directory",
+            ConfigurationError::ModuleParse { .. } => "config_module_parse",
+            ConfigurationError::SymlinkMapRead { .. } | ConfigurationError::SymlinkMap { .. } => {
+                "config_symlink_map"
+            }
+            ConfigurationError::Parse(_) => "config_parse",
+            ConfigurationError::Io(_) => "io",
+        }
+    }
+
+    /// Wrap a module deserialization error with the path of the file and, for
+    /// unknown fields, the closest valid field name.
+    fn module_parse(path: &Path, offset: usize, err: &serde_yaml::Error) -> Self {
+        let (line, column) = err.location().map_or((0, 0), |location| {
+            (offset + location.line(), location.column())
+        });
+
+        // location is already part of the message
+        let mut message = err.to_string();
+        if let Some(index) = message.rfind(" at line ") {
+            message.truncate(index);
+        }
+
+        ConfigurationError::ModuleParse {
+            path: path.to_path_buf(),
+            line,
+            column,
+            suggestion: suggest_field(&message),
+            message,
+        }
+    }
+}
+
+/// Number of selected modules above which they are parsed in parallel.
+const PARALLEL_THRESHOLD: usize = 8;
+
+/// Paths configuration is loaded from.
+#[derive(Clone, Debug)]
+pub struct ConfigPaths {
+    /// Top-level configuration file.
+    pub config: PathBuf,
+    /// Directories containing module configuration files, from lowest to
+    /// highest priority.
+    pub confdirs: Vec<PathBuf>,
+}
+
+/// A module replaced by one of the same name in a higher priority directory.
+#[derive(PartialEq, Debug)]
+pub struct Override {
+    /// Name of the module.
+    pub name: String,
+    /// File of the module that is used.
+    pub path: PathBuf,
+    /// File of the module that was replaced.
+    pub replaced: PathBuf,
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Module {} from {} overrides {} (later directories have priority)",
+            self.name,
+            self.path.display(),
+            self.replaced.display()
+        )
+    }
+}
+
+/// A module found in the configuration directories, see [`list_modules`].
+#[derive(Debug)]
+pub struct Listing {
+    /// Name of the module.
+    pub name: String,
+    /// File of the definition that is used.
+    pub path: PathBuf,
+    /// Files of the definitions it replaces, from highest priority.
+    pub replaced: Vec<PathBuf>,
+    /// The parsed module, or why it could not be parsed.
+    pub module: Result<Module, ConfigurationError>,
+}
+
+/// Load the top-level configuration and the modules it selects, in the order
+/// they are listed.
+///
+/// Module constraints are not checked, see [`filter_constraints`].
+pub fn load_initramfs_config(
+    paths: &ConfigPaths,
+) -> Result<(Initramfs, Vec<Module>), ConfigurationError> {
+    let config: Initramfs = read_config(&paths.config)?;
+    let modules = load_modules(&paths.confdirs, &config.modules)?;
+
+    Ok((config, modules))
+}
+
+/// Read and parse the top-level configuration file at the provided path.
+pub fn read_config<T>(path: &Path) -> Result<T, ConfigurationError>
+where
+    T: DeserializeOwned,
+{
+    if !path.exists() || !path.is_file() {
+        return Err(ConfigurationError::ExpectedFile(path.to_path_buf()));
+    }
+
+    debug!("Parsing top-level config file: {:?}", path);
+    let data = fs::read(path)?;
+
+    Ok(serde_yaml::from_slice(&data)?)
+}
+
+/// Parse all module configuration files available in the provided directories,
+/// from lowest to highest priority. Missing directories are skipped.
+///
+/// Every module is parsed and the first invalid one fails, use
+/// [`load_modules`] to only parse the modules that are needed.
+pub fn read_modules(paths: &[PathBuf]) -> Result<BTreeMap<String, Module>, ConfigurationError> {
+    let scan = scan_modules(paths)?;
+
+    if let Some((_, err)) = scan.unnamed.into_iter().next() {
+        return Err(err);
+    }
+
+    let documents: Vec<_> = scan.documents.values().collect();
+    let modules = parse_documents(&documents)?;
+
+    Ok(modules
+        .into_iter()
+        .map(|module| (module.name.clone(), module))
+        .collect())
+}
+
+/// Find the modules with the provided names in the configuration directories
+/// and parse them, in order.
+///
+/// Files are scanned for module names and only the selected modules are
+/// parsed, errors in other modules are not reported.
+pub fn load_modules(
+    paths: &[PathBuf],
+    names: &[String],
+) -> Result<Vec<Module>, ConfigurationError> {
+    let scan = scan_modules(paths)?;
+
+    for (path, err) in &scan.unnamed {
+        warn!(path:% = path.display(); "Skipping module config file without a valid name: {}", err);
+    }
+
+    let documents = names
+        .iter()
+        .map(|name| {
+            scan.documents
+                .get(name)
+                .ok_or_else(|| ConfigurationError::UnknownModule(name.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    parse_documents(&documents)
+}
+
+/// List every module found in the configuration directories by name, with the
+/// definitions it replaces. Invalid modules are listed along with their error.
+pub fn list_modules(paths: &[PathBuf]) -> Result<Vec<Listing>, ConfigurationError> {
+    let scan = scan_modules(paths)?;
+
+    for (path, err) in &scan.unnamed {
+        warn!(path:% = path.display(); "Skipping module config file without a valid name: {}", err);
+    }
+
+    let mut replaced: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+    for item in scan.overrides.iter().rev() {
+        replaced
+            .entry(item.name.as_str())
+            .or_default()
+            .push(item.replaced.clone());
+    }
+
+    Ok(scan
+        .documents
+        .iter()
+        .map(|(name, document)| Listing {
+            name: name.clone(),
+            path: document.path.clone(),
+            replaced: replaced.remove(name.as_str()).unwrap_or_default(),
+            module: document.parse(),
+        })
+        .collect())
+}
+
+/// A yaml document holding a module configuration.
+struct Document {
+    /// File the document was found in.
+    path: PathBuf,
+    /// Number of lines in the file before the document.
+    offset: usize,
+    text: String,
+}
+
+impl Document {
+    fn parse(&self) -> Result<Module, ConfigurationError> {
+        debug!(path:% = self.path.display(); "Parsing module config file: {:?}", self.path);
+
+        let mut module: Module = serde_yaml::from_str(&self.text)
+            .map_err(|err| ConfigurationError::module_parse(&self.path, self.offset, &err))?;
+
+        if let Some(map) = module.symlink_map.take() {
+            let dir = self.path.parent().unwrap_or(Path::new(""));
+            let path = dir.join(&map.path);
+
+            debug!(path:% = path.display(); "Reading symlink map: {:?}", path);
+            let data =
+                fs::read_to_string(&path).map_err(|source| ConfigurationError::SymlinkMapRead {
+                    path: path.clone(),
+                    source,
+                })?;
+
+            let symlinks = parse_symlink_map(&path, &data, map.relative)?;
+            module.symlinks.extend(symlinks);
+        }
+
+        Ok(module)
+    }
+}
+
+/// Parse the `path -> target` lines of a symlink map read from `path`, blank
+/// lines and comments are skipped.
+pub fn parse_symlink_map(
+    path: &Path,
+    data: &str,
+    relative: bool,
+) -> Result<Vec<Symlink>, ConfigurationError> {
+    let mut symlinks = Vec::new();
+
+    for (index, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |message: String| ConfigurationError::SymlinkMap {
+            path: path.to_path_buf(),
+            line: index + 1,
+            message,
+        };
+
+        let Some((link, target)) = line.split_once("->") else {
+            return Err(invalid(format!(
+                "expected 'path -> target', found '{line}'"
+            )));
+        };
+
+        let (link, target) = (link.trim(), target.trim());
+        if link.is_empty() || target.is_empty() {
+            return Err(invalid(format!(
+                "expected 'path -> target', found '{line}'"
+            )));
+        }
+
+        let link = ImagePath::new(link).map_err(|err| invalid(err.to_string()))?;
+
+        symlinks.push(Symlink {
+            path: link,
+            target: PathBuf::from(target),
+            relative,
+        });
+    }
+
+    Ok(symlinks)
+}
+
+/// Module documents found in configuration directories, by module name.
+struct Scan {
+    documents: BTreeMap<String, Document>,
+    /// Documents whose name could not be found, with the parse error.
+    unnamed: Vec<(PathBuf, ConfigurationError)>,
+    overrides: Vec<Override>,
+}
+
+/// Find the name of every module document without parsing them, the later
+/// definition of a module replaces the previous one.
+fn scan_modules(paths: &[PathBuf]) -> Result<Scan, ConfigurationError> {
+    let mut scan = Scan {
+        documents: BTreeMap::new(),
+        unnamed: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    for path in paths {
+        if !path.exists() {
+            continue;
+        }
+
+        if !path.is_dir() {
+            return Err(ConfigurationError::ExpectedDirectory(path.clone()));
+        }
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+
+        files.sort_by_cached_key(|path| file_order(path));
+
+        for path in files {
+            debug!(path:% = path.display(); "Scanning module config file: {:?}", path);
+            let data = fs::read_to_string(&path)?;
+            let documents = split_documents(&data);
+
+            if documents.is_empty() {
+                debug!(path:% = path.display(); "Skipping empty module config file: {:?}", path);
+                continue;
+            }
+
+            for (offset, text) in documents {
+                let document = Document {
+                    path: path.clone(),
+                    offset,
+                    text,
+                };
+
+                // fall back to parsing when the name is not a plain scalar
+                let name = match scan_name(&document.text) {
+                    Some(name) => name,
+                    None => match document.parse() {
+                        Ok(module) => module.name,
+                        Err(err) => {
+                            scan.unnamed.push((path.clone(), err));
+                            continue;
+                        }
+                    },
+                };
+
+                if let Some(replaced) = scan.documents.get(&name) {
+                    let item = Override {
+                        name: name.clone(),
+                        path: path.clone(),
+                        replaced: replaced.path.clone(),
+                    };
+
+                    info!(module = name.as_str(); "{}", item);
+                    scan.overrides.push(item);
+                }
+
+                scan.documents.insert(name, document);
+            }
+        }
+    }
+
+    Ok(scan)
+}
+
+/// Parse module documents, in parallel when there are more than a few.
+fn parse_documents(documents: &[&Document]) -> Result<Vec<Module>, ConfigurationError> {
+    if documents.len() > PARALLEL_THRESHOLD {
+        documents
+            .par_iter()
+            .map(|document| document.parse())
+            .collect()
+    } else {
+        documents.iter().map(|document| document.parse()).collect()
+    }
+}
+
+/// Split a yaml stream into its non-empty documents, along with the number of
+/// lines before each of them.
+fn split_documents(data: &str) -> Vec<(usize, String)> {
+    let mut documents = Vec::new();
+    let mut current = String::new();
+    let mut offset = 0;
+
+    let mut finish = |current: &mut String, offset: usize| {
+        if !is_empty_document(current) {
+            documents.push((offset, std::mem::take(current)));
+        }
+
+        current.clear();
+    };
+
+    for (index, line) in data.lines().enumerate() {
+        if line == "..." {
+            finish(&mut current, offset);
+            offset = index + 1;
+            continue;
+        }
+
+        if line == "---" || line.starts_with("--- ") {
+            finish(&mut current, offset);
+
+            // content may follow the marker on the same line
+            offset = index;
+            line.trim_start_matches('-')
+                .trim_start()
+                .clone_into(&mut current);
+            current.push('\n');
+            continue;
+        }
+
+        current.push_str(line);
+        current.push('\n');
+    }
+
+    finish(&mut current, offset);
+    documents
+}
+
+/// Find the value of the top-level `name` key of a document when it is a
+/// plain or quoted scalar on a single line.
+fn scan_name(text: &str) -> Option<String> {
+    let line = text.lines().find(|line| line.starts_with("name:"))?;
+    let value = line["name:".len()..].split(" #").next()?.trim();
+
+    let name = if let Some(quoted) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        quoted.replace("\'\'", "\'")
+    } else if let Some(quoted) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        if quoted.contains('\\') {
+            return None;
+        }
+
+        quoted.to_string()
+    } else {
+        // anchors, aliases, tags, block and flow values need a parser
+        if value.is_empty() || value.starts_with(['&', '*', '!', '|', '>', '[', '{', '"', '\'']) {
+            return None;
+        }
+
+        value.to_string()
+    };
+
+    Some(name)
+}
+
+/// Sort key of a module file: files with a numeric prefix come first, in
+/// numeric order, then the others by name.
+fn file_order(path: &Path) -> (bool, u64, PathBuf) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let prefix = name
+        .split_once('-')
+        .and_then(|(prefix, _)| prefix.parse::<u64>().ok());
+
+    (
+        prefix.is_none(),
+        prefix.unwrap_or_default(),
+        path.to_path_buf(),
+    )
+}
+
+/// Take the modules with the provided names out of the parsed ones, in order.
+pub fn select_modules(
+    modules: &mut BTreeMap<String, Module>,
+    names: &[String],
+) -> Result<Vec<Module>, ConfigurationError> {
+    names
+        .iter()
+        .map(|name| {
+            modules
+                .remove(name.as_str())
+                .ok_or_else(|| ConfigurationError::UnknownModule(name.clone()))
+        })
+        .collect()
+}
+
+/// Skip the modules whose constraints do not hold on the platform.
+pub fn filter_constraints(modules: Vec<Module>, platform: &Platform) -> Vec<Module> {
+    let mut selected = Vec::new();
+    let mut skipped = Vec::new();
+
+    for module in modules {
+        if let Err(reason) = module.constraints.check(platform) {
+            info!("Skipping module {} (constraint): {}", module.name, reason);
+            skipped.push(module.name);
+            continue;
+        }
+
+        selected.push(module);
+    }
+
+    if !skipped.is_empty() {
+        info!("Modules skipped (constraint): {}", skipped.join(", "));
+    }
+
+    selected
+}
+
+/// Check if a yaml document only contains whitespace, comments, directives or
+/// document markers.
+fn is_empty_document(data: &str) -> bool {
+    data.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with('%')
+            || line == "---"
+            || line == "..."
+    })
+}
+
+/// Find the closest expected field in a serde unknown field error message.
+fn suggest_field(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("unknown field `")?;
+    let (field, expected) = rest.split_once('`')?;
+
+    // expected field names are the odd items when splitting on backticks
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (edit_distance(field, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= candidate.len() / 3 + 1)
+        .min()
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(left: &str, right: &str) -> usize {
+    let right: Vec<char> = right.chars().collect();
+    let mut previous: Vec<usize> = (0..=right.len()).collect();
+
+    for (i, l) in left.chars().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, r) in right.iter().enumerate() {
+            let substitution = previous[j] + usize::from(l != *r);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+
+        previous = current;
+    }
+
+    previous[right.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    #[test]
+    fn test_load_initramfs_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let local = dir.join("local.d");
+        let vendor = dir.join("vendor.d");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(&vendor).unwrap();
+
+        let config = dir.join("elusive.yaml");
+        let unknown = dir.join("unknown.yaml");
+        fs::write(&config, "init: /init\nmodules: [udev, base]\n").unwrap();
+        fs::write(&unknown, "init: /init\nmodules: [base, nfs]\n").unwrap();
+
+        fs::write(local.join("base.yaml"), "name: base\nbinaries: [ls]\n").unwrap();
+        fs::write(local.join("udev.yaml"), "name: udev\n").unwrap();
+        fs::write(vendor.join("base.yaml"), "name: base\nbinaries: [sh]\n").unwrap();
+        fs::write(vendor.join("extra.yaml"), "name: extra\n").unwrap();
+
+        let paths = ConfigPaths {
+            config,
+            confdirs: vec![local, vendor, dir.join("missing")],
+        };
+        let loaded = load_initramfs_config(&paths);
+        let unknown = load_initramfs_config(&ConfigPaths {
+            config: unknown,
+            ..paths.clone()
+        });
+        let missing = load_initramfs_config(&ConfigPaths {
+            config: dir.join("missing.yaml"),
+            ..paths.clone()
+        });
+        let not_dir = load_initramfs_config(&ConfigPaths {
+            confdirs: vec![paths.config.clone()],
+            ..paths
+        });
+
+        let (config, modules) = loaded.unwrap();
+        assert_eq!(config.modules, ["udev", "base"]);
+
+        let names: Vec<_> = modules.iter().map(|module| module.name.as_str()).collect();
+        assert_eq!(names, ["udev", "base"]);
+        assert_eq!(modules[1].binaries.len(), 1);
+        assert_eq!(modules[1].binaries[0].path, Path::new("sh"));
+
+        let codes = [unknown, missing, not_dir].map(|result| result.unwrap_err().code());
+        assert_eq!(
+            codes,
+            [
+                "config_unknown_module",
+                "config_expected_file",
+                "config_expected_directory"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let vendor = dir.join("vendor.d");
+        let local = dir.join("local.d");
+        fs::create_dir_all(&vendor).unwrap();
+        fs::create_dir_all(&local).unwrap();
+
+        fs::write(vendor.join("base.yaml"), "name: base\nbinaries: [sh]\n").unwrap();
+        fs::write(vendor.join("udev.yaml"), "name: udev\n").unwrap();
+        fs::write(local.join("base.yaml"), "name: base\nbinaries: [ls]\n").unwrap();
+        fs::write(local.join("2-net.yaml"), "name: net\n").unwrap();
+        fs::write(local.join("10-net.yaml"), "name: net\nbinaries: [ip]\n").unwrap();
+
+        let confdirs = [vendor.clone(), local.clone()];
+        let modules = read_modules(&confdirs);
+        let scan = scan_modules(&confdirs);
+
+        let (modules, overrides) = (modules.unwrap(), scan.unwrap().overrides);
+        assert_eq!(modules.keys().collect::<Vec<_>>(), ["base", "net", "udev"]);
+        assert_eq!(modules["base"].binaries[0].path, Path::new("ls"));
+        assert_eq!(modules["net"].binaries[0].path, Path::new("ip"));
+
+        assert_eq!(
+            overrides,
+            [
+                Override {
+                    name: "net".to_string(),
+                    path: local.join("10-net.yaml"),
+                    replaced: local.join("2-net.yaml"),
+                },
+                Override {
+                    name: "base".to_string(),
+                    path: local.join("base.yaml"),
+                    replaced: vendor.join("base.yaml"),
+                },
+            ]
+        );
+        assert_eq!(
+            overrides[1].to_string(),
+            format!(
+                "Module base from {} overrides {} (later directories have priority)",
+                local.join("base.yaml").display(),
+                vendor.join("base.yaml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_list_modules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let vendor = dir.join("vendor.d");
+        let local = dir.join("local.d");
+        fs::create_dir_all(&vendor).unwrap();
+        fs::create_dir_all(&local).unwrap();
+
+        fs::write(vendor.join("base.yaml"), "name: base\nbinaries: [sh]\n").unwrap();
+        fs::write(
+            vendor.join("udev.yaml"),
+            "name: udev\nbinaires: [udevadm]\n",
+        )
+        .unwrap();
+        fs::write(local.join("1-base.yaml"), "name: base\n").unwrap();
+        fs::write(
+            local.join("2-base.yaml"),
+            "name: base\nbinaries: [ls, ip]\n",
+        )
+        .unwrap();
+
+        let confdirs = [vendor.clone(), local.clone()];
+        let listings = list_modules(&confdirs);
+        let unknown = load_modules(&confdirs, &["net".to_string()]);
+
+        let listings = listings.unwrap();
+        let names: Vec<_> = listings.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["base", "udev"]);
+
+        // the winning definition comes first, then what it replaces
+        assert_eq!(listings[0].path, local.join("2-base.yaml"));
+        assert_eq!(
+            listings[0].replaced,
+            [local.join("1-base.yaml"), vendor.join("base.yaml")]
+        );
+        assert_eq!(listings[0].module.as_ref().unwrap().binaries.len(), 2);
+
+        assert!(listings[1].replaced.is_empty());
+        assert_eq!(
+            listings[1].module.as_ref().unwrap_err().code(),
+            "config_module_parse"
+        );
+
+        let unknown = unknown.unwrap_err();
+        assert_eq!(unknown.code(), "config_unknown_module");
+        assert!(unknown.to_string().contains("'net'"));
+    }
+
+    #[test]
+    fn test_load_modules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(
+            dir.join("10-multi.yaml"),
+            "# storage modules\n---\nname: 'blk-ata'\nbinaries: [hdparm]\n---\n{name: blk-nvme}\n...\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("20-broken.yaml"),
+            "name: broken # unselected\nbinaires: [ls]\n---\nname: typo\nbinaries: {\n",
+        )
+        .unwrap();
+        fs::write(dir.join("30-garbage.yaml"), "name: [\n").unwrap();
+
+        let names = ["blk-nvme", "blk-ata"].map(String::from);
+        let loaded = load_modules(&[dir.to_path_buf()], &names);
+        let broken = load_modules(&[dir.to_path_buf()], &["typo".to_string()]);
+        let strict = read_modules(&[dir.to_path_buf()]);
+
+        let loaded = loaded.unwrap();
+        let names: Vec<_> = loaded.iter().map(|module| module.name.as_str()).collect();
+        assert_eq!(names, ["blk-nvme", "blk-ata"]);
+        assert_eq!(loaded[1].binaries[0].path, Path::new("hdparm"));
+
+        // line numbers are relative to the file, not the document
+        let ConfigurationError::ModuleParse { path, line, .. } = broken.unwrap_err() else {
+            panic!("expected a module parse error");
+        };
+        assert_eq!(path, dir.join("20-broken.yaml"));
+        assert_eq!(line, 5);
+
+        assert_eq!(strict.unwrap_err().code(), "config_module_parse");
+    }
+
+    #[test]
+    fn test_read_modules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let empty = dir.join("empty");
+        let typo = dir.join("typo");
+        fs::create_dir_all(&empty).unwrap();
+        fs::create_dir_all(&typo).unwrap();
+
+        fs::write(empty.join("99-local.yaml"), "# placeholder\n\n").unwrap();
+        fs::write(empty.join("base.yaml"), "name: base\n").unwrap();
+        fs::write(typo.join("typo.yaml"), "name: typo\nbinaires:\n  - ls\n").unwrap();
+
+        let modules = read_modules(slice::from_ref(&empty));
+        let err = read_modules(slice::from_ref(&typo)).unwrap_err();
+
+        assert_eq!(modules.unwrap().keys().collect::<Vec<_>>(), ["base"]);
+
+        let message = err.to_string();
+
+        let ConfigurationError::ModuleParse {
+            path,
+            line,
+            suggestion,
+            ..
+        } = err
+        else {
+            panic!("unexpected error: {message}");
+        };
+
+        assert_eq!(path, typo.join("typo.yaml"));
+        assert_eq!(line, 2);
+        assert_eq!(suggestion.as_deref(), Some("binaries"));
+        assert!(message.contains("typo.yaml:2:"), "{message}");
+        assert!(message.contains("did you mean 'binaries'?"), "{message}");
+    }
+
+    #[test]
+    fn test_parse_symlink_map() {
+        let path = Path::new("links.txt");
+        let symlinks = parse_symlink_map(
+            path,
+            "# converted from dracut\n\
+             \n\
+             /usr/bin/sh -> /usr/bin/bash\n\
+             \t/sbin/init->../usr/lib/systemd/systemd  \n\
+             /etc/mtab -> /proc/self/mounts # not a comment\n",
+            true,
+        )
+        .unwrap();
+
+        let links: Vec<_> = symlinks
+            .iter()
+            .map(|symlink| {
+                (
+                    symlink.path.as_path(),
+                    symlink.target.as_path(),
+                    symlink.relative,
+                )
+            })
+            .collect();
+        assert_eq!(
+            links,
+            [
+                (Path::new("/usr/bin/sh"), Path::new("/usr/bin/bash"), true),
+                (
+                    Path::new("/sbin/init"),
+                    Path::new("../usr/lib/systemd/systemd"),
+                    true
+                ),
+                (
+                    Path::new("/etc/mtab"),
+                    Path::new("/proc/self/mounts # not a comment"),
+                    true
+                ),
+            ]
+        );
+
+        let error = |data: &str| {
+            let err = parse_symlink_map(path, data, false).unwrap_err();
+            assert_eq!(err.code(), "config_symlink_map");
+            err.to_string()
+        };
+
+        assert_eq!(
+            error("# header\n/usr/bin/sh /usr/bin/bash\n"),
+            "invalid symlink map links.txt:2: expected 'path -> target', found '/usr/bin/sh /usr/bin/bash'"
+        );
+        assert_eq!(
+            error("/usr/bin/sh ->\n"),
+            "invalid symlink map links.txt:1: expected 'path -> target', found '/usr/bin/sh ->'"
+        );
+        assert_eq!(
+            error("\n\n\nusr/bin/sh -> bash\n"),
+            "invalid symlink map links.txt:4: path in the initramfs must be absolute: usr/bin/sh"
+        );
+    }
+
+    #[test]
+    fn test_symlink_map() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("maps")).unwrap();
+
+        fs::write(dir.join("maps/links.txt"), "/usr/bin/sh -> /usr/bin/bash\n").unwrap();
+        fs::write(
+            dir.join("dracut.yaml"),
+            "name: dracut
+\
+             symlinks:
+  - {path: /bin/ls, target: /usr/bin/ls}
+\
+             symlink_map: {path: maps/links.txt, relative: true}
+\
+             ---
+\
+             name: missing
+symlink_map: maps/missing.txt
+",
+        )
+        .unwrap();
+
+        let loaded = load_modules(&[dir.to_path_buf()], &["dracut".to_string()]);
+        let missing = load_modules(&[dir.to_path_buf()], &["missing".to_string()]);
+
+        let module = loaded.unwrap().remove(0);
+        assert!(module.symlink_map.is_none());
+        assert_eq!(module.symlinks.len(), 2);
+        assert_eq!(module.symlinks[1].path, Path::new("/usr/bin/sh"));
+        assert_eq!(module.symlinks[1].resolved_target(), Path::new("bash"));
+        assert_eq!(
+            module.symlinks[0].resolved_target(),
+            Path::new("/usr/bin/ls")
+        );
+
+        let missing = missing.unwrap_err();
+        assert_eq!(missing.code(), "config_symlink_map");
+        assert!(missing.to_string().contains("maps/missing.txt"));
+    }
+
+    #[test]
+    fn test_filter_constraints() {
+        let parse = |data: &str| serde_yaml::from_str::<Module>(data).unwrap();
+
+        let mut modules = BTreeMap::from([
+            ("base".to_string(), parse("name: base\n")),
+            (
+                "x86".to_string(),
+                parse("name: x86\nconstraints:\n  arch: [x86_64]\n"),
+            ),
+            (
+                "recent".to_string(),
+                parse("name: recent\nconstraints:\n  kernel_version: \">=6.1\"\n"),
+            ),
+        ]);
+        let platform = Platform {
+            arch: "aarch64".to_string(),
+            release: "6.6.31-rpi".to_string(),
+        };
+
+        let names = ["base", "x86", "recent"].map(String::from);
+        let selected = select_modules(&mut modules, &names).unwrap();
+        let selected = filter_constraints(selected, &platform);
+        let selected: Vec<_> = selected.iter().map(|module| module.name.as_str()).collect();
+
+        assert_eq!(selected, ["base", "recent"]);
+        assert!(modules.is_empty());
+        assert!(serde_yaml::from_str::<Module>(
+            "name: bad\nconstraints:\n  kernel_version: \"~5\"\n"
+        )
+        .is_err());
+    }
+}
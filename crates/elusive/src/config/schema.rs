@@ -0,0 +1,456 @@
+//! JSON Schema for configuration files, so editors can validate them.
+//!
+//! The schema is written by hand and has to be kept in sync with the
+//! configuration types, tests check the property names still match.
+
+use serde_json::{json, Value};
+
+const SCHEMA_DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+fn string_list() -> Value {
+    json!({ "type": "array", "items": { "type": "string" } })
+}
+
+fn size() -> Value {
+    json!({
+        "oneOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^\\s*[0-9]+\\s*([kKmMgG]([iI]?[bB])?|[bB])?\\s*$" }
+        ]
+    })
+}
+
+fn severity() -> Value {
+    json!({ "enum": ["warn", "error", "ignore", "off"] })
+}
+
+fn mode() -> Value {
+    json!({
+        "oneOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^(0o)?[0-7]{1,4}$" }
+        ]
+    })
+}
+
+fn remote() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["url", "sha256"],
+        "properties": {
+            "url": { "type": "string" },
+            "sha256": { "type": "string", "pattern": "^[0-9a-fA-F]{64}$" }
+        }
+    })
+}
+
+fn symlink() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["path", "target"],
+        "properties": {
+            "path": { "type": "string" },
+            "target": { "type": "string" },
+            "relative": { "type": "boolean" }
+        }
+    })
+}
+
+fn environment() -> Value {
+    json!({
+        "type": "object",
+        "propertyNames": { "pattern": "^[A-Za-z_][A-Za-z0-9_]*$" },
+        "additionalProperties": { "type": ["string", "integer", "boolean"] }
+    })
+}
+
+fn settings() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "kernel_module_path": { "type": "string" },
+            "verify_symbols": { "type": "boolean" },
+            "unresolved_symbols": severity(),
+            "strip": { "type": "boolean" },
+            "debug_sidecar": { "type": "string" },
+            "secret_filter": { "enum": ["error", "skip", "allow"] },
+            "secret_patterns": string_list(),
+            "allow_commands": { "type": "boolean" },
+            "prune_empty_dirs": { "type": "boolean" },
+            "prune_unused_libs": { "type": "boolean" },
+            "canonical": { "type": "boolean" },
+            "archive_format": { "enum": ["newc", "crc"] },
+            "split_incompressible": { "type": "boolean" },
+            "auto_depmod": { "type": "boolean" },
+            "kernel_module_denylist": string_list(),
+            "kernel_module_allowlist": string_list(),
+            "strict_module_policy": { "type": "boolean" },
+            "module_compression": { "enum": ["none", "gzip", "zstd"] },
+            "kernel_config_conflicts": severity(),
+            "library_denylist": string_list(),
+            "strict_library_policy": { "type": "boolean" },
+            "library_extra": string_list(),
+            "host_only": { "type": "boolean" },
+            "modules_from_lsmod": { "type": "boolean" },
+            "modules_from_file": { "type": "string" },
+            "lsmod_denylist": string_list(),
+            "shutdown_mode": { "enum": ["legacy", "systemd-hook", "exitrd"] },
+            "init_lint": { "type": "boolean" },
+            "init_exec": severity(),
+            "cmdline_conflicts": severity(),
+            "auto_generators": { "type": "boolean" },
+            "xattr_script": { "type": "string" },
+            "on_error": { "enum": ["abort", "best-effort"] },
+            "environment_path": { "type": "string" },
+            "systemd_environment": { "type": "boolean" },
+            "generate_ld_cache": { "type": "boolean" },
+            "run_ldconfig": { "type": "boolean" },
+            "emergency": {
+                "oneOf": [
+                    { "enum": ["none", "systemd"] },
+                    {
+                        "type": "object",
+                        "additionalProperties": false,
+                        "required": ["busybox"],
+                        "properties": {
+                            "busybox": {
+                                "type": "object",
+                                "additionalProperties": false,
+                                "required": ["path"],
+                                "properties": { "path": { "type": "string" } }
+                            }
+                        }
+                    }
+                ]
+            },
+            "bare": { "type": "boolean" },
+            "usr_merge": { "type": "boolean" },
+            "unit_search_paths": string_list(),
+            "package_backend": { "enum": ["pacman", "dpkg", "rpm"] },
+            "package_prefixes": string_list(),
+            "unit_dependency_keys": string_list(),
+            "crypttab_problems": severity(),
+            "dangling_symlinks": severity(),
+            "permission_lints": severity(),
+            "permission_rules": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "disabled": {
+                        "type": "array",
+                        "items": {
+                            "enum": ["world_writable", "setuid", "sensitive_readable", "not_executable"]
+                        }
+                    },
+                    "allow_world_writable": string_list(),
+                    "allow_setuid": string_list()
+                }
+            },
+            "case_collision": severity(),
+            "skeleton": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "disable_defaults": { "type": "boolean" },
+                    "extra_dirs": string_list(),
+                    "extra_symlinks": { "type": "array", "items": symlink() }
+                }
+            },
+            "max_file_size": size(),
+            "max_total_size": size(),
+            "max_size": size(),
+            "encoder": { "enum": ["none", "gzip", "zstd"] },
+            "warn_size": size(),
+            "zstd_dictionary": { "type": "string" },
+            "cache_dir": { "type": "string" },
+            "remote_timeout": { "type": "integer", "minimum": 1 }
+        }
+    })
+}
+
+/// Schema for the top-level configuration file, which holds both initramfs
+/// and microcode configuration.
+pub fn config() -> Value {
+    json!({
+        "$schema": SCHEMA_DRAFT,
+        "title": "elusive configuration",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "amd_ucode": { "type": "string" },
+            "intel_ucode": { "type": "string" },
+            "layout": { "enum": ["early", "late"] },
+            "init": {
+                "oneOf": [
+                    { "type": "string" },
+                    {
+                        "type": "object",
+                        "additionalProperties": false,
+                        "required": ["use", "exec"],
+                        "properties": {
+                            "use": { "type": "string" },
+                            "args": { "type": "string" },
+                            "exec": { "type": "string" }
+                        }
+                    }
+                ]
+            },
+            "shutdown": { "oneOf": [{ "type": "string" }, string_list()] },
+            "settings": settings(),
+            "modules": string_list(),
+            "shutdown_modules": string_list(),
+            "prepend": string_list(),
+            "append": string_list(),
+            "environment": environment()
+        }
+    })
+}
+
+/// Schema for module configuration files.
+pub fn module() -> Value {
+    let binary = json!({
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "path": { "type": "string" },
+                    "directory": { "type": "string" },
+                    "recursive": { "type": "boolean" },
+                    "strip": { "type": "boolean" },
+                    "resolve_deps": { "type": "boolean" },
+                    "remote": remote()
+                }
+            }
+        ]
+    });
+
+    let install = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "dir_mode": mode(),
+            "file_mode": mode(),
+            "owner": { "type": "integer", "minimum": 0 },
+            "group": { "type": "integer", "minimum": 0 },
+            "exceptions": { "type": "object", "additionalProperties": mode() }
+        }
+    });
+
+    let source = json!({
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "required": ["path", "rename"],
+                "properties": {
+                    "path": { "type": "string" },
+                    "rename": { "type": "string", "pattern": "^[^/]+$" }
+                }
+            }
+        ]
+    });
+
+    let file = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "allOf": [
+            {
+                "oneOf": [
+                    { "required": ["destination"] },
+                    { "required": ["destination_file", "sources"] }
+                ]
+            },
+            {
+                "oneOf": [
+                    { "required": ["sources"] },
+                    { "required": ["content"] },
+                    { "required": ["content_base64"] },
+                    { "required": ["remote"] }
+                ]
+            }
+        ],
+        "properties": {
+            "sources": { "type": "array", "items": source },
+            "destination": { "type": "string" },
+            "destination_file": { "type": "string" },
+            "content": { "type": "string" },
+            "content_base64": { "type": "string" },
+            "remote": remote(),
+            "mode": mode(),
+            "secret_filter": { "enum": ["error", "skip", "allow"] },
+            "install": install,
+            "ignore_defaults": { "type": "boolean" },
+            "exclude": string_list()
+        }
+    });
+
+    let kernel_module = json!({
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "oneOf": [{ "required": ["name"] }, { "required": ["path"] }],
+                "properties": {
+                    "name": { "type": "string" },
+                    "path": { "type": "string" },
+                    "deps": { "type": "boolean" },
+                    "softdeps": { "type": "boolean" }
+                }
+            }
+        ]
+    });
+
+    let unit = json!({
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "additionalProperties": false,
+                "oneOf": [{ "required": ["name"] }, { "required": ["wants_of"] }],
+                "properties": {
+                    "name": { "type": "string" },
+                    "wants_of": { "type": "string" },
+                    "optional": { "type": "boolean" }
+                }
+            }
+        ]
+    });
+
+    let template = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["destination", "content"],
+        "properties": {
+            "destination": { "type": "string" },
+            "content": { "type": "string" },
+            "vars": { "type": "object", "additionalProperties": { "type": "string" } }
+        }
+    });
+
+    let console = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "keymap": { "type": "string" },
+            "font": { "type": "string" },
+            "terminfo": string_list()
+        }
+    });
+
+    let constraints = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "arch": string_list(),
+            "kernel_version": { "type": "string" }
+        }
+    });
+
+    json!({
+        "$schema": SCHEMA_DRAFT,
+        "title": "elusive module",
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["name"],
+        "properties": {
+            "name": { "type": "string" },
+            "binaries": { "type": "array", "items": binary },
+            "files": { "type": "array", "items": file },
+            "symlinks": { "type": "array", "items": symlink() },
+            "payloads": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "required": ["path", "destination"],
+                    "properties": {
+                        "path": { "type": "string" },
+                        "destination": { "type": "string" },
+                        "type": { "enum": ["auto", "squashfs", "erofs"] }
+                    }
+                }
+            },
+            "symlink_map": {
+                "oneOf": [
+                    { "type": "string" },
+                    {
+                        "type": "object",
+                        "additionalProperties": false,
+                        "required": ["path"],
+                        "properties": {
+                            "path": { "type": "string" },
+                            "relative": { "type": "boolean" }
+                        }
+                    }
+                ]
+            },
+            "kernel_modules": { "type": "array", "items": kernel_module },
+            "units": { "type": "array", "items": unit },
+            "packages": string_list(),
+            "generators": string_list(),
+            "kernel_cmdline": string_list(),
+            "sysctl": {
+                "type": "object",
+                "additionalProperties": {
+                    "type": ["string", "integer", "boolean"]
+                }
+            },
+            "tmpfiles": string_list(),
+            "modules_load": string_list(),
+            "environment": environment(),
+            "templates": { "type": "array", "items": template },
+            "console": console,
+            "constraints": constraints,
+            "critical": { "type": "boolean" }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config;
+
+    // the fields serde expects, taken from its unknown field error
+    fn expected_fields<T>(document: &str) -> Vec<String>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let err = serde_yaml::from_str::<T>(document)
+            .err()
+            .unwrap()
+            .to_string();
+        let (_, expected) = err.split_once("expected one of").unwrap();
+
+        expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .map(String::from)
+            .collect()
+    }
+
+    fn property_names(schema: &Value) -> Vec<String> {
+        let properties = schema["properties"].as_object().unwrap();
+        properties.keys().cloned().collect()
+    }
+
+    #[test]
+    fn test_schema_fields() {
+        let mut module = expected_fields::<config::Module>("name: test\nunknown: 1\n");
+        let mut settings = expected_fields::<config::Settings>("unknown: 1\n");
+        module.sort();
+        settings.sort();
+
+        assert_eq!(property_names(&self::module()), module);
+        assert_eq!(property_names(&self::settings()), settings);
+    }
+}
@@ -0,0 +1,379 @@
+//! Console assets needed by early boot prompts.
+//!
+//! Passphrase prompts need the keymap, the console font and the terminfo
+//! entries of the terminal to behave like on the booted system. They are
+//! located in the kbd and ncurses data directories, relative to a root
+//! directory so that fixture trees can be used in place of the running host.
+//!
+//! Keymaps may include other files (`include "qwertz-layout"`), which are
+//! searched next to the including file then in `include` directories up to
+//! the keymaps directory, as done by `loadkeys`.
+
+use flate2::read::GzDecoder;
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use walkdir::WalkDir;
+
+/// Directories holding keymaps, relative to the root.
+const KEYMAP_DIRS: &[&str] = &["usr/share/kbd/keymaps", "usr/lib/kbd/keymaps"];
+/// Directories holding console fonts, relative to the root.
+const FONT_DIRS: &[&str] = &["usr/share/kbd/consolefonts", "usr/lib/kbd/consolefonts"];
+/// Directories holding the terminfo database, relative to the root.
+const TERMINFO_DIRS: &[&str] = &["usr/share/terminfo", "usr/lib/terminfo", "etc/terminfo"];
+
+const KEYMAP_EXTENSIONS: &[&str] = &[".map.gz", ".map"];
+const INCLUDE_EXTENSIONS: &[&str] = &["", ".inc", ".inc.gz", ".map", ".map.gz", ".gz"];
+const FONT_EXTENSIONS: &[&str] = &[".psfu.gz", ".psf.gz", ".psfu", ".psf", ".gz", ""];
+
+/// Magic number of gzip compressed keymaps.
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+/// Custom error type for console asset lookup.
+#[derive(thiserror::Error, Debug)]
+pub enum ConsoleError {
+    #[error("i/o error: {0}")]
+    InputOutput(io::Error),
+    #[error("failed to walk directory: {0}")]
+    Walk(walkdir::Error),
+    #[error("could not find {kind} {name}, searched: {}", display_paths(searched))]
+    NotFound {
+        kind: &'static str,
+        name: String,
+        searched: Vec<PathBuf>,
+    },
+}
+
+impl From<io::Error> for ConsoleError {
+    fn from(err: io::Error) -> Self {
+        Self::InputOutput(err)
+    }
+}
+
+impl From<walkdir::Error> for ConsoleError {
+    fn from(err: walkdir::Error) -> Self {
+        Self::Walk(err)
+    }
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<_> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    paths.join(", ")
+}
+
+/// A file to install in the initramfs.
+#[derive(PartialEq, Debug)]
+pub struct Asset {
+    /// Where the file is on the host.
+    pub source: PathBuf,
+    /// Where the file goes in the initramfs, the same path without the root.
+    pub destination: PathBuf,
+}
+
+/// Console data directories, rooted at a directory.
+pub struct Share {
+    root: PathBuf,
+}
+
+impl Share {
+    /// Look up assets of the running host.
+    pub fn new() -> Self {
+        Self::with_root("/")
+    }
+
+    /// Look up assets in a tree rooted at the provided directory.
+    pub fn with_root<P>(root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Share { root: root.into() }
+    }
+
+    /// Find a keymap by name (e.g. `de-latin1`) along with every file it
+    /// includes, directly or not.
+    pub fn keymap(&self, name: &str) -> Result<Vec<Asset>, ConsoleError> {
+        let dirs = self.dirs(KEYMAP_DIRS);
+        let mut found = None;
+
+        'search: for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+            let walk = WalkDir::new(dir).sort_by_file_name();
+
+            for entry in walk {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy();
+
+                let matches = KEYMAP_EXTENSIONS
+                    .iter()
+                    .any(|ext| file_name.strip_suffix(ext) == Some(name));
+
+                if matches && entry.file_type().is_file() {
+                    found = Some((dir.clone(), entry.into_path()));
+                    break 'search;
+                }
+            }
+        }
+
+        let Some((base, path)) = found else {
+            return Err(ConsoleError::NotFound {
+                kind: "keymap",
+                name: name.to_string(),
+                searched: dirs,
+            });
+        };
+
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![path];
+
+        while let Some(path) = pending.pop() {
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+
+            for include in includes(&read_keymap(&path)?) {
+                pending.push(self.find_include(&base, &path, &include)?);
+            }
+        }
+
+        Ok(seen.into_iter().map(|path| self.asset(path)).collect())
+    }
+
+    /// Find a console font by name (e.g. `lat9w-16`).
+    pub fn font(&self, name: &str) -> Result<Asset, ConsoleError> {
+        let dirs = self.dirs(FONT_DIRS);
+
+        let path = dirs
+            .iter()
+            .flat_map(|dir| {
+                FONT_EXTENSIONS
+                    .iter()
+                    .map(move |ext| dir.join(format!("{name}{ext}")))
+            })
+            .find(|path| path.is_file());
+
+        match path {
+            Some(path) => Ok(self.asset(path)),
+            None => Err(ConsoleError::NotFound {
+                kind: "console font",
+                name: name.to_string(),
+                searched: dirs,
+            }),
+        }
+    }
+
+    /// Find a terminfo entry by name (e.g. `linux`), stored in a directory
+    /// named after its first letter, or its hexadecimal value on some systems.
+    pub fn terminfo(&self, name: &str) -> Result<Asset, ConsoleError> {
+        let not_found = |searched| ConsoleError::NotFound {
+            kind: "terminfo entry",
+            name: name.to_string(),
+            searched,
+        };
+
+        let Some(first) = name.chars().next() else {
+            return Err(not_found(Vec::new()));
+        };
+
+        let letters = [first.to_string(), format!("{:x}", u32::from(first))];
+        let searched: Vec<_> = self
+            .dirs(TERMINFO_DIRS)
+            .iter()
+            .flat_map(|dir| letters.iter().map(move |letter| dir.join(letter)))
+            .collect();
+
+        match searched
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+        {
+            Some(path) => Ok(self.asset(path)),
+            None => Err(not_found(searched)),
+        }
+    }
+
+    fn dirs(&self, dirs: &[&str]) -> Vec<PathBuf> {
+        dirs.iter().map(|dir| self.root.join(dir)).collect()
+    }
+
+    /// Search an included file from the directory of the including file up
+    /// to the keymaps directory.
+    fn find_include(&self, base: &Path, from: &Path, name: &str) -> Result<PathBuf, ConsoleError> {
+        let mut searched = Vec::new();
+        let mut current = from.parent();
+
+        while let Some(dir) = current.filter(|dir| dir.starts_with(base)) {
+            for candidate in [dir.to_path_buf(), dir.join("include")] {
+                let path = INCLUDE_EXTENSIONS
+                    .iter()
+                    .map(|ext| candidate.join(format!("{name}{ext}")))
+                    .find(|path| path.is_file());
+
+                if let Some(path) = path {
+                    return Ok(path);
+                }
+
+                searched.push(candidate);
+            }
+
+            current = dir.parent();
+        }
+
+        Err(ConsoleError::NotFound {
+            kind: "keymap include",
+            name: name.to_string(),
+            searched,
+        })
+    }
+
+    fn asset(&self, source: PathBuf) -> Asset {
+        let relative = source
+            .strip_prefix(&self.root)
+            .expect("asset should be under root path");
+
+        Asset {
+            destination: Path::new("/").join(relative),
+            source,
+        }
+    }
+}
+
+impl Default for Share {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read a keymap, decompressing it if needed.
+fn read_keymap(path: &Path) -> Result<String, io::Error> {
+    let data = fs::read(path)?;
+
+    if !data.starts_with(GZIP_MAGIC) {
+        return Ok(String::from_utf8_lossy(&data).into_owned());
+    }
+
+    let mut content = String::new();
+    GzDecoder::new(data.as_slice()).read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Get the names of files included by a keymap.
+fn includes(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("include"))
+        .filter(|rest| rest.starts_with([' ', '\t', '"']))
+        .map(|rest| rest.trim().trim_matches('"').to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn write_gzip(path: &Path, content: &str) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, encoder.finish().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_share() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let keymaps = root.join("usr/share/kbd/keymaps/i386");
+        let terminfo = root.join("usr/share/terminfo");
+
+        write_gzip(
+            &keymaps.join("qwertz/de-latin1.map.gz"),
+            "# German\ninclude \"qwertz-layout\"\ninclude \"linux-with-alt-and-altgr\"\nkeycode 1 = Escape\n",
+        );
+        write_gzip(
+            &keymaps.join("include/linux-with-alt-and-altgr.inc"),
+            "include \"linux-keys-bare\"\n",
+        );
+        fs::write(keymaps.join("qwertz/qwertz-layout.inc"), "keycode 21 = z\n").unwrap();
+        fs::write(
+            keymaps.join("include/linux-keys-bare.inc"),
+            "! includes nothing\n",
+        )
+        .unwrap();
+        fs::write(keymaps.join("qwertz/broken.map"), "include \"missing\"\n").unwrap();
+
+        fs::create_dir_all(terminfo.join("l")).unwrap();
+        fs::create_dir_all(terminfo.join("76")).unwrap();
+        fs::write(terminfo.join("l/linux"), b"\x1a\x01").unwrap();
+        fs::write(terminfo.join("76/vt102"), b"\x1a\x01").unwrap();
+
+        let fonts = root.join("usr/share/kbd/consolefonts");
+        fs::create_dir_all(&fonts).unwrap();
+        fs::write(fonts.join("lat9w-16.psfu.gz"), b"\x1f\x8b").unwrap();
+
+        let share = Share::with_root(root);
+        let keymap = share.keymap("de-latin1");
+        let broken = share.keymap("broken");
+        let missing = share.keymap("us");
+        let font = share.font("lat9w-16");
+        let linux = share.terminfo("linux");
+        let vt102 = share.terminfo("vt102");
+        let xterm = share.terminfo("xterm");
+
+        let destinations: Vec<_> = keymap
+            .unwrap()
+            .into_iter()
+            .map(|asset| asset.destination)
+            .collect();
+        assert_eq!(
+            destinations,
+            [
+                "/usr/share/kbd/keymaps/i386/include/linux-keys-bare.inc",
+                "/usr/share/kbd/keymaps/i386/include/linux-with-alt-and-altgr.inc",
+                "/usr/share/kbd/keymaps/i386/qwertz/de-latin1.map.gz",
+                "/usr/share/kbd/keymaps/i386/qwertz/qwertz-layout.inc",
+            ]
+            .map(PathBuf::from)
+        );
+
+        assert!(matches!(
+            broken,
+            Err(ConsoleError::NotFound {
+                kind: "keymap include",
+                ..
+            })
+        ));
+        let message = missing.unwrap_err().to_string();
+        assert!(message.contains("keymap us"), "{message}");
+        assert!(message.contains("usr/lib/kbd/keymaps"), "{message}");
+
+        assert_eq!(
+            font.unwrap(),
+            Asset {
+                source: fonts.join("lat9w-16.psfu.gz"),
+                destination: PathBuf::from("/usr/share/kbd/consolefonts/lat9w-16.psfu.gz"),
+            }
+        );
+        assert_eq!(
+            linux.unwrap().destination,
+            Path::new("/usr/share/terminfo/l/linux")
+        );
+        assert_eq!(
+            vt102.unwrap().destination,
+            Path::new("/usr/share/terminfo/76/vt102")
+        );
+
+        let Err(ConsoleError::NotFound { searched, .. }) = xterm else {
+            panic!("terminfo entry should be missing");
+        };
+        assert!(searched.contains(&terminfo.join("x")));
+    }
+}
@@ -0,0 +1,309 @@
+//! Architecture and kernel version constraints of configuration modules.
+//!
+//! Kernel versions are parsed leniently: only the leading numeric components
+//! of a release are kept, so `6.1.0-13-amd64` is `6.1.0` and release
+//! candidates such as `6.8.0-rc3` count as the version they lead to. Missing
+//! components are zero, `6.8` and `6.8.0` are the same version.
+//!
+//! Requirements are comma separated comparisons, all of which must hold:
+//!
+//! ```text
+//! >=5.15
+//! >=5.15, <6.0
+//! =6.1
+//! ```
+
+use crate::kmod::{self, KmodError};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Custom error type for constraint parsing.
+#[derive(thiserror::Error, Debug)]
+pub enum ConstraintError {
+    #[error("invalid kernel version requirement: {0}")]
+    InvalidRequirement(String),
+}
+
+/// A kernel version, compared component by component.
+#[derive(Clone, Debug)]
+pub struct KernelVersion(Vec<u64>);
+
+impl KernelVersion {
+    /// Parse the leading numeric components of a kernel release, ignoring
+    /// the local suffix. Returns `None` when the release has no number.
+    pub fn from_release(release: &str) -> Option<Self> {
+        let end = release
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(release.len());
+
+        let components = release[..end]
+            .split('.')
+            .map_while(|component| component.parse().ok())
+            .collect::<Vec<u64>>();
+
+        (!components.is_empty()).then_some(KernelVersion(components))
+    }
+
+    fn component(&self, index: usize) -> u64 {
+        self.0.get(index).copied().unwrap_or(0)
+    }
+}
+
+impl PartialEq for KernelVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for KernelVersion {}
+
+impl PartialOrd for KernelVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KernelVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.0.len().max(other.0.len());
+
+        (0..len)
+            .map(|index| self.component(index).cmp(&other.component(index)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let components: Vec<_> = self.0.iter().map(u64::to_string).collect();
+        write!(f, "{}", components.join("."))
+    }
+}
+
+/// Comparison operators accepted in requirements, longest first.
+const OPERATORS: &[(&str, &[Ordering])] = &[
+    (">=", &[Ordering::Greater, Ordering::Equal]),
+    ("<=", &[Ordering::Less, Ordering::Equal]),
+    ("==", &[Ordering::Equal]),
+    (">", &[Ordering::Greater]),
+    ("<", &[Ordering::Less]),
+    ("=", &[Ordering::Equal]),
+];
+
+/// A kernel version requirement such as `>=5.15`.
+#[derive(Clone, Debug)]
+pub struct VersionRequirement {
+    source: String,
+    comparisons: Vec<(&'static [Ordering], KernelVersion)>,
+}
+
+impl VersionRequirement {
+    /// Check if the version satisfies every comparison.
+    pub fn matches(&self, version: &KernelVersion) -> bool {
+        self.comparisons
+            .iter()
+            .all(|(accepted, required)| accepted.contains(&version.cmp(required)))
+    }
+}
+
+impl FromStr for VersionRequirement {
+    type Err = ConstraintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConstraintError::InvalidRequirement(s.to_string());
+        let mut comparisons = Vec::new();
+
+        for comparison in s.split(',') {
+            let comparison = comparison.trim();
+
+            // a bare version must match exactly
+            let (accepted, version) = OPERATORS
+                .iter()
+                .find_map(|(operator, accepted)| {
+                    comparison
+                        .strip_prefix(operator)
+                        .map(|version| (*accepted, version))
+                })
+                .unwrap_or((&[Ordering::Equal], comparison));
+
+            let version = version.trim();
+            if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit() || c == '.') {
+                return Err(invalid());
+            }
+
+            let version = KernelVersion::from_release(version).ok_or_else(invalid)?;
+            comparisons.push((accepted, version));
+        }
+
+        Ok(VersionRequirement {
+            source: s.trim().to_string(),
+            comparisons,
+        })
+    }
+}
+
+impl fmt::Display for VersionRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionRequirement {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for VersionRequirement {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+/// The machine modules are selected for.
+#[derive(Debug)]
+pub struct Platform {
+    /// Machine hardware name, as given by `uname -m`.
+    pub arch: String,
+    /// Kernel release the initramfs is built for.
+    pub release: String,
+}
+
+impl Platform {
+    /// Detect the platform of the running kernel, the release is the one of
+    /// the module directory if one is provided.
+    pub fn detect(kernel_module_path: Option<&Path>) -> Result<Self, KmodError> {
+        Ok(Platform {
+            arch: kmod::machine()?,
+            release: kmod::release_of(kernel_module_path)?,
+        })
+    }
+}
+
+/// Constraints a module has on the platform.
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Constraints {
+    /// Architectures the module applies to, any if empty.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub arch: Vec<String>,
+    /// Kernel versions the module applies to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel_version: Option<VersionRequirement>,
+}
+
+impl Constraints {
+    /// Check if the module applies to any platform.
+    pub fn is_empty(&self) -> bool {
+        self.arch.is_empty() && self.kernel_version.is_none()
+    }
+
+    /// Check the constraints against the platform, returning why they do not
+    /// hold if so.
+    pub fn check(&self, platform: &Platform) -> Result<(), String> {
+        if !self.arch.is_empty() && !self.arch.contains(&platform.arch) {
+            return Err(format!(
+                "architecture {} is not one of: {}",
+                platform.arch,
+                self.arch.join(", ")
+            ));
+        }
+
+        if let Some(requirement) = &self.kernel_version {
+            let satisfied = KernelVersion::from_release(&platform.release)
+                .is_some_and(|version| requirement.matches(&version));
+
+            if !satisfied {
+                return Err(format!(
+                    "kernel {} does not match {}",
+                    platform.release, requirement
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(release: &str) -> KernelVersion {
+        KernelVersion::from_release(release).unwrap()
+    }
+
+    fn requirement(s: &str) -> VersionRequirement {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_kernel_version() {
+        assert_eq!(version("6.1.0-13-amd64"), version("6.1"));
+        assert_eq!(version("6.8.0-rc3"), version("6.8.0"));
+        assert_eq!(version("6.9.7-arch1-1"), version("6.9.7"));
+        assert_eq!(version("5.15.0+"), version("5.15"));
+        assert!(version("5.4.280") < version("5.15"));
+        assert!(version("6.10") > version("6.9.12"));
+        assert!(KernelVersion::from_release("custom").is_none());
+
+        assert!(requirement(">=5.15").matches(&version("6.1.0-13-amd64")));
+        assert!(requirement(">=5.15").matches(&version("5.15.0")));
+        assert!(!requirement(">=5.15").matches(&version("5.10.209-1-lts")));
+        assert!(requirement(">=6.8").matches(&version("6.8.0-rc1")));
+        assert!(!requirement("<6.8").matches(&version("6.8-rc7")));
+        assert!(requirement(">=5.15, <6.0").matches(&version("5.19.17")));
+        assert!(!requirement(">=5.15, <6.0").matches(&version("6.0.0")));
+        assert!(requirement("6.1").matches(&version("6.1.0-13-amd64")));
+        assert!(!requirement("=6.1").matches(&version("6.1.2")));
+
+        assert!("".parse::<VersionRequirement>().is_err());
+        assert!(">=".parse::<VersionRequirement>().is_err());
+        assert!("~5.15".parse::<VersionRequirement>().is_err());
+        assert!(">=5.15-rc1".parse::<VersionRequirement>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let platform = Platform {
+            arch: "aarch64".to_string(),
+            release: "6.6.31-rpi".to_string(),
+        };
+
+        let x86 = Constraints {
+            arch: vec!["x86_64".to_string()],
+            kernel_version: None,
+        };
+        let recent = Constraints {
+            arch: vec!["x86_64".to_string(), "aarch64".to_string()],
+            kernel_version: Some(requirement(">=6.1")),
+        };
+        let old = Constraints {
+            arch: Vec::new(),
+            kernel_version: Some(requirement("<6")),
+        };
+
+        assert!(Constraints::default().check(&platform).is_ok());
+        assert!(recent.check(&platform).is_ok());
+        assert_eq!(
+            x86.check(&platform).unwrap_err(),
+            "architecture aarch64 is not one of: x86_64"
+        );
+        assert_eq!(
+            old.check(&platform).unwrap_err(),
+            "kernel 6.6.31-rpi does not match <6"
+        );
+    }
+}
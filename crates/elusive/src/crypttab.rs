@@ -0,0 +1,139 @@
+//! Parser for `/etc/crypttab`.
+//!
+//! Each line describes an encrypted volume with up to four whitespace
+//! separated columns: the volume name, the encrypted device, an optional key
+//! file and an optional comma separated list of options.
+//!
+//! ```text
+//! # <name>  <device>         <key file>        <options>
+//! root      UUID=1234-abcd   none              luks,discard
+//! data      /dev/sdb1        /etc/keys/data    luks
+//! swap      /dev/sda3        -                 tpm2-device=auto
+//! ```
+
+use std::path::PathBuf;
+
+/// Custom error type for crypttab parsing.
+#[derive(thiserror::Error, Debug)]
+pub enum CrypttabError {
+    #[error("line {0}: missing encrypted device")]
+    MissingDevice(usize),
+    #[error("line {0}: too many columns")]
+    TooManyColumns(usize),
+}
+
+/// An encrypted volume listed in crypttab.
+#[derive(PartialEq, Debug)]
+pub struct Volume {
+    /// Name of the unlocked device in `/dev/mapper`.
+    pub name: String,
+    /// The encrypted device, as a path or `UUID=`, `PARTUUID=`, ... spec.
+    pub device: String,
+    /// Key file, if not asking for a passphrase.
+    pub keyfile: Option<PathBuf>,
+    /// Options, with their value if any.
+    pub options: Vec<(String, Option<String>)>,
+}
+
+impl Volume {
+    /// Check if the volume has the provided option, with or without a value.
+    pub fn has_option(&self, name: &str) -> bool {
+        self.options.iter().any(|(key, _)| key == name)
+    }
+}
+
+/// Parse the content of a crypttab file, ignoring comments and empty lines.
+pub fn parse(content: &str) -> Result<Vec<Volume>, CrypttabError> {
+    let mut volumes = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let number = index + 1;
+        let columns: Vec<&str> = line.split_whitespace().collect();
+
+        let (name, device, keyfile, options) = match columns[..] {
+            [] | [_] => return Err(CrypttabError::MissingDevice(number)),
+            [name, device] => (name, device, None, None),
+            [name, device, keyfile] => (name, device, Some(keyfile), None),
+            [name, device, keyfile, options] => (name, device, Some(keyfile), Some(options)),
+            _ => return Err(CrypttabError::TooManyColumns(number)),
+        };
+
+        // "none" and "-" ask for a passphrase
+        let keyfile = keyfile
+            .filter(|keyfile| !matches!(*keyfile, "none" | "-"))
+            .map(PathBuf::from);
+
+        let options = options
+            .into_iter()
+            .flat_map(|options| options.split(','))
+            .filter(|option| !option.is_empty())
+            .map(|option| match option.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (option.to_string(), None),
+            })
+            .collect();
+
+        volumes.push(Volume {
+            name: name.to_string(),
+            device: device.to_string(),
+            keyfile,
+            options,
+        });
+    }
+
+    Ok(volumes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let volumes = parse(
+            "# comment\n\
+             \n\
+             root UUID=1234 none luks,discard\n\
+             data /dev/sdb1 /etc/keys/data.key\n\
+             swap /dev/sda3 - tpm2-device=auto,tpm2-pcrs=7\n\
+             home /dev/sda4\n",
+        )
+        .unwrap();
+
+        assert_eq!(volumes.len(), 4);
+        assert_eq!(volumes[0].name, "root");
+        assert_eq!(volumes[0].device, "UUID=1234");
+        assert_eq!(volumes[0].keyfile, None);
+        assert!(volumes[0].has_option("discard"));
+
+        assert_eq!(
+            volumes[1].keyfile,
+            Some(PathBuf::from("/etc/keys/data.key"))
+        );
+        assert!(volumes[1].options.is_empty());
+
+        assert_eq!(volumes[2].keyfile, None);
+        assert_eq!(
+            volumes[2].options,
+            [
+                ("tpm2-device".to_string(), Some("auto".to_string())),
+                ("tpm2-pcrs".to_string(), Some("7".to_string()))
+            ]
+        );
+        assert_eq!(volumes[3].device, "/dev/sda4");
+
+        assert!(matches!(
+            parse("# header\nroot\n"),
+            Err(CrypttabError::MissingDevice(2))
+        ));
+        assert!(matches!(
+            parse("root /dev/sda none luks extra\n"),
+            Err(CrypttabError::TooManyColumns(1))
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+//! Files of installed distribution packages.
+//!
+//! The file list of a package is queried from the package manager of the host
+//! (`pacman -Ql`, `dpkg-query -L` or `rpm -ql`), detected from the first of
+//! these commands that can be run unless a backend is configured. Listed paths
+//! outside of the allowed prefixes are left out, as well as documentation,
+//! manual pages and translations along with the default ignore patterns (see
+//! [`crate::ignore`]).
+//!
+//! Commands go through a [`CommandRunner`], so that queries can be answered
+//! without a package manager.
+
+use crate::ignore::Ignore;
+
+use log::debug;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::rc::Rc;
+
+/// Prefixes of the package files added when none are configured.
+pub const DEFAULT_PREFIXES: &[&str] = &["/usr", "/etc"];
+
+/// Directories of packages left out along with the default ignore patterns,
+/// relative to the root.
+const EXCLUDED_DIRS: &[&str] = &[
+    "usr/share/doc",
+    "usr/share/gtk-doc",
+    "usr/share/help",
+    "usr/share/info",
+    "usr/share/licenses",
+    "usr/share/locale",
+    "usr/share/man",
+];
+
+/// Custom error type for package queries.
+#[derive(thiserror::Error, Debug)]
+pub enum PackageError {
+    #[error("no package manager found, set settings.package_backend")]
+    NoBackend,
+    #[error("failed to run {program}: {source}")]
+    Command { program: String, source: io::Error },
+    #[error("package {package} is not installed according to {backend}: {message}")]
+    NotInstalled {
+        package: String,
+        backend: Backend,
+        message: String,
+    },
+}
+
+/// Package manager queried for the files of packages.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Arch Linux and derivatives.
+    Pacman,
+    /// Debian and derivatives.
+    Dpkg,
+    /// Fedora, openSUSE and other RPM based distributions.
+    Rpm,
+}
+
+impl Backend {
+    /// Backends in the order they are detected.
+    const ALL: [Backend; 3] = [Backend::Pacman, Backend::Dpkg, Backend::Rpm];
+
+    fn program(self) -> &'static str {
+        match self {
+            Backend::Pacman => "pacman",
+            Backend::Dpkg => "dpkg-query",
+            Backend::Rpm => "rpm",
+        }
+    }
+
+    fn list_args(self) -> &'static [&'static str] {
+        match self {
+            Backend::Pacman => &["-Ql"],
+            Backend::Dpkg => &["-L"],
+            Backend::Rpm => &["-ql"],
+        }
+    }
+
+    /// Parse the file list printed by the package manager.
+    ///
+    /// Pacman prefixes each path with the package name, dpkg lists `/.` and
+    /// diversions (`diverted by ... to: /path`), rpm prints `(contains no
+    /// files)` for empty packages: only absolute paths are kept.
+    pub fn parse_file_list(self, output: &str) -> Vec<PathBuf> {
+        output
+            .lines()
+            .filter_map(|line| match self {
+                Backend::Pacman => line.split_once(' ').map(|(_, path)| path),
+                Backend::Dpkg | Backend::Rpm => Some(line),
+            })
+            .filter(|path| path.starts_with('/') && *path != "/.")
+            .map(PathBuf::from)
+            .collect()
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Backend::Pacman => "pacman",
+            Backend::Dpkg => "dpkg",
+            Backend::Rpm => "rpm",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Runs the commands of package managers.
+pub trait CommandRunner {
+    /// Run the program with the arguments and wait for its output. Programs
+    /// that cannot be found fail with [`io::ErrorKind::NotFound`].
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+}
+
+/// Runs commands on the host.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct HostRunner;
+
+impl CommandRunner for HostRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+}
+
+/// Queries the file lists of packages.
+#[derive(Clone)]
+pub struct PackageQuery {
+    runner: Rc<dyn CommandRunner>,
+    backend: Option<Backend>,
+    prefixes: Vec<PathBuf>,
+    ignore: Ignore,
+}
+
+impl PackageQuery {
+    /// Create a query running commands on the host.
+    pub fn new() -> Self {
+        Self::with_runner(HostRunner)
+    }
+
+    /// Create a query running commands with the provided runner.
+    pub fn with_runner<R>(runner: R) -> Self
+    where
+        R: CommandRunner + 'static,
+    {
+        let ignore = Ignore::new(true, EXCLUDED_DIRS).expect("excluded dirs should be valid");
+
+        PackageQuery {
+            runner: Rc::new(runner),
+            backend: None,
+            prefixes: DEFAULT_PREFIXES.iter().map(PathBuf::from).collect(),
+            ignore,
+        }
+    }
+
+    /// Set the package manager instead of detecting it.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = Some(backend);
+    }
+
+    /// Set the prefixes package files must be under to be added.
+    pub fn set_prefixes(&mut self, prefixes: Vec<PathBuf>) {
+        self.prefixes = prefixes;
+    }
+
+    /// Get the package manager, detecting it on first use.
+    pub fn backend(&mut self) -> Result<Backend, PackageError> {
+        if let Some(backend) = self.backend {
+            return Ok(backend);
+        }
+
+        for backend in Backend::ALL {
+            let program = backend.program();
+
+            match self.runner.run(program, &["--version"]) {
+                Ok(_) => {
+                    debug!("Using package manager: {}", backend);
+                    self.backend = Some(backend);
+                    return Ok(backend);
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(source) => {
+                    return Err(PackageError::Command {
+                        program: program.to_string(),
+                        source,
+                    })
+                }
+            }
+        }
+
+        Err(PackageError::NoBackend)
+    }
+
+    /// Get the files of an installed package that should be added, in the
+    /// order the package manager lists them. Directories are listed as well,
+    /// the caller decides what to do with them.
+    pub fn files(&mut self, package: &str) -> Result<Vec<PathBuf>, PackageError> {
+        let backend = self.backend()?;
+        let program = backend.program();
+
+        let mut args = backend.list_args().to_vec();
+        args.push(package);
+
+        let output = self
+            .runner
+            .run(program, &args)
+            .map_err(|source| PackageError::Command {
+                program: program.to_string(),
+                source,
+            })?;
+
+        if !output.status.success() {
+            // rpm reports missing packages on stdout
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let message = stderr
+                .lines()
+                .chain(stdout.lines())
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .map_or_else(|| output.status.to_string(), str::to_string);
+
+            return Err(PackageError::NotInstalled {
+                package: package.to_string(),
+                backend,
+                message,
+            });
+        }
+
+        let files = backend
+            .parse_file_list(&String::from_utf8_lossy(&output.stdout))
+            .into_iter()
+            .filter(|path| self.is_included(path))
+            .collect();
+
+        Ok(files)
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        if !self.prefixes.iter().any(|prefix| path.starts_with(prefix)) {
+            return false;
+        }
+
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let ignored = relative
+            .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| self.ignore.is_ignored(ancestor));
+
+        if ignored {
+            debug!("Ignoring package file: {}", path.display());
+        }
+
+        !ignored
+    }
+}
+
+impl Default for PackageQuery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    /// Runner answering with canned output, keyed by the program and its
+    /// arguments. Programs without any answer are not found.
+    #[derive(Default)]
+    pub(crate) struct FakeRunner {
+        outputs: HashMap<String, (i32, String, String)>,
+    }
+
+    impl FakeRunner {
+        pub(crate) fn answer(&mut self, command: &str, code: i32, stdout: &str, stderr: &str) {
+            let answer = (code, stdout.to_string(), stderr.to_string());
+            self.outputs.insert(command.to_string(), answer);
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+            let command = [program].iter().chain(args).copied().collect::<Vec<_>>();
+            let found = self
+                .outputs
+                .keys()
+                .any(|key| key.split(' ').next() == Some(program));
+            if !found {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+
+            let (code, stdout, stderr) = self
+                .outputs
+                .get(&command.join(" "))
+                .cloned()
+                .unwrap_or_default();
+
+            Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.into_bytes(),
+                stderr: stderr.into_bytes(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse_file_list() {
+        let pacman = "cryptsetup /usr/\n\
+                      cryptsetup /usr/bin/\n\
+                      cryptsetup /usr/bin/cryptsetup\n";
+        assert_eq!(
+            Backend::Pacman.parse_file_list(pacman),
+            ["/usr/", "/usr/bin/", "/usr/bin/cryptsetup"].map(PathBuf::from)
+        );
+
+        let dpkg = "/.\n\
+                    /usr\n\
+                    /usr/sbin/cryptsetup\n\
+                    /usr/lib/x86_64-linux-gnu/libcryptsetup.so.12\n\
+                    package diverts others to: /usr/sbin/cryptsetup.distrib\n\
+                    diverted by local to: /usr/sbin/veritysetup.real\n";
+        assert_eq!(
+            Backend::Dpkg.parse_file_list(dpkg),
+            [
+                "/usr",
+                "/usr/sbin/cryptsetup",
+                "/usr/lib/x86_64-linux-gnu/libcryptsetup.so.12"
+            ]
+            .map(PathBuf::from)
+        );
+
+        let rpm = "/usr/sbin/cryptsetup\n/usr/share/man/man8/cryptsetup.8.gz\n";
+        assert_eq!(
+            Backend::Rpm.parse_file_list(rpm),
+            [
+                "/usr/sbin/cryptsetup",
+                "/usr/share/man/man8/cryptsetup.8.gz"
+            ]
+            .map(PathBuf::from)
+        );
+        assert!(Backend::Rpm
+            .parse_file_list("(contains no files)\n")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_files() {
+        let mut runner = FakeRunner::default();
+        runner.answer("dpkg-query --version", 0, "Debian dpkg-query 1.21\n", "");
+        runner.answer("rpm --version", 0, "RPM version 4.18\n", "");
+        runner.answer(
+            "dpkg-query -L kbd",
+            0,
+            "/.\n\
+             /etc/kbd/config\n\
+             /usr/bin/loadkeys\n\
+             /usr/share/doc/kbd/README\n\
+             /usr/share/man/man1/loadkeys.1.gz\n\
+             /usr/share/locale/de/LC_MESSAGES/kbd.mo\n\
+             /usr/share/keymaps/i386/qwerty/us.map.gz\n\
+             /usr/share/keymaps/i386/qwerty/us.map.gz~\n\
+             /lib/udev/rules.d/60-kbd.rules\n\
+             /var/lib/kbd/state\n",
+            "",
+        );
+        runner.answer(
+            "dpkg-query -L missing",
+            1,
+            "",
+            "dpkg-query: package 'missing' is not installed\n",
+        );
+
+        let mut query = PackageQuery::with_runner(runner);
+
+        // pacman is not found, dpkg comes before rpm
+        assert_eq!(query.backend().unwrap(), Backend::Dpkg);
+        assert_eq!(
+            query.files("kbd").unwrap(),
+            [
+                "/etc/kbd/config",
+                "/usr/bin/loadkeys",
+                "/usr/share/keymaps/i386/qwerty/us.map.gz"
+            ]
+            .map(PathBuf::from)
+        );
+
+        query.set_prefixes(vec![PathBuf::from("/lib")]);
+        assert_eq!(
+            query.files("kbd").unwrap(),
+            [PathBuf::from("/lib/udev/rules.d/60-kbd.rules")]
+        );
+
+        let err = query.files("missing").unwrap_err();
+        assert!(matches!(
+            &err,
+            PackageError::NotInstalled { package, backend: Backend::Dpkg, message }
+                if package == "missing"
+                    && message == "dpkg-query: package 'missing' is not installed"
+        ));
+
+        // a configured backend is used even if it is not installed
+        query.set_backend(Backend::Pacman);
+        assert!(matches!(
+            query.files("kbd"),
+            Err(PackageError::Command { program, .. }) if program == "pacman"
+        ));
+
+        let mut query = PackageQuery::with_runner(FakeRunner::default());
+        assert!(matches!(query.backend(), Err(PackageError::NoBackend)));
+    }
+}
@@ -0,0 +1,172 @@
+//! Files added at the same path by different configuration modules.
+//!
+//! Several modules may provide the same file, e.g. `/etc/nsswitch.conf`. Copies
+//! with identical content are deduplicated, copies with different content are
+//! a conflict since only one of them can end up in the initramfs. Conflicts
+//! show where both copies come from and how they differ: the first differing
+//! lines for text, sizes and digests for binary content.
+
+use crate::measurement::hex;
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::PathBuf;
+use std::str;
+
+/// Lines of each copy shown in a text excerpt.
+const EXCERPT_LINES: usize = 5;
+
+/// Characters of a line shown in a text excerpt.
+const EXCERPT_WIDTH: usize = 120;
+
+/// Hex digits of the digests shown for binary content.
+const DIGEST_LEN: usize = 12;
+
+/// Where a copy of a file comes from.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Origin {
+    /// Configuration module adding the copy.
+    pub module: String,
+    /// Why the copy is added, e.g. `file /etc/nsswitch.conf`.
+    pub source: String,
+}
+
+/// Copies of a file with different content.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Divergent {
+    /// Path of the file in the initramfs.
+    pub path: PathBuf,
+    /// Copy already in the initramfs.
+    pub first: Origin,
+    /// Copy being added.
+    pub second: Origin,
+    /// How the content differs, see [`difference`].
+    pub difference: String,
+}
+
+impl fmt::Display for Divergent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "conflicting copies of {}: module {} added {}, module {} adds {}\n{}",
+            self.path.display(),
+            self.first.module,
+            self.first.source,
+            self.second.module,
+            self.second.source,
+            self.difference
+        )
+    }
+}
+
+/// Describe how two copies of a file differ, as a unified diff excerpt of
+/// the first differing lines when both are text.
+pub fn difference(first: &[u8], second: &[u8]) -> String {
+    match (text(first), text(second)) {
+        (Some(first), Some(second)) => excerpt(first, second),
+        _ => format!(
+            "binary content differs: {} vs {}",
+            summary(first),
+            summary(second)
+        ),
+    }
+}
+
+fn text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+
+    str::from_utf8(data).ok()
+}
+
+// lines between the common prefix and suffix, capped
+fn excerpt(first: &str, second: &str) -> String {
+    let first: Vec<_> = first.lines().collect();
+    let second: Vec<_> = second.lines().collect();
+
+    let prefix = first
+        .iter()
+        .zip(&second)
+        .take_while(|(left, right)| left == right)
+        .count();
+    let suffix = first[prefix..]
+        .iter()
+        .rev()
+        .zip(second[prefix..].iter().rev())
+        .take_while(|(left, right)| left == right)
+        .count();
+
+    let removed = &first[prefix..first.len() - suffix];
+    let added = &second[prefix..second.len() - suffix];
+
+    if removed.is_empty() && added.is_empty() {
+        return "text content differs only in line endings".to_string();
+    }
+
+    let line = prefix + 1;
+    let mut out = format!("@@ -{line},{} +{line},{} @@", removed.len(), added.len());
+    push_lines(&mut out, '-', removed);
+    push_lines(&mut out, '+', added);
+
+    out
+}
+
+fn push_lines(out: &mut String, sign: char, lines: &[&str]) {
+    for line in lines.iter().take(EXCERPT_LINES) {
+        out.push('\n');
+        out.push(sign);
+        out.extend(line.chars().take(EXCERPT_WIDTH));
+
+        if line.chars().count() > EXCERPT_WIDTH {
+            out.push_str("...");
+        }
+    }
+
+    if lines.len() > EXCERPT_LINES {
+        let more = lines.len() - EXCERPT_LINES;
+        out.push_str(&format!("\n{sign}... {more} more lines"));
+    }
+}
+
+fn summary(data: &[u8]) -> String {
+    let digest = hex(&Sha256::digest(data));
+    format!("{} bytes (sha256 {})", data.len(), &digest[..DIGEST_LEN])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_difference() {
+        let first = "passwd: files\ngroup: files\nshadow: files\n";
+        let second = "passwd: files systemd\ngroup: files systemd\nshadow: files\n";
+        assert_eq!(
+            difference(first.as_bytes(), second.as_bytes()),
+            "@@ -1,2 +1,2 @@\n-passwd: files\n-group: files\n\
+             +passwd: files systemd\n+group: files systemd"
+        );
+
+        // added lines only, capped
+        let long: String = (0..8).map(|index| format!("line {index}\n")).collect();
+        assert_eq!(
+            difference(b"", long.as_bytes()),
+            "@@ -1,0 +1,8 @@\n+line 0\n+line 1\n+line 2\n+line 3\n+line 4\n+... 3 more lines"
+        );
+
+        let wide = "x".repeat(200);
+        let excerpt = difference(b"short\n", wide.as_bytes());
+        assert!(excerpt.ends_with(&format!("+{}...", "x".repeat(EXCERPT_WIDTH))));
+
+        assert_eq!(
+            difference(b"a\n", b"a"),
+            "text content differs only in line endings"
+        );
+
+        assert_eq!(
+            difference(b"\x7fELF\x00\x01", b"\x7fELF\x00\x02\x03"),
+            "binary content differs: 6 bytes (sha256 7ab58c495f91) vs 7 bytes (sha256 02623b432604)"
+        );
+    }
+}
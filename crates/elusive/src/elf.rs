@@ -5,19 +5,27 @@
 
 use crate::search::search_paths;
 
+use flate2::Crc;
 use log::error;
+use object::build::elf::{Builder, SectionData};
 use object::elf::FileHeader64;
-use object::elf::PT_DYNAMIC;
-use object::elf::{DT_NEEDED, DT_STRSZ, DT_STRTAB};
+use object::elf::{DT_NEEDED, DT_SONAME, DT_STRSZ, DT_STRTAB, VER_FLG_BASE};
+use object::elf::{PT_DYNAMIC, SHF_ALLOC, SHT_NOBITS, SHT_PROGBITS};
 use object::read::elf::{Dyn, FileHeader, ProgramHeader};
 use object::read::FileKind;
 use object::{Endianness, StringTable};
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
+const MAGIC_ELF: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+/// Section naming the separate debug file of a stripped ELF file.
+pub const DEBUGLINK_SECTION: &str = ".gnu_debuglink";
+
 const BINARY_SEARCH_PATHS: &[&str] = &[
     "/usr/bin/",
     "/usr/sbin/",
@@ -29,7 +37,8 @@ const BINARY_SEARCH_PATHS: &[&str] = &[
     "/sbin/",
 ];
 
-const LIBRARY_SEARCH_PATHS: &[&str] = &[
+/// Directories searched for libraries, on the host and in the initramfs.
+pub const LIBRARY_SEARCH_PATHS: &[&str] = &[
     "/usr/lib/",
     "/usr/lib64/",
     "/usr/lib/systemd/",
@@ -51,6 +60,8 @@ pub enum ElfError {
     BinaryNotFound(OsString),
     #[error("could not find library: {0:?}")]
     LibraryNotFound(OsString),
+    #[error("error rewriting elf: {0}")]
+    Rewrite(object::build::Error),
 }
 
 impl From<io::Error> for ElfError {
@@ -65,71 +76,282 @@ impl From<object::Error> for ElfError {
     }
 }
 
+impl From<object::build::Error> for ElfError {
+    fn from(err: object::build::Error) -> Self {
+        Self::Rewrite(err)
+    }
+}
+
+/// A versioned symbol requirement of an ELF file (from `.gnu.version_r`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VersionNeed {
+    /// Name of the library expected to provide the version (e.g. `libc.so.6`).
+    pub library: String,
+    /// Name of the required version (e.g. `GLIBC_2.38`).
+    pub version: String,
+}
+
+/// Dynamic dependencies of an ELF file, resolved on the host.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Dependencies {
+    /// Paths of the libraries needed (`DT_NEEDED`).
+    pub libraries: Vec<PathBuf>,
+    /// Path of the program interpreter (`PT_INTERP`).
+    pub interpreter: Option<PathBuf>,
+}
+
+/// Cache of dependency resolution, shared by the ELF files of a build so
+/// common libraries are only searched and parsed once.
+#[derive(Clone, Default, Debug)]
+pub struct ElfCache {
+    /// Resolved dependencies, keyed by canonical path.
+    dependencies: HashMap<PathBuf, Dependencies>,
+    /// Library paths, keyed by soname.
+    libraries: HashMap<OsString, PathBuf>,
+    /// Number of files parsed.
+    parsed: usize,
+    /// Number of library searches on the host.
+    searched: usize,
+}
+
+impl ElfCache {
+    /// Get the dependencies of the ELF file at the given path, parsing the
+    /// provided data unless the file was already seen, possibly under
+    /// another name.
+    pub fn dependencies(&mut self, path: &Path, data: &[u8]) -> Result<Dependencies, ElfError> {
+        let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(dependencies) = self.dependencies.get(&key) {
+            return Ok(dependencies.clone());
+        }
+
+        self.parsed += 1;
+        let libraries = Elf::needed(data)?
+            .iter()
+            .map(|name| self.find_library(name))
+            .collect::<Result<_, _>>()?;
+
+        let dependencies = Dependencies {
+            libraries,
+            interpreter: Elf::interpreter_from_data(data)?,
+        };
+
+        self.dependencies.insert(key, dependencies.clone());
+        Ok(dependencies)
+    }
+
+    /// Find a library by name, see [`Elf::find_library`].
+    pub fn find_library<P>(&mut self, name: P) -> Result<PathBuf, ElfError>
+    where
+        P: AsRef<OsStr>,
+    {
+        let name = name.as_ref();
+        if let Some(path) = self.libraries.get(name) {
+            return Ok(path.clone());
+        }
+
+        self.searched += 1;
+        let path = Elf::find_library(name)?;
+
+        self.libraries.insert(name.into(), path.clone());
+        Ok(path)
+    }
+
+    /// Record the path of a library so that it is not searched for, e.g. for
+    /// a library outside of the search paths.
+    pub fn insert_library<P>(&mut self, name: P, path: PathBuf)
+    where
+        P: AsRef<OsStr>,
+    {
+        self.libraries.insert(name.as_ref().into(), path);
+    }
+
+    /// Get the number of ELF files parsed so far.
+    pub fn parsed(&self) -> usize {
+        self.parsed
+    }
+
+    /// Get the number of library searches on the host so far.
+    pub fn searched(&self) -> usize {
+        self.searched
+    }
+}
+
+/// An ELF file split into a stripped copy and its debug file.
+pub struct SplitDebug {
+    /// Stripped data, linking to the debug file through `.gnu_debuglink`.
+    pub stripped: Vec<u8>,
+    /// Debug sections and symbol table removed from the stripped copy.
+    pub debug: Vec<u8>,
+}
+
 /// Utility type for ELF files.
 pub struct Elf;
 
 impl Elf {
+    /// Check whether the provided data looks like an ELF file.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(&MAGIC_ELF)
+    }
+
     /// Get a list of dynamic libraries linked by the ELF file available at the given path.
     pub fn linked_libraries(path: &Path) -> Result<Vec<PathBuf>, ElfError> {
-        let data = fs::read(path)?;
-        let data = data.as_slice();
+        Self::linked_libraries_from_data(&fs::read(path)?)
+    }
+
+    /// Same as [`Elf::linked_libraries`], from already read ELF data.
+    pub fn linked_libraries_from_data(data: &[u8]) -> Result<Vec<PathBuf>, ElfError> {
+        Self::needed(data)?.iter().map(Self::find_library).collect()
+    }
+
+    /// Get the path of the program interpreter (dynamic loader) requested by the
+    /// ELF file available at the given path, if any (`PT_INTERP`).
+    pub fn interpreter(path: &Path) -> Result<Option<PathBuf>, ElfError> {
+        Self::interpreter_from_data(&fs::read(path)?)
+    }
+
+    /// Same as [`Elf::interpreter`], from already read ELF data.
+    pub fn interpreter_from_data(data: &[u8]) -> Result<Option<PathBuf>, ElfError> {
+        let elf = parse_header(data)?;
+        let endian = elf.endian()?;
+
+        for header in elf.program_headers(endian, data)? {
+            if let Some(interp) = header.interpreter(endian, data)? {
+                return Ok(Some(PathBuf::from(OsStr::from_bytes(interp))));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Get the names of dynamic libraries needed by the provided ELF data (`DT_NEEDED`).
+    pub fn needed(data: &[u8]) -> Result<Vec<OsString>, ElfError> {
+        dynamic_strings(data, DT_NEEDED)
+    }
+
+    /// Get the shared object name of the provided ELF data, if any (`DT_SONAME`).
+    pub fn soname(data: &[u8]) -> Result<Option<OsString>, ElfError> {
+        Ok(dynamic_strings(data, DT_SONAME)?.into_iter().next())
+    }
+
+    /// Remove debug sections as well as the static symbol table from the provided
+    /// ELF data. Dynamic symbols and version sections are kept intact.
+    pub fn strip(data: &[u8]) -> Result<Vec<u8>, ElfError> {
+        let mut buf = Vec::new();
+        stripped(data)?.write(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Strip the provided ELF data like [`Elf::strip`], keeping what is removed
+    /// in a separate debug file. The stripped data names it `debuglink` in a
+    /// `.gnu_debuglink` section along with its CRC32, so debuggers find it.
+    /// `None` when there is nothing to strip.
+    pub fn split_debug(data: &[u8], debuglink: &str) -> Result<Option<SplitDebug>, ElfError> {
+        let mut builder = Builder::read(data)?;
+        if !builder
+            .sections
+            .iter()
+            .any(|section| is_stripped_section(section.name.as_slice()))
+        {
+            return Ok(None);
+        }
+
+        // like objcopy --only-keep-debug, loaded sections keep their headers
+        // but not their content
+        for section in builder.sections.iter_mut() {
+            if is_stripped_section(section.name.as_slice())
+                || section.sh_flags & u64::from(SHF_ALLOC) == 0
+            {
+                continue;
+            }
+
+            if let SectionData::Data(bytes) = &section.data {
+                section.data = SectionData::UninitializedData(bytes.len() as u64);
+                section.sh_type = SHT_NOBITS;
+            }
+        }
+
+        let mut debug = Vec::new();
+        builder.write(&mut debug)?;
 
-        let kind = FileKind::parse(data)?;
-        if kind != FileKind::Elf64 {
-            error!("Failed to parse binary");
-            return Err(ElfError::Not64BitElf);
+        let mut crc = Crc::new();
+        crc.update(&debug);
+
+        let mut contents = debuglink.as_bytes().to_vec();
+        contents.push(0);
+        contents.resize(contents.len().next_multiple_of(4), 0);
+
+        let mut builder = stripped(data)?;
+        match builder.endian {
+            Endianness::Little => contents.extend(crc.sum().to_le_bytes()),
+            Endianness::Big => contents.extend(crc.sum().to_be_bytes()),
         }
 
-        let elf = FileHeader64::<Endianness>::parse(data)?;
+        let section = builder.sections.add();
+        section.name = DEBUGLINK_SECTION.into();
+        section.sh_type = SHT_PROGBITS;
+        section.sh_addralign = 4;
+        section.data = SectionData::Data(contents.into());
+
+        let mut stripped = Vec::new();
+        builder.write(&mut stripped)?;
+
+        Ok(Some(SplitDebug { stripped, debug }))
+    }
+
+    /// Get the versioned symbol requirements declared by the provided ELF data.
+    pub fn version_requirements(data: &[u8]) -> Result<Vec<VersionNeed>, ElfError> {
+        let elf = parse_header(data)?;
         let endian = elf.endian()?;
-        let headers = elf.program_headers(endian, data)?;
-
-        let mut strtab = 0;
-        let mut strsz = 0;
-
-        let mut offsets: Vec<u64> = Vec::new();
-
-        for header in headers {
-            if header.p_type(endian) == PT_DYNAMIC {
-                if let Some(dynamic) = header.dynamic(endian, data)? {
-                    for entry in dynamic {
-                        let d_tag = entry.d_tag(endian);
-
-                        if d_tag == DT_STRTAB as u64 {
-                            strtab = entry.d_val(endian);
-                        } else if d_tag == DT_STRSZ as u64 {
-                            strsz = entry.d_val(endian);
-                        } else if d_tag == DT_NEEDED as u64 {
-                            offsets.push(entry.d_val(endian));
-                        }
-                    }
+        let sections = elf.sections(endian, data)?;
+
+        let mut needs = Vec::new();
+
+        if let Some((mut verneeds, link)) = sections.gnu_verneed(endian, data)? {
+            let strings = sections.strings(endian, data, link)?;
+
+            while let Some((verneed, mut vernauxs)) = verneeds.next()? {
+                let library = String::from_utf8_lossy(verneed.file(endian, strings)?);
+
+                while let Some(vernaux) = vernauxs.next()? {
+                    let version = String::from_utf8_lossy(vernaux.name(endian, strings)?);
+
+                    needs.push(VersionNeed {
+                        library: library.to_string(),
+                        version: version.to_string(),
+                    });
                 }
             }
         }
 
-        let found = headers
-            .iter()
-            .filter_map(|header| header.data_range(endian, data, strtab, strsz).ok())
-            .flatten()
-            .next();
+        Ok(needs)
+    }
 
-        let mut needed = Vec::new();
+    /// Get the symbol versions defined by the provided ELF data (from `.gnu.version_d`).
+    pub fn version_definitions(data: &[u8]) -> Result<Vec<String>, ElfError> {
+        let elf = parse_header(data)?;
+        let endian = elf.endian()?;
+        let sections = elf.sections(endian, data)?;
 
-        if let Some(data) = found {
-            let dynstr = StringTable::new(data, 0, data.len() as u64);
+        let mut definitions = Vec::new();
 
-            for offset in offsets {
-                let offset = offset.try_into().expect("offset fits in 32 bits");
-                let name = dynstr.get(offset).expect("offset exists in string table");
+        if let Some((mut verdefs, link)) = sections.gnu_verdef(endian, data)? {
+            let strings = sections.strings(endian, data, link)?;
 
-                let lib = OsStr::from_bytes(name);
-                let path = Self::find_library(lib)?;
+            while let Some((verdef, mut verdauxs)) = verdefs.next()? {
+                // the base definition is the soname of the file, not a version
+                if verdef.vd_flags.get(endian) & VER_FLG_BASE != 0 {
+                    continue;
+                }
 
-                needed.push(path);
+                if let Some(verdaux) = verdauxs.next()? {
+                    let name = String::from_utf8_lossy(verdaux.name(endian, strings)?);
+                    definitions.push(name.to_string());
+                }
             }
         }
 
-        Ok(needed)
+        Ok(definitions)
     }
 
     /// Find an ELF binary with the given name and return its path if it exists.
@@ -151,9 +373,229 @@ impl Elf {
     }
 }
 
+// get the strings referenced by dynamic entries with the given tag
+fn dynamic_strings(data: &[u8], tag: u32) -> Result<Vec<OsString>, ElfError> {
+    let elf = parse_header(data)?;
+    let endian = elf.endian()?;
+    let headers = elf.program_headers(endian, data)?;
+
+    let mut strtab = 0;
+    let mut strsz = 0;
+
+    let mut offsets: Vec<u64> = Vec::new();
+
+    for header in headers {
+        if header.p_type(endian) == PT_DYNAMIC {
+            if let Some(dynamic) = header.dynamic(endian, data)? {
+                for entry in dynamic {
+                    let d_tag = entry.d_tag(endian);
+
+                    if d_tag == DT_STRTAB as u64 {
+                        strtab = entry.d_val(endian);
+                    } else if d_tag == DT_STRSZ as u64 {
+                        strsz = entry.d_val(endian);
+                    } else if d_tag == u64::from(tag) {
+                        offsets.push(entry.d_val(endian));
+                    }
+                }
+            }
+        }
+    }
+
+    let found = headers
+        .iter()
+        .filter_map(|header| header.data_range(endian, data, strtab, strsz).ok())
+        .flatten()
+        .next();
+
+    let mut strings = Vec::new();
+
+    if let Some(data) = found {
+        let dynstr = StringTable::new(data, 0, data.len() as u64);
+
+        for offset in offsets {
+            let offset = offset.try_into().expect("offset fits in 32 bits");
+            let name = dynstr.get(offset).expect("offset exists in string table");
+
+            strings.push(OsStr::from_bytes(name).to_os_string());
+        }
+    }
+
+    Ok(strings)
+}
+
+// sections removed by stripping
+fn is_stripped_section(name: &[u8]) -> bool {
+    name.starts_with(b".debug_") || name == b".symtab" || name == b".strtab"
+}
+
+fn stripped(data: &[u8]) -> Result<Builder<'_>, ElfError> {
+    let mut builder = Builder::read(data)?;
+
+    for section in builder.sections.iter_mut() {
+        if is_stripped_section(section.name.as_slice()) {
+            section.delete = true;
+        }
+    }
+
+    for symbol in builder.symbols.iter_mut() {
+        symbol.delete = true;
+    }
+
+    builder.delete_orphans();
+
+    Ok(builder)
+}
+
+fn parse_header(data: &[u8]) -> Result<&FileHeader64<Endianness>, ElfError> {
+    let kind = FileKind::parse(data)?;
+    if kind != FileKind::Elf64 {
+        error!("Failed to parse binary");
+        return Err(ElfError::Not64BitElf);
+    }
+
+    Ok(FileHeader64::<Endianness>::parse(data)?)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use object::elf::{DT_NULL, EM_X86_64, ET_DYN, PF_R, PT_LOAD};
+    use object::elf::{VER_DEF_CURRENT, VER_NEED_CURRENT};
+    use object::write::elf::{FileHeader, ProgramHeader, Verdef, Vernaux, Verneed, Writer};
+    use object::{Object, ObjectSection};
+
+    // shared object with an optional DT_SONAME, defining and requiring symbol
+    // versions, loaded at address zero so that addresses are file offsets
+    pub(crate) fn versioned_elf(
+        soname: Option<&str>,
+        defines: &[&str],
+        needs: &[(&str, &str)],
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut writer = Writer::new(Endianness::Little, true, &mut data);
+
+        let base = writer.add_dynamic_string(soname.unwrap_or("fixture").as_bytes());
+        let defines: Vec<_> = defines
+            .iter()
+            .map(|name| writer.add_dynamic_string(name.as_bytes()))
+            .collect();
+        let needs: Vec<_> = needs
+            .iter()
+            .map(|(library, version)| {
+                let library = writer.add_dynamic_string(library.as_bytes());
+                (library, writer.add_dynamic_string(version.as_bytes()))
+            })
+            .collect();
+        let verdefs = if defines.is_empty() {
+            0
+        } else {
+            defines.len() + 1
+        };
+        let dynamics = 3 + usize::from(soname.is_some());
+
+        writer.reserve_file_header();
+        writer.reserve_program_headers(2);
+        let dynstr = writer.reserve_dynstr();
+        let dynamic = writer.reserve_dynamic(dynamics);
+        let verdef = writer.reserve_gnu_verdef(verdefs, verdefs);
+        let verneed = writer.reserve_gnu_verneed(needs.len(), needs.len());
+        let size = writer.reserved_len() as u64;
+
+        writer.reserve_null_section_index();
+        writer.reserve_dynstr_section_index();
+        writer.reserve_dynamic_section_index();
+        if verdefs > 0 {
+            writer.reserve_gnu_verdef_section_index();
+        }
+        if !needs.is_empty() {
+            writer.reserve_gnu_verneed_section_index();
+        }
+        writer.reserve_shstrtab_section_index();
+        writer.reserve_shstrtab();
+        writer.reserve_section_headers();
+
+        writer
+            .write_file_header(&FileHeader {
+                os_abi: 0,
+                abi_version: 0,
+                e_type: ET_DYN,
+                e_machine: EM_X86_64,
+                e_entry: 0,
+                e_flags: 0,
+            })
+            .unwrap();
+        writer.write_align_program_headers();
+        for (p_type, offset, len) in [
+            (PT_LOAD, 0, size),
+            (PT_DYNAMIC, dynamic as u64, 16 * dynamics as u64),
+        ] {
+            writer.write_program_header(&ProgramHeader {
+                p_type,
+                p_flags: PF_R,
+                p_offset: offset,
+                p_vaddr: offset,
+                p_paddr: offset,
+                p_filesz: len,
+                p_memsz: len,
+                p_align: 8,
+            });
+        }
+
+        let strsz = writer.dynstr_len() as u64;
+        writer.write_dynstr();
+        writer.write_align_dynamic();
+        if soname.is_some() {
+            writer.write_dynamic_string(DT_SONAME, base);
+        }
+        writer.write_dynamic(DT_STRTAB, dynstr as u64);
+        writer.write_dynamic(DT_STRSZ, strsz);
+        writer.write_dynamic(DT_NULL, 0);
+
+        writer.write_align_gnu_verdef();
+        if verdefs > 0 {
+            writer.write_gnu_verdef(&Verdef {
+                version: VER_DEF_CURRENT,
+                flags: VER_FLG_BASE,
+                index: 1,
+                aux_count: 1,
+                name: base,
+            });
+        }
+        for (index, name) in defines.into_iter().enumerate() {
+            writer.write_gnu_verdef(&Verdef {
+                version: VER_DEF_CURRENT,
+                flags: 0,
+                index: index as u16 + 2,
+                aux_count: 1,
+                name,
+            });
+        }
+
+        writer.write_align_gnu_verneed();
+        for (index, (library, version)) in needs.into_iter().enumerate() {
+            writer.write_gnu_verneed(&Verneed {
+                version: VER_NEED_CURRENT,
+                aux_count: 1,
+                file: library,
+            });
+            writer.write_gnu_vernaux(&Vernaux {
+                flags: 0,
+                index: (verdefs + index + 1) as u16,
+                name: version,
+            });
+        }
+
+        writer.write_shstrtab();
+        writer.write_null_section_header();
+        writer.write_dynstr_section_header(dynstr as u64);
+        writer.write_dynamic_section_header(dynamic as u64);
+        writer.write_gnu_verdef_section_header(verdef as u64);
+        writer.write_gnu_verneed_section_header(verneed as u64);
+        writer.write_shstrtab_section_header();
+
+        data
+    }
 
     #[test]
     fn test_failure() {
@@ -187,4 +629,118 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_cache() {
+        // the dynamic loader does not need any library
+        let exe = std::env::current_exe().unwrap();
+        let Some(loader) = Elf::interpreter(&exe).unwrap() else {
+            return;
+        };
+        let data = fs::read(&loader).unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let link = dir.join("ld.so");
+        std::os::unix::fs::symlink(&loader, &link).unwrap();
+
+        let mut cache = ElfCache::default();
+        let first = cache.dependencies(&loader, &data).unwrap();
+        let linked = cache.dependencies(&link, &data);
+
+        assert_eq!(first, linked.unwrap());
+        assert_eq!(cache.dependencies(&loader, &data).unwrap(), first);
+        assert_eq!(cache.parsed(), 1);
+
+        let name = loader.file_name().unwrap();
+        if let Ok(path) = cache.find_library(name) {
+            assert_eq!(cache.find_library(name).unwrap(), path);
+            assert_eq!(cache.searched(), 1);
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let library = versioned_elf(Some("libfixture.so.1"), &["FIXTURE_1.0"], &[]);
+        let binary = versioned_elf(None, &[], &[("libfixture.so.1", "FIXTURE_2.0")]);
+
+        assert_eq!(Elf::version_definitions(&library).unwrap(), ["FIXTURE_1.0"]);
+        assert_eq!(Elf::version_requirements(&library).unwrap(), []);
+        assert_eq!(Elf::soname(&library).unwrap().unwrap(), "libfixture.so.1");
+        assert_eq!(
+            Elf::version_definitions(&binary).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            Elf::version_requirements(&binary).unwrap(),
+            [VersionNeed {
+                library: "libfixture.so.1".to_string(),
+                version: "FIXTURE_2.0".to_string(),
+            }]
+        );
+        assert_eq!(Elf::soname(&binary).unwrap(), None);
+    }
+
+    #[test]
+    fn test_strip() {
+        // test binaries are built with symbols and debug information
+        let path = std::env::current_exe().unwrap();
+        let data = fs::read(path).unwrap();
+        let stripped = Elf::strip(&data).unwrap();
+
+        assert!(stripped.len() < data.len());
+        assert_eq!(Elf::needed(&data).unwrap(), Elf::needed(&stripped).unwrap());
+        assert_eq!(
+            Elf::version_requirements(&data).unwrap(),
+            Elf::version_requirements(&stripped).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_split_debug() {
+        let path = std::env::current_exe().unwrap();
+        let data = fs::read(path).unwrap();
+        let split = Elf::split_debug(&data, "elusive.debug").unwrap().unwrap();
+
+        let section = |data: &[u8], name: &str| {
+            let file = object::File::parse(data).unwrap();
+            file.section_by_name(name)
+                .map(|section| section.data().unwrap().to_vec())
+        };
+
+        let link = section(&split.stripped, DEBUGLINK_SECTION).unwrap();
+        assert_eq!(link.len(), 20);
+        assert_eq!(&link[..14], b"elusive.debug\0");
+
+        let mut crc = Crc::new();
+        crc.update(&split.debug);
+        let stored = u32::from_ne_bytes(link[16..].try_into().unwrap());
+        assert_eq!(stored, crc.sum());
+
+        assert!(section(&split.stripped, ".debug_info").is_none());
+        assert!(section(&split.debug, ".debug_info").is_some());
+        assert_eq!(
+            Elf::needed(&data).unwrap(),
+            Elf::needed(&split.stripped).unwrap()
+        );
+
+        // nothing left to split
+        assert!(
+            Elf::split_debug(&Elf::strip(&data).unwrap(), "elusive.debug")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_interpreter() {
+        assert!(Elf::interpreter(Path::new("/dev/null")).is_err());
+
+        let ls = PathBuf::from("/bin/ls");
+        if ls.exists() {
+            let interp = Elf::interpreter(&ls).unwrap().unwrap();
+            assert!(interp.is_absolute());
+            assert!(interp.exists());
+        }
+    }
 }
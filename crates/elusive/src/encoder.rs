@@ -1,12 +1,63 @@
 //! Convenience types for handling cpio archive compression.
 
+use crate::io::CountingWriter;
+
+use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use serde::{Deserialize, Deserializer};
+use std::ffi::OsStr;
+use std::fmt;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::str::FromStr;
 use zstd::Encoder as ZstdEncoder;
 
+/// Magic number of gzip streams.
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+/// Magic number of zstd frames.
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+
+/// Magic numbers of compression formats the kernel can unpack an initramfs
+/// from (gzip, bzip2, lzma, xz, lzo, lz4 and zstd).
+const COMPRESSED_MAGICS: &[&[u8]] = &[
+    b"\x1f\x8b",
+    b"BZh",
+    b"\x5d\x00\x00",
+    b"\xfd7zXZ\x00",
+    b"\x89LZO",
+    b"\x02\x21\x4c\x18",
+    b"\x28\xb5\x2f\xfd",
+];
+
+/// Smallest file considered incompressible, below it the header of a separate
+/// segment costs about as much as compressing the file.
+pub const MIN_INCOMPRESSIBLE_SIZE: usize = 16 << 10;
+
+/// Extensions of files that are already compressed.
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "bz2", "gz", "jpeg", "jpg", "lz4", "lzma", "lzo", "png", "sfs", "squashfs", "xz", "zip", "zst",
+];
+
+/// Magic numbers of compressed formats on top of the ones of
+/// [`COMPRESSED_MAGICS`]: squashfs, zip and png.
+const INCOMPRESSIBLE_MAGICS: &[&[u8]] = &[b"hsqs", b"PK\x03\x04", b"\x89PNG"];
+
+/// Bytes sampled to estimate the entropy of a file.
+const ENTROPY_SAMPLE: usize = 64 << 10;
+
+/// Entropy above which a sample is considered random, in bits per byte.
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Check if data starts with the magic number of a compression format
+/// supported by the kernel for initramfs archives.
+pub fn is_compressed(data: &[u8]) -> bool {
+    COMPRESSED_MAGICS
+        .iter()
+        .any(|magic| data.starts_with(magic))
+}
+
 /// Custom error type for archive compression handling.
 #[derive(thiserror::Error, Debug)]
 pub enum EncoderError {
@@ -14,6 +65,20 @@ pub enum EncoderError {
     InputOutput(io::Error),
     #[error("unknown encoder: {0}")]
     UnknownEncoder(String),
+    #[error("unsupported compression format, only gzip and zstd can be decoded")]
+    UnsupportedCompression,
+    #[error(
+        "cannot finish encoding: {consumed} of {expected} input bytes encoded, {written} encoded bytes handed to the writer: {err}"
+    )]
+    ShortWrite {
+        /// Bytes of input accepted by the encoder.
+        consumed: u64,
+        /// Bytes of input to encode.
+        expected: u64,
+        /// Bytes of encoded output accepted by the writer.
+        written: u64,
+        err: io::Error,
+    },
 }
 
 impl From<io::Error> for EncoderError {
@@ -29,37 +94,172 @@ pub enum Encoder {
     None,
     Gzip,
     Zstd,
+    /// Zstd with a dictionary, the kernel cannot decompress the result so it
+    /// is only suitable for raw zstd output (e.g. build caches).
+    ZstdDictionary(Vec<u8>),
+}
+
+/// A writer whose output is only complete once finished, e.g. compressed
+/// streams that end with a trailer.
+pub trait FinishableWrite: Write {
+    /// Write what is still buffered and the end of the stream, then flush the
+    /// inner writer.
+    fn finish(self: Box<Self>) -> Result<(), io::Error>;
+}
+
+/// Writes data as is.
+struct Passthrough<W>(W);
+
+impl<W: Write> Write for Passthrough<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> FinishableWrite for Passthrough<W> {
+    fn finish(mut self: Box<Self>) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> FinishableWrite for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<(), io::Error> {
+        GzEncoder::finish(*self)?.flush()
+    }
+}
+
+impl<W: Write> FinishableWrite for ZstdEncoder<'_, W> {
+    fn finish(self: Box<Self>) -> Result<(), io::Error> {
+        ZstdEncoder::finish(*self)?.flush()
+    }
 }
 
 impl Encoder {
-    /// Encode the provided bytes using the specified encoder variant.
-    pub fn encode<T>(&self, data: &[u8], mut out: T) -> Result<(), EncoderError>
+    /// Encode the provided bytes using the specified encoder variant. The
+    /// stream is finished explicitly, so a failure to write its end is
+    /// reported along with how much was written, see
+    /// [`EncoderError::ShortWrite`].
+    pub fn encode<T>(&self, data: &[u8], out: T) -> Result<(), EncoderError>
     where
         T: Write,
     {
-        match self {
-            Encoder::None => {
-                out.write_all(data)?;
-            }
-            Encoder::Gzip => {
-                let mut gzenc = GzEncoder::new(&mut out, Compression::default());
-                gzenc.write_all(data)?;
-            }
+        let mut output = CountingWriter::new(out);
+        let mut input = CountingWriter::new(self.writer(&mut output)?);
+
+        let result = input.write_all(data);
+        let consumed = input.count();
+        let result = result.and_then(|()| input.into_inner().finish());
+
+        result.map_err(|err| EncoderError::ShortWrite {
+            consumed,
+            expected: data.len() as u64,
+            written: output.count(),
+            err,
+        })
+    }
+
+    /// Wrap a writer so data written to the result is encoded into it. The
+    /// result has to be finished for the output to be complete, dropping it
+    /// may lose the end of the stream.
+    pub fn writer<'a, W>(&self, out: W) -> Result<Box<dyn FinishableWrite + 'a>, EncoderError>
+    where
+        W: Write + 'a,
+    {
+        let writer: Box<dyn FinishableWrite + 'a> = match self {
+            Encoder::None => Box::new(Passthrough(out)),
+            Encoder::Gzip => Box::new(GzEncoder::new(out, Compression::default())),
             Encoder::Zstd => {
-                let mut zstdenc = ZstdEncoder::new(&mut out, 3)?;
+                let mut zstdenc = ZstdEncoder::new(out, 3)?;
+                zstdenc.multithread(num_cpus::get() as u32)?;
 
-                let nproc = num_cpus::get() as u32;
-                zstdenc.multithread(nproc)?;
+                Box::new(zstdenc)
+            }
+            Encoder::ZstdDictionary(dictionary) => {
+                let mut zstdenc = ZstdEncoder::with_dictionary(out, 3, dictionary)?;
+                zstdenc.multithread(num_cpus::get() as u32)?;
 
-                zstdenc.write_all(data)?;
-                zstdenc.finish()?;
+                Box::new(zstdenc)
             }
-        }
+        };
 
-        Ok(())
+        Ok(writer)
     }
 }
 
+/// Guess whether compressing a file would be wasted effort: small files never
+/// are, files are otherwise recognized by their extension, their magic number,
+/// or the entropy of a sample taken from their middle.
+pub fn is_incompressible(path: &Path, data: &[u8]) -> bool {
+    if data.len() < MIN_INCOMPRESSIBLE_SIZE {
+        return false;
+    }
+
+    let extension = path.extension().and_then(OsStr::to_str);
+    if extension.is_some_and(|extension| INCOMPRESSIBLE_EXTENSIONS.contains(&extension)) {
+        return true;
+    }
+
+    if is_compressed(data)
+        || INCOMPRESSIBLE_MAGICS
+            .iter()
+            .any(|magic| data.starts_with(magic))
+    {
+        return true;
+    }
+
+    // headers are often less random than the rest of the file
+    let start = data.len().saturating_sub(ENTROPY_SAMPLE) / 2;
+    let end = data.len().min(start + ENTROPY_SAMPLE);
+
+    entropy(&data[start..end]) > ENTROPY_THRESHOLD
+}
+
+/// Shannon entropy of the bytes of a sample, in bits per byte.
+fn entropy(sample: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+
+    let len = sample.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Decompress gzip or zstd data, uncompressed data is returned as is.
+pub fn decode(data: &[u8]) -> Result<Vec<u8>, EncoderError> {
+    if data.starts_with(GZIP_MAGIC) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut decoded)?;
+        return Ok(decoded);
+    }
+
+    if data.starts_with(ZSTD_MAGIC) {
+        return Ok(zstd::decode_all(data)?);
+    }
+
+    if is_compressed(data) {
+        return Err(EncoderError::UnsupportedCompression);
+    }
+
+    Ok(data.to_vec())
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes from the provided samples.
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, EncoderError> {
+    Ok(zstd::dict::from_samples(samples, max_size)?)
+}
+
 impl FromStr for Encoder {
     type Err = EncoderError;
 
@@ -73,6 +273,46 @@ impl FromStr for Encoder {
     }
 }
 
+/// Name of the encoder as accepted by [`Encoder::from_str`].
+impl fmt::Display for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoder::None => write!(f, "none"),
+            Encoder::Gzip => write!(f, "gzip"),
+            Encoder::Zstd => write!(f, "zstd"),
+            Encoder::ZstdDictionary(_) => write!(f, "zstd with dictionary"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Encoder {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::{Error, Visitor};
+
+        struct EncoderVisitor;
+
+        impl Visitor<'_> for EncoderVisitor {
+            type Value = Encoder;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "an encoder name such as 'gzip'")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                v.parse().map_err(Error::custom)
+            }
+        }
+
+        deserializer.deserialize_str(EncoderVisitor)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -94,6 +334,194 @@ mod tests {
         assert!(Encoder::from_str("someotherencoder").is_err());
     }
 
+    #[test]
+    fn test_deserialize() {
+        for encoder in [Encoder::None, Encoder::Gzip, Encoder::Zstd] {
+            let parsed: Encoder = serde_yaml::from_str(&encoder.to_string()).unwrap();
+            assert_eq!(parsed, encoder);
+        }
+
+        let unknown = serde_yaml::from_str::<Encoder>("lz4").unwrap_err();
+        assert!(
+            unknown.to_string().contains("unknown encoder: lz4"),
+            "{unknown}"
+        );
+        assert!(serde_yaml::from_str::<Encoder>("19").is_err());
+    }
+
+    #[test]
+    fn test_incompressible() {
+        // xorshift, random enough to look compressed
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let random: Vec<u8> = (0..MIN_INCOMPRESSIBLE_SIZE * 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let text = b"options snd_hda_intel power_save=1\n".repeat(2048);
+
+        let mut zstd = Vec::new();
+        Encoder::Zstd.encode(&text, &mut zstd).unwrap();
+        zstd.resize(MIN_INCOMPRESSIBLE_SIZE, 0);
+
+        let firmware = Path::new("/usr/lib/firmware/iwlwifi-ty-a0-gf-a0-83.ucode");
+        let module = Path::new("/usr/lib/modules/6.1.0/kernel/snd.ko");
+        let compressed = Path::new("/usr/lib/firmware/amdgpu/navi10_sos.bin.zst");
+
+        assert!(is_incompressible(firmware, &random));
+        assert!(!is_incompressible(firmware, &random[..1024]));
+        assert!(!is_incompressible(module, &text));
+        assert!(is_incompressible(module, &zstd));
+        assert!(is_incompressible(compressed, &text));
+
+        assert!(entropy(&random) > 7.9);
+        assert!(entropy(&text) < 5.0);
+        assert_eq!(entropy(&[0; 64]), 0.0);
+    }
+
+    #[test]
+    fn test_dictionary() {
+        // similar small files, like configuration or unit files
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| {
+                format!(
+                    "[Unit]\nDescription=Sample unit {i}\nAfter=local-fs-{}.target\n\n\
+                     [Service]\nType=oneshot\nExecStart=/usr/bin/sample --id {}\n",
+                    i % 7,
+                    i * 31
+                )
+                .into_bytes()
+            })
+            .collect();
+
+        let dictionary = train_dictionary(&samples, 4096).unwrap();
+        assert!(!dictionary.is_empty() && dictionary.len() <= 4096);
+
+        let data = samples[42].clone();
+        let mut buf_zstd = Vec::new();
+        let mut buf_dictionary = Vec::new();
+
+        Encoder::Zstd.encode(&data, &mut buf_zstd).unwrap();
+        Encoder::ZstdDictionary(dictionary.clone())
+            .encode(&data, &mut buf_dictionary)
+            .unwrap();
+
+        assert!(buf_dictionary.len() < buf_zstd.len());
+
+        // frames need the dictionary to be decompressed
+        assert!(decode(&buf_dictionary).is_err());
+        let mut decoder =
+            zstd::Decoder::with_dictionary(buf_dictionary.as_slice(), &dictionary).unwrap();
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_writer() {
+        let data = dummy_archive().serialize().unwrap();
+        // zstd accepts any content as a raw dictionary
+        let dictionary = data.clone();
+
+        let encoders = [
+            Encoder::None,
+            Encoder::Gzip,
+            Encoder::Zstd,
+            Encoder::ZstdDictionary(dictionary),
+        ];
+
+        for encoder in encoders {
+            let mut out = Vec::new();
+            let mut writer = encoder.writer(&mut out).unwrap();
+
+            // streamed in small chunks, as a serializer would
+            for chunk in data.chunks(7) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let decoded = if let Encoder::ZstdDictionary(dictionary) = &encoder {
+                let mut decoder =
+                    zstd::Decoder::with_dictionary(out.as_slice(), dictionary).unwrap();
+                let mut decoded = Vec::new();
+                decoder.read_to_end(&mut decoded).unwrap();
+                decoded
+            } else {
+                decode(&out).unwrap()
+            };
+
+            assert_eq!(decoded, data, "{encoder:?}");
+        }
+    }
+
+    /// Writer failing with ENOSPC once `capacity` bytes are written.
+    struct Full {
+        data: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+            let available = self.capacity - self.data.len();
+            if available == 0 {
+                return Err(io::Error::from(io::ErrorKind::StorageFull));
+            }
+
+            let len = buf.len().min(available);
+            self.data.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_short_write() {
+        let data = dummy_archive().serialize().unwrap();
+
+        let mut full = Full {
+            data: Vec::new(),
+            capacity: 100,
+        };
+        let err = Encoder::None.encode(&data, &mut full).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "cannot finish encoding: 100 of {} input bytes encoded, 100 encoded bytes handed to the writer: no storage space",
+                data.len()
+            )
+        );
+
+        // the end of compressed streams is only written when finished
+        for encoder in [Encoder::Gzip, Encoder::Zstd] {
+            let mut full = Full {
+                data: Vec::new(),
+                capacity: 10,
+            };
+            let err = encoder.encode(&data, &mut full).unwrap_err();
+
+            let EncoderError::ShortWrite {
+                consumed,
+                expected,
+                written,
+                err,
+            } = err
+            else {
+                panic!("unexpected error for {encoder:?}: {err}");
+            };
+
+            assert_eq!(expected, data.len() as u64);
+            assert!(consumed <= expected, "{encoder:?}");
+            assert_eq!(written, 10, "{encoder:?}");
+            assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        }
+    }
+
     #[test]
     fn test_encode_ext() {
         let archive = dummy_archive();
@@ -112,5 +540,17 @@ mod tests {
 
         // zstd should always compress better
         assert!(buf_none.len() > buf_zstd.len());
+
+        assert!(!is_compressed(&buf_none));
+        assert!(is_compressed(&buf_gzip));
+        assert!(is_compressed(&buf_zstd));
+
+        assert_eq!(decode(&buf_none).unwrap(), data);
+        assert_eq!(decode(&buf_gzip).unwrap(), data);
+        assert_eq!(decode(&buf_zstd).unwrap(), data);
+        assert!(matches!(
+            decode(b"BZh91AY&SY"),
+            Err(EncoderError::UnsupportedCompression)
+        ));
     }
 }
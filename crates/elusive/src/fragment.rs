@@ -0,0 +1,554 @@
+//! Configuration fragments read by systemd in early userspace.
+//!
+//! Configuration modules contribute sysctl settings, `tmpfiles.d` lines,
+//! kernel modules to load at boot and environment variables for the init,
+//! merged into a single file of each kind generated by elusive. Fragments are
+//! validated when added: typos in these files are at best reported in the
+//! boot log.
+
+use std::collections::BTreeMap;
+use std::{fmt, iter};
+
+/// Path of the generated sysctl settings in the initramfs.
+pub const SYSCTL_PATH: &str = "/etc/sysctl.d/90-elusive.conf";
+
+/// Path of the generated `tmpfiles.d` lines in the initramfs.
+pub const TMPFILES_PATH: &str = "/etc/tmpfiles.d/elusive.conf";
+
+/// Path of the generated list of kernel modules to load in the initramfs.
+pub const MODULES_LOAD_PATH: &str = "/etc/modules-load.d/elusive.conf";
+
+/// Default path of the generated environment variables in the initramfs.
+pub const ENVIRONMENT_PATH: &str = "/etc/initrd-env";
+
+/// Path of the generated systemd manager drop-in in the initramfs.
+pub const SYSTEMD_ENVIRONMENT_PATH: &str = "/etc/systemd/system.conf.d/10-elusive-env.conf";
+
+/// Header of the generated files.
+const HEADER: &str = "# Generated by elusive\n";
+
+/// Line types known to systemd-tmpfiles.
+const TMPFILES_TYPES: &str = "fFwdDevqQpLcbCxXrRzZtThHaA";
+
+/// Modifiers following the line type of a `tmpfiles.d` line.
+const TMPFILES_MODIFIERS: &str = "+!-=~^$?";
+
+/// Custom error type for configuration fragments.
+#[derive(thiserror::Error, Debug)]
+pub enum FragmentError {
+    #[error("invalid sysctl key: {0:?}")]
+    SysctlKey(String),
+    #[error("invalid value for sysctl {0}: {1:?}")]
+    SysctlValue(String, String),
+    #[error("sysctl {0}")]
+    SysctlConflict(Box<Conflict>),
+    #[error("invalid tmpfiles.d line {line:?}: {reason}")]
+    Tmpfiles { line: String, reason: &'static str },
+    #[error("invalid kernel module name for modules-load.d: {0:?}")]
+    ModuleName(String),
+    #[error("invalid environment variable name: {0:?}")]
+    EnvironmentKey(String),
+    #[error("invalid value for environment variable {0}: {1:?}")]
+    EnvironmentValue(String, String),
+    #[error("environment variable {0}")]
+    EnvironmentConflict(Box<Conflict>),
+}
+
+/// A key set to two different values.
+#[derive(Debug)]
+pub struct Conflict {
+    pub key: String,
+    pub first: String,
+    pub first_module: Option<String>,
+    pub second: String,
+    pub second_module: Option<String>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = |module: &Option<String>| {
+            module
+                .as_ref()
+                .map(|module| format!(" by module '{module}'"))
+                .unwrap_or_default()
+        };
+
+        write!(
+            f,
+            "{} is set to '{}'{} and to '{}'{}",
+            self.key,
+            self.first,
+            origin(&self.first_module),
+            self.second,
+            origin(&self.second_module)
+        )
+    }
+}
+
+/// A sysctl setting or environment variable and the configuration module it
+/// comes from.
+#[derive(Clone, Debug)]
+struct Assignment {
+    key: String,
+    value: String,
+    module: Option<String>,
+}
+
+impl Assignment {
+    fn conflict(&self, value: &str, module: Option<&str>) -> Conflict {
+        Conflict {
+            key: self.key.clone(),
+            first: self.value.clone(),
+            first_module: self.module.clone(),
+            second: value.to_string(),
+            second_module: module.map(String::from),
+        }
+    }
+}
+
+/// Fragments merged from every configuration module.
+#[derive(Clone, Default, Debug)]
+pub struct Fragments {
+    // keyed by the normalized key to detect conflicts
+    sysctl: BTreeMap<String, Assignment>,
+    tmpfiles: Vec<String>,
+    modules_load: Vec<String>,
+    environment: BTreeMap<String, Assignment>,
+}
+
+impl Fragments {
+    /// Add a sysctl setting, returning whether it was not set yet. Setting a
+    /// key to another value than before is a conflict, even from the same
+    /// module.
+    pub fn add_sysctl(
+        &mut self,
+        key: &str,
+        value: &str,
+        module: Option<&str>,
+    ) -> Result<bool, FragmentError> {
+        validate_sysctl(key, value)?;
+
+        let value = value.trim();
+        let normalized = key.trim_start_matches('-').replace('/', ".");
+
+        if let Some(existing) = self.sysctl.get(&normalized) {
+            if existing.value == value {
+                return Ok(false);
+            }
+
+            return Err(FragmentError::SysctlConflict(Box::new(
+                existing.conflict(value, module),
+            )));
+        }
+
+        let sysctl = Assignment {
+            key: key.to_string(),
+            value: value.to_string(),
+            module: module.map(String::from),
+        };
+
+        self.sysctl.insert(normalized, sysctl);
+        Ok(true)
+    }
+
+    /// Add a `tmpfiles.d` line, returning whether it was not added yet.
+    pub fn add_tmpfiles(&mut self, line: &str) -> Result<bool, FragmentError> {
+        validate_tmpfiles(line)?;
+
+        let line = line.trim();
+        if self.tmpfiles.iter().any(|existing| existing == line) {
+            return Ok(false);
+        }
+
+        self.tmpfiles.push(line.to_string());
+        Ok(true)
+    }
+
+    /// Add a kernel module to load at boot, returning whether it was not
+    /// listed yet.
+    pub fn add_modules_load(&mut self, name: &str) -> Result<bool, FragmentError> {
+        let valid = !name.is_empty()
+            && !name.ends_with(".ko")
+            && name
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !matches!(c, '/' | '#' | ';'));
+
+        if !valid {
+            return Err(FragmentError::ModuleName(name.to_string()));
+        }
+
+        if self.modules_load.iter().any(|existing| existing == name) {
+            return Ok(false);
+        }
+
+        self.modules_load.push(name.to_string());
+        Ok(true)
+    }
+
+    /// Add an environment variable, returning whether it was not set yet.
+    /// Setting a variable to another value than before is a conflict, even
+    /// from the same module.
+    pub fn add_environment(
+        &mut self,
+        key: &str,
+        value: &str,
+        module: Option<&str>,
+    ) -> Result<bool, FragmentError> {
+        validate_environment(key, value)?;
+
+        if let Some(existing) = self.environment.get(key) {
+            if existing.value == value {
+                return Ok(false);
+            }
+
+            return Err(FragmentError::EnvironmentConflict(Box::new(
+                existing.conflict(value, module),
+            )));
+        }
+
+        let variable = Assignment {
+            key: key.to_string(),
+            value: value.to_string(),
+            module: module.map(String::from),
+        };
+
+        self.environment.insert(key.to_string(), variable);
+        Ok(true)
+    }
+
+    /// Render the sysctl settings, sorted by key.
+    pub fn sysctl(&self) -> String {
+        let lines: Vec<_> = self
+            .sysctl
+            .values()
+            .map(|sysctl| format!("{} = {}", sysctl.key, sysctl.value))
+            .collect();
+
+        render(&lines)
+    }
+
+    /// Render the `tmpfiles.d` lines, in the order they were added.
+    pub fn tmpfiles(&self) -> String {
+        render(&self.tmpfiles)
+    }
+
+    /// Render the kernel modules to load, in the order they were added.
+    pub fn modules_load(&self) -> String {
+        render(&self.modules_load)
+    }
+
+    /// Render the environment variables as shell assignments, sorted by name.
+    pub fn environment(&self) -> String {
+        let lines: Vec<_> = self
+            .environment
+            .values()
+            .map(|variable| format!("{}={}", variable.key, shell_quote(&variable.value)))
+            .collect();
+
+        render(&lines)
+    }
+
+    /// Render the environment variables as a systemd manager drop-in, sorted
+    /// by name.
+    pub fn systemd_environment(&self) -> String {
+        let lines: Vec<_> = iter::once("[Manager]".to_string())
+            .chain(self.environment.values().map(|variable| {
+                let assignment = format!("{}={}", variable.key, variable.value);
+                format!("DefaultEnvironment={}", systemd_quote(&assignment))
+            }))
+            .collect();
+
+        render(&lines)
+    }
+}
+
+fn render(lines: &[String]) -> String {
+    let mut rendered = String::from(HEADER);
+
+    for line in lines {
+        rendered.push_str(line);
+        rendered.push('\n');
+    }
+
+    rendered
+}
+
+// single quote unless the value only has characters the shell leaves alone
+fn shell_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+
+    if plain {
+        return value.to_string();
+    }
+
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// double quote with C-style escapes, as systemd splits assignments on spaces
+fn systemd_quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Check that an environment variable can be set by a shell: names are valid
+/// shell identifiers and values hold no nul byte.
+pub fn validate_environment(key: &str, value: &str) -> Result<(), FragmentError> {
+    let mut chars = key.chars();
+    let valid_key = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid_key {
+        return Err(FragmentError::EnvironmentKey(key.to_string()));
+    }
+
+    if value.contains('\0') {
+        return Err(FragmentError::EnvironmentValue(
+            key.to_string(),
+            value.to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that a sysctl setting can be written to `sysctl.d`: keys are made of
+/// dot or slash separated components, optionally prefixed with `-` to ignore
+/// failures, and values hold a single line.
+pub fn validate_sysctl(key: &str, value: &str) -> Result<(), FragmentError> {
+    let name = key.strip_prefix('-').unwrap_or(key);
+    let valid_key = !name.is_empty()
+        && name
+            .split(['.', '/'])
+            .all(|component| !component.is_empty())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '/' | '*' | ':'));
+
+    if !valid_key {
+        return Err(FragmentError::SysctlKey(key.to_string()));
+    }
+
+    if value.contains(['\n', '\r']) {
+        return Err(FragmentError::SysctlValue(
+            key.to_string(),
+            value.to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check the type, path and mode fields of a `tmpfiles.d` line, the fields
+/// after them are left to systemd-tmpfiles.
+pub fn validate_tmpfiles(line: &str) -> Result<(), FragmentError> {
+    let invalid = |reason| FragmentError::Tmpfiles {
+        line: line.to_string(),
+        reason,
+    };
+
+    if line.contains(['\n', '\r']) {
+        return Err(invalid("expected a single line"));
+    }
+
+    if line.trim_start().starts_with('#') {
+        return Ok(());
+    }
+
+    let mut fields = line.split_whitespace();
+    let Some(kind) = fields.next() else {
+        return Err(invalid("empty line"));
+    };
+
+    let mut chars = kind.chars();
+    let known = chars.next().is_some_and(|c| TMPFILES_TYPES.contains(c));
+    if !known || !chars.all(|c| TMPFILES_MODIFIERS.contains(c)) {
+        return Err(invalid("unknown line type"));
+    }
+
+    // paths may start with a specifier such as %t
+    match fields.next() {
+        Some(path) if path.starts_with(['/', '%', '"']) => (),
+        Some(_) => return Err(invalid("path must be absolute")),
+        None => return Err(invalid("missing path")),
+    }
+
+    if let Some(mode) = fields.next() {
+        let digits = mode.strip_prefix(['~', ':']).unwrap_or(mode);
+        let octal = (3..=4).contains(&digits.len())
+            && u32::from_str_radix(digits, 8).is_ok_and(|mode| mode <= 0o7777);
+
+        if mode != "-" && !octal {
+            return Err(invalid("invalid mode"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sysctl() {
+        let mut fragments = Fragments::default();
+        assert!(fragments
+            .add_sysctl("vm.swappiness", "10", Some("tuning"))
+            .unwrap());
+        assert!(fragments
+            .add_sysctl("-net/ipv4/ip_forward", " 1", None)
+            .unwrap());
+        assert!(!fragments
+            .add_sysctl("vm/swappiness", "10", Some("other"))
+            .unwrap());
+
+        assert_eq!(
+            fragments.sysctl(),
+            "# Generated by elusive\n-net/ipv4/ip_forward = 1\nvm.swappiness = 10\n"
+        );
+
+        let conflict = fragments
+            .add_sysctl("vm.swappiness", "60", Some("other"))
+            .unwrap_err();
+        assert_eq!(
+            conflict.to_string(),
+            "sysctl vm.swappiness is set to '10' by module 'tuning' and to '60' by module 'other'"
+        );
+
+        for key in [
+            "",
+            "vm..swappiness",
+            "vm swappiness",
+            ".vm",
+            "vm.swappiness=",
+        ] {
+            assert!(matches!(
+                fragments.add_sysctl(key, "1", None),
+                Err(FragmentError::SysctlKey(_))
+            ));
+        }
+
+        assert!(matches!(
+            fragments.add_sysctl("kernel.domainname", "a\nb", None),
+            Err(FragmentError::SysctlValue(..))
+        ));
+    }
+
+    #[test]
+    fn test_tmpfiles() {
+        let mut fragments = Fragments::default();
+        let valid = [
+            "d /run/cryptsetup 0700 root root -",
+            "L+ /etc/mtab - - - - ../proc/self/mounts",
+            "f~ /etc/issue :0644 - - - ZWx1c2l2ZQ==",
+            "d %t/elusive",
+            "# comments are fine",
+        ];
+
+        for line in valid {
+            assert!(fragments.add_tmpfiles(line).unwrap(), "{line}");
+        }
+        assert!(!fragments.add_tmpfiles(valid[0]).unwrap());
+
+        let reason = |line: &str| match validate_tmpfiles(line) {
+            Err(FragmentError::Tmpfiles { reason, .. }) => reason,
+            other => panic!("{line}: {other:?}"),
+        };
+
+        assert_eq!(reason(""), "empty line");
+        assert_eq!(reason("y /run/elusive"), "unknown line type");
+        assert_eq!(reason("dd /run/elusive"), "unknown line type");
+        assert_eq!(reason("d run/elusive"), "path must be absolute");
+        assert_eq!(reason("d"), "missing path");
+        assert_eq!(reason("d /run/elusive 0999"), "invalid mode");
+        assert_eq!(reason("d /run/elusive rwx"), "invalid mode");
+
+        let rendered = fragments.tmpfiles();
+        assert!(rendered.starts_with("# Generated by elusive\nd /run/cryptsetup"));
+        assert_eq!(rendered.lines().count(), valid.len() + 1);
+    }
+
+    #[test]
+    fn test_environment() {
+        let mut fragments = Fragments::default();
+        assert!(fragments
+            .add_environment("ROOT", "UUID=1234", Some("root"))
+            .unwrap());
+        assert!(fragments
+            .add_environment("BANNER", "it's \"quoted\"", None)
+            .unwrap());
+        assert!(fragments.add_environment("EMPTY", "", None).unwrap());
+        assert!(!fragments
+            .add_environment("ROOT", "UUID=1234", Some("other"))
+            .unwrap());
+
+        assert_eq!(
+            fragments.environment(),
+            "# Generated by elusive\nBANNER='it'\\''s \"quoted\"'\nEMPTY=''\nROOT=UUID=1234\n"
+        );
+        assert_eq!(
+            fragments.systemd_environment(),
+            "# Generated by elusive\n[Manager]\n\
+             DefaultEnvironment=\"BANNER=it's \\\"quoted\\\"\"\n\
+             DefaultEnvironment=\"EMPTY=\"\n\
+             DefaultEnvironment=\"ROOT=UUID=1234\"\n"
+        );
+
+        let conflict = fragments
+            .add_environment("ROOT", "/dev/sda2", Some("other"))
+            .unwrap_err();
+        assert_eq!(
+            conflict.to_string(),
+            "environment variable ROOT is set to 'UUID=1234' by module 'root' and to '/dev/sda2' by module 'other'"
+        );
+
+        for key in ["", "1ROOT", "ROOT-UUID", "ROOT UUID", "ROOT="] {
+            assert!(matches!(
+                fragments.add_environment(key, "1", None),
+                Err(FragmentError::EnvironmentKey(_))
+            ));
+        }
+
+        assert!(matches!(
+            fragments.add_environment("NUL", "a\0b", None),
+            Err(FragmentError::EnvironmentValue(..))
+        ));
+    }
+
+    #[test]
+    fn test_modules_load() {
+        let mut fragments = Fragments::default();
+        assert!(fragments.add_modules_load("dm_crypt").unwrap());
+        assert!(fragments.add_modules_load("tpm-crb").unwrap());
+        assert!(!fragments.add_modules_load("dm_crypt").unwrap());
+
+        for name in ["", "dm crypt", "dm_crypt.ko", "/lib/dm_crypt", "#dm_crypt"] {
+            assert!(matches!(
+                fragments.add_modules_load(name),
+                Err(FragmentError::ModuleName(_))
+            ));
+        }
+
+        assert_eq!(
+            fragments.modules_load(),
+            "# Generated by elusive\ndm_crypt\ntpm-crb\n"
+        );
+    }
+}
@@ -0,0 +1,652 @@
+//! Detection of the kernel modules needed to boot the current host.
+//!
+//! Host-only mode looks at the filesystems needed at boot and at the block
+//! devices backing them, so an initramfs can be tailored to this machine
+//! instead of including every storage driver. Devices are described by their
+//! modalias in sysfs, which libkmod resolves to kernel modules.
+//!
+//! Everything is read relative to a root directory, so trees captured from
+//! real machines (`proc/self/mountinfo`, `etc/fstab`, `sys/...`) can be used
+//! in place of the running host.
+//!
+//! The kernel modules loaded on the host (`proc/modules`) can be added as a
+//! whole instead, for an initramfs sure to drive this exact machine.
+
+use log::debug;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Mount points whose filesystems and devices are always needed at boot.
+const MOUNT_POINTS: &[&str] = &["/", "/usr", "/boot"];
+
+/// Input event types, see `linux/input-event-codes.h`.
+const EV_KEY: u64 = 1 << 0x01;
+const EV_REP: u64 = 1 << 0x14;
+
+/// Glob patterns of loaded kernel modules left out when nothing uses them,
+/// unless `lsmod_denylist` is set: bluetooth, sound, webcams, wireless,
+/// virtualization and firewalls are not needed to boot.
+pub const LSMOD_DENYLIST: &[&str] = &[
+    "bluetooth",
+    "bnep",
+    "bt*",
+    "snd*",
+    "soundcore",
+    "uvcvideo",
+    "videobuf2*",
+    "cfg80211",
+    "mac80211",
+    "rfkill",
+    "iwl*",
+    "kvm*",
+    "nf_*",
+    "nft_*",
+    "xt_*",
+    "joydev",
+    "pcspkr",
+];
+
+/// Custom error type for host-only detection.
+#[derive(thiserror::Error, Debug)]
+pub enum HostOnlyError {
+    #[error("i/o error: {0}")]
+    InputOutput(io::Error),
+    #[error("invalid mountinfo line: {0}")]
+    Mountinfo(String),
+    #[error("invalid /proc/modules line: {0}")]
+    ProcModules(String),
+}
+
+impl From<io::Error> for HostOnlyError {
+    fn from(err: io::Error) -> Self {
+        Self::InputOutput(err)
+    }
+}
+
+/// What the host needs to boot, as kernel module names and aliases.
+#[derive(PartialEq, Default, Debug)]
+pub struct HostModules {
+    /// Filesystem types of the mounts needed at boot.
+    pub filesystems: BTreeSet<String>,
+    /// Modules needed by device mapper and software RAID stacks.
+    pub modules: BTreeSet<String>,
+    /// Modaliases of the devices backing mounts and of keyboards.
+    pub modaliases: BTreeSet<String>,
+}
+
+impl HostModules {
+    /// Iterate over every module name or alias to look up.
+    pub fn lookups(&self) -> impl Iterator<Item = &str> {
+        self.filesystems
+            .iter()
+            .chain(&self.modules)
+            .chain(&self.modaliases)
+            .map(String::as_str)
+    }
+}
+
+/// A kernel module loaded on the host, read from `/proc/modules`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LoadedModule {
+    /// Name of the module, with underscores.
+    pub name: String,
+    /// Number of modules and references using it.
+    pub use_count: u32,
+}
+
+/// A mount read from mountinfo.
+struct Mount {
+    /// Device number as `major:minor`.
+    device: String,
+    mount_point: PathBuf,
+    fstype: String,
+    source: String,
+}
+
+/// Host to inspect, rooted at a directory.
+pub struct Host {
+    root: PathBuf,
+}
+
+impl Host {
+    /// Inspect the running host.
+    pub fn new() -> Self {
+        Self::with_root("/")
+    }
+
+    /// Inspect a host tree rooted at the provided directory.
+    pub fn with_root<P>(root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Host { root: root.into() }
+    }
+
+    /// Detect the filesystems, storage stack and keyboards of the host.
+    pub fn detect(&self) -> Result<HostModules, HostOnlyError> {
+        // sysfs paths are compared after resolving symlinks
+        let host = Host {
+            root: fs::canonicalize(&self.root)?,
+        };
+
+        let mut modules = HostModules::default();
+        let mut devices = Vec::new();
+
+        for mount in host.mounts()? {
+            if !MOUNT_POINTS
+                .iter()
+                .any(|path| mount.mount_point == Path::new(path))
+            {
+                continue;
+            }
+
+            debug!(
+                "Host-only mount: {} ({})",
+                mount.mount_point.display(),
+                mount.fstype
+            );
+            modules.filesystems.insert(mount.fstype);
+
+            // btrfs and other multi-device filesystems use anonymous device numbers
+            let device = match host.block_from_number(&mount.device) {
+                Some(device) => Some(device),
+                None => host.block_from_source(&mount.source),
+            };
+            devices.extend(device);
+        }
+
+        for fields in host.table("etc/fstab")? {
+            let (Some(source), Some(fstype)) = (fields.first(), fields.get(2)) else {
+                continue;
+            };
+
+            if let Some(device) = host.block_from_source(source) {
+                modules.filesystems.insert(fstype.clone());
+                devices.push(device);
+            }
+        }
+
+        for fields in host.table("etc/crypttab")? {
+            let Some(source) = fields.get(1) else {
+                continue;
+            };
+
+            modules.modules.insert("dm_crypt".to_string());
+            devices.extend(host.block_from_source(source));
+        }
+
+        let mut visited = HashSet::new();
+        for device in devices {
+            host.add_block_device(&device, &mut modules, &mut visited)?;
+        }
+
+        host.add_keyboards(&mut modules)?;
+        Ok(modules)
+    }
+
+    /// Read the kernel modules loaded on the host.
+    pub fn loaded_modules(&self) -> Result<Vec<LoadedModule>, HostOnlyError> {
+        let modules = fs::read_to_string(self.root.join("proc/modules"))?;
+        parse_proc_modules(&modules)
+    }
+
+    fn mounts(&self) -> Result<Vec<Mount>, HostOnlyError> {
+        let mountinfo = fs::read_to_string(self.root.join("proc/self/mountinfo"))?;
+        let mut mounts = Vec::new();
+
+        for line in mountinfo.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let separator = fields.iter().position(|field| *field == "-");
+
+            // optional fields end with a single dash, then fstype and source
+            let mount = match (separator, fields.get(2), fields.get(4)) {
+                (Some(separator), Some(device), Some(mount_point)) if separator >= 6 => {
+                    let (Some(fstype), Some(source)) =
+                        (fields.get(separator + 1), fields.get(separator + 2))
+                    else {
+                        return Err(HostOnlyError::Mountinfo(line.to_string()));
+                    };
+
+                    Mount {
+                        device: (*device).to_string(),
+                        mount_point: PathBuf::from(unescape(mount_point)),
+                        fstype: (*fstype).to_string(),
+                        source: unescape(source),
+                    }
+                }
+                _ => return Err(HostOnlyError::Mountinfo(line.to_string())),
+            };
+
+            mounts.push(mount);
+        }
+
+        Ok(mounts)
+    }
+
+    // whitespace separated fields of a table such as fstab, ignoring comments
+    fn table(&self, path: &str) -> Result<Vec<Vec<String>>, HostOnlyError> {
+        let Some(content) = read_optional(&self.root.join(path))? else {
+            return Ok(Vec::new());
+        };
+
+        let table = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.split_whitespace().map(unescape).collect())
+            .collect();
+
+        Ok(table)
+    }
+
+    fn block_from_number(&self, device: &str) -> Option<PathBuf> {
+        fs::canonicalize(self.root.join("sys/dev/block").join(device)).ok()
+    }
+
+    // resolve /dev paths and UUID=, LABEL=, ... to a block device in sysfs
+    fn block_from_source(&self, source: &str) -> Option<PathBuf> {
+        let path = match source.split_once('=') {
+            Some(("UUID", value)) => self.root.join("dev/disk/by-uuid").join(value),
+            Some(("LABEL", value)) => self.root.join("dev/disk/by-label").join(value),
+            Some(("PARTUUID", value)) => self.root.join("dev/disk/by-partuuid").join(value),
+            Some(("PARTLABEL", value)) => self.root.join("dev/disk/by-partlabel").join(value),
+            _ => self.root.join(source.strip_prefix('/')?),
+        };
+
+        let node = fs::canonicalize(path).ok()?;
+        if !node.starts_with(self.root.join("dev")) {
+            return None;
+        }
+
+        let name = node.file_name()?;
+        fs::canonicalize(self.root.join("sys/class/block").join(name)).ok()
+    }
+
+    fn add_block_device(
+        &self,
+        device: &Path,
+        modules: &mut HostModules,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<(), HostOnlyError> {
+        if !visited.insert(device.to_path_buf()) {
+            return Ok(());
+        }
+
+        debug!("Host-only block device: {}", device.display());
+
+        if device.join("dm").is_dir() {
+            modules.modules.insert("dm_mod".to_string());
+
+            let uuid = read_optional(&device.join("dm/uuid"))?.unwrap_or_default();
+            if uuid.starts_with("CRYPT-") {
+                modules.modules.insert("dm_crypt".to_string());
+            }
+        }
+
+        if let Some(level) = read_optional(&device.join("md/level"))? {
+            let module = match level.trim() {
+                "raid4" | "raid5" | "raid6" => "raid456",
+                level => level,
+            };
+
+            modules.modules.insert(module.to_string());
+        }
+
+        // stacked devices (dm, md) are built on top of their slaves
+        match fs::read_dir(device.join("slaves")) {
+            Ok(slaves) => {
+                for slave in slaves {
+                    let slave = fs::canonicalize(slave?.path())?;
+                    self.add_block_device(&slave, modules, visited)?;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        // partitions are driven through their disk
+        let disk = match device.parent() {
+            Some(parent) if device.join("partition").exists() => parent,
+            _ => device,
+        };
+
+        let hardware = disk.join("device");
+        if hardware.exists() {
+            self.add_modaliases(&hardware, modules)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_keyboards(&self, modules: &mut HostModules) -> Result<(), HostOnlyError> {
+        let inputs = match fs::read_dir(self.root.join("sys/class/input")) {
+            Ok(inputs) => inputs,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        for input in inputs {
+            let input = input?.path();
+            let Some(ev) = read_optional(&input.join("capabilities/ev"))? else {
+                continue;
+            };
+
+            // keyboards report keys with autorepeat, unlike mice and buttons
+            let ev = u64::from_str_radix(ev.trim(), 16).unwrap_or_default();
+            if ev & EV_KEY != 0 && ev & EV_REP != 0 {
+                debug!("Host-only keyboard: {}", input.display());
+                self.add_modaliases(&input, modules)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // collect modaliases of a device and of every parent device up to the bus
+    fn add_modaliases(
+        &self,
+        device: &Path,
+        modules: &mut HostModules,
+    ) -> Result<(), HostOnlyError> {
+        let devices = self.root.join("sys/devices");
+        let device = fs::canonicalize(device)?;
+
+        for dir in device
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&devices))
+        {
+            if let Some(modalias) = read_optional(&dir.join("modalias"))? {
+                let modalias = modalias.trim();
+
+                if !modalias.is_empty() {
+                    modules.modaliases.insert(modalias.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse the content of `/proc/modules`: name, size, use count, users,
+/// state and address of each module.
+pub fn parse_proc_modules(content: &str) -> Result<Vec<LoadedModule>, HostOnlyError> {
+    let mut modules = Vec::new();
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        let module = match (fields.first(), fields.get(2)) {
+            (Some(name), Some(count)) => LoadedModule {
+                name: (*name).to_string(),
+                use_count: count
+                    .parse()
+                    .map_err(|_| HostOnlyError::ProcModules(line.to_string()))?,
+            },
+            _ => return Err(HostOnlyError::ProcModules(line.to_string())),
+        };
+
+        modules.push(module);
+    }
+
+    Ok(modules)
+}
+
+/// Parse a list of kernel module names, one per line, ignoring comments.
+/// Only the first word of a line is kept, so the output of `lsmod` or a copy
+/// of `/proc/modules` can be used as well.
+pub fn parse_module_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        // header of lsmod
+        .filter(|name| *name != "Module")
+        .map(str::to_string)
+        .collect()
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// mountinfo and fstab escape whitespace and backslashes as octal (e.g. \040)
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let octal = bytes
+            .get(index + 1..index + 4)
+            .filter(|digits| {
+                bytes[index] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d))
+            })
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+
+        match octal {
+            Some(byte) => {
+                unescaped.push(byte);
+                index += 4;
+            }
+            None => {
+                unescaped.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    const MOUNTINFO: &str = "\
+22 1 254:0 / / rw,relatime shared:1 - ext4 /dev/mapper/root rw
+23 22 0:21 / /proc rw,nosuid shared:5 - proc proc rw
+24 22 259:1 / /boot rw,relatime shared:2 - vfat /dev/nvme0n1p1 rw
+25 22 0:35 / /mnt/my\\040data rw - btrfs /dev/sdb rw
+";
+
+    // write a file, creating parent directories
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn link(root: &Path, path: &str, target: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        symlink(target, path).unwrap();
+    }
+
+    // laptop with LUKS on NVMe, an unencrypted /boot, a USB disk in fstab
+    // and a USB keyboard, trimmed down from a real sysfs tree
+    fn fixture(root: &Path) {
+        let pci = "sys/devices/pci0000:00/0000:00:06.0";
+        let nvme = format!("{pci}/nvme/nvme0/nvme0n1");
+        let usb = "sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0";
+        let disk = format!("{usb}/host0/target0:0:0/0:0:0:0");
+
+        write(root, "proc/self/mountinfo", MOUNTINFO);
+        write(
+            root,
+            "etc/fstab",
+            "# comment\nUUID=1234 /boot vfat defaults 0 2\nLABEL=backup /backup xfs noauto 0 0\n",
+        );
+        write(root, "etc/crypttab", "root UUID=abcd none luks\n");
+
+        write(root, &format!("{pci}/modalias"), "pci:v0000144Dd0000A808\n");
+        write(root, &format!("{nvme}/nvme0n1p1/partition"), "1\n");
+        write(root, &format!("{nvme}/nvme0n1p2/partition"), "2\n");
+        link(root, &format!("{nvme}/device"), "../../nvme0");
+
+        write(
+            root,
+            "sys/devices/virtual/block/dm-0/dm/uuid",
+            "CRYPT-LUKS2-abcd-root\n",
+        );
+        link(
+            root,
+            "sys/devices/virtual/block/dm-0/slaves/nvme0n1p2",
+            &format!("../../../../../../{nvme}/nvme0n1p2"),
+        );
+
+        write(
+            root,
+            &format!("{usb}/modalias"),
+            "usb:v0781p5581d0100dc00dsc00dp00ic08isc06ip50in00\n",
+        );
+        write(root, &format!("{disk}/modalias"), "scsi:t-0x00\n");
+        fs::create_dir_all(root.join(format!("{disk}/block/sdb"))).unwrap();
+        link(root, &format!("{disk}/block/sdb/device"), "../..");
+
+        let keyboard = "sys/devices/pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0/input/input4";
+        let mouse = "sys/devices/pci0000:00/0000:00:14.0/usb1/1-4/1-4:1.0/input/input5";
+        write(root, &format!("{keyboard}/capabilities/ev"), "120013\n");
+        write(
+            root,
+            &format!("{keyboard}/modalias"),
+            "input:b0003v046Dp4075e0111-e0,1,4,11,14\n",
+        );
+        write(
+            root,
+            "sys/devices/pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0/modalias",
+            "usb:v046Dp4075d0101dc00dsc00dp00ic03isc01ip01in00\n",
+        );
+        write(root, &format!("{mouse}/capabilities/ev"), "17\n");
+        link(
+            root,
+            "sys/class/input/input4",
+            &format!("../../../{keyboard}"),
+        );
+        link(root, "sys/class/input/input5", &format!("../../../{mouse}"));
+
+        for (name, target) in [
+            ("nvme0n1", nvme.clone()),
+            ("nvme0n1p1", format!("{nvme}/nvme0n1p1")),
+            ("nvme0n1p2", format!("{nvme}/nvme0n1p2")),
+            ("dm-0", "sys/devices/virtual/block/dm-0".to_string()),
+            ("sdb", format!("{disk}/block/sdb")),
+        ] {
+            link(
+                root,
+                &format!("sys/class/block/{name}"),
+                &format!("../../../{target}"),
+            );
+        }
+
+        link(
+            root,
+            "sys/dev/block/254:0",
+            "../../../sys/devices/virtual/block/dm-0",
+        );
+        link(
+            root,
+            "sys/dev/block/259:1",
+            &format!("../../../{nvme}/nvme0n1p1"),
+        );
+
+        fs::create_dir_all(root.join("dev/mapper")).unwrap();
+        for name in ["nvme0n1p1", "nvme0n1p2", "dm-0", "sdb"] {
+            write(root, &format!("dev/{name}"), "");
+        }
+        link(root, "dev/mapper/root", "../dm-0");
+        link(root, "dev/disk/by-uuid/1234", "../../nvme0n1p1");
+        link(root, "dev/disk/by-uuid/abcd", "../../nvme0n1p2");
+        link(root, "dev/disk/by-label/backup", "../../sdb");
+    }
+
+    #[test]
+    fn test_detect() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fixture(root);
+
+        let detected = Host::with_root(root).detect();
+
+        let detected = detected.unwrap();
+        assert_eq!(
+            detected.filesystems,
+            BTreeSet::from(["ext4".into(), "vfat".into(), "xfs".into()])
+        );
+        assert_eq!(
+            detected.modules,
+            BTreeSet::from(["dm_crypt".into(), "dm_mod".into()])
+        );
+        assert_eq!(
+            detected.modaliases,
+            BTreeSet::from([
+                "input:b0003v046Dp4075e0111-e0,1,4,11,14".into(),
+                "pci:v0000144Dd0000A808".into(),
+                "scsi:t-0x00".into(),
+                "usb:v046Dp4075d0101dc00dsc00dp00ic03isc01ip01in00".into(),
+                "usb:v0781p5581d0100dc00dsc00dp00ic08isc06ip50in00".into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("/mnt/my\\040data"), "/mnt/my data");
+        assert_eq!(unescape("back\\134slash"), "back\\slash");
+        assert_eq!(unescape("trailing\\04"), "trailing\\04");
+    }
+
+    #[test]
+    fn test_loaded_modules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write(
+            root,
+            "proc/modules",
+            "snd_hda_intel 61440 3 - Live 0x0000000000000000\n\
+             dm_crypt 65536 1 - Live 0x0000000000000000\n\
+             bluetooth 1024000 0 - Live 0x0000000000000000\n",
+        );
+
+        let modules = Host::with_root(root).loaded_modules().unwrap();
+
+        let names: Vec<_> = modules.iter().map(|module| module.name.as_str()).collect();
+        assert_eq!(names, ["snd_hda_intel", "dm_crypt", "bluetooth"]);
+        assert_eq!(modules[0].use_count, 3);
+        assert_eq!(modules[2].use_count, 0);
+
+        assert!(matches!(
+            parse_proc_modules("ext4 1081344 two - Live 0x0"),
+            Err(HostOnlyError::ProcModules(_))
+        ));
+        assert!(parse_proc_modules("ext4").is_err());
+    }
+
+    #[test]
+    fn test_parse_module_list() {
+        let lsmod = "\
+Module                  Size  Used by
+snd_hda_intel          61440  3
+dm_crypt               65536  1
+";
+        assert_eq!(parse_module_list(lsmod), ["snd_hda_intel", "dm_crypt"]);
+        assert_eq!(
+            parse_module_list("# captured\nnvme\n\n  xhci-pci\n"),
+            ["nvme", "xhci-pci"]
+        );
+    }
+}
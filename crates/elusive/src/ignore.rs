@@ -0,0 +1,130 @@
+//! Files left out when walking directory trees.
+//!
+//! Editor backups, version control metadata and Python caches are ignored by
+//! default. Dotfiles are not, since placeholder files such as `.keep` can be
+//! needed in the initramfs.
+//!
+//! Patterns without a `/` are matched against the name of each entry, others
+//! against its path relative to the walked directory. A matching directory is
+//! skipped along with its content.
+
+use glob::{MatchOptions, Pattern, PatternError};
+use std::path::Path;
+
+/// Patterns ignored unless disabled.
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    // editor backups and swap files
+    "*~",
+    ".#*",
+    "#*#",
+    "*.swp",
+    "*.swo",
+    "*.bak",
+    "*.orig",
+    "*.rej",
+    // version control
+    ".git",
+    ".gitignore",
+    ".gitkeep",
+    ".gitattributes",
+    ".gitmodules",
+    ".hg",
+    ".hgignore",
+    ".svn",
+    // python caches
+    "__pycache__",
+    "*.pyc",
+    "*.pyo",
+];
+
+const PATH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Compiled set of ignore patterns.
+#[derive(Clone, Debug)]
+pub struct Ignore {
+    names: Vec<Pattern>,
+    paths: Vec<Pattern>,
+}
+
+impl Ignore {
+    /// Compile the default patterns, if enabled, along with the provided ones.
+    pub fn new<S>(defaults: bool, exclude: &[S]) -> Result<Self, PatternError>
+    where
+        S: AsRef<str>,
+    {
+        let defaults = DEFAULT_PATTERNS.iter().filter(|_| defaults);
+        let mut ignore = Ignore {
+            names: Vec::new(),
+            paths: Vec::new(),
+        };
+
+        for pattern in defaults.copied().chain(exclude.iter().map(AsRef::as_ref)) {
+            let pattern = pattern.trim_start_matches('/');
+
+            if pattern.contains('/') {
+                ignore.paths.push(Pattern::new(pattern)?);
+            } else {
+                ignore.names.push(Pattern::new(pattern)?);
+            }
+        }
+
+        Ok(ignore)
+    }
+
+    /// Check if the entry at the provided path, relative to the walked
+    /// directory, is ignored.
+    pub fn is_ignored(&self, relative: &Path) -> bool {
+        let name = relative.file_name().unwrap_or_default().to_string_lossy();
+
+        self.names.iter().any(|pattern| pattern.matches(&name))
+            || self
+                .paths
+                .iter()
+                .any(|pattern| pattern.matches_path_with(relative, PATH_OPTIONS))
+    }
+}
+
+impl Default for Ignore {
+    fn default() -> Self {
+        Self::new::<&str>(true, &[]).expect("default patterns should be valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore() {
+        let defaults = Ignore::default();
+        let custom = Ignore::new(false, &["*.md", "/share/doc/*"]).unwrap();
+
+        let ignored = [
+            "hook.sh~",
+            "lib/.#hook.sh",
+            "#hook.sh#",
+            ".hook.sh.swp",
+            ".git",
+            "sub/.gitkeep",
+            "lib/__pycache__",
+            "lib/module.cpython-312.pyc",
+        ];
+        for path in ignored {
+            assert!(defaults.is_ignored(Path::new(path)), "{path}");
+            assert!(!custom.is_ignored(Path::new(path)), "{path}");
+        }
+
+        for path in [".keep", "etc/.profile", "hook.sh", "gitkeep"] {
+            assert!(!defaults.is_ignored(Path::new(path)), "{path}");
+        }
+
+        assert!(custom.is_ignored(Path::new("README.md")));
+        assert!(custom.is_ignored(Path::new("share/doc/README")));
+        assert!(!custom.is_ignored(Path::new("share/doc/sub/README")));
+        assert!(!custom.is_ignored(Path::new("doc/share/doc")));
+    }
+}
@@ -0,0 +1,394 @@
+//! Checks and helpers for the init of the initramfs.
+//!
+//! The kernel runs `/init` as PID 1, which has to reap the orphans it inherits
+//! and handle signals, otherwise zombies pile up and the payload cannot be
+//! stopped cleanly. The lint only looks at the VFS and reports when `/init`
+//! does not look like it does either:
+//!
+//! - scripts need their interpreter in the initramfs and should end with the
+//!   `exec` of a known init, or of `switch_root` handing over to the real root,
+//! - ELF files are compared by file name and soname to a list of known inits.
+//!
+//! Separately, [`check_exec`] follows what the kernel does to execute `/init`
+//! through the symlinks of the VFS, which a missing `/bin -> usr/bin` breaks
+//! for `#!/bin/sh` scripts with a misleading "no working init found" panic.
+
+use crate::elf::Elf;
+use crate::vfs::Vfs;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Path of the init in the initramfs.
+pub const INIT_PATH: &str = "/init";
+
+/// Names of executables known to behave as PID 1.
+pub const KNOWN_INITS: &[&str] = &[
+    "systemd",
+    "busybox",
+    "tini",
+    "tini-static",
+    "catatonit",
+    "s6-svscan",
+];
+
+/// Names of executables handing PID 1 over to the init of the real root.
+const HANDOVERS: &[&str] = &["switch_root", "run-init"];
+
+/// Interpreters followed from `/init`, the kernel refuses deeper chains of
+/// scripts.
+const MAX_INTERPRETERS: usize = 4;
+
+const HINT: &str = "use an init helper (init: { use: tini, exec: ... }) or see settings.emergency";
+
+/// Check that `/init` looks like it can run as PID 1. The host path of the
+/// configured init, if any, is used to name ELF files installed as `/init`.
+pub fn lint(vfs: &Vfs, source: Option<&Path>) -> Vec<String> {
+    let path = vfs.resolve(INIT_PATH);
+    let Some(data) = vfs.get(&path).and_then(|entry| entry.data.as_deref()) else {
+        return vec![format!("{INIT_PATH} is not a file in the initramfs")];
+    };
+
+    if let Some(script) = data.strip_prefix(b"#!") {
+        lint_script(vfs, &String::from_utf8_lossy(script))
+    } else if Elf::is_elf(data) {
+        lint_elf(data, source)
+    } else {
+        vec![format!(
+            "{INIT_PATH} is neither a script nor an ELF executable; {HINT}"
+        )]
+    }
+}
+
+/// Check that the kernel can execute `/init` from the initramfs: the
+/// interpreter of a script, and the program interpreter of an ELF file, have
+/// to resolve to files of the VFS through its symlinks. Returns why the exec
+/// would fail, a missing `/init` is left to the lint.
+pub fn check_exec(vfs: &Vfs) -> Option<String> {
+    let mut path = PathBuf::from(INIT_PATH);
+    let mut data = vfs.lookup(&path).ok()?.1.data.as_deref()?;
+
+    for _ in 0..MAX_INTERPRETERS {
+        let (kind, interpreter) = if let Some(script) = data.strip_prefix(b"#!") {
+            let line = script
+                .split(|&byte| byte == b'\n')
+                .next()
+                .unwrap_or_default();
+
+            match String::from_utf8_lossy(line).split_whitespace().next() {
+                Some(interpreter) => ("interpreter", PathBuf::from(interpreter)),
+                None => return Some(format!("{} has no interpreter after #!", path.display())),
+            }
+        } else if Elf::is_elf(data) {
+            match Elf::interpreter_from_data(data) {
+                Ok(Some(interpreter)) => ("program interpreter", interpreter),
+                Ok(None) => return None,
+                Err(err) => {
+                    return Some(format!(
+                        "{} is not a valid ELF file: {}",
+                        path.display(),
+                        err
+                    ))
+                }
+            }
+        } else {
+            return None;
+        };
+
+        data = match vfs.lookup(&interpreter) {
+            Ok((_, entry)) if entry.is_file() => entry.data.as_deref().unwrap_or_default(),
+            Ok((resolved, _)) => {
+                return Some(format!(
+                    "{kind} {} of {} resolves to {}, which is not a file",
+                    interpreter.display(),
+                    path.display(),
+                    resolved.display()
+                ));
+            }
+            Err(unresolved) => {
+                return Some(format!(
+                    "{kind} {} of {} cannot be resolved in the initramfs: {unresolved}",
+                    interpreter.display(),
+                    path.display()
+                ));
+            }
+        };
+
+        path = interpreter;
+    }
+
+    Some(format!(
+        "{INIT_PATH} goes through more than {MAX_INTERPRETERS} interpreters"
+    ))
+}
+
+/// Generate a script that execs the init helper with the payload, all paths
+/// being in the initramfs. Arguments are split by the shell.
+pub fn wrapper_script(shell: &Path, helper: &Path, args: Option<&str>, exec: &Path) -> String {
+    let args = args.map(|args| format!(" {args}")).unwrap_or_default();
+
+    format!(
+        "#!{}\nexec {}{args} {}\n",
+        shell.display(),
+        helper.display(),
+        exec.display()
+    )
+}
+
+// the script is what follows the `#!`
+fn lint_script(vfs: &Vfs, script: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut lines = script.lines();
+
+    match lines.next().and_then(|line| line.split_whitespace().next()) {
+        Some(interpreter) if !vfs.contains_file(vfs.resolve(interpreter)) => {
+            problems.push(format!(
+                "interpreter {interpreter} of {INIT_PATH} is not in the initramfs"
+            ));
+        }
+        Some(_) => (),
+        None => problems.push(format!("{INIT_PATH} has no interpreter after #!")),
+    }
+
+    let last = lines
+        .map(str::trim)
+        .rfind(|line| !line.is_empty() && !line.starts_with('#'));
+
+    let target = last.and_then(|line| {
+        let mut words = line.split_whitespace();
+        (words.next() == Some("exec"))
+            .then(|| words.next())
+            .flatten()
+    });
+
+    match target {
+        Some(target) => {
+            // e.g. /sbin/init may be a symlink to busybox
+            let resolved = vfs.resolve(target);
+            let names = [Path::new(target).file_name(), resolved.file_name()];
+            let known = names.into_iter().flatten().any(|name| {
+                is_known_init(name) || HANDOVERS.iter().any(|handover| name == *handover)
+            });
+
+            if !known {
+                problems.push(format!(
+                    "{INIT_PATH} execs {target}, which is not a known init and may not reap zombies as PID 1; {HINT}"
+                ));
+            }
+        }
+        None => problems.push(format!(
+            "{INIT_PATH} does not end with the exec of an init, the shell stays PID 1 and does not reap zombies; {HINT}"
+        )),
+    }
+
+    problems
+}
+
+fn lint_elf(data: &[u8], source: Option<&Path>) -> Vec<String> {
+    // /sbin/init is usually a symlink to the actual init on the host
+    let source = source.map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    let name = source.as_deref().and_then(Path::file_name);
+    let soname = Elf::soname(data).ok().flatten();
+
+    if name.into_iter().chain(soname.as_deref()).any(is_known_init) {
+        return Vec::new();
+    }
+
+    let name = name.map_or("unknown".into(), OsStr::to_string_lossy);
+    vec![format!(
+        "{INIT_PATH} ({name}) is not a known init and may not reap zombies or handle signals as PID 1; {HINT}"
+    )]
+}
+
+// sonames carry a suffix, e.g. libfoo.so.1
+fn is_known_init(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    let name = name.split(".so").next().unwrap_or_default();
+
+    KNOWN_INITS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::ImagePath;
+    use crate::vfs::Entry;
+
+    use std::path::PathBuf;
+
+    fn vfs_with_init(init: &[u8]) -> Vfs {
+        let mut vfs = Vfs::new();
+        for dir in ["/usr/bin", "/sbin"] {
+            vfs.create_dir_all(&ImagePath::new(dir).unwrap()).unwrap();
+        }
+
+        for (path, entry) in [
+            ("/usr/bin/sh", Entry::file(b"sh".to_vec())),
+            ("/usr/bin/busybox", Entry::file(b"busybox".to_vec())),
+            ("/sbin/init", Entry::symlink("../usr/bin/busybox")),
+            (INIT_PATH, Entry::file(init.to_vec())),
+        ] {
+            vfs.create_entry(&ImagePath::new(path).unwrap(), entry)
+                .unwrap();
+        }
+
+        vfs
+    }
+
+    #[test]
+    fn test_lint_script() {
+        let check = |script: &str| lint(&vfs_with_init(script.as_bytes()), None);
+
+        // exec of a known init, directly, through a symlink or after a handover
+        assert!(check("#!/usr/bin/sh\nmount -a\nexec /usr/bin/tini -- /app\n").is_empty());
+        assert!(check("#!/usr/bin/sh\nexec /sbin/init\n# done\n\n").is_empty());
+        assert!(check("#!/usr/bin/sh\nexec switch_root /sysroot /sbin/init\n").is_empty());
+
+        let problems = check("#!/usr/bin/bash\nexec tini /app\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("interpreter /usr/bin/bash"));
+
+        let problems = check("#!/usr/bin/sh\n/app\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("does not end with the exec"));
+
+        let problems = check("#!/usr/bin/sh\nexec /app --serve\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("execs /app"));
+
+        let problems = check("#!\nexec tini /app\n");
+        assert_eq!(problems, ["/init has no interpreter after #!"]);
+    }
+
+    #[test]
+    fn test_lint_elf() {
+        let data = fs::read("/proc/self/exe").unwrap();
+        let vfs = vfs_with_init(&data);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("catatonit"), &data).unwrap();
+        std::os::unix::fs::symlink("catatonit", dir.join("init")).unwrap();
+        fs::write(dir.join("myinit"), &data).unwrap();
+
+        // named after the file, following host symlinks
+        let known = lint(&vfs, Some(&dir.join("catatonit")));
+        let symlink = lint(&vfs, Some(&dir.join("init")));
+        let unknown = lint(&vfs, Some(&dir.join("myinit")));
+
+        assert!(known.is_empty());
+        assert!(symlink.is_empty());
+        assert_eq!(unknown.len(), 1);
+        assert!(unknown[0].contains("(myinit) is not a known init"));
+        assert!(unknown[0].contains("settings.emergency"));
+
+        let problems = lint(&vfs, None);
+        assert!(problems[0].contains("(unknown) is not a known init"));
+
+        let problems = lint(&vfs_with_init(b"\0data"), None);
+        assert!(problems[0].contains("neither a script nor an ELF"));
+
+        assert_eq!(
+            lint(&Vfs::new(), Some(&PathBuf::from("/sbin/init"))),
+            ["/init is not a file in the initramfs"]
+        );
+    }
+
+    #[test]
+    fn test_check_exec() {
+        let image = |path: &str| ImagePath::new(path).unwrap();
+        let merged = |init: &[u8]| {
+            let mut vfs = vfs_with_init(init);
+            vfs.create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+                .unwrap();
+            vfs
+        };
+
+        // the interpreter is found through the /bin symlink
+        assert_eq!(check_exec(&merged(b"#!/bin/sh\nexec /sbin/init\n")), None);
+        assert_eq!(check_exec(&merged(b"#! /sbin/init -s\n")), None);
+
+        // without it, the kernel cannot execute /init
+        let broken = check_exec(&vfs_with_init(b"#!/bin/sh\nexec /sbin/init\n"));
+        assert_eq!(
+            broken.as_deref(),
+            Some(
+                "interpreter /bin/sh of /init cannot be resolved in the initramfs: \
+                 /bin/sh does not exist, /bin is missing"
+            )
+        );
+
+        let problem = check_exec(&merged(b"#!/bin/bash\n")).unwrap();
+        assert!(
+            problem.ends_with("/usr/bin/bash does not exist"),
+            "{problem}"
+        );
+        let problem = check_exec(&merged(b"#!/usr\n")).unwrap();
+        assert!(
+            problem.ends_with("resolves to /usr, which is not a file"),
+            "{problem}"
+        );
+        let problem = check_exec(&merged(b"#!\n")).unwrap();
+        assert_eq!(problem, "/init has no interpreter after #!");
+
+        // scripts running scripts, up to a point
+        let mut vfs = merged(b"#!/usr/bin/loop\n");
+        vfs.create_entry(
+            &image("/usr/bin/loop"),
+            Entry::file(b"#!/bin/loop\n".to_vec()),
+        )
+        .unwrap();
+        let problem = check_exec(&vfs).unwrap();
+        assert!(problem.contains("more than 4 interpreters"), "{problem}");
+
+        // the program interpreter of an ELF file, directly or as interpreter
+        let data = fs::read("/proc/self/exe").unwrap();
+        let Some(interpreter) = Elf::interpreter_from_data(&data).unwrap() else {
+            return;
+        };
+
+        let mut vfs = merged(&data);
+        let problem = check_exec(&vfs).unwrap();
+        assert!(
+            problem.starts_with(&format!(
+                "program interpreter {} of /init cannot be resolved",
+                interpreter.display()
+            )),
+            "{problem}"
+        );
+
+        let loader = ImagePath::new(&interpreter).unwrap();
+        vfs.create_dir_all(&loader.parent().unwrap()).unwrap();
+        vfs.create_entry(&loader, Entry::file(fs::read(&interpreter).unwrap()))
+            .unwrap();
+        vfs.create_entry(&image("/usr/bin/app"), Entry::file(data.clone()))
+            .unwrap();
+        vfs.remove_entry(image(INIT_PATH));
+        vfs.create_entry(&image(INIT_PATH), Entry::file(b"#!/usr/bin/app\n".to_vec()))
+            .unwrap();
+        assert_eq!(check_exec(&vfs), None);
+    }
+
+    #[test]
+    fn test_wrapper_script() {
+        let script = wrapper_script(
+            Path::new("/usr/bin/sh"),
+            Path::new("/usr/bin/tini"),
+            Some("--"),
+            Path::new("/usr/bin/myapp"),
+        );
+        assert_eq!(
+            script,
+            "#!/usr/bin/sh\nexec /usr/bin/tini -- /usr/bin/myapp\n"
+        );
+
+        let script = wrapper_script(
+            Path::new("/bin/sh"),
+            Path::new("/bin/catatonit"),
+            None,
+            Path::new("/app"),
+        );
+        assert_eq!(script, "#!/bin/sh\nexec /bin/catatonit /app\n");
+    }
+}
@@ -3,25 +3,56 @@
 //! This module provides an API to help generating a compressed
 //! cpio archive to use as an initramfs.
 
+use crate::collision;
 use crate::config;
-use crate::elf::{Elf, ElfError};
-use crate::kmod::{Kmod, KmodError, Module, ModuleFormat};
-use crate::newc::Archive;
-use crate::systemd::{Unit, UnitError};
+use crate::console::{Asset, ConsoleError, Share};
+use crate::crypttab;
+use crate::distro::{PackageError, PackageQuery};
+use crate::duplicate::{self, Divergent, Origin};
+use crate::elf::{self, Elf, ElfCache, ElfError, VersionNeed};
+use crate::encoder::Encoder;
+use crate::fragment::{self, FragmentError, Fragments};
+use crate::hostonly::{self, Host, HostModules, HostOnlyError};
+use crate::ignore::Ignore;
+use crate::init;
+use crate::kconfig::{self, KernelConfig};
+use crate::kmod::{self, Kmod, KmodError, Module, ModuleCompression, ModuleFormat};
+use crate::library_policy::{FilteredLibrary, LibraryPolicy};
+use crate::module_policy::{Denial, FilteredModule, ModulePolicy};
+use crate::newc::{self, Archive};
+use crate::package;
+use crate::paths::{escaped, HostPath, ImagePath, PathError};
+use crate::payload::{self, PayloadError, PayloadModules};
+use crate::permissions;
+use crate::plan::{BuildPlan, Step};
+use crate::provenance::{Node, Provenance, CHAIN_SEPARATOR};
+use crate::remote::{self, Fetcher, RemoteError};
+use crate::size::Size;
+use crate::stats::{BuildStats, ModuleStats};
+use crate::systemd::{self, Unit, UnitError};
+use crate::template::{self, TemplateError};
 use crate::vfs::{Entry, Vfs, VfsError};
+use crate::xattr::Xattrs;
 
-use flate2::read::GzDecoder;
-use log::{debug, error};
+use glob::Pattern;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
-use std::{fs, io};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::{env, fmt, fs, io, iter, mem};
 use walkdir::WalkDir;
-use zstd::Decoder as ZstdDecoder;
 
 /// Default directories to include in the initramfs.
 const ROOT_DIRS: &[&str] = &[
-    "/dev", "/etc", "/proc", "/root", "/run", "/sys", "/tmp", "/usr", "/var",
+    "/dev", "/etc", "/proc", "/root", "/run", "/sys", "/tmp", "/usr", "/usr/bin", "/usr/lib",
+    "/var",
 ];
 
 /// Default symlinks to create within the initramfs.
@@ -35,11 +66,103 @@ const ROOT_SYMLINKS: &[(&str, &str)] = &[
     ("/var/run", "../run"),
 ];
 
+/// Path of the crypttab in the initramfs.
+const CRYPTTAB_PATH: &str = "/etc/crypttab";
+
+/// Path of systemd in the initramfs, used to detect systemd images.
+const SYSTEMD_PATH: &str = "/usr/lib/systemd/systemd";
+
+/// Largest file read from the host unless configured otherwise.
+const DEFAULT_MAX_FILE_SIZE: Size = Size(256 << 20);
+
+/// Extensions of compressed kernel modules, which are installed decompressed.
+const MODULE_COMPRESSIONS: &[&str] = &["zst", "xz", "gz"];
+
+/// Directory of the executables systemd runs right before halting.
+const SYSTEMD_SHUTDOWN_DIR: &str = "/usr/lib/systemd/system-shutdown";
+
+/// Libraries loaded by systemd-cryptsetup at runtime for `tpm2-device`.
+const TPM2_LIBRARIES: &[&str] = &[
+    "libtss2-esys.so.0",
+    "libtss2-mu.so.0",
+    "libtss2-rc.so.0",
+    "libtss2-tcti-device.so.0",
+];
+
+/// Provenance reason of libraries added for the dependencies of ELF files.
+const NEEDED_REASON: &str = "DT_NEEDED";
+
+/// Directories searched by the dynamic loader without `/etc/ld.so.cache`.
+const LOADER_DEFAULT_DIRS: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+/// Program generating `/etc/ld.so.cache`, looked up in `PATH`.
+const LDCONFIG: &str = "ldconfig";
+
+/// Units added for the systemd emergency shell, with their dependencies.
+const EMERGENCY_UNITS: &[&str] = &["emergency.target", "rescue.target"];
+
+/// Aliases of `rescue.target`, installed next to it.
+const RESCUE_ALIASES: &[&str] = &["kbrequest.target", "runlevel1.target"];
+
+/// Profile of the busybox emergency shell.
+const EMERGENCY_PROFILE: &[u8] = b"export PATH=/usr/sbin:/usr/bin\nexport PS1='rescue# '\n";
+
+/// Synthetic configuration module the kernel modules loaded on the host are
+/// attributed to, see [`Step::LoadedModules`].
+const LSMOD_MODULE: &str = "lsmod";
+
+/// NSS backend used by sulogin to read `/etc/passwd` and `/etc/shadow`.
+const NSS_FILES_LIBRARY: &str = "libnss_files.so.2";
+
+/// Split-/usr directories and their merged-/usr location, matching the
+/// default symlinks.
+const USR_MERGE: &[(&str, &str)] = &[
+    ("/bin", "/usr/bin"),
+    ("/lib", "/usr/lib"),
+    ("/lib64", "/usr/lib"),
+    ("/sbin", "/usr/bin"),
+    ("/usr/lib64", "/usr/lib"),
+    ("/usr/sbin", "/usr/bin"),
+];
+
+/// Host paths that should not end up in an initramfs by accident.
+const SECRET_PATTERNS: &[&str] = &[
+    "/etc/gshadow",
+    "/etc/gshadow-",
+    "/etc/machine-id",
+    "/etc/shadow",
+    "/etc/shadow-",
+    "/etc/ssh/ssh_host_*_key",
+];
+
 /// Custom error type for initramfs generation.
 #[derive(thiserror::Error, Debug)]
 pub enum InitramfsError {
     #[error("i/o error: {0}")]
     InputOutput(io::Error),
+    #[error(
+        "failed to read {kind} {}{}: {source}",
+        escaped(path),
+        module.as_ref().map(|m| format!(" for module '{m}'")).unwrap_or_default()
+    )]
+    Source {
+        kind: &'static str,
+        path: PathBuf,
+        module: Option<String>,
+        source: io::Error,
+    },
+    #[error(
+        "{kind} {} is {size}, over max_file_size ({limit}){}; raise settings.max_file_size or pass --max-file-size if it belongs in the initramfs",
+        escaped(path),
+        module.as_ref().map(|m| format!(" for module '{m}'")).unwrap_or_default()
+    )]
+    FileTooLarge {
+        kind: &'static str,
+        path: PathBuf,
+        module: Option<String>,
+        size: Size,
+        limit: Size,
+    },
     #[error("failed to walk directory: {0}")]
     Walk(walkdir::Error),
     #[error("vfs error: {0}")]
@@ -50,6 +173,151 @@ pub enum InitramfsError {
     System(UnitError),
     #[error("elf error: {0}")]
     Elf(ElfError),
+    #[error("{0} versioned symbol requirement(s) are not provided by the initramfs")]
+    UnresolvedSymbols(usize),
+    #[error("{0} problem(s) found with the volumes listed in /etc/crypttab")]
+    Crypttab(usize),
+    #[error("{0} dangling symlinks")]
+    DanglingSymlinks(usize),
+    #[error("{0} permission problem(s) found in the initramfs")]
+    Permissions(usize),
+    #[error("{0} group(s) of paths differing only by case found in the initramfs")]
+    CaseCollisions(usize),
+    #[error("the kernel cannot execute /init: {0}")]
+    InitExec(String),
+    #[error("no shutdown entrypoint configured")]
+    MissingShutdown,
+    #[error("/{name} is already provided by {first}, cannot replace it with {second}")]
+    EntrypointConflict {
+        name: &'static str,
+        first: String,
+        second: String,
+    },
+    #[error("{0} shutdown executables configured but /shutdown holds a single script, set settings.shutdown_mode to systemd-hook to install them all")]
+    ShutdownList(usize),
+    #[error("conflicting kernel command line parameters: {0} and {1}")]
+    CmdlineConflict(String, String),
+    #[error("refusing to include secret file: {}", escaped(.0))]
+    SecretFile(PathBuf),
+    #[error("invalid pattern: {0}")]
+    Pattern(glob::PatternError),
+    #[error("template error: {0}")]
+    Template(TemplateError),
+    #[error("host-only detection error: {0}")]
+    HostOnly(HostOnlyError),
+    #[error("console error: {0}")]
+    Console(ConsoleError),
+    #[error(
+        "{} is a default symlink to {}; set skeleton.disable_defaults or remove the conflicting entry",
+        escaped(.0),
+        escaped(.1)
+    )]
+    DefaultSymlinkConflict(PathBuf, PathBuf),
+    #[error(
+        "{} is a skeleton symlink to {}; remove it from skeleton.extra_symlinks or remove the conflicting entry",
+        escaped(.0),
+        escaped(.1)
+    )]
+    SkeletonSymlinkConflict(PathBuf, PathBuf),
+    #[error("invalid path: {0}")]
+    Path(PathError),
+    #[error("remote source error: {0}")]
+    Remote(RemoteError),
+    #[error("package error: {0}")]
+    Package(PackageError),
+    #[error("payload error: {0}")]
+    Payload(PayloadError),
+    #[error("{0}")]
+    Divergent(Box<Divergent>),
+    #[error("{} is not a regular file, only regular files can be renamed", escaped(.0))]
+    RenameNotFile(PathBuf),
+    #[error("kernel module {name} is denied ({denial}), requested by {requested_by}")]
+    ModuleDenied {
+        name: String,
+        denial: Denial,
+        requested_by: String,
+    },
+    #[error("library {name} is denied (denylist pattern '{pattern}'), needed by {requested_by}")]
+    LibraryDenied {
+        name: String,
+        pattern: String,
+        requested_by: String,
+    },
+    #[error("kernel configuration {} does not support {feature}, it needs {needs}", escaped(.config))]
+    KernelConfig {
+        config: PathBuf,
+        feature: String,
+        needs: String,
+    },
+    #[error("configuration fragment error: {0}")]
+    Fragment(FragmentError),
+    #[error("build step '{0}' needs a kernel module context")]
+    StepNeedsKmod(String),
+    #[error("failed to run ldconfig: {0}")]
+    Ldconfig(io::Error),
+    #[error("ldconfig failed ({0}): {1}")]
+    LdconfigFailed(ExitStatus, String),
+}
+
+impl InitramfsError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            InitramfsError::InputOutput(_) => "initramfs_io",
+            InitramfsError::Source { .. } => "initramfs_source",
+            InitramfsError::FileTooLarge { .. } => "initramfs_file_too_large",
+            InitramfsError::Walk(_) => "initramfs_walk",
+            InitramfsError::Vfs(VfsError::TotalSizeExceeded { .. }) => "initramfs_total_size",
+            InitramfsError::Vfs(_) => "initramfs_vfs",
+            InitramfsError::Kmod(KmodError::ModuleNotFound(_)) => "initramfs_kmod_not_found",
+            InitramfsError::Kmod(KmodError::MissingDepmodIndex(_)) => {
+                "initramfs_kmod_missing_index"
+            }
+            InitramfsError::Kmod(_) => "initramfs_kmod",
+            InitramfsError::System(_) => "initramfs_systemd",
+            InitramfsError::Elf(_) => "initramfs_elf",
+            InitramfsError::UnresolvedSymbols(_) => "initramfs_unresolved_symbols",
+            InitramfsError::Crypttab(_) => "initramfs_crypttab",
+            InitramfsError::DanglingSymlinks(_) => "initramfs_dangling_symlinks",
+            InitramfsError::Permissions(_) => "initramfs_permissions",
+            InitramfsError::CaseCollisions(_) => "initramfs_case_collisions",
+            InitramfsError::InitExec(_) => "initramfs_init_exec",
+            InitramfsError::MissingShutdown => "initramfs_missing_shutdown",
+            InitramfsError::EntrypointConflict { .. } => "initramfs_entrypoint_conflict",
+            InitramfsError::ShutdownList(_) => "initramfs_shutdown_list",
+            InitramfsError::CmdlineConflict(..) => "initramfs_cmdline_conflict",
+            InitramfsError::SecretFile(_) => "initramfs_secret_file",
+            InitramfsError::Pattern(_) => "initramfs_pattern",
+            InitramfsError::Template(_) => "initramfs_template",
+            InitramfsError::HostOnly(_) => "initramfs_hostonly",
+            InitramfsError::Console(_) => "initramfs_console",
+            InitramfsError::DefaultSymlinkConflict(..)
+            | InitramfsError::SkeletonSymlinkConflict(..) => "initramfs_skeleton_conflict",
+            InitramfsError::Path(_) => "initramfs_path",
+            InitramfsError::Remote(RemoteError::ChecksumMismatch { .. }) => {
+                "initramfs_remote_checksum"
+            }
+            InitramfsError::Remote(_) => "initramfs_remote",
+            InitramfsError::Package(PackageError::NotInstalled { .. }) => {
+                "initramfs_package_not_installed"
+            }
+            InitramfsError::Package(_) => "initramfs_package",
+            InitramfsError::Payload(_) => "initramfs_payload",
+            InitramfsError::ModuleDenied { .. } => "initramfs_module_denied",
+            InitramfsError::LibraryDenied { .. } => "initramfs_library_denied",
+            InitramfsError::RenameNotFile(_) => "initramfs_rename_not_file",
+            InitramfsError::Divergent(_) => "initramfs_divergent_duplicate",
+            InitramfsError::KernelConfig { .. } => "initramfs_kernel_config",
+            InitramfsError::Fragment(FragmentError::SysctlConflict(_)) => {
+                "initramfs_sysctl_conflict"
+            }
+            InitramfsError::Fragment(_) => "initramfs_fragment",
+            InitramfsError::StepNeedsKmod(_) => "initramfs_step_needs_kmod",
+            InitramfsError::Ldconfig(_) | InitramfsError::LdconfigFailed(..) => {
+                "initramfs_ldconfig"
+            }
+        }
+    }
 }
 
 impl From<io::Error> for InitramfsError {
@@ -58,6 +326,12 @@ impl From<io::Error> for InitramfsError {
     }
 }
 
+impl From<PathError> for InitramfsError {
+    fn from(err: PathError) -> Self {
+        Self::Path(err)
+    }
+}
+
 impl From<walkdir::Error> for InitramfsError {
     fn from(err: walkdir::Error) -> Self {
         Self::Walk(err)
@@ -88,452 +362,6810 @@ impl From<ElfError> for InitramfsError {
     }
 }
 
-/// Builder for initramfs generation.
-pub struct Initramfs {
-    /// Virtual filesystem built for this initramfs.
-    vfs: Vfs,
+impl From<RemoteError> for InitramfsError {
+    fn from(err: RemoteError) -> Self {
+        Self::Remote(err)
+    }
 }
 
-impl Initramfs {
-    /// Create a new builder.
-    pub fn new() -> Result<Self, InitramfsError> {
-        let mut vfs = Vfs::default();
+impl From<PackageError> for InitramfsError {
+    fn from(err: PackageError) -> Self {
+        Self::Package(err)
+    }
+}
 
-        for dir in ROOT_DIRS {
-            debug!("Adding default directory: {}", dir);
-            vfs.create_dir(dir)?;
-        }
+impl From<PayloadError> for InitramfsError {
+    fn from(err: PayloadError) -> Self {
+        Self::Payload(err)
+    }
+}
+
+impl From<glob::PatternError> for InitramfsError {
+    fn from(err: glob::PatternError) -> Self {
+        Self::Pattern(err)
+    }
+}
+
+impl From<FragmentError> for InitramfsError {
+    fn from(err: FragmentError) -> Self {
+        Self::Fragment(err)
+    }
+}
+
+impl From<TemplateError> for InitramfsError {
+    fn from(err: TemplateError) -> Self {
+        Self::Template(err)
+    }
+}
+
+impl From<ConsoleError> for InitramfsError {
+    fn from(err: ConsoleError) -> Self {
+        Self::Console(err)
+    }
+}
 
-        for (src, dest) in ROOT_SYMLINKS {
-            debug!("Adding default symlink: {} -> {}", src, dest);
-            vfs.create_entry(src, Entry::symlink(dest))?;
+impl From<HostOnlyError> for InitramfsError {
+    fn from(err: HostOnlyError) -> Self {
+        Self::HostOnly(err)
+    }
+}
+
+/// Options for adding ELF binaries.
+#[derive(Clone, Copy, Debug)]
+pub struct ElfOptions {
+    /// Walk subdirectories when adding a directory of binaries.
+    pub recursive: bool,
+    /// Add linked libraries and the program interpreter.
+    pub resolve_deps: bool,
+}
+
+impl Default for ElfOptions {
+    fn default() -> Self {
+        ElfOptions {
+            recursive: false,
+            resolve_deps: true,
         }
+    }
+}
 
-        Ok(Initramfs { vfs })
+impl From<&config::Binary> for ElfOptions {
+    fn from(binary: &config::Binary) -> Self {
+        ElfOptions {
+            recursive: binary.recursive,
+            resolve_deps: binary.resolve_deps,
+        }
     }
+}
 
-    /// Create a new builder from a configuration.
-    pub fn from_config(
-        config: &config::Initramfs,
-        modules: &[config::Module],
-    ) -> Result<Self, InitramfsError> {
-        let mut initramfs = Initramfs::new()?;
-        initramfs.add_init(&config.init)?;
+/// Options for adding kernel modules, applied to dependencies as well.
+#[derive(Clone, Copy, Debug)]
+pub struct ModuleOptions {
+    /// Add hard dependencies.
+    pub deps: bool,
+    /// Add soft dependencies.
+    pub softdeps: bool,
+}
 
-        if let Some(shutdown) = &config.shutdown {
-            initramfs.add_shutdown(shutdown)?;
+impl Default for ModuleOptions {
+    fn default() -> Self {
+        ModuleOptions {
+            deps: true,
+            softdeps: true,
         }
+    }
+}
 
-        let settings = &config.settings;
-        let mut kmod = match &settings.kernel_module_path {
-            Some(path) => {
-                if !path.exists() {
-                    let err = io::Error::new(io::ErrorKind::NotFound, path.display().to_string());
-                    return Err(InitramfsError::InputOutput(err));
-                }
+impl From<&config::KernelModule> for ModuleOptions {
+    fn from(module: &config::KernelModule) -> Self {
+        ModuleOptions {
+            deps: module.deps,
+            softdeps: module.softdeps,
+        }
+    }
+}
 
-                Kmod::with_directory(path)
-            }
-            None => Kmod::new(),
-        }?;
+/// An item of a configuration module left out in best effort mode, see
+/// [`config::OnError`].
+#[derive(serde::Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct Failure {
+    /// Name of the configuration module.
+    pub module: String,
+    /// What could not be added, e.g. `binary /usr/bin/plymouth`.
+    pub item: String,
+    /// Stable identifier of the error, see [`InitramfsError::code`].
+    pub code: &'static str,
+    /// Error message.
+    pub message: String,
+}
 
-        for module in modules {
-            for binary in &module.binaries {
-                initramfs.add_elf(&binary.path)?;
-            }
+/// What provides an entrypoint, a shutdown script is a plain path.
+#[derive(Clone)]
+struct Entrypoint {
+    /// Source compared to tell a repeated entrypoint from a conflicting one.
+    source: config::Init,
+    /// Description of the source for errors, with the module adding it.
+    description: String,
+}
 
-            for spec in &module.files {
-                initramfs.add_files(&spec.sources, &spec.destination)?;
-            }
+/// Builder for initramfs generation.
+#[derive(Clone)]
+pub struct Initramfs {
+    /// Virtual filesystem built for this initramfs.
+    vfs: Vfs,
+    /// Strip ELF files when adding them.
+    strip: bool,
+    /// Keep what is stripped from ELF files as separate debug files.
+    split_debug: bool,
+    /// Debug files of stripped ELF files, by path in the initramfs.
+    debug_files: BTreeMap<PathBuf, Vec<u8>>,
+    /// Kernel command line parameters required by the content of the initramfs.
+    cmdline: Vec<String>,
+    /// What to do with files matching secret patterns.
+    secret_filter: config::SecretFilter,
+    /// Patterns of host paths considered secret.
+    secret_patterns: Vec<Pattern>,
+    /// Files left out when copying directories.
+    ignore: Ignore,
+    /// Remove empty directories before building the archive.
+    prune_empty_dirs: bool,
+    /// Remove libraries nothing needs before building the archive.
+    prune_unused_libs: bool,
+    /// Serialize the archive in canonical form.
+    canonical: bool,
+    /// Variant of the cpio format the archive is serialized in.
+    archive_format: newc::Format,
+    /// Place files destined to split-/usr directories under /usr.
+    usr_merge: bool,
+    /// Directories searched for systemd units, from highest precedence.
+    unit_search_paths: Vec<PathBuf>,
+    /// Keys of systemd units naming the units added along with them.
+    unit_dependency_keys: Vec<String>,
+    /// Sysctl, tmpfiles.d, modules-load.d and environment fragments of
+    /// configuration modules.
+    fragments: Fragments,
+    /// Where environment variables are written.
+    environment_path: PathBuf,
+    /// Whether environment variables are also passed to systemd services.
+    systemd_environment: bool,
+    /// Directories searched for systemd generators, from highest precedence.
+    generator_search_paths: Vec<PathBuf>,
+    /// Directories created by the skeleton, kept when pruning.
+    skeleton_dirs: Vec<PathBuf>,
+    /// Symlinks created by the skeleton, other entries cannot replace them.
+    skeleton_symlinks: BTreeMap<PathBuf, SkeletonSymlink>,
+    /// Buffer reused to decompress kernel modules.
+    scratch: Vec<u8>,
+    /// Dependencies of ELF files already resolved.
+    elf_cache: ElfCache,
+    /// Largest file read from the host.
+    max_file_size: Option<Size>,
+    /// Extended attributes of files read from the host, when collected.
+    xattrs: Option<Xattrs>,
+    /// Downloads remote sources.
+    fetcher: Fetcher,
+    /// Lists the files of distribution packages.
+    packages: PackageQuery,
+    /// Time spent in each phase of the build.
+    stats: BuildStats,
+    /// Why entries were included.
+    provenance: Provenance,
+    /// Kernel modules kept out of the initramfs.
+    module_policy: ModulePolicy,
+    /// Kernel modules left out by the policy.
+    filtered_modules: Vec<FilteredModule>,
+    /// Libraries kept out of the dependencies of ELF files.
+    library_policy: LibraryPolicy,
+    /// Libraries left out by the policy.
+    filtered_libraries: Vec<FilteredLibrary>,
+    /// Payloads added and the kernel modules mounting them.
+    payloads: Vec<PayloadModules>,
+    /// What provided each entrypoint (`init`, `shutdown`).
+    entrypoints: BTreeMap<&'static str, Entrypoint>,
+    /// Compression of installed kernel modules.
+    module_compression: ModuleCompression,
+    /// Configuration of the kernel modules are added for, if found.
+    kernel_config: Option<KernelConfig>,
+    /// How features the kernel configuration does not support are reported.
+    kernel_config_conflicts: config::Severity,
+    /// How kernel command line parameters with a conflicting value are
+    /// reported.
+    cmdline_conflicts: config::Severity,
+    /// Leave items of non critical modules out when they cannot be added.
+    best_effort: bool,
+    /// Items left out in best effort mode.
+    failures: Vec<Failure>,
+    /// Steps registered to run before the next plan.
+    pre_steps: Hooks,
+    /// Steps registered to run after the next plan.
+    post_steps: Hooks,
+}
 
-            for symlink in &module.symlinks {
-                initramfs.add_symlink(&symlink.path, &symlink.target)?;
-            }
+/// Step registered by library users, run once.
+type Hook = Box<dyn FnOnce(&mut Initramfs) -> Result<(), InitramfsError>>;
 
-            for module in &module.kernel_modules {
-                match module {
-                    config::KernelModule::Name(name) => {
-                        initramfs.add_module_from_name(&mut kmod, name)?;
-                    }
-                    config::KernelModule::Path(path) => {
-                        initramfs.add_module_from_path(&mut kmod, path)?;
-                    }
-                }
-            }
+/// Registered steps, copies of a builder start without them.
+#[derive(Default)]
+struct Hooks(Vec<Hook>);
 
-            for unit in &module.units {
-                initramfs.add_systemd_unit(&unit.name)?;
-            }
-        }
+impl Clone for Hooks {
+    fn clone(&self) -> Self {
+        Hooks::default()
+    }
+}
+
+/// A symlink from the skeleton.
+#[derive(Clone)]
+struct SkeletonSymlink {
+    target: PathBuf,
+    /// Whether the symlink is one of the defaults or was configured.
+    default: bool,
+}
+
+impl Initramfs {
+    /// Create a new builder with the default skeleton.
+    pub fn new() -> Result<Self, InitramfsError> {
+        Self::with_skeleton(&config::Skeleton::default())
+    }
+
+    /// Create a new builder with the provided skeleton.
+    pub fn with_skeleton(skeleton: &config::Skeleton) -> Result<Self, InitramfsError> {
+        let mut initramfs = Self::empty();
 
+        initramfs.phase("skeleton", |this| this.add_skeleton(skeleton))?;
         Ok(initramfs)
     }
 
-    /// Add the init script from the provided path to the initramfs.
-    pub fn add_init(&mut self, path: &Path) -> Result<(), InitramfsError> {
-        debug!("Adding init entrypoint: {}", path.display());
-        self.add_entrypoint("init", path)?;
+    /// Create a new builder for a bare archive, which only contains the root
+    /// directory and does not move files under `/usr`.
+    pub fn new_bare() -> Self {
+        let mut initramfs = Self::empty();
+        initramfs.set_usr_merge(false);
 
-        Ok(())
+        initramfs
     }
 
-    /// Add the shutdown script, similar to init.
-    pub fn add_shutdown(&mut self, path: &Path) -> Result<(), InitramfsError> {
-        debug!("Adding shutdown entrypoint: {}", path.display());
-        self.add_entrypoint("shutdown", path)?;
+    fn empty() -> Self {
+        let secret_patterns = SECRET_PATTERNS
+            .iter()
+            .map(|pattern| Pattern::new(pattern).expect("pattern is valid"))
+            .collect();
 
-        Ok(())
+        Initramfs {
+            vfs: Vfs::default(),
+            strip: false,
+            split_debug: false,
+            debug_files: BTreeMap::new(),
+            cmdline: Vec::new(),
+            secret_filter: config::SecretFilter::default(),
+            secret_patterns,
+            ignore: Ignore::default(),
+            prune_empty_dirs: false,
+            prune_unused_libs: false,
+            canonical: false,
+            archive_format: newc::Format::default(),
+            usr_merge: true,
+            unit_search_paths: systemd::UNIT_SEARCH_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            unit_dependency_keys: systemd::DEFAULT_DEPENDENCY_KEYS
+                .iter()
+                .map(|key| key.to_string())
+                .collect(),
+            fragments: Fragments::default(),
+            environment_path: PathBuf::from(fragment::ENVIRONMENT_PATH),
+            systemd_environment: false,
+            generator_search_paths: systemd::GENERATOR_SEARCH_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            skeleton_dirs: Vec::new(),
+            skeleton_symlinks: BTreeMap::new(),
+            scratch: Vec::new(),
+            elf_cache: ElfCache::default(),
+            max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+            xattrs: None,
+            fetcher: Fetcher::new(remote::default_cache_dir()),
+            packages: PackageQuery::new(),
+            stats: BuildStats::default(),
+            provenance: Provenance::default(),
+            module_policy: ModulePolicy::default(),
+            filtered_modules: Vec::new(),
+            library_policy: LibraryPolicy::default(),
+            filtered_libraries: Vec::new(),
+            payloads: Vec::new(),
+            entrypoints: BTreeMap::new(),
+            module_compression: ModuleCompression::None,
+            kernel_config: None,
+            kernel_config_conflicts: config::Severity::Warn,
+            cmdline_conflicts: config::Severity::Warn,
+            best_effort: false,
+            failures: Vec::new(),
+            pre_steps: Hooks::default(),
+            post_steps: Hooks::default(),
+        }
     }
 
-    /// Adds an elf binary to the initramfs, also adding its dynamic dependencies.
-    pub fn add_elf(&mut self, path: &Path) -> Result<(), InitramfsError> {
-        let path = if path.is_relative() {
-            Elf::find_binary(path)?
+    fn from_settings(settings: &config::Settings) -> Result<Self, InitramfsError> {
+        let mut initramfs = if settings.bare {
+            debug!("Bare archive, skipping the skeleton");
+            Self::new_bare()
         } else {
-            path.to_path_buf()
+            Self::with_skeleton(&settings.skeleton)?
         };
 
-        if self.vfs.contains(&path) {
-            return Ok(());
+        // limits apply from the first entry read, init included
+        if let Some(limit) = settings.max_file_size {
+            initramfs.set_max_file_size(Some(limit));
         }
+        initramfs.set_max_total_size(settings.max_total_size);
+        initramfs.set_collect_xattrs(settings.xattr_script.is_some());
 
-        if let Some(parent) = path.parent() {
-            self.vfs.create_dir_all(parent)?;
+        let cache_dir = settings
+            .cache_dir
+            .clone()
+            .unwrap_or_else(remote::default_cache_dir);
+        let mut fetcher = Fetcher::new(cache_dir);
+        if let Some(seconds) = settings.remote_timeout {
+            fetcher.set_timeout(Duration::from_secs(seconds));
         }
+        initramfs.set_fetcher(fetcher);
 
-        if !path.exists() {
-            error!("Failed to find binary: {}", path.display());
-
-            let err = io::Error::new(io::ErrorKind::NotFound, path.display().to_string());
-            return Err(InitramfsError::InputOutput(err));
+        if let Some(backend) = settings.package_backend {
+            initramfs.packages.set_backend(backend);
         }
-
-        debug!("Adding binary: {}", path.display());
-        let file = File::open(&path)?;
-        let entry = Entry::try_from(file)?;
-
-        self.vfs.create_entry(&path, entry)?;
-
-        for dependency in Elf::linked_libraries(&path)? {
-            self.add_elf(&dependency)?;
+        if let Some(prefixes) = &settings.package_prefixes {
+            initramfs.packages.set_prefixes(prefixes.clone());
         }
 
-        Ok(())
+        Ok(initramfs)
     }
 
-    /// Add the filesystem tree from the provided source to the provided destination in the.
-    /// initramfs.
-    pub fn add_files<P>(&mut self, sources: &[P], destination: &Path) -> Result<(), InitramfsError>
-    where
-        P: AsRef<Path>,
-    {
-        debug!("Copying files into {}", destination.display());
-        self.vfs.create_dir_all(destination)?;
+    /// Create a new builder from a configuration.
+    pub fn from_config(
+        config: &config::Initramfs,
+        modules: &[config::Module],
+    ) -> Result<Self, InitramfsError> {
+        let mut initramfs = Initramfs::for_config(config)?;
+        initramfs.run_plan(&BuildPlan::from_config(config, modules), config, modules)?;
 
-        for source in sources {
-            let source = source.as_ref();
+        Ok(initramfs)
+    }
 
-            if !source.exists() {
-                error!("Failed to find file: {}", source.display());
+    /// Create a builder with the content of a configuration that does not
+    /// depend on the kernel, everything but kernel modules, to be completed
+    /// for each kernel with [`Initramfs::for_kernel`].
+    pub fn shared_from_config(
+        config: &config::Initramfs,
+        modules: &[config::Module],
+    ) -> Result<Self, InitramfsError> {
+        let mut initramfs = Initramfs::for_config(config)?;
+        initramfs.run_plan(&BuildPlan::shared(config, modules), config, modules)?;
 
-                let err = io::Error::new(io::ErrorKind::NotFound, source.display().to_string());
-                return Err(InitramfsError::InputOutput(err));
-            }
+        Ok(initramfs)
+    }
 
-            let metadata = fs::metadata(source)?;
-            let ty = metadata.file_type();
+    /// Complete a copy of a builder created by [`Initramfs::shared_from_config`]
+    /// with the kernel modules found in the provided module directory (e.g.
+    /// `/usr/lib/modules/6.1.0`) or kernel package, then run the checks and
+    /// settings that look at the whole initramfs.
+    pub fn for_kernel(
+        &self,
+        config: &config::Initramfs,
+        modules: &[config::Module],
+        kernel_module_path: &Path,
+    ) -> Result<Self, InitramfsError> {
+        let mut kmod = LazyKmod::new(&config.settings, Some(kernel_module_path));
+        let plan = BuildPlan::for_kernel(config, modules);
 
-            if ty.is_dir() {
-                let walk = WalkDir::new(source).min_depth(1);
+        let mut initramfs = self.clone();
+        initramfs.run_plan_with(&plan, config, modules, Some(&mut kmod))?;
 
-                for entry in walk {
-                    let entry = entry?;
+        Ok(initramfs)
+    }
 
-                    let source_path = entry.path();
-                    let path = destination.join(
-                        source_path
-                            .strip_prefix(source)
-                            .expect("entry should be under root path"),
-                    );
+    /// Create a builder with the skeleton and limits of a configuration but
+    /// nothing else yet, to register steps with [`Initramfs::add_pre_step`]
+    /// and [`Initramfs::add_post_step`] before [`Initramfs::run_plan`].
+    pub fn for_config(config: &config::Initramfs) -> Result<Self, InitramfsError> {
+        Initramfs::from_settings(&config.settings)
+    }
 
-                    if self.vfs.contains(&path) {
-                        continue;
-                    }
+    /// Register a step run before the steps of the next plan.
+    pub fn add_pre_step<F>(&mut self, step: F)
+    where
+        F: FnOnce(&mut Initramfs) -> Result<(), InitramfsError> + 'static,
+    {
+        self.pre_steps.0.push(Box::new(step));
+    }
 
-                    let file = File::open(source_path)?;
-                    let entry = Entry::try_from(file)?;
-                    self.vfs.create_entry(path, entry)?;
-                }
-            } else {
-                let name = source.file_name().expect("path should contain file name");
-                let path = destination.join(name);
+    /// Register a step run after the steps of the next plan.
+    pub fn add_post_step<F>(&mut self, step: F)
+    where
+        F: FnOnce(&mut Initramfs) -> Result<(), InitramfsError> + 'static,
+    {
+        self.post_steps.0.push(Box::new(step));
+    }
 
-                if self.vfs.contains(&path) {
-                    continue;
-                }
+    /// Run the registered pre steps, the steps of a plan derived from the
+    /// provided configuration, then the registered post steps. The kernel
+    /// module context is created from the settings if a step needs one, once
+    /// a kernel module is actually added: configurations without kernel
+    /// modules never look at the module directory.
+    pub fn run_plan(
+        &mut self,
+        plan: &BuildPlan,
+        config: &config::Initramfs,
+        modules: &[config::Module],
+    ) -> Result<(), InitramfsError> {
+        let mut kmod = plan
+            .needs_kmod()
+            .then(|| LazyKmod::new(&config.settings, None));
 
-                let file = File::open(source)?;
-                let entry = Entry::try_from(file)?;
-                self.vfs.create_entry(path, entry)?;
-            }
+        self.run_plan_with(plan, config, modules, kmod.as_mut())
+    }
+
+    fn run_plan_with(
+        &mut self,
+        plan: &BuildPlan,
+        config: &config::Initramfs,
+        modules: &[config::Module],
+        mut kmod: Option<&mut LazyKmod>,
+    ) -> Result<(), InitramfsError> {
+        for step in mem::take(&mut self.pre_steps.0) {
+            step(self)?;
         }
 
-        Ok(())
-    }
+        for step in plan.steps() {
+            self.run_step(step, config, modules, kmod.as_deref_mut())?;
+        }
 
-    /// Add a symlink to the initramfs.
-    pub fn add_symlink(&mut self, path: &Path, target: &Path) -> Result<(), InitramfsError> {
-        if self.vfs.contains(target) {
-            return Ok(());
+        for step in mem::take(&mut self.post_steps.0) {
+            step(self)?;
         }
 
-        if let Some(parent) = path.parent() {
-            self.vfs.create_dir_all(parent)?;
+        for filtered in &self.filtered_modules {
+            info!(
+                module = filtered.name.as_str();
+                "Kernel module {} left out ({}), requested by {}",
+                filtered.name, filtered.denial, filtered.requested_by
+            );
         }
 
-        debug!("Adding symlink: {} -> {}", path.display(), target.display());
+        for filtered in &self.filtered_libraries {
+            info!(
+                "Library {} left out (denylist pattern '{}'), needed by {}",
+                filtered.name, filtered.pattern, filtered.requested_by
+            );
+        }
 
-        let entry = Entry::symlink(target);
-        self.vfs.create_entry(path, entry)?;
+        debug!(
+            "Parsed {} ELF files, searched {} libraries",
+            self.elf_cache.parsed(),
+            self.elf_cache.searched()
+        );
 
         Ok(())
     }
 
-    /// Add a named kernel module to the initramfs.
-    pub fn add_module_from_name(
+    fn run_step(
         &mut self,
-        kmod: &mut Kmod,
-        name: &str,
+        step: &Step,
+        config: &config::Initramfs,
+        modules: &[config::Module],
+        kmod: Option<&mut LazyKmod>,
     ) -> Result<(), InitramfsError> {
-        let module = kmod.module_from_name(name)?;
+        let settings = &config.settings;
 
-        debug!("Adding kernel module with name: {}", name);
-        self.add_module(kmod, &module)?;
+        // modules named by the step, in its order
+        let named = |names: &[String]| -> Vec<&config::Module> {
+            names
+                .iter()
+                .filter_map(|name| modules.iter().find(|module| module.name == *name))
+                .collect()
+        };
 
-        Ok(())
-    }
+        let kmod = match kmod {
+            None if step.needs_kmod() => {
+                return Err(InitramfsError::StepNeedsKmod(step.to_string()));
+            }
+            kmod => kmod,
+        };
 
-    /// Add a kernel module to the initramfs from the provided path.
-    pub fn add_module_from_path(
-        &mut self,
-        kmod: &mut Kmod,
-        path: &Path,
-    ) -> Result<(), InitramfsError> {
-        let module = kmod.module_from_path(path)?;
+        match (step, kmod) {
+            (Step::Init, _) => self.phase("init", |this| match &config.init {
+                Some(config::Init::Path(path)) => this.add_init(path),
+                Some(config::Init::Wrapper(wrapper)) => this.add_init_wrapper(wrapper),
+                None => Ok(()),
+            }),
+            (Step::Shutdown, _) => self.add_config_shutdown(config, modules),
+            (
+                Step::Modules {
+                    names,
+                    kernel_modules,
+                },
+                kmod,
+            ) => {
+                let kmod = kmod.filter(|_| *kernel_modules);
+                self.add_config_modules(settings, &named(names), kmod)
+            }
+            (Step::KernelModules(names), Some(kmod)) => self.phase("kernel_modules", |this| {
+                this.add_config_kernel_modules(&named(names), kmod)
+            }),
+            (Step::Checks, Some(kmod)) => {
+                self.phase("checks", |this| this.check_config(settings, kmod))
+            }
+            (Step::HostOnly, Some(kmod)) => {
+                info!("Detecting kernel modules needed by this host");
+                let host = Host::new().detect()?;
+                let kmod = self.kmod(kmod)?;
 
-        debug!("Adding kernel module from path: {}", path.display());
-        self.add_module(kmod, &module)?;
+                let node = Node::Setting("host_only");
+                self.provenance
+                    .record(node.clone(), "setting host_only".to_string());
 
-        Ok(())
-    }
+                self.with_parent(node, |this| this.add_host_modules(kmod, &host))
+            }
+            (Step::LoadedModules, Some(kmod)) => {
+                let names = loaded_module_names(settings, &Host::new())?;
+                let kmod = self.kmod(kmod)?;
 
-    /// Add a systemd unit to the initramfs. This function also adds
-    /// binaries used by the unit to the initramfs (ExecStart) and
-    /// create relevant symlinks to enable them.
-    ///
-    /// Service units are 'installed' in the sysinit target and socket
-    /// services in the socket target.
-    pub fn add_systemd_unit(&mut self, name: &str) -> Result<(), InitramfsError> {
-        let Unit {
-            path,
-            data,
-            dependencies,
-            binaries,
-            install_path,
-        } = Unit::from_name(name)?;
+                self.add_loaded_modules(kmod, &names)
+            }
+            (
+                Step::KernelModules(_) | Step::Checks | Step::HostOnly | Step::LoadedModules,
+                None,
+            ) => {
+                unreachable!("kmod is checked above")
+            }
+            (Step::Emergency, _) => {
+                let node = Node::Setting("emergency");
+                self.provenance
+                    .record(node.clone(), "setting emergency".to_string());
 
-        if !self.vfs.contains(&path) {
-            debug!("Adding systemd unit: {}", name);
+                self.with_parent(node, |this| this.add_emergency(&settings.emergency))
+            }
+            (Step::AutoGenerators, _) => {
+                if !self.provenance.contains_units() {
+                    return Ok(());
+                }
 
-            let entry = Entry::file(data);
-            let parent = path.parent().expect("parent directory");
+                let node = Node::Setting("auto_generators");
+                self.provenance
+                    .record(node.clone(), "setting auto_generators".to_string());
 
-            self.vfs.create_dir_all(parent)?;
-            self.vfs.create_entry(path, entry)?;
-        }
+                self.with_parent(node, Self::add_default_generators)
+            }
+            (Step::Environment, _) => {
+                let node = Node::Setting("environment");
+                self.provenance
+                    .record(node.clone(), "configuration environment".to_string());
 
-        // add binaries required by the unit
-        for binary in binaries {
-            self.add_elf(Path::new(&binary))?;
-        }
+                self.with_parent(node, |this| {
+                    for (key, value) in &config.environment {
+                        this.add_environment(key, &value.0)?;
+                    }
 
-        // install the unit by adding symlink
-        if let Some(path) = install_path {
-            let target = Path::new("..").join(name);
-            self.add_symlink(&path, &target)?;
-        }
+                    Ok(())
+                })
+            }
+            (Step::LdSoConf { ldconfig }, _) => self.add_ld_so_conf(*ldconfig),
+            (Step::XattrScript, _) => match &settings.xattr_script {
+                Some(path) => self.add_xattr_script(path),
+                None => Ok(()),
+            },
+            (Step::InitLint, _) => {
+                let source = match &config.init {
+                    Some(config::Init::Path(path)) => Some(path.as_path()),
+                    Some(config::Init::Wrapper(_)) => None,
+                    None => return Ok(()),
+                };
 
-        for dependency in dependencies {
-            self.add_systemd_unit(&dependency)?;
-        }
+                for problem in init::lint(&self.vfs, source) {
+                    warn!("{}", problem);
+                }
 
-        Ok(())
+                Ok(())
+            }
+        }
     }
 
-    /// Return an archive from this initramfs.
-    pub fn into_archive(self) -> Archive {
-        Archive::from(self.vfs)
-    }
+    /// Create a new builder for the shutdown initramfs (exitrd) from a configuration.
+    ///
+    /// The exitrd is what systemd pivots into (`/run/initramfs`) at shutdown, it
+    /// only contains the shutdown entrypoint and the provided modules.
+    pub fn exitrd_from_config(
+        config: &config::Initramfs,
+        modules: &[config::Module],
+    ) -> Result<Self, InitramfsError> {
+        let shutdown = match config.shutdown.as_slice() {
+            [] => return Err(InitramfsError::MissingShutdown),
+            [shutdown] => shutdown,
+            list => return Err(InitramfsError::ShutdownList(list.len())),
+        };
 
-    fn add_entrypoint(&mut self, name: &str, path: &Path) -> Result<(), InitramfsError> {
-        let dest = format!("/{name}");
-        if self.vfs.contains(&dest) {
-            return Ok(());
-        }
+        let mut kmod = LazyKmod::new(&config.settings, None);
+        let mut initramfs = Initramfs::from_settings(&config.settings)?;
+        initramfs.add_shutdown(shutdown)?;
 
-        if !path.exists() {
-            error!("Failed to find {}: {}", name, path.display());
+        let modules: Vec<_> = modules.iter().collect();
+        initramfs.add_config_modules(&config.settings, &modules, Some(&mut kmod))?;
+        initramfs.phase("checks", |this| {
+            this.check_config(&config.settings, &mut kmod)
+        })?;
 
-            let err = io::Error::new(io::ErrorKind::NotFound, path.display().to_string());
-            return Err(InitramfsError::InputOutput(err));
-        }
+        Ok(initramfs)
+    }
+
+    // install the shutdown executables where the configured mode expects them
+    fn add_config_shutdown(
+        &mut self,
+        config: &config::Initramfs,
+        modules: &[config::Module],
+    ) -> Result<(), InitramfsError> {
+        if config.shutdown.is_empty() {
+            return Ok(());
+        }
 
-        let file = File::open(path)?;
-        let entry = Entry::try_from(file)?;
+        let units = modules.iter().any(|module| !module.units.is_empty());
+        let mode = config.settings.shutdown_mode.unwrap_or(if units {
+            config::ShutdownMode::SystemdHook
+        } else {
+            config::ShutdownMode::Legacy
+        });
 
-        self.vfs.create_entry(dest, entry)?;
+        match mode {
+            config::ShutdownMode::Legacy => {
+                if units {
+                    warn!(
+                        "Shutdown script installed at /shutdown, which systemd does not run; \
+                         set settings.shutdown_mode to systemd-hook or exitrd"
+                    );
+                }
 
-        Ok(())
+                match config.shutdown.as_slice() {
+                    [shutdown] => self.add_shutdown(shutdown),
+                    list => Err(InitramfsError::ShutdownList(list.len())),
+                }
+            }
+            config::ShutdownMode::SystemdHook => {
+                for hook in &config.shutdown {
+                    self.add_shutdown_hook(hook)?;
+                }
+
+                Ok(())
+            }
+            config::ShutdownMode::Exitrd => {
+                if config.shutdown.len() > 1 {
+                    return Err(InitramfsError::ShutdownList(config.shutdown.len()));
+                }
+
+                info!("Leaving shutdown to the exitrd, generate it with `elusive exitrd`");
+                Ok(())
+            }
+        }
     }
 
-    fn add_module(&mut self, kmod: &mut Kmod, module: &Module) -> Result<(), InitramfsError> {
-        // builtin module, nothing to do
-        if module.is_builtin() {
-            return Ok(());
+    fn add_config_modules(
+        &mut self,
+        settings: &config::Settings,
+        modules: &[&config::Module],
+        mut kmod: Option<&mut LazyKmod>,
+    ) -> Result<(), InitramfsError> {
+        self.set_strip(settings.strip);
+        self.set_split_debug(settings.debug_sidecar.is_some());
+        self.set_secret_filter(settings.secret_filter);
+        self.set_prune_empty_dirs(settings.prune_empty_dirs);
+        self.set_prune_unused_libs(settings.prune_unused_libs);
+        self.set_canonical(settings.canonical);
+        self.set_archive_format(settings.archive_format);
+        self.set_best_effort(settings.on_error == config::OnError::BestEffort);
+        self.set_systemd_environment(settings.systemd_environment);
+        let links = !settings.bare && !settings.skeleton.disable_defaults;
+        self.set_usr_merge(settings.usr_merge.unwrap_or(links));
+        self.set_cmdline_conflicts(settings.cmdline_conflicts);
+
+        if let Some(paths) = &settings.unit_search_paths {
+            self.set_unit_search_paths(paths.clone());
         }
 
-        // add module dependencies, first
-        let debug = module.info()?;
-        for name in debug
-            .depends()
-            .iter()
-            .chain(debug.pre_softdeps())
-            .chain(debug.post_softdeps())
-        {
-            let module = kmod.module_from_name(name)?;
-            self.add_module(kmod, &module)?;
+        if let Some(keys) = &settings.unit_dependency_keys {
+            self.set_unit_dependency_keys(keys.clone());
         }
 
-        // get final path first to avoid reading the file
-        // if we have already included it in the vfs
-        let path = module.install_path()?;
-        if let Some(parent) = path.parent() {
-            self.vfs.create_dir_all(parent)?;
+        if let Some(path) = &settings.environment_path {
+            self.set_environment_path(path);
         }
 
-        if self.vfs.contains(&path) {
-            return Ok(());
+        for pattern in &settings.secret_patterns {
+            self.add_secret_pattern(pattern)?;
         }
 
-        // finally, decompress and create the entry in the vfs
-        let compressed = fs::read(module.host_path().expect("module isn't builtin"))?;
-        let format = ModuleFormat::from_bytes(&compressed)?;
+        let mut policy = ModulePolicy::new(
+            &settings.kernel_module_denylist,
+            settings.kernel_module_allowlist.as_deref(),
+        )?;
+        policy.set_strict(settings.strict_module_policy);
+        self.set_module_policy(policy);
 
-        let data = uncompress_module(&compressed, &format)?;
+        let mut policy = LibraryPolicy::new(&settings.library_denylist)?;
+        policy.set_strict(settings.strict_library_policy);
+        self.set_library_policy(policy);
 
-        let entry = Entry::file(data);
-        self.vfs.create_entry(path, entry)?;
+        self.phase("modules", |this| {
+            for module in modules {
+                let node = Node::Module(module.name.clone());
+                this.provenance
+                    .record(node.clone(), format!("module {}", module.name));
+
+                this.with_parent(node, |this| {
+                    this.add_config_module(settings, module, kmod.as_deref_mut())
+                })?;
+            }
+
+            for library in &settings.library_extra {
+                this.add_library(library)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    // kernel modules of configuration modules, when left out of the content
+    // shared by several kernels
+    fn add_config_kernel_modules(
+        &mut self,
+        modules: &[&config::Module],
+        kmod: &mut LazyKmod,
+    ) -> Result<(), InitramfsError> {
+        for module in modules.iter().filter(|module| uses_kmod(module)) {
+            let start = Instant::now();
+            let kmod = self.kmod(kmod)?;
+
+            self.with_parent(Node::Module(module.name.clone()), |this| {
+                this.tolerate(module, "kernel modules".to_string(), |this| {
+                    this.add_kernel_modules(module, kmod)
+                })
+            })?;
+
+            let elapsed = start.elapsed();
+            if let Some(stats) = self
+                .stats
+                .modules
+                .iter_mut()
+                .find(|stats| stats.module == module.name)
+            {
+                stats.kmod += elapsed;
+                stats.time += elapsed;
+            }
+        }
 
         Ok(())
     }
-}
 
-fn uncompress_module(data: &[u8], format: &ModuleFormat) -> Result<Vec<u8>, InitramfsError> {
-    let mut buf = Vec::new();
+    fn add_kernel_modules(
+        &mut self,
+        module: &config::Module,
+        kmod: &mut Kmod,
+    ) -> Result<(), InitramfsError> {
+        for kernel_module in &module.kernel_modules {
+            let options = ModuleOptions::from(kernel_module);
+
+            match &kernel_module.source {
+                config::ModuleSource::Name(name) => {
+                    self.add_module_from_name_with_options(kmod, name, options)?;
+                }
+                config::ModuleSource::Path(path) => {
+                    self.add_module_from_path_with_options(kmod, path, options)?;
+                }
+            }
+        }
 
-    match format {
-        ModuleFormat::Elf => buf.extend(data),
-        ModuleFormat::Zstd => {
-            let mut decoder = ZstdDecoder::new(data)?;
-            decoder.read_to_end(&mut buf)?;
+        for name in &module.modules_load {
+            self.add_module_from_name(kmod, name)?;
         }
-        ModuleFormat::Gzip => {
-            let mut decoder = GzDecoder::new(data);
-            decoder.read_to_end(&mut buf)?;
+
+        for payload in &module.payloads {
+            self.add_payload_modules(kmod, &payload.destination)?;
         }
+
+        Ok(())
     }
 
-    Ok(buf)
-}
+    // lints run once every module is included
+    fn check_config(
+        &mut self,
+        settings: &config::Settings,
+        kmod: &mut LazyKmod,
+    ) -> Result<(), InitramfsError> {
+        let problems = match self.crypttab_volumes() {
+            Ok(volumes) if volumes.is_empty() => Vec::new(),
+            Ok(volumes) => {
+                let kmod = self.kmod(kmod)?;
+                self.add_crypttab_requirements(kmod, &volumes)?
+            }
+            Err(problem) => vec![problem],
+        };
+        report(
+            settings.crypttab_problems,
+            &problems,
+            InitramfsError::Crypttab,
+        )?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::config;
+        if settings.verify_symbols {
+            report(
+                settings.unresolved_symbols,
+                &self.verify_symbols(),
+                InitramfsError::UnresolvedSymbols,
+            )?;
+        }
 
-    use std::path::PathBuf;
+        if settings.dangling_symlinks != config::Severity::Ignore {
+            report(
+                settings.dangling_symlinks,
+                &self.vfs.dangling_symlinks(),
+                InitramfsError::DanglingSymlinks,
+            )?;
+        }
 
-    #[test]
-    fn test_initramfs() {
-        let mut binaries = Vec::new();
-        let mut files = Vec::new();
-        let mut kernel_modules = Vec::new();
+        if settings.permission_lints != config::Severity::Ignore {
+            report(
+                settings.permission_lints,
+                &permissions::lint(&self.vfs, &settings.permission_rules),
+                InitramfsError::Permissions,
+            )?;
+        }
 
-        let mut builder = Initramfs::new().unwrap();
-        builder.add_init(Path::new("/sbin/init")).unwrap();
+        if settings.case_collision != config::Severity::Ignore {
+            report(
+                settings.case_collision,
+                &collision::collisions(&self.vfs, self.provenance.owners()),
+                InitramfsError::CaseCollisions,
+            )?;
+        }
 
-        let ls = PathBuf::from("/usr/bin/ls");
-        if ls.exists() {
-            builder.add_elf(&ls).unwrap();
-            binaries.push(config::Binary { path: ls });
+        if settings.init_exec != config::Severity::Ignore {
+            if let Some(problem) = init::check_exec(&self.vfs) {
+                match settings.init_exec {
+                    config::Severity::Warn => warn!("The kernel cannot execute /init: {}", problem),
+                    config::Severity::Error => return Err(InitramfsError::InitExec(problem)),
+                    config::Severity::Ignore => (),
+                }
+            }
         }
 
-        let libc = PathBuf::from("/usr/lib/libc.so.6");
-        if libc.exists() {
-            builder.add_elf(&libc).unwrap();
-            binaries.push(config::Binary { path: libc });
+        // the filesystem may be builtin in this kernel only, or not at all
+        for payload in &self.payloads {
+            let module = payload.filesystem.module().unwrap_or_default();
+
+            if payload.filesystem_missing() {
+                warn!(
+                    "Payload {} cannot be mounted, kernel module {} was not found",
+                    payload.destination, module
+                );
+            } else if payload.filesystem_builtin() {
+                warn!(
+                    "Payload {} relies on the builtin {} filesystem, no kernel module added for it",
+                    payload.destination, module
+                );
+            }
         }
 
-        let hosts = PathBuf::from("/etc/hosts");
-        if hosts.exists() {
-            builder.add_files(&[&hosts], Path::new("/etc")).unwrap();
-            files.push(config::File {
-                destination: PathBuf::from("/etc"),
-                sources: vec![hosts],
-            });
+        Ok(())
+    }
+
+    fn add_config_module(
+        &mut self,
+        settings: &config::Settings,
+        module: &config::Module,
+        kmod: Option<&mut LazyKmod>,
+    ) -> Result<(), InitramfsError> {
+        debug!(module = module.name.as_str(); "Processing module: {}", module.name);
+
+        let start = Instant::now();
+        let (entries, bytes) = (self.vfs.len(), self.vfs.data_size().bytes());
+        let mut stats = ModuleStats {
+            module: self.provenance.module().unwrap_or(&module.name).to_string(),
+            ..ModuleStats::default()
+        };
+
+        for binary in &module.binaries {
+            self.set_strip(binary.strip.unwrap_or(settings.strip));
+
+            let options = ElfOptions::from(binary);
+            let item = format!("binary {}", binary.path.display());
+
+            self.tolerate(module, item, |this| match &binary.remote {
+                Some(remote) => ImagePath::new(&binary.path)
+                    .map_err(InitramfsError::from)
+                    .and_then(|path| this.add_remote_binary(remote, &path, options)),
+                None if binary.path.is_absolute() && binary.path.is_dir() => {
+                    this.add_elf_directory(&binary.path, options)
+                }
+                None => this.add_elf_with_options(&binary.path, options),
+            })?;
         }
 
-        let udev = PathBuf::from("/usr/lib/udev/rules.d");
-        if udev.exists() {
-            builder
-                .add_files(&[udev.clone()], Path::new("/lib/udev/rules.d"))
-                .unwrap();
+        for package in &module.packages {
+            self.tolerate(module, format!("package {package}"), |this| {
+                this.add_package(package)
+            })?;
+        }
 
-            files.push(config::File {
-                sources: vec![udev],
-                destination: PathBuf::from("/lib/udev/rules.d"),
-            });
+        self.set_strip(settings.strip);
+        stats.elf = start.elapsed();
+
+        let files = Instant::now();
+        for spec in &module.files {
+            let item = format!("files for {}", spec.destination.display());
+            self.tolerate(module, item, |this| {
+                this.add_file_spec(settings, module, spec)
+            })?;
         }
 
-        let mut kmod = Kmod::new().unwrap();
-        let btrfs = kmod.module_from_name("btrfs").unwrap();
+        self.set_ignore(Ignore::default());
 
-        if btrfs.host_path().is_some() {
-            builder.add_module(&mut kmod, &btrfs).unwrap();
-            kernel_modules.push(config::KernelModule::Name("btrfs".to_string()));
+        for symlink in &module.symlinks {
+            let item = format!("symlink {}", symlink.path.display());
+            self.tolerate(module, item, |this| {
+                this.add_link(&symlink.path, &symlink.resolved_target())
+            })?;
         }
 
-        let config = config::Initramfs {
-            init: PathBuf::from("/sbin/init"),
-            shutdown: None,
-            settings: config::Settings::default(),
-            modules: Vec::new(),
-        };
+        for payload in &module.payloads {
+            let item = format!("payload {}", payload.destination.display());
+            self.tolerate(module, item, |this| this.add_payload(payload))?;
+        }
+        stats.files = files.elapsed();
 
-        let modules = vec![config::Module {
-            name: "test".to_string(),
-            binaries,
-            files,
-            kernel_modules,
-            symlinks: Vec::new(),
-            units: Vec::new(),
-        }];
+        for (key, value) in &module.sysctl {
+            self.add_sysctl(key, &value.0)?;
+        }
 
-        assert_eq!(
-            builder.into_archive(),
-            Initramfs::from_config(&config, &modules)
-                .unwrap()
-                .into_archive(),
+        for line in &module.tmpfiles {
+            self.add_tmpfiles_line(line)?;
+        }
+
+        for name in &module.modules_load {
+            self.list_modules_load(name)?;
+        }
+
+        for (key, value) in &module.environment {
+            self.add_environment(key, &value.0)?;
+        }
+
+        let kmods = Instant::now();
+        if let Some(kmod) = kmod.filter(|_| uses_kmod(module)) {
+            let kmod = self.kmod(kmod)?;
+            self.tolerate(module, "kernel modules".to_string(), |this| {
+                this.add_kernel_modules(module, kmod)
+            })?;
+        }
+        stats.kmod = kmods.elapsed();
+
+        let units = Instant::now();
+        for unit in &module.units {
+            self.tolerate(module, format!("unit {unit}"), |this| {
+                this.add_config_unit(unit)
+            })?;
+        }
+
+        for generator in &module.generators {
+            self.tolerate(module, format!("generator {generator}"), |this| {
+                this.add_systemd_generator(generator)
+            })?;
+        }
+        stats.units = units.elapsed();
+
+        for fragment in &module.kernel_cmdline {
+            self.add_kernel_cmdline(fragment)?;
+        }
+
+        let templates = Instant::now();
+        for spec in &module.templates {
+            let item = format!("template {}", spec.destination.display());
+            self.tolerate(module, item, |this| {
+                let vars = template::resolve_vars(&spec.vars, settings.allow_commands)?;
+                this.add_template_file(&spec.destination, &spec.content, &vars)
+            })?;
+        }
+        stats.files += templates.elapsed();
+
+        if let Some(console) = &module.console {
+            self.tolerate(module, "console".to_string(), |this| {
+                this.add_console(&Share::new(), console)
+            })?;
+        }
+
+        stats.time = start.elapsed();
+        stats.entries = self.vfs.len().saturating_sub(entries);
+        stats.bytes = self.vfs.data_size().bytes().saturating_sub(bytes);
+        self.stats.modules.push(stats);
+
+        Ok(())
+    }
+
+    // sources, content or remote file of a file specification
+    fn add_file_spec(
+        &mut self,
+        settings: &config::Settings,
+        module: &config::Module,
+        spec: &config::File,
+    ) -> Result<(), InitramfsError> {
+        let mode = spec.mode.unwrap_or(config::Mode(0o644));
+
+        if let Some(content) = &spec.content {
+            return self.add_content_file(&spec.destination, content.clone(), mode);
+        }
+
+        if let Some(remote) = &spec.remote {
+            return self.add_remote_file(remote, &spec.destination, mode);
+        }
+
+        let filter = spec.secret_filter.unwrap_or(settings.secret_filter);
+        self.set_ignore(Ignore::new(spec.ignore_defaults, &spec.exclude)?);
+
+        let result = match &spec.install {
+            Some(install) => {
+                self.add_installed_tree(&spec.sources, &spec.destination, filter, install)
+            }
+            None => self.add_tree_with_filter(&spec.sources, &spec.destination, filter),
+        }
+        .and_then(|()| {
+            for renamed in &spec.renamed {
+                let destination = spec.destination.join(&renamed.rename)?;
+                let install = spec.install.as_ref();
+                self.add_renamed_file(&renamed.path, &destination, filter, install)?;
+            }
+
+            Ok(())
+        });
+
+        if let Err(InitramfsError::SecretFile(path)) = &result {
+            error!(
+                module = module.name.as_str(), path:% = path.display();
+                "Module '{}' includes secret file: {}", module.name, path.display()
+            );
+        }
+
+        result
+    }
+
+    // in best effort mode, record the failure of an item of a module that is
+    // not critical and carry on, without whatever the item added before failing
+    fn tolerate<F>(
+        &mut self,
+        module: &config::Module,
+        item: String,
+        f: F,
+    ) -> Result<(), InitramfsError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), InitramfsError>,
+    {
+        if !self.best_effort || module.critical {
+            return f(self);
+        }
+
+        let snapshot = self.clone();
+        let Err(err) = f(self) else {
+            return Ok(());
+        };
+
+        *self = snapshot;
+        warn!(
+            module = module.name.as_str(), code = err.code();
+            "Module '{}': leaving out {}: {}", module.name, item, err
         );
+
+        self.failures.push(Failure {
+            module: module.name.clone(),
+            item,
+            code: err.code(),
+            message: err.to_string(),
+        });
+
+        Ok(())
+    }
+
+    /// Set whether ELF files are stripped of debug sections and static symbols
+    /// when added to the initramfs. Files on disk are never modified.
+    pub fn set_strip(&mut self, strip: bool) {
+        self.strip = strip;
+    }
+
+    /// Set whether what is stripped from ELF files is kept as a separate debug
+    /// file, linked from the stripped copy through `.gnu_debuglink`.
+    pub fn set_split_debug(&mut self, split_debug: bool) {
+        self.split_debug = split_debug;
+    }
+
+    /// Get the debug files of the ELF files stripped so far, by path of the
+    /// stripped file in the initramfs, see [`Initramfs::set_split_debug`].
+    pub fn debug_files(&self) -> &BTreeMap<PathBuf, Vec<u8>> {
+        &self.debug_files
+    }
+
+    /// Set what to do when files added with [`Initramfs::add_files`] match a
+    /// secret pattern.
+    pub fn set_secret_filter(&mut self, filter: config::SecretFilter) {
+        self.secret_filter = filter;
+    }
+
+    /// Set whether directories left empty are removed from the archive, the
+    /// default root directories are always kept.
+    pub fn set_prune_empty_dirs(&mut self, prune: bool) {
+        self.prune_empty_dirs = prune;
+    }
+
+    /// Set whether libraries nothing needs are removed from the archive, see
+    /// [`Initramfs::unused_libraries`].
+    pub fn set_prune_unused_libs(&mut self, prune: bool) {
+        self.prune_unused_libs = prune;
+    }
+
+    /// Set where environment variables are written, before adding any.
+    pub fn set_environment_path(&mut self, path: &ImagePath) {
+        self.environment_path = path.to_path_buf();
+    }
+
+    /// Set whether environment variables are also written to a systemd
+    /// manager drop-in setting `DefaultEnvironment=`, before adding any.
+    pub fn set_systemd_environment(&mut self, systemd_environment: bool) {
+        self.systemd_environment = systemd_environment;
+    }
+
+    /// Set whether the archive is serialized in canonical form, see
+    /// [`Archive::set_canonical`].
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    /// Set the variant of the cpio format the archive is serialized in, see
+    /// [`Archive::set_format`].
+    pub fn set_archive_format(&mut self, format: newc::Format) {
+        self.archive_format = format;
+    }
+
+    /// Set whether the extended attributes of files read from the host are
+    /// collected, see [`Initramfs::add_xattr_script`].
+    pub fn set_collect_xattrs(&mut self, collect: bool) {
+        self.xattrs = match (collect, self.xattrs.take()) {
+            (true, xattrs) => Some(xattrs.unwrap_or_default()),
+            (false, _) => None,
+        };
+    }
+
+    /// Add a shell script restoring the extended attributes collected so far,
+    /// since the archive cannot carry them. Nothing is added when collection
+    /// is disabled.
+    pub fn add_xattr_script(&mut self, path: &ImagePath) -> Result<(), InitramfsError> {
+        let Some(xattrs) = &self.xattrs else {
+            return Ok(());
+        };
+
+        debug!("Adding extended attributes script: {}", path.display());
+        let script = xattrs.script();
+        self.add_content_file(path, script, config::Mode(0o755))
+    }
+
+    /// Add `/etc/ld.so.conf` listing the directories of the initramfs holding
+    /// shared libraries outside of the default loader paths, e.g.
+    /// `/usr/lib/xtables`.
+    ///
+    /// The dynamic loader only reads `/etc/ld.so.cache`, which is generated by
+    /// running the host `ldconfig` on a copy of the libraries when `ldconfig`
+    /// is set. Otherwise the init is expected to run `ldconfig` itself.
+    pub fn add_ld_so_conf(&mut self, ldconfig: bool) -> Result<(), InitramfsError> {
+        let dirs = self.library_dirs();
+
+        let mut conf = String::from("# Generated by elusive\n");
+        let default = |dir: &&PathBuf| {
+            LOADER_DEFAULT_DIRS
+                .iter()
+                .any(|path| dir == &Path::new(path))
+        };
+
+        for dir in dirs.iter().filter(|dir| !default(dir)) {
+            // ld.so.conf is line oriented, names with newlines cannot be listed
+            match dir.to_str() {
+                Some(dir) if !dir.contains('\n') => {
+                    conf.push_str(dir);
+                    conf.push('\n');
+                }
+                _ => warn!("Library directory left out of ld.so.conf: {}", escaped(dir)),
+            }
+        }
+
+        let path = ImagePath::new("/etc/ld.so.conf")?;
+        let reason = "setting generate_ld_cache".to_string();
+        self.add_data_file(
+            &path,
+            conf.clone().into_bytes(),
+            config::Mode(0o644),
+            reason,
+        )?;
+
+        if ldconfig {
+            let cache = self.ld_cache(&dirs, &conf)?;
+
+            let path = ImagePath::new("/etc/ld.so.cache")?;
+            let reason = "setting run_ldconfig".to_string();
+            self.add_data_file(&path, cache, config::Mode(0o644), reason)?;
+        }
+
+        Ok(())
+    }
+
+    // directories holding regular files that look like shared libraries
+    fn library_dirs(&self) -> BTreeSet<PathBuf> {
+        self.vfs
+            .iter()
+            .filter(|(path, entry)| {
+                entry.is_file()
+                    && is_library_name(path)
+                    && entry.data.as_deref().is_some_and(Elf::is_elf)
+            })
+            .filter_map(|(path, _)| path.parent().map(Path::to_path_buf))
+            .collect()
+    }
+
+    // run the host ldconfig against a temporary copy of the libraries
+    fn ld_cache(&self, dirs: &BTreeSet<PathBuf>, conf: &str) -> Result<Vec<u8>, InitramfsError> {
+        let root = tempfile::tempdir()?;
+        let relative = |path: &Path| root.path().join(path.strip_prefix("/").unwrap_or(path));
+
+        fs::create_dir_all(relative(Path::new("/etc")))?;
+        fs::write(relative(Path::new("/etc/ld.so.conf")), conf)?;
+
+        for dir in dirs {
+            fs::create_dir_all(relative(dir))?;
+        }
+
+        let libraries = self.vfs.iter().filter(|(path, _)| {
+            is_library_name(path) && path.parent().is_some_and(|parent| dirs.contains(parent))
+        });
+
+        for (path, entry) in libraries {
+            let data = entry.data.as_deref().unwrap_or_default();
+
+            if entry.is_symlink() {
+                std::os::unix::fs::symlink(OsStr::from_bytes(data), relative(path))?;
+            } else if entry.is_file() {
+                fs::write(relative(path), data)?;
+            }
+        }
+
+        debug!("Running ldconfig in {}", root.path().display());
+        let output = Command::new(LDCONFIG)
+            .arg("-X")
+            .arg("-r")
+            .arg(root.path())
+            .output()
+            .map_err(InitramfsError::Ldconfig)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(InitramfsError::LdconfigFailed(
+                output.status,
+                stderr.trim().to_string(),
+            ));
+        }
+
+        Ok(fs::read(relative(Path::new("/etc/ld.so.cache")))?)
+    }
+
+    /// Set the size of the largest file read from the host, files are checked
+    /// before being read. Defaults to 256 MiB, `None` disables the check.
+    pub fn set_max_file_size(&mut self, limit: Option<Size>) {
+        self.max_file_size = limit;
+    }
+
+    /// Set the limit of the total size of the data of the initramfs, checked
+    /// as entries are added. Unlimited by default.
+    pub fn set_max_total_size(&mut self, limit: Option<Size>) {
+        self.vfs.set_max_data_size(limit);
+    }
+
+    /// Set how remote sources are downloaded, by default into
+    /// [`remote::default_cache_dir`].
+    pub fn set_fetcher(&mut self, fetcher: Fetcher) {
+        self.fetcher = fetcher;
+    }
+
+    /// Set how the files of distribution packages are listed, by default by
+    /// the package manager of the host.
+    pub fn set_package_query(&mut self, packages: PackageQuery) {
+        self.packages = packages;
+    }
+
+    /// Set the files left out when copying directories.
+    pub fn set_ignore(&mut self, ignore: Ignore) {
+        self.ignore = ignore;
+    }
+
+    /// Set whether files destined to `/bin`, `/sbin`, `/lib`, `/lib64` or
+    /// `/usr/sbin` are placed under `/usr` instead, so that they do not
+    /// conflict with the default symlinks when sources come from a split-/usr
+    /// host.
+    pub fn set_usr_merge(&mut self, usr_merge: bool) {
+        self.usr_merge = usr_merge;
+    }
+
+    /// Set the kernel modules kept out of the initramfs, see
+    /// [`crate::module_policy`].
+    pub fn set_module_policy(&mut self, policy: ModulePolicy) {
+        self.module_policy = policy;
+    }
+
+    /// Set whether items of configuration modules that cannot be added are
+    /// left out instead of failing, unless their module is critical.
+    pub fn set_best_effort(&mut self, best_effort: bool) {
+        self.best_effort = best_effort;
+    }
+
+    /// Get the items left out in best effort mode so far.
+    pub fn failures(&self) -> &[Failure] {
+        &self.failures
+    }
+
+    /// Get the kernel modules left out by the policy so far.
+    pub fn filtered_modules(&self) -> &[FilteredModule] {
+        &self.filtered_modules
+    }
+
+    /// Set the libraries kept out of the dependencies of ELF files, see
+    /// [`crate::library_policy`].
+    pub fn set_library_policy(&mut self, policy: LibraryPolicy) {
+        self.library_policy = policy;
+    }
+
+    /// Get the libraries left out by the policy so far.
+    pub fn filtered_libraries(&self) -> &[FilteredLibrary] {
+        &self.filtered_libraries
+    }
+
+    /// Get the payloads added so far and the kernel modules mounting them.
+    pub fn payloads(&self) -> &[PayloadModules] {
+        &self.payloads
+    }
+
+    /// Set the compression of kernel modules added from now on, they are
+    /// installed decompressed by default.
+    pub fn set_module_compression(&mut self, compression: ModuleCompression) {
+        self.module_compression = compression;
+    }
+
+    /// Set the configuration of the kernel modules are added for, see
+    /// [`crate::kconfig`].
+    pub fn set_kernel_config(&mut self, config: Option<KernelConfig>) {
+        self.kernel_config = config;
+    }
+
+    /// Get the configuration of the kernel modules were added for, if found.
+    pub fn kernel_config(&self) -> Option<&KernelConfig> {
+        self.kernel_config.as_ref()
+    }
+
+    /// Set how a module compression or encoder that the kernel configuration
+    /// does not support is reported.
+    pub fn set_kernel_config_conflicts(&mut self, severity: config::Severity) {
+        self.kernel_config_conflicts = severity;
+    }
+
+    /// Check that the kernel can decompress an initramfs compressed with the
+    /// encoder, according to its configuration.
+    pub fn check_encoder(&self, encoder: &Encoder) -> Result<(), InitramfsError> {
+        let Some(option) = kconfig::encoder_option(encoder) else {
+            return Ok(());
+        };
+
+        match &self.kernel_config {
+            Some(config) if !config.is_enabled(option) => self.kernel_config_conflict(
+                config,
+                format!("{encoder} compressed initramfs images"),
+                format!("CONFIG_{option}"),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    // get the kernel module context, created on first use along with the
+    // settings that depend on the kernel
+    fn kmod<'k>(&mut self, lazy: &'k mut LazyKmod) -> Result<&'k mut Kmod, InitramfsError> {
+        if lazy.kmod.is_none() {
+            let kmod = match lazy.path {
+                Some(path) => kmod_from_path(path, lazy.settings)?,
+                None => kmod_from_settings(lazy.settings)?,
+            };
+
+            self.use_kernel_config(lazy.settings, &kmod)?;
+            lazy.kmod = Some(kmod);
+        }
+
+        Ok(lazy.kmod.as_mut().expect("kmod is created above"))
+    }
+
+    // find the configuration of the kernel of a module context, then pick the
+    // compression of its modules
+    fn use_kernel_config(
+        &mut self,
+        settings: &config::Settings,
+        kmod: &Kmod,
+    ) -> Result<(), InitramfsError> {
+        let release = kmod.kernel_release();
+        let running = kmod::release_of(None).is_ok_and(|running| running == release);
+
+        self.set_kernel_config_conflicts(settings.kernel_config_conflicts);
+        self.set_kernel_config(KernelConfig::find(release, kmod.directory(), running));
+
+        let compression = match (&self.kernel_config, settings.module_compression) {
+            (Some(config), Some(compression))
+                if !config.supports_module_compression(compression) =>
+            {
+                self.kernel_config_conflict(
+                    config,
+                    format!("{compression} compressed kernel modules"),
+                    format!(
+                        "CONFIG_MODULE_DECOMPRESS and CONFIG_{}",
+                        kconfig::module_compress_option(compression)
+                    ),
+                )?;
+
+                compression
+            }
+            (_, Some(compression)) => compression,
+            (Some(config), None) => {
+                let compression = config.default_module_compression();
+                debug!(
+                    "Using {} kernel module compression from: {}",
+                    compression,
+                    config.source().display()
+                );
+
+                compression
+            }
+            (None, None) => ModuleCompression::None,
+        };
+
+        self.set_module_compression(compression);
+        Ok(())
+    }
+
+    // report a feature the kernel does not support with the configured
+    // severity
+    fn kernel_config_conflict(
+        &self,
+        config: &KernelConfig,
+        feature: String,
+        needs: String,
+    ) -> Result<(), InitramfsError> {
+        let err = InitramfsError::KernelConfig {
+            config: config.source().to_path_buf(),
+            feature,
+            needs,
+        };
+
+        match self.kernel_config_conflicts {
+            config::Severity::Warn => warn!("{}", err),
+            config::Severity::Error => return Err(err),
+            config::Severity::Ignore => (),
+        }
+
+        Ok(())
+    }
+
+    /// Set the directories searched for systemd units, ordered from highest to
+    /// lowest precedence.
+    pub fn set_unit_search_paths(&mut self, paths: Vec<PathBuf>) {
+        self.unit_search_paths = paths;
+    }
+
+    /// Set the keys of the `[Unit]` section of systemd units naming the units
+    /// added along with them (e.g. `Requires` or `PartOf`).
+    pub fn set_unit_dependency_keys(&mut self, keys: Vec<String>) {
+        self.unit_dependency_keys = keys;
+    }
+
+    /// Set the directories searched for systemd generators, ordered from
+    /// highest to lowest precedence.
+    pub fn set_generator_search_paths(&mut self, paths: Vec<PathBuf>) {
+        self.generator_search_paths = paths;
+    }
+
+    /// Add a glob pattern of host paths considered secret.
+    pub fn add_secret_pattern(&mut self, pattern: &str) -> Result<(), InitramfsError> {
+        self.secret_patterns.push(Pattern::new(pattern)?);
+        Ok(())
+    }
+
+    /// Add the init script from the provided path to the initramfs. Adding it
+    /// again is a no-op, adding a different init is an
+    /// [`InitramfsError::EntrypointConflict`].
+    pub fn add_init(&mut self, path: &Path) -> Result<(), InitramfsError> {
+        debug!("Adding init entrypoint: {}", path.display());
+        self.add_entrypoint("init", path)?;
+
+        Ok(())
+    }
+
+    /// Add a generated init script that execs the init helper with the payload,
+    /// along with the shell running it, the helper and the payload.
+    pub fn add_init_wrapper(
+        &mut self,
+        wrapper: &config::InitWrapper,
+    ) -> Result<(), InitramfsError> {
+        debug!("Adding init wrapper: {}", wrapper.helper.display());
+        let reason = format!(
+            "init wrapper {} for {}",
+            wrapper.helper.display(),
+            wrapper.exec.display()
+        );
+        let source = config::Init::Wrapper(wrapper.clone());
+        let Some(entrypoint) = self.entrypoint("init", source, reason)? else {
+            return Ok(());
+        };
+
+        let shell = self.add_init_executable(Path::new("sh"))?;
+        let helper = self.add_init_executable(&wrapper.helper)?;
+        let exec = self.add_init_executable(&wrapper.exec)?;
+
+        let script = init::wrapper_script(&shell, &helper, wrapper.args.as_deref(), &exec);
+        let dest = ImagePath::new(init::INIT_PATH)?;
+        self.add_content_file(&dest, script.into_bytes(), config::Mode(0o755))?;
+        self.entrypoints.insert("init", entrypoint);
+
+        Ok(())
+    }
+
+    // add an executable run by the init wrapper, returning its path in the initramfs
+    fn add_init_executable(&mut self, path: &Path) -> Result<ImagePath, InitramfsError> {
+        let path = if path.is_relative() {
+            Elf::find_binary(path)?
+        } else {
+            path.to_path_buf()
+        };
+
+        let dest = ImagePath::new(self.vfs.resolve_parent(self.usr_path(&path)?))?;
+        if self.vfs.contains(&dest) {
+            return Ok(dest);
+        }
+
+        let entry = self.read_source("binary", &path)?;
+        let reason = format!("init wrapper {}", path.display());
+
+        if entry.data.as_deref().is_some_and(Elf::is_elf) {
+            self.add_elf_because(&path, ElfOptions::default(), reason)?;
+        } else {
+            debug!("Adding non-ELF file: {}", path.display());
+            self.provenance
+                .record(Node::Path(dest.to_path_buf()), reason);
+
+            if let Some(parent) = dest.parent() {
+                self.vfs.create_dir_all(&parent)?;
+            }
+            self.vfs.create_entry(&dest, entry)?;
+            self.record_xattrs("binary", &path, &dest)?;
+        }
+
+        Ok(dest)
+    }
+
+    /// Add the shutdown script, similar to init.
+    pub fn add_shutdown(&mut self, path: &Path) -> Result<(), InitramfsError> {
+        debug!("Adding shutdown entrypoint: {}", path.display());
+        self.add_entrypoint("shutdown", path)?;
+
+        Ok(())
+    }
+
+    /// Add an executable run by systemd right before the system halts, in
+    /// `/usr/lib/systemd/system-shutdown/`.
+    pub fn add_shutdown_hook(&mut self, path: &Path) -> Result<(), InitramfsError> {
+        let name = path.file_name().expect("path should contain file name");
+        let dest = ImagePath::new(SYSTEMD_SHUTDOWN_DIR)?.join(name)?;
+        self.check_skeleton(&dest)?;
+
+        debug!("Adding shutdown hook: {}", path.display());
+        if self.vfs.contains(&dest) {
+            return Ok(());
+        }
+
+        let entry = self.read_source("shutdown hook", path)?;
+        let reason = format!("shutdown hook {}", path.display());
+        self.provenance
+            .record(Node::Path(dest.to_path_buf()), reason);
+
+        if let Some(parent) = dest.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+        self.vfs.create_entry(&dest, entry)?;
+        self.record_xattrs("shutdown hook", path, &dest)?;
+
+        Ok(())
+    }
+
+    /// Adds an elf binary to the initramfs, also adding its dynamic dependencies
+    /// and program interpreter.
+    ///
+    /// Symlinks already present in the initramfs are followed when placing the
+    /// file, so that e.g. `/lib64/ld-linux-x86-64.so.2` ends up in `/usr/lib`.
+    pub fn add_elf(&mut self, path: &Path) -> Result<(), InitramfsError> {
+        self.add_elf_with_options(path, ElfOptions::default())
+    }
+
+    /// Adds an elf binary to the initramfs, see [`Initramfs::add_elf`]. Its
+    /// dependencies are only added when `options.resolve_deps` is set.
+    pub fn add_elf_with_options(
+        &mut self,
+        path: &Path,
+        options: ElfOptions,
+    ) -> Result<(), InitramfsError> {
+        let reason = format!("binary {}", path.display());
+        self.add_elf_because(path, options, reason)
+    }
+
+    fn add_elf_because(
+        &mut self,
+        path: &Path,
+        options: ElfOptions,
+        reason: String,
+    ) -> Result<(), InitramfsError> {
+        let path = if path.is_relative() {
+            Elf::find_binary(path)?
+        } else {
+            path.to_path_buf()
+        };
+
+        let dest = ImagePath::new(self.vfs.resolve_parent(self.usr_path(&path)?))?;
+        self.add_elf_at(&path, dest, None, options, reason)
+    }
+
+    /// Download a binary pinned by its digest (see [`remote`]) and add it at
+    /// the provided path in the initramfs, along with its dependencies when
+    /// `options.resolve_deps` is set.
+    pub fn add_remote_binary(
+        &mut self,
+        remote: &config::Remote,
+        destination: &ImagePath,
+        options: ElfOptions,
+    ) -> Result<(), InitramfsError> {
+        let path = self.fetcher.fetch(remote)?;
+
+        let dest = ImagePath::new(self.vfs.resolve_parent(self.usr_path(destination)?))?;
+        let reason = format!("remote {}", remote.url);
+        self.add_elf_at(&path, dest, None, options, reason)
+    }
+
+    // add an elf binary at the given path in the initramfs, dependencies are
+    // placed as usual, the binary is read from path unless already provided
+    fn add_elf_at(
+        &mut self,
+        path: &Path,
+        dest: ImagePath,
+        entry: Option<Entry>,
+        options: ElfOptions,
+        reason: String,
+    ) -> Result<(), InitramfsError> {
+        self.provenance
+            .record(Node::Path(dest.to_path_buf()), reason);
+
+        if self.vfs.contains(&dest) {
+            return Ok(());
+        }
+
+        debug!(path:% = path.display(); "Adding binary: {}", path.display());
+        let mut entry = match entry {
+            Some(entry) => entry,
+            None => self.read_source("binary", path)?,
+        };
+
+        // dependencies are read from the original data, before stripping
+        let dependencies = match (options.resolve_deps, entry.data.as_deref()) {
+            (true, Some(data)) => Some(self.elf_cache.dependencies(path, data)?),
+            _ => None,
+        };
+
+        let mut debug = None;
+        if self.strip {
+            if let Some(data) = entry.data.as_mut().filter(|data| Elf::is_elf(data)) {
+                match self.split_debug {
+                    true => match Elf::split_debug(data, &debuglink(&dest)) {
+                        Ok(Some(split)) => {
+                            *data = split.stripped;
+                            debug = Some(split.debug);
+                        }
+                        Ok(None) => (),
+                        Err(err) => warn!("Failed to strip {}: {}", path.display(), err),
+                    },
+                    false => match Elf::strip(data) {
+                        Ok(stripped) => *data = stripped,
+                        Err(err) => warn!("Failed to strip {}: {}", path.display(), err),
+                    },
+                }
+            }
+        }
+
+        if let Some(parent) = dest.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        self.vfs.create_entry(&dest, entry)?;
+        if let Some(debug) = debug {
+            self.debug_files.insert(dest.to_path_buf(), debug);
+        }
+        self.record_xattrs("binary", path, &dest)?;
+
+        let Some(elf::Dependencies {
+            libraries,
+            interpreter,
+        }) = dependencies
+        else {
+            debug!("Skipping dependencies of binary: {}", path.display());
+            return Ok(());
+        };
+
+        self.with_parent(Node::Path(dest.into()), |this| {
+            for dependency in libraries {
+                let name = dependency.file_name().unwrap_or_default();
+
+                if let Some(pattern) = this.library_policy.check(&name.to_string_lossy()) {
+                    let filtered = FilteredLibrary {
+                        name: name.to_string_lossy().into_owned(),
+                        pattern: pattern.to_string(),
+                        requested_by: path.display().to_string(),
+                    };
+
+                    this.deny_library(filtered)?;
+                    continue;
+                }
+
+                let reason = format!("{NEEDED_REASON} {}", name.to_string_lossy());
+                this.add_elf_because(&dependency, ElfOptions::default(), reason)?;
+            }
+
+            if let Some(interpreter) = interpreter {
+                debug!("Adding program interpreter: {}", interpreter.display());
+
+                let reason = format!("PT_INTERP {}", interpreter.display());
+                this.add_elf_because(&interpreter, ElfOptions::default(), reason)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Add a library that no ELF file needs, along with its dependencies, by
+    /// name in the library search paths or by path.
+    pub fn add_library(&mut self, library: &str) -> Result<(), InitramfsError> {
+        let path = match Path::new(library) {
+            path if path.is_absolute() => path.to_path_buf(),
+            _ => self.elf_cache.find_library(library)?,
+        };
+
+        let reason = format!("library {library}");
+        self.add_elf_because(&path, ElfOptions::default(), reason)
+    }
+
+    // leave a library out, or fail with a strict policy
+    fn deny_library(&mut self, filtered: FilteredLibrary) -> Result<(), InitramfsError> {
+        if self.library_policy.is_strict() {
+            return Err(InitramfsError::LibraryDenied {
+                name: filtered.name,
+                pattern: filtered.pattern,
+                requested_by: filtered.requested_by,
+            });
+        }
+
+        if !self.filtered_libraries.contains(&filtered) {
+            warn!(
+                "Leaving out library {} (denylist pattern '{}'), needed by {}",
+                filtered.name, filtered.pattern, filtered.requested_by
+            );
+            self.filtered_libraries.push(filtered);
+        }
+
+        Ok(())
+    }
+
+    /// Add every binary found in the provided directory to the initramfs. ELF files
+    /// are added with their dynamic dependencies, other files (e.g. scripts) are
+    /// copied verbatim and symlinks are preserved.
+    pub fn add_elf_directory(
+        &mut self,
+        dir: &Path,
+        options: ElfOptions,
+    ) -> Result<(), InitramfsError> {
+        debug!("Adding binaries from directory: {}", dir.display());
+        let dest = self.usr_path(dir)?;
+        self.check_skeleton(&dest)?;
+        self.vfs.create_dir_all(&dest)?;
+
+        let reason = format!("binaries {}", dir.display());
+        self.provenance.record(Node::Path(dest.into()), reason);
+
+        let max_depth = if options.recursive { usize::MAX } else { 1 };
+        let walk = WalkDir::new(dir).min_depth(1).max_depth(max_depth);
+
+        for entry in walk {
+            let entry = entry?;
+            let path = entry.path();
+            let dest = self.usr_path(path)?;
+
+            if self.vfs.contains(&dest) {
+                continue;
+            }
+
+            let ty = entry.file_type();
+            if ty.is_symlink() {
+                self.add_link(&ImagePath::new(path)?, &fs::read_link(path)?)?;
+            } else if ty.is_dir() {
+                self.vfs.create_dir_all(&dest)?;
+            } else {
+                let entry = self.read_source("binary", path)?;
+                let reason = format!("binary {}", path.display());
+
+                let is_elf = entry.data.as_deref().is_some_and(Elf::is_elf);
+                if is_elf {
+                    let dest = ImagePath::new(self.vfs.resolve_parent(dest))?;
+                    self.add_elf_at(path, dest, Some(entry), options, reason)?;
+                } else {
+                    debug!("Adding non-ELF file: {}", path.display());
+                    self.provenance
+                        .record(Node::Path(dest.to_path_buf()), reason);
+                    self.vfs.create_entry(&dest, entry)?;
+                    self.record_xattrs("binary", path, &dest)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the files of an installed distribution package at their path, as
+    /// listed by the package manager (see [`crate::distro`]). ELF files are
+    /// added along with their dependencies, symlinks are recreated and
+    /// directories only come along with the files they hold.
+    pub fn add_package(&mut self, package: &str) -> Result<(), InitramfsError> {
+        debug!("Adding files of package: {}", package);
+        let reason = format!("package {package}");
+
+        for path in self.packages.files(package)? {
+            let dest = self.usr_path(&path)?;
+
+            if self.vfs.contains(&dest) {
+                continue;
+            }
+
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => return Err(self.source_error("package file", &path, err)),
+            };
+
+            if metadata.is_symlink() {
+                self.add_link(&ImagePath::new(&path)?, &fs::read_link(&path)?)?;
+            } else if metadata.is_file() {
+                let entry = self.read_source("package file", &path)?;
+
+                if entry.data.as_deref().is_some_and(Elf::is_elf) {
+                    self.add_elf_because(&path, ElfOptions::default(), reason.clone())?;
+                } else {
+                    self.check_skeleton(&dest)?;
+                    if let Some(parent) = dest.parent() {
+                        self.vfs.create_dir_all(&parent)?;
+                    }
+
+                    self.provenance
+                        .record(Node::Path(dest.to_path_buf()), reason.clone());
+                    self.vfs.create_entry(&dest, entry)?;
+                    self.record_xattrs("package file", &path, &dest)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the filesystem tree from the provided sources to the provided
+    /// destination in the initramfs. Ignored files (see
+    /// [`Initramfs::set_ignore`]) found in directories are left out.
+    pub fn add_tree(
+        &mut self,
+        sources: &[HostPath],
+        destination: &ImagePath,
+    ) -> Result<(), InitramfsError> {
+        self.add_tree_with_filter(sources, destination, self.secret_filter)
+    }
+
+    /// Same as [`Initramfs::add_tree`], overriding the secret filter.
+    pub fn add_tree_with_filter(
+        &mut self,
+        sources: &[HostPath],
+        destination: &ImagePath,
+        filter: config::SecretFilter,
+    ) -> Result<(), InitramfsError> {
+        self.copy_files(sources, destination, filter, None)
+    }
+
+    /// Same as [`Initramfs::add_tree_with_filter`], with ownership and modes
+    /// taken from the install specification instead of the host.
+    pub fn add_installed_tree(
+        &mut self,
+        sources: &[HostPath],
+        destination: &ImagePath,
+        filter: config::SecretFilter,
+        install: &config::Install,
+    ) -> Result<(), InitramfsError> {
+        self.copy_files(sources, destination, filter, Some(install))
+    }
+
+    /// Add the filesystem tree from the provided sources to the provided
+    /// destination in the initramfs.
+    #[deprecated(since = "0.15.0", note = "use Initramfs::add_tree with typed paths")]
+    pub fn add_files<P>(&mut self, sources: &[P], destination: &Path) -> Result<(), InitramfsError>
+    where
+        P: AsRef<Path>,
+    {
+        let sources = host_paths(sources);
+        self.add_tree(&sources, &ImagePath::new(destination)?)
+    }
+
+    /// Same as [`Initramfs::add_files`], overriding the secret filter.
+    #[deprecated(
+        since = "0.15.0",
+        note = "use Initramfs::add_tree_with_filter with typed paths"
+    )]
+    pub fn add_files_with_filter<P>(
+        &mut self,
+        sources: &[P],
+        destination: &Path,
+        filter: config::SecretFilter,
+    ) -> Result<(), InitramfsError>
+    where
+        P: AsRef<Path>,
+    {
+        let sources = host_paths(sources);
+        self.add_tree_with_filter(&sources, &ImagePath::new(destination)?, filter)
+    }
+
+    /// Same as [`Initramfs::add_files_with_filter`], with ownership and modes
+    /// taken from the install specification instead of the host.
+    #[deprecated(
+        since = "0.15.0",
+        note = "use Initramfs::add_installed_tree with typed paths"
+    )]
+    pub fn add_installed_files<P>(
+        &mut self,
+        sources: &[P],
+        destination: &Path,
+        filter: config::SecretFilter,
+        install: &config::Install,
+    ) -> Result<(), InitramfsError>
+    where
+        P: AsRef<Path>,
+    {
+        let sources = host_paths(sources);
+        self.add_installed_tree(&sources, &ImagePath::new(destination)?, filter, install)
+    }
+
+    fn copy_files(
+        &mut self,
+        sources: &[HostPath],
+        destination: &ImagePath,
+        filter: config::SecretFilter,
+        install: Option<&config::Install>,
+    ) -> Result<(), InitramfsError> {
+        let destination = &self.usr_path(destination)?;
+        self.check_skeleton(destination)?;
+
+        debug!(path:% = destination.display(); "Copying files into {}", destination.display());
+        self.vfs.create_dir_all(destination)?;
+
+        for source in sources {
+            let source = source.as_path();
+
+            // secret files may not be readable, check before opening them
+            if self.filter_secret(source, filter)? {
+                continue;
+            }
+
+            let file = self.open_source("file", source)?;
+            let metadata = file
+                .metadata()
+                .map_err(|err| self.source_error("file", source, err))?;
+
+            if metadata.is_dir() {
+                self.vfs
+                    .create_dir_all_with_mode(destination, Some(metadata.mode()))?;
+                let mut walk = WalkDir::new(source).min_depth(1).into_iter();
+
+                while let Some(entry) = walk.next() {
+                    let entry = entry?;
+
+                    let source_path = entry.path();
+                    let relative = source_path
+                        .strip_prefix(source)
+                        .expect("entry should be under root path");
+
+                    if self.ignore.is_ignored(relative) {
+                        debug!("Ignoring file: {}", source_path.display());
+
+                        if entry.file_type().is_dir() {
+                            walk.skip_current_dir();
+                        }
+
+                        continue;
+                    }
+
+                    let path = destination.join(relative)?;
+                    self.check_skeleton(&path)?;
+
+                    // directories created implicitly get the real metadata
+                    let placeholder = entry.file_type().is_dir() && self.vfs.is_placeholder(&path);
+                    let duplicate = entry.file_type().is_file() && self.is_foreign_file(&path);
+                    if (self.vfs.contains(&path) && !placeholder && !duplicate)
+                        || self.filter_secret(source_path, filter)?
+                    {
+                        continue;
+                    }
+
+                    let mut entry = self.read_source("file", source_path)?;
+
+                    if let Some(install) = install {
+                        apply_install(install, relative, &mut entry);
+                    }
+
+                    let reason = format!("file {}", source_path.display());
+                    if duplicate {
+                        self.check_duplicate(&path, &entry, reason)?;
+                        continue;
+                    }
+
+                    self.provenance
+                        .record(Node::Path(path.to_path_buf()), reason);
+                    self.vfs.create_entry(&path, entry)?;
+                    self.record_xattrs("file", source_path, &path)?;
+                }
+            } else {
+                let name = source.file_name().expect("path should contain file name");
+                let path = destination.join(name)?;
+                self.copy_file(file, source, &path, install)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a regular file from the host at exactly the provided path in the
+    /// initramfs, along with its parents, e.g. to install a script as
+    /// `/etc/rc.local`. Ownership and modes are taken from the install
+    /// specification when provided.
+    pub fn add_renamed_file(
+        &mut self,
+        source: &HostPath,
+        destination: &ImagePath,
+        filter: config::SecretFilter,
+        install: Option<&config::Install>,
+    ) -> Result<(), InitramfsError> {
+        let destination = &match (destination.parent(), destination.file_name()) {
+            (Some(parent), Some(name)) => self.usr_path(&parent)?.join(name)?,
+            _ => destination.clone(),
+        };
+
+        let source = source.as_path();
+        if self.filter_secret(source, filter)? {
+            return Ok(());
+        }
+
+        let file = self.open_source("file", source)?;
+        let metadata = file
+            .metadata()
+            .map_err(|err| self.source_error("file", source, err))?;
+
+        if !metadata.is_file() {
+            return Err(InitramfsError::RenameNotFile(source.to_path_buf()));
+        }
+
+        if let Some(parent) = destination.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        debug!(path:% = destination.display(); "Copying {} to {}", source.display(), destination.display());
+        self.copy_file(file, source, destination, install)
+    }
+
+    // copy a single non-directory source, the path of its copy being decided
+    fn copy_file(
+        &mut self,
+        file: File,
+        source: &Path,
+        path: &ImagePath,
+        install: Option<&config::Install>,
+    ) -> Result<(), InitramfsError> {
+        self.check_skeleton(path)?;
+
+        let duplicate = self.is_foreign_file(path);
+        if self.vfs.contains(path) && !duplicate {
+            return Ok(());
+        }
+
+        let mut entry =
+            Entry::try_from(file).map_err(|err| self.source_error("file", source, err))?;
+
+        if let (Some(install), Some(name)) = (install, path.file_name()) {
+            apply_install(install, Path::new(name), &mut entry);
+        }
+
+        let reason = format!("file {}", source.display());
+        if duplicate && entry.is_file() {
+            return self.check_duplicate(path, &entry, reason);
+        } else if duplicate {
+            return Ok(());
+        }
+
+        self.provenance
+            .record(Node::Path(path.to_path_buf()), reason);
+        self.vfs.create_entry(path, entry)?;
+        self.record_xattrs("file", source, path)?;
+
+        Ok(())
+    }
+
+    /// Add an empty directory at the provided path in the initramfs, along
+    /// with its parents.
+    pub fn add_directory(&mut self, path: &ImagePath) -> Result<(), InitramfsError> {
+        let path = &self.usr_path(path)?;
+        self.check_skeleton(path)?;
+
+        debug!("Adding directory: {}", path.display());
+        self.provenance
+            .record(Node::Path(path.to_path_buf()), "directory".to_string());
+        self.vfs.create_dir_all(path)?;
+
+        Ok(())
+    }
+
+    /// Add a symlink at the provided path in the initramfs, pointing to the
+    /// target as is: relative targets are resolved from the directory of the
+    /// symlink once unpacked.
+    pub fn add_link(&mut self, path: &ImagePath, target: &Path) -> Result<(), InitramfsError> {
+        // a symlink replacing a top-level directory is kept where it is
+        let path = &match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => self.usr_path(&parent)?.join(name)?,
+            _ => path.clone(),
+        };
+        self.check_skeleton(path)?;
+
+        if self.vfs.contains(target) {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        debug!("Adding symlink: {} -> {}", path.display(), target.display());
+
+        let reason = format!("symlink to {}", target.display());
+        self.provenance
+            .record(Node::Path(path.to_path_buf()), reason);
+
+        let entry = Entry::symlink(target);
+        self.vfs.create_entry(path, entry)?;
+
+        Ok(())
+    }
+
+    /// Add a symlink to the initramfs.
+    #[deprecated(since = "0.15.0", note = "use Initramfs::add_link with typed paths")]
+    pub fn add_symlink(&mut self, path: &Path, target: &Path) -> Result<(), InitramfsError> {
+        self.add_link(&ImagePath::new(path)?, target)
+    }
+
+    /// Render a template and add the result as a file at the provided path in
+    /// the initramfs.
+    pub fn add_template_file(
+        &mut self,
+        destination: &ImagePath,
+        content: &str,
+        vars: &BTreeMap<String, String>,
+    ) -> Result<(), InitramfsError> {
+        let rendered = template::render(content, vars)?;
+        self.check_skeleton(destination)?;
+
+        let entry = Entry::file(rendered.into_bytes());
+        if self.is_foreign_file(destination) {
+            return self.check_duplicate(destination, &entry, "template".to_string());
+        }
+
+        if let Some(parent) = destination.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        debug!("Adding rendered template: {}", destination.display());
+
+        let node = Node::Path(destination.to_path_buf());
+        self.provenance.record(node, "template".to_string());
+        self.vfs.create_entry(destination, entry)?;
+
+        Ok(())
+    }
+
+    /// Add a filesystem image to the initramfs, see [`crate::payload`]. Its
+    /// kernel modules are added with [`Initramfs::add_payload_modules`].
+    ///
+    /// Images are usually large, so they are not subject to the maximum file
+    /// size, only to the maximum total size.
+    pub fn add_payload(&mut self, payload: &config::Payload) -> Result<(), InitramfsError> {
+        let path = payload.path.as_path();
+        let destination = &payload.destination;
+        self.check_skeleton(destination)?;
+
+        let mut file = File::open(path).map_err(|err| self.source_error("payload", path, err))?;
+        let len = file
+            .metadata()
+            .map_err(|err| self.source_error("payload", path, err))?
+            .len();
+
+        // read in one go into a buffer of the final size
+        let mut data = Vec::with_capacity(usize::try_from(len).unwrap_or_default());
+        file.read_to_end(&mut data)
+            .map_err(|err| self.source_error("payload", path, err))?;
+
+        let filesystem = payload.filesystem.resolve(path.to_path_buf(), &data)?;
+        debug!(
+            "Adding {} payload {} as: {}",
+            filesystem,
+            path.display(),
+            destination.display()
+        );
+
+        if let Some(parent) = destination.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        let node = Node::Path(destination.to_path_buf());
+        self.provenance
+            .record(node, format!("payload {}", path.display()));
+        self.vfs.create_entry(destination, Entry::file(data))?;
+
+        self.payloads.push(PayloadModules {
+            destination: destination.display().to_string(),
+            filesystem,
+            modules: Vec::new(),
+            builtin: Vec::new(),
+            missing: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    /// Add the kernel modules mounting a payload previously added at
+    /// `destination`: the module of its filesystem, `loop` and `overlay`.
+    /// Modules that are builtin or cannot be found are recorded instead, see
+    /// [`Initramfs::payloads`].
+    pub fn add_payload_modules(
+        &mut self,
+        kmod: &mut Kmod,
+        destination: &ImagePath,
+    ) -> Result<(), InitramfsError> {
+        let destination = destination.display().to_string();
+        let Some(index) = self
+            .payloads
+            .iter()
+            .position(|payload| payload.destination == destination)
+        else {
+            return Ok(());
+        };
+
+        let filesystem = self.payloads[index].filesystem.module();
+        for name in filesystem
+            .into_iter()
+            .chain(payload::MOUNT_MODULES.iter().copied())
+        {
+            let module = match kmod.module_from_name(name) {
+                Ok(module) if !module.is_builtin() => Some(module),
+                Ok(_) => None,
+                Err(KmodError::ModuleNotFound(_)) if kmod.is_builtin(name) => None,
+                Err(KmodError::ModuleNotFound(_)) => {
+                    push_unique(&mut self.payloads[index].missing, name);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let Some(module) = module else {
+                push_unique(&mut self.payloads[index].builtin, name);
+                continue;
+            };
+
+            push_unique(&mut self.payloads[index].modules, name);
+            debug!(module = name; "Adding kernel module for payload {}: {}", destination, name);
+
+            let reason = format!("payload {destination}");
+            self.add_module(kmod, &module, ModuleOptions::default(), reason, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Render a template and add the result as a file to the initramfs.
+    #[deprecated(
+        since = "0.15.0",
+        note = "use Initramfs::add_template_file with typed paths"
+    )]
+    pub fn add_template(
+        &mut self,
+        destination: &Path,
+        content: &str,
+        vars: &BTreeMap<String, String>,
+    ) -> Result<(), InitramfsError> {
+        self.add_template_file(&ImagePath::new(destination)?, content, vars)
+    }
+
+    /// Add a file with the provided content and permissions at the provided
+    /// path in the initramfs, with a fixed modification time for reproducible
+    /// archives.
+    pub fn add_content_file(
+        &mut self,
+        destination: &ImagePath,
+        content: Vec<u8>,
+        mode: config::Mode,
+    ) -> Result<(), InitramfsError> {
+        self.add_data_file(destination, content, mode, "inline content".to_string())
+    }
+
+    /// Download a file pinned by its digest (see [`remote`]) and add it with
+    /// the provided permissions at the provided path in the initramfs, like
+    /// [`Initramfs::add_content_file`].
+    pub fn add_remote_file(
+        &mut self,
+        remote: &config::Remote,
+        destination: &ImagePath,
+        mode: config::Mode,
+    ) -> Result<(), InitramfsError> {
+        let path = self.fetcher.fetch(remote)?;
+        let content = fs::read(&path).map_err(|err| self.source_error("file", &path, err))?;
+
+        self.add_data_file(destination, content, mode, format!("remote {}", remote.url))
+    }
+
+    fn add_data_file(
+        &mut self,
+        destination: &ImagePath,
+        content: Vec<u8>,
+        mode: config::Mode,
+        reason: String,
+    ) -> Result<(), InitramfsError> {
+        let destination = &match (destination.parent(), destination.file_name()) {
+            (Some(parent), Some(name)) => self.usr_path(&parent)?.join(name)?,
+            _ => destination.clone(),
+        };
+        self.check_skeleton(destination)?;
+
+        let mut entry = Entry::file(content);
+        entry.metadata.mode = (entry.metadata.mode & 0o170_000) | mode.0;
+        entry.metadata.mtime = source_date_epoch();
+
+        if self.is_foreign_file(destination) {
+            return self.check_duplicate(destination, &entry, reason);
+        }
+
+        if self.vfs.contains(destination) {
+            return Ok(());
+        }
+
+        if let Some(parent) = destination.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        debug!(path:% = destination.display(); "Adding {}: {}", reason, destination.display());
+
+        let node = Node::Path(destination.to_path_buf());
+        self.provenance.record(node, reason);
+        self.vfs.create_entry(destination, entry)?;
+
+        Ok(())
+    }
+
+    /// Add a file with the provided content and permissions to the initramfs,
+    /// with a fixed modification time for reproducible archives.
+    #[deprecated(
+        since = "0.15.0",
+        note = "use Initramfs::add_content_file with typed paths"
+    )]
+    pub fn add_content(
+        &mut self,
+        destination: &Path,
+        content: Vec<u8>,
+        mode: config::Mode,
+    ) -> Result<(), InitramfsError> {
+        self.add_content_file(&ImagePath::new(destination)?, content, mode)
+    }
+
+    /// Add the keymap, console font and terminfo entries found in the
+    /// provided data directories, at the same paths as on the host.
+    pub fn add_console(
+        &mut self,
+        share: &Share,
+        console: &config::Console,
+    ) -> Result<(), InitramfsError> {
+        if let Some(keymap) = &console.keymap {
+            for asset in share.keymap(keymap)? {
+                self.add_console_asset(asset, format!("keymap {keymap}"))?;
+            }
+        }
+
+        if let Some(font) = &console.font {
+            self.add_console_asset(share.font(font)?, format!("console font {font}"))?;
+        }
+
+        for name in &console.terminfo {
+            self.add_console_asset(share.terminfo(name)?, format!("terminfo {name}"))?;
+        }
+
+        Ok(())
+    }
+
+    fn add_console_asset(&mut self, asset: Asset, reason: String) -> Result<(), InitramfsError> {
+        let Asset {
+            source,
+            destination,
+        } = asset;
+        let destination = ImagePath::new(destination)?;
+        self.check_skeleton(&destination)?;
+
+        self.provenance
+            .record(Node::Path(destination.to_path_buf()), reason);
+
+        if self.vfs.contains(&destination) {
+            return Ok(());
+        }
+
+        debug!(path:% = destination.display(); "Adding console asset: {}", destination.display());
+
+        let entry = self.read_source("console asset", &source)?;
+        let parent = destination.parent().expect("parent directory");
+
+        self.vfs.create_dir_all(&parent)?;
+        self.vfs.create_entry(&destination, entry)?;
+
+        Ok(())
+    }
+
+    /// Add a named kernel module to the initramfs.
+    pub fn add_module_from_name(
+        &mut self,
+        kmod: &mut Kmod,
+        name: &str,
+    ) -> Result<(), InitramfsError> {
+        self.add_module_from_name_with_options(kmod, name, ModuleOptions::default())
+    }
+
+    /// Add a named kernel module to the initramfs, only walking the
+    /// dependencies enabled in `options`.
+    pub fn add_module_from_name_with_options(
+        &mut self,
+        kmod: &mut Kmod,
+        name: &str,
+        options: ModuleOptions,
+    ) -> Result<(), InitramfsError> {
+        let Some(module) = lookup_module(kmod, name)? else {
+            return Ok(());
+        };
+
+        debug!(module = name; "Adding kernel module with name: {}", name);
+        self.add_module(
+            kmod,
+            &module,
+            options,
+            format!("kernel module {name}"),
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    /// Add the kernel modules needed by the host, as detected by
+    /// [`Host::detect`]. Names and aliases without a matching module, such as
+    /// pseudo filesystems, are skipped.
+    pub fn add_host_modules(
+        &mut self,
+        kmod: &mut Kmod,
+        host: &HostModules,
+    ) -> Result<(), InitramfsError> {
+        let mut added = BTreeSet::new();
+
+        for lookup in host.lookups() {
+            let names = kmod.resolve_alias(lookup)?;
+
+            if names.is_empty() {
+                debug!("No kernel module found for host: {}", lookup);
+            }
+
+            for name in names {
+                if !added.insert(name.clone()) {
+                    continue;
+                }
+
+                info!(
+                    module = name.as_str(), source = "host-only";
+                    "Adding host-only kernel module: {} (from {})", name, lookup
+                );
+                self.add_module_from_name(kmod, &name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add kernel modules by name, attributed to the synthetic `lsmod`
+    /// configuration module. Modules missing from the module directory, e.g.
+    /// loaded on the host but built for another kernel, are skipped with a
+    /// warning.
+    pub fn add_loaded_modules(
+        &mut self,
+        kmod: &mut Kmod,
+        names: &[String],
+    ) -> Result<(), InitramfsError> {
+        let node = Node::Module(LSMOD_MODULE.to_string());
+        self.provenance
+            .record(node.clone(), "setting modules_from_lsmod".to_string());
+
+        self.with_parent(node, |this| {
+            for name in names {
+                info!(
+                    module = name.as_str(), source = "lsmod";
+                    "Adding loaded kernel module: {}", name
+                );
+
+                match this.add_module_from_name(kmod, name) {
+                    Err(InitramfsError::Kmod(KmodError::ModuleNotFound(_))) => {
+                        warn!(module = name.as_str(); "Loaded kernel module not found: {}", name);
+                    }
+                    result => result?,
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Add a kernel module to the initramfs from the provided path.
+    pub fn add_module_from_path(
+        &mut self,
+        kmod: &mut Kmod,
+        path: &Path,
+    ) -> Result<(), InitramfsError> {
+        self.add_module_from_path_with_options(kmod, path, ModuleOptions::default())
+    }
+
+    /// Add a kernel module to the initramfs from the provided path, only
+    /// walking the dependencies enabled in `options`.
+    pub fn add_module_from_path_with_options(
+        &mut self,
+        kmod: &mut Kmod,
+        path: &Path,
+        options: ModuleOptions,
+    ) -> Result<(), InitramfsError> {
+        let module = kmod.module_from_path(path)?;
+
+        debug!(path:% = path.display(); "Adding kernel module from path: {}", path.display());
+        let reason = format!("kernel module {}", path.display());
+        self.add_module(kmod, &module, options, reason, None)?;
+
+        // out of tree modules are usually not signed by the distribution
+        let enforced = kmod::signatures_enforced()
+            || self
+                .kernel_config
+                .as_ref()
+                .is_some_and(KernelConfig::enforces_signatures);
+
+        if enforced && !kmod::is_signed(self.read_module(path)?) {
+            warn!(
+                path:% = path.display();
+                "Kernel enforces module signatures but module is not signed: {}", path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Add the units selected by a configuration module: a single unit, the
+    /// units matching a glob pattern or the units wanted by a target, each
+    /// with [`Initramfs::add_systemd_unit`].
+    pub fn add_config_unit(&mut self, unit: &config::Unit) -> Result<(), InitramfsError> {
+        let names = match (&unit.name, &unit.wants_of) {
+            (Some(pattern), _) if systemd::is_unit_pattern(pattern) => {
+                systemd::match_units(pattern, &self.unit_search_paths)?
+            }
+            (Some(name), _) => {
+                return match self.add_systemd_unit(name) {
+                    Err(InitramfsError::System(UnitError::UnitNotFound(missing)))
+                        if unit.optional && missing == OsStr::new(name) =>
+                    {
+                        info!(unit = name.as_str(); "Skipping missing optional systemd unit: {}", name);
+                        Ok(())
+                    }
+                    result => result,
+                };
+            }
+            (None, Some(target)) => systemd::wanted_units(target, &self.unit_search_paths),
+            (None, None) => Vec::new(),
+        };
+
+        if names.is_empty() {
+            if !unit.optional {
+                return Err(UnitError::NoMatch(unit.to_string()).into());
+            }
+
+            info!("No systemd unit found for optional {}", unit);
+        }
+
+        for name in names {
+            self.add_systemd_unit(&name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a systemd unit to the initramfs. This function also adds
+    /// binaries used by the unit to the initramfs (ExecStart) and
+    /// create relevant symlinks to enable them.
+    ///
+    /// Service units are 'installed' in the sysinit target and socket
+    /// services in the socket target. Masked units are skipped.
+    pub fn add_systemd_unit(&mut self, name: &str) -> Result<(), InitramfsError> {
+        let unit =
+            Unit::from_name_with_keys(name, &self.unit_search_paths, &self.unit_dependency_keys);
+
+        let unit = match unit {
+            Err(UnitError::Masked(path)) => {
+                info!(
+                    unit = name, path:% = path.display();
+                    "Skipping masked systemd unit: {}", name
+                );
+                return Ok(());
+            }
+            result => result?,
+        };
+
+        let Unit {
+            path,
+            overrides,
+            data,
+            dependencies,
+            binaries,
+            required_files,
+            install_path,
+        } = unit;
+
+        if !overrides.is_empty() {
+            let overridden: Vec<_> = overrides
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+
+            info!(
+                unit = name, path:% = path.display();
+                "Using locally overridden unit: {} (overrides {})",
+                path.display(),
+                overridden.join(", ")
+            );
+        }
+
+        let path = ImagePath::new(path)?;
+        let node = Node::Unit(name.to_string());
+        self.provenance.record(node.clone(), format!("unit {name}"));
+
+        self.with_parent(node, |this| {
+            this.provenance
+                .record(Node::Path(path.to_path_buf()), "unit file".to_string());
+
+            if !this.vfs.contains(&path) {
+                debug!(unit = name; "Adding systemd unit: {}", name);
+
+                let entry = Entry::file(data);
+                let parent = path.parent().expect("parent directory");
+
+                this.vfs.create_dir_all(&parent)?;
+                this.vfs.create_entry(&path, entry)?;
+            }
+
+            // add binaries required by the unit
+            for binary in binaries {
+                let reason = format!("ExecStart {binary}");
+                this.add_elf_because(Path::new(&binary), ElfOptions::default(), reason)?;
+            }
+
+            // add other files read by the unit, at the same path
+            for (file, optional) in required_files {
+                if optional && !file.exists() {
+                    debug!(unit = name; "Skipping missing optional file: {}", file.display());
+                    continue;
+                }
+
+                let destination = ImagePath::new(file.parent().unwrap_or(Path::new("/")))?;
+                this.add_tree(&[HostPath::new(file)], &destination)?;
+            }
+
+            // install the unit by adding symlink
+            if let Some(path) = install_path {
+                let target = Path::new("..").join(name);
+                this.add_link(&ImagePath::new(path)?, &target)?;
+            }
+
+            for dependency in dependencies {
+                this.add_systemd_unit(&dependency)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Add a systemd generator by name, from the generator search paths into
+    /// `/usr/lib/systemd/system-generators/` where systemd runs it at startup.
+    pub fn add_systemd_generator(&mut self, name: &str) -> Result<(), InitramfsError> {
+        let path = systemd::find_generator(name, &self.generator_search_paths)?;
+        let dest = ImagePath::new(systemd::GENERATOR_DIR)?.join(name)?;
+
+        debug!("Adding systemd generator: {}", name);
+        let reason = format!("generator {name}");
+        self.add_elf_at(&path, dest, None, ElfOptions::default(), reason)
+    }
+
+    // add the default generators found on the host, which systemd needs to
+    // find the root filesystem
+    fn add_default_generators(&mut self) -> Result<(), InitramfsError> {
+        for name in systemd::DEFAULT_GENERATORS {
+            match self.add_systemd_generator(name) {
+                Err(InitramfsError::System(UnitError::GeneratorNotFound(_))) => {
+                    debug!("Systemd generator not found, skipping: {}", name);
+                }
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a shell to fall back to when init fails.
+    ///
+    /// With busybox, `/usr/bin/rescue` mounts the API filesystems before
+    /// starting a login shell. With systemd, the emergency and rescue targets
+    /// are added along with sulogin and what it needs to read `/etc/shadow`.
+    pub fn add_emergency(&mut self, emergency: &config::Emergency) -> Result<(), InitramfsError> {
+        match emergency {
+            config::Emergency::None => Ok(()),
+            config::Emergency::Busybox { path } => {
+                info!("Adding busybox emergency shell: {}", path.display());
+                self.add_elf(path)?;
+
+                let busybox = path.display();
+                let rescue = format!(
+                    "#!{busybox} sh\n\
+                     {busybox} mount -t proc proc /proc\n\
+                     {busybox} mount -t sysfs sysfs /sys\n\
+                     {busybox} mount -t devtmpfs devtmpfs /dev\n\
+                     exec {busybox} sh -l\n"
+                );
+
+                self.add_content_file(
+                    &ImagePath::new("/usr/bin/rescue")?,
+                    rescue.into_bytes(),
+                    config::Mode(0o755),
+                )?;
+                self.add_content_file(
+                    &ImagePath::new("/etc/profile")?,
+                    EMERGENCY_PROFILE.to_vec(),
+                    config::Mode(0o644),
+                )
+            }
+            config::Emergency::Systemd => {
+                info!("Adding systemd emergency and rescue targets");
+
+                for unit in EMERGENCY_UNITS {
+                    self.add_systemd_unit(unit)?;
+                }
+
+                // sulogin reads root's entry through NSS, glibc has the files
+                // backend built in since 2.34 so the library may not exist
+                self.add_elf(Path::new("sulogin"))?;
+                if let Ok(path) = self.elf_cache.find_library(NSS_FILES_LIBRARY) {
+                    self.add_elf(&path)?;
+                }
+                self.add_content_file(
+                    &ImagePath::new("/etc/nsswitch.conf")?,
+                    b"passwd: files\nshadow: files\ngroup: files\n".to_vec(),
+                    config::Mode(0o644),
+                )?;
+
+                // requests for the rescue target go through these aliases
+                let (rescue, _) = match Unit::find_unit_in("rescue.target", &self.unit_search_paths)
+                {
+                    Err(UnitError::Masked(_)) => return Ok(()),
+                    result => result?,
+                };
+                let directory = rescue.parent().expect("parent directory");
+                for alias in RESCUE_ALIASES {
+                    let path = ImagePath::new(directory.join(alias))?;
+                    self.add_link(&path, Path::new("rescue.target"))?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that the volumes listed in `/etc/crypttab`, if the initramfs has
+    /// one, can be unlocked. The `dm_crypt` kernel module, systemd-cryptsetup
+    /// on systemd images and TPM2 libraries used by `tpm2-device` are added
+    /// when found on the host. Returns what is still missing.
+    pub fn check_crypttab(&mut self, kmod: &mut Kmod) -> Result<Vec<String>, InitramfsError> {
+        let volumes = match self.crypttab_volumes() {
+            Ok(volumes) => volumes,
+            Err(problem) => return Ok(vec![problem]),
+        };
+
+        if volumes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.add_crypttab_requirements(kmod, &volumes)
+    }
+
+    // the kernel module and host files the volumes of the crypttab need
+    fn add_crypttab_requirements(
+        &mut self,
+        kmod: &mut Kmod,
+        volumes: &[crypttab::Volume],
+    ) -> Result<Vec<String>, InitramfsError> {
+        self.with_parent(Node::Path(PathBuf::from(CRYPTTAB_PATH)), |this| {
+            let mut problems = Vec::new();
+            match kmod.module_from_name("dm_crypt") {
+                Ok(module) => {
+                    let reason = "kernel module dm_crypt".to_string();
+                    this.add_module(kmod, &module, ModuleOptions::default(), reason, None)?;
+                }
+                Err(err) => {
+                    problems.push(format!("crypttab requires kernel module dm_crypt: {err}"));
+                }
+            }
+
+            problems.extend(this.check_crypttab_volumes(volumes)?);
+            Ok(problems)
+        })
+    }
+
+    fn crypttab_volumes(&self) -> Result<Vec<crypttab::Volume>, String> {
+        let Some((_, entry)) = self
+            .vfs
+            .iter()
+            .find(|(path, _)| *path == Path::new(CRYPTTAB_PATH))
+        else {
+            return Ok(Vec::new());
+        };
+
+        let content = String::from_utf8_lossy(entry.data.as_deref().unwrap_or_default());
+        crypttab::parse(&content).map_err(|err| format!("invalid {CRYPTTAB_PATH}: {err}"))
+    }
+
+    // everything but kernel modules, so that it does not depend on the host kernel
+    fn check_crypttab_volumes(
+        &mut self,
+        volumes: &[crypttab::Volume],
+    ) -> Result<Vec<String>, InitramfsError> {
+        let mut problems = Vec::new();
+
+        if self.vfs.contains(SYSTEMD_PATH) {
+            for binary in ["systemd-cryptsetup", "systemd-cryptsetup-generator"] {
+                match Elf::find_binary(binary) {
+                    Ok(path) => self.add_elf(&path)?,
+                    Err(_) => {
+                        problems.push(format!("crypttab requires {binary} on systemd images"))
+                    }
+                }
+            }
+
+            // recent systemd generates units instead of shipping a template
+            if let Err(err) = self.add_systemd_unit("systemd-cryptsetup@.service") {
+                debug!("No systemd-cryptsetup@.service template: {}", err);
+            }
+        }
+
+        if volumes
+            .iter()
+            .any(|volume| volume.has_option("tpm2-device"))
+        {
+            for library in TPM2_LIBRARIES {
+                match self.elf_cache.find_library(library) {
+                    Ok(path) => self.add_elf(&path)?,
+                    Err(_) => problems.push(format!("tpm2-device requires library {library}")),
+                }
+            }
+        }
+
+        for volume in volumes {
+            let Some(keyfile) = &volume.keyfile else {
+                continue;
+            };
+
+            // key files on another device (path:device) or raw devices are
+            // only available at boot
+            let local = keyfile.is_absolute()
+                && !keyfile.starts_with("/dev")
+                && !keyfile.as_os_str().as_bytes().contains(&b':');
+
+            if local && !self.vfs.contains(self.vfs.resolve_parent(keyfile)) {
+                problems.push(format!(
+                    "key file {} for volume {} is not in the initramfs",
+                    keyfile.display(),
+                    volume.name
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Check that every versioned symbol requirement of the ELF files in the
+    /// initramfs is provided by a library also present in the initramfs.
+    pub fn verify_symbols(&self) -> Vec<UnresolvedSymbol> {
+        let mut requirements = Vec::new();
+        let mut definitions: HashMap<OsString, Vec<String>> = HashMap::new();
+
+        for (path, entry) in self.vfs.iter() {
+            let Some(data) = entry.data.as_deref() else {
+                continue;
+            };
+
+            if !Elf::is_elf(data) {
+                continue;
+            }
+
+            // skip files we cannot parse (e.g. 32 bit libraries)
+            let (Ok(needs), Ok(defs)) = (
+                Elf::version_requirements(data),
+                Elf::version_definitions(data),
+            ) else {
+                debug!("Skipping symbol verification for: {}", path.display());
+                continue;
+            };
+
+            // requirements name the library by its soname, whatever the path
+            // it is installed at
+            let name = match Elf::soname(data) {
+                Ok(Some(soname)) => Some(soname),
+                _ => path.file_name().map(OsString::from),
+            };
+
+            if let Some(name) = name {
+                definitions.entry(name).or_default().extend(defs);
+            }
+
+            requirements.push((path, needs));
+        }
+
+        let mut unresolved = Vec::new();
+        for (path, needs) in requirements {
+            for need in needs {
+                let provided = definitions
+                    .get(OsString::from(&need.library).as_os_str())
+                    .is_some_and(|defs| defs.contains(&need.version));
+
+                if !provided {
+                    unresolved.push(UnresolvedSymbol {
+                        path: path.clone(),
+                        need,
+                    });
+                }
+            }
+        }
+
+        unresolved
+    }
+
+    /// Find the libraries under `/usr/lib` that no ELF file in the initramfs
+    /// needs, directly or through other libraries, from the data of the
+    /// entries rather than the host files. Only libraries added for the
+    /// `DT_NEEDED` entries of other files are considered, the ones added on
+    /// their own (e.g. to be loaded with dlopen) are kept.
+    pub fn unused_libraries(&self) -> Vec<PathBuf> {
+        let mut dependencies = BTreeMap::new();
+
+        for (path, entry) in self.vfs.iter() {
+            let Some(data) = entry.data.as_deref().filter(|_| entry.is_file()) else {
+                continue;
+            };
+
+            if !Elf::is_elf(data) {
+                continue;
+            }
+
+            // files we cannot parse (e.g. 32 bit libraries) are never pruned
+            let (Ok(needed), Ok(interpreter)) =
+                (Elf::needed(data), Elf::interpreter_from_data(data))
+            else {
+                debug!("Skipping dependencies of: {}", path.display());
+                continue;
+            };
+
+            dependencies.insert(path.as_path(), (needed, interpreter));
+        }
+
+        let (libraries, mut pending): (Vec<_>, Vec<_>) = dependencies
+            .keys()
+            .copied()
+            .partition(|path| self.is_needed_library(path));
+        let mut reachable: HashSet<_> = pending.iter().copied().collect();
+
+        while let Some(path) = pending.pop() {
+            let (needed, interpreter) = &dependencies[path];
+            let interpreter = interpreter.as_ref().map(|path| self.vfs.resolve(path));
+            let needed = needed.iter().filter_map(|name| self.find_library(name));
+
+            for dependency in needed.chain(interpreter) {
+                let Some((&path, _)) = dependencies.get_key_value(dependency.as_path()) else {
+                    continue;
+                };
+
+                if reachable.insert(path) {
+                    pending.push(path);
+                }
+            }
+        }
+
+        libraries
+            .into_iter()
+            .filter(|path| !reachable.contains(path))
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Remove the libraries nothing needs, see [`Initramfs::unused_libraries`],
+    /// along with the symlinks pointing to them. Returns the removed libraries.
+    pub fn prune_unused_libraries(&mut self) -> Vec<PathBuf> {
+        let unused = self.unused_libraries();
+        let removed: HashSet<_> = unused.iter().collect();
+
+        let symlinks: Vec<_> = self
+            .vfs
+            .iter()
+            .filter(|(path, entry)| entry.is_symlink() && removed.contains(&self.vfs.resolve(path)))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in symlinks.iter().chain(&unused) {
+            self.vfs.remove_entry(path);
+        }
+
+        unused
+    }
+
+    // check if the path is a library only added as a dependency
+    fn is_needed_library(&self, path: &Path) -> bool {
+        let is_library = path.starts_with("/usr/lib")
+            && path
+                .file_name()
+                .is_some_and(|name| name.as_bytes().windows(3).any(|part| part == b".so"));
+
+        let reasons: Vec<_> = self
+            .provenance
+            .reasons(&Node::Path(path.to_path_buf()))
+            .collect();
+
+        is_library
+            && !reasons.is_empty()
+            && reasons
+                .iter()
+                .all(|reason| reason.starts_with(NEEDED_REASON))
+    }
+
+    // find a library in the initramfs the way the dynamic loader would
+    fn find_library(&self, name: &OsStr) -> Option<PathBuf> {
+        elf::LIBRARY_SEARCH_PATHS
+            .iter()
+            .map(|dir| self.vfs.resolve(Path::new(dir).join(name)))
+            .find(|path| self.vfs.contains_file(path))
+    }
+
+    /// Write the content of this initramfs as a directory tree.
+    pub fn write_to_dir(&self, path: &Path) -> Result<(), InitramfsError> {
+        self.vfs.write_to_dir(path)?;
+        Ok(())
+    }
+
+    /// Set how kernel command line parameters repeating a key with another
+    /// value are reported, see [`Initramfs::add_kernel_cmdline`].
+    pub fn set_cmdline_conflicts(&mut self, severity: config::Severity) {
+        self.cmdline_conflicts = severity;
+    }
+
+    /// Add a kernel command line fragment, which may contain multiple whitespace
+    /// separated parameters. Duplicate parameters are ignored, while parameters
+    /// with the same key but a different value are reported with the configured
+    /// severity and kept unless it is an error.
+    pub fn add_kernel_cmdline(&mut self, fragment: &str) -> Result<(), InitramfsError> {
+        for param in fragment.split_whitespace() {
+            if self.cmdline.iter().any(|existing| existing == param) {
+                continue;
+            }
+
+            if let Some((key, _)) = param.split_once('=') {
+                let conflict = self
+                    .cmdline
+                    .iter()
+                    .find(|existing| existing.split_once('=').is_some_and(|(k, _)| k == key));
+
+                if let Some(existing) = conflict {
+                    let err = InitramfsError::CmdlineConflict(existing.clone(), param.to_string());
+
+                    match self.cmdline_conflicts {
+                        config::Severity::Warn => warn!("{}", err),
+                        config::Severity::Error => return Err(err),
+                        config::Severity::Ignore => (),
+                    }
+                }
+            }
+
+            debug!("Adding kernel command line parameter: {}", param);
+            self.cmdline.push(param.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Add a sysctl setting to `/etc/sysctl.d/90-elusive.conf`. Setting a key
+    /// again to the same value is ignored, to another value it is rejected.
+    pub fn add_sysctl(&mut self, key: &str, value: &str) -> Result<(), InitramfsError> {
+        let module = self.provenance.module().map(String::from);
+
+        if self.fragments.add_sysctl(key, value, module.as_deref())? {
+            let content = self.fragments.sysctl();
+            self.write_fragment(fragment::SYSCTL_PATH, content, format!("sysctl {key}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a line to `/etc/tmpfiles.d/elusive.conf`, after checking its type,
+    /// path and mode fields.
+    pub fn add_tmpfiles_line(&mut self, line: &str) -> Result<(), InitramfsError> {
+        if self.fragments.add_tmpfiles(line)? {
+            let content = self.fragments.tmpfiles();
+            self.write_fragment(fragment::TMPFILES_PATH, content, "tmpfiles.d".to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Add an environment variable for the init, written to `/etc/initrd-env`
+    /// unless configured otherwise. Setting a variable again to the same value
+    /// is ignored, to another value it is rejected.
+    pub fn add_environment(&mut self, key: &str, value: &str) -> Result<(), InitramfsError> {
+        let module = self.provenance.module().map(String::from);
+
+        if !self
+            .fragments
+            .add_environment(key, value, module.as_deref())?
+        {
+            return Ok(());
+        }
+
+        let content = self.fragments.environment();
+        let path = self.environment_path.clone();
+        self.write_fragment(path, content, format!("environment {key}"))?;
+
+        if self.systemd_environment {
+            let content = self.fragments.systemd_environment();
+            let path = fragment::SYSTEMD_ENVIRONMENT_PATH;
+            self.write_fragment(path, content, format!("environment {key}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a kernel module by name along with its dependencies, and list it in
+    /// `/etc/modules-load.d/elusive.conf` so it is loaded at boot.
+    pub fn add_modules_load(&mut self, kmod: &mut Kmod, name: &str) -> Result<(), InitramfsError> {
+        self.list_modules_load(name)?;
+        self.add_module_from_name(kmod, name)
+    }
+
+    // listed in modules-load.d, the module itself may be added later
+    fn list_modules_load(&mut self, name: &str) -> Result<(), InitramfsError> {
+        if self.fragments.add_modules_load(name)? {
+            let content = self.fragments.modules_load();
+            let reason = format!("modules-load.d {name}");
+            self.write_fragment(fragment::MODULES_LOAD_PATH, content, reason)?;
+        }
+
+        Ok(())
+    }
+
+    // fragments are rendered again as they are merged
+    fn write_fragment<P>(
+        &mut self,
+        path: P,
+        content: String,
+        reason: String,
+    ) -> Result<(), InitramfsError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = ImagePath::new(path)?;
+        self.vfs.remove_entry(&path);
+
+        self.add_data_file(&path, content.into_bytes(), config::Mode(0o644), reason)
+    }
+
+    /// Explain why entries matching the pattern, or the path it resolves to, are
+    /// in the initramfs. Every distinct chain of reasons is returned, starting
+    /// from configuration modules or other roots. Directories only created to
+    /// hold other entries have no chain.
+    pub fn explain(&self, pattern: &Pattern) -> Vec<(PathBuf, Vec<String>)> {
+        let resolved = self.vfs.resolve_parent(pattern.as_str());
+
+        self.vfs
+            .iter()
+            .map(|(path, _)| path)
+            .filter(|path| pattern.matches_path(path) || **path == resolved)
+            .map(|path| {
+                let chains = self
+                    .provenance
+                    .chains(&Node::Path(path.clone()))
+                    .into_iter()
+                    .map(|chain| chain.join(CHAIN_SEPARATOR))
+                    .collect();
+
+                (path.clone(), chains)
+            })
+            .collect()
+    }
+
+    /// Get the configuration module that first added each entry, for size
+    /// accounting. Entries added outside of modules (init, skeleton, host-only
+    /// detection, parent directories...) are left out.
+    pub fn module_owners(&self) -> &BTreeMap<PathBuf, String> {
+        self.provenance.owners()
+    }
+
+    /// Get the time spent in each phase of the build so far, see [`BuildStats`].
+    pub fn stats(&self) -> &BuildStats {
+        &self.stats
+    }
+
+    /// Get the kernel command line parameters collected from configuration modules.
+    pub fn kernel_cmdline(&self) -> &[String] {
+        &self.cmdline
+    }
+
+    /// Return an archive from this initramfs.
+    pub fn into_archive(mut self) -> Archive {
+        if self.prune_unused_libs {
+            for path in self.prune_unused_libraries() {
+                info!("Pruned unused library: {}", path.display());
+            }
+        }
+
+        if self.prune_empty_dirs {
+            let pruned = self.vfs.prune_empty_dirs(&self.skeleton_dirs);
+            debug!("Pruned {} empty directories", pruned);
+        }
+
+        let mut archive = Archive::from(self.vfs);
+        archive.set_canonical(self.canonical);
+        archive.set_format(self.archive_format);
+        archive
+    }
+
+    // create the base layout, remembering what it contains for later checks
+    fn add_skeleton(&mut self, skeleton: &config::Skeleton) -> Result<(), InitramfsError> {
+        let defaults = if skeleton.disable_defaults {
+            debug!("Default skeleton disabled");
+            (&[][..], &[][..])
+        } else {
+            (ROOT_DIRS, ROOT_SYMLINKS)
+        };
+
+        let dirs = defaults
+            .0
+            .iter()
+            .map(|dir| ImagePath::new(dir).expect("skeleton is absolute"))
+            .chain(skeleton.extra_dirs.iter().cloned());
+
+        for dir in dirs {
+            debug!("Adding skeleton directory: {}", dir.display());
+            self.vfs.create_dir_all(&dir)?;
+            self.provenance
+                .record(Node::Path(dir.to_path_buf()), "skeleton".to_string());
+            self.skeleton_dirs.push(dir.into_path_buf());
+        }
+
+        let symlinks = defaults
+            .1
+            .iter()
+            .map(|(path, target)| {
+                let path = ImagePath::new(path).expect("skeleton is absolute");
+                (path, PathBuf::from(target), true)
+            })
+            .chain(
+                skeleton
+                    .extra_symlinks
+                    .iter()
+                    .map(|symlink| (symlink.path.clone(), symlink.resolved_target(), false)),
+            );
+
+        for (path, target, default) in symlinks {
+            debug!(
+                "Adding skeleton symlink: {} -> {}",
+                path.display(),
+                target.display()
+            );
+
+            if let Some(parent) = path.parent() {
+                self.vfs.create_dir_all(&parent)?;
+            }
+
+            self.vfs.create_entry(&path, Entry::symlink(&target))?;
+            self.provenance
+                .record(Node::Path(path.to_path_buf()), "skeleton".to_string());
+            self.skeleton_symlinks
+                .insert(path.into_path_buf(), SkeletonSymlink { target, default });
+        }
+
+        Ok(())
+    }
+
+    // entries cannot replace, or be placed through, a skeleton symlink
+    fn check_skeleton(&self, path: &Path) -> Result<(), InitramfsError> {
+        for ancestor in path.ancestors() {
+            let Some(symlink) = self.skeleton_symlinks.get(ancestor) else {
+                continue;
+            };
+
+            let path = ancestor.to_path_buf();
+            let target = symlink.target.clone();
+
+            return Err(if symlink.default {
+                InitramfsError::DefaultSymlinkConflict(path, target)
+            } else {
+                InitramfsError::SkeletonSymlinkConflict(path, target)
+            });
+        }
+
+        Ok(())
+    }
+
+    // rewrite destinations under split-/usr directories to their merged location
+    fn usr_path(&self, path: &Path) -> Result<ImagePath, PathError> {
+        if !self.usr_merge {
+            return ImagePath::new(path);
+        }
+
+        for (split, merged) in USR_MERGE {
+            if let Ok(rest) = path.strip_prefix(split) {
+                return ImagePath::new(Path::new(merged).join(rest));
+            }
+        }
+
+        ImagePath::new(path)
+    }
+
+    fn add_entrypoint(&mut self, name: &'static str, path: &Path) -> Result<(), InitramfsError> {
+        let reason = format!("{name} {}", path.display());
+        let source = config::Init::Path(path.to_path_buf());
+        let Some(entrypoint) = self.entrypoint(name, source, reason.clone())? else {
+            return Ok(());
+        };
+
+        let dest = ImagePath::root().join(name)?;
+        if self.vfs.contains(&dest) {
+            return Ok(());
+        }
+
+        let entry = self.read_source(name, path)?;
+        self.entrypoints.insert(name, entrypoint);
+
+        self.provenance
+            .record(Node::Path(dest.to_path_buf()), reason);
+        self.vfs.create_entry(&dest, entry)?;
+        self.record_xattrs(name, path, &dest)?;
+
+        Ok(())
+    }
+
+    // describe what provides an entrypoint, `None` if the same source already
+    // provided it, whichever module added it: a different source would silently
+    // be ignored otherwise
+    fn entrypoint(
+        &self,
+        name: &'static str,
+        source: config::Init,
+        reason: String,
+    ) -> Result<Option<Entrypoint>, InitramfsError> {
+        let description = match self.provenance.module() {
+            Some(module) => format!("{reason} (module {module})"),
+            None => reason,
+        };
+
+        match self.entrypoints.get(name) {
+            Some(first) if first.source == source => Ok(None),
+            Some(first) => Err(InitramfsError::EntrypointConflict {
+                name,
+                first: first.description.clone(),
+                second: description,
+            }),
+            None => Ok(Some(Entrypoint {
+                source,
+                description,
+            })),
+        }
+    }
+
+    // run `f` as a phase of the build, recording its time and what it added
+    fn phase<T, F>(&mut self, name: &str, f: F) -> Result<T, InitramfsError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, InitramfsError>,
+    {
+        let start = Instant::now();
+        let (entries, bytes) = (self.vfs.len(), self.vfs.data_size().bytes());
+
+        let result = f(self)?;
+
+        self.stats.add_phase(
+            name,
+            start.elapsed(),
+            self.vfs.len().saturating_sub(entries),
+            self.vfs.data_size().bytes().saturating_sub(bytes),
+        );
+
+        Ok(result)
+    }
+
+    // run `f` with `node` as the parent of everything it includes
+    fn with_parent<T, F>(&mut self, node: Node, f: F) -> Result<T, InitramfsError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, InitramfsError>,
+    {
+        self.provenance.push(node);
+        let result = f(self);
+        self.provenance.pop();
+
+        result
+    }
+
+    /// Check the provided host path against secret patterns, returning whether
+    /// the file should be left out.
+    fn filter_secret(
+        &self,
+        path: &Path,
+        filter: config::SecretFilter,
+    ) -> Result<bool, InitramfsError> {
+        if filter == config::SecretFilter::Allow {
+            return Ok(false);
+        }
+
+        let absolute = std::path::absolute(path)?;
+        let secret = self
+            .secret_patterns
+            .iter()
+            .any(|pattern| pattern.matches_path(&absolute));
+
+        match (secret, filter) {
+            (false, _) | (_, config::SecretFilter::Allow) => Ok(false),
+            (true, config::SecretFilter::Skip) => {
+                warn!("Skipping secret file: {}", path.display());
+                Ok(true)
+            }
+            (true, config::SecretFilter::Error) => Err(InitramfsError::SecretFile(path.into())),
+        }
+    }
+
+    fn add_module(
+        &mut self,
+        kmod: &mut Kmod,
+        module: &Module,
+        options: ModuleOptions,
+        reason: String,
+        dependent: Option<&str>,
+    ) -> Result<(), InitramfsError> {
+        let name = module.name().unwrap_or_default();
+
+        // builtin module, nothing to do
+        if module.is_builtin() {
+            if kmod.is_builtin(name) {
+                info!(module = name; "Kernel module {} is builtin, skipping", name);
+            } else {
+                debug!(module = name; "Kernel module {} has no file, assuming builtin", name);
+            }
+
+            return Ok(());
+        }
+
+        if let Some(denial) = self.module_policy.check(name) {
+            let requested_by = match (dependent, self.provenance.module()) {
+                (Some(dependent), _) => dependent.to_string(),
+                (None, Some(config)) => format!("{reason} in module {config}"),
+                (None, None) => reason,
+            };
+
+            return self.deny_module(name, denial, requested_by);
+        }
+
+        // get final path first to avoid reading the file or walking
+        // dependencies again if we have already included it in the vfs
+        let uncompressed = ImagePath::new(module.install_path()?)?;
+        let path = match self.module_compression.extension() {
+            Some(extension) => module_with_extension(&uncompressed, extension)?,
+            None => uncompressed.clone(),
+        };
+        self.provenance
+            .record(Node::Path(path.to_path_buf()), reason);
+
+        if self.vfs.contains(&path) {
+            return Ok(());
+        }
+
+        // add module dependencies, first
+        let info = kmod.module_info(module)?;
+        let depends = info
+            .depends()
+            .iter()
+            .filter(|_| options.deps)
+            .map(|name| (name, "depends"));
+        let softdeps = info
+            .pre_softdeps()
+            .iter()
+            .chain(info.post_softdeps())
+            .filter(|_| options.softdeps)
+            .map(|name| (name, "softdep"));
+
+        self.with_parent(Node::Path(path.to_path_buf()), |this| {
+            for (dependency, kind) in depends.chain(softdeps) {
+                if let Some(module) = lookup_module(kmod, dependency)? {
+                    let reason = format!("{kind} {dependency}");
+                    this.add_module(kmod, &module, options, reason, Some(name))?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        if let Some(parent) = path.parent() {
+            self.vfs.create_dir_all(&parent)?;
+        }
+
+        // the same module copied with another compression, e.g. by a files
+        // spec of the whole modules directory, would be loaded from either
+        for copy in self.module_copies(&uncompressed, &path)? {
+            let mut origin: Vec<_> = self
+                .provenance
+                .reasons(&Node::Path(copy.to_path_buf()))
+                .filter(|reason| reason.starts_with("file "))
+                .map(String::from)
+                .collect();
+
+            if let Some(name) = self.provenance.owners().get(copy.as_path()) {
+                origin.push(format!("in module {name}"));
+            }
+
+            warn!(
+                "Kernel module {} replaces {} ({})",
+                path.display(),
+                copy.display(),
+                origin.join(", ")
+            );
+            self.vfs.remove_entry(&copy);
+        }
+
+        // finally, decompress, compress as configured and create the entry in
+        // the vfs
+        let compression = self.module_compression;
+        let data = compression
+            .compress(self.read_module(module.host_path().expect("module isn't builtin"))?)?;
+
+        let entry = Entry::file(data);
+        self.vfs.create_entry(&path, entry)?;
+
+        Ok(())
+    }
+
+    // leave a module out, or fail with a strict policy
+    fn deny_module(
+        &mut self,
+        name: &str,
+        denial: Denial,
+        requested_by: String,
+    ) -> Result<(), InitramfsError> {
+        if self.module_policy.is_strict() {
+            return Err(InitramfsError::ModuleDenied {
+                name: name.to_string(),
+                denial,
+                requested_by,
+            });
+        }
+
+        let filtered = FilteredModule {
+            name: name.to_string(),
+            denial,
+            requested_by,
+        };
+
+        if !self.filtered_modules.contains(&filtered) {
+            warn!(
+                module = name;
+                "Leaving out kernel module {} ({}), requested by {}",
+                name, filtered.denial, filtered.requested_by
+            );
+            self.filtered_modules.push(filtered);
+        }
+
+        Ok(())
+    }
+
+    // entries holding the module installed at path under another compression
+    // extension, e.g. ext4.ko.zst or ext4.ko next to ext4.ko.gz
+    fn module_copies(
+        &self,
+        uncompressed: &ImagePath,
+        path: &ImagePath,
+    ) -> Result<Vec<ImagePath>, InitramfsError> {
+        let mut copies = Vec::new();
+        let compressed = MODULE_COMPRESSIONS
+            .iter()
+            .map(|extension| module_with_extension(uncompressed, extension));
+
+        for copy in iter::once(Ok(uncompressed.clone())).chain(compressed) {
+            let copy = copy?;
+            if copy != *path && self.vfs.contains_file(&copy) {
+                copies.push(copy);
+            }
+        }
+
+        Ok(copies)
+    }
+
+    // decompress a module into the scratch buffer, which keeps its capacity
+    // across modules instead of growing a new buffer each time
+    fn read_module(&mut self, path: &Path) -> Result<&[u8], InitramfsError> {
+        let file = self.open_source("kernel module", path)?;
+        let (_, mut reader) = ModuleFormat::from_reader(file)?;
+
+        self.scratch.clear();
+        reader
+            .read_to_end(&mut self.scratch)
+            .map_err(|err| self.source_error("kernel module", path, err))?;
+
+        Ok(&self.scratch)
+    }
+
+    // whether the path holds a regular file added by another configuration
+    // module, which a new copy must not silently lose to
+    fn is_foreign_file(&self, path: &ImagePath) -> bool {
+        let owner = self.provenance.owners().get(path.as_path());
+
+        match (owner, self.provenance.module()) {
+            (Some(owner), Some(module)) => owner != module && self.vfs.contains_file(path),
+            _ => false,
+        }
+    }
+
+    // keep the copy of a file added by another module if the new one is
+    // identical, see [`crate::duplicate`]
+    fn check_duplicate(
+        &mut self,
+        path: &ImagePath,
+        entry: &Entry,
+        reason: String,
+    ) -> Result<(), InitramfsError> {
+        let existing = self.vfs.get(path).and_then(|entry| entry.data.as_deref());
+        let data = entry.data.as_deref();
+
+        if existing == data {
+            debug!(path:% = path.display(); "Skipping identical copy of {}: {}", path.display(), reason);
+            self.provenance
+                .record(Node::Path(path.to_path_buf()), reason);
+            return Ok(());
+        }
+
+        let (module, source) = self
+            .provenance
+            .origin(path.as_path())
+            .expect("foreign files have an origin");
+
+        Err(InitramfsError::Divergent(Box::new(Divergent {
+            path: path.to_path_buf(),
+            first: Origin {
+                module: module.to_string(),
+                source: source.to_string(),
+            },
+            second: Origin {
+                module: self.provenance.module().unwrap_or_default().to_string(),
+                source: reason,
+            },
+            difference: duplicate::difference(
+                existing.unwrap_or_default(),
+                data.unwrap_or_default(),
+            ),
+        })))
+    }
+
+    // open a source file once, its metadata and content are then read from
+    // the same handle so it cannot change in between
+    fn open_source(&self, kind: &'static str, path: &Path) -> Result<File, InitramfsError> {
+        let file = File::open(path).map_err(|err| self.source_error(kind, path, err))?;
+
+        // check the size before anything is buffered
+        if let Some(limit) = self.max_file_size {
+            let metadata = file
+                .metadata()
+                .map_err(|err| self.source_error(kind, path, err))?;
+
+            if metadata.is_file() && metadata.len() > limit.bytes() {
+                return Err(InitramfsError::FileTooLarge {
+                    kind,
+                    path: path.to_path_buf(),
+                    module: self.provenance.module().map(String::from),
+                    size: Size(metadata.len()),
+                    limit,
+                });
+            }
+        }
+
+        Ok(file)
+    }
+
+    fn read_source(&self, kind: &'static str, path: &Path) -> Result<Entry, InitramfsError> {
+        let file = self.open_source(kind, path)?;
+        Entry::try_from(file).map_err(|err| self.source_error(kind, path, err))
+    }
+
+    // remember the extended attributes of a host file added at dest
+    fn record_xattrs(
+        &mut self,
+        kind: &'static str,
+        source: &Path,
+        dest: &ImagePath,
+    ) -> Result<(), InitramfsError> {
+        let Some(xattrs) = &mut self.xattrs else {
+            return Ok(());
+        };
+
+        match xattrs.collect(source, dest) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.source_error(kind, source, err)),
+        }
+    }
+
+    fn source_error(&self, kind: &'static str, path: &Path, err: io::Error) -> InitramfsError {
+        InitramfsError::Source {
+            kind,
+            path: path.to_path_buf(),
+            module: self.provenance.module().map(String::from),
+            source: err,
+        }
+    }
+}
+
+/// A versioned symbol requirement that no library in the initramfs provides.
+#[derive(Debug)]
+pub struct UnresolvedSymbol {
+    /// Path of the ELF file in the initramfs.
+    pub path: PathBuf,
+    /// The unmet requirement.
+    pub need: VersionNeed,
+}
+
+impl fmt::Display for UnresolvedSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requires {} from {} which is not provided by the initramfs",
+            self.path.display(),
+            self.need.version,
+            self.need.library,
+        )
+    }
+}
+
+// modification time of generated files, SOURCE_DATE_EPOCH when set
+fn host_paths<P>(paths: &[P]) -> Vec<HostPath>
+where
+    P: AsRef<Path>,
+{
+    paths
+        .iter()
+        .map(|path| HostPath::from(path.as_ref()))
+        .collect()
+}
+
+// log the findings of a check at the configured severity, failing with the
+// error built from their count when it is an error
+fn report<T>(
+    severity: config::Severity,
+    findings: &[T],
+    err: fn(usize) -> InitramfsError,
+) -> Result<(), InitramfsError>
+where
+    T: fmt::Display,
+{
+    for finding in findings {
+        match severity {
+            config::Severity::Warn => warn!("{}", finding),
+            config::Severity::Error => error!("{}", finding),
+            config::Severity::Ignore => (),
+        }
+    }
+
+    if !findings.is_empty() && severity == config::Severity::Error {
+        return Err(err(findings.len()));
+    }
+
+    Ok(())
+}
+
+// whether the file name looks like a shared library, `lib*.so*`
+fn is_library_name(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .and_then(|name| name.strip_prefix("lib"))
+        .is_some_and(|name| {
+            name.match_indices(".so")
+                .any(|(index, _)| matches!(name.as_bytes().get(index + 3), None | Some(b'.')))
+        })
+}
+
+// file name of the debug file of a stripped ELF file, e.g. ls.debug for
+// /usr/bin/ls
+fn debuglink(path: &ImagePath) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("{name}.debug")
+}
+
+fn source_date_epoch() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// replace host ownership and permissions, keeping the file type
+fn apply_install(install: &config::Install, relative: &Path, entry: &mut Entry) {
+    let default = if entry.is_dir() {
+        install.dir_mode.unwrap_or(config::Mode(0o755))
+    } else {
+        install.file_mode.unwrap_or(config::Mode(0o644))
+    };
+
+    let mode = install.exceptions.get(relative).copied().unwrap_or(default);
+
+    entry.metadata.mode = (entry.metadata.mode & 0o170_000) | mode.0;
+    entry.metadata.uid = install.owner;
+    entry.metadata.gid = install.group;
+}
+
+// the path of a module installed with a compression extension, e.g.
+// ext4.ko.zst for ext4.ko
+fn module_with_extension(path: &ImagePath, extension: &str) -> Result<ImagePath, InitramfsError> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+
+    let parent = path.parent().unwrap_or_else(ImagePath::root);
+    Ok(parent.join(name)?)
+}
+
+// add a name to a list unless it is already there, modules of a payload are
+// looked up once per kernel
+fn push_unique(list: &mut Vec<String>, name: &str) {
+    if !list.iter().any(|item| item == name) {
+        list.push(name.to_string());
+    }
+}
+
+// look a module up by name, modules listed in modules.builtin are skipped
+// when no module file matches the name
+fn lookup_module(kmod: &mut Kmod, name: &str) -> Result<Option<Rc<Module>>, InitramfsError> {
+    match kmod.module_from_name(name) {
+        Ok(module) => Ok(Some(module)),
+        Err(KmodError::ModuleNotFound(_)) if kmod.is_builtin(name) => {
+            info!(module = name; "Kernel module {} is builtin, skipping", name);
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// names of the kernel modules loaded on the host and listed in
+// `modules_from_file`, without unused modules matching `lsmod_denylist`
+fn loaded_module_names(
+    settings: &config::Settings,
+    host: &Host,
+) -> Result<Vec<String>, InitramfsError> {
+    let mut names = Vec::new();
+
+    if settings.modules_from_lsmod {
+        let policy = match &settings.lsmod_denylist {
+            Some(denylist) => ModulePolicy::new(denylist, None)?,
+            None => ModulePolicy::new(hostonly::LSMOD_DENYLIST, None)?,
+        };
+
+        for module in host.loaded_modules()? {
+            match policy.check(&module.name) {
+                Some(denial) if module.use_count == 0 => {
+                    debug!(
+                        "Skipping unused loaded kernel module {} ({})",
+                        module.name, denial
+                    );
+                }
+                _ => push_unique(&mut names, &module.name),
+            }
+        }
+    }
+
+    if let Some(path) = &settings.modules_from_file {
+        let list = fs::read_to_string(path)?;
+
+        for name in hostonly::parse_module_list(&list) {
+            push_unique(&mut names, &name);
+        }
+    }
+
+    Ok(names)
+}
+
+/// Kernel module context created from the settings on first use, see
+/// [`Initramfs::run_plan`].
+struct LazyKmod<'a> {
+    settings: &'a config::Settings,
+    /// Module directory or kernel package, `kernel_module_path` or the
+    /// running kernel when `None`.
+    path: Option<&'a Path>,
+    kmod: Option<Kmod>,
+}
+
+impl<'a> LazyKmod<'a> {
+    fn new(settings: &'a config::Settings, path: Option<&'a Path>) -> Self {
+        LazyKmod {
+            settings,
+            path,
+            kmod: None,
+        }
+    }
+}
+
+// whether a configuration module adds kernel modules
+fn uses_kmod(module: &config::Module) -> bool {
+    !module.kernel_modules.is_empty()
+        || !module.modules_load.is_empty()
+        || !module.payloads.is_empty()
+}
+
+fn kmod_from_settings(settings: &config::Settings) -> Result<Kmod, InitramfsError> {
+    if let Some(path) = &settings.kernel_module_path {
+        return kmod_from_path(path, settings);
+    }
+
+    let mut kmod = Kmod::new()?;
+    kmod.set_auto_depmod(settings.auto_depmod);
+    Ok(kmod)
+}
+
+// kmod context for a module directory or a kernel package
+fn kmod_from_path(path: &Path, settings: &config::Settings) -> Result<Kmod, InitramfsError> {
+    if !path.exists() {
+        let err = io::Error::new(io::ErrorKind::NotFound, path.display().to_string());
+        return Err(InitramfsError::InputOutput(err));
+    }
+
+    let mut kmod = if package::is_package(path) {
+        Kmod::with_package(path)
+    } else {
+        Kmod::with_directory(path)
+    }?;
+
+    kmod.set_auto_depmod(settings.auto_depmod);
+    Ok(kmod)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::payload::PayloadType;
+    use object::{Object, ObjectSection};
+
+    use std::ffi::{CString, OsStr};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use std::{env, slice};
+
+    fn image(path: &str) -> ImagePath {
+        ImagePath::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_initramfs() {
+        let mut binaries = Vec::new();
+        let mut files = Vec::new();
+        let mut kernel_modules = Vec::new();
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.add_init(Path::new("/sbin/init")).unwrap();
+
+        let ls = PathBuf::from("/usr/bin/ls");
+        if ls.exists() {
+            builder.add_elf(&ls).unwrap();
+            binaries.push(config::Binary {
+                path: ls,
+                recursive: false,
+                strip: None,
+                resolve_deps: true,
+                remote: None,
+            });
+        }
+
+        let libc = PathBuf::from("/usr/lib/libc.so.6");
+        if libc.exists() {
+            builder.add_elf(&libc).unwrap();
+            binaries.push(config::Binary {
+                path: libc,
+                recursive: false,
+                strip: None,
+                resolve_deps: true,
+                remote: None,
+            });
+        }
+
+        let hosts = HostPath::from("/etc/hosts");
+        if hosts.exists() {
+            let destination = ImagePath::new("/etc").unwrap();
+            builder
+                .add_tree(slice::from_ref(&hosts), &destination)
+                .unwrap();
+            files.push(config::File {
+                destination,
+                sources: vec![hosts],
+                renamed: Vec::new(),
+                content: None,
+                remote: None,
+                mode: None,
+                secret_filter: None,
+                install: None,
+                ignore_defaults: true,
+                exclude: Vec::new(),
+            });
+        }
+
+        let udev = HostPath::from("/usr/lib/udev/rules.d");
+        if udev.exists() {
+            let destination = ImagePath::new("/lib/udev/rules.d").unwrap();
+            builder
+                .add_tree(slice::from_ref(&udev), &destination)
+                .unwrap();
+
+            files.push(config::File {
+                sources: vec![udev],
+                destination,
+                renamed: Vec::new(),
+                content: None,
+                remote: None,
+                mode: None,
+                secret_filter: None,
+                install: None,
+                ignore_defaults: true,
+                exclude: Vec::new(),
+            });
+        }
+
+        let mut kmod = Kmod::new().unwrap();
+        let btrfs = kmod.module_from_name("btrfs").unwrap();
+
+        if btrfs.host_path().is_some() {
+            builder
+                .add_module(
+                    &mut kmod,
+                    &btrfs,
+                    ModuleOptions::default(),
+                    String::new(),
+                    None,
+                )
+                .unwrap();
+            kernel_modules.push(config::ModuleSource::Name("btrfs".to_string()).into());
+        }
+
+        let config = config::Initramfs {
+            init: Some(config::Init::Path(PathBuf::from("/sbin/init"))),
+            shutdown: Vec::new(),
+            settings: config::Settings::default(),
+            modules: Vec::new(),
+            shutdown_modules: Vec::new(),
+            environment: BTreeMap::new(),
+            prepend: Vec::new(),
+            append: Vec::new(),
+        };
+
+        let modules = vec![config::Module {
+            name: "test".to_string(),
+            binaries,
+            files,
+            kernel_modules,
+            symlinks: Vec::new(),
+            symlink_map: None,
+            payloads: Vec::new(),
+            units: Vec::new(),
+            packages: Vec::new(),
+            generators: Vec::new(),
+            kernel_cmdline: Vec::new(),
+            sysctl: BTreeMap::new(),
+            tmpfiles: Vec::new(),
+            modules_load: Vec::new(),
+            environment: BTreeMap::new(),
+            templates: Vec::new(),
+            console: None,
+            constraints: Default::default(),
+            critical: false,
+        }];
+
+        assert_eq!(
+            builder.into_archive(),
+            Initramfs::from_config(&config, &modules)
+                .unwrap()
+                .into_archive(),
+        );
+    }
+
+    #[test]
+    fn test_verify_symbols() {
+        let binary = elf::tests::versioned_elf(
+            None,
+            &[],
+            &[
+                ("libfixture.so.1", "FIXTURE_1.0"),
+                ("libfixture.so.1", "FIXTURE_2.0"),
+            ],
+        );
+        let library = elf::tests::versioned_elf(Some("libfixture.so.1"), &["FIXTURE_1.0"], &[]);
+
+        // the library is found by its soname, not the name it is installed as
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .vfs
+            .create_entry(&image("/usr/bin/tool"), Entry::file(binary))
+            .unwrap();
+        builder
+            .vfs
+            .create_entry(
+                &image("/usr/lib/libfixture-renamed.so"),
+                Entry::file(library),
+            )
+            .unwrap();
+
+        let unresolved = builder.verify_symbols();
+
+        assert_eq!(unresolved.len(), 1);
+        assert_eq!(unresolved[0].path, Path::new("/usr/bin/tool"));
+        assert_eq!(
+            unresolved[0].need,
+            VersionNeed {
+                library: "libfixture.so.1".to_string(),
+                version: "FIXTURE_2.0".to_string(),
+            }
+        );
+    }
+
+    // minimal 64 bit ELF file with DT_NEEDED entries and a program interpreter,
+    // loaded at address zero so that addresses are file offsets
+    fn fixture_elf(needed: &[&str], interpreter: Option<&str>) -> Vec<u8> {
+        let headers = 2 + u64::from(interpreter.is_some());
+        let strtab = 64 + 56 * headers;
+
+        let mut strings = vec![0];
+        let mut offsets = Vec::new();
+        for name in needed.iter().chain(&interpreter) {
+            offsets.push(strings.len() as u64);
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        strings.resize(strings.len().next_multiple_of(8), 0);
+
+        let mut dynamic = Vec::new();
+        for offset in &offsets[..needed.len()] {
+            dynamic.extend([1, *offset]);
+        }
+        dynamic.extend([5, strtab, 10, strings.len() as u64, 0, 0]);
+
+        let dynamic_offset = strtab + strings.len() as u64;
+        let size = dynamic_offset + 8 * dynamic.len() as u64;
+
+        let mut data = b"\x7fELF\x02\x01\x01".to_vec();
+        data.resize(16, 0);
+        data.extend(3u16.to_le_bytes());
+        data.extend(62u16.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend([0u64, 64, 0].iter().flat_map(|value| value.to_le_bytes()));
+        data.extend(0u32.to_le_bytes());
+        for value in [64u16, 56, headers as u16, 64, 0, 0] {
+            data.extend(value.to_le_bytes());
+        }
+
+        let mut program_header = |kind: u32, offset: u64, size: u64| {
+            data.extend(kind.to_le_bytes());
+            data.extend(4u32.to_le_bytes());
+            for value in [offset, offset, offset, size, size, 8] {
+                data.extend(value.to_le_bytes());
+            }
+        };
+        program_header(1, 0, size);
+        program_header(2, dynamic_offset, size - dynamic_offset);
+        if let (Some(interpreter), Some(offset)) = (interpreter, offsets.last()) {
+            program_header(3, strtab + offset, interpreter.len() as u64 + 1);
+        }
+
+        data.extend(strings);
+        data.extend(dynamic.iter().flat_map(|value| value.to_le_bytes()));
+        data
+    }
+
+    #[test]
+    fn test_library_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+
+        let tool = dir.join("tool");
+        fs::write(&tool, fixture_elf(&["libmid.so.1", "libcommon.so.1"], None)).unwrap();
+        let libraries = [
+            (
+                "libmid.so.1",
+                fixture_elf(&["libonly.so.1", "libcommon.so.1"], None),
+            ),
+            ("libonly.so.1", fixture_elf(&[], None)),
+            ("libcommon.so.1", fixture_elf(&[], None)),
+            ("libgcc_s.so.1", fixture_elf(&[], None)),
+        ];
+        for (name, data) in &libraries {
+            fs::write(dir.join("lib").join(name), data).unwrap();
+        }
+
+        let build = |strict: bool| {
+            let mut builder = Initramfs::new_bare();
+            for (name, _) in &libraries {
+                builder
+                    .elf_cache
+                    .insert_library(name, dir.join("lib").join(name));
+            }
+
+            let mut policy = LibraryPolicy::new(&["libmid.so*"]).unwrap();
+            policy.set_strict(strict);
+            builder.set_library_policy(policy);
+
+            let result = builder
+                .add_elf(&tool)
+                .and_then(|()| builder.add_library("libgcc_s.so.1"));
+            (builder, result)
+        };
+
+        let (builder, result) = build(false);
+        let (_, strict) = build(true);
+        result.unwrap();
+
+        let libs: Vec<_> = libraries
+            .iter()
+            .map(|(name, _)| builder.vfs.contains(dir.join("lib").join(name)))
+            .collect();
+
+        // libonly is only needed by the denied library
+        assert_eq!(libs, [false, false, true, true]);
+        assert!(builder.vfs.contains(&tool));
+        assert_eq!(
+            builder.filtered_libraries(),
+            [FilteredLibrary {
+                name: "libmid.so.1".to_string(),
+                pattern: "libmid.so*".to_string(),
+                requested_by: tool.display().to_string(),
+            }]
+        );
+
+        assert_eq!(strict.err().unwrap().code(), "initramfs_library_denied");
+    }
+
+    #[test]
+    fn test_unused_libraries() {
+        let interpreter = "/lib64/ld-linux-x86-64.so.2";
+        let mut builder = Initramfs::new().unwrap();
+
+        let mut add = |path: &str, entry: Entry, reason: &str| {
+            builder
+                .provenance
+                .record(Node::Path(PathBuf::from(path)), reason.to_string());
+            builder.vfs.create_entry(&image(path), entry).unwrap();
+        };
+
+        // libfoo is only reached through its soname symlink
+        let tool = fixture_elf(&["libfoo.so.1"], Some(interpreter));
+        let foo = fixture_elf(&["libbar.so.2"], None);
+        let leaf = fixture_elf(&[], None);
+        add("/usr/bin/tool", Entry::file(tool), "binary /usr/bin/tool");
+        add(
+            "/usr/lib/ld-linux-x86-64.so.2",
+            Entry::file(leaf.clone()),
+            "PT_INTERP",
+        );
+        add(
+            "/usr/lib/libfoo.so.1",
+            Entry::symlink("libfoo.so.1.2.3"),
+            "DT_NEEDED",
+        );
+        add(
+            "/usr/lib/libfoo.so.1.2.3",
+            Entry::file(foo),
+            "DT_NEEDED libfoo.so.1",
+        );
+        add(
+            "/usr/lib/libbar.so.2",
+            Entry::file(leaf.clone()),
+            "DT_NEEDED libbar.so.2",
+        );
+
+        // needed by nothing, the second one is listed on its own
+        add(
+            "/usr/lib/libold.so.1",
+            Entry::file(leaf.clone()),
+            "DT_NEEDED libold.so.1",
+        );
+        add(
+            "/usr/lib/libold.so",
+            Entry::symlink("libold.so.1"),
+            "DT_NEEDED",
+        );
+        add(
+            "/usr/lib/libdl.so.1",
+            Entry::file(leaf),
+            "binary /usr/lib/libdl.so.1",
+        );
+
+        assert_eq!(
+            builder.unused_libraries(),
+            [PathBuf::from("/usr/lib/libold.so.1")]
+        );
+
+        builder.set_prune_unused_libs(true);
+        let archive = builder.into_archive();
+        let paths: BTreeSet<_> = archive
+            .entries()
+            .iter()
+            .map(|(path, _)| path.to_str().unwrap())
+            .filter(|path| path.starts_with("/usr/lib/"))
+            .collect();
+
+        assert_eq!(
+            paths,
+            BTreeSet::from([
+                "/usr/lib/ld-linux-x86-64.so.2",
+                "/usr/lib/libbar.so.2",
+                "/usr/lib/libdl.so.1",
+                "/usr/lib/libfoo.so.1",
+                "/usr/lib/libfoo.so.1.2.3",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_elf_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+
+        // a minimal 64 bit ELF header, without any dynamic dependencies
+        let mut elf = vec![0x7F, b'E', b'L', b'F', 2, 1, 1];
+        elf.resize(16, 0);
+        elf.extend([2, 0, 0x3E, 0, 1, 0, 0, 0]);
+        elf.resize(52, 0);
+        elf.extend([64, 0]);
+        elf.resize(64, 0);
+
+        fs::write(dir.join("binary"), &elf).unwrap();
+        fs::write(dir.join("script"), b"#!/bin/sh\n").unwrap();
+        fs::write(dir.join("sub/nested"), b"#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink("binary", dir.join("link")).unwrap();
+
+        let mut flat = Initramfs::new().unwrap();
+        let flat_result = flat.add_elf_directory(dir, ElfOptions::default());
+
+        let mut recursive = Initramfs::new().unwrap();
+        let options = ElfOptions {
+            recursive: true,
+            ..ElfOptions::default()
+        };
+        let recursive_result = recursive.add_elf_directory(dir, options);
+
+        flat_result.unwrap();
+        recursive_result.unwrap();
+
+        assert!(flat.vfs.contains(dir.join("binary")));
+        assert!(flat.vfs.contains(dir.join("script")));
+        assert!(!flat.vfs.contains(dir.join("sub/nested")));
+        assert!(recursive.vfs.contains(dir.join("sub/nested")));
+
+        let (_, link) = flat
+            .vfs
+            .iter()
+            .find(|(path, _)| **path == dir.join("link"))
+            .unwrap();
+
+        assert!(link.is_symlink());
+        assert_eq!(link.data.as_deref(), Some(b"binary".as_slice()));
+    }
+
+    #[test]
+    fn test_interpreter() {
+        let Some(ls) = ["/usr/bin/ls", "/bin/ls"]
+            .iter()
+            .map(Path::new)
+            .find(|path| path.exists())
+        else {
+            return;
+        };
+
+        let Some(interpreter) = Elf::interpreter(ls).unwrap() else {
+            return;
+        };
+
+        // only add the interpreter, as resolving libraries depends on the host
+        let mut builder = Initramfs::new().unwrap();
+        builder.add_elf(&interpreter).unwrap();
+
+        let dest = builder.vfs.resolve_parent(&interpreter);
+        assert!(builder.vfs.contains(&dest));
+    }
+
+    #[test]
+    fn test_resolve_deps() {
+        let exe = env::current_exe().unwrap();
+        let options = ElfOptions {
+            resolve_deps: false,
+            ..ElfOptions::default()
+        };
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.add_elf_with_options(&exe, options).unwrap();
+
+        let files: Vec<_> = builder
+            .vfs
+            .iter()
+            .filter(|(_, entry)| !entry.is_dir())
+            .map(|(path, _)| path)
+            .filter(|path| {
+                !ROOT_SYMLINKS
+                    .iter()
+                    .any(|(link, _)| *path == Path::new(link))
+            })
+            .collect();
+
+        assert_eq!(files, [builder.usr_path(&exe).unwrap().as_path()]);
+    }
+
+    #[test]
+    fn test_split_debug() {
+        let exe = env::current_exe().unwrap();
+        let options = ElfOptions {
+            resolve_deps: false,
+            ..ElfOptions::default()
+        };
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_strip(true);
+        builder.set_split_debug(true);
+        builder.add_elf_with_options(&exe, options).unwrap();
+
+        let dest = builder.usr_path(&exe).unwrap();
+        let debug = &builder.debug_files()[dest.as_path()];
+        let data = builder.vfs.get(&dest).unwrap().data.as_deref().unwrap();
+
+        let link = Elf::split_debug(data, "unused").unwrap();
+        assert!(link.is_none(), "the initramfs copy is stripped");
+
+        let file = object::File::parse(data).unwrap();
+        let link = file
+            .section_by_name(elf::DEBUGLINK_SECTION)
+            .unwrap()
+            .data()
+            .unwrap();
+        let name = format!("{}.debug", exe.file_name().unwrap().to_str().unwrap());
+        assert!(link.starts_with(name.as_bytes()));
+
+        let file = object::File::parse(debug.as_slice()).unwrap();
+        assert!(file.section_by_name(".debug_info").is_some());
+
+        // only stripped files have a debug file
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_split_debug(true);
+        builder.add_elf_with_options(&exe, options).unwrap();
+        assert!(builder.debug_files().is_empty());
+    }
+
+    // snd depends on soundcore and softdepends on snd_seq, which also
+    // depends on soundcore
+    const SOUND_MODULES: &[(&str, &[&str])] = &[
+        (
+            "snd",
+            &["name=snd", "depends=soundcore", "softdep=post: snd_seq"],
+        ),
+        ("soundcore", &["name=soundcore", "depends="]),
+        ("snd_seq", &["name=snd_seq", "depends=soundcore"]),
+    ];
+
+    fn sound_kmod(release: &Path) -> Kmod {
+        let kernel = release.join("kernel");
+        fs::create_dir_all(&kernel).unwrap();
+
+        let mut kmod = Kmod::with_directory(release).unwrap();
+
+        for (name, modinfo) in SOUND_MODULES {
+            let path = kernel.join(format!("{name}.ko"));
+            if !path.exists() {
+                kmod::tests::fake_module(&path, modinfo);
+            }
+
+            // dependencies are found by name once libkmod knows their path
+            kmod.module_from_path(path).unwrap();
+        }
+
+        kmod
+    }
+
+    #[test]
+    fn test_module_deps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+
+        let build = |options: ModuleOptions| {
+            let mut kmod = sound_kmod(&release);
+
+            let mut builder = Initramfs::new().unwrap();
+            builder
+                .add_module_from_name_with_options(&mut kmod, "snd", options)
+                .unwrap();
+
+            let mut added: Vec<_> = builder
+                .vfs
+                .iter()
+                .filter_map(|(path, _)| {
+                    path.strip_prefix(Path::new("/usr/lib/modules/6.0.0-elusive/kernel"))
+                        .ok()
+                })
+                .filter_map(|path| path.file_stem()?.to_str().map(String::from))
+                .collect();
+
+            added.sort();
+            added
+        };
+
+        let all = build(ModuleOptions::default());
+        let no_softdeps = build(ModuleOptions {
+            softdeps: false,
+            ..ModuleOptions::default()
+        });
+        let none = build(ModuleOptions {
+            deps: false,
+            softdeps: false,
+        });
+
+        assert_eq!(all, ["snd", "snd_seq", "soundcore"]);
+        assert_eq!(no_softdeps, ["snd", "soundcore"]);
+        assert_eq!(none, ["snd"]);
+    }
+
+    #[test]
+    fn test_module_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+
+        let build = |policy: ModulePolicy| {
+            let mut kmod = sound_kmod(&release);
+
+            let mut builder = Initramfs::new().unwrap();
+            builder.set_module_policy(policy);
+            let result = builder.add_module_from_name(&mut kmod, "snd");
+
+            let mut added: Vec<_> = builder
+                .vfs
+                .iter()
+                .filter_map(|(path, _)| {
+                    path.strip_prefix(Path::new("/usr/lib/modules/6.0.0-elusive/kernel"))
+                        .ok()
+                })
+                .filter_map(|path| path.file_stem()?.to_str().map(String::from))
+                .collect();
+
+            added.sort();
+            result.map(|()| (added, builder.filtered_modules().to_vec()))
+        };
+
+        // the softdep in the middle of snd -> snd_seq -> soundcore is cut
+        let (denied, denied_filtered) =
+            build(ModulePolicy::new(&["snd-seq"], None).unwrap()).unwrap();
+        let (allowed, allowed_filtered) =
+            build(ModulePolicy::new(&[], Some(&["snd*"])).unwrap()).unwrap();
+
+        let mut strict = ModulePolicy::new(&["snd_seq"], None).unwrap();
+        strict.set_strict(true);
+        let strict = build(strict);
+
+        assert_eq!(denied, ["snd", "soundcore"]);
+        assert_eq!(
+            denied_filtered,
+            [FilteredModule {
+                name: "snd_seq".to_string(),
+                denial: Denial::Denylist("snd-seq".to_string()),
+                requested_by: "snd".to_string(),
+            }]
+        );
+
+        // soundcore is requested by both snd and snd_seq
+        assert_eq!(allowed, ["snd", "snd_seq"]);
+        let requested_by: Vec<_> = allowed_filtered
+            .iter()
+            .map(|filtered| (filtered.name.as_str(), filtered.requested_by.as_str()))
+            .collect();
+        assert_eq!(
+            requested_by,
+            [("soundcore", "snd"), ("soundcore", "snd_seq")]
+        );
+        assert!(allowed_filtered
+            .iter()
+            .all(|filtered| filtered.denial == Denial::NotAllowed));
+
+        let err = strict.unwrap_err();
+        assert_eq!(err.code(), "initramfs_module_denied");
+        assert_eq!(
+            err.to_string(),
+            "kernel module snd_seq is denied (denylist pattern 'snd_seq'), requested by snd"
+        );
+
+        // a denied module added directly is left out as well
+        let mut kmod = sound_kmod(&release);
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_module_policy(ModulePolicy::new(&["snd"], None).unwrap());
+        builder.add_module_from_name(&mut kmod, "snd").unwrap();
+        drop(kmod);
+
+        assert_eq!(
+            builder.filtered_modules()[0].requested_by,
+            "kernel module snd"
+        );
+        assert!(!builder.vfs.iter().any(|(path, _)| path.ends_with("snd.ko")));
+    }
+
+    #[test]
+    fn test_compressed_module_copy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        let mut kmod = sound_kmod(&release);
+
+        // the modules directory copied with compressed modules
+        let copy = dir.join("copy");
+        fs::create_dir_all(&copy).unwrap();
+        fs::write(copy.join("snd.ko.zst"), b"compressed").unwrap();
+        fs::write(copy.join("other.ko.zst"), b"compressed").unwrap();
+
+        let kernel = image("/usr/lib/modules/6.0.0-elusive/kernel");
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .add_tree(
+                &[
+                    HostPath::new(copy.join("snd.ko.zst")),
+                    HostPath::new(copy.join("other.ko.zst")),
+                ],
+                &kernel,
+            )
+            .unwrap();
+        builder
+            .add_module_from_name_with_options(
+                &mut kmod,
+                "snd",
+                ModuleOptions {
+                    deps: false,
+                    softdeps: false,
+                },
+            )
+            .unwrap();
+
+        drop(kmod);
+
+        assert!(builder.vfs.contains_file(kernel.join("snd.ko").unwrap()));
+        assert!(!builder.vfs.contains(kernel.join("snd.ko.zst").unwrap()));
+        assert!(builder
+            .vfs
+            .contains_file(kernel.join("other.ko.zst").unwrap()));
+    }
+
+    #[test]
+    fn test_kernel_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        let mut kmod = sound_kmod(&release);
+
+        // only the configuration in the module directory exists
+        fs::write(
+            release.join("config"),
+            "CONFIG_RD_ZSTD=y\n\
+             CONFIG_MODULE_COMPRESS_ZSTD=y\n\
+             CONFIG_MODULE_DECOMPRESS=y\n",
+        )
+        .unwrap();
+
+        let kernel = image("/usr/lib/modules/6.0.0-elusive/kernel");
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .use_kernel_config(&config::Settings::default(), &kmod)
+            .unwrap();
+
+        // an uncompressed copy is replaced by the compressed module
+        builder.vfs.create_dir_all(&kernel).unwrap();
+        builder
+            .vfs
+            .create_entry(
+                &kernel.join("soundcore.ko").unwrap(),
+                Entry::file(b"copy".to_vec()),
+            )
+            .unwrap();
+        builder
+            .add_module_from_name(&mut kmod, "soundcore")
+            .unwrap();
+
+        let compressed = builder
+            .vfs
+            .get(kernel.join("soundcore.ko.zst").unwrap())
+            .and_then(|entry| entry.data.clone());
+        let replaced = builder.vfs.contains(kernel.join("soundcore.ko").unwrap());
+        let zstd = builder.check_encoder(&Encoder::Zstd);
+        let gzip = builder.check_encoder(&Encoder::Gzip);
+
+        let settings = |compression, conflicts| config::Settings {
+            module_compression: Some(compression),
+            kernel_config_conflicts: conflicts,
+            ..config::Settings::default()
+        };
+
+        let mut builder = Initramfs::new().unwrap();
+        let conflict = builder.use_kernel_config(
+            &settings(ModuleCompression::Gzip, config::Severity::Error),
+            &kmod,
+        );
+        let strict_gzip = builder.check_encoder(&Encoder::Gzip);
+        builder
+            .use_kernel_config(
+                &settings(ModuleCompression::Gzip, config::Severity::Warn),
+                &kmod,
+            )
+            .unwrap();
+        let warned = builder.module_compression;
+
+        drop(kmod);
+
+        let compressed = compressed.expect("compressed module");
+        let module = zstd::decode_all(compressed.as_slice()).unwrap();
+        assert!(module.starts_with(b"\x7fELF"));
+        assert!(!replaced);
+        assert_eq!(
+            builder.kernel_config().unwrap().source(),
+            release.join("config")
+        );
+
+        // reported as warnings by default
+        assert!(zstd.is_ok());
+        assert!(gzip.is_ok());
+
+        let err = conflict.unwrap_err();
+        assert_eq!(err.code(), "initramfs_kernel_config");
+        assert!(err.to_string().ends_with(
+            "does not support gzip compressed kernel modules, \
+             it needs CONFIG_MODULE_DECOMPRESS and CONFIG_MODULE_COMPRESS_GZIP"
+        ));
+        assert_eq!(strict_gzip.unwrap_err().code(), "initramfs_kernel_config");
+
+        // the explicit choice is kept
+        assert_eq!(warned, ModuleCompression::Gzip);
+    }
+
+    #[test]
+    fn test_payloads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        let kernel = release.join("kernel");
+        fs::create_dir_all(kernel.join("fs/squashfs")).unwrap();
+        fs::create_dir_all(kernel.join("drivers/block")).unwrap();
+
+        kmod::tests::fake_module(&kernel.join("fs/squashfs/squashfs.ko"), &["name=squashfs"]);
+        kmod::tests::fake_module(&kernel.join("drivers/block/loop.ko"), &["name=loop"]);
+        fs::write(
+            release.join("modules.dep"),
+            "kernel/fs/squashfs/squashfs.ko:\nkernel/drivers/block/loop.ko:\n",
+        )
+        .unwrap();
+        fs::write(
+            release.join("modules.builtin"),
+            "kernel/fs/overlayfs/overlay.ko\n",
+        )
+        .unwrap();
+
+        // tiny images holding only the superblock magic
+        let mut squashfs = b"hsqs".to_vec();
+        squashfs.resize(4096, 0);
+        let mut erofs = vec![0; 1024];
+        erofs.extend_from_slice(&[0xe2, 0xe1, 0xf5, 0xe0]);
+        erofs.resize(4096, 0);
+        fs::write(dir.join("root.squashfs"), &squashfs).unwrap();
+        fs::write(dir.join("root.erofs"), &erofs).unwrap();
+
+        let payload = |name: &str, destination: &str, filesystem| config::Payload {
+            path: HostPath::new(dir.join(name)),
+            destination: image(destination),
+            filesystem,
+        };
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        for path in ["fs/squashfs/squashfs.ko", "drivers/block/loop.ko"] {
+            kmod.module_from_path(kernel.join(path)).unwrap();
+        }
+
+        // images are exempt from the maximum file size
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_max_file_size(Some(Size(1024)));
+
+        let squashfs_payload = payload("root.squashfs", "/live/root.squashfs", PayloadType::Auto);
+        builder.add_payload(&squashfs_payload).unwrap();
+        builder
+            .add_payload_modules(&mut kmod, &squashfs_payload.destination)
+            .unwrap();
+
+        let erofs_payload = payload("root.erofs", "/root.erofs", PayloadType::Erofs);
+        builder.add_payload(&erofs_payload).unwrap();
+        builder
+            .add_payload_modules(&mut kmod, &erofs_payload.destination)
+            .unwrap();
+
+        let mismatch = builder
+            .add_payload(&payload("root.erofs", "/other.img", PayloadType::Squashfs))
+            .unwrap_err();
+
+        let data = builder
+            .vfs
+            .get(image("/live/root.squashfs"))
+            .and_then(|entry| entry.data.clone());
+        let modules = image("/usr/lib/modules/6.0.0-elusive/kernel");
+        let squashfs_module = builder
+            .vfs
+            .contains(modules.join("fs/squashfs/squashfs.ko").unwrap());
+        let loop_module = builder
+            .vfs
+            .contains(modules.join("drivers/block/loop.ko").unwrap());
+
+        drop(kmod);
+
+        assert_eq!(data, Some(squashfs));
+        assert!(squashfs_module && loop_module);
+        assert!(!builder.vfs.contains(image("/other.img")));
+        assert_eq!(mismatch.code(), "initramfs_payload");
+
+        let [live, root] = builder.payloads() else {
+            panic!("unexpected payloads: {:?}", builder.payloads());
+        };
+
+        assert_eq!(live.destination, "/live/root.squashfs");
+        assert_eq!(live.filesystem, PayloadType::Squashfs);
+        assert_eq!(live.modules, ["squashfs", "loop"]);
+        assert_eq!(live.builtin, ["overlay"]);
+        assert!(live.missing.is_empty());
+        assert!(!live.filesystem_missing() && !live.filesystem_builtin());
+
+        // modules shared with the first payload are recorded for both
+        assert_eq!(root.filesystem, PayloadType::Erofs);
+        assert_eq!(root.modules, ["loop"]);
+        assert_eq!(root.missing, ["erofs"]);
+        assert!(root.filesystem_missing());
+    }
+
+    #[test]
+    fn test_loaded_modules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        let kernel = release.join("kernel");
+        fs::create_dir_all(kernel.join("sound/pci/hda")).unwrap();
+        fs::create_dir_all(kernel.join("drivers/md")).unwrap();
+
+        // file names use dashes, /proc/modules uses underscores
+        kmod::tests::fake_module(
+            &kernel.join("sound/pci/hda/snd-hda-intel.ko"),
+            &["name=snd_hda_intel"],
+        );
+        kmod::tests::fake_module(&kernel.join("drivers/md/dm-crypt.ko"), &["name=dm_crypt"]);
+        fs::write(
+            release.join("modules.dep"),
+            "kernel/sound/pci/hda/snd-hda-intel.ko:\nkernel/drivers/md/dm-crypt.ko:\n",
+        )
+        .unwrap();
+
+        let host = dir.join("host");
+        fs::create_dir_all(host.join("proc")).unwrap();
+        fs::write(
+            host.join("proc/modules"),
+            "snd_hda_intel 61440 3 - Live 0x0000000000000000\n\
+             snd_seq_dummy 12288 0 - Live 0x0000000000000000\n\
+             dm_crypt 65536 1 - Live 0x0000000000000000\n\
+             nvidia 62083072 0 - Live 0x0000000000000000 (POE)\n",
+        )
+        .unwrap();
+        fs::write(dir.join("modules.txt"), "# captured\ndm-crypt\nvfat\n").unwrap();
+
+        let mut settings = config::Settings {
+            modules_from_lsmod: true,
+            ..Default::default()
+        };
+        let names = loaded_module_names(&settings, &Host::with_root(&host)).unwrap();
+
+        settings.lsmod_denylist = Some(vec!["nvidia".to_string()]);
+        settings.modules_from_file = Some(dir.join("modules.txt"));
+        let custom = loaded_module_names(&settings, &Host::with_root(&host)).unwrap();
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        for path in ["sound/pci/hda/snd-hda-intel.ko", "drivers/md/dm-crypt.ko"] {
+            kmod.module_from_path(kernel.join(path)).unwrap();
+        }
+
+        // vfat is neither loaded nor in the module directory
+        let mut builder = Initramfs::new().unwrap();
+        builder.add_loaded_modules(&mut kmod, &custom).unwrap();
+
+        drop(kmod);
+
+        assert_eq!(names, ["snd_hda_intel", "dm_crypt", "nvidia"]);
+        assert_eq!(
+            custom,
+            [
+                "snd_hda_intel",
+                "snd_seq_dummy",
+                "dm_crypt",
+                "dm-crypt",
+                "vfat"
+            ]
+        );
+
+        // both modules are found, and the report attributes them to lsmod
+        let owners = builder.module_owners();
+        assert_eq!(owners.len(), 2);
+        for (path, module) in owners {
+            assert!(builder.vfs.contains(ImagePath::new(path).unwrap()));
+            assert_eq!(module, "lsmod");
+        }
+    }
+
+    #[test]
+    fn test_generators() {
+        use std::os::unix::fs::symlink;
+
+        let exe = env::current_exe().unwrap();
+        let Some(loader) = Elf::interpreter(&exe).unwrap() else {
+            return;
+        };
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let generators = dir.join("system-generators");
+        let units = dir.join("system");
+        fs::create_dir_all(&generators).unwrap();
+        fs::create_dir_all(&units).unwrap();
+        fs::write(units.join("test.target"), "[Unit]\nDescription=Test\n").unwrap();
+
+        // the dynamic loader does not need any library
+        symlink(&loader, generators.join("systemd-fstab-generator")).unwrap();
+        symlink(&loader, generators.join("custom-generator")).unwrap();
+        symlink(&exe, generators.join("linked-generator")).unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_generator_search_paths(vec![generators.clone()]);
+        builder.set_unit_search_paths(vec![units.clone()]);
+
+        let custom = builder.add_systemd_generator("custom-generator");
+        let missing = builder.add_systemd_generator("missing-generator");
+        let before_units = builder.provenance.contains_units();
+        builder.add_systemd_unit("test.target").unwrap();
+        let defaults = builder.add_default_generators();
+
+        // resolving libraries depends on the host
+        let linked = Elf::find_library("libc.so.6")
+            .is_ok()
+            .then(|| builder.add_systemd_generator("linked-generator"));
+
+        custom.unwrap();
+        defaults.unwrap();
+        assert_eq!(missing.unwrap_err().code(), "initramfs_systemd");
+        assert!(!before_units && builder.provenance.contains_units());
+
+        let generator_dir = image(systemd::GENERATOR_DIR);
+        for name in ["custom-generator", "systemd-fstab-generator"] {
+            assert!(builder.vfs.contains_file(generator_dir.join(name).unwrap()));
+        }
+        assert!(!builder
+            .vfs
+            .contains(generator_dir.join("systemd-gpt-auto-generator").unwrap()));
+
+        if let Some(linked) = linked {
+            linked.unwrap();
+            assert!(builder
+                .vfs
+                .contains_file(generator_dir.join("linked-generator").unwrap()));
+            assert!(builder.vfs.contains(builder.vfs.resolve_parent(&loader)));
+        }
+    }
+
+    #[test]
+    fn test_masked_unit() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let local = dir.join("etc");
+        let vendor = dir.join("usr");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(&vendor).unwrap();
+
+        let target = "[Unit]\nDescription=Sample\nRequires=masked.service\n";
+        let service = "[Unit]\nDescription=Masked\n\n[Service]\nExecStart=/usr/bin/true\n";
+        fs::write(vendor.join("sample.target"), "[Unit]\nDescription=Vendor\n").unwrap();
+        fs::write(local.join("sample.target"), target).unwrap();
+        fs::write(vendor.join("masked.service"), service).unwrap();
+        symlink("/dev/null", local.join("masked.service")).unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_unit_search_paths(vec![local.clone(), vendor.clone()]);
+        let result = builder.add_systemd_unit("sample.target");
+        result.unwrap();
+
+        let units: Vec<_> = builder
+            .vfs
+            .iter()
+            .filter(|(path, _)| path.starts_with(dir))
+            .filter(|(_, entry)| entry.is_file())
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        assert_eq!(units, [local.join("sample.target")]);
+    }
+
+    #[test]
+    fn test_package() {
+        use crate::distro::tests::FakeRunner;
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::create_dir_all(dir.join("share/doc")).unwrap();
+        fs::write(dir.join("bin/hook"), b"#!/bin/sh\n").unwrap();
+        symlink("hook", dir.join("bin/hook-link")).unwrap();
+
+        let listed = ["", "/bin", "/bin/hook", "/bin/hook-link", "/share/doc"]
+            .map(|path| format!("hooks {}{path}\n", dir.display()))
+            .concat();
+
+        let mut runner = FakeRunner::default();
+        runner.answer("pacman -Ql hooks", 0, &listed, "");
+        runner.answer(
+            "pacman -Ql missing",
+            1,
+            "",
+            "error: package 'missing' was not found\n",
+        );
+
+        let mut query = PackageQuery::with_runner(runner);
+        query.set_prefixes(vec![dir.to_path_buf()]);
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_package_query(query);
+        let result = builder.add_package("hooks");
+        let missing = builder.add_package("missing").err().unwrap();
+        result.unwrap();
+
+        let entries: BTreeMap<_, _> = builder.vfs.iter().collect();
+        assert_eq!(
+            entries[&dir.join("bin/hook")].data.as_deref(),
+            Some(&b"#!/bin/sh\n"[..])
+        );
+        assert_eq!(entries[&dir.join("bin/hook-link")], &Entry::symlink("hook"));
+        assert!(!entries.contains_key(&dir.join("share/doc")));
+        assert_eq!(missing.code(), "initramfs_package_not_installed");
+    }
+
+    #[test]
+    fn test_unit_required_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let units = dir.join("units");
+        fs::create_dir_all(&units).unwrap();
+
+        let environment = dir.join("sample.env");
+        let credential = dir.join("sample.key");
+        let script = dir.join("prepare.sh");
+        fs::write(&environment, "SAMPLE=1\n").unwrap();
+        fs::write(&credential, "secret").unwrap();
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+
+        let service = format!(
+            "[Service]\n\
+             ExecStartPre={}\n\
+             ExecStart=/usr/bin/true\n\
+             EnvironmentFile={}\n\
+             EnvironmentFile=-{}\n\
+             LoadCredential=key:{}\n",
+            script.display(),
+            environment.display(),
+            dir.join("missing.env").display(),
+            credential.display(),
+        );
+        fs::write(units.join("sample.service"), service).unwrap();
+        fs::write(
+            units.join("broken.service"),
+            format!(
+                "[Service]\nExecStart=/usr/bin/true\nEnvironmentFile={}\n",
+                dir.join("missing.env").display()
+            ),
+        )
+        .unwrap();
+
+        // the executable is already included, its libraries are not looked up
+        let executable = ImagePath::new("/usr/bin/true").unwrap();
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_unit_search_paths(vec![units.clone()]);
+        builder
+            .vfs
+            .create_dir_all(&executable.parent().unwrap())
+            .unwrap();
+        builder
+            .vfs
+            .create_entry(&executable, Entry::file(Vec::new()))
+            .unwrap();
+
+        let result = builder.add_systemd_unit("sample.service");
+        let broken = builder.add_systemd_unit("broken.service");
+        result.unwrap();
+        assert!(broken.is_err());
+
+        for path in [&environment, &credential, &script] {
+            assert!(builder.vfs.contains_file(path), "{}", path.display());
+        }
+        assert!(!builder.vfs.contains(dir.join("missing.env")));
+    }
+
+    #[test]
+    fn test_config_units() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("sysinit.target.wants")).unwrap();
+
+        for name in [
+            "a-one.service",
+            "a-two.socket",
+            "b.service",
+            "sysinit.target",
+        ] {
+            fs::write(dir.join(name), "[Unit]\nDescription=Sample\n").unwrap();
+        }
+        symlink("../b.service", dir.join("sysinit.target.wants/b.service")).unwrap();
+
+        let unit = |document: &str| -> config::Unit { serde_yaml::from_str(document).unwrap() };
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_unit_search_paths(vec![dir.to_path_buf()]);
+
+        let pattern = builder.add_config_unit(&unit("a-*.{service,socket}"));
+        let wants = builder.add_config_unit(&unit("{ wants_of: sysinit.target }"));
+        let optional = builder.add_config_unit(&unit("{ name: c.service, optional: true }"));
+        let optional_pattern = builder.add_config_unit(&unit("{ name: c*, optional: true }"));
+        let missing = builder.add_config_unit(&unit("c*"));
+
+        pattern.unwrap();
+        wants.unwrap();
+        optional.unwrap();
+        optional_pattern.unwrap();
+        assert!(matches!(
+            missing,
+            Err(InitramfsError::System(UnitError::NoMatch(name))) if name == "c*"
+        ));
+
+        for name in ["a-one.service", "a-two.socket", "b.service"] {
+            assert!(builder.vfs.contains(dir.join(name)), "{name}");
+        }
+        assert!(!builder.vfs.contains(dir.join("sysinit.target")));
+    }
+
+    #[test]
+    fn test_shutdown_mode() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("shutdown"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("sync-disks"), "#!/bin/sh\n").unwrap();
+
+        let plain: config::Module = serde_yaml::from_str("name: plain\n").unwrap();
+        let systemd: config::Module =
+            serde_yaml::from_str("name: systemd\nunits: [systemd-journald.service]\n").unwrap();
+
+        let build = |shutdown: &str, mode: Option<&str>, module: &config::Module| {
+            let mut document =
+                format!("shutdown: {shutdown}\nmodules: []\nsettings:\n  bare: true\n");
+            if let Some(mode) = mode {
+                document.push_str(&format!("  shutdown_mode: {mode}\n"));
+            }
+
+            let config: config::Initramfs = serde_yaml::from_str(&document).unwrap();
+            let mut builder = Initramfs::new().unwrap();
+            builder
+                .add_config_shutdown(&config, slice::from_ref(module))
+                .map(|()| {
+                    let paths = builder.vfs.iter().map(|(path, _)| path.clone());
+                    paths
+                        .filter(|path| path.ends_with("shutdown") || path.ends_with("sync-disks"))
+                        .filter(|path| !path.ends_with("system-shutdown"))
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        let script = dir.join("shutdown").display().to_string();
+        let hooks = format!("[{script}, {}]", dir.join("sync-disks").display());
+        let legacy = build(&script, None, &plain);
+        let detected = build(&hooks, None, &systemd);
+        let forced = build(&script, Some("legacy"), &systemd);
+        let exitrd = build(&script, Some("exitrd"), &systemd);
+        let list = build(&hooks, Some("legacy"), &plain);
+
+        assert_eq!(legacy.unwrap(), [PathBuf::from("/shutdown")]);
+        assert_eq!(
+            detected.unwrap(),
+            [
+                PathBuf::from("/usr/lib/systemd/system-shutdown/shutdown"),
+                PathBuf::from("/usr/lib/systemd/system-shutdown/sync-disks"),
+            ]
+        );
+        assert_eq!(forced.unwrap(), [PathBuf::from("/shutdown")]);
+        assert!(exitrd.unwrap().is_empty());
+        assert!(matches!(list, Err(InitramfsError::ShutdownList(2))));
+    }
+
+    #[test]
+    fn test_emergency() {
+        // stands in for busybox, it is statically linked on glibc systems
+        let busybox = Elf::find_binary("ldconfig").unwrap();
+        let emergency = config::Emergency::Busybox {
+            path: busybox.clone(),
+        };
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.add_emergency(&emergency).unwrap();
+
+        let entries: BTreeMap<_, _> = builder.vfs.iter().collect();
+        let rescue = entries[&PathBuf::from("/usr/bin/rescue")];
+        let script = String::from_utf8_lossy(rescue.data.as_deref().unwrap());
+        assert_eq!(rescue.metadata.mode, 0o100_755);
+        assert!(script.contains("mount -t devtmpfs devtmpfs /dev"));
+        assert!(script.ends_with(&format!("exec {} sh -l\n", busybox.display())));
+        assert!(entries.contains_key(&PathBuf::from("/etc/profile")));
+
+        // sulogin and its libraries have to be found on the host
+        if Initramfs::new_bare().add_elf(Path::new("sulogin")).is_err() {
+            return;
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let emergency = "[Unit]\nDescription=Emergency\nRequires=emergency.service\n";
+        let service = "[Unit]\nDescription=Emergency Shell\n\n[Service]\nExecStart=-/bin/sh\n";
+        let rescue = "[Unit]\nDescription=Rescue\nRequires=rescue.service\n";
+        fs::write(dir.join("emergency.target"), emergency).unwrap();
+        fs::write(dir.join("emergency.service"), service).unwrap();
+        fs::write(dir.join("rescue.target"), rescue).unwrap();
+        fs::write(dir.join("rescue.service"), service).unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_unit_search_paths(vec![dir.to_path_buf()]);
+        let result = builder.add_emergency(&config::Emergency::Systemd);
+        result.unwrap();
+
+        let entries: BTreeMap<_, _> = builder.vfs.iter().collect();
+        for unit in [
+            "emergency.target",
+            "emergency.service",
+            "rescue.target",
+            "rescue.service",
+        ] {
+            assert!(entries[&dir.join(unit)].is_file(), "{unit}");
+        }
+        for alias in RESCUE_ALIASES {
+            let entry = entries[&dir.join(alias)];
+            assert_eq!(entry.data.as_deref(), Some(&b"rescue.target"[..]));
+        }
+        assert!(entries.contains_key(&PathBuf::from("/usr/bin/sulogin")));
+        assert!(entries.contains_key(&PathBuf::from("/etc/nsswitch.conf")));
+    }
+
+    #[test]
+    fn test_explain() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut kmod = sound_kmod(&dir.join("6.0.0-elusive"));
+
+        let mut builder = Initramfs::new().unwrap();
+        let node = Node::Module("sound".to_string());
+        builder
+            .provenance
+            .record(node.clone(), "module sound".to_string());
+
+        let result = builder.with_parent(node, |this| {
+            this.add_module_from_name(&mut kmod, "snd")?;
+
+            let path = image("/etc/modprobe.d/snd.conf");
+            this.add_template_file(&path, "options snd index=0", &BTreeMap::new())
+        });
+
+        drop(kmod);
+        result.unwrap();
+
+        let pattern = Pattern::new("*/soundcore*").unwrap();
+        assert_eq!(
+            builder.explain(&pattern),
+            [(
+                PathBuf::from("/usr/lib/modules/6.0.0-elusive/kernel/soundcore.ko"),
+                vec![
+                    "module sound → kernel module snd → depends soundcore".to_string(),
+                    "module sound → kernel module snd → softdep snd_seq → depends soundcore"
+                        .to_string(),
+                ]
+            )]
+        );
+
+        // literal paths are resolved through symlinks
+        let pattern = Pattern::new("/lib/modules/6.0.0-elusive/kernel/snd.ko").unwrap();
+        let explained = builder.explain(&pattern);
+        assert_eq!(explained.len(), 1);
+        assert_eq!(explained[0].1, ["module sound → kernel module snd"]);
+
+        let pattern = Pattern::new("/etc/modprobe.d*").unwrap();
+        assert_eq!(
+            builder.explain(&pattern),
+            [
+                (PathBuf::from("/etc/modprobe.d"), Vec::new()),
+                (
+                    PathBuf::from("/etc/modprobe.d/snd.conf"),
+                    vec!["module sound → template".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kernel_cmdline() {
+        let mut builder = Initramfs::new().unwrap();
+
+        builder
+            .add_kernel_cmdline("rd.driver.pre=vfio-pci quiet")
+            .unwrap();
+        builder
+            .add_kernel_cmdline("quiet  resume=/dev/sda2")
+            .unwrap();
+        builder
+            .add_kernel_cmdline("rd.driver.pre=vfio-pci")
+            .unwrap();
+
+        assert_eq!(
+            builder.kernel_cmdline(),
+            ["rd.driver.pre=vfio-pci", "quiet", "resume=/dev/sda2"]
+        );
+
+        // repeated keys are kept unless conflicts are errors
+        let repeated = builder.add_kernel_cmdline("console=tty0 console=ttyS0");
+        builder.set_cmdline_conflicts(config::Severity::Error);
+        let conflict = builder.add_kernel_cmdline("resume=/dev/sda3");
+
+        repeated.unwrap();
+        assert_eq!(
+            builder.kernel_cmdline(),
+            [
+                "rd.driver.pre=vfio-pci",
+                "quiet",
+                "resume=/dev/sda2",
+                "console=tty0",
+                "console=ttyS0"
+            ]
+        );
+        assert!(matches!(conflict, Err(InitramfsError::CmdlineConflict(..))));
+    }
+
+    #[test]
+    fn test_inline_content() {
+        let spec: config::File =
+            serde_yaml::from_str("destination: /etc/vconsole.conf\ncontent: |\n  KEYMAP=us\n")
+                .unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .add_content_file(
+                &spec.destination,
+                spec.content.unwrap(),
+                config::Mode(0o644),
+            )
+            .unwrap();
+        builder
+            .add_content_file(
+                &image("/sbin/hook"),
+                b"#!/bin/sh\n".to_vec(),
+                config::Mode(0o755),
+            )
+            .unwrap();
+
+        let entries: BTreeMap<_, _> = builder.vfs.iter().collect();
+        let vconsole = entries[&PathBuf::from("/etc/vconsole.conf")];
+        assert_eq!(vconsole.data.as_deref(), Some(&b"KEYMAP=us\n"[..]));
+        assert_eq!(vconsole.metadata.mode, 0o100_644);
+        assert_eq!(vconsole.metadata.mtime, source_date_epoch());
+
+        let hook = entries[&PathBuf::from("/usr/bin/hook")];
+        assert_eq!(hook.metadata.mode, 0o100_755);
+    }
+
+    #[test]
+    fn test_remote_file() {
+        use sha2::{Digest, Sha256};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("bundle.bin"), b"firmware").unwrap();
+
+        let remote = config::Remote {
+            url: format!("file://{}", dir.join("bundle.bin").display()),
+            sha256: crate::measurement::hex(&Sha256::digest(b"firmware")),
+        };
+        let mismatch = config::Remote {
+            sha256: crate::measurement::hex(&Sha256::digest(b"other")),
+            ..remote.clone()
+        };
+
+        let mut builder = Initramfs::new_bare();
+        builder.set_fetcher(Fetcher::new(dir.join("cache")));
+        let destination = image("/lib/firmware/bundle.bin");
+        let added = builder.add_remote_file(&remote, &destination, config::Mode(0o644));
+        let failed = builder.add_remote_file(&mismatch, &image("/etc/other"), config::Mode(0o644));
+        added.unwrap();
+
+        let err = failed.unwrap_err();
+        assert_eq!(err.code(), "initramfs_remote_checksum");
+        assert!(!builder.vfs.contains("/etc/other"));
+
+        let entry = builder.vfs.get("/lib/firmware/bundle.bin").unwrap();
+        assert_eq!(entry.data.as_deref(), Some(&b"firmware"[..]));
+        assert_eq!(entry.metadata.mode, 0o100_644);
+        assert_eq!(
+            builder
+                .provenance
+                .reasons(&Node::Path(destination.to_path_buf()))
+                .collect::<Vec<_>>(),
+            [format!("remote {}", remote.url)]
+        );
+    }
+
+    #[test]
+    fn test_secret_filter() {
+        use config::SecretFilter;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::write(dir.join("etc/shadow"), b"root:$6$hash:::::::").unwrap();
+        fs::write(dir.join("etc/passwd"), b"root:x:0:0::/root:/bin/sh").unwrap();
+
+        let sources = [HostPath::new(dir.join("etc"))];
+        let build = |global, spec| {
+            let mut builder = Initramfs::new()?;
+            builder.set_secret_filter(global);
+            builder.add_secret_pattern("**/etc/shadow")?;
+
+            match spec {
+                Some(filter) => builder.add_tree_with_filter(&sources, &image("/etc"), filter),
+                None => builder.add_tree(&sources, &image("/etc")),
+            }
+            .map(|()| builder)
+        };
+
+        let error = build(SecretFilter::Error, None);
+        let skip = build(SecretFilter::Skip, None);
+        let allow = build(SecretFilter::Allow, None);
+        let overridden = build(SecretFilter::Error, Some(SecretFilter::Skip));
+
+        assert!(matches!(error, Err(InitramfsError::SecretFile(_))));
+
+        let skip = skip.unwrap();
+        assert!(skip.vfs.contains("/etc/passwd"));
+        assert!(!skip.vfs.contains("/etc/shadow"));
+
+        let allow = allow.unwrap();
+        assert!(allow.vfs.contains("/etc/shadow"));
+
+        let overridden = overridden.unwrap();
+        assert!(overridden.vfs.contains("/etc/passwd"));
+        assert!(!overridden.vfs.contains("/etc/shadow"));
+    }
+
+    #[test]
+    fn test_ignore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let hooks = dir.join("hooks");
+        fs::create_dir_all(hooks.join("lib/__pycache__")).unwrap();
+        fs::create_dir_all(hooks.join(".git")).unwrap();
+
+        let ignored = [
+            "hook.sh~",
+            ".#hook.sh",
+            "#hook.sh#",
+            ".hook.sh.swp",
+            ".hook.sh.swo",
+            "hook.sh.bak",
+            "hook.sh.orig",
+            "hook.sh.rej",
+            ".gitignore",
+            ".gitkeep",
+            ".gitattributes",
+            ".gitmodules",
+            ".hgignore",
+            ".git/HEAD",
+            "lib/__pycache__/util.cpython-312.pyc",
+            "lib/util.pyc",
+            "lib/util.pyo",
+        ];
+        let kept = ["hook.sh", ".keep", "lib/util.py", "README.md"];
+
+        for name in ignored.iter().chain(&kept) {
+            fs::write(hooks.join(name), b"data").unwrap();
+        }
+
+        let build = |ignore| {
+            let mut builder = Initramfs::new().unwrap();
+            builder.set_ignore(ignore);
+            builder
+                .add_tree(&[HostPath::new(&hooks)], &image("/hooks"))
+                .map(|()| builder)
+        };
+
+        let defaults = build(Ignore::default());
+        let all = build(Ignore::new::<&str>(false, &[]).unwrap());
+        let excluded = build(Ignore::new(true, &["*.md", "lib/*.py"]).unwrap());
+
+        let (defaults, all, excluded) = (defaults.unwrap(), all.unwrap(), excluded.unwrap());
+        let root = Path::new("/hooks");
+
+        for name in ignored {
+            assert!(!defaults.vfs.contains(root.join(name)), "{name}");
+            assert!(all.vfs.contains(root.join(name)), "{name}");
+        }
+        assert!(!defaults.vfs.contains(root.join(".git")));
+        assert!(!defaults.vfs.contains(root.join("lib/__pycache__")));
+
+        for name in kept {
+            assert!(defaults.vfs.contains(root.join(name)), "{name}");
+        }
+
+        assert!(excluded.vfs.contains(root.join("hook.sh")));
+        assert!(!excluded.vfs.contains(root.join("hook.sh~")));
+        assert!(!excluded.vfs.contains(root.join("README.md")));
+        assert!(!excluded.vfs.contains(root.join("lib/util.py")));
+    }
+
+    #[test]
+    fn test_bare() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let payload = dir.join("payload");
+        let release = dir.join("modules/6.0.0-elusive");
+        fs::create_dir_all(payload.join("bin")).unwrap();
+        fs::create_dir_all(release.join("kernel")).unwrap();
+        fs::write(payload.join("bin/update"), b"#!/bin/sh\n").unwrap();
+        fs::write(payload.join("manifest.json"), b"{}").unwrap();
+
+        let config: config::Initramfs = serde_yaml::from_str(&format!(
+            "settings:\n  bare: true\n  kernel_module_path: {}\nmodules: [payload]\n",
+            release.display()
+        ))
+        .unwrap();
+        let module: config::Module = serde_yaml::from_str(&format!(
+            "name: payload\nfiles:\n  - sources: [{}]\n    destination: /\n",
+            payload.display()
+        ))
+        .unwrap();
+
+        let bare = Initramfs::from_config(&config, &[module]);
+
+        let archive = bare.unwrap().into_archive();
+        let paths: Vec<_> = archive.entries().iter().map(|(path, _)| path).collect();
+
+        assert_eq!(
+            paths,
+            ["/", "/bin", "/bin/update", "/manifest.json"].map(Path::new)
+        );
+    }
+
+    #[test]
+    fn test_usr_merge_without_skeleton() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let payload = dir.join("payload");
+        let release = dir.join("modules/6.0.0-elusive");
+        fs::create_dir_all(payload.join("bin")).unwrap();
+        fs::create_dir_all(release.join("kernel")).unwrap();
+        fs::write(payload.join("bin/sh"), b"#!/bin/busybox\n").unwrap();
+        fs::write(dir.join("init"), b"#!/bin/sh\n").unwrap();
+
+        // without the default symlinks, /bin is not a link to /usr/bin
+        let config: config::Initramfs = serde_yaml::from_str(&format!(
+            "init: {}\nsettings:\n  kernel_module_path: {}\n  skeleton:\n    disable_defaults: true\nmodules: [payload]\n",
+            dir.join("init").display(),
+            release.display()
+        ))
+        .unwrap();
+        let module: config::Module = serde_yaml::from_str(&format!(
+            "name: payload\nfiles:\n  - sources: [{}]\n    destination: /bin\n",
+            payload.join("bin/sh").display()
+        ))
+        .unwrap();
+
+        let initramfs = Initramfs::from_config(&config, &[module]).unwrap();
+
+        assert!(initramfs.vfs.contains("/bin/sh"));
+        assert!(!initramfs.vfs.contains("/usr/bin/sh"));
+    }
+
+    #[test]
+    fn test_lazy_kmod() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("missing/6.0.0-elusive");
+        fs::create_dir_all(dir.join("payload")).unwrap();
+        fs::write(dir.join("payload/data"), b"data").unwrap();
+        fs::write(dir.join("tool"), fixture_elf(&[], None)).unwrap();
+        fs::write(dir.join("init"), b"#!/bin/sh\n").unwrap();
+
+        let config: config::Initramfs = serde_yaml::from_str(&format!(
+            "init: {}\nsettings:\n  kernel_module_path: {}\nmodules: [tools]\n",
+            dir.join("init").display(),
+            release.display()
+        ))
+        .unwrap();
+        let module = |extra: &str| -> config::Module {
+            serde_yaml::from_str(&format!(
+                "name: tools\nbinaries: [{}]\nfiles:\n  - sources: [{}]\n    destination: /srv\n{extra}",
+                dir.join("tool").display(),
+                dir.join("payload").display()
+            ))
+            .unwrap()
+        };
+
+        // the module directory is only needed once a kernel module is added
+        let files_only = Initramfs::from_config(&config, &[module("")]);
+        let with_kmod = Initramfs::from_config(&config, &[module("kernel_modules: [loop]\n")]);
+
+        let initramfs = files_only.unwrap();
+        assert!(initramfs.vfs.contains(dir.join("tool")));
+        assert!(initramfs.vfs.contains("/srv/data"));
+
+        let Err(InitramfsError::InputOutput(err)) = with_kmod else {
+            panic!("expected a missing module directory");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), release.display().to_string());
+    }
+
+    #[test]
+    fn test_for_kernel() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let releases = [dir.join("6.0.0-elusive"), dir.join("6.1.0-elusive")];
+        fs::create_dir_all(dir.join("payload")).unwrap();
+        fs::write(dir.join("payload/data"), b"shared").unwrap();
+
+        for release in &releases {
+            for (name, modinfo) in SOUND_MODULES {
+                let path = release.join(format!("kernel/sound/{name}.ko"));
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                kmod::tests::fake_module(&path, modinfo);
+            }
+        }
+
+        let config: config::Initramfs = serde_yaml::from_str(
+            "settings:\n  bare: true\n  auto_depmod: true\nmodules: [sound]\n",
+        )
+        .unwrap();
+        let module: config::Module = serde_yaml::from_str(&format!(
+            "name: sound\nfiles:\n  - sources: [{}]\n    destination: /srv\nkernel_modules: [snd]\n",
+            dir.join("payload/data").display()
+        ))
+        .unwrap();
+        let modules = [module];
+
+        let shared = Initramfs::shared_from_config(&config, &modules).unwrap();
+        let built: Vec<_> = releases
+            .iter()
+            .map(|release| shared.for_kernel(&config, &modules, release))
+            .collect();
+
+        let split = |initramfs: Initramfs| {
+            let archive = initramfs.into_archive();
+            let (kernel, shared): (Vec<_>, Vec<_>) = archive
+                .entries()
+                .iter()
+                .map(|(path, entry)| (path.to_path_buf(), entry.data.clone()))
+                .partition(|(path, _)| path.starts_with("/usr/lib/modules"));
+
+            let kernel: Vec<_> = kernel.into_iter().map(|(path, _)| path).collect();
+            (shared, kernel)
+        };
+
+        assert!(!shared.vfs.contains("/usr/lib/modules"));
+        let mut built = built.into_iter().map(|initramfs| split(initramfs.unwrap()));
+        let (first_shared, first_kernel) = built.next().unwrap();
+        let (second_shared, second_kernel) = built.next().unwrap();
+
+        assert_eq!(first_shared, second_shared);
+        assert!(first_shared
+            .iter()
+            .any(|(path, data)| path == Path::new("/srv/data")
+                && data.as_deref() == Some(&b"shared"[..])));
+
+        assert_ne!(first_kernel, second_kernel);
+        assert!(first_kernel.contains(&PathBuf::from(
+            "/usr/lib/modules/6.0.0-elusive/kernel/sound/snd.ko"
+        )));
+        assert!(second_kernel.contains(&PathBuf::from(
+            "/usr/lib/modules/6.1.0-elusive/kernel/sound/soundcore.ko"
+        )));
+        assert!(!second_kernel
+            .iter()
+            .any(|path| path.starts_with("/usr/lib/modules/6.0.0-elusive")));
+    }
+
+    #[test]
+    fn test_fragments() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+
+        for (name, modinfo) in SOUND_MODULES {
+            let path = release.join(format!("kernel/sound/{name}.ko"));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            kmod::tests::fake_module(&path, modinfo);
+        }
+
+        let config: config::Initramfs = serde_yaml::from_str(&format!(
+            "settings:\n  bare: true\n  auto_depmod: true\n  kernel_module_path: {}\nmodules: [sound, tuning]\n",
+            release.display()
+        ))
+        .unwrap();
+        let documents = [
+            "name: sound\nsysctl:\n  kernel.printk: 3 4 1 3\nmodules_load: [snd]\n",
+            "name: tuning\nsysctl:\n  vm.swappiness: 10\n  kernel/printk: 3 4 1 3\n\
+                tmpfiles: ['d /run/elusive 0755 root root -']\n",
+            "name: conflict\nsysctl:\n  vm.swappiness: 60\n",
+        ];
+        let mut modules: Vec<config::Module> = documents
+            .iter()
+            .map(|document| serde_yaml::from_str(document).unwrap())
+            .collect();
+
+        let conflict = modules.pop().unwrap();
+        let built = Initramfs::from_config(&config, &modules);
+        modules.push(conflict);
+        let conflicting = Initramfs::from_config(&config, &modules);
+
+        let built = built.unwrap();
+        let read = |path: &str| {
+            let entry = built.vfs.get(path).unwrap();
+            String::from_utf8(entry.data.clone().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            read(fragment::SYSCTL_PATH),
+            "# Generated by elusive\nkernel.printk = 3 4 1 3\nvm.swappiness = 10\n"
+        );
+        assert_eq!(
+            read(fragment::TMPFILES_PATH),
+            "# Generated by elusive\nd /run/elusive 0755 root root -\n"
+        );
+        assert_eq!(
+            read(fragment::MODULES_LOAD_PATH),
+            "# Generated by elusive\nsnd\n"
+        );
+
+        // listed modules are added with their dependencies
+        let modules = Path::new("/usr/lib/modules/6.0.0-elusive/kernel/sound");
+        assert!(built.vfs.contains_file(modules.join("snd.ko")));
+        assert!(built.vfs.contains_file(modules.join("soundcore.ko")));
+
+        let err = conflicting.err().unwrap();
+        assert_eq!(err.code(), "initramfs_sysctl_conflict");
+        assert!(err
+            .to_string()
+            .ends_with("sysctl vm.swappiness is set to '10' by module 'tuning' and to '60' by module 'conflict'"));
+    }
+
+    #[test]
+    fn test_plan_steps() {
+        use std::cell::RefCell;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("modules/6.0.0-elusive");
+        fs::create_dir_all(dir.join("payload")).unwrap();
+        fs::create_dir_all(release.join("kernel")).unwrap();
+        fs::write(dir.join("payload/data"), b"data").unwrap();
+
+        let config: config::Initramfs = serde_yaml::from_str(&format!(
+            "settings:\n  bare: true\n  generate_ld_cache: true\n  kernel_module_path: {}\nmodules: [payload]\n",
+            release.display()
+        ))
+        .unwrap();
+        let module: config::Module = serde_yaml::from_str(&format!(
+            "name: payload\nfiles:\n  - sources: [{}]\n    destination: /srv\n",
+            dir.join("payload/data").display()
+        ))
+        .unwrap();
+        let modules = slice::from_ref(&module);
+
+        // what each registered step sees of the config steps
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let seen = |this: &Initramfs| {
+            (
+                this.vfs.contains("/srv/data"),
+                this.vfs.contains("/etc/ld.so.conf"),
+            )
+        };
+
+        let mut builder = Initramfs::for_config(&config).unwrap();
+        for name in ["first", "second"] {
+            let order = order.clone();
+            builder.add_pre_step(move |this| {
+                order.borrow_mut().push((name, seen(this)));
+                let path = ImagePath::new("/etc/pre")?.join(name)?;
+                this.add_content_file(&path, name.into(), config::Mode(0o644))
+            });
+        }
+
+        let post = order.clone();
+        builder.add_post_step(move |this| {
+            post.borrow_mut().push(("post", seen(this)));
+            this.add_content_file(&image("/etc/post"), b"post".to_vec(), config::Mode(0o644))
+        });
+
+        let plan = BuildPlan::from_config(&config, modules);
+        let result = builder.run_plan(&plan, &config, modules);
+        let again = builder.run_plan(&BuildPlan::from_steps(Vec::new()), &config, modules);
+
+        let mut without_kmod = Initramfs::for_config(&config).unwrap();
+        let checks = BuildPlan::from_steps(vec![Step::Checks]);
+        let err = without_kmod.run_plan_with(&checks, &config, modules, None);
+
+        result.unwrap();
+        again.unwrap();
+
+        // registered steps run once, around the config steps
+        assert_eq!(
+            *order.borrow(),
+            [
+                ("first", (false, false)),
+                ("second", (false, false)),
+                ("post", (true, true))
+            ]
+        );
+        assert!(builder.vfs.contains_file("/etc/pre/first"));
+        assert!(builder.vfs.contains_file("/etc/pre/second"));
+        assert!(builder.vfs.contains_file("/etc/post"));
+
+        assert_eq!(err.unwrap_err().code(), "initramfs_step_needs_kmod");
+    }
+
+    #[test]
+    fn test_build_stats() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("modules/6.0.0-elusive");
+        fs::create_dir_all(dir.join("payload")).unwrap();
+        fs::create_dir_all(release.join("kernel")).unwrap();
+        fs::write(dir.join("payload/data"), vec![0; 4096]).unwrap();
+        fs::write(dir.join("init"), b"#!/bin/sh\n").unwrap();
+
+        let config: config::Initramfs = serde_yaml::from_str(&format!(
+            "init: {}\nsettings:\n  kernel_module_path: {}\nmodules: [first, second]\n",
+            dir.join("init").display(),
+            release.display()
+        ))
+        .unwrap();
+        let modules: Vec<config::Module> = ["first", "second"]
+            .iter()
+            .map(|name| {
+                serde_yaml::from_str(&format!(
+                    "name: {name}\nfiles:\n  - sources: [{}]\n    destination: /{name}\n",
+                    dir.join("payload").display()
+                ))
+                .unwrap()
+            })
+            .collect();
+
+        let initramfs = Initramfs::from_config(&config, &modules);
+
+        let stats = initramfs.unwrap().stats().clone();
+        let names: Vec<_> = stats
+            .phases
+            .iter()
+            .map(|phase| phase.name.as_str())
+            .collect();
+        assert_eq!(names, ["skeleton", "init", "modules", "checks"]);
+        assert!(stats.total() > Duration::ZERO);
+        assert!(stats.phase("skeleton").unwrap().entries > 0);
+
+        let phase = stats.phase("modules").unwrap();
+        assert_eq!(phase.bytes, 2 * 4096);
+        assert_eq!(stats.modules.len(), 2);
+        assert_eq!(stats.modules[1].module, "second");
+        assert_eq!(stats.modules[1].entries, 2);
+
+        // modules are timed within the phase, which only adds the loop around them
+        let modules: Duration = stats.modules.iter().map(|module| module.time).sum();
+        assert!(modules > Duration::ZERO && modules <= phase.time);
+        assert!(phase.time - modules < Duration::from_millis(50));
+        assert!(stats
+            .modules
+            .iter()
+            .all(|module| module.files <= module.time));
+    }
+
+    #[test]
+    fn test_entrypoints() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for name in ["init", "other-init", "shutdown", "other-shutdown"] {
+            fs::write(dir.join(name), format!("#!/bin/sh\n# {name}\n")).unwrap();
+        }
+
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .with_parent(Node::Module("base".to_string()), |this| {
+                this.add_init(&dir.join("init"))
+            })
+            .unwrap();
+        builder.add_shutdown(&dir.join("shutdown")).unwrap();
+
+        // the same entrypoint again is fine, whichever module adds it
+        let same_init = builder.with_parent(Node::Module("extra".to_string()), |this| {
+            this.add_init(&dir.join("init"))
+        });
+        let same_shutdown = builder.with_parent(Node::Module("debug".to_string()), |this| {
+            this.add_shutdown(&dir.join("shutdown"))
+        });
+
+        let init = builder.add_init(&dir.join("other-init")).unwrap_err();
+        let shutdown = builder
+            .with_parent(Node::Module("debug".to_string()), |this| {
+                this.add_shutdown(&dir.join("other-shutdown"))
+            })
+            .unwrap_err();
+
+        // a failed entrypoint does not count as provided
+        let mut missing = Initramfs::new().unwrap();
+        let first = missing.add_init(&dir.join("missing"));
+        let second = missing.add_init(&dir.join("init"));
+
+        same_init.unwrap();
+        same_shutdown.unwrap();
+        assert_eq!(init.code(), "initramfs_entrypoint_conflict");
+        assert_eq!(
+            init.to_string(),
+            format!(
+                "/init is already provided by init {} (module base), \
+                 cannot replace it with init {}",
+                dir.join("init").display(),
+                dir.join("other-init").display()
+            )
+        );
+        assert_eq!(
+            shutdown.to_string(),
+            format!(
+                "/shutdown is already provided by shutdown {}, \
+                 cannot replace it with shutdown {} (module debug)",
+                dir.join("shutdown").display(),
+                dir.join("other-shutdown").display()
+            )
+        );
+
+        let data = builder
+            .vfs
+            .get(image("/init"))
+            .and_then(|entry| entry.data.clone());
+        assert_eq!(data.as_deref(), Some(b"#!/bin/sh\n# init\n".as_slice()));
+
+        assert!(first.is_err());
+        second.unwrap();
+    }
+
+    #[test]
+    fn test_missing_source() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("present"), b"data").unwrap();
+
+        // a file removed while the tree is walked
+        let vanished = dir.join("vanished");
+        symlink(dir.join("removed"), &vanished).unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        let init = builder.add_init(&dir.join("init"));
+        let files = builder.with_parent(Node::Module("base".to_string()), |this| {
+            this.add_tree(&[HostPath::new(dir)], &image("/etc/base"))
+        });
+
+        let message = init.unwrap_err().to_string();
+        let expected = format!("failed to read init {}: ", dir.join("init").display());
+        assert!(message.starts_with(&expected), "{message}");
+        assert!(message.contains("No such file or directory"), "{message}");
+
+        let Err(InitramfsError::Source {
+            kind, path, module, ..
+        }) = &files
+        else {
+            panic!("unexpected result: {files:?}");
+        };
+        assert_eq!(
+            (*kind, path, module.as_deref()),
+            ("file", &vanished, Some("base"))
+        );
+
+        let message = files.unwrap_err().to_string();
+        assert!(message.contains(" for module 'base': "), "{message}");
+    }
+
+    #[test]
+    fn test_divergent_duplicates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for module in ["base", "extra", "other"] {
+            fs::create_dir_all(dir.join(module)).unwrap();
+        }
+
+        let nsswitch = "passwd: files\ngroup: files\n";
+        fs::write(dir.join("base/nsswitch.conf"), nsswitch).unwrap();
+        fs::write(dir.join("base/blob.bin"), b"\x00\x01\x02").unwrap();
+        fs::write(dir.join("extra/nsswitch.conf"), nsswitch).unwrap();
+        fs::write(
+            dir.join("other/nsswitch.conf"),
+            "passwd: files systemd\ngroup: files\n",
+        )
+        .unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        let module = |name: &str| Node::Module(name.to_string());
+
+        let base = builder.with_parent(module("base"), |this| {
+            this.add_tree(&[HostPath::new(dir.join("base"))], &image("/etc"))
+        });
+        let identical = builder.with_parent(module("extra"), |this| {
+            this.add_tree(&[HostPath::new(dir.join("extra"))], &image("/etc"))
+        });
+        let text = builder.with_parent(module("other"), |this| {
+            let source = HostPath::new(dir.join("other/nsswitch.conf"));
+            let filter = config::SecretFilter::default();
+            this.add_renamed_file(&source, &image("/etc/nsswitch.conf"), filter, None)
+        });
+        let binary = builder.with_parent(module("other"), |this| {
+            let data = b"\x00\x01\x02\x03".to_vec();
+            this.add_content_file(&image("/etc/blob.bin"), data, config::Mode(0o644))
+        });
+
+        base.unwrap();
+        identical.unwrap();
+
+        // identical copies are kept once, owned by the first module
+        let entry = builder.vfs.get("/etc/nsswitch.conf").unwrap();
+        assert_eq!(entry.data.as_deref(), Some(nsswitch.as_bytes()));
+        assert_eq!(
+            builder.module_owners()[Path::new("/etc/nsswitch.conf")],
+            "base"
+        );
+
+        let err = text.unwrap_err();
+        assert_eq!(err.code(), "initramfs_divergent_duplicate");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "conflicting copies of /etc/nsswitch.conf: module base added file {}, \
+                 module other adds file {}\n\
+                 @@ -1,1 +1,1 @@\n-passwd: files\n+passwd: files systemd",
+                dir.join("base/nsswitch.conf").display(),
+                dir.join("other/nsswitch.conf").display()
+            )
+        );
+
+        let message = binary.unwrap_err().to_string();
+        assert!(
+            message.starts_with("conflicting copies of /etc/blob.bin: module base added file "),
+            "{message}"
+        );
+        assert!(
+            message.ends_with(", module other adds inline content\nbinary content differs: 3 bytes (sha256 ae4b3280e56e) vs 4 bytes (sha256 054edec1d021)"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_directory_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let private = dir.join("etc/ssl/private");
+        fs::create_dir_all(&private).unwrap();
+        fs::write(private.join("key.pem"), "key").unwrap();
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o700)).unwrap();
+
+        // the file deep in the tree is added first, creating its parents
+        let mut builder = Initramfs::new_bare();
+        let key = [HostPath::new(private.join("key.pem"))];
+        builder.add_tree(&key, &image("/etc/ssl/private")).unwrap();
+        assert!(builder.vfs.is_placeholder("/etc/ssl/private"));
+
+        let result = builder.add_tree(&[HostPath::new(dir.join("etc"))], &image("/etc"));
+        result.unwrap();
+
+        let data = builder.into_archive().serialize().unwrap();
+        let archive = Archive::deserialize(&data).unwrap();
+        let entries: BTreeMap<_, _> = archive.entries().iter().cloned().collect();
+
+        assert_eq!(
+            entries[Path::new("/etc/ssl/private")].metadata.mode,
+            0o040_700
+        );
+        assert_eq!(entries[Path::new("/etc/ssl")].metadata.mode, 0o040_755);
+        assert!(entries[Path::new("/etc/ssl/private/key.pem")].is_file());
+    }
+
+    #[test]
+    fn test_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let name = OsStr::from_bytes(b"caf\xe9.bin");
+        fs::create_dir_all(dir.join("firmware")).unwrap();
+        fs::write(dir.join("firmware").join(name), b"firmware").unwrap();
+
+        let mut builder = Initramfs::new_bare();
+        let firmware = [HostPath::new(dir.join("firmware"))];
+        let added = builder.add_tree(&firmware, &image("/usr/lib/firmware"));
+        let missing = [HostPath::new(dir.join(OsStr::from_bytes(b"\xff")))];
+        let missing = builder.add_tree(&missing, &image("/etc"));
+        added.unwrap();
+
+        let message = missing.unwrap_err().to_string();
+        assert!(message.contains("/\\xff: "), "{message}");
+
+        let data = builder.into_archive().serialize().unwrap();
+        let archive = Archive::deserialize(&data).unwrap();
+        let entries: BTreeMap<_, _> = archive.entries().iter().cloned().collect();
+
+        let path = Path::new("/usr/lib/firmware").join(name);
+        assert_eq!(entries[&path].data.as_deref(), Some(&b"firmware"[..]));
+    }
+
+    #[test]
+    fn test_ld_so_conf() {
+        let build = |ldconfig: bool| {
+            let mut builder = Initramfs::new().unwrap();
+            let library = fixture_elf(&[], None);
+
+            for path in [
+                "/usr/lib/libc.so.6",
+                "/usr/lib/xtables/libxt_tcp.so",
+                "/usr/lib/systemd/libsystemd-shared-255.so",
+                "/usr/libexec/helper",
+                "/opt/vendor/lib/libvendor.so.1.2",
+            ] {
+                builder
+                    .vfs
+                    .create_dir_all(&image(path).parent().unwrap())
+                    .unwrap();
+                builder
+                    .vfs
+                    .create_entry(&image(path), Entry::file(library.clone()))
+                    .unwrap();
+            }
+
+            // not libraries, either by content or by name
+            builder.vfs.create_dir_all(&image("/opt/data")).unwrap();
+            builder
+                .vfs
+                .create_entry(
+                    &image("/opt/data/libnotes.so"),
+                    Entry::file(b"text".to_vec()),
+                )
+                .unwrap();
+            builder
+                .vfs
+                .create_entry(&image("/opt/data/libsocket.sock"), Entry::file(library))
+                .unwrap();
+
+            builder.add_ld_so_conf(ldconfig).unwrap();
+            builder
+        };
+
+        let builder = build(false);
+        let conf = builder.vfs.get("/etc/ld.so.conf").unwrap();
+        assert_eq!(
+            conf.data.as_deref().unwrap(),
+            b"# Generated by elusive\n\
+              /opt/vendor/lib\n\
+              /usr/lib/systemd\n\
+              /usr/lib/xtables\n"
+        );
+        assert!(!builder.vfs.contains("/etc/ld.so.cache"));
+
+        let builder = build(true);
+        let cache = builder.vfs.get("/etc/ld.so.cache").unwrap();
+        assert!(cache
+            .data
+            .as_deref()
+            .unwrap()
+            .starts_with(b"glibc-ld.so.cache"));
+    }
+
+    #[test]
+    fn test_renamed_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("files")).unwrap();
+        fs::write(dir.join("files/init-single.sh"), b"#!/bin/sh\n").unwrap();
+        fs::write(dir.join("files/hosts.initrd"), b"127.0.0.1 localhost\n").unwrap();
+
+        let module: config::Module = serde_yaml::from_str(&format!(
+            "name: rename\nfiles:\n  - sources: [{0}/files/init-single.sh]\n    destination_file: /etc/rc.d/rc.local\n  - sources: [{{ path: {0}/files/hosts.initrd, rename: hosts }}]\n    destination: /etc\n",
+            dir.display()
+        ))
+        .unwrap();
+        let directory: config::Module = serde_yaml::from_str(&format!(
+            "name: directory\nfiles:\n  - sources: [{}/files]\n    destination_file: /etc/files\n",
+            dir.display()
+        ))
+        .unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        let settings = config::Settings::default();
+        let renamed = builder.add_config_module(&settings, &module, None);
+        let directory = builder.add_config_module(&settings, &directory, None);
+
+        renamed.unwrap();
+        let rc_local = builder.vfs.get("/etc/rc.d/rc.local").unwrap();
+        assert_eq!(rc_local.data.as_deref(), Some(&b"#!/bin/sh\n"[..]));
+        assert!(builder.vfs.contains_file("/etc/hosts"));
+        assert!(!builder.vfs.contains("/etc/rc.d/init-single.sh"));
+        assert!(!builder.vfs.contains("/etc/hosts.initrd"));
+
+        let err = directory.unwrap_err();
+        assert_eq!(err.code(), "initramfs_rename_not_file");
+        assert!(err.to_string().contains("is not a regular file"));
+    }
+
+    #[test]
+    fn test_environment() {
+        let config: config::Initramfs = serde_yaml::from_str(
+            "settings:\n  bare: true\n  systemd_environment: true\n\
+             modules: [root, console]\nenvironment:\n  DEBUG: false\n",
+        )
+        .unwrap();
+        let documents = [
+            "name: root\nenvironment:\n  ROOT: UUID=1234\n  CONSOLE: tty0\n",
+            "name: console\nenvironment:\n  CONSOLE: tty0\n  FONT: \"Lat2 Terminus16\"\n",
+            "name: conflict\nenvironment:\n  ROOT: /dev/sda2\n",
+        ];
+        let mut modules: Vec<config::Module> = documents
+            .iter()
+            .map(|document| serde_yaml::from_str(document).unwrap())
+            .collect();
+
+        let plan = BuildPlan::from_steps(vec![
+            Step::Modules {
+                names: vec!["root".into(), "console".into(), "conflict".into()],
+                kernel_modules: false,
+            },
+            Step::Environment,
+        ]);
+        let build = |modules: &[config::Module]| {
+            let mut initramfs = Initramfs::for_config(&config)?;
+            initramfs.run_plan(&plan, &config, modules)?;
+            Ok::<_, InitramfsError>(initramfs)
+        };
+
+        let conflict = modules.pop().unwrap();
+        let built = build(&modules).unwrap();
+        modules.push(conflict);
+        let err = build(&modules).err().unwrap();
+
+        let read = |path: &str| {
+            let entry = built.vfs.get(path).unwrap();
+            String::from_utf8(entry.data.clone().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            read(fragment::ENVIRONMENT_PATH),
+            "# Generated by elusive\nCONSOLE=tty0\nDEBUG=false\nFONT='Lat2 Terminus16'\nROOT=UUID=1234\n"
+        );
+        assert_eq!(
+            read(fragment::SYSTEMD_ENVIRONMENT_PATH),
+            "# Generated by elusive\n[Manager]\nDefaultEnvironment=\"CONSOLE=tty0\"\n\
+             DefaultEnvironment=\"DEBUG=false\"\nDefaultEnvironment=\"FONT=Lat2 Terminus16\"\n\
+             DefaultEnvironment=\"ROOT=UUID=1234\"\n"
+        );
+
+        assert_eq!(err.code(), "initramfs_fragment");
+        assert!(err.to_string().ends_with(
+            "environment variable ROOT is set to 'UUID=1234' by module 'root' and to '/dev/sda2' by module 'conflict'"
+        ));
+    }
+
+    #[test]
+    fn test_best_effort() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("hosts"), b"127.0.0.1 localhost\n").unwrap();
+
+        // the tool is added before its denied library fails it
+        fs::write(dir.join("tool"), fixture_elf(&["libdenied.so.1"], None)).unwrap();
+        fs::write(dir.join("libdenied.so.1"), fixture_elf(&[], None)).unwrap();
+
+        let config: config::Initramfs = serde_yaml::from_str(
+            "settings:\n  bare: true\n  on_error: best-effort\n  \
+             library_denylist: [libdenied.so*]\n  strict_library_policy: true\nmodules: []\n",
+        )
+        .unwrap();
+        let documents = [
+            format!(
+                "name: optional\nbinaries: [{0}/missing-binary, {0}/tool]\n\
+                 files:\n  - sources: [{0}/missing-file]\n    destination: /etc\n",
+                dir.display()
+            ),
+            format!(
+                "name: core\ncritical: true\nfiles:\n  - sources: [{}/hosts]\n    destination: /etc\n",
+                dir.display()
+            ),
+            format!(
+                "name: broken\ncritical: true\nbinaries: [{}/missing-binary]\n",
+                dir.display()
+            ),
+        ];
+        let modules: Vec<config::Module> = documents
+            .iter()
+            .map(|document| serde_yaml::from_str(document).unwrap())
+            .collect();
+
+        let build = |names: &[&str]| {
+            let plan = BuildPlan::from_steps(vec![Step::Modules {
+                names: names.iter().map(|name| name.to_string()).collect(),
+                kernel_modules: false,
+            }]);
+
+            let mut initramfs = Initramfs::for_config(&config)?;
+            initramfs
+                .elf_cache
+                .insert_library("libdenied.so.1", dir.join("libdenied.so.1"));
+            initramfs.run_plan(&plan, &config, &modules)?;
+            Ok::<_, InitramfsError>(initramfs)
+        };
+
+        let degraded = build(&["optional", "core"]);
+        let failed = build(&["optional", "core", "broken"]);
+
+        let degraded = degraded.unwrap();
+        assert!(degraded.vfs.contains_file("/etc/hosts"));
+        assert!(!degraded.vfs.contains(dir.join("tool")));
+        let tool = Node::Path(dir.join("tool"));
+        assert_eq!(degraded.provenance.reasons(&tool).count(), 0);
+
+        let failures = degraded.failures();
+        assert_eq!(failures.len(), 3);
+        assert!(failures.iter().all(|failure| failure.module == "optional"));
+        assert!(failures[0].item.starts_with("binary "));
+        assert!(failures[0].item.ends_with("missing-binary"));
+        assert!(failures[1].item.ends_with("/tool"));
+        assert_eq!(failures[1].code, "initramfs_library_denied");
+        assert!(failures[2].item.starts_with("files for /etc"));
+        assert_eq!(failures[2].code, "initramfs_source");
+
+        // critical modules still fail the generation
+        let err = failed.err().unwrap();
+        assert_eq!(err.code(), failures[0].code);
+    }
+
+    #[test]
+    fn test_negative_mtime() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let path = dir.join("old");
+        fs::write(&path, b"old\n").unwrap();
+
+        let cstring = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let time = libc::timespec {
+            tv_sec: -86_400,
+            tv_nsec: 0,
+        };
+        let times = [time, time];
+        let ret = unsafe { libc::utimensat(libc::AT_FDCWD, cstring.as_ptr(), times.as_ptr(), 0) };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+
+        let mut builder = Initramfs::new().unwrap();
+        let result = builder.add_tree(&[HostPath::new(&path)], &image("/etc"));
+
+        result.unwrap();
+        assert_eq!(builder.vfs.get("/etc/old").unwrap().metadata.mtime, 0);
+
+        let data = builder.into_archive().serialize().unwrap();
+        let archive = Archive::deserialize(&data).unwrap();
+        let (_, old) = archive
+            .entries()
+            .iter()
+            .find(|(path, _)| path == Path::new("/etc/old"))
+            .unwrap();
+        assert_eq!(old.metadata.mtime, 0);
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .vfs
+            .create_dir_all(&image("/usr/lib/modules/6.1.0"))
+            .unwrap();
+        builder.set_prune_empty_dirs(true);
+
+        let data = builder.into_archive().serialize().unwrap();
+        let archive = Archive::deserialize(&data).unwrap();
+        let paths: Vec<_> = archive.entries().iter().map(|(path, _)| path).collect();
+
+        for dir in ROOT_DIRS {
+            assert!(paths.contains(&&PathBuf::from(dir)), "missing {dir}");
+        }
+        assert!(!paths.contains(&&PathBuf::from("/usr/lib/modules")));
+    }
+
+    #[test]
+    fn test_usr_merge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("fw.bin"), b"firmware").unwrap();
+        fs::write(dir.join("hook"), b"#!/bin/sh\n").unwrap();
+
+        // destinations as found on a split-/usr host, keeping them as is
+        // requires a skeleton without the default symlinks
+        let build = |usr_merge: bool| {
+            let skeleton = config::Skeleton {
+                disable_defaults: !usr_merge,
+                ..Default::default()
+            };
+
+            let mut builder = Initramfs::with_skeleton(&skeleton)?;
+            builder.set_usr_merge(usr_merge);
+            builder.add_tree(
+                &[HostPath::new(dir.join("fw.bin"))],
+                &image("/lib/firmware"),
+            )?;
+            builder.add_tree(&[HostPath::new(dir.join("hook"))], &image("/sbin"))?;
+            builder.add_link(&image("/bin/sh"), Path::new("busybox"))?;
+
+            Ok::<_, InitramfsError>(builder)
+        };
+
+        let merged = build(true);
+        let split = build(false);
+
+        let merged = merged.unwrap();
+        for path in ["/usr/lib/firmware/fw.bin", "/usr/bin/hook", "/usr/bin/sh"] {
+            assert!(merged.vfs.contains(path), "missing {path}");
+        }
+
+        for (path, entry) in merged.vfs.iter() {
+            for (split, _) in USR_MERGE {
+                if path == Path::new(split) {
+                    assert!(entry.is_symlink(), "{split} is not a symlink");
+                } else {
+                    assert!(!path.starts_with(split), "{} not merged", path.display());
+                }
+            }
+        }
+
+        let split = split.unwrap();
+        assert!(split.vfs.contains("/lib/firmware/fw.bin"));
+        assert!(!split.vfs.contains("/usr/lib/firmware/fw.bin"));
+    }
+
+    #[test]
+    fn test_install() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("etc/ssh")).unwrap();
+        fs::write(dir.join("etc/passwd"), b"root:x:0:0::/root:/bin/sh").unwrap();
+        fs::write(dir.join("etc/shadow"), b"root:*:::::::").unwrap();
+        fs::write(dir.join("etc/ssh/ssh_config"), b"").unwrap();
+
+        // modes from a permissive umask in a git checkout
+        for (path, mode) in [
+            ("etc/passwd", 0o664),
+            ("etc/shadow", 0o664),
+            ("etc/ssh", 0o775),
+            ("etc/ssh/ssh_config", 0o664),
+        ] {
+            fs::set_permissions(dir.join(path), fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let spec: config::File = serde_yaml::from_str(&format!(
+            "sources: [{}]\ndestination: /etc\ninstall:\n  file_mode: 0644\n  exceptions:\n    shadow: \"0600\"\n",
+            dir.join("etc").display()
+        ))
+        .unwrap();
+        let install = spec.install.as_ref().unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        let result = builder.add_installed_tree(
+            &spec.sources,
+            &spec.destination,
+            config::SecretFilter::Allow,
+            install,
+        );
+        result.unwrap();
+
+        let modes: Vec<_> = builder
+            .vfs
+            .iter()
+            .filter(|(path, _)| path.starts_with("/etc") && *path != Path::new("/etc"))
+            .map(|(path, entry)| {
+                assert_eq!((entry.metadata.uid, entry.metadata.gid), (0, 0));
+                (path.to_str().unwrap(), entry.metadata.mode)
+            })
+            .collect();
+
+        assert_eq!(
+            modes,
+            [
+                ("/etc/passwd", 0o100_644),
+                ("/etc/shadow", 0o100_600),
+                ("/etc/ssh", 0o040_755),
+                ("/etc/ssh/ssh_config", 0o100_644),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_canonical_owners() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("hook.sh"), b"#!/bin/sh\n").unwrap();
+
+        let install: config::Install =
+            serde_yaml::from_str("owner: 1000\ngroup: 100\nfile_mode: \"0750\"\n").unwrap();
+
+        let mut builder = Initramfs::new().unwrap();
+        builder.set_canonical(true);
+        let result = builder.add_installed_tree(
+            &[HostPath::new(dir.join("hook.sh"))],
+            &image("/etc/hooks"),
+            config::SecretFilter::Allow,
+            &install,
+        );
+        result.unwrap();
+
+        let hook =
+            &builder.vfs.iter().collect::<BTreeMap<_, _>>()[&PathBuf::from("/etc/hooks/hook.sh")];
+        assert_eq!((hook.metadata.uid, hook.metadata.gid), (1000, 100));
+
+        let data = builder.into_archive().serialize().unwrap();
+        let archive = Archive::deserialize(&data).unwrap();
+        for (path, entry) in archive.entries() {
+            let metadata = &entry.metadata;
+            assert_eq!(
+                (metadata.uid, metadata.gid, metadata.mtime),
+                (0, 0, 0),
+                "{path:?}"
+            );
+        }
+
+        let (_, hook) = archive
+            .entries()
+            .iter()
+            .find(|(path, _)| path == Path::new("/etc/hooks/hook.sh"))
+            .unwrap();
+        assert_eq!(hook.metadata.mode, 0o100_750);
+    }
+
+    #[test]
+    fn test_skeleton() {
+        let skeleton: config::Skeleton = serde_yaml::from_str(
+            "extra_dirs: [/sysroot]\nextra_symlinks:\n  - path: /etc/mtab\n    target: ../proc/self/mounts\n",
+        )
+        .unwrap();
+
+        let builder = Initramfs::with_skeleton(&skeleton).unwrap();
+        assert!(builder.vfs.contains_dir("/sysroot"));
+        assert!(builder.vfs.contains_dir("/dev"));
+        assert!(builder
+            .vfs
+            .iter()
+            .any(|(path, entry)| path == Path::new("/etc/mtab") && entry.is_symlink()));
+
+        let disabled = config::Skeleton {
+            disable_defaults: true,
+            ..skeleton
+        };
+        let builder = Initramfs::with_skeleton(&disabled).unwrap();
+        assert!(builder.vfs.contains_dir("/sysroot"));
+        assert!(!builder.vfs.contains("/dev"));
+        assert!(!builder.vfs.contains("/bin"));
+
+        let mut builder = Initramfs::with_skeleton(&disabled).unwrap();
+        let Err(err) = builder.add_link(&image("/etc/mtab"), Path::new("/proc/mounts")) else {
+            panic!("configured symlink replaced");
+        };
+        assert_eq!(
+            err.to_string(),
+            "/etc/mtab is a skeleton symlink to ../proc/self/mounts; remove it from skeleton.extra_symlinks or remove the conflicting entry"
+        );
+
+        let mut builder = Initramfs::new().unwrap();
+        assert!(builder.vfs.dangling_symlinks().is_empty());
+
+        let Err(err) = builder.add_template_file(&image("/var/run/state"), "", &BTreeMap::new())
+        else {
+            panic!("entry created through a default symlink");
+        };
+        assert_eq!(
+            err.to_string(),
+            "/var/run is a default symlink to ../run; set skeleton.disable_defaults or remove the conflicting entry"
+        );
+    }
+
+    #[test]
+    fn test_crypttab() {
+        let check = |crypttab: &str, files: &[&str]| {
+            let mut builder = Initramfs::new().unwrap();
+            builder
+                .add_template_file(&image(CRYPTTAB_PATH), crypttab, &BTreeMap::new())
+                .unwrap();
+
+            for file in files {
+                builder
+                    .add_template_file(&image(file), "key", &BTreeMap::new())
+                    .unwrap();
+            }
+
+            let volumes = builder.crypttab_volumes().unwrap();
+            let problems = builder.check_crypttab_volumes(&volumes).unwrap();
+            (builder, problems)
+        };
+
+        // plain password
+        let (_, problems) = check("# passphrase\nroot UUID=1234 none luks\n", &[]);
+        assert!(problems.is_empty());
+
+        // key file, missing then included
+        let keyfile = "data /dev/sdb1 /etc/keys/data.key luks\nusb /dev/sdc1 /key:LABEL=usb\n";
+        let (_, problems) = check(keyfile, &[]);
+        assert_eq!(
+            problems,
+            ["key file /etc/keys/data.key for volume data is not in the initramfs"]
+        );
+
+        // problems fail the generation at the error severity only
+        let report = |severity| report(severity, &problems, InitramfsError::Crypttab);
+        assert!(report(config::Severity::Warn).is_ok());
+        assert!(report(config::Severity::Ignore).is_ok());
+        assert_eq!(
+            report(config::Severity::Error).unwrap_err().code(),
+            "initramfs_crypttab"
+        );
+        let (_, problems) = check(keyfile, &["/etc/keys/data.key"]);
+        assert!(problems.is_empty());
+
+        // tpm2 libraries are either pulled from the host or reported
+        let (builder, problems) = check("root UUID=1234 - tpm2-device=auto\n", &[]);
+        for library in TPM2_LIBRARIES {
+            let reported = problems.iter().any(|problem| problem.contains(library));
+            let included = builder
+                .vfs
+                .iter()
+                .any(|(path, _)| path.file_name() == Some(OsStr::new(library)));
+
+            assert!(reported != included, "{library}");
+        }
+
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .add_template_file(&image(CRYPTTAB_PATH), "root\n", &BTreeMap::new())
+            .unwrap();
+        assert!(builder.crypttab_volumes().is_err());
+    }
+
+    #[test]
+    fn test_init_wrapper() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("tini"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("app"), "#!/bin/sh\n").unwrap();
+
+        // the host shell is not read when one is already included
+        let mut builder = Initramfs::new().unwrap();
+        builder
+            .add_content_file(&image("/usr/bin/sh"), b"sh".to_vec(), config::Mode(0o755))
+            .unwrap();
+
+        let wrapper = config::InitWrapper {
+            helper: dir.join("tini"),
+            args: Some("--".to_string()),
+            exec: dir.join("app"),
+        };
+        let result = builder.add_init_wrapper(&wrapper);
+        result.unwrap();
+
+        let init = builder.vfs.get("/init").unwrap();
+        let expected = format!(
+            "#!/usr/bin/sh\nexec {} -- {}\n",
+            dir.join("tini").display(),
+            dir.join("app").display()
+        );
+        assert_eq!(init.data.as_deref(), Some(expected.as_bytes()));
+        assert!(builder.vfs.contains_file(dir.join("tini")));
+        assert!(builder.vfs.contains_file(dir.join("app")));
+        assert!(init::lint(&builder.vfs, None).is_empty());
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("blob"), "too large").unwrap();
+
+        let mut builder = Initramfs::new_bare();
+        builder.set_max_file_size(Some(Size(4)));
+        let sources = [HostPath::new(dir.join("blob"))];
+        let result = builder.with_parent(Node::Module("blobs".to_string()), |this| {
+            this.add_tree(&sources, &image("/blob"))
+        });
+
+        let err = result.unwrap_err();
+        assert_eq!(err.code(), "initramfs_file_too_large");
+        let message = err.to_string();
+        assert!(message.contains(&dir.join("blob").display().to_string()));
+        assert!(message.contains("module 'blobs'"), "{message}");
+        assert!(message.contains("max_file_size (4 B)"), "{message}");
+
+        let mut builder = Initramfs::new_bare();
+        builder.set_max_total_size(Some(Size(4)));
+        let err = builder
+            .add_content_file(&image("/blob"), b"too large".to_vec(), config::Mode(0o644))
+            .unwrap_err();
+        assert_eq!(err.code(), "initramfs_total_size");
     }
 }
@@ -1,9 +1,14 @@
 //! I/O utilities.
 
-use log::error;
-use std::ffi::OsStr;
-use std::path::Path;
+use log::{error, warn};
+use std::ffi::{CString, OsStr};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{env, fs, io};
+use tempfile::TempPath;
 
 /// Allow reading from either a file or standard input.
 pub enum Input {
@@ -46,13 +51,85 @@ impl io::Read for Input {
     }
 }
 
-/// Allow writing to either a file or standard output.
+/// Allow writing to either a file or standard output, or discarding data
+/// while counting written bytes.
 pub enum Output {
     Stdout(io::Stdout),
     File(fs::File),
+    Sink(u64),
+    Multi(MultiWriter),
+    Writer(Box<dyn io::Write + Send>),
 }
 
 impl Output {
+    /// Create an Output that discards data and only counts written bytes.
+    pub fn sink() -> Self {
+        Output::Sink(0)
+    }
+
+    /// Get the number of bytes written if the Output is a sink.
+    pub fn bytes_written(&self) -> Option<u64> {
+        match self {
+            Output::Sink(count) => Some(*count),
+            _ => None,
+        }
+    }
+
+    /// Create an Output duplicating writes to all provided paths, see
+    /// [`MultiWriter`].
+    pub fn multi<T>(paths: &[T]) -> Result<Self, io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        MultiWriter::new(paths).map(Output::Multi)
+    }
+
+    /// Discard what was written so far, removing the temporary files of a
+    /// [`MultiWriter`]. Other outputs are left untouched.
+    pub fn discard(&mut self) {
+        if let Output::Multi(multi) = self {
+            multi.discard();
+        }
+    }
+
+    /// Move the files written by a [`MultiWriter`] into place. Other outputs
+    /// are left untouched.
+    pub fn commit(&mut self) -> Result<(), io::Error> {
+        match self {
+            Output::Multi(multi) => multi.commit(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush the Output and wait for regular files to reach the disk, so a
+    /// crash or full filesystem is noticed before the output is used. If a
+    /// [`MultiWriter`] fails, the files it created are removed.
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        io::Write::flush(self)?;
+
+        match self {
+            Output::File(file) if file.metadata()?.is_file() => file.sync_all(),
+            Output::Multi(multi) => multi.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create an Output writing to an open file descriptor, such as a pipe or
+    /// a memfd, which is closed when the Output is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptor must be open and owned by the caller, which must
+    /// not use or close it afterwards, see [`FromRawFd::from_raw_fd`].
+    pub unsafe fn from_fd(fd: RawFd) -> Self {
+        Output::File(fs::File::from_raw_fd(fd))
+    }
+
+    /// Create an Output writing to the provided writer.
+    pub fn from_writer(writer: Box<dyn io::Write + Send>) -> Self {
+        Output::Writer(writer)
+    }
+
     /// Create an Output from a provided path. If the path is '-'.
     /// then the Output will write to standard output.
     pub fn from_path<T>(path: T) -> Result<Self, io::Error>
@@ -65,27 +142,32 @@ impl Output {
             return Ok(Output::Stdout(io::stdout()));
         }
 
-        let absolute = if path.is_absolute() {
-            path.to_path_buf()
-        } else {
-            env::current_dir()?.join(path)
-        };
+        let file = fs::File::create(output_path(path)?)?;
+        Ok(Output::File(file))
+    }
+}
 
-        if !absolute.parent().map(Path::exists).unwrap_or(false) {
-            error!(
-                "Output file parent directory does not exist: {}",
-                absolute.display()
-            );
+// absolute output path, checking that its parent directory exists
+fn output_path(path: &Path) -> Result<PathBuf, io::Error> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
 
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                absolute.to_string_lossy(),
-            ));
-        }
+    if !absolute.parent().map(Path::exists).unwrap_or(false) {
+        error!(
+            "Output file parent directory does not exist: {}",
+            absolute.display()
+        );
 
-        let file = fs::File::create(absolute)?;
-        Ok(Output::File(file))
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            absolute.to_string_lossy(),
+        ));
     }
+
+    Ok(absolute)
 }
 
 impl io::Write for Output {
@@ -93,6 +175,12 @@ impl io::Write for Output {
         match self {
             Output::Stdout(stdout) => stdout.write(buf),
             Output::File(file) => file.write(buf),
+            Output::Sink(count) => {
+                *count += buf.len() as u64;
+                Ok(buf.len())
+            }
+            Output::Multi(multi) => multi.write(buf),
+            Output::Writer(writer) => writer.write(buf),
         }
     }
 
@@ -100,7 +188,307 @@ impl io::Write for Output {
         match self {
             Output::Stdout(stdout) => stdout.flush(),
             Output::File(file) => file.flush(),
+            Output::Sink(_) => Ok(()),
+            Output::Multi(multi) => multi.flush(),
+            Output::Writer(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Count bytes written to the inner writer.
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W> CountingWriter<W> {
+    /// Wrap a writer, starting from zero.
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// Get the number of bytes written so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Get a mutable reference to the inner writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Get back the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> io::Write for CountingWriter<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Measure the time spent in writes to the inner writer.
+pub struct TimedWriter<W> {
+    inner: W,
+    elapsed: Duration,
+}
+
+impl<W> TimedWriter<W> {
+    /// Wrap a writer, starting from zero.
+    pub fn new(inner: W) -> Self {
+        TimedWriter {
+            inner,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Get the time spent writing and flushing so far.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+impl<W> io::Write for TimedWriter<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let start = Instant::now();
+        let result = self.inner.write(buf);
+        self.elapsed += start.elapsed();
+
+        result
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        let start = Instant::now();
+        let result = self.inner.flush();
+        self.elapsed += start.elapsed();
+
+        result
+    }
+}
+
+/// Duplicate writes to several outputs, so data is only produced once.
+///
+/// Standard output is written to at most once. Regular files are written to
+/// a temporary file in the same directory, and only replace the previous
+/// file once [`MultiWriter::commit`] is called. If any output fails, the
+/// temporary files are removed and previous files are left untouched.
+pub struct MultiWriter {
+    outputs: Vec<Output>,
+    staged: Vec<(TempPath, PathBuf)>,
+}
+
+impl MultiWriter {
+    /// Open all provided paths for writing, see [`Output::from_path`].
+    pub fn new<T>(paths: &[T]) -> Result<Self, io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        let mut multi = MultiWriter {
+            outputs: Vec::new(),
+            staged: Vec::new(),
+        };
+
+        let mut seen = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+
+            if seen.contains(&path) {
+                continue;
+            }
+
+            seen.push(path);
+
+            let output = match Self::stage(path) {
+                Ok(Some((file, temp, target))) => {
+                    multi.staged.push((temp, target));
+                    Output::File(file)
+                }
+                Ok(None) => match Output::from_path(path) {
+                    Ok(output) => output,
+                    Err(err) => return Err(multi.abort(err)),
+                },
+                Err(err) => return Err(multi.abort(err)),
+            };
+
+            multi.outputs.push(output);
+        }
+
+        Ok(multi)
+    }
+
+    // temporary file next to a regular or missing output, which keeps the
+    // permissions of the file it replaces
+    fn stage(path: &Path) -> Result<Option<(fs::File, TempPath, PathBuf)>, io::Error> {
+        if path == OsStr::new("-") {
+            return Ok(None);
+        }
+
+        // replace the file behind a symlink, not the symlink itself
+        let (target, permissions) = match fs::canonicalize(path) {
+            Ok(target) => match fs::metadata(&target)? {
+                metadata if metadata.is_file() => (target, Some(metadata.permissions())),
+                _ => return Ok(None),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (output_path(path)?, None),
+            Err(err) => return Err(err),
+        };
+
+        let parent = target.parent().unwrap_or(Path::new("/"));
+        let (file, temp) = tempfile::Builder::new()
+            .prefix(".elusive-")
+            .permissions(fs::Permissions::from_mode(0o666))
+            .tempfile_in(parent)?
+            .into_parts();
+
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+
+        Ok(Some((file, temp, target)))
+    }
+
+    /// Stop writing and remove the temporary files, leaving previous files
+    /// untouched.
+    pub fn discard(&mut self) {
+        self.outputs.clear();
+
+        for (temp, _) in self.staged.drain(..) {
+            let path = temp.to_path_buf();
+
+            if let Err(err) = temp.close() {
+                warn!(
+                    "Failed to remove partial output {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Flush every output and rename the temporary files over their final
+    /// paths. Every rename is checked first so that outputs are either all
+    /// replaced or left untouched. On failure, the temporary files not yet
+    /// renamed are removed, and the error names the outputs already replaced
+    /// if a rename still fails.
+    pub fn commit(&mut self) -> Result<(), io::Error> {
+        io::Write::flush(self)?;
+
+        let checked = self
+            .staged
+            .iter()
+            .try_for_each(|(temp, target)| check_rename(temp, target));
+
+        if let Err(err) = checked {
+            return Err(self.abort(err));
+        }
+
+        let mut replaced = Vec::new();
+        while !self.staged.is_empty() {
+            let (temp, target) = self.staged.remove(0);
+
+            if let Err(err) = temp.persist(&target) {
+                self.staged.push((err.path, target));
+
+                let err = match replaced.is_empty() {
+                    true => err.error,
+                    false => io::Error::new(
+                        err.error.kind(),
+                        format!("{}, already replaced: {}", err.error, replaced.join(", ")),
+                    ),
+                };
+                return Err(self.abort(err));
+            }
+
+            replaced.push(target.display().to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Sync every output, see [`Output::sync`].
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        let result = self.outputs.iter_mut().try_for_each(Output::sync);
+
+        result.map_err(|err| self.abort(err))
+    }
+
+    // remove created files and hand back the original error
+    fn abort(&mut self, err: io::Error) -> io::Error {
+        self.discard();
+        err
+    }
+}
+
+// fail before anything is replaced if renaming a temporary file over its
+// target cannot work
+fn check_rename(temp: &Path, target: &Path) -> Result<(), io::Error> {
+    let device = fs::metadata(temp)?.dev();
+
+    match fs::symlink_metadata(target) {
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", target.display()),
+            ));
+        }
+        Ok(metadata) if metadata.dev() != device => {
+            return Err(io::Error::new(
+                io::ErrorKind::CrossesDevices,
+                format!("{} is on another filesystem", target.display()),
+            ));
         }
+        Ok(_) => (),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+
+    let parent = target.parent().unwrap_or(Path::new("/"));
+    let path = CString::new(parent.as_os_str().as_bytes())?;
+
+    // SAFETY: the path is a valid nul-terminated string
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!("cannot write to {}: {}", parent.display(), err),
+        ));
+    }
+
+    Ok(())
+}
+
+impl io::Write for MultiWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let result = self
+            .outputs
+            .iter_mut()
+            .try_for_each(|output| output.write_all(buf));
+
+        match result {
+            Ok(()) => Ok(buf.len()),
+            Err(err) => Err(self.abort(err)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        let result = self.outputs.iter_mut().try_for_each(Output::flush);
+
+        result.map_err(|err| self.abort(err))
     }
 }
 
@@ -113,4 +501,93 @@ mod tests {
         assert!(matches!(Input::from_path("-").unwrap(), Input::Stdin(_)));
         assert!(matches!(Output::from_path("-").unwrap(), Output::Stdout(_)));
     }
+
+    #[test]
+    fn test_sink() {
+        use std::io::Write;
+
+        let mut sink = Output::sink();
+        sink.write_all(b"data").unwrap();
+        sink.write_all(b"more").unwrap();
+
+        assert_eq!(sink.bytes_written(), Some(8));
+    }
+
+    #[test]
+    fn test_from_fd() {
+        use std::io::{Read, Seek, Write};
+        use std::os::fd::IntoRawFd;
+
+        let file = tempfile::tempfile().unwrap();
+        let mut reader = file.try_clone().unwrap();
+
+        let mut output = unsafe { Output::from_fd(file.into_raw_fd()) };
+        output.write_all(b"data").unwrap();
+        drop(output);
+
+        let mut data = Vec::new();
+        reader.rewind().unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data");
+    }
+
+    #[test]
+    fn test_multi() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let first = dir.join("first.img");
+        let second = dir.join("second.img");
+
+        let mut multi = Output::multi(&[&first, &second, &first]).unwrap();
+        multi.write_all(b"data").unwrap();
+        multi.commit().unwrap();
+        drop(multi);
+
+        let identical = fs::read(&first).unwrap() == fs::read(&second).unwrap();
+        let length = fs::metadata(&first).unwrap().len();
+
+        // missing parent directory fails at creation
+        let bad = Output::multi(&[dir.join("partial.img"), dir.join("missing/bad.img")]);
+        let partial_removed = !dir.join("partial.img").exists();
+
+        // writes to /dev/full fail with ENOSPC
+        let mut full =
+            Output::multi(&[dir.join("partial.img"), PathBuf::from("/dev/full")]).unwrap();
+        let write = full.write_all(b"data").and_then(|()| full.flush());
+        let full_removed = !dir.join("partial.img").exists();
+
+        // previous files are only replaced on commit
+        let mut failed = Output::multi(&[&first, Path::new("/dev/full")]).unwrap();
+        let replace = failed.write_all(b"other").and_then(|()| failed.flush());
+        let uncommitted = Output::multi(&[&second]).unwrap();
+        drop(uncommitted);
+        let kept = fs::read(&first).unwrap() == b"data" && fs::read(&second).unwrap() == b"data";
+
+        // an output that cannot be replaced leaves the others untouched
+        let blocked = dir.join("blocked.img");
+        let mut both = Output::multi(&[&first, &blocked]).unwrap();
+        both.write_all(b"new").unwrap();
+        fs::create_dir(&blocked).unwrap();
+        let commit = both.commit();
+        let untouched = fs::read(&first).unwrap() == b"data";
+        fs::remove_dir(&blocked).unwrap();
+
+        let leftovers = fs::read_dir(dir).unwrap().count();
+
+        assert!(identical);
+        assert_eq!(length, 4);
+        assert!(bad.is_err());
+        assert!(partial_removed);
+        assert!(write.is_err());
+        assert!(full_removed);
+        assert!(replace.is_err());
+        assert!(commit.is_err());
+        assert!(untouched);
+        assert!(kept);
+        assert_eq!(leftovers, 2);
+        assert!(Path::new("/dev/full").exists());
+    }
 }
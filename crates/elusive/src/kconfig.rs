@@ -0,0 +1,331 @@
+//! Configuration of the kernel an initramfs is generated for.
+//!
+//! The configuration tells which compression formats the kernel can
+//! decompress: kernel modules (`CONFIG_MODULE_DECOMPRESS` along with the
+//! `CONFIG_MODULE_COMPRESS_*` format modules were built with) and the
+//! initramfs itself (`CONFIG_RD_*`). It is read, in order, from:
+//!
+//! - `/proc/config.gz`, only for the running kernel
+//! - `/boot/config-<release>`
+//! - `config` in the module directory, e.g. `/usr/lib/modules/<release>/config`
+//!
+//! Detection is best effort: a configuration that cannot be read is skipped.
+
+use crate::encoder::Encoder;
+use crate::kmod::ModuleCompression;
+
+use flate2::read::GzDecoder;
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Configuration of the running kernel, exposed by `CONFIG_IKCONFIG_PROC`.
+const PROC_CONFIG: &str = "/proc/config.gz";
+
+/// Directory holding the configuration of installed kernels.
+const BOOT_DIR: &str = "/boot";
+
+/// Configuration file in a module directory.
+const MODULE_DIR_CONFIG: &str = "config";
+
+const MAGIC_GZ: [u8; 2] = [0x1F, 0x8B];
+
+/// Options of a kernel configuration (`CONFIG_*`).
+#[derive(Clone, Debug)]
+pub struct KernelConfig {
+    options: HashMap<String, String>,
+    source: PathBuf,
+}
+
+impl KernelConfig {
+    /// Parse the `CONFIG_<NAME>=<value>` lines of a kernel configuration,
+    /// options that are not set are left out.
+    pub fn parse(data: &str, source: &Path) -> Self {
+        let options = data
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .filter_map(|(name, value)| {
+                let name = name.trim().strip_prefix("CONFIG_")?;
+                Some((name.to_string(), value.trim().trim_matches('"').to_string()))
+            })
+            .collect();
+
+        KernelConfig {
+            options,
+            source: source.to_path_buf(),
+        }
+    }
+
+    /// Read a kernel configuration, gzip compressed or not.
+    pub fn read(path: &Path) -> Result<Self, io::Error> {
+        let data = fs::read(path)?;
+
+        let mut text = String::new();
+        if data.starts_with(&MAGIC_GZ) {
+            GzDecoder::new(data.as_slice()).read_to_string(&mut text)?;
+        } else {
+            text = String::from_utf8(data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+
+        Ok(Self::parse(&text, path))
+    }
+
+    /// Find the configuration of a kernel release whose modules are in the
+    /// provided directory, `running` is set when it is the release of the
+    /// running kernel.
+    pub fn find(release: &str, module_dir: &Path, running: bool) -> Option<Self> {
+        let mut paths = Vec::new();
+
+        if running {
+            paths.push(PathBuf::from(PROC_CONFIG));
+        }
+
+        paths.push(Path::new(BOOT_DIR).join(format!("config-{release}")));
+        paths.push(module_dir.join(MODULE_DIR_CONFIG));
+
+        Self::find_in(&paths)
+    }
+
+    /// Read the first configuration that can be read out of the provided
+    /// paths.
+    pub fn find_in(paths: &[PathBuf]) -> Option<Self> {
+        for path in paths {
+            match Self::read(path) {
+                Ok(config) => {
+                    debug!(path:% = path.display(); "Using kernel configuration: {}", path.display());
+                    return Some(config);
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => {
+                    debug!("Skipping kernel configuration {}: {}", path.display(), err);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Get the path the configuration was read from.
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Get the value of an option, without its `CONFIG_` prefix.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    /// Check whether an option is built in or built as a module.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        matches!(self.get(name), Some("y" | "m"))
+    }
+
+    /// Check whether the kernel refuses to load unsigned modules.
+    pub fn enforces_signatures(&self) -> bool {
+        self.is_enabled("MODULE_SIG_FORCE")
+    }
+
+    /// Check whether the kernel loads modules with this compression by
+    /// itself. It only decompresses the format its modules were built with.
+    pub fn supports_module_compression(&self, compression: ModuleCompression) -> bool {
+        match compression {
+            ModuleCompression::None => true,
+            compression => {
+                self.is_enabled("MODULE_DECOMPRESS")
+                    && self.is_enabled(module_compress_option(compression))
+            }
+        }
+    }
+
+    /// Get the compression of modules the kernel loads by itself, modules are
+    /// left uncompressed unless it can decompress them.
+    pub fn default_module_compression(&self) -> ModuleCompression {
+        [ModuleCompression::Zstd, ModuleCompression::Gzip]
+            .into_iter()
+            .find(|&compression| self.supports_module_compression(compression))
+            .unwrap_or_default()
+    }
+
+    /// Check whether the kernel can decompress an initramfs compressed with
+    /// the encoder.
+    pub fn supports_encoder(&self, encoder: &Encoder) -> bool {
+        match encoder_option(encoder) {
+            Some(option) => self.is_enabled(option),
+            None => !matches!(encoder, Encoder::ZstdDictionary(_)),
+        }
+    }
+}
+
+/// Option selecting the compression of modules when the kernel is built.
+pub fn module_compress_option(compression: ModuleCompression) -> &'static str {
+    match compression {
+        ModuleCompression::None => "MODULE_COMPRESS_NONE",
+        ModuleCompression::Gzip => "MODULE_COMPRESS_GZIP",
+        ModuleCompression::Zstd => "MODULE_COMPRESS_ZSTD",
+    }
+}
+
+/// Option of the initramfs decompressor needed for the encoder, if any.
+pub fn encoder_option(encoder: &Encoder) -> Option<&'static str> {
+    match encoder {
+        Encoder::None | Encoder::ZstdDictionary(_) => None,
+        Encoder::Gzip => Some("RD_GZIP"),
+        Encoder::Zstd => Some("RD_ZSTD"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    // in-kernel zstd module decompression, as in recent distribution kernels
+    const DECOMPRESS_ZSTD: &str = "\
+# Automatically generated file; DO NOT EDIT.
+# Linux/x86 6.6.8 Kernel Configuration
+CONFIG_CC_VERSION_TEXT=\"gcc (GCC) 13.2.1\"
+CONFIG_BLK_DEV_INITRD=y
+CONFIG_RD_GZIP=y
+CONFIG_RD_XZ=y
+CONFIG_RD_ZSTD=y
+CONFIG_MODULE_SIG=y
+# CONFIG_MODULE_SIG_FORCE is not set
+# CONFIG_MODULE_COMPRESS_NONE is not set
+# CONFIG_MODULE_COMPRESS_GZIP is not set
+# CONFIG_MODULE_COMPRESS_XZ is not set
+CONFIG_MODULE_COMPRESS_ZSTD=y
+CONFIG_MODULE_DECOMPRESS=y
+";
+
+    // modules compressed at install time, left to kmod to decompress
+    const COMPRESS_ONLY_ZSTD: &str = "\
+CONFIG_BLK_DEV_INITRD=y
+CONFIG_RD_GZIP=y
+CONFIG_RD_ZSTD=y
+CONFIG_MODULE_COMPRESS_ZSTD=y
+# CONFIG_MODULE_DECOMPRESS is not set
+";
+
+    // older kernel without zstd initramfs support, enforcing signatures
+    const LEGACY_GZIP: &str = "\
+# Linux/x86 5.4.0 Kernel Configuration
+CONFIG_BLK_DEV_INITRD=y
+CONFIG_RD_GZIP=y
+CONFIG_RD_XZ=y
+CONFIG_MODULE_SIG=y
+CONFIG_MODULE_SIG_FORCE=y
+CONFIG_MODULE_COMPRESS=y
+CONFIG_MODULE_COMPRESS_GZIP=y
+";
+
+    // in-kernel xz module decompression, which cannot be produced
+    const DECOMPRESS_XZ: &str = "\
+CONFIG_BLK_DEV_INITRD=y
+CONFIG_RD_GZIP=y
+CONFIG_RD_ZSTD=y
+CONFIG_MODULE_COMPRESS_XZ=y
+CONFIG_MODULE_DECOMPRESS=y
+";
+
+    fn parse(data: &str) -> KernelConfig {
+        KernelConfig::parse(data, Path::new("config"))
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(DECOMPRESS_ZSTD);
+
+        assert_eq!(config.get("CC_VERSION_TEXT"), Some("gcc (GCC) 13.2.1"));
+        assert_eq!(config.get("RD_ZSTD"), Some("y"));
+        assert_eq!(config.get("MODULE_SIG_FORCE"), None);
+        assert_eq!(config.get("MODULE_COMPRESS_GZIP"), None);
+        assert!(config.is_enabled("MODULE_DECOMPRESS"));
+        assert!(!config.is_enabled("MODULE_COMPRESS_NONE"));
+        assert!(!config.enforces_signatures());
+        assert!(parse(LEGACY_GZIP).enforces_signatures());
+    }
+
+    #[test]
+    fn test_module_compression() {
+        use ModuleCompression::{Gzip, None, Zstd};
+
+        let supported = |data: &str| {
+            let config = parse(data);
+            [None, Gzip, Zstd].map(|compression| config.supports_module_compression(compression))
+        };
+
+        assert_eq!(supported(DECOMPRESS_ZSTD), [true, false, true]);
+        assert_eq!(supported(COMPRESS_ONLY_ZSTD), [true, false, false]);
+        assert_eq!(supported(LEGACY_GZIP), [true, false, false]);
+        assert_eq!(supported(DECOMPRESS_XZ), [true, false, false]);
+
+        assert_eq!(parse(DECOMPRESS_ZSTD).default_module_compression(), Zstd);
+        assert_eq!(parse(COMPRESS_ONLY_ZSTD).default_module_compression(), None);
+        assert_eq!(parse(LEGACY_GZIP).default_module_compression(), None);
+        assert_eq!(parse(DECOMPRESS_XZ).default_module_compression(), None);
+
+        let gzip = parse("CONFIG_MODULE_COMPRESS_GZIP=y\nCONFIG_MODULE_DECOMPRESS=y\n");
+        assert_eq!(gzip.default_module_compression(), Gzip);
+    }
+
+    #[test]
+    fn test_encoder() {
+        let supported = |data: &str| {
+            let config = parse(data);
+            [
+                Encoder::None,
+                Encoder::Gzip,
+                Encoder::Zstd,
+                Encoder::ZstdDictionary(Vec::new()),
+            ]
+            .map(|encoder| config.supports_encoder(&encoder))
+        };
+
+        assert_eq!(supported(DECOMPRESS_ZSTD), [true, true, true, false]);
+        assert_eq!(supported(LEGACY_GZIP), [true, true, false, false]);
+        assert_eq!(supported(""), [true, false, false, false]);
+    }
+
+    #[test]
+    fn test_find() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let proc = dir.join("config.gz");
+        let boot = dir.join("config-6.6.8");
+        let modules = dir.join("config");
+        let unreadable = dir.join("unreadable");
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(DECOMPRESS_ZSTD.as_bytes()).unwrap();
+        fs::write(&proc, encoder.finish().unwrap()).unwrap();
+        fs::write(&boot, LEGACY_GZIP).unwrap();
+        fs::write(&modules, COMPRESS_ONLY_ZSTD).unwrap();
+        fs::write(&unreadable, [0xff, 0xfe]).unwrap();
+
+        let source = |paths: &[&PathBuf]| {
+            let paths: Vec<_> = paths.iter().map(|path| path.to_path_buf()).collect();
+            KernelConfig::find_in(&paths).map(|config| config.source().to_path_buf())
+        };
+
+        let gunzipped = KernelConfig::read(&proc).unwrap();
+        let precedence = source(&[&proc, &boot, &modules]);
+        let fallback = source(&[&dir.join("missing.gz"), &boot, &modules]);
+        let skipped = source(&[&unreadable, &modules]);
+        let missing = source(&[&dir.join("missing")]);
+
+        assert!(gunzipped.is_enabled("RD_ZSTD"));
+        assert_eq!(precedence, Some(proc));
+        assert_eq!(fallback, Some(boot));
+        assert_eq!(skipped, Some(modules));
+        assert_eq!(missing, None);
+    }
+}
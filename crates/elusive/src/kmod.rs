@@ -1,19 +1,60 @@
-//! Wrapper around libkmod for kernel module handling.
-
-#[allow(clippy::wildcard_imports)]
-use kmod_sys::*;
-
-use std::ffi::CString;
+//! Kernel module handling.
+//!
+//! Modules are looked up with libkmod when the `libkmod` feature is enabled
+//! (the default), otherwise with a pure Rust implementation reading the text
+//! indexes written by depmod and the `.modinfo` section of module files.
+
+use crate::package::{self, KernelPackage};
+use crate::paths::escaped;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info};
+use serde::Deserialize;
+use std::collections::hash_map::Entry as MapEntry;
+use std::collections::{BTreeSet, HashMap};
 use std::ffi::{CStr, OsStr};
+use std::io::{Cursor, Read, Write};
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::ffi::OsStringExt;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::{ffi, io, ptr, str};
+use std::{ffi, fmt, fs, io, str};
+use walkdir::WalkDir;
+use zstd::Decoder as ZstdDecoder;
+
+#[cfg(feature = "libkmod")]
+mod libkmod;
+#[cfg(not(feature = "libkmod"))]
+mod native;
+
+#[cfg(feature = "libkmod")]
+use self::libkmod as backend;
+#[cfg(not(feature = "libkmod"))]
+use self::native as backend;
 
 const UNKNOWN_MODULE: &str = "unknown";
 
+/// Directory for modules found outside of a `kernel` directory, which has
+/// priority over in-tree modules for depmod.
+const UPDATES_DIRECTORY: &str = "updates";
+
+/// Marker at the end of modules with an appended signature.
+const MODULE_SIGNATURE_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+/// Directory holding the module directory of each installed kernel.
+pub const MODULES_DIR: &str = "/usr/lib/modules";
+
+/// List of the modules built into the kernel, relative to the module directory.
+const MODULES_BUILTIN: &str = "modules.builtin";
+
+/// Extensions of kernel module files, compressed or not.
+pub(crate) const MODULE_EXTENSIONS: &[&str] = &[".ko", ".ko.gz", ".ko.xz", ".ko.zst"];
+
+/// Sysfs parameter telling if the running kernel only loads signed modules.
+const SIG_ENFORCE_PATH: &str = "/sys/module/module/parameters/sig_enforce";
+
 const MAGIC_ELF: [u8; 4] = [0x7F, b'E', b'L', b'F'];
 
 const MAGIC_GZ: [u8; 2] = [0x1F, 0x8B];
@@ -21,7 +62,7 @@ const MAGIC_ZSTD: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
 
 const FORMAT_MIN_BYTES_LEN: usize = 6;
 
-/// Custom error type to represent libkmod failures.
+/// Custom error type to represent kernel module lookup failures.
 #[derive(thiserror::Error, Debug)]
 pub enum KmodError {
     #[error("i/o error: {0}")]
@@ -34,6 +75,8 @@ pub enum KmodError {
     ContextNewFailed,
     #[error("failed to create module from name: {0}")]
     ModuleFromNameFailed(String),
+    #[error("kernel module not found: {0}")]
+    ModuleNotFound(String),
     #[error("failed to create module from path: {0}")]
     ModuleFromPathFailed(PathBuf),
     #[error("failed to get module information: {0}")]
@@ -46,6 +89,16 @@ pub enum KmodError {
     BadDirectory(PathBuf),
     #[error("the module is already built-in")]
     ModuleBuiltIn,
+    #[error("cannot derive a module name from: {0}")]
+    UnknownModuleName(PathBuf),
+    #[error("no depmod index in {0}, run depmod for this kernel or set settings.auto_depmod to look modules up by file name")]
+    MissingDepmodIndex(PathBuf),
+    #[error("no kernel module directory (usr/lib/modules/<release>) in package: {0}")]
+    EmptyPackage(PathBuf),
+    #[error("package {0} holds modules for several kernels: {1}")]
+    AmbiguousPackage(PathBuf, String),
+    #[error("kernel release of module directory is not valid utf8: {}", escaped(.0))]
+    NonUtf8Release(PathBuf),
 }
 
 impl From<io::Error> for KmodError {
@@ -66,41 +119,112 @@ impl From<ffi::NulError> for KmodError {
     }
 }
 
-/// Wrapper handler for libkmod's `kmod_ctx`.
+/// Number of lookups and module information queries made to the backend.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct KmodStats {
+    /// Lookups of modules by name.
+    pub lookups: usize,
+    /// Module information queries.
+    pub info: usize,
+}
+
+/// Kernel module context of a module directory, wrapping libkmod's `kmod_ctx`
+/// with the `libkmod` feature.
+///
+/// Lookups by name and module information are cached for the lifetime of the
+/// context. The reference counted caches make this type neither `Send` nor
+/// `Sync`, libkmod contexts are not thread safe either: each thread needs its
+/// own.
 pub struct Kmod {
     kernel_release: Rc<String>,
-    ctx: *mut kmod_ctx,
+    ctx: backend::Context,
+    dir: PathBuf,
+    indexed: bool,
+    auto_depmod: bool,
+    files: Option<HashMap<String, PathBuf>>,
+    package: Option<KernelPackage>,
+    modules: HashMap<String, Rc<Module>>,
+    infos: HashMap<String, Rc<ModuleInfo>>,
+    builtin: BTreeSet<String>,
+    stats: KmodStats,
 }
 
 impl Kmod {
-    /// Create a new libkmod context.
+    /// Create a new context for the running kernel.
     pub fn new() -> Result<Self, KmodError> {
         let kernel_release = get_kernel_release()?;
-        let ctx = Self::kmod_init_ctx(&Path::new("/usr/lib/modules").join(&kernel_release))?;
+        debug!(release = kernel_release.as_str(); "Using kernel modules for release: {}", kernel_release);
 
-        Ok(Kmod {
-            kernel_release: Rc::new(kernel_release),
-            ctx,
-        })
+        let dir = Path::new(MODULES_DIR).join(&kernel_release);
+        Self::from_parts(kernel_release, dir)
     }
 
-    /// Create a new libkmod context with the specified kernel module directory.
+    /// Create a new context with the specified kernel module directory.
     pub fn with_directory(dir: &Path) -> Result<Self, KmodError> {
         if !Path::exists(&dir.join("kernel")) {
             return Err(KmodError::BadDirectory(dir.into()));
         }
 
-        let filename = dir.file_name().expect("path it not root");
+        let kernel_release = release_of_directory(dir)?;
+        debug!(
+            release = kernel_release.as_str(), path:% = dir.display();
+            "Using kernel modules for release: {}", kernel_release
+        );
+
+        Self::from_parts(kernel_release, dir.to_path_buf())
+    }
+
+    /// Create a new context for the modules of a kernel package (see
+    /// [`package`]), which are extracted to a temporary directory as they are
+    /// looked up. The directory is removed along with the context.
+    pub fn with_package(path: &Path) -> Result<Self, KmodError> {
+        let package = KernelPackage::open(path)?;
+        let kernel_release = package.release().to_string();
+        debug!(
+            release = kernel_release.as_str(), path:% = path.display();
+            "Using kernel modules for release: {}", kernel_release
+        );
+
+        let mut kmod = Self::from_parts(kernel_release, package.module_dir())?;
+        kmod.package = Some(package);
+
+        Ok(kmod)
+    }
 
-        let kernel_release = filename.to_string_lossy().to_string();
-        let ctx = Self::kmod_init_ctx(dir)?;
+    fn from_parts(kernel_release: String, dir: PathBuf) -> Result<Self, KmodError> {
+        let ctx = backend::Context::new(&dir)?;
+        let builtin = read_builtin(&dir)?;
+
+        // lookups by name fail for every module without an index
+        let indexed = backend::DEPMOD_INDEXES
+            .iter()
+            .any(|index| dir.join(index).exists());
+        if !indexed {
+            debug!(path:% = dir.display(); "No depmod index in: {}", dir.display());
+        }
 
-        let kmod = Kmod {
+        Ok(Kmod {
             kernel_release: Rc::new(kernel_release),
             ctx,
-        };
+            dir,
+            indexed,
+            auto_depmod: false,
+            files: None,
+            package: None,
+            modules: HashMap::new(),
+            infos: HashMap::new(),
+            builtin,
+            stats: KmodStats::default(),
+        })
+    }
 
-        Ok(kmod)
+    /// Set whether modules are looked up by file name in the module directory
+    /// when it has no depmod index, instead of failing with
+    /// [`KmodError::MissingDepmodIndex`]. Dependencies are still read from the
+    /// modinfo of each module, aliases other than module names are not
+    /// resolved.
+    pub fn set_auto_depmod(&mut self, auto_depmod: bool) {
+        self.auto_depmod = auto_depmod;
     }
 
     /// Get the kernel release for modules in the context directory.
@@ -108,51 +232,173 @@ impl Kmod {
         &self.kernel_release
     }
 
+    /// Get the module directory of the context, a temporary directory for
+    /// kernel packages.
+    pub fn directory(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Check whether a module with the provided name is listed as built into
+    /// the kernel by `modules.builtin`.
+    pub fn is_builtin<T>(&self, name: T) -> bool
+    where
+        T: AsRef<str>,
+    {
+        self.builtin.contains(&normalize_name(name.as_ref()))
+    }
+
+    /// Get the number of lookups and queries made to the backend so far.
+    pub fn stats(&self) -> KmodStats {
+        self.stats
+    }
+
     /// Get a Module with the provided name by searching it in the filesystem.
-    pub fn module_from_name<T>(&mut self, name: T) -> Result<Module, KmodError>
+    /// Modules previously resolved, by name or path, are reused.
+    pub fn module_from_name<T>(&mut self, name: T) -> Result<Rc<Module>, KmodError>
+    where
+        T: AsRef<str>,
+    {
+        let name = name.as_ref();
+        if let Some(module) = self.modules.get(name) {
+            return Ok(module.clone());
+        }
+
+        if !self.indexed {
+            return self.module_from_file_name(name);
+        }
+
+        self.stats.lookups += 1;
+        let module = Rc::new(Module::from_name(self, name)?);
+        self.extract(&module)?;
+        self.modules.insert(name.to_string(), module.clone());
+
+        Ok(module)
+    }
+
+    /// Resolve a module name or alias, such as a device modalias, to the names
+    /// of every matching kernel module. Unknown aliases resolve to nothing.
+    pub fn resolve_alias<T>(&mut self, alias: T) -> Result<Vec<String>, KmodError>
     where
         T: AsRef<str>,
     {
-        Module::from_name(self, name)
+        let alias = alias.as_ref();
+
+        if !self.indexed {
+            return match self.module_from_file_name(alias) {
+                Ok(module) => Ok(module.name().map(str::to_string).into_iter().collect()),
+                Err(KmodError::ModuleNotFound(_)) => {
+                    debug!("Cannot resolve alias without depmod index: {}", alias);
+                    Ok(Vec::new())
+                }
+                Err(err) => Err(err),
+            };
+        }
+
+        self.stats.lookups += 1;
+        let names = self
+            .ctx
+            .lookup(alias)?
+            .iter()
+            .filter_map(|handle| handle.name()?.to_str())
+            .map(str::to_string)
+            .collect();
+
+        Ok(names)
     }
 
     /// Get a Module from the provided path which must point to a kernel module.
-    pub fn module_from_path<T>(&mut self, path: T) -> Result<Module, KmodError>
+    /// The module is then used for lookups by name, so that dependencies on
+    /// out of tree modules are resolved to them.
+    pub fn module_from_path<T>(&mut self, path: T) -> Result<Rc<Module>, KmodError>
     where
         T: AsRef<Path>,
     {
-        Module::from_path(self, path)
+        let module = Rc::new(Module::from_path(self, path)?);
+
+        if let Some(name) = module.name() {
+            self.modules.insert(name.to_string(), module.clone());
+        }
+
+        Ok(module)
+    }
+
+    /// Get information on the provided kernel module, only querying the
+    /// backend once per module name.
+    pub fn module_info(&mut self, module: &Module) -> Result<Rc<ModuleInfo>, KmodError> {
+        let name = module.name().map(str::to_string);
+
+        if let Some(info) = name.as_ref().and_then(|name| self.infos.get(name)) {
+            return Ok(info.clone());
+        }
+
+        self.stats.info += 1;
+        let info = Rc::new(module.info()?);
+
+        if let Some(name) = name {
+            self.infos.insert(name, info.clone());
+        }
+
+        Ok(info)
     }
 
-    fn kmod_init_ctx(dir: &Path) -> Result<*mut kmod_ctx, KmodError> {
-        let cstring = CString::new(dir.as_os_str().as_bytes())?;
-        let inner = unsafe { kmod_new(cstring.as_ptr(), ptr::null()) };
+    // look a module up in the module directory without depmod index
+    fn module_from_file_name(&mut self, name: &str) -> Result<Rc<Module>, KmodError> {
+        if !self.auto_depmod {
+            return Err(KmodError::MissingDepmodIndex(self.dir.clone()));
+        }
 
-        if inner.is_null() {
-            return Err(KmodError::ContextNewFailed);
+        if self.files.is_none() {
+            info!(
+                path:% = self.dir.display();
+                "No depmod index, looking kernel modules up by file name in: {}",
+                self.dir.display()
+            );
+            let files = match &self.package {
+                Some(package) => index_module_files(&self.dir, package.module_files()),
+                None => scan_module_files(&self.dir)?,
+            };
+
+            self.files = Some(files);
         }
 
-        Ok(inner)
+        let path = self
+            .files
+            .as_ref()
+            .and_then(|files| files.get(&normalize_name(name)))
+            .cloned()
+            .ok_or_else(|| KmodError::ModuleNotFound(name.to_string()))?;
+
+        if let Some(package) = &mut self.package {
+            package.extract(&path)?;
+        }
+
+        let module = self.module_from_path(path)?;
+        self.modules.insert(name.to_string(), module.clone());
+
+        Ok(module)
     }
 
-    fn as_mut_ptr(&mut self) -> *mut kmod_ctx {
-        self.ctx
+    // extract the file of a module found in the indexes of a package
+    fn extract(&mut self, module: &Module) -> Result<(), KmodError> {
+        if let (Some(package), Some(path)) = (&mut self.package, module.host_path()) {
+            package.extract(path)?;
+        }
+
+        Ok(())
     }
 }
 
 impl Drop for Kmod {
     fn drop(&mut self) {
-        unsafe {
-            let ret = kmod_unref(self.ctx);
-            assert!(ret.is_null());
-        }
+        // cached modules hold a reference to the context
+        self.modules.clear();
     }
 }
 
-/// Wrapper handler for libkmod's `kmod_module`.
+/// Kernel module, wrapping libkmod's `kmod_module` with the `libkmod` feature.
 pub struct Module {
     kernel_release: Rc<String>,
-    inner: *mut kmod_module,
+    inner: backend::Handle,
 }
 
 impl Module {
@@ -161,23 +407,11 @@ impl Module {
         T: AsRef<str>,
     {
         let name = name.as_ref();
-        let cstr = CString::new(name)?;
-
-        let mut list: MaybeUninit<*mut kmod_list> = MaybeUninit::zeroed();
-
-        let inner = unsafe {
-            let ret =
-                kmod_module_new_from_lookup(ctx.as_mut_ptr(), cstr.as_ptr(), list.as_mut_ptr());
-
-            if ret < 0 {
-                return Err(KmodError::ModuleFromNameFailed(name.to_string()));
-            }
+        debug!(module = name; "Looking up kernel module: {}", name);
 
-            let list = list.assume_init();
-            let module = kmod_module_get_module(list);
-
-            kmod_module_unref_list(list);
-            module
+        // nothing matched, there is no module to take the first of
+        let Some(inner) = ctx.ctx.lookup(name)?.into_iter().next() else {
+            return Err(KmodError::ModuleNotFound(name.to_string()));
         };
 
         Ok(Module {
@@ -191,22 +425,7 @@ impl Module {
     where
         T: AsRef<Path>,
     {
-        let path = path.as_ref();
-        let data = path.as_os_str().to_os_string().into_vec();
-        let cstr = CString::new(data)?;
-
-        let mut inner: MaybeUninit<*mut kmod_module> = MaybeUninit::uninit();
-
-        let inner = unsafe {
-            let ret =
-                kmod_module_new_from_path(ctx.as_mut_ptr(), cstr.as_ptr(), inner.as_mut_ptr());
-
-            if ret < 0 {
-                return Err(KmodError::ModuleFromPathFailed(path.to_path_buf()));
-            }
-
-            inner.assume_init()
-        };
+        let inner = backend::Handle::from_path(&mut ctx.ctx, path.as_ref())?;
 
         Ok(Module {
             kernel_release: ctx.kernel_release.clone(),
@@ -214,32 +433,19 @@ impl Module {
         })
     }
 
-    /// Get the name of this kernel module.
+    /// Get the name of this kernel module, if it is valid utf8.
     pub fn name(&self) -> Option<&str> {
-        let cstr = unsafe {
-            let name = kmod_module_get_name(self.inner);
-            if name.is_null() {
-                return None;
-            }
-
-            CStr::from_ptr(name)
-        };
+        self.raw_name()?.to_str()
+    }
 
-        cstr.to_str().ok()
+    /// Get the name of this kernel module as the backend reports it.
+    pub fn raw_name(&self) -> Option<&OsStr> {
+        self.inner.name()
     }
 
     /// Get the host path of this kernel module.
     pub fn host_path(&self) -> Option<&Path> {
-        let cstr = unsafe {
-            let path = kmod_module_get_path(self.inner);
-            if path.is_null() {
-                return None;
-            }
-
-            CStr::from_ptr(path)
-        };
-
-        Some(Path::new(OsStr::from_bytes(cstr.to_bytes())))
+        self.inner.path()
     }
 
     /// Get the install path for this kernel module.
@@ -248,16 +454,35 @@ impl Module {
             return Err(KmodError::ModuleBuiltIn);
         };
 
-        let inner_path = host_path
-            .components()
-            .skip_while(|component| component.as_os_str() != "kernel");
+        // the backend usually derives a name, fall back to the file name without
+        // its extensions otherwise
+        let name = match self.raw_name() {
+            Some(name) => name,
+            None => host_path
+                .file_name()
+                .and_then(|name| name.as_bytes().split(|&byte| byte == b'.').next())
+                .filter(|name| !name.is_empty())
+                .map(OsStr::from_bytes)
+                .ok_or_else(|| KmodError::UnknownModuleName(host_path.to_path_buf()))?,
+        };
 
-        let mut install_path = PathBuf::from("/usr/lib/modules").join(self.kernel_release.as_ref());
+        let mut install_path = PathBuf::from(MODULES_DIR).join(self.kernel_release.as_ref());
 
-        install_path.extend(inner_path);
-        install_path.set_file_name(self.name().expect("module has a name"));
-        install_path.set_extension("ko");
+        // keep the layout of in-tree modules, others go to updates/
+        let mut inner_path = host_path
+            .components()
+            .skip_while(|component| component.as_os_str() != "kernel")
+            .peekable();
+
+        if inner_path.peek().is_some() {
+            install_path.extend(inner_path);
+            install_path.set_file_name(name);
+        } else {
+            install_path.push(UPDATES_DIRECTORY);
+            install_path.push(name);
+        }
 
+        install_path.set_extension("ko");
         Ok(install_path)
     }
 
@@ -272,14 +497,6 @@ impl Module {
     }
 }
 
-impl Drop for Module {
-    fn drop(&mut self) {
-        unsafe {
-            kmod_module_unref(self.inner);
-        }
-    }
-}
-
 /// Information obtained from a kernel module.
 pub struct ModuleInfo {
     /// All aliases for this kernel module.
@@ -295,57 +512,33 @@ pub struct ModuleInfo {
 impl ModuleInfo {
     /// Create a new `ModuleInfo` from the provided Module.
     pub fn new(module: &Module) -> Result<Self, KmodError> {
-        let mut list: MaybeUninit<*mut kmod_list> = MaybeUninit::zeroed();
-
         let mut aliases = Vec::new();
         let mut depends = Vec::new();
         let mut softpre = Vec::new();
         let mut softpost = Vec::new();
 
-        unsafe {
-            let ret = kmod_module_get_info(module.inner, list.as_mut_ptr());
-            if ret < 0 {
-                return Err(KmodError::ModuleGetInfoFailed(
-                    module.name().unwrap_or(UNKNOWN_MODULE).to_string(),
-                ));
-            }
-
-            let list = list.assume_init();
-            let mut item = list;
-
-            while !item.is_null() {
-                let key = kmod_module_info_get_key(item);
-                let value = kmod_module_info_get_value(item);
-
-                let key = CStr::from_ptr(key).to_str()?;
-                let value = CStr::from_ptr(value);
-
-                match key {
-                    "alias" => aliases.push(value.to_str()?.to_string()),
-                    "depends" => {
-                        for depend in value.to_str()?.split(',') {
-                            if !depend.is_empty() {
-                                depends.push(depend.to_string());
-                            }
+        for (key, value) in module.inner.info()? {
+            match key.as_str() {
+                "alias" => aliases.push(str::from_utf8(&value)?.to_string()),
+                "depends" => {
+                    for depend in str::from_utf8(&value)?.split(',') {
+                        if !depend.is_empty() {
+                            depends.push(depend.to_string());
                         }
                     }
-                    "softdep" => {
-                        let value = value.to_str()?;
+                }
+                "softdep" => {
+                    let value = str::from_utf8(&value)?;
 
-                        if let Some(softdep) = value.strip_prefix("pre: ") {
-                            softpre.push(softdep.to_string());
-                        } else if let Some(softdep) = value.strip_prefix("post: ") {
-                            softpost.push(softdep.to_string());
-                        }
+                    if let Some(softdep) = value.strip_prefix("pre: ") {
+                        softpre.push(softdep.to_string());
+                    } else if let Some(softdep) = value.strip_prefix("post: ") {
+                        softpost.push(softdep.to_string());
                     }
-                    // TODO: firmware ?
-                    _ => (),
                 }
-
-                item = kmod_list_next(list, item);
+                // TODO: firmware ?
+                _ => (),
             }
-
-            kmod_module_info_free_list(list);
         }
 
         Ok(ModuleInfo {
@@ -377,6 +570,16 @@ impl ModuleInfo {
     }
 }
 
+/// Check if uncompressed module data ends with an appended signature.
+pub fn is_signed(data: &[u8]) -> bool {
+    data.ends_with(MODULE_SIGNATURE_MAGIC)
+}
+
+/// Check if the running kernel refuses to load unsigned modules.
+pub fn signatures_enforced() -> bool {
+    fs::read_to_string(SIG_ENFORCE_PATH).is_ok_and(|value| value.trim() == "Y")
+}
+
 /// Enum to represent various compression format for modules.
 pub enum ModuleFormat {
     Elf,
@@ -405,6 +608,38 @@ impl ModuleFormat {
         Err(KmodError::UnknownMagic)
     }
 
+    /// Detect the format from the first bytes of the reader and return a
+    /// reader over the uncompressed module.
+    pub fn from_reader<'a, R>(mut reader: R) -> Result<(Self, Box<dyn Read + 'a>), KmodError>
+    where
+        R: Read + 'a,
+    {
+        let mut magic = [0; FORMAT_MIN_BYTES_LEN];
+        let mut len = 0;
+
+        // a single read may return less than requested
+        while len < magic.len() {
+            match reader.read(&mut magic[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let format = Self::from_bytes(&magic[..len])?;
+
+        // put the magic back in front of the rest of the stream
+        let stream = Cursor::new(magic).chain(reader);
+        let reader: Box<dyn Read + 'a> = match format {
+            ModuleFormat::Elf => Box::new(stream),
+            ModuleFormat::Zstd => Box::new(ZstdDecoder::new(stream)?),
+            ModuleFormat::Gzip => Box::new(GzDecoder::new(stream)),
+        };
+
+        Ok((format, reader))
+    }
+
     pub fn extension(&self) -> &str {
         match self {
             ModuleFormat::Elf => "ko",
@@ -414,7 +649,193 @@ impl ModuleFormat {
     }
 }
 
+/// Compression of the kernel modules installed in the initramfs.
+///
+/// Modules are read decompressed, then compressed again when installed. The
+/// kernel only loads compressed modules by itself when built with
+/// `CONFIG_MODULE_DECOMPRESS` for that format, `kmod` decompresses them
+/// otherwise.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl ModuleCompression {
+    /// Get the extension appended to the `.ko` extension of modules.
+    pub fn extension(&self) -> Option<&str> {
+        match self {
+            ModuleCompression::None => None,
+            ModuleCompression::Gzip => Some("gz"),
+            ModuleCompression::Zstd => Some("zst"),
+        }
+    }
+
+    /// Compress uncompressed module data.
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, KmodError> {
+        let compressed = match self {
+            ModuleCompression::None => data.to_vec(),
+            ModuleCompression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            ModuleCompression::Zstd => zstd::encode_all(data, 0)?,
+        };
+
+        Ok(compressed)
+    }
+}
+
+impl fmt::Display for ModuleCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleCompression::None => write!(f, "none"),
+            ModuleCompression::Gzip => write!(f, "gzip"),
+            ModuleCompression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Read the names of the modules listed in the `modules.builtin` file of the
+/// module directory, if any.
+fn read_builtin(dir: &Path) -> Result<BTreeSet<String>, KmodError> {
+    let data = match fs::read_to_string(dir.join(MODULES_BUILTIN)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let names = data
+        .lines()
+        .filter_map(|line| Path::new(line.trim()).file_name())
+        .filter_map(|name| name.to_str()?.split('.').next())
+        .filter(|name| !name.is_empty())
+        .map(normalize_name)
+        .collect();
+
+    Ok(names)
+}
+
+/// Map the normalized names of the module files found in the module directory
+/// to their path. Like depmod, modules under `updates` have priority.
+fn scan_module_files(dir: &Path) -> Result<HashMap<String, PathBuf>, KmodError> {
+    let mut paths = Vec::new();
+
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        paths.push(entry.map_err(io::Error::from)?.into_path());
+    }
+
+    Ok(index_module_files(dir, paths))
+}
+
+/// Map the normalized names of module files to their path, see
+/// [`scan_module_files`].
+fn index_module_files<I>(dir: &Path, paths: I) -> HashMap<String, PathBuf>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    let mut files: HashMap<String, (PathBuf, bool)> = HashMap::new();
+
+    for path in paths {
+        let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+            continue;
+        };
+
+        let Some(stem) = MODULE_EXTENSIONS
+            .iter()
+            .find_map(|extension| name.strip_suffix(extension))
+        else {
+            continue;
+        };
+
+        let updates = path
+            .strip_prefix(dir)
+            .is_ok_and(|relative| relative.starts_with(UPDATES_DIRECTORY));
+
+        match files.entry(normalize_name(stem)) {
+            MapEntry::Vacant(vacant) => {
+                vacant.insert((path, updates));
+            }
+            MapEntry::Occupied(mut occupied) => {
+                if updates && !occupied.get().1 {
+                    occupied.insert((path, updates));
+                }
+            }
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(name, (path, _))| (name, path))
+        .collect()
+}
+
+/// Dashes and underscores are interchangeable in module names.
+fn normalize_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Get the kernel release modules are looked up for: the name of the module
+/// directory or the release of the kernel package if one is provided, the
+/// running kernel otherwise.
+pub fn release_of(dir: Option<&Path>) -> Result<String, KmodError> {
+    if let Some(path) = dir.filter(|path| package::is_package(path)) {
+        return package::release_of(path);
+    }
+
+    match dir {
+        Some(dir) => release_of_directory(dir),
+        None => get_kernel_release(),
+    }
+}
+
+/// Get the releases of the kernels installed in a directory such as
+/// [`MODULES_DIR`], sorted. Directories without in-tree modules, usually
+/// leftovers of removed kernels, are skipped.
+pub fn installed_releases(dir: &Path) -> Result<Vec<String>, KmodError> {
+    let mut releases = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.join("kernel").is_dir() {
+            releases.push(release_of_directory(&path)?);
+        }
+    }
+
+    releases.sort();
+    Ok(releases)
+}
+
+/// Get the kernel release from the name of a module directory.
+fn release_of_directory(dir: &Path) -> Result<String, KmodError> {
+    let name = dir.file_name().expect("path is not root");
+
+    name.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| KmodError::NonUtf8Release(dir.to_path_buf()))
+}
+
+/// Get the machine hardware name of the running kernel (`uname -m`).
+pub fn machine() -> Result<String, KmodError> {
+    let utsname = uname()?;
+    let cstr = unsafe { CStr::from_ptr(utsname.machine.as_ref().as_ptr()) };
+
+    Ok(cstr.to_string_lossy().to_string())
+}
+
 fn get_kernel_release() -> Result<String, KmodError> {
+    let utsname = uname()?;
+    let cstr = unsafe { CStr::from_ptr(utsname.release.as_ref().as_ptr()) };
+
+    Ok(cstr.to_str()?.to_string())
+}
+
+fn uname() -> Result<libc::utsname, KmodError> {
     let mut utsname: MaybeUninit<libc::utsname> = MaybeUninit::uninit();
 
     unsafe {
@@ -423,9 +844,247 @@ fn get_kernel_release() -> Result<String, KmodError> {
             return Err(io::Error::last_os_error().into());
         }
 
-        let utsname = utsname.assume_init();
-        let cstr = CStr::from_ptr(utsname.release.as_ref().as_ptr());
+        Ok(utsname.assume_init())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use object::write::Object;
+    use object::{Architecture, BinaryFormat, Endianness, SectionKind};
+
+    // relocatable ELF with only a .modinfo section, enough for libkmod
+    pub(crate) fn fake_module(path: &Path, modinfo: &[&str]) {
+        let mut object = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let section =
+            object.add_section(Vec::new(), b".modinfo".to_vec(), SectionKind::ReadOnlyData);
 
-        Ok(cstr.to_str().expect("kernel ").to_string())
+        let data: Vec<u8> = modinfo
+            .iter()
+            .flat_map(|entry| entry.bytes().chain([0]))
+            .collect();
+        object.append_section_data(section, &data, 1);
+
+        fs::write(path, object.write().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        fs::create_dir_all(release.join("kernel")).unwrap();
+
+        let first = dir.join("first.ko");
+        let second = dir.join("second.ko");
+        fake_module(&first, &["name=first", "depends=second"]);
+        fake_module(&second, &["name=second", "depends="]);
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        let second = kmod.module_from_path(&second).unwrap();
+        let first = kmod.module_from_path(&first).unwrap();
+
+        let info = kmod.module_info(&first).unwrap();
+        kmod.module_info(&first).unwrap();
+
+        // resolved from the path lookup above
+        let depend = kmod.module_from_name(&info.depends()[0]).unwrap();
+        kmod.module_info(&depend).unwrap();
+        let stats = kmod.stats();
+
+        drop((first, second, depend));
+        drop(kmod);
+
+        assert_eq!(info.depends(), ["second"]);
+        assert_eq!(
+            stats,
+            KmodStats {
+                lookups: 0,
+                info: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_installed_releases() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("6.6.1-arch1/kernel")).unwrap();
+        fs::create_dir_all(dir.join("6.1.0-lts/kernel")).unwrap();
+        fs::create_dir_all(dir.join("6.0.0-removed/extramodules")).unwrap();
+
+        let releases = installed_releases(dir);
+
+        assert_eq!(releases.unwrap(), ["6.1.0-lts", "6.6.1-arch1"]);
+    }
+
+    #[test]
+    fn test_builtin() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        fs::create_dir_all(release.join("kernel")).unwrap();
+        fs::write(release.join("modules.dep"), "").unwrap();
+        fs::write(
+            release.join(MODULES_BUILTIN),
+            "kernel/fs/ext4/ext4.ko\nkernel/drivers/hid/hid-generic.ko\n",
+        )
+        .unwrap();
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        let missing = kmod.module_from_name("elusive-nonsense");
+        let builtin = ["ext4", "hid_generic", "hid-generic"].map(|name| kmod.is_builtin(name));
+        let absent = kmod.is_builtin("btrfs");
+
+        drop(kmod);
+
+        assert!(
+            matches!(missing, Err(KmodError::ModuleNotFound(ref name)) if name == "elusive-nonsense"),
+            "{:?}",
+            missing.err()
+        );
+        assert_eq!(builtin, [true; 3]);
+        assert!(!absent);
+    }
+
+    #[test]
+    fn test_missing_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        fs::create_dir_all(release.join("kernel/fs/ext4")).unwrap();
+        fs::create_dir_all(release.join("kernel/lib")).unwrap();
+        fs::create_dir_all(release.join("updates")).unwrap();
+
+        fake_module(
+            &release.join("kernel/fs/ext4/ext4.ko"),
+            &["name=ext4", "depends=crc16,mbcache"],
+        );
+        fake_module(&release.join("kernel/lib/crc16.ko"), &["name=crc16"]);
+        fake_module(&release.join("updates/crc16.ko"), &["name=crc16"]);
+        fake_module(&release.join("kernel/fs/mbcache.ko"), &["name=mbcache"]);
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        let missing = kmod.module_from_name("ext4").err();
+        let from_path = kmod
+            .module_from_path(release.join("kernel/lib/crc16.ko"))
+            .is_ok();
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        kmod.set_auto_depmod(true);
+        let ext4 = kmod.module_from_name("ext4").unwrap();
+        let info = kmod.module_info(&ext4).unwrap();
+        let paths: Vec<_> = info
+            .depends()
+            .iter()
+            .map(|name| kmod.module_from_name(name).unwrap())
+            .map(|module| module.host_path().unwrap().to_path_buf())
+            .collect();
+        let ext4_path = ext4.host_path().unwrap().to_path_buf();
+        let alias = kmod.resolve_alias("ext4").unwrap();
+        let unknown = kmod.module_from_name("btrfs").err();
+
+        drop(ext4);
+        drop(kmod);
+
+        assert!(
+            matches!(missing, Some(KmodError::MissingDepmodIndex(ref path)) if *path == release),
+            "{missing:?}"
+        );
+        assert!(from_path);
+        assert_eq!(ext4_path, release.join("kernel/fs/ext4/ext4.ko"));
+        assert_eq!(
+            paths,
+            [
+                release.join("updates/crc16.ko"),
+                release.join("kernel/fs/mbcache.ko")
+            ]
+        );
+        assert_eq!(alias, ["ext4"]);
+        assert!(matches!(unknown, Some(KmodError::ModuleNotFound(_))));
+    }
+
+    #[test]
+    fn test_install_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        fs::create_dir_all(release.join("kernel/drivers/misc")).unwrap();
+        fs::create_dir_all(dir.join("extra")).unwrap();
+
+        let in_tree = release.join("kernel/drivers/misc/in-tree.ko");
+        let out_of_tree = dir.join("extra/vendor-driver.v2.ko");
+        fake_module(&in_tree, &["name=in_tree"]);
+        fake_module(&out_of_tree, &["name=vendor_driver"]);
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        let in_tree = kmod.module_from_path(&in_tree).unwrap();
+        let out_of_tree = kmod.module_from_path(&out_of_tree).unwrap();
+
+        let in_tree_path = in_tree.install_path().unwrap();
+        let out_of_tree_path = out_of_tree.install_path().unwrap();
+
+        drop((in_tree, out_of_tree));
+        drop(kmod);
+
+        assert_eq!(
+            in_tree_path,
+            Path::new("/usr/lib/modules/6.0.0-elusive/kernel/drivers/misc/in_tree.ko")
+        );
+        assert_eq!(
+            out_of_tree_path,
+            Path::new("/usr/lib/modules/6.0.0-elusive/updates/vendor_driver.ko")
+        );
+    }
+
+    #[test]
+    fn test_is_signed() {
+        let mut data = b"\x7fELF module data".to_vec();
+        assert!(!is_signed(&data));
+
+        data.extend(b"signature");
+        data.extend(MODULE_SIGNATURE_MAGIC);
+        assert!(is_signed(&data));
+    }
+
+    #[test]
+    fn test_from_reader() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let module: Vec<u8> = MAGIC_ELF.iter().copied().chain(0..=255).collect();
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&module).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(module.as_slice(), 0).unwrap();
+
+        for data in [&module, &gzip, &zstd] {
+            // split the magic so that the first read is short
+            let (_, mut reader) = ModuleFormat::from_reader(data[..2].chain(&data[2..])).unwrap();
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, module);
+        }
+
+        let (format, _) = ModuleFormat::from_reader(gzip.as_slice()).unwrap();
+        assert_eq!(format.extension(), "ko.gz");
+        let (format, _) = ModuleFormat::from_reader(zstd.as_slice()).unwrap();
+        assert_eq!(format.extension(), "ko.zst");
+
+        for data in [&[][..], &MAGIC_ELF[..]] {
+            assert!(matches!(
+                ModuleFormat::from_reader(data),
+                Err(KmodError::TooSmallForMagic)
+            ));
+        }
+        assert!(matches!(
+            ModuleFormat::from_reader(&b"garbage"[..]),
+            Err(KmodError::UnknownMagic)
+        ));
     }
 }
@@ -0,0 +1,180 @@
+//! Kernel module lookups through libkmod.
+
+use super::KmodError;
+
+#[allow(clippy::wildcard_imports)]
+use kmod_sys::*;
+
+use std::ffi::{CStr, CString, OsStr};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::ptr;
+
+/// Indexes written by depmod, libkmod cannot look modules up by name without
+/// one of them.
+pub(super) const DEPMOD_INDEXES: &[&str] = &["modules.dep.bin", "modules.dep"];
+
+/// Wrapper handler for libkmod's `kmod_ctx`.
+pub(super) struct Context {
+    inner: *mut kmod_ctx,
+}
+
+impl Context {
+    /// Create a new libkmod context for the module directory.
+    pub(super) fn new(dir: &Path) -> Result<Self, KmodError> {
+        let cstring = CString::new(dir.as_os_str().as_bytes())?;
+        let inner = unsafe { kmod_new(cstring.as_ptr(), ptr::null()) };
+
+        if inner.is_null() {
+            return Err(KmodError::ContextNewFailed);
+        }
+
+        Ok(Context { inner })
+    }
+
+    /// Get every module matching a module name or alias.
+    pub(super) fn lookup(&mut self, name: &str) -> Result<Vec<Handle>, KmodError> {
+        let cstr = CString::new(name)?;
+
+        let mut list: MaybeUninit<*mut kmod_list> = MaybeUninit::zeroed();
+        let mut handles = Vec::new();
+
+        unsafe {
+            let ret = kmod_module_new_from_lookup(self.inner, cstr.as_ptr(), list.as_mut_ptr());
+
+            if ret < 0 {
+                return Err(KmodError::ModuleFromNameFailed(name.to_string()));
+            }
+
+            let list = list.assume_init();
+            let mut item = list;
+
+            while !item.is_null() {
+                let inner = kmod_module_get_module(item);
+
+                if inner.is_null() {
+                    kmod_module_unref_list(list);
+                    return Err(KmodError::ModuleFromNameFailed(name.to_string()));
+                }
+
+                handles.push(Handle { inner });
+                item = kmod_list_next(list, item);
+            }
+
+            kmod_module_unref_list(list);
+        }
+
+        Ok(handles)
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            let ret = kmod_unref(self.inner);
+            assert!(ret.is_null());
+        }
+    }
+}
+
+/// Wrapper handler for libkmod's `kmod_module`.
+pub(super) struct Handle {
+    inner: *mut kmod_module,
+}
+
+impl Handle {
+    /// Create a module from the provided path.
+    pub(super) fn from_path(ctx: &mut Context, path: &Path) -> Result<Self, KmodError> {
+        let data = path.as_os_str().to_os_string().into_vec();
+        let cstr = CString::new(data)?;
+
+        let mut inner: MaybeUninit<*mut kmod_module> = MaybeUninit::uninit();
+
+        let inner = unsafe {
+            let ret = kmod_module_new_from_path(ctx.inner, cstr.as_ptr(), inner.as_mut_ptr());
+
+            if ret < 0 {
+                return Err(KmodError::ModuleFromPathFailed(path.to_path_buf()));
+            }
+
+            inner.assume_init()
+        };
+
+        Ok(Handle { inner })
+    }
+
+    /// Get the name of the module as libkmod reports it.
+    pub(super) fn name(&self) -> Option<&OsStr> {
+        let cstr = unsafe {
+            let name = kmod_module_get_name(self.inner);
+            if name.is_null() {
+                return None;
+            }
+
+            CStr::from_ptr(name)
+        };
+
+        Some(OsStr::from_bytes(cstr.to_bytes()))
+    }
+
+    /// Get the host path of the module, `None` for builtin modules.
+    pub(super) fn path(&self) -> Option<&Path> {
+        let cstr = unsafe {
+            let path = kmod_module_get_path(self.inner);
+            if path.is_null() {
+                return None;
+            }
+
+            CStr::from_ptr(path)
+        };
+
+        Some(Path::new(OsStr::from_bytes(cstr.to_bytes())))
+    }
+
+    /// Get the modinfo entries of the module, as keys and raw values.
+    pub(super) fn info(&self) -> Result<Vec<(String, Vec<u8>)>, KmodError> {
+        let mut list: MaybeUninit<*mut kmod_list> = MaybeUninit::zeroed();
+        let mut entries = Vec::new();
+
+        unsafe {
+            let ret = kmod_module_get_info(self.inner, list.as_mut_ptr());
+            if ret < 0 {
+                let name = self.name().and_then(OsStr::to_str);
+                return Err(KmodError::ModuleGetInfoFailed(
+                    name.unwrap_or(super::UNKNOWN_MODULE).to_string(),
+                ));
+            }
+
+            let list = list.assume_init();
+            let mut item = list;
+
+            while !item.is_null() {
+                let key = CStr::from_ptr(kmod_module_info_get_key(item)).to_str();
+                let value = CStr::from_ptr(kmod_module_info_get_value(item));
+
+                match key {
+                    Ok(key) => entries.push((key.to_string(), value.to_bytes().to_vec())),
+                    Err(err) => {
+                        kmod_module_info_free_list(list);
+                        return Err(err.into());
+                    }
+                }
+
+                item = kmod_list_next(list, item);
+            }
+
+            kmod_module_info_free_list(list);
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            kmod_module_unref(self.inner);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Kernel module lookups without libkmod.
+//!
+//! Modules are looked up in the text indexes written by depmod: names in
+//! `modules.dep`, then `modules.builtin`, then the patterns of
+//! `modules.alias`. Module information is read from the `.modinfo` section of
+//! the module file, decompressed first if needed. Only gzip and zstd
+//! compressed modules can be read, and builtin modules have no information.
+
+use super::{normalize_name, read_builtin, KmodError, ModuleFormat};
+
+use glob::Pattern;
+use log::debug;
+use object::{Object, ObjectSection};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::{fs, str};
+
+/// Text index written by depmod, modules cannot be looked up by name without
+/// it.
+pub(super) const DEPMOD_INDEXES: &[&str] = &["modules.dep"];
+
+/// Aliases of modules written by depmod, such as device modaliases.
+const MODULES_ALIAS: &str = "modules.alias";
+
+/// Section of module files holding `key=value` strings.
+const MODINFO_SECTION: &str = ".modinfo";
+
+/// Indexes of a module directory.
+struct Index {
+    /// Path of each module listed in `modules.dep`, by normalized name.
+    modules: HashMap<String, PathBuf>,
+    /// Alias patterns and the name of the module they resolve to.
+    aliases: Vec<(Pattern, String)>,
+    /// Normalized names of the builtin modules.
+    builtin: BTreeSet<String>,
+}
+
+/// Module directory whose indexes are read on the first lookup.
+pub(super) struct Context {
+    dir: PathBuf,
+    index: Option<Index>,
+}
+
+impl Context {
+    /// Create a new context for the module directory.
+    pub(super) fn new(dir: &Path) -> Result<Self, KmodError> {
+        Ok(Context {
+            dir: dir.to_path_buf(),
+            index: None,
+        })
+    }
+
+    /// Get every module matching a module name or alias.
+    pub(super) fn lookup(&mut self, name: &str) -> Result<Vec<Handle>, KmodError> {
+        let index = self.index()?;
+        let normalized = normalize_name(name);
+
+        if let Some(path) = index.modules.get(&normalized) {
+            return Ok(vec![Handle::new(path.clone())]);
+        }
+
+        if index.builtin.contains(&normalized) {
+            return Ok(vec![Handle::builtin(normalized)]);
+        }
+
+        let mut names = Vec::new();
+        for (_, module) in index
+            .aliases
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(name))
+        {
+            if !names.contains(module) {
+                names.push(module.clone());
+            }
+        }
+
+        let handles = names
+            .into_iter()
+            .filter_map(|module| {
+                let module = normalize_name(&module);
+
+                match index.modules.get(&module) {
+                    Some(path) => Some(Handle::new(path.clone())),
+                    None if index.builtin.contains(&module) => Some(Handle::builtin(module)),
+                    None => None,
+                }
+            })
+            .collect();
+
+        Ok(handles)
+    }
+
+    fn index(&mut self) -> Result<&Index, KmodError> {
+        if self.index.is_none() {
+            debug!(path:% = self.dir.display(); "Reading depmod indexes in: {}", self.dir.display());
+
+            self.index = Some(Index {
+                modules: read_dep(&self.dir)?,
+                aliases: read_aliases(&self.dir)?,
+                builtin: read_builtin(&self.dir)?,
+            });
+        }
+
+        Ok(self.index.as_ref().expect("index is read above"))
+    }
+}
+
+/// Kernel module found in the indexes or from its path.
+pub(super) struct Handle {
+    name: Option<OsString>,
+    path: Option<PathBuf>,
+}
+
+impl Handle {
+    fn new(path: PathBuf) -> Self {
+        Handle {
+            name: module_name(&path),
+            path: Some(path),
+        }
+    }
+
+    fn builtin(name: String) -> Self {
+        Handle {
+            name: Some(name.into()),
+            path: None,
+        }
+    }
+
+    /// Create a module from the provided path.
+    pub(super) fn from_path(_ctx: &mut Context, path: &Path) -> Result<Self, KmodError> {
+        if !path.is_file() {
+            return Err(KmodError::ModuleFromPathFailed(path.to_path_buf()));
+        }
+
+        Ok(Handle::new(path.to_path_buf()))
+    }
+
+    /// Get the name of the module, derived from its file name like libkmod.
+    pub(super) fn name(&self) -> Option<&OsStr> {
+        self.name.as_deref()
+    }
+
+    /// Get the host path of the module, `None` for builtin modules.
+    pub(super) fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Get the modinfo entries of the module, as keys and raw values.
+    pub(super) fn info(&self) -> Result<Vec<(String, Vec<u8>)>, KmodError> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+
+        let failed = || {
+            let name = self.name.as_deref().and_then(OsStr::to_str);
+            KmodError::ModuleGetInfoFailed(name.unwrap_or(super::UNKNOWN_MODULE).to_string())
+        };
+
+        let (_, mut reader) = ModuleFormat::from_reader(fs::File::open(path)?)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let file = object::File::parse(data.as_slice()).map_err(|_| failed())?;
+        let Some(section) = file.section_by_name(MODINFO_SECTION) else {
+            return Ok(Vec::new());
+        };
+        let modinfo = section.data().map_err(|_| failed())?;
+
+        modinfo
+            .split(|&byte| byte == 0)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let separator = entry.iter().position(|&byte| byte == b'=')?;
+                Some((&entry[..separator], &entry[separator + 1..]))
+            })
+            .map(|(key, value)| Ok((str::from_utf8(key)?.to_string(), value.to_vec())))
+            .collect()
+    }
+}
+
+/// Derive the name of a module from its file name, up to the first dot.
+fn module_name(path: &Path) -> Option<OsString> {
+    let name = path.file_name()?.to_str()?.split('.').next()?;
+    (!name.is_empty()).then(|| normalize_name(name).into())
+}
+
+/// Read the module paths listed in `modules.dep`, relative to the module
+/// directory. Dependencies are read from the modules themselves.
+fn read_dep(dir: &Path) -> Result<HashMap<String, PathBuf>, KmodError> {
+    let data = fs::read_to_string(dir.join(DEPMOD_INDEXES[0]))?;
+    let mut modules = HashMap::new();
+
+    for line in data.lines() {
+        let Some((module, _)) = line.split_once(':') else {
+            continue;
+        };
+
+        let path = dir.join(module.trim());
+        if let Some(name) = module_name(&path).and_then(|name| name.into_string().ok()) {
+            modules.entry(name).or_insert(path);
+        }
+    }
+
+    Ok(modules)
+}
+
+/// Read the `alias <pattern> <module>` lines of `modules.alias`, if any.
+fn read_aliases(dir: &Path) -> Result<Vec<(Pattern, String)>, KmodError> {
+    let data = match fs::read_to_string(dir.join(MODULES_ALIAS)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut aliases = Vec::new();
+
+    for line in data.lines() {
+        let mut words = line.split_whitespace();
+
+        let (Some("alias"), Some(alias), Some(module)) = (words.next(), words.next(), words.next())
+        else {
+            continue;
+        };
+
+        match Pattern::new(alias) {
+            Ok(pattern) => aliases.push((pattern, module.to_string())),
+            Err(err) => debug!("Skipping invalid module alias {}: {}", alias, err),
+        }
+    }
+
+    Ok(aliases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::fake_module;
+    use super::super::Kmod;
+    use super::*;
+
+    #[test]
+    fn test_native_lookup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let release = dir.join("6.0.0-elusive");
+        fs::create_dir_all(release.join("kernel/drivers/net")).unwrap();
+        fs::create_dir_all(release.join("kernel/lib")).unwrap();
+
+        fake_module(
+            &release.join("kernel/drivers/net/e1000e.ko"),
+            &[
+                "alias=pci:v00008086d000010D3sv*sd*bc*sc*i*",
+                "depends=crc-itu-t",
+                "softdep=pre: crc32c",
+                "softdep=post: e1000e-extra",
+                "name=e1000e",
+            ],
+        );
+        fake_module(
+            &release.join("kernel/lib/crc-itu-t.ko"),
+            &["name=crc_itu_t"],
+        );
+
+        // compressed modules are read through the decompressing reader
+        let crc = fs::read(release.join("kernel/lib/crc-itu-t.ko")).unwrap();
+        let compressed = zstd::encode_all(crc.as_slice(), 0).unwrap();
+        fs::write(release.join("kernel/lib/crc32c.ko.zst"), compressed).unwrap();
+
+        fs::write(
+            release.join("modules.dep"),
+            "kernel/drivers/net/e1000e.ko: kernel/lib/crc-itu-t.ko\n\
+             kernel/lib/crc-itu-t.ko:\n\
+             kernel/lib/crc32c.ko.zst:\n",
+        )
+        .unwrap();
+        fs::write(
+            release.join(MODULES_ALIAS),
+            "# Aliases extracted from modules themselves.\n\
+             alias pci:v00008086d000010D3sv*sd*bc*sc*i* e1000e\n\
+             alias crc32c-generic crc32c\n\
+             alias fs-ext4 ext4\n",
+        )
+        .unwrap();
+        fs::write(release.join("modules.builtin"), "kernel/fs/ext4/ext4.ko\n").unwrap();
+
+        let mut kmod = Kmod::with_directory(&release).unwrap();
+        let e1000e = kmod.module_from_name("e1000e").unwrap();
+        let info = kmod.module_info(&e1000e).unwrap();
+        let crc = kmod.module_from_name("crc_itu_t").unwrap();
+        let crc32c = kmod.module_from_name("crc32c").unwrap();
+        let crc32c_info = kmod.module_info(&crc32c).unwrap();
+
+        let modalias = kmod
+            .resolve_alias("pci:v00008086d000010D3sv00001028sd00000001bc02sc00i00")
+            .unwrap();
+        let generic = kmod.resolve_alias("crc32c-generic").unwrap();
+        let builtin = kmod.resolve_alias("fs-ext4").unwrap();
+        let unknown = kmod.resolve_alias("pci:v0000FFFFd0000FFFF").unwrap();
+        let missing = kmod.module_from_name("btrfs").err();
+
+        let paths =
+            [&e1000e, &crc, &crc32c].map(|module| module.host_path().map(Path::to_path_buf));
+        let install_path = crc32c.install_path().unwrap();
+
+        drop((e1000e, crc, crc32c));
+        drop(kmod);
+
+        assert_eq!(info.aliases(), ["pci:v00008086d000010D3sv*sd*bc*sc*i*"]);
+        assert_eq!(info.depends(), ["crc-itu-t"]);
+        assert_eq!(info.pre_softdeps(), ["crc32c"]);
+        assert_eq!(info.post_softdeps(), ["e1000e-extra"]);
+        assert_eq!(crc32c_info.aliases(), [] as [&str; 0]);
+
+        assert_eq!(
+            paths,
+            [
+                Some(release.join("kernel/drivers/net/e1000e.ko")),
+                Some(release.join("kernel/lib/crc-itu-t.ko")),
+                Some(release.join("kernel/lib/crc32c.ko.zst")),
+            ]
+        );
+        assert_eq!(
+            install_path,
+            Path::new("/usr/lib/modules/6.0.0-elusive/kernel/lib/crc32c.ko")
+        );
+
+        assert_eq!(modalias, ["e1000e"]);
+        assert_eq!(generic, ["crc32c"]);
+        assert_eq!(builtin, ["ext4"]);
+        assert!(unknown.is_empty());
+        assert!(matches!(missing, Some(KmodError::ModuleNotFound(_))));
+    }
+
+    #[test]
+    fn test_module_name() {
+        let name = |path: &str| module_name(Path::new(path));
+
+        assert_eq!(
+            name("kernel/lib/crc-itu-t.ko.zst"),
+            Some("crc_itu_t".into())
+        );
+        assert_eq!(
+            name("/extra/vendor-driver.v2.ko"),
+            Some("vendor_driver".into())
+        );
+        assert_eq!(name("kernel/.ko"), None);
+    }
+}
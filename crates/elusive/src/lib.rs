@@ -2,19 +2,51 @@
 #![allow(clippy::missing_errors_doc)]
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::must_use_candidate)]
+// the configuration schema is a single large json! invocation
+#![recursion_limit = "256"]
 
 #[doc(hidden)]
 pub mod cli;
 
+pub mod collision;
 pub mod config;
+pub mod console;
+pub mod constraint;
+pub mod crypttab;
+pub mod distro;
+pub mod duplicate;
 pub mod elf;
 pub mod encoder;
+pub mod fragment;
+pub mod hostonly;
+pub mod ignore;
+pub mod init;
 pub mod initramfs;
 pub mod io;
+pub mod kconfig;
 pub mod kmod;
+pub mod library_policy;
+pub mod logger;
+pub mod measurement;
 pub mod microcode;
+pub mod module_policy;
 pub mod newc;
+pub mod package;
+pub mod paths;
+pub mod payload;
+pub mod permissions;
+pub mod plan;
+pub mod provenance;
+pub mod remote;
+pub mod report;
+pub mod signing;
+pub mod size;
+pub mod stats;
 pub mod systemd;
+pub mod template;
 pub mod vfs;
+pub mod xattr;
 
 mod search;
+
+pub use config::loader::{load_initramfs_config, ConfigPaths, ConfigurationError};
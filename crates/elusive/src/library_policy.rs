@@ -0,0 +1,86 @@
+//! Libraries kept out of the dependency closure of ELF files.
+//!
+//! Libraries matching a denylist pattern are not added when an ELF file needs
+//! them (`DT_NEEDED`), and their own dependencies are not followed: libraries
+//! only they need are left out as well. This trims features that are not used
+//! in the initramfs, such as compression formats or journal sealing pulled in
+//! by `libsystemd`, at the risk of binaries failing to load.
+//!
+//! Patterns are matched against library file names, e.g. `liblz4.so*`.
+
+use glob::{Pattern, PatternError};
+use serde::Serialize;
+
+/// Compiled denylist of library names.
+#[derive(Clone, Default, Debug)]
+pub struct LibraryPolicy {
+    deny: Vec<Pattern>,
+    strict: bool,
+}
+
+/// A library left out of the initramfs by the policy.
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+pub struct FilteredLibrary {
+    /// File name of the library.
+    pub name: String,
+    /// Denylist pattern it matches.
+    pub pattern: String,
+    /// ELF file that needs it.
+    pub requested_by: String,
+}
+
+impl LibraryPolicy {
+    /// Compile the denylist.
+    pub fn new<S>(deny: &[S]) -> Result<Self, PatternError>
+    where
+        S: AsRef<str>,
+    {
+        let deny = deny
+            .iter()
+            .map(|pattern| Pattern::new(pattern.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(LibraryPolicy {
+            deny,
+            strict: false,
+        })
+    }
+
+    /// Set whether denied libraries fail the generation instead of being left
+    /// out with a warning.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Check whether denied libraries fail the generation.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Get the denylist pattern matching the library, `None` when it may be
+    /// added.
+    pub fn check(&self, name: &str) -> Option<&str> {
+        self.deny
+            .iter()
+            .find(|pattern| pattern.matches(name))
+            .map(Pattern::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = LibraryPolicy::new(&["liblz4.so*", "libgcrypt.so.20"]).unwrap();
+
+        assert_eq!(policy.check("liblz4.so.1"), Some("liblz4.so*"));
+        assert_eq!(policy.check("libgcrypt.so.20"), Some("libgcrypt.so.20"));
+        assert_eq!(policy.check("libgcrypt.so.21"), None);
+        assert_eq!(policy.check("libc.so.6"), None);
+
+        assert!(LibraryPolicy::new(&["[lib"]).is_err());
+        assert_eq!(LibraryPolicy::default().check("liblz4.so.1"), None);
+    }
+}
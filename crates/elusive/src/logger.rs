@@ -0,0 +1,139 @@
+//! Logging setup for the command line interface.
+//!
+//! Records are either written using the default human readable format of
+//! `env_logger`, or as JSON objects (one per line) that also include the
+//! structured key-values attached to log sites, for build systems to parse.
+
+use env_logger::{Builder, Env};
+use log::kv::{Error, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Number};
+use std::io::Write;
+use std::str::FromStr;
+
+/// Custom error type for log format parsing.
+#[derive(thiserror::Error, Debug)]
+pub enum LogFormatError {
+    #[error("unknown log format: {0}")]
+    UnknownFormat(String),
+}
+
+/// Represents the format used to write log records.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = LogFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            other => Err(LogFormatError::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+/// Initialize the global logger with the provided format.
+pub fn init(format: LogFormat) {
+    let env = Env::default().filter_or("RUST_LOG", "info");
+    let mut builder = Builder::from_env(env);
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", format_json(record)));
+    }
+
+    builder.init();
+}
+
+/// Format a log record as a single line JSON object.
+pub fn format_json(record: &Record) -> String {
+    let mut object = Map::new();
+
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+
+    let mut visitor = JsonVisitor(&mut object);
+    // collecting into a map cannot fail
+    let _ = record.key_values().visit(&mut visitor);
+
+    serde_json::Value::Object(object).to_string()
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(number) = value.to_u64() {
+            serde_json::Value::Number(Number::from(number))
+        } else if let Some(boolean) = value.to_bool() {
+            serde_json::Value::Bool(boolean)
+        } else {
+            serde_json::Value::String(value.to_string())
+        };
+
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+
+    #[test]
+    fn test_fromstr() {
+        assert_eq!(LogFormat::from_str("human").unwrap(), LogFormat::Human);
+        assert_eq!(LogFormat::from_str("json").unwrap(), LogFormat::Json);
+
+        assert!(LogFormat::from_str("someotherformat").is_err());
+    }
+
+    #[test]
+    fn test_format_json() {
+        let kvs: &[(&str, &str)] = &[("path", "/usr/bin/ls"), ("module", "base")];
+        let lines = [
+            format_json(
+                &Record::builder()
+                    .level(Level::Info)
+                    .target("elusive::initramfs")
+                    .args(format_args!("Adding binary: {}", "/usr/bin/ls"))
+                    .key_values(&kvs)
+                    .build(),
+            ),
+            format_json(
+                &Record::builder()
+                    .level(Level::Warn)
+                    .target("elusive::cli")
+                    .args(format_args!("Writing initramfs"))
+                    .key_values(&[("bytes", 42u64)])
+                    .build(),
+            ),
+        ];
+
+        for line in &lines {
+            assert!(!line.contains('\n'));
+
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            for key in ["level", "target", "message"] {
+                assert!(value.get(key).is_some());
+            }
+        }
+
+        let first: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(first["level"], "INFO");
+        assert_eq!(first["message"], "Adding binary: /usr/bin/ls");
+        assert_eq!(first["path"], "/usr/bin/ls");
+        assert_eq!(first["module"], "base");
+
+        let second: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(second["bytes"], 42);
+    }
+}
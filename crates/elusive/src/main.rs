@@ -1,19 +1,27 @@
 #![deny(clippy::all)]
 
 use elusive::cli;
-use elusive::cli::Args;
+use elusive::logger;
+use elusive::logger::LogFormat;
 
 use anyhow::Result;
-use clap::Parser;
-use env_logger::Env;
+use log::error;
+use std::process;
 
 /// Entrypoint of the program
 fn main() -> Result<()> {
-    let env = Env::default().filter_or("RUST_LOG", "info");
-    env_logger::init_from_env(env);
+    let args = cli::parse_args();
 
-    let args = Args::parse();
-    cli::elusive(args)?;
+    let format = args.log_format.unwrap_or_default();
+    logger::init(format);
 
-    Ok(())
+    match cli::elusive(args) {
+        Ok(cli::Status::Complete) => Ok(()),
+        Ok(cli::Status::Degraded(code)) => process::exit(code),
+        Err(err) if format == LogFormat::Json => {
+            error!(code = cli::error_code(&err); "{err:#}");
+            process::exit(1);
+        }
+        Err(err) => Err(err),
+    }
 }
@@ -0,0 +1,140 @@
+//! Measurement manifests for generated images.
+//!
+//! Attestation tooling pre-computes the PCR values a boot will produce from
+//! the SHA-256 digest of the image, as loaded from the `.initrd` section of a
+//! unified kernel image, and the digests of the entries it contains:
+//!
+//! ```json
+//! {
+//!   "image": { "size": 4096, "sha256": "9f86d0..." },
+//!   "entries": [
+//!     { "path": "/init", "type": "file", "size": 512, "sha256": "2c26b4..." },
+//!     { "path": "/bin", "type": "symlink", "size": 7, "sha256": "60303a..." }
+//!   ]
+//! }
+//! ```
+//!
+//! Symlinks are hashed over their target, directories and other entries
+//! without data are not listed.
+//!
+//! Confidential computing tooling (TDX, SEV) instead expects the SHA-384
+//! digest of the uncompressed archive, which only depends on the content of
+//! the image when it is serialized in canonical form.
+
+use crate::newc::Archive;
+use crate::paths::serialize_escaped;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha384};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Digests of an image and of its entries.
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    /// Digest of the whole image, as written.
+    pub image: ImageDigest,
+    /// Digests of the entries of the generated archive, sorted by path.
+    pub entries: Vec<EntryDigest>,
+}
+
+/// Digest of a whole image.
+#[derive(Serialize, Debug)]
+pub struct ImageDigest {
+    /// Size of the image in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 digest.
+    pub sha256: String,
+}
+
+/// Digest of the data of an archive entry.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct EntryDigest {
+    /// Path of the entry in the initramfs.
+    #[serde(serialize_with = "serialize_escaped")]
+    pub path: PathBuf,
+    /// Type of the entry, `file` or `symlink`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Size of the data in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 digest of the data.
+    pub sha256: String,
+}
+
+/// Hash the data of every file and symlink of the archive.
+pub fn entry_digests(archive: &Archive) -> Vec<EntryDigest> {
+    let mut digests: Vec<_> = archive
+        .entries()
+        .iter()
+        .filter_map(|(path, entry)| {
+            let kind = if entry.is_file() {
+                "file"
+            } else if entry.is_symlink() {
+                "symlink"
+            } else {
+                return None;
+            };
+
+            let data = entry.data.as_deref().unwrap_or_default();
+            Some(EntryDigest {
+                path: path.clone(),
+                kind,
+                size: data.len() as u64,
+                sha256: hex(&Sha256::digest(data)),
+            })
+        })
+        .collect();
+
+    digests.sort_by(|l, r| l.path.cmp(&r.path));
+    digests
+}
+
+/// Get the hex encoded SHA-384 digest of a serialized, uncompressed archive.
+pub fn archive_sha384(data: &[u8]) -> String {
+    hex(&Sha384::digest(data))
+}
+
+/// Encode bytes as lowercase hexadecimal.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::vfs::Entry;
+
+    #[test]
+    fn test_entry_digests() {
+        let archive = Archive::from([
+            (PathBuf::from("/usr/bin"), Entry::directory()),
+            (PathBuf::from("/usr/bin/sh"), Entry::file(b"test".to_vec())),
+            (PathBuf::from("/bin"), Entry::symlink("usr/bin")),
+        ]);
+
+        let digests = entry_digests(&archive);
+        assert_eq!(
+            digests,
+            [
+                EntryDigest {
+                    path: PathBuf::from("/bin"),
+                    kind: "symlink",
+                    size: 7,
+                    sha256: hex(&Sha256::digest(b"usr/bin")),
+                },
+                EntryDigest {
+                    path: PathBuf::from("/usr/bin/sh"),
+                    kind: "file",
+                    size: 4,
+                    sha256: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+                        .to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -2,14 +2,17 @@
 //!
 //! This module provides an API to help generating a microcode bundle
 //! for early loading by the Linux kernel according to its initramfs
-//! specification.
+//! specification, or with the individual blobs laid out for late loading
+//! through the reload interface.
 
-use crate::config::Microcode;
-use crate::newc::Archive;
+use crate::config::{Microcode, UcodeLayout};
+use crate::newc::{self, Archive, NewcError};
+use crate::paths::{ImagePath, PathError};
 use crate::vfs::{Entry, Vfs, VfsError};
 
-use log::info;
-use std::path::Path;
+use log::{debug, info};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 /// Path where the blobs will be searched by the Linux kernel.
@@ -20,6 +23,16 @@ const AMD_UCODE_NAME: &str = "AuthenticAMD.bin";
 /// Name of the microcode blob for Intel.
 const INTEL_UCODE_NAME: &str = "GenuineIntel.bin";
 
+/// Directory of the individual AMD blobs for late loading.
+const AMD_FIRMWARE_DIR: &str = "/usr/lib/firmware/amd-ucode";
+/// Directory of the individual Intel blobs for late loading.
+const INTEL_FIRMWARE_DIR: &str = "/usr/lib/firmware/intel-ucode";
+
+/// Name of the standalone AMD archive written by split output.
+pub const AMD_SPLIT_NAME: &str = "amd-ucode.img";
+/// Name of the standalone Intel archive written by split output.
+pub const INTEL_SPLIT_NAME: &str = "intel-ucode.img";
+
 /// Custom error type for microcode archive generation.
 #[derive(thiserror::Error, Debug)]
 pub enum MicrocodeError {
@@ -27,6 +40,26 @@ pub enum MicrocodeError {
     InputOutput(io::Error),
     #[error("vfs error: {0}")]
     Vfs(VfsError),
+    #[error("no {1} microcode blob found in archive: {0}")]
+    MissingBlob(PathBuf, &'static str),
+    #[error("cpio error: {0}")]
+    Newc(NewcError),
+    #[error("invalid path: {0}")]
+    Path(PathError),
+}
+
+impl MicrocodeError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            MicrocodeError::InputOutput(_) => "microcode_io",
+            MicrocodeError::Vfs(_) => "microcode_vfs",
+            MicrocodeError::MissingBlob(..) => "microcode_missing_blob",
+
+            MicrocodeError::Newc(err) => err.code(),
+            MicrocodeError::Path(_) => "microcode_path",
+        }
+    }
 }
 
 impl From<io::Error> for MicrocodeError {
@@ -35,40 +68,73 @@ impl From<io::Error> for MicrocodeError {
     }
 }
 
+impl From<NewcError> for MicrocodeError {
+    fn from(err: NewcError) -> Self {
+        Self::Newc(err)
+    }
+}
+
+impl From<PathError> for MicrocodeError {
+    fn from(err: PathError) -> Self {
+        Self::Path(err)
+    }
+}
+
 impl From<VfsError> for MicrocodeError {
     fn from(err: VfsError) -> Self {
         Self::Vfs(err)
     }
 }
 
+/// Get the path of a blob in the microcode tree, or the tree itself.
+fn ucode_path(name: &str) -> ImagePath {
+    ImagePath::new(Path::new(UCODE_TREE).join(name)).expect("microcode tree is absolute")
+}
+
+/// A microcode blob with the name it is installed under.
+struct Blob {
+    name: OsString,
+    data: Vec<u8>,
+}
+
 /// Builder pattern for microcode bundle generation.
 pub struct MicrocodeBundle {
     /// Flag to check if amd ucode was already added.
     amd: bool,
     /// Flag to check if intel ucode was already added.
     intel: bool,
+    /// How blobs are laid out in the archive.
+    layout: UcodeLayout,
     /// Virtual filesystem built for this microcode archive.
     vfs: Vfs,
 }
 
 impl MicrocodeBundle {
-    /// Create a new bundle.
+    /// Create a new bundle for early loading.
     pub fn new() -> Result<Self, MicrocodeError> {
+        Self::with_layout(UcodeLayout::Early)
+    }
+
+    /// Create a new bundle with the provided layout.
+    pub fn with_layout(layout: UcodeLayout) -> Result<Self, MicrocodeError> {
         let mut vfs = Vfs::new();
 
-        info!("Adding default microcode directory: {}", UCODE_TREE);
-        vfs.create_dir_all(UCODE_TREE)?;
+        if layout == UcodeLayout::Early {
+            info!("Adding default microcode directory: {}", UCODE_TREE);
+            vfs.create_dir_all(&ucode_path(""))?;
+        }
 
         Ok(MicrocodeBundle {
             amd: false,
             intel: false,
+            layout,
             vfs,
         })
     }
 
     /// Create a new bundle from a configuration.
     pub fn from_config(config: &Microcode) -> Result<Self, MicrocodeError> {
-        let mut bundle = MicrocodeBundle::new()?;
+        let mut bundle = MicrocodeBundle::with_layout(config.layout)?;
 
         if let Some(path) = &config.amd_ucode {
             bundle.add_amd_ucode(path)?;
@@ -81,6 +147,28 @@ impl MicrocodeBundle {
         Ok(bundle)
     }
 
+    /// Create a standalone early bundle per configured vendor, along with the
+    /// file name it is written as (e.g. `amd-ucode.img`).
+    pub fn split_from_config(
+        config: &Microcode,
+    ) -> Result<Vec<(&'static str, Self)>, MicrocodeError> {
+        let mut bundles = Vec::new();
+
+        if let Some(path) = &config.amd_ucode {
+            let mut bundle = MicrocodeBundle::new()?;
+            bundle.add_amd_ucode(path)?;
+            bundles.push((AMD_SPLIT_NAME, bundle));
+        }
+
+        if let Some(path) = &config.intel_ucode {
+            let mut bundle = MicrocodeBundle::new()?;
+            bundle.add_intel_ucode(path)?;
+            bundles.push((INTEL_SPLIT_NAME, bundle));
+        }
+
+        Ok(bundles)
+    }
+
     /// Bundle amd microcode from the provided path.
     pub fn add_amd_ucode(&mut self, path: &Path) -> Result<(), MicrocodeError> {
         if self.amd {
@@ -88,12 +176,7 @@ impl MicrocodeBundle {
         }
 
         info!("Bundling AMD microcode");
-
-        let data = bundle_ucode(path)?;
-        let entry = Entry::file(data);
-
-        let path = Path::new(UCODE_TREE).join(AMD_UCODE_NAME);
-        self.vfs.create_entry(path, entry)?;
+        self.add_ucode(path, AMD_UCODE_NAME, AMD_FIRMWARE_DIR)?;
         self.amd = true;
 
         Ok(())
@@ -106,13 +189,32 @@ impl MicrocodeBundle {
         }
 
         info!("Bundling Intel microcode");
+        self.add_ucode(path, INTEL_UCODE_NAME, INTEL_FIRMWARE_DIR)?;
+        self.intel = true;
 
-        let data = bundle_ucode(path)?;
-        let entry = Entry::file(data);
+        Ok(())
+    }
 
-        let path = Path::new(UCODE_TREE).join(INTEL_UCODE_NAME);
-        self.vfs.create_entry(path, entry)?;
-        self.intel = true;
+    // early blobs go to the kernel tree, late ones to the firmware directory
+    fn add_ucode(
+        &mut self,
+        path: &Path,
+        name: &'static str,
+        firmware_dir: &str,
+    ) -> Result<(), MicrocodeError> {
+        let dir = match self.layout {
+            UcodeLayout::Early => ucode_path(""),
+            UcodeLayout::Late => {
+                let dir = ImagePath::new(firmware_dir)?;
+                self.vfs.create_dir_all(&dir)?;
+                dir
+            }
+        };
+
+        for blob in bundle_ucode(path, name, self.layout)? {
+            let dest = dir.join(&blob.name)?;
+            self.vfs.create_entry(&dest, Entry::file(blob.data))?;
+        }
 
         Ok(())
     }
@@ -124,25 +226,97 @@ impl MicrocodeBundle {
     }
 }
 
-/// Bundle multiple vendor specific microcode blobs into a single blob.
-fn bundle_ucode(dir: &Path) -> Result<Vec<u8>, MicrocodeError> {
-    let mut data = Vec::new();
+/// Bundle vendor specific microcode from the provided path.
+///
+/// The path may be a directory of raw blobs, a pre-built microcode cpio image
+/// (e.g. `/boot/amd-ucode.img`) or a single raw blob. For early loading, blobs
+/// are concatenated into a single one with the provided name, while they keep
+/// their original names for late loading.
+fn bundle_ucode(
+    path: &Path,
+    name: &'static str,
+    layout: UcodeLayout,
+) -> Result<Vec<Blob>, MicrocodeError> {
+    let blobs = read_ucode(path, name)?;
+
+    match layout {
+        UcodeLayout::Early => Ok(vec![Blob {
+            name: name.into(),
+            data: blobs.into_iter().flat_map(|blob| blob.data).collect(),
+        }]),
+        UcodeLayout::Late => Ok(blobs),
+    }
+}
+
+/// Read the vendor specific microcode blobs found at the provided path.
+fn read_ucode(path: &Path, name: &'static str) -> Result<Vec<Blob>, MicrocodeError> {
+    if path.is_dir() {
+        return read_ucode_dir(path);
+    }
+
+    let data = fs::read(path)?;
+    if !data.starts_with(newc::MAGIC) {
+        debug!("Using raw microcode blob: {}", path.display());
+
+        let name = path.file_name().unwrap_or(name.as_ref()).into();
+        return Ok(vec![Blob { name, data }]);
+    }
+
+    debug!("Extracting microcode from cpio image: {}", path.display());
+    let archive = Archive::deserialize(&data)?;
+    let blob = Path::new(UCODE_TREE).join(name);
+
+    archive
+        .entries()
+        .iter()
+        .find(|(path, _)| *path == blob)
+        .and_then(|(_, entry)| entry.data.clone())
+        .map(|data| {
+            vec![Blob {
+                name: name.into(),
+                data,
+            }]
+        })
+        .ok_or_else(|| MicrocodeError::MissingBlob(path.to_path_buf(), name))
+}
+
+/// Read multiple vendor specific microcode blobs, sorted by name.
+fn read_ucode_dir(dir: &Path) -> Result<Vec<Blob>, MicrocodeError> {
+    let mut blobs = Vec::new();
 
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
 
         if entry.file_type()?.is_file() {
-            data.extend(fs::read(entry.path())?);
+            blobs.push(Blob {
+                name: entry.file_name(),
+                data: fs::read(entry.path())?,
+            });
         }
     }
 
-    Ok(data)
+    blobs.sort_by(|l, r| l.name.cmp(&r.name));
+    Ok(blobs)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
+    use std::collections::BTreeMap;
+
+    fn early(path: &Path, name: &'static str) -> Result<Vec<u8>, MicrocodeError> {
+        let mut blobs = bundle_ucode(path, name, UcodeLayout::Early)?;
+        Ok(blobs.remove(0).data)
+    }
+
+    fn files(bundle: MicrocodeBundle) -> BTreeMap<PathBuf, Vec<u8>> {
+        let archive = bundle.into_archive();
+        archive
+            .entries()
+            .iter()
+            .filter_map(|(path, entry)| Some((path.clone(), entry.data.clone()?)))
+            .collect()
+    }
 
     #[test]
     fn test_microcode_bundle() -> Result<(), MicrocodeError> {
@@ -156,4 +330,105 @@ mod tests {
         let _ = bundle.into_archive();
         Ok(())
     }
+
+    #[test]
+    fn test_ucode_sources() -> Result<(), MicrocodeError> {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("amd-ucode"))?;
+
+        // directory of raw blobs
+        fs::write(dir.join("amd-ucode/family_17h.bin"), b"ucode")?;
+        let from_dir = early(&dir.join("amd-ucode"), AMD_UCODE_NAME)?;
+
+        // single raw blob
+        fs::write(dir.join("AuthenticAMD.bin"), b"ucode")?;
+        let from_blob = early(&dir.join("AuthenticAMD.bin"), AMD_UCODE_NAME)?;
+
+        // pre-built cpio image
+        let mut bundle = MicrocodeBundle::new()?;
+        bundle.add_amd_ucode(&dir.join("amd-ucode"))?;
+        fs::write(
+            dir.join("amd-ucode.img"),
+            bundle.into_archive().serialize()?,
+        )?;
+        let from_image = early(&dir.join("amd-ucode.img"), AMD_UCODE_NAME)?;
+        let missing = early(&dir.join("amd-ucode.img"), INTEL_UCODE_NAME);
+
+        assert_eq!(from_dir, b"ucode");
+        assert_eq!(from_blob, b"ucode");
+        assert_eq!(from_image, b"ucode");
+        assert!(matches!(missing, Err(MicrocodeError::MissingBlob(..))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ucode_layouts() -> Result<(), MicrocodeError> {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("amd-ucode"))?;
+        fs::create_dir_all(dir.join("intel-ucode"))?;
+        fs::write(dir.join("amd-ucode/microcode_amd_fam19h.bin"), b"19h")?;
+        fs::write(dir.join("amd-ucode/microcode_amd_fam17h.bin"), b"17h")?;
+        fs::write(dir.join("intel-ucode/06-8e-09"), b"intel")?;
+
+        let config = |layout| Microcode {
+            amd_ucode: Some(dir.join("amd-ucode")),
+            intel_ucode: Some(dir.join("intel-ucode")),
+            layout,
+        };
+
+        let early = files(MicrocodeBundle::from_config(&config(UcodeLayout::Early))?);
+        let late = files(MicrocodeBundle::from_config(&config(UcodeLayout::Late))?);
+        let split = MicrocodeBundle::split_from_config(&config(UcodeLayout::Late))?;
+
+        // blobs are concatenated in name order
+        let expected = BTreeMap::from([
+            (
+                PathBuf::from("/kernel/x86/microcode/AuthenticAMD.bin"),
+                b"17h19h".to_vec(),
+            ),
+            (
+                PathBuf::from("/kernel/x86/microcode/GenuineIntel.bin"),
+                b"intel".to_vec(),
+            ),
+        ]);
+        assert_eq!(early, expected);
+
+        let expected = BTreeMap::from([
+            (
+                PathBuf::from("/usr/lib/firmware/amd-ucode/microcode_amd_fam17h.bin"),
+                b"17h".to_vec(),
+            ),
+            (
+                PathBuf::from("/usr/lib/firmware/amd-ucode/microcode_amd_fam19h.bin"),
+                b"19h".to_vec(),
+            ),
+            (
+                PathBuf::from("/usr/lib/firmware/intel-ucode/06-8e-09"),
+                b"intel".to_vec(),
+            ),
+        ]);
+        assert_eq!(late, expected);
+
+        // split bundles are always early and hold a single vendor
+        let split: BTreeMap<_, _> = split
+            .into_iter()
+            .map(|(name, bundle)| (name, files(bundle)))
+            .collect();
+        assert_eq!(
+            split[AMD_SPLIT_NAME],
+            BTreeMap::from([(ucode_path(AMD_UCODE_NAME).to_path_buf(), b"17h19h".to_vec())])
+        );
+        assert_eq!(
+            split[INTEL_SPLIT_NAME],
+            BTreeMap::from([(
+                ucode_path(INTEL_UCODE_NAME).to_path_buf(),
+                b"intel".to_vec()
+            )])
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,128 @@
+//! Kernel modules kept out of the initramfs.
+//!
+//! Modules matching a denylist pattern are never added, not even as a
+//! dependency or soft dependency of an allowed module: the dependency edge is
+//! cut and the dependent is added without it. With an allowlist, modules
+//! matching none of its patterns are denied as well.
+//!
+//! Patterns are matched against module names, where dashes and underscores
+//! are interchangeable like they are for the kernel.
+
+use glob::{Pattern, PatternError};
+use std::fmt;
+
+/// Compiled denylist and allowlist of kernel module names.
+#[derive(Clone, Default, Debug)]
+pub struct ModulePolicy {
+    deny: Vec<Pattern>,
+    allow: Option<Vec<Pattern>>,
+    strict: bool,
+}
+
+/// Why a kernel module is denied.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Denial {
+    /// The module matches a denylist pattern.
+    Denylist(String),
+    /// The module matches none of the allowlist patterns.
+    NotAllowed,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::Denylist(pattern) => write!(f, "denylist pattern '{pattern}'"),
+            Denial::NotAllowed => write!(f, "not in allowlist"),
+        }
+    }
+}
+
+/// A kernel module left out of the initramfs by the policy.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FilteredModule {
+    /// Name of the module.
+    pub name: String,
+    /// Why it was left out.
+    pub denial: Denial,
+    /// What requested it: the dependent module, or how it was added.
+    pub requested_by: String,
+}
+
+impl ModulePolicy {
+    /// Compile the denylist and, if any, the allowlist.
+    pub fn new<S>(deny: &[S], allow: Option<&[S]>) -> Result<Self, PatternError>
+    where
+        S: AsRef<str>,
+    {
+        let compile = |patterns: &[S]| {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern.as_ref()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(ModulePolicy {
+            deny: compile(deny)?,
+            allow: allow.map(compile).transpose()?,
+            strict: false,
+        })
+    }
+
+    /// Set whether denied modules fail the generation instead of being left
+    /// out with a warning.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Check whether denied modules fail the generation.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Get why the module is denied, `None` when it may be added.
+    pub fn check(&self, name: &str) -> Option<Denial> {
+        let spellings = [
+            name.to_string(),
+            name.replace('-', "_"),
+            name.replace('_', "-"),
+        ];
+        let matches = |pattern: &Pattern| spellings.iter().any(|name| pattern.matches(name));
+
+        if let Some(pattern) = self.deny.iter().find(|pattern| matches(pattern)) {
+            return Some(Denial::Denylist(pattern.as_str().to_string()));
+        }
+
+        match &self.allow {
+            Some(allow) if !allow.iter().any(matches) => Some(Denial::NotAllowed),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let deny = ModulePolicy::new(&["dccp*", "firewire-*"], None).unwrap();
+        assert_eq!(
+            deny.check("dccp_ipv4"),
+            Some(Denial::Denylist("dccp*".to_string()))
+        );
+        assert_eq!(
+            deny.check("firewire_core"),
+            Some(Denial::Denylist("firewire-*".to_string()))
+        );
+        assert_eq!(deny.check("ext4"), None);
+
+        let allow = ModulePolicy::new(&["ext4"], Some(&["ext4", "jbd2", "crc*"])).unwrap();
+        assert_eq!(allow.check("jbd2"), None);
+        assert_eq!(allow.check("crc32c_generic"), None);
+        assert_eq!(allow.check("btrfs"), Some(Denial::NotAllowed));
+        assert!(allow.check("ext4").is_some());
+
+        assert!(ModulePolicy::new(&["[dccp"], None).is_err());
+        assert_eq!(ModulePolicy::default().check("dccp"), None);
+    }
+}
@@ -3,43 +3,500 @@
 //! This module implements the cpio newc format
 //! that can be used with the Linux kernel to
 //! load an initramfs.
+//!
+//! Archives are written with the `070701` magic by default, or with the
+//! `070702` magic (see [`Format::Crc`]) where the header of each regular file
+//! carries the sum of its data bytes. The kernel unpacker in
+//! `init/initramfs.c` accepts both magics and, for `070702` entries, compares
+//! the sum with the data it unpacked, failing with "bad data checksum" on a
+//! mismatch. Kernels whose unpacker only knows `070701` stop at the first
+//! entry with "no cpio magic", check the target kernel before switching.
 
-use crate::vfs::{Entry, Metadata};
+use crate::paths::escaped;
+use crate::vfs::{self, DiffEntry, Entry, Metadata};
 
-use log::trace;
-use std::ffi::CString;
-use std::io;
+use log::{trace, warn};
+use serde::Deserialize;
+use std::collections::hash_map::Entry as MapEntry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::str;
 
 /// Magic number for newc cpio files.
-const MAGIC: &[u8] = b"070701";
+pub const MAGIC: &[u8] = b"070701";
+/// Magic number for newc cpio files with checksums.
+pub const MAGIC_CRC: &[u8] = b"070702";
+/// Length of a newc header: magic followed by 13 fields of 8 hex digits.
+const HEADER_LEN: usize = 6 + 13 * 8;
 /// Magic bytes for cpio trailer entries.
 const TRAILER: &str = "TRAILER!!!";
+/// Canonical archives are padded to a multiple of this size, so that their
+/// digest does not depend on how they are compressed.
+const CANONICAL_BLOCK: usize = 512;
+
+/// Longest file name the kernel unpacks, terminating nul included.
+const PATH_MAX: usize = 4096;
+/// Hardlinked data is copied to every member of its group when parsing, the
+/// copies may not add up to more than this many bytes.
+const MAX_HARDLINK_DATA: usize = 1 << 30;
 
 /// Offset for inode number to avoid reserved inodes (arbitrary).
+///
+/// Inode numbers only depend on the sorted list of paths: the entry at index
+/// `i` of the archive sorted by path (root excluded) gets `INO_OFFSET + i`,
+/// hardlinked entries share the inode of their lexicographically-first member
+/// and the trailer gets the next free index. Changing the offset changes the
+/// bytes of every generated archive.
 const INO_OFFSET: u64 = 1337;
 
+/// Variant of the newc format an archive is written in.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// Plain newc, magic `070701`, checksum fields are zero.
+    #[default]
+    Newc,
+    /// Newc with checksums, magic `070702`, regular files carry the sum of
+    /// their data bytes (see [`checksum`]) and other entries zero.
+    Crc,
+}
+
+impl Format {
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Format::Newc => MAGIC,
+            Format::Crc => MAGIC_CRC,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum NewcError {
+    #[error("path in the archive must be absolute: {}", escaped(.0))]
+    RelativePath(PathBuf),
+    #[error("path in the archive contains a nul byte: {0:?}")]
+    NulInPath(PathBuf),
+    #[error("path in the archive is longer than {PATH_MAX} bytes: {0:?}")]
+    PathTooLong(PathBuf),
+    #[error("path in the archive is reserved for the trailer: {}", escaped(.0))]
+    ReservedPath(PathBuf),
+    #[error("{field} of {} does not fit in a newc header: {value}", escaped(.path))]
+    FieldOverflow {
+        path: PathBuf,
+        field: &'static str,
+        value: u64,
+    },
+    #[error("invalid cpio archive: {0}")]
+    InvalidArchive(&'static str),
+    #[error("checksum of {} is {actual:08x}, header says {expected:08x}", escaped(.path))]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl NewcError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            NewcError::RelativePath(_) => "newc_relative_path",
+            NewcError::NulInPath(_) => "newc_nul_in_path",
+            NewcError::PathTooLong(_) => "newc_path_too_long",
+            NewcError::ReservedPath(_) => "newc_reserved_path",
+            NewcError::FieldOverflow { .. } => "newc_field_overflow",
+            NewcError::InvalidArchive(_) => "newc_invalid_archive",
+            NewcError::ChecksumMismatch { .. } => "newc_checksum_mismatch",
+        }
+    }
+}
+
 /// Represents a cpio archive.
 #[derive(PartialEq, Debug)]
 pub struct Archive {
     entries: Vec<(PathBuf, Entry)>,
+    hardlinks: Vec<BTreeSet<PathBuf>>,
+    canonical: bool,
+    format: Format,
 }
 
 impl Archive {
+    /// Parse a cpio archive in newc format. Entries after the trailer are ignored.
+    ///
+    /// The archive may come from anywhere: malformed input is reported as
+    /// [`NewcError::InvalidArchive`], and memory used is bounded by the input
+    /// size plus [`MAX_HARDLINK_DATA`]. The checksums of regular files written
+    /// with the `070702` magic are verified, the format of the archive is the
+    /// one of its first entry.
+    pub fn deserialize(data: &[u8]) -> Result<Self, NewcError> {
+        let mut entries = Vec::new();
+        let mut inodes: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        let mut offset = 0;
+        let mut format = None;
+
+        loop {
+            let header = slice(data, offset, HEADER_LEN)
+                .ok_or(NewcError::InvalidArchive("truncated header"))?;
+
+            let crc = match &header[..6] {
+                MAGIC => false,
+                MAGIC_CRC => true,
+                _ => return Err(NewcError::InvalidArchive("bad magic")),
+            };
+            format.get_or_insert(if crc { Format::Crc } else { Format::Newc });
+
+            let field = |index: usize| -> Result<u64, NewcError> {
+                let start = 6 + index * 8;
+                let hex = &header[start..start + 8];
+
+                // from_str_radix accepts a leading sign, header fields do not
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(NewcError::InvalidArchive("bad header field"));
+                }
+
+                let hex = str::from_utf8(hex).expect("hex digits are ascii");
+                Ok(u64::from_str_radix(hex, 16).expect("8 hex digits fit in u64"))
+            };
+
+            let metadata = Metadata {
+                mode: field(1)? as u32,
+                uid: field(2)?,
+                gid: field(3)?,
+                nlink: field(4)?,
+                mtime: field(5)?,
+                dev_major: field(7)?,
+                dev_minor: field(8)?,
+                rdev_major: field(9)?,
+                rdev_minor: field(10)?,
+            };
+
+            let file_size =
+                usize::try_from(field(6)?).map_err(|_| NewcError::InvalidArchive("bad size"))?;
+            let filename_len = usize::try_from(field(11)?)
+                .map_err(|_| NewcError::InvalidArchive("bad name size"))?;
+
+            offset += HEADER_LEN;
+            let filename = slice(data, offset, filename_len)
+                .ok_or(NewcError::InvalidArchive("truncated file name"))?;
+
+            // file name is nul terminated
+            let filename = filename.strip_suffix(&[0]).unwrap_or(filename);
+            if filename.contains(&0) {
+                return Err(NewcError::InvalidArchive("nul byte in file name"));
+            }
+            offset = align(offset + filename_len);
+
+            if filename == TRAILER.as_bytes() {
+                break;
+            }
+
+            let file = slice(data, offset, file_size)
+                .ok_or(NewcError::InvalidArchive("truncated file data"))?;
+            offset = align(offset + file_size);
+
+            let path = Path::new("/").join(OsStr::from_bytes(filename));
+            if crc && metadata.mode & 0o170_000 == 0o100_000 {
+                let expected = field(12)? as u32;
+                let actual = checksum(file);
+
+                if actual != expected {
+                    return Err(NewcError::ChecksumMismatch {
+                        path,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+
+            let data = if metadata.mode & 0o170_000 == 0o040_000 {
+                None
+            } else {
+                // entries sharing an inode are hardlinks of each other
+                if metadata.nlink > 1 {
+                    inodes.entry(field(0)?).or_default().push(entries.len());
+                }
+
+                Some(file.to_vec())
+            };
+
+            entries.push((path, Entry { metadata, data }));
+        }
+
+        let mut hardlinks = Vec::new();
+        let mut expanded = 0usize;
+
+        for members in inodes.into_values().filter(|members| members.len() > 1) {
+            // only one member holds the data, whichever the archiver picked
+            let data = members
+                .iter()
+                .filter_map(|&index| entries[index].1.data.clone())
+                .find(|data| !data.is_empty());
+
+            let copies = data.as_ref().map_or(0, Vec::len) * (members.len() - 1);
+            expanded = expanded.saturating_add(copies);
+            if expanded > MAX_HARDLINK_DATA {
+                return Err(NewcError::InvalidArchive("too many hardlinks"));
+            }
+
+            for &index in &members {
+                entries[index].1.data = Some(data.clone().unwrap_or_default());
+            }
+
+            hardlinks.push(
+                members
+                    .iter()
+                    .map(|&index| entries[index].0.clone())
+                    .collect(),
+            );
+        }
+
+        Ok(Archive {
+            entries,
+            hardlinks,
+            canonical: false,
+            format: format.unwrap_or_default(),
+        })
+    }
+
+    /// Get the entries of this archive.
+    pub fn entries(&self) -> &[(PathBuf, Entry)] {
+        &self.entries
+    }
+
+    /// Get the groups of paths hardlinked together.
+    pub fn hardlinks(&self) -> &[BTreeSet<PathBuf>] {
+        &self.hardlinks
+    }
+
+    /// Hardlink regular files of the archive together, groups sharing a path
+    /// are merged. Paths that are not regular files of the archive are ignored
+    /// when serializing.
+    pub fn add_hardlinks<I, P>(&mut self, paths: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        let mut group: BTreeSet<PathBuf> = paths.into_iter().map(Into::into).collect();
+
+        self.hardlinks.retain(|other| {
+            if other.is_disjoint(&group) {
+                return true;
+            }
+
+            group.extend(other.iter().cloned());
+            false
+        });
+
+        self.hardlinks.push(group);
+    }
+
+    /// Move the regular files matching the predicate to a new archive, meant to
+    /// be serialized as a separate segment extracted after this one, which
+    /// keeps every directory. Hardlinked files stay, links do not cross
+    /// segments.
+    pub fn split_off<F>(&mut self, mut predicate: F) -> Archive
+    where
+        F: FnMut(&Path, &Entry) -> bool,
+    {
+        let groups = self.hardlink_groups();
+        let (split, kept) = self.entries.drain(..).partition(|(path, entry)| {
+            entry.is_file() && !groups.contains_key(path) && predicate(path, entry)
+        });
+
+        self.entries = kept;
+
+        Archive {
+            entries: split,
+            hardlinks: Vec::new(),
+            canonical: self.canonical,
+            format: self.format,
+        }
+    }
+
+    /// Set whether the archive is serialized in canonical form, for images
+    /// measured before boot (e.g. confidential computing). Only modes, device
+    /// numbers of special files, hardlinks and data are kept: modification
+    /// times, owners and device numbers of the host filesystem are zeroed
+    /// whatever the entries hold, link counts are 1 except for hardlinked files,
+    /// and the archive is padded with zeroes to a multiple of 512 bytes.
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    /// Get the format this archive is serialized in.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Set the format this archive is serialized in. Headers have the same
+    /// size in both formats, so trailer, padding and entry sizes are the same.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Get the number of bytes each entry takes once serialized, header and
+    /// padding included. The implicit root is left out, as well as the trailer
+    /// and canonical padding, so sizes add up to less than the serialized
+    /// archive. Hardlinked files only count their data for the member that
+    /// carries it.
+    pub fn entry_sizes(&self) -> BTreeMap<PathBuf, u64> {
+        let groups = self.hardlink_groups();
+        let mut carried = HashSet::new();
+        let mut sizes = BTreeMap::new();
+
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(path, _)| path != Path::new("/"))
+            .collect();
+        entries.sort_by(|l, r| l.0.cmp(&r.0));
+
+        for (path, entry) in entries {
+            let name_len = path.as_os_str().len(); // leading / replaced by nul
+            let mut data_len = entry.data.as_ref().map_or(0, Vec::len);
+
+            if let Some(&(group, _)) = groups.get(path) {
+                if !carried.insert(group) {
+                    data_len = 0;
+                }
+            }
+
+            let size = align(HEADER_LEN + name_len) + align(data_len);
+            sizes.insert(path.clone(), size as u64);
+        }
+
+        sizes
+    }
+
+    /// Compute the differences between this archive and another one.
+    pub fn diff(&self, other: &Archive) -> Vec<DiffEntry> {
+        let left = self.entries.iter().map(|(path, entry)| (path, entry));
+        let right = other.entries.iter().map(|(path, entry)| (path, entry));
+
+        vfs::diff_entries(left, right)
+    }
+
     /// Serialize this entry into cpio newc format.
-    pub fn serialize(mut self) -> Result<Vec<u8>, io::Error> {
+    ///
+    /// Link counts are computed from the content of the archive: directories
+    /// have one link for themselves, one from their parent and one from each
+    /// child directory, hardlinked files have one link per member of their
+    /// group and other entries have a single link.
+    ///
+    /// Entries are sorted by path before inodes are assigned, so the output
+    /// does not depend on the order entries were added in (see [`INO_OFFSET`]).
+    /// The data of hardlinked files is written with the first member only,
+    /// the kernel links the following ones to it.
+    ///
+    /// Entries the kernel would misread are rejected: paths must be absolute,
+    /// without nul bytes and at most [`PATH_MAX`] bytes long, and numbers must
+    /// fit in the 32 bits of their header field. Modification times are the
+    /// exception and are clamped with a warning, they are zero in canonical
+    /// archives anyway.
+    pub fn serialize(mut self) -> Result<Vec<u8>, NewcError> {
+        // root is implicit in the archive
+        self.entries.retain(|(path, _)| path != Path::new("/"));
         self.entries.sort_by(|l, r| l.0.cmp(&r.0));
 
-        let mut newc = NewcSerializer::new();
-        for (path, entry) in self.entries {
-            newc.serialize_entry(&path, entry)?;
+        let subdirs = self.count_subdirs();
+        let groups = self.hardlink_groups();
+        let mut group_inodes = HashMap::new();
+        let count = self.entries.len() as u64;
+
+        let mut newc = NewcSerializer::new(self.format);
+        for (index, (path, mut entry)) in self.entries.into_iter().enumerate() {
+            let mut ino = INO_OFFSET + index as u64;
+
+            entry.metadata.nlink = if entry.is_dir() {
+                2 + subdirs.get(path.as_path()).copied().unwrap_or(0)
+            } else {
+                1
+            };
+
+            if self.canonical {
+                entry.metadata = Metadata {
+                    mode: entry.metadata.mode,
+                    nlink: 1,
+                    rdev_major: entry.metadata.rdev_major,
+                    rdev_minor: entry.metadata.rdev_minor,
+                    ..Default::default()
+                };
+            }
+
+            if let Some(&(group, size)) = groups.get(&path) {
+                entry.metadata.nlink = size;
+
+                match group_inodes.entry(group) {
+                    MapEntry::Occupied(first) => {
+                        ino = *first.get();
+                        entry.data = Some(Vec::new());
+                    }
+                    MapEntry::Vacant(first) => {
+                        first.insert(ino);
+                    }
+                }
+            }
+
+            let name = entry_name(&path)?;
+            newc.serialize_entry(&path, name, ino, entry)?;
         }
 
         // add trailer entry at the end of the archive
-        newc.serialize_entry(Path::new(TRAILER), Entry::directory())?;
-        Ok(newc.into_inner())
+        let mut trailer = Entry::directory();
+        trailer.metadata.nlink = 1;
+        let path = Path::new(TRAILER);
+        newc.serialize_entry(path, TRAILER.as_bytes(), INO_OFFSET + count, trailer)?;
+
+        let mut buf = newc.into_inner();
+        if self.canonical {
+            buf.resize(buf.len().next_multiple_of(CANONICAL_BLOCK), 0);
+        }
+
+        Ok(buf)
+    }
+
+    // group index and size of every hardlinked regular file of the archive
+    fn hardlink_groups(&self) -> HashMap<PathBuf, (usize, u64)> {
+        let files: HashSet<&Path> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_file())
+            .map(|(path, _)| path.as_path())
+            .collect();
+
+        let mut groups = HashMap::new();
+        for (index, group) in self.hardlinks.iter().enumerate() {
+            let members: Vec<_> = group
+                .iter()
+                .filter(|path| files.contains(path.as_path()))
+                .collect();
+
+            if members.len() < 2 {
+                continue;
+            }
+
+            for member in &members {
+                groups.insert((*member).clone(), (index, members.len() as u64));
+            }
+        }
+
+        groups
+    }
+
+    // number of child directories for each directory of the archive
+    fn count_subdirs(&self) -> HashMap<PathBuf, u64> {
+        let mut subdirs = HashMap::new();
+
+        for (path, entry) in &self.entries {
+            if let (true, Some(parent)) = (entry.is_dir(), path.parent()) {
+                *subdirs.entry(parent.to_path_buf()).or_default() += 1;
+            }
+        }
+
+        subdirs
     }
 }
 
@@ -50,28 +507,36 @@ where
     fn from(value: T) -> Self {
         let entries = value.into_iter().collect();
 
-        Archive { entries }
+        Archive {
+            entries,
+            hardlinks: Vec::new(),
+            canonical: false,
+            format: Format::default(),
+        }
     }
 }
 
+/// Writer of newc entries, inodes are assigned by [`Archive::serialize`].
 struct NewcSerializer {
-    count: u64,
     buf: Vec<u8>,
+    format: Format,
 }
 
 impl NewcSerializer {
-    fn new() -> Self {
+    fn new(format: Format) -> Self {
         NewcSerializer {
-            count: 0,
             buf: Vec::new(),
+            format,
         }
     }
 
-    fn serialize_entry(&mut self, path: &Path, entry: Entry) -> Result<(), io::Error> {
-        if path == Path::new("/") {
-            return Ok(());
-        }
-
+    fn serialize_entry(
+        &mut self,
+        path: &Path,
+        name: &[u8],
+        ino: u64,
+        entry: Entry,
+    ) -> Result<(), NewcError> {
         trace!("Serializing entry: {:?}", entry);
         let Metadata {
             mode,
@@ -85,46 +550,45 @@ impl NewcSerializer {
             rdev_minor,
         } = entry.metadata;
 
-        // get rid of root / for non-trailer entries
-        let path = if path == Path::new(TRAILER) {
-            path
-        } else {
-            path.strip_prefix("/").expect("path is under root")
-        };
-
-        // serialize the header for this entry
-        let filename = CString::new(path.as_os_str().as_bytes())?.into_bytes_with_nul();
-        let filename_len = filename.len();
-
-        let ino = self.count + INO_OFFSET;
-        self.count += 1;
-
         let file_size = match &entry.data {
             Some(data) => data.len(),
             None => 0,
         };
 
-        // magic + 8 * fields + filename + file
-        self.buf.reserve(6 + (13 * 8) + filename.len() + file_size);
-        self.buf.write_all(MAGIC)?;
-        write!(self.buf, "{ino:08x}")?;
-        write!(self.buf, "{mode:08x}")?;
-        write!(self.buf, "{uid:08x}")?;
-        write!(self.buf, "{gid:08x}")?;
-        write!(self.buf, "{nlink:08x}")?;
-        write!(self.buf, "{mtime:08x}")?;
-        write!(self.buf, "{file_size:08x}")?;
-        write!(self.buf, "{dev_major:08x}")?;
-        write!(self.buf, "{dev_minor:08x}")?;
-        write!(self.buf, "{rdev_major:08x}")?;
-        write!(self.buf, "{rdev_minor:08x}")?;
-        write!(self.buf, "{filename_len:08x}")?;
-        write!(self.buf, "{:08x}", 0)?; // CRC, null bytes with our MAGIC
-        self.buf.write_all(&filename)?;
+        let crc = match (&entry.data, self.format) {
+            (Some(data), Format::Crc) if entry.is_file() => checksum(data),
+            _ => 0,
+        };
+
+        let field = |field, value| header_field(path, field, value);
+        let fields = [
+            field("inode", ino)?,
+            mode,
+            field("uid", uid)?,
+            field("gid", gid)?,
+            field("link count", nlink)?,
+            header_mtime(path, mtime),
+            field("size", file_size as u64)?,
+            field("device major", dev_major)?,
+            field("device minor", dev_minor)?,
+            field("rdev major", rdev_major)?,
+            field("rdev minor", rdev_minor)?,
+            (name.len() + 1) as u32, // nul terminated
+            crc,
+        ];
+
+        // magic + 13 fields + filename + file
+        self.buf.reserve(HEADER_LEN + name.len() + 1 + file_size);
+        self.buf.extend_from_slice(self.format.magic());
+        for field in fields {
+            write!(self.buf, "{field:08x}").expect("writing to a vec cannot fail");
+        }
+        self.buf.extend_from_slice(name);
+        self.buf.push(0);
         pad_buf(&mut self.buf);
 
         if let Some(data) = entry.data {
-            self.buf.write_all(&data)?;
+            self.buf.extend_from_slice(&data);
             pad_buf(&mut self.buf);
         }
 
@@ -136,6 +600,68 @@ impl NewcSerializer {
     }
 }
 
+/// Checksum of the data of a regular file in a `070702` archive: the sum of
+/// its bytes, wrapping at 32 bits.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0u32, |sum, &byte| sum.wrapping_add(u32::from(byte)))
+}
+
+// name of an entry in the archive, its path without the leading /
+fn entry_name(path: &Path) -> Result<&[u8], NewcError> {
+    let name = path
+        .strip_prefix("/")
+        .map_err(|_| NewcError::RelativePath(path.to_path_buf()))?
+        .as_os_str()
+        .as_bytes();
+
+    if name.contains(&0) {
+        return Err(NewcError::NulInPath(path.to_path_buf()));
+    }
+
+    if name.len() >= PATH_MAX {
+        return Err(NewcError::PathTooLong(path.to_path_buf()));
+    }
+
+    // the kernel stops unpacking at the trailer, whatever follows it
+    if name == TRAILER.as_bytes() {
+        return Err(NewcError::ReservedPath(path.to_path_buf()));
+    }
+
+    Ok(name)
+}
+
+fn header_field(path: &Path, field: &'static str, value: u64) -> Result<u32, NewcError> {
+    u32::try_from(value).map_err(|_| NewcError::FieldOverflow {
+        path: path.to_path_buf(),
+        field,
+        value,
+    })
+}
+
+// modification times after 2106 do not fit in the header, unlike other
+// fields this is not worth failing the archive for
+fn header_mtime(path: &Path, mtime: u64) -> u32 {
+    u32::try_from(mtime).unwrap_or_else(|_| {
+        warn!(
+            "Modification time {} of {} does not fit in 32 bits, using {}",
+            mtime,
+            escaped(path),
+            u32::MAX
+        );
+        u32::MAX
+    })
+}
+
+// bytes of `data` from `offset`, or none if it is too short
+fn slice(data: &[u8], offset: usize, len: usize) -> Option<&[u8]> {
+    data.get(offset..)?.get(..len)
+}
+
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
 // pad the buffer so entries align according to cpio requirements.
 fn pad_buf(buf: &mut Vec<u8>) {
     let rem = buf.len() % 4;
@@ -149,18 +675,592 @@ fn pad_buf(buf: &mut Vec<u8>) {
 mod tests {
     use super::*;
 
-    use crate::vfs::Entry;
+    use crate::paths::ImagePath;
+    use crate::vfs::{Entry, Vfs};
+
+    use std::{env, fs};
+
+    fn image(path: &str) -> ImagePath {
+        ImagePath::new(path).unwrap()
+    }
+
+    /// Hex dump of the archive built by `golden_vfs`.
+    const GOLDEN: &str = include_str!("../testdata/newc.hex");
+
+    // small fixed tree with file names and data lengths hitting every
+    // alignment offset, and a name long enough to need two hex digits
+    fn golden_vfs() -> Vfs {
+        let mut vfs = Vfs::new();
+        vfs.create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+            .unwrap();
+        vfs.create_dir_all(&image("/etc")).unwrap();
+        vfs.create_entry(&image("/etc/a"), Entry::file(b"a".to_vec()))
+            .unwrap();
+        vfs.create_entry(&image("/etc/ab"), Entry::file(b"ab".to_vec()))
+            .unwrap();
+        vfs.create_entry(&image("/etc/abc"), Entry::file(b"abc".to_vec()))
+            .unwrap();
+        vfs.create_entry(&image("/etc/abcd"), Entry::file(b"abcd".to_vec()))
+            .unwrap();
+        vfs.create_entry(
+            &image("/etc/long-file-name.conf"),
+            Entry::file(b"key=value\n".to_vec()),
+        )
+        .unwrap();
+
+        let mut device = Entry {
+            data: Some(Vec::new()),
+            ..Default::default()
+        };
+        device.metadata.mode = 0o020_600;
+        device.metadata.nlink = 1;
+        device.metadata.rdev_major = 5;
+        device.metadata.rdev_minor = 1;
+        vfs.create_entry(&image("/console"), device).unwrap();
+
+        vfs
+    }
+
+    fn to_hex(data: &[u8]) -> String {
+        data.chunks(16)
+            .map(|chunk| chunk.iter().map(|byte| format!("{byte:02x}")).collect())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    // set ELUSIVE_UPDATE_GOLDEN to regenerate the checked-in dump
+    fn assert_golden(actual: &[u8], golden: &str, path: &str) {
+        if env::var_os("ELUSIVE_UPDATE_GOLDEN").is_some() {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+            fs::write(path, to_hex(actual) + "\n").unwrap();
+            return;
+        }
+
+        let expected = from_hex(golden);
+        if actual == expected {
+            return;
+        }
+
+        let offset = actual
+            .iter()
+            .zip(&expected)
+            .position(|(l, r)| l != r)
+            .unwrap_or(actual.len().min(expected.len()));
+
+        let diff: Vec<_> = match (
+            Archive::deserialize(&expected),
+            Archive::deserialize(actual),
+        ) {
+            (Ok(expected), Ok(actual)) => expected
+                .diff(&actual)
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        panic!(
+            "archive differs from {path} at byte {offset} ({} != {} bytes)\n{}",
+            expected.len(),
+            actual.len(),
+            diff.join("\n")
+        );
+    }
 
     #[test]
     fn test_serialize() {
-        let mut serializer = NewcSerializer::new();
+        let mut serializer = NewcSerializer::new(Format::Newc);
 
         let entry = Entry::file(b"data".to_vec());
         serializer
-            .serialize_entry(Path::new("/test"), entry)
+            .serialize_entry(Path::new("/test"), b"test", INO_OFFSET, entry)
             .unwrap();
 
         let buf = serializer.into_inner();
         assert!(!buf.is_empty());
     }
+
+    #[test]
+    fn test_serialize_invalid() {
+        let serialize = |path: &[u8], entry: Entry| {
+            let path = PathBuf::from(OsStr::from_bytes(path));
+            Archive::from([(path, entry)]).serialize().map(|_| ())
+        };
+
+        let file = || Entry::file(Vec::new());
+        let mut owner = file();
+        owner.metadata.uid = 1 << 32;
+
+        let name = |len: usize| [b"/".as_slice(), &vec![b'a'; len]].concat();
+        let results = [
+            serialize(b"relative", file()),
+            serialize(b"/nul\0byte", file()),
+            serialize(&name(PATH_MAX), file()),
+            serialize(b"/./TRAILER!!!", file()),
+            serialize(b"/owner", owner),
+        ];
+        let codes: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().unwrap_err().code())
+            .collect();
+
+        assert_eq!(
+            codes,
+            [
+                "newc_relative_path",
+                "newc_nul_in_path",
+                "newc_path_too_long",
+                "newc_reserved_path",
+                "newc_field_overflow",
+            ]
+        );
+
+        // the leading / is not written, the terminating nul is
+        assert!(serialize(&name(PATH_MAX - 1), file()).is_ok());
+    }
+
+    #[test]
+    fn test_serialize_mtime() {
+        let mtime_field = |mtime: u64| {
+            let mut entry = Entry::file(Vec::new());
+            entry.metadata.mtime = mtime;
+
+            let data = Archive::from([(PathBuf::from("/file"), entry)])
+                .serialize()
+                .unwrap();
+
+            // sixth field of the first header
+            let offset = MAGIC.len() + 5 * 8;
+            String::from_utf8(data[offset..offset + 8].to_vec()).unwrap()
+        };
+
+        assert_eq!(mtime_field(0), "00000000");
+        assert_eq!(mtime_field(2_147_483_648), "80000000");
+        assert_eq!(mtime_field(u64::from(u32::MAX)), "ffffffff");
+        assert_eq!(mtime_field(1 << 32), "ffffffff");
+        assert_eq!(mtime_field(u64::MAX), "ffffffff");
+    }
+
+    #[test]
+    fn test_deserialize_invalid() {
+        fn entry(name: &str, data: &[u8], nlink: u32) -> Vec<u8> {
+            let fields = [1, 0o100_644, 0, 0, nlink, 0, data.len() as u32];
+            let mut entry = MAGIC.to_vec();
+            for field in fields
+                .into_iter()
+                .chain([0, 0, 0, 0, name.len() as u32 + 1, 0])
+            {
+                entry.extend(format!("{field:08x}").bytes());
+            }
+
+            entry.extend(name.bytes().chain([0]));
+            pad_buf(&mut entry);
+            entry.extend(data);
+            pad_buf(&mut entry);
+            entry
+        }
+
+        let parse = |archive: &[u8]| Archive::deserialize(archive).map(|_| ());
+        let trailer = entry(TRAILER, &[], 1);
+        assert!(parse(&[entry("file", b"data", 1), trailer.clone()].concat()).is_ok());
+
+        let mut signed = entry("file", &[], 1);
+        signed[6 + 6 * 8] = b'+';
+        assert!(parse(&signed).is_err());
+
+        assert!(parse(&entry("a\0b", &[], 1)).is_err());
+
+        let mut truncated = entry("file", &[], 1);
+        truncated[6 + 11 * 8..6 + 12 * 8].copy_from_slice(b"ffffffff");
+        assert!(parse(&truncated).is_err());
+
+        // a large file linked from many small entries
+        let size = 1 << 16;
+        let count = (MAX_HARDLINK_DATA / size + 2) as u32;
+        let mut links = entry("data", &vec![b'x'; size], count);
+        for index in 1..count {
+            links.extend(entry(&format!("{index}"), &[], count));
+        }
+        links.extend(&trailer);
+
+        assert!(matches!(
+            Archive::deserialize(&links),
+            Err(NewcError::InvalidArchive("too many hardlinks"))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let archive = Archive::from([
+            (PathBuf::from("/"), Entry::directory()),
+            (PathBuf::from("/dir"), Entry::directory()),
+            (PathBuf::from("/dir/file"), Entry::file(b"data".to_vec())),
+            (PathBuf::from("/link"), Entry::symlink("dir/file")),
+        ]);
+
+        let data = archive.serialize().unwrap();
+        let parsed = Archive::deserialize(&data).unwrap();
+
+        let paths: Vec<_> = parsed.entries().iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["/dir", "/dir/file", "/link"]);
+        assert_eq!(parsed.entries()[1].1, Entry::file(b"data".to_vec()));
+        assert_eq!(parsed.entries()[2].1, Entry::symlink("dir/file"));
+
+        assert!(Archive::deserialize(&data[..data.len() / 2]).is_err());
+        assert!(Archive::deserialize(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_crc() {
+        let mut archive = Archive::from([
+            (PathBuf::from("/dir"), Entry::directory()),
+            (PathBuf::from("/dir/file"), Entry::file(b"data".to_vec())),
+            (PathBuf::from("/dir/empty"), Entry::file(Vec::new())),
+            (PathBuf::from("/link"), Entry::symlink("dir/file")),
+        ]);
+        let plain_len = Archive::from(archive.entries().to_vec())
+            .serialize()
+            .unwrap()
+            .len();
+
+        archive.set_format(Format::Crc);
+        let mut data = archive.serialize().unwrap();
+        assert_eq!(data.len(), plain_len);
+
+        let parsed = Archive::deserialize(&data).unwrap();
+        assert_eq!(parsed.format(), Format::Crc);
+        assert_eq!(parsed.entries()[2].1, Entry::file(b"data".to_vec()));
+
+        // every header uses the checksum magic, trailer included
+        let headers: Vec<_> = data
+            .windows(MAGIC.len())
+            .enumerate()
+            .filter(|(_, window)| *window == MAGIC || *window == MAGIC_CRC)
+            .map(|(offset, window)| (offset, window == MAGIC_CRC))
+            .collect();
+        assert_eq!(headers.len(), 5);
+        assert!(headers.iter().all(|&(_, crc)| crc));
+
+        // only regular files carry a checksum
+        let checksums: Vec<_> = headers
+            .iter()
+            .map(|&(offset, _)| &data[offset + HEADER_LEN - 8..offset + HEADER_LEN])
+            .collect();
+        assert_eq!(
+            checksums,
+            [
+                b"00000000", // /dir
+                b"00000000", // /dir/empty
+                b"0000019a", // /dir/file
+                b"00000000", // /link
+                b"00000000", // trailer
+            ]
+        );
+
+        let data_offset = data
+            .windows(4)
+            .position(|window| window == b"data")
+            .unwrap();
+        data[data_offset] ^= 1;
+        assert!(matches!(
+            Archive::deserialize(&data),
+            Err(NewcError::ChecksumMismatch { path, expected: 0x19a, .. })
+                if path == Path::new("/dir/file")
+        ));
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"data"), 0x64 + 0x61 + 0x74 + 0x61);
+        assert_eq!(checksum(&vec![0xff; 1 << 25]), 0xff << 25);
+        assert_eq!(checksum(&vec![0xff; (1 << 24) + 1]), 0xff + (0xff << 24));
+    }
+
+    #[test]
+    fn test_nlink() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/usr/bin")).unwrap();
+        vfs.create_dir_all(&image("/usr/lib")).unwrap();
+        vfs.create_entry(&image("/usr/bin/sh"), Entry::file(Vec::new()))
+            .unwrap();
+        vfs.create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+            .unwrap();
+
+        let data = Archive::from(vfs).serialize().unwrap();
+        let parsed = Archive::deserialize(&data).unwrap();
+
+        let nlinks: Vec<_> = parsed
+            .entries()
+            .iter()
+            .map(|(path, entry)| (path.to_str().unwrap(), entry.metadata.nlink))
+            .collect();
+        assert_eq!(
+            nlinks,
+            [
+                ("/bin", 1),
+                ("/usr", 4),
+                ("/usr/bin", 2),
+                ("/usr/bin/sh", 1),
+                ("/usr/lib", 2)
+            ]
+        );
+    }
+
+    // xorshift generator, good enough to build and shuffle trees
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+
+        fn shuffle<T>(&mut self, items: &mut [T]) {
+            for i in (1..items.len()).rev() {
+                items.swap(i, self.below(i + 1));
+            }
+        }
+    }
+
+    fn random_vfs(rng: &mut Rng) -> Vfs {
+        let mut vfs = Vfs::new();
+        let mut dirs = vec![PathBuf::from("/")];
+
+        for index in 0..64 {
+            let parent = dirs[rng.below(dirs.len())].clone();
+            let path = parent.join(format!("entry-{index}"));
+
+            let entry = match rng.below(3) {
+                0 => {
+                    dirs.push(path.clone());
+                    Entry::directory()
+                }
+                1 => Entry::symlink(format!("target-{}", rng.next())),
+                _ => Entry::file(rng.next().to_le_bytes()[..rng.below(8)].to_vec()),
+            };
+
+            vfs.create_entry(&ImagePath::new(path).unwrap(), entry)
+                .unwrap();
+        }
+
+        vfs
+    }
+
+    #[test]
+    fn test_insertion_order() {
+        for seed in 1..=16 {
+            let mut rng = Rng(seed);
+            let vfs = random_vfs(&mut rng);
+            let files: Vec<_> = vfs
+                .iter()
+                .filter(|(_, entry)| entry.is_file())
+                .map(|(path, _)| path.clone())
+                .collect();
+
+            let mut expected = None;
+            for _ in 0..8 {
+                let mut entries: Vec<_> = vfs
+                    .iter()
+                    .map(|(path, entry)| (path.clone(), entry.clone()))
+                    .collect();
+                rng.shuffle(&mut entries);
+
+                let mut group = files.iter().take(3).cloned().collect::<Vec<_>>();
+                rng.shuffle(&mut group);
+
+                let mut archive = Archive::from(entries);
+                archive.add_hardlinks(group);
+                let data = archive.serialize().unwrap();
+
+                match &expected {
+                    Some(expected) => assert!(data == *expected, "seed {seed}"),
+                    None => expected = Some(data),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_canonical() {
+        use crate::measurement;
+
+        let mut rng = Rng(7);
+        let vfs = random_vfs(&mut rng);
+
+        let mut digests = BTreeSet::new();
+        for _ in 0..4 {
+            // host metadata differs from one build to the other
+            let mut entries: Vec<_> = vfs
+                .iter()
+                .map(|(path, entry)| {
+                    let mut entry = entry.clone();
+                    entry.metadata.mtime = rng.next() >> 32;
+                    entry.metadata.uid = rng.next() >> 48;
+                    entry.metadata.gid = rng.next() >> 48;
+                    entry.metadata.dev_minor = rng.next() >> 56;
+                    (path.clone(), entry)
+                })
+                .collect();
+            rng.shuffle(&mut entries);
+
+            let mut archive = Archive::from(entries);
+            archive.set_canonical(true);
+            let data = archive.serialize().unwrap();
+            assert_eq!(data.len() % CANONICAL_BLOCK, 0);
+
+            for (_, entry) in Archive::deserialize(&data).unwrap().entries() {
+                let Metadata {
+                    uid,
+                    gid,
+                    mtime,
+                    dev_minor,
+                    ..
+                } = entry.metadata;
+                assert_eq!((uid, gid, mtime, dev_minor), (0, 0, 0, 0));
+            }
+
+            digests.insert(measurement::archive_sha384(&data));
+        }
+
+        assert_eq!(digests.len(), 1);
+    }
+
+    #[test]
+    fn test_hardlinks() {
+        let mut archive = Archive::from([
+            (
+                PathBuf::from("/usr/bin/gzip"),
+                Entry::file(b"GZIP".to_vec()),
+            ),
+            (
+                PathBuf::from("/usr/bin/gunzip"),
+                Entry::file(b"GZIP".to_vec()),
+            ),
+            (
+                PathBuf::from("/usr/bin/zcat"),
+                Entry::file(b"GZIP".to_vec()),
+            ),
+            (PathBuf::from("/usr/bin/sh"), Entry::file(b"sh".to_vec())),
+            (PathBuf::from("/usr/bin"), Entry::directory()),
+            (PathBuf::from("/usr"), Entry::directory()),
+        ]);
+        archive.add_hardlinks(["/usr/bin/zcat", "/usr/bin/gzip"]);
+        archive.add_hardlinks(["/usr/bin/gunzip", "/usr/bin/zcat", "/usr/bin/missing"]);
+
+        let data = archive.serialize().unwrap();
+        let parsed = Archive::deserialize(&data).unwrap();
+
+        let group = ["/usr/bin/gunzip", "/usr/bin/gzip", "/usr/bin/zcat"].map(PathBuf::from);
+        assert_eq!(parsed.hardlinks(), [BTreeSet::from(group.clone())]);
+
+        for (path, entry) in parsed.entries() {
+            if group.contains(path) {
+                assert_eq!(entry.data.as_deref(), Some(&b"GZIP"[..]), "{path:?}");
+                assert_eq!(entry.metadata.nlink, 3);
+            }
+        }
+
+        // data is only written once, with the lexicographically-first member
+        let inodes: Vec<_> = data
+            .windows(MAGIC.len() + 8)
+            .filter(|window| window.starts_with(MAGIC))
+            .map(|window| str::from_utf8(&window[MAGIC.len()..]).unwrap().to_string())
+            .collect();
+        assert_eq!(inodes[2], inodes[3]);
+        assert_eq!(inodes[3], inodes[5]);
+        assert_eq!(
+            data.windows(4).filter(|window| window == b"GZIP").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut archive = Archive::from([
+            (PathBuf::from("/usr"), Entry::directory()),
+            (PathBuf::from("/usr/lib"), Entry::directory()),
+            (
+                PathBuf::from("/usr/lib/blob.bin"),
+                Entry::file(b"blob".to_vec()),
+            ),
+            (
+                PathBuf::from("/usr/lib/linked.bin"),
+                Entry::file(b"linked".to_vec()),
+            ),
+            (
+                PathBuf::from("/usr/lib/other.bin"),
+                Entry::file(b"linked".to_vec()),
+            ),
+            (
+                PathBuf::from("/usr/lib/conf"),
+                Entry::file(b"conf".to_vec()),
+            ),
+        ]);
+        archive.add_hardlinks(["/usr/lib/linked.bin", "/usr/lib/other.bin"]);
+        archive.set_canonical(true);
+
+        let split = archive.split_off(|path, _| path.extension().is_some_and(|ext| ext == "bin"));
+
+        let paths = |archive: &Archive| -> Vec<PathBuf> {
+            archive
+                .entries()
+                .iter()
+                .map(|(path, _)| path.clone())
+                .collect()
+        };
+        assert_eq!(paths(&split), [PathBuf::from("/usr/lib/blob.bin")]);
+        assert_eq!(
+            paths(&archive),
+            [
+                "/usr",
+                "/usr/lib",
+                "/usr/lib/linked.bin",
+                "/usr/lib/other.bin",
+                "/usr/lib/conf"
+            ]
+            .map(PathBuf::from)
+        );
+
+        // both segments are complete archives
+        let data = split.serialize().unwrap();
+        assert_eq!(data.len() % CANONICAL_BLOCK, 0);
+        let parsed = Archive::deserialize(&data).unwrap();
+        assert_eq!(parsed.entries().len(), 1);
+        assert_eq!(
+            Archive::deserialize(&archive.serialize().unwrap())
+                .unwrap()
+                .hardlinks()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_golden() {
+        let data = Archive::from(golden_vfs()).serialize().unwrap();
+        assert_golden(&data, GOLDEN, "testdata/newc.hex");
+
+        // root is implicit in the archive
+        let expected = golden_vfs()
+            .into_iter()
+            .filter(|(path, _)| path != Path::new("/"));
+        let parsed = Archive::deserialize(&data).unwrap();
+        assert_eq!(parsed.diff(&Archive::from(expected)), []);
+    }
 }
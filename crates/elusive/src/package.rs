@@ -0,0 +1,376 @@
+//! Kernel packages used as kernel module directories.
+//!
+//! A kernel package is a tar archive, uncompressed or compressed with gzip, xz
+//! or zstd, holding the module directory of a kernel under
+//! `usr/lib/modules/<release>` or `lib/modules/<release>`, as shipped by
+//! distributions. libkmod only works on files, so the `modules.*` files of the
+//! directory (depmod indexes, `modules.builtin`...) are extracted up front to
+//! a temporary directory, and module files are extracted there as they are
+//! looked up. The directory is removed when the package is dropped.
+//!
+//! Other members, such as firmware, are ignored.
+
+use crate::kmod::KmodError;
+
+use flate2::read::GzDecoder;
+use log::debug;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use tempfile::TempDir;
+use xz2::read::XzDecoder;
+use zstd::Decoder as ZstdDecoder;
+
+/// Directories holding module directories in packages, relative to the root.
+const MODULE_ROOTS: &[&str] = &["usr/lib/modules", "lib/modules"];
+
+/// Prefix of the files written by depmod and kernel builds next to modules.
+const INDEX_PREFIX: &str = "modules.";
+
+const MAGIC_GZ: &[u8] = b"\x1f\x8b";
+const MAGIC_XZ: &[u8] = b"\xfd7zXZ\x00";
+const MAGIC_ZSTD: &[u8] = b"\x28\xb5\x2f\xfd";
+
+/// Compression of a package, detected from its first bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Location of the data of a member in the uncompressed archive.
+#[derive(Clone, Copy, Debug)]
+struct Member {
+    offset: u64,
+    size: u64,
+}
+
+/// A kernel package extracted on demand.
+pub struct KernelPackage {
+    path: PathBuf,
+    compression: Compression,
+    release: String,
+    dir: TempDir,
+    /// Module files not extracted yet, by path relative to the module directory.
+    members: HashMap<PathBuf, Member>,
+    /// Every module file of the package, by path relative to the module directory.
+    modules: BTreeSet<PathBuf>,
+    /// Decompressed stream kept open between extractions, with its position.
+    stream: Option<(Box<dyn Read>, u64)>,
+}
+
+impl KernelPackage {
+    /// Open a kernel package, extracting the `modules.*` files of its module
+    /// directory. Packages holding modules for several kernels are rejected.
+    pub fn open(path: &Path) -> Result<Self, KmodError> {
+        let compression = detect_compression(path)?;
+        let dir = tempfile::Builder::new()
+            .prefix("elusive-kernel-")
+            .tempdir()?;
+
+        debug!(path:% = path.display(); "Reading kernel package: {}", path.display());
+
+        let mut releases = BTreeSet::new();
+        let mut members = HashMap::new();
+
+        let mut archive = tar::Archive::new(open_stream(path, compression)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let member_path = entry.path()?.into_owned();
+            let Some((release, relative)) = split_module_path(&member_path) else {
+                continue;
+            };
+
+            releases.insert(release.clone());
+            let name = relative.to_string_lossy();
+
+            if relative.components().count() == 1 && name.starts_with(INDEX_PREFIX) {
+                let target = dir.path().join(&release).join(&relative);
+                fs::create_dir_all(dir.path().join(&release))?;
+                io::copy(&mut entry, &mut File::create(target)?)?;
+            } else if is_module_file(&name) {
+                let member = Member {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                };
+
+                members.insert(relative, member);
+            }
+        }
+
+        let release = match releases.len() {
+            0 => return Err(KmodError::EmptyPackage(path.to_path_buf())),
+            1 => releases.pop_first().expect("one release"),
+            _ => {
+                let releases: Vec<_> = releases.into_iter().collect();
+                return Err(KmodError::AmbiguousPackage(
+                    path.to_path_buf(),
+                    releases.join(", "),
+                ));
+            }
+        };
+
+        // libkmod checks the layout of module directories
+        fs::create_dir_all(dir.path().join(&release).join("kernel"))?;
+
+        debug!(
+            release = release.as_str(), modules = members.len();
+            "Found {} kernel modules for release {} in package", members.len(), release
+        );
+
+        Ok(KernelPackage {
+            path: path.to_path_buf(),
+            compression,
+            release,
+            modules: members.keys().cloned().collect(),
+            members,
+            dir,
+            stream: None,
+        })
+    }
+
+    /// Get the kernel release of the modules in the package.
+    pub fn release(&self) -> &str {
+        &self.release
+    }
+
+    /// Get the temporary module directory modules are extracted to.
+    pub fn module_dir(&self) -> PathBuf {
+        self.dir.path().join(&self.release)
+    }
+
+    /// Get the paths of every module file of the package, in the module
+    /// directory, extracted or not.
+    pub fn module_files(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        let dir = self.module_dir();
+        self.modules.iter().map(move |relative| dir.join(relative))
+    }
+
+    /// Extract the module file at the path, in the module directory, if it is
+    /// part of the package and was not extracted yet.
+    pub fn extract(&mut self, path: &Path) -> Result<(), KmodError> {
+        let dir = self.module_dir();
+        let Ok(relative) = path.strip_prefix(&dir) else {
+            return Ok(());
+        };
+
+        let Some(member) = self.members.remove(relative) else {
+            return Ok(());
+        };
+
+        debug!(path:% = relative.display(); "Extracting kernel module from package: {}", relative.display());
+
+        let target = dir.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut reader = self.seek(member.offset)?;
+        let copied = io::copy(
+            &mut (&mut reader).take(member.size),
+            &mut File::create(&target)?,
+        )?;
+        self.stream = Some((reader, member.offset + copied));
+
+        if copied != member.size {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated kernel package");
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    // get a stream positioned at the offset of the uncompressed archive,
+    // reusing the current one when the offset is ahead of it
+    fn seek(&mut self, offset: u64) -> Result<Box<dyn Read>, KmodError> {
+        if let Some((mut reader, position)) = self.stream.take() {
+            if position <= offset {
+                io::copy(&mut (&mut reader).take(offset - position), &mut io::sink())?;
+                return Ok(reader);
+            }
+        }
+
+        if self.compression == Compression::None {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            return Ok(Box::new(BufReader::new(file)));
+        }
+
+        let mut reader = open_stream(&self.path, self.compression)?;
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        Ok(reader)
+    }
+}
+
+/// Check if a kernel module path points to a package rather than a directory.
+pub fn is_package(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Get the kernel release of the modules in a package, from the path of the
+/// first module directory member, without extracting anything.
+pub fn release_of(path: &Path) -> Result<String, KmodError> {
+    let compression = detect_compression(path)?;
+    let mut archive = tar::Archive::new(open_stream(path, compression)?);
+
+    for entry in archive.entries()? {
+        if let Some((release, _)) = split_module_path(&entry?.path()?) {
+            return Ok(release);
+        }
+    }
+
+    Err(KmodError::EmptyPackage(path.to_path_buf()))
+}
+
+fn detect_compression(path: &Path) -> Result<Compression, KmodError> {
+    let mut magic = Vec::with_capacity(MAGIC_XZ.len());
+    File::open(path)?
+        .take(MAGIC_XZ.len() as u64)
+        .read_to_end(&mut magic)?;
+
+    let compression = if magic.starts_with(MAGIC_GZ) {
+        Compression::Gzip
+    } else if magic.starts_with(MAGIC_XZ) {
+        Compression::Xz
+    } else if magic.starts_with(MAGIC_ZSTD) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+
+    Ok(compression)
+}
+
+fn open_stream(path: &Path, compression: Compression) -> Result<Box<dyn Read>, KmodError> {
+    let file = BufReader::new(File::open(path)?);
+
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(GzDecoder::new(file)),
+        Compression::Xz => Box::new(XzDecoder::new(file)),
+        Compression::Zstd => Box::new(ZstdDecoder::with_buffer(file)?),
+    };
+
+    Ok(reader)
+}
+
+// split a member path into the release and the path relative to its module
+// directory, members outside of module directories are ignored
+fn split_module_path(path: &Path) -> Option<(String, PathBuf)> {
+    let normal: PathBuf = path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+
+    let inner = MODULE_ROOTS
+        .iter()
+        .find_map(|root| normal.strip_prefix(root).ok())?;
+
+    let mut components = inner.components();
+    let release = components.next()?.as_os_str().to_str()?.to_string();
+    let relative = components.as_path().to_path_buf();
+
+    (!relative.as_os_str().is_empty()).then_some((release, relative))
+}
+
+fn is_module_file(name: &str) -> bool {
+    crate::kmod::MODULE_EXTENSIONS
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kmod::tests::fake_module;
+    use crate::kmod::Kmod;
+
+    #[test]
+    fn test_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let first = dir.join("first.ko");
+        let second = dir.join("second.ko");
+        fake_module(&first, &["name=first", "depends=second"]);
+        fake_module(&second, &["name=second", "depends="]);
+        fs::write(dir.join("modules.builtin"), "kernel/fs/ext4/ext4.ko\n").unwrap();
+        fs::write(dir.join("fw.bin"), b"firmware").unwrap();
+
+        let root = "./usr/lib/modules/6.0.0-elusive";
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, name) in [
+            (
+                dir.join("modules.builtin"),
+                format!("{root}/modules.builtin"),
+            ),
+            (first, format!("{root}/kernel/drivers/misc/first.ko")),
+            (dir.join("fw.bin"), "./usr/lib/firmware/fw.bin".to_string()),
+            (second, format!("{root}/kernel/lib/second.ko")),
+        ] {
+            builder.append_path_with_name(path, name).unwrap();
+        }
+
+        let tarball = builder.into_inner().unwrap();
+        let package = dir.join("linux-6.0.0.tar.zst");
+        fs::write(&package, zstd::encode_all(tarball.as_slice(), 0).unwrap()).unwrap();
+
+        let release = crate::kmod::release_of(Some(&package)).unwrap();
+
+        let mut kmod = Kmod::with_package(&package).unwrap();
+        kmod.set_auto_depmod(true);
+        let builtin = kmod.is_builtin("ext4");
+
+        let first = kmod.module_from_name("first").unwrap();
+        let first_path = first.host_path().unwrap().to_path_buf();
+        let second_path = first_path.with_file_name("../../lib/second.ko");
+        let extracted = (first_path.exists(), second_path.exists());
+
+        let info = kmod.module_info(&first).unwrap();
+        let second = kmod.module_from_name(&info.depends()[0]).unwrap();
+        let second_path = second.host_path().unwrap().to_path_buf();
+        let data = fs::read(&second_path).unwrap();
+
+        drop((first, second));
+        drop(kmod);
+        let removed = !first_path.exists();
+
+        assert_eq!(release, "6.0.0-elusive");
+        assert!(builtin);
+        assert!(first_path.ends_with("6.0.0-elusive/kernel/drivers/misc/first.ko"));
+        assert_eq!(extracted, (true, false));
+        assert!(second_path.ends_with("6.0.0-elusive/kernel/lib/second.ko"));
+        assert_eq!(&data[..4], b"\x7fELF");
+        assert!(removed);
+    }
+
+    #[test]
+    fn test_split_module_path() {
+        assert_eq!(
+            split_module_path(Path::new(
+                "./usr/lib/modules/6.9.1-arch1/kernel/fs/ext4.ko.zst"
+            )),
+            Some((
+                "6.9.1-arch1".to_string(),
+                PathBuf::from("kernel/fs/ext4.ko.zst")
+            ))
+        );
+        assert_eq!(
+            split_module_path(Path::new("lib/modules/6.1.0/modules.dep")),
+            Some(("6.1.0".to_string(), PathBuf::from("modules.dep")))
+        );
+        assert_eq!(split_module_path(Path::new("usr/lib/modules/6.1.0")), None);
+        assert_eq!(
+            split_module_path(Path::new("usr/lib/firmware/fw.bin")),
+            None
+        );
+    }
+}
@@ -0,0 +1,427 @@
+//! Typed paths, telling host files apart from entries of the initramfs.
+//!
+//! Most builder functions take both a file read from the host and a location
+//! inside the image, which are easy to swap when both are plain paths.
+//! [`HostPath`] and [`ImagePath`] make the difference part of the type, so
+//! swapped arguments do not compile.
+//!
+//! Host paths are used as given, relative ones being resolved against the
+//! working directory. Image paths are always absolute and normalized: no `.`
+//! or `..` components and no repeated or trailing separators.
+//!
+//! Both may hold bytes that are not valid UTF-8, as any file name can. Use
+//! [`escaped`] rather than [`Path::display`] to show them, so these bytes are
+//! not silently replaced.
+
+use serde::{Deserialize, Serialize, Serializer};
+use std::ffi::OsStr;
+use std::fmt;
+use std::iter;
+use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PathError {
+    #[error("path in the initramfs must be absolute: {}", escaped(.0))]
+    Relative(PathBuf),
+    #[error("path in the initramfs cannot contain '..': {}", escaped(.0))]
+    ParentDir(PathBuf),
+    #[error("path in the initramfs cannot contain a nul byte: {0:?}")]
+    Nul(PathBuf),
+}
+
+impl PathError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PathError::Relative(_) => "path_relative",
+            PathError::ParentDir(_) => "path_parent_dir",
+            PathError::Nul(_) => "path_nul",
+        }
+    }
+}
+
+/// A path on the host, where sources are read from.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct HostPath(PathBuf);
+
+impl HostPath {
+    /// Create a host path, any path is valid.
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        HostPath(path.into())
+    }
+
+    /// Get the underlying path.
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Convert into the underlying path.
+    pub fn into_path_buf(self) -> PathBuf {
+        self.0
+    }
+}
+
+/// An absolute and normalized path inside the initramfs.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "PathBuf")]
+pub struct ImagePath(PathBuf);
+
+impl ImagePath {
+    /// Create a path inside the initramfs, which must be absolute and cannot
+    /// contain `..`. `.` components and redundant separators are removed.
+    pub fn new<P>(path: P) -> Result<Self, PathError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        if !path.has_root() {
+            return Err(PathError::Relative(path.to_path_buf()));
+        }
+
+        // the archive stores nul terminated names
+        if path.as_os_str().as_bytes().contains(&0) {
+            return Err(PathError::Nul(path.to_path_buf()));
+        }
+
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::ParentDir => return Err(PathError::ParentDir(path.to_path_buf())),
+                Component::CurDir => (),
+                other => normalized.push(other),
+            }
+        }
+
+        Ok(ImagePath(normalized))
+    }
+
+    /// Get the root of the initramfs.
+    pub fn root() -> Self {
+        ImagePath(PathBuf::from("/"))
+    }
+
+    /// Get the parent directory, `None` for the root.
+    pub fn parent(&self) -> Option<ImagePath> {
+        self.0
+            .parent()
+            .map(|parent| ImagePath(parent.to_path_buf()))
+    }
+
+    /// Append a path, see [`ImagePath::new`] for the rules the result follows.
+    pub fn join<P>(&self, path: P) -> Result<ImagePath, PathError>
+    where
+        P: AsRef<Path>,
+    {
+        ImagePath::new(self.0.join(path))
+    }
+
+    /// Get the target of a symlink at this path relative to its directory,
+    /// e.g. `../lib/libc.so.6` for `/usr/lib/libc.so.6` from `/usr/bin/ldd`.
+    /// `.` and `..` in an absolute target are resolved without looking at
+    /// the filesystem, relative targets are returned as is.
+    pub fn relative_target(&self, target: &Path) -> PathBuf {
+        if !target.has_root() {
+            return target.to_path_buf();
+        }
+
+        let mut components = Vec::new();
+        for component in target.components() {
+            match component {
+                Component::Normal(name) => components.push(name),
+                Component::ParentDir => {
+                    components.pop();
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+            }
+        }
+
+        let dir: Vec<_> = self
+            .0
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+
+        let common = dir
+            .iter()
+            .zip(&components)
+            .take_while(|(left, right)| left == right)
+            .count();
+
+        let mut relative: PathBuf = iter::repeat_n("..", dir.len() - common).collect();
+        relative.extend(&components[common..]);
+
+        if relative.as_os_str().is_empty() {
+            relative.push(".");
+        }
+
+        relative
+    }
+    /// Get the underlying path.
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Convert into the underlying path.
+    pub fn into_path_buf(self) -> PathBuf {
+        self.0
+    }
+}
+
+macro_rules! path_impls {
+    ($name:ident) => {
+        impl Deref for $name {
+            type Target = Path;
+
+            fn deref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl AsRef<Path> for $name {
+            fn as_ref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl AsRef<OsStr> for $name {
+            fn as_ref(&self) -> &OsStr {
+                self.0.as_os_str()
+            }
+        }
+
+        impl From<$name> for PathBuf {
+            fn from(path: $name) -> Self {
+                path.0
+            }
+        }
+
+        impl PartialEq<Path> for $name {
+            fn eq(&self, other: &Path) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&Path> for $name {
+            fn eq(&self, other: &&Path) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl PartialEq<PathBuf> for $name {
+            fn eq(&self, other: &PathBuf) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+path_impls!(HostPath);
+path_impls!(ImagePath);
+
+impl From<PathBuf> for HostPath {
+    fn from(path: PathBuf) -> Self {
+        HostPath(path)
+    }
+}
+
+impl From<&Path> for HostPath {
+    fn from(path: &Path) -> Self {
+        HostPath(path.to_path_buf())
+    }
+}
+
+impl From<&str> for HostPath {
+    fn from(path: &str) -> Self {
+        HostPath(PathBuf::from(path))
+    }
+}
+
+impl TryFrom<PathBuf> for ImagePath {
+    type Error = PathError;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        ImagePath::new(path)
+    }
+}
+
+impl TryFrom<&Path> for ImagePath {
+    type Error = PathError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        ImagePath::new(path)
+    }
+}
+
+impl TryFrom<&str> for ImagePath {
+    type Error = PathError;
+
+    fn try_from(path: &str) -> Result<Self, Self::Error> {
+        ImagePath::new(path)
+    }
+}
+
+/// Display a path with the bytes that are not valid UTF-8 escaped as `\xNN`,
+/// where [`Path::display`] replaces them with U+FFFD.
+pub fn escaped(path: &Path) -> Escaped<'_> {
+    Escaped(path)
+}
+
+/// Path displayed by [`escaped`].
+pub struct Escaped<'a>(&'a Path);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.0.as_os_str().as_bytes().utf8_chunks() {
+            f.write_str(chunk.valid())?;
+
+            for byte in chunk.invalid() {
+                write!(f, "\\x{byte:02x}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serialize a path as its [`escaped`] form, serde rejects paths that are not
+/// valid UTF-8.
+pub fn serialize_escaped<S>(path: &Path, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(&escaped(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_path() {
+        let normalized = ImagePath::new("/usr//lib/./modules/").unwrap();
+        assert_eq!(normalized, PathBuf::from("/usr/lib/modules"));
+        assert_eq!(ImagePath::new("/").unwrap(), ImagePath::root());
+
+        assert!(matches!(
+            ImagePath::new("usr/lib"),
+            Err(PathError::Relative(_))
+        ));
+        assert!(matches!(
+            ImagePath::new("./usr/lib"),
+            Err(PathError::Relative(_))
+        ));
+        assert!(matches!(
+            ImagePath::new("/usr/../etc"),
+            Err(PathError::ParentDir(_))
+        ));
+
+        let lib = ImagePath::new("/usr/lib").unwrap();
+        assert_eq!(lib.parent().unwrap(), PathBuf::from("/usr"));
+        assert!(ImagePath::root().parent().is_none());
+        assert_eq!(
+            lib.join("modules").unwrap(),
+            PathBuf::from("/usr/lib/modules")
+        );
+        assert!(lib.join("../../..").is_err());
+        assert!(matches!(
+            ImagePath::new("/etc/mo\0td"),
+            Err(PathError::Nul(_))
+        ));
+
+        let yaml: Result<ImagePath, _> = serde_yaml::from_str("etc/motd");
+        assert!(yaml.is_err());
+        let yaml: HostPath = serde_yaml::from_str("etc/motd").unwrap();
+        assert_eq!(yaml, PathBuf::from("etc/motd"));
+    }
+
+    #[test]
+    fn test_relative_target() {
+        let relative = |link: &str, target: &str| {
+            let link = ImagePath::new(link).unwrap();
+            link.relative_target(Path::new(target))
+        };
+
+        // siblings and descendants of the link directory
+        assert_eq!(relative("/usr/bin/sh", "/usr/bin/bash"), Path::new("bash"));
+        assert_eq!(
+            relative(
+                "/etc/systemd/system/foo.service",
+                "/etc/systemd/system/units/foo"
+            ),
+            Path::new("units/foo")
+        );
+
+        // cousins and ancestors
+        assert_eq!(
+            relative("/usr/bin/ldd", "/usr/lib/libc.so.6"),
+            Path::new("../lib/libc.so.6")
+        );
+        assert_eq!(
+            relative(
+                "/etc/systemd/system/default.target",
+                "/usr/lib/systemd/system/initrd.target"
+            ),
+            Path::new("../../../usr/lib/systemd/system/initrd.target")
+        );
+        assert_eq!(
+            relative("/usr/lib/modules/6.1/build", "/usr/lib"),
+            Path::new("../..")
+        );
+        assert_eq!(relative("/usr/lib/self", "/usr/lib"), Path::new("."));
+        assert_eq!(relative("/usr/lib/root", "/"), Path::new("../.."));
+
+        // links at the root
+        assert_eq!(relative("/lib64", "/usr/lib"), Path::new("usr/lib"));
+        assert_eq!(relative("/init", "/init.real"), Path::new("init.real"));
+        assert_eq!(relative("/root", "/"), Path::new("."));
+
+        // dots in the target, above the root stays at the root
+        assert_eq!(
+            relative("/usr/bin/ldd", "/usr/bin/../lib/./libc.so.6"),
+            Path::new("../lib/libc.so.6")
+        );
+        assert_eq!(
+            relative("/etc/mtab", "/../../proc/self/mounts"),
+            Path::new("../proc/self/mounts")
+        );
+
+        // relative targets are kept
+        assert_eq!(
+            relative("/etc/mtab", "../proc/self/mounts"),
+            Path::new("../proc/self/mounts")
+        );
+    }
+
+    #[test]
+    fn test_escaped() {
+        let path = Path::new(OsStr::from_bytes(b"/lib/firmware/caf\xe9\xff-\xc3\xa9.bin"));
+        assert_eq!(
+            escaped(path).to_string(),
+            "/lib/firmware/caf\\xe9\\xff-\u{e9}.bin"
+        );
+
+        let err = ImagePath::new(OsStr::from_bytes(b"fw/\xff")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "path in the initramfs must be absolute: fw/\\xff"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Filesystem images embedded in the initramfs.
+//!
+//! A payload is a read-only filesystem image, such as the squashfs root of a
+//! live system, added as a single file and mounted from the initramfs through
+//! a loop device, usually under an overlay. The kernel modules of its
+//! filesystem, `loop` and `overlay` are added along with it.
+//!
+//! The filesystem is either configured or detected from the superblock
+//! magic of the image.
+
+use crate::paths::escaped;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Kernel modules needed to mount any payload, besides its filesystem.
+pub const MOUNT_MODULES: &[&str] = &["loop", "overlay"];
+
+/// Magic of squashfs images, at the start of the superblock.
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+/// Magic of erofs images, little endian 0xE0F5E1E2.
+const EROFS_MAGIC: &[u8] = &[0xe2, 0xe1, 0xf5, 0xe0];
+
+/// Offset of the erofs superblock in the image.
+const EROFS_SUPER_OFFSET: usize = 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PayloadError {
+    #[error("cannot detect the filesystem of payload {}", escaped(.0))]
+    Unknown(PathBuf),
+    #[error("payload {} is configured as {expected} but looks like {found}", escaped(.path))]
+    Mismatch {
+        path: PathBuf,
+        expected: PayloadType,
+        found: PayloadType,
+    },
+}
+
+/// Filesystem of a payload.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadType {
+    /// Detect the filesystem from the image.
+    #[default]
+    Auto,
+    Squashfs,
+    Erofs,
+}
+
+impl PayloadType {
+    /// Detect the filesystem of an image from its magic, `None` when it is
+    /// neither squashfs nor erofs.
+    pub fn detect(data: &[u8]) -> Option<PayloadType> {
+        if data.starts_with(SQUASHFS_MAGIC) {
+            return Some(PayloadType::Squashfs);
+        }
+
+        data.get(EROFS_SUPER_OFFSET..)
+            .filter(|superblock| superblock.starts_with(EROFS_MAGIC))
+            .map(|_| PayloadType::Erofs)
+    }
+
+    /// Resolve the filesystem of the image at `path`: detected when `Auto`,
+    /// otherwise checked against the magic.
+    pub fn resolve(self, path: PathBuf, data: &[u8]) -> Result<PayloadType, PayloadError> {
+        match (self, PayloadType::detect(data)) {
+            (_, None) => Err(PayloadError::Unknown(path)),
+            (PayloadType::Auto, Some(found)) => Ok(found),
+            (expected, Some(found)) if expected != found => Err(PayloadError::Mismatch {
+                path,
+                expected,
+                found,
+            }),
+            (expected, Some(_)) => Ok(expected),
+        }
+    }
+
+    /// Get the kernel module of the filesystem, `None` for `Auto`.
+    pub fn module(self) -> Option<&'static str> {
+        match self {
+            PayloadType::Auto => None,
+            PayloadType::Squashfs => Some("squashfs"),
+            PayloadType::Erofs => Some("erofs"),
+        }
+    }
+}
+
+impl fmt::Display for PayloadType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PayloadType::Auto => "auto",
+            PayloadType::Squashfs => "squashfs",
+            PayloadType::Erofs => "erofs",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// A payload added to the initramfs and the kernel modules mounting it.
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+pub struct PayloadModules {
+    /// Path of the payload in the initramfs.
+    pub destination: String,
+    /// Filesystem of the payload.
+    pub filesystem: PayloadType,
+    /// Kernel modules added for it.
+    pub modules: Vec<String>,
+    /// Kernel modules built into the kernel.
+    pub builtin: Vec<String>,
+    /// Kernel modules that could not be found.
+    pub missing: Vec<String>,
+}
+
+impl PayloadModules {
+    /// Check whether the filesystem module is neither added nor builtin.
+    pub fn filesystem_missing(&self) -> bool {
+        self.filesystem
+            .module()
+            .is_some_and(|module| self.missing.iter().any(|name| name == module))
+    }
+
+    /// Check whether the filesystem is built into the kernel.
+    pub fn filesystem_builtin(&self) -> bool {
+        self.filesystem
+            .module()
+            .is_some_and(|module| self.builtin.iter().any(|name| name == module))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // tiny images holding only the superblock magic
+    fn squashfs() -> Vec<u8> {
+        let mut data = b"hsqs".to_vec();
+        data.resize(96, 0);
+        data
+    }
+
+    fn erofs() -> Vec<u8> {
+        let mut data = vec![0; EROFS_SUPER_OFFSET];
+        data.extend_from_slice(&[0xe2, 0xe1, 0xf5, 0xe0]);
+        data.resize(EROFS_SUPER_OFFSET + 128, 0);
+        data
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            PayloadType::detect(&squashfs()),
+            Some(PayloadType::Squashfs)
+        );
+        assert_eq!(PayloadType::detect(&erofs()), Some(PayloadType::Erofs));
+        assert_eq!(PayloadType::detect(b"hsq"), None);
+        assert_eq!(PayloadType::detect(&[0; 2048]), None);
+        assert_eq!(
+            PayloadType::detect(&erofs()[..EROFS_SUPER_OFFSET + 2]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let path = || PathBuf::from("/srv/root.img");
+
+        let auto = PayloadType::Auto.resolve(path(), &erofs()).unwrap();
+        let squashfs_ok = PayloadType::Squashfs.resolve(path(), &squashfs()).unwrap();
+        let mismatch = PayloadType::Squashfs.resolve(path(), &erofs()).unwrap_err();
+        let unknown = PayloadType::Auto
+            .resolve(path(), b"not an image")
+            .unwrap_err();
+
+        assert_eq!(auto, PayloadType::Erofs);
+        assert_eq!(auto.module(), Some("erofs"));
+        assert_eq!(squashfs_ok, PayloadType::Squashfs);
+        assert_eq!(
+            mismatch.to_string(),
+            "payload /srv/root.img is configured as squashfs but looks like erofs"
+        );
+        assert!(matches!(unknown, PayloadError::Unknown(_)));
+    }
+}
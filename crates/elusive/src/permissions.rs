@@ -0,0 +1,331 @@
+//! Lints over the permissions of the final initramfs tree.
+//!
+//! Files copied from the host keep their mode, so a misconfigured tree can
+//! end up with a world-writable `/etc/passwd` or a readable `/etc/shadow` in
+//! the image. The lints only look at the VFS and report what they find:
+//!
+//! - `world_writable`: entries writable by others, except sticky directories
+//!   such as `/tmp` and explicitly allowed paths,
+//! - `setuid`: setuid and setgid files that were not marked as expected,
+//! - `sensitive_readable`: secrets readable by others, shadow files and keys
+//!   under `/etc` along with the crypttab and the key files it references,
+//! - `not_executable`: executables (dynamically linked ELF files, scripts)
+//!   without any execute bit.
+
+use crate::crypttab;
+use crate::elf::Elf;
+use crate::vfs::{Entry, Vfs};
+
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const WORLD_WRITABLE: u32 = 0o002;
+const WORLD_READABLE: u32 = 0o004;
+const EXECUTABLE: u32 = 0o111;
+const SETUID: u32 = 0o4000;
+const SETGID: u32 = 0o2000;
+const STICKY: u32 = 0o1000;
+
+/// Directories allowed to be world-writable as long as they are sticky.
+const STICKY_DIRS: &[&str] = &["/tmp", "/var/tmp", "/dev/shm"];
+
+/// Names of files under `/etc` that must not be readable by others.
+const SENSITIVE_NAMES: &[&str] = &["shadow", "shadow-", "gshadow", "gshadow-", "opasswd"];
+/// Extensions of files under `/etc` that must not be readable by others.
+const SENSITIVE_EXTENSIONS: &[&str] = &["key"];
+/// Directories under `/etc` whose files must not be readable by others.
+const SENSITIVE_DIRS: &[&str] = &["keys", "private"];
+
+/// Path of the crypttab, sensitive when it references key files.
+const CRYPTTAB: &str = "/etc/crypttab";
+
+/// A permission lint.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Lint {
+    /// Entries writable by others.
+    WorldWritable,
+    /// Unexpected setuid or setgid files.
+    Setuid,
+    /// Secrets under `/etc` and crypttab key files readable by others.
+    SensitiveReadable,
+    /// Executables without any execute bit.
+    NotExecutable,
+}
+
+impl Lint {
+    /// Get the name of the lint, as used in the configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::WorldWritable => "world_writable",
+            Lint::Setuid => "setuid",
+            Lint::SensitiveReadable => "sensitive_readable",
+            Lint::NotExecutable => "not_executable",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Lint::WorldWritable => "writable by others",
+            Lint::Setuid => "unexpected setuid or setgid bit",
+            Lint::SensitiveReadable => "sensitive file readable by others",
+            Lint::NotExecutable => "executable without any execute bit",
+        }
+    }
+}
+
+/// Which lints run and what they accept.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    /// Lints that do not run.
+    #[serde(default = "Vec::new")]
+    pub disabled: Vec<Lint>,
+    /// Paths allowed to be writable by others, sticky bit or not.
+    #[serde(default = "Vec::new")]
+    pub allow_world_writable: Vec<PathBuf>,
+    /// Paths expected to be setuid or setgid.
+    #[serde(default = "Vec::new")]
+    pub allow_setuid: Vec<PathBuf>,
+}
+
+impl Rules {
+    fn enabled(&self, lint: Lint) -> bool {
+        !self.disabled.contains(&lint)
+    }
+}
+
+/// A problem found by a lint.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Finding {
+    /// Lint that found the problem.
+    pub lint: Lint,
+    /// Path of the entry.
+    pub path: PathBuf,
+    /// Mode of the entry, without the file type.
+    pub mode: u32,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (mode {:04o}): {} [{}]",
+            self.path.display(),
+            self.mode,
+            self.lint.describe(),
+            self.lint.name()
+        )
+    }
+}
+
+/// Run the enabled lints over every entry of the VFS, symlinks excepted.
+pub fn lint(vfs: &Vfs, rules: &Rules) -> Vec<Finding> {
+    let keyfiles = crypttab_keyfiles(vfs);
+    let mut findings = Vec::new();
+
+    for (path, entry) in vfs.iter() {
+        if entry.is_symlink() {
+            continue;
+        }
+
+        let mode = entry.metadata.mode & 0o7777;
+        let mut report = |lint| {
+            if rules.enabled(lint) {
+                findings.push(Finding {
+                    lint,
+                    path: path.clone(),
+                    mode,
+                });
+            }
+        };
+
+        if mode & WORLD_WRITABLE != 0 && !allowed_world_writable(path, entry, rules) {
+            report(Lint::WorldWritable);
+        }
+
+        if !entry.is_file() {
+            continue;
+        }
+
+        if mode & (SETUID | SETGID) != 0 && !rules.allow_setuid.contains(path) {
+            report(Lint::Setuid);
+        }
+
+        if mode & WORLD_READABLE != 0 && (is_sensitive(path, entry) || keyfiles.contains(path)) {
+            report(Lint::SensitiveReadable);
+        }
+
+        if mode & EXECUTABLE == 0 && is_executable(entry.data.as_deref().unwrap_or_default()) {
+            report(Lint::NotExecutable);
+        }
+    }
+
+    findings
+}
+
+fn allowed_world_writable(path: &Path, entry: &Entry, rules: &Rules) -> bool {
+    if rules
+        .allow_world_writable
+        .iter()
+        .any(|allowed| allowed == path)
+    {
+        return true;
+    }
+
+    entry.is_dir()
+        && entry.metadata.mode & STICKY != 0
+        && STICKY_DIRS.iter().any(|dir| Path::new(dir) == path)
+}
+
+fn is_sensitive(path: &Path, entry: &Entry) -> bool {
+    let Ok(relative) = path.strip_prefix("/etc") else {
+        return false;
+    };
+
+    if path == Path::new(CRYPTTAB) {
+        let content = String::from_utf8_lossy(entry.data.as_deref().unwrap_or_default());
+        return crypttab::parse(&content)
+            .is_ok_and(|volumes| volumes.iter().any(|volume| volume.keyfile.is_some()));
+    }
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+    SENSITIVE_NAMES.contains(&name.as_ref())
+        || SENSITIVE_EXTENSIONS.contains(&extension.as_ref())
+        || relative.parent().is_some_and(|parent| {
+            parent
+                .components()
+                .any(|dir| SENSITIVE_DIRS.iter().any(|name| dir.as_os_str() == *name))
+        })
+}
+
+/// Get the key files referenced by the crypttab of the VFS.
+fn crypttab_keyfiles(vfs: &Vfs) -> BTreeSet<PathBuf> {
+    let data = vfs
+        .iter()
+        .find(|(path, _)| *path == Path::new(CRYPTTAB))
+        .and_then(|(_, entry)| entry.data.as_deref());
+
+    let Some(data) = data else {
+        return BTreeSet::new();
+    };
+
+    crypttab::parse(&String::from_utf8_lossy(data))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|volume| volume.keyfile)
+        .map(|keyfile| vfs.resolve(keyfile))
+        .collect()
+}
+
+/// Check whether the data is a script or a dynamically linked ELF executable,
+/// shared libraries only need to be readable.
+fn is_executable(data: &[u8]) -> bool {
+    if data.starts_with(b"#!") {
+        return true;
+    }
+
+    Elf::is_elf(data) && matches!(Elf::interpreter_from_data(data), Ok(Some(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::paths::ImagePath;
+
+    fn with_mode(mut entry: Entry, mode: u32) -> Entry {
+        entry.metadata.mode = (entry.metadata.mode & !0o7777) | mode;
+        entry
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut vfs = Vfs::new();
+        let entries = [
+            ("/tmp", with_mode(Entry::directory(), 0o1777)),
+            ("/run", with_mode(Entry::directory(), 0o777)),
+            ("/etc", Entry::directory()),
+            ("/etc/keys", Entry::directory()),
+            (
+                "/etc/passwd",
+                with_mode(Entry::file(b"root:x:0:0::/root:/bin/sh\n".to_vec()), 0o666),
+            ),
+            (
+                "/etc/shadow",
+                with_mode(Entry::file(b"root:!::::::\n".to_vec()), 0o644),
+            ),
+            ("/etc/gshadow", with_mode(Entry::file(Vec::new()), 0o600)),
+            (
+                "/etc/keys/root.bin",
+                with_mode(Entry::file(Vec::new()), 0o644),
+            ),
+            (
+                "/etc/crypttab",
+                Entry::file(b"root /dev/sda2 /root.key luks\n".to_vec()),
+            ),
+            ("/root.key", Entry::file(b"secret".to_vec())),
+            (
+                "/init",
+                with_mode(Entry::file(b"#!/bin/sh\n".to_vec()), 0o644),
+            ),
+            ("/usr/bin/mount", with_mode(Entry::file(Vec::new()), 0o4755)),
+            ("/usr/bin/su", with_mode(Entry::file(Vec::new()), 0o4755)),
+            (
+                "/usr/lib/libc.so",
+                with_mode(Entry::file(b"\x7fELF".to_vec()), 0o644),
+            ),
+            ("/usr/lib/link", Entry::symlink("libc.so")),
+        ];
+
+        for (path, entry) in entries {
+            let path = ImagePath::new(path).unwrap();
+            vfs.create_dir_all(&path.parent().unwrap()).unwrap();
+            vfs.create_entry(&path, entry).unwrap();
+        }
+
+        let rules = Rules {
+            allow_setuid: vec![PathBuf::from("/usr/bin/mount")],
+            ..Default::default()
+        };
+        let findings: Vec<_> = lint(&vfs, &rules)
+            .into_iter()
+            .map(|finding| (finding.lint, finding.path))
+            .collect();
+
+        let expected = [
+            (Lint::SensitiveReadable, "/etc/crypttab"),
+            (Lint::SensitiveReadable, "/etc/keys/root.bin"),
+            (Lint::WorldWritable, "/etc/passwd"),
+            (Lint::SensitiveReadable, "/etc/shadow"),
+            (Lint::NotExecutable, "/init"),
+            (Lint::SensitiveReadable, "/root.key"),
+            (Lint::WorldWritable, "/run"),
+            (Lint::Setuid, "/usr/bin/su"),
+        ]
+        .map(|(lint, path)| (lint, PathBuf::from(path)));
+        assert_eq!(findings, expected);
+
+        let rules = Rules {
+            disabled: vec![Lint::SensitiveReadable, Lint::Setuid],
+            allow_world_writable: vec![PathBuf::from("/run")],
+            ..Default::default()
+        };
+        let findings: Vec<_> = lint(&vfs, &rules)
+            .into_iter()
+            .map(|finding| finding.to_string())
+            .collect();
+
+        assert_eq!(
+            findings,
+            [
+                "/etc/passwd (mode 0666): writable by others [world_writable]",
+                "/init (mode 0644): executable without any execute bit [not_executable]",
+            ]
+        );
+    }
+}
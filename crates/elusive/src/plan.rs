@@ -0,0 +1,261 @@
+//! Ordered steps building an initramfs from a configuration.
+//!
+//! A [`BuildPlan`] lists what [`Initramfs::run_plan`] does with a
+//! configuration, only including the steps the configuration asks for. Library
+//! users can run their own steps before and after it with
+//! [`Initramfs::add_pre_step`] and [`Initramfs::add_post_step`].
+//!
+//! [`Initramfs::run_plan`]: crate::initramfs::Initramfs::run_plan
+//! [`Initramfs::add_pre_step`]: crate::initramfs::Initramfs::add_pre_step
+//! [`Initramfs::add_post_step`]: crate::initramfs::Initramfs::add_post_step
+
+use crate::config;
+
+use std::fmt;
+
+/// Step of a [`BuildPlan`], derived from a part of the configuration.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Step {
+    /// Add the init script or the init wrapper.
+    Init,
+    /// Install the shutdown executables.
+    Shutdown,
+    /// Apply the settings, then add the content of the named configuration
+    /// modules in order.
+    Modules {
+        names: Vec<String>,
+        /// Whether the kernel modules of configuration modules are added too,
+        /// otherwise they are left to [`Step::KernelModules`].
+        kernel_modules: bool,
+    },
+    /// Add the environment variables of the configuration.
+    Environment,
+    /// Add the kernel modules of the named configuration modules.
+    KernelModules(Vec<String>),
+    /// Run the checks looking at the whole initramfs.
+    Checks,
+    /// Add the kernel modules needed by this host.
+    HostOnly,
+    /// Add the kernel modules loaded on the host or listed in a file.
+    LoadedModules,
+    /// Add the emergency shell.
+    Emergency,
+    /// Add the default systemd generators if units were included.
+    AutoGenerators,
+    /// Write `/etc/ld.so.conf`, and `/etc/ld.so.cache` with ldconfig.
+    LdSoConf { ldconfig: bool },
+    /// Write the script restoring extended attributes.
+    XattrScript,
+    /// Warn about problems found in the init script.
+    InitLint,
+}
+
+impl Step {
+    /// Check whether the step needs a kernel module context.
+    pub fn needs_kmod(&self) -> bool {
+        match self {
+            Step::Modules { kernel_modules, .. } => *kernel_modules,
+            Step::KernelModules(_) | Step::Checks | Step::HostOnly | Step::LoadedModules => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Init => write!(f, "init"),
+            Step::Shutdown => write!(f, "shutdown"),
+            Step::Modules {
+                names,
+                kernel_modules,
+            } => {
+                write!(f, "modules: {}", names.join(", "))?;
+
+                if !kernel_modules {
+                    write!(f, " (without kernel modules)")?;
+                }
+
+                Ok(())
+            }
+            Step::Environment => write!(f, "environment"),
+            Step::KernelModules(names) => write!(f, "kernel modules: {}", names.join(", ")),
+            Step::Checks => write!(f, "checks"),
+            Step::HostOnly => write!(f, "host-only kernel modules"),
+            Step::LoadedModules => write!(f, "loaded kernel modules"),
+            Step::Emergency => write!(f, "emergency shell"),
+            Step::AutoGenerators => write!(f, "default systemd generators"),
+            Step::LdSoConf { ldconfig: false } => write!(f, "ld.so.conf"),
+            Step::LdSoConf { ldconfig: true } => write!(f, "ld.so.conf and ld.so.cache"),
+            Step::XattrScript => write!(f, "xattr script"),
+            Step::InitLint => write!(f, "init lint"),
+        }
+    }
+}
+
+/// Steps derived from a configuration, run in order.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BuildPlan {
+    steps: Vec<Step>,
+}
+
+impl BuildPlan {
+    /// Plan the complete build of a configuration.
+    pub fn from_config(config: &config::Initramfs, modules: &[config::Module]) -> Self {
+        let mut plan = Self::content(config, modules, true);
+        plan.steps.extend(Self::whole(config));
+
+        plan
+    }
+
+    /// Plan the content of a configuration that does not depend on the
+    /// kernel, completed by [`BuildPlan::for_kernel`].
+    pub fn shared(config: &config::Initramfs, modules: &[config::Module]) -> Self {
+        Self::content(config, modules, false)
+    }
+
+    /// Plan the kernel modules left out of [`BuildPlan::shared`], then the
+    /// steps looking at the whole initramfs.
+    pub fn for_kernel(config: &config::Initramfs, modules: &[config::Module]) -> Self {
+        let mut steps = vec![Step::KernelModules(names(modules))];
+        steps.extend(Self::whole(config));
+
+        BuildPlan { steps }
+    }
+
+    /// Create a plan from arbitrary steps.
+    pub fn from_steps(steps: Vec<Step>) -> Self {
+        BuildPlan { steps }
+    }
+
+    /// Get the steps of the plan.
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Check whether any step needs a kernel module context.
+    pub fn needs_kmod(&self) -> bool {
+        self.steps.iter().any(Step::needs_kmod)
+    }
+
+    fn content(
+        config: &config::Initramfs,
+        modules: &[config::Module],
+        kernel_modules: bool,
+    ) -> Self {
+        let mut steps = Vec::new();
+
+        if config.init.is_some() {
+            steps.push(Step::Init);
+        }
+
+        if !config.shutdown.is_empty() {
+            steps.push(Step::Shutdown);
+        }
+
+        steps.push(Step::Modules {
+            names: names(modules),
+            kernel_modules,
+        });
+
+        if !config.environment.is_empty() {
+            steps.push(Step::Environment);
+        }
+
+        BuildPlan { steps }
+    }
+
+    // steps looking at the whole initramfs, once every module is included
+    fn whole(config: &config::Initramfs) -> Vec<Step> {
+        let settings = &config.settings;
+        let mut steps = vec![Step::Checks];
+
+        if settings.host_only {
+            steps.push(Step::HostOnly);
+        }
+
+        if settings.modules_from_lsmod || settings.modules_from_file.is_some() {
+            steps.push(Step::LoadedModules);
+        }
+
+        if settings.emergency != config::Emergency::None {
+            steps.push(Step::Emergency);
+        }
+
+        if settings.auto_generators {
+            steps.push(Step::AutoGenerators);
+        }
+
+        if settings.generate_ld_cache || settings.run_ldconfig {
+            steps.push(Step::LdSoConf {
+                ldconfig: settings.run_ldconfig,
+            });
+        }
+
+        if settings.xattr_script.is_some() {
+            steps.push(Step::XattrScript);
+        }
+
+        if settings.init_lint && config.init.is_some() {
+            steps.push(Step::InitLint);
+        }
+
+        steps
+    }
+}
+
+/// Numbered list of the steps, one per line.
+impl fmt::Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{}. {}", index + 1, step)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn names(modules: &[config::Module]) -> Vec<String> {
+    modules.iter().map(|module| module.name.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let config: config::Initramfs = serde_yaml::from_str(
+            "init: /sbin/init\nsettings:\n  emergency: systemd\n  run_ldconfig: true\n  init_lint: true\nmodules: [base, lvm]\n",
+        )
+        .unwrap();
+        let modules: Vec<config::Module> = ["base", "lvm"]
+            .iter()
+            .map(|name| serde_yaml::from_str(&format!("name: {name}\n")).unwrap())
+            .collect();
+
+        let plan = BuildPlan::from_config(&config, &modules);
+        assert_eq!(
+            plan.to_string(),
+            "1. init\n2. modules: base, lvm\n3. checks\n4. emergency shell\n\
+             5. ld.so.conf and ld.so.cache\n6. init lint\n"
+        );
+        assert!(plan.needs_kmod());
+
+        let shared = BuildPlan::shared(&config, &modules);
+        let kernel = BuildPlan::for_kernel(&config, &modules);
+        assert!(!shared.needs_kmod());
+        assert_eq!(
+            shared.steps().last(),
+            Some(&Step::Modules {
+                names: vec!["base".to_string(), "lvm".to_string()],
+                kernel_modules: false,
+            })
+        );
+        assert_eq!(
+            kernel.steps()[0],
+            Step::KernelModules(vec!["base".to_string(), "lvm".to_string()])
+        );
+        assert_eq!(kernel.steps()[1..], plan.steps()[2..]);
+    }
+}
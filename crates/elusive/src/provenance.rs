@@ -0,0 +1,212 @@
+//! Record why entries are included in the initramfs.
+//!
+//! Whenever the builder adds something, it records an edge from what is being
+//! processed (a configuration module, a systemd unit, an ELF binary, ...) to
+//! the new node, with a short reason such as `DT_NEEDED libcrypto.so.3`. The
+//! chains leading to a node can then be walked back to configuration modules
+//! or other roots.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Separator used when displaying chains.
+pub const CHAIN_SEPARATOR: &str = " → ";
+
+/// Something that can cause other nodes to be included.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Node {
+    /// A configuration module.
+    Module(String),
+    /// A systemd unit.
+    Unit(String),
+    /// A setting pulling content on its own (e.g. host-only detection).
+    Setting(&'static str),
+    /// An entry in the initramfs.
+    Path(PathBuf),
+}
+
+/// Inclusion graph built along with the initramfs.
+#[derive(Clone, Default, Debug)]
+pub struct Provenance {
+    /// Nodes currently being processed, the last one is the parent of new nodes.
+    parents: Vec<Node>,
+    /// Incoming edges of every node, with the reason of each.
+    edges: BTreeMap<Node, BTreeSet<(Option<Node>, String)>>,
+    /// Configuration module that first added each entry.
+    owners: BTreeMap<PathBuf, String>,
+    /// Reason each entry owned by a module was first added for.
+    first_reasons: BTreeMap<PathBuf, String>,
+}
+
+impl Provenance {
+    /// Record that the node is included because of the current parent, if any.
+    pub fn record(&mut self, node: Node, reason: String) {
+        let parent = self.parents.last().cloned();
+
+        if let (Node::Path(path), Some(module)) = (&node, self.module()) {
+            if !self.edges.contains_key(&node) {
+                self.owners.insert(path.clone(), module.to_string());
+                self.first_reasons.insert(path.clone(), reason.clone());
+            }
+        }
+
+        self.edges.entry(node).or_default().insert((parent, reason));
+    }
+
+    /// Make the node the parent of nodes recorded until the matching [`Provenance::pop`].
+    pub fn push(&mut self, node: Node) {
+        self.parents.push(node);
+    }
+
+    /// Restore the previous parent.
+    pub fn pop(&mut self) {
+        self.parents.pop();
+    }
+
+    /// Get the name of the configuration module being processed, if any.
+    pub fn module(&self) -> Option<&str> {
+        self.parents.iter().find_map(|node| match node {
+            Node::Module(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Check if anything was recorded for the node.
+    pub fn contains(&self, node: &Node) -> bool {
+        self.edges.contains_key(node)
+    }
+
+    /// Check if any systemd unit was recorded.
+    pub fn contains_units(&self) -> bool {
+        self.edges.keys().any(|node| matches!(node, Node::Unit(_)))
+    }
+
+    /// Get the configuration module that first added each entry, entries first
+    /// added outside of any module are left out.
+    pub fn owners(&self) -> &BTreeMap<PathBuf, String> {
+        &self.owners
+    }
+
+    /// Get the configuration module that first added an entry and the reason
+    /// it was added for, `None` if it was first added outside of any module.
+    pub fn origin(&self, path: &Path) -> Option<(&str, &str)> {
+        let module = self.owners.get(path)?;
+        let reason = self.first_reasons.get(path)?;
+
+        Some((module, reason))
+    }
+
+    /// Get the reasons the node was directly included for.
+    pub fn reasons(&self, node: &Node) -> impl Iterator<Item = &str> {
+        self.edges
+            .get(node)
+            .into_iter()
+            .flatten()
+            .map(|(_, reason)| reason.as_str())
+    }
+
+    /// Get every chain of reasons leading to the node, starting from roots.
+    pub fn chains(&self, node: &Node) -> BTreeSet<Vec<String>> {
+        let mut visiting = BTreeSet::new();
+        self.walk(node, &mut visiting)
+    }
+
+    fn walk<'a>(
+        &'a self,
+        node: &'a Node,
+        visiting: &mut BTreeSet<&'a Node>,
+    ) -> BTreeSet<Vec<String>> {
+        let mut chains = BTreeSet::new();
+
+        let Some(edges) = self.edges.get(node) else {
+            return chains;
+        };
+
+        // a node reached again through its own dependencies adds nothing
+        if !visiting.insert(node) {
+            return chains;
+        }
+
+        for (parent, reason) in edges {
+            let Some(parent) = parent else {
+                chains.insert(vec![reason.clone()]);
+                continue;
+            };
+
+            for mut chain in self.walk(parent, visiting) {
+                chain.push(reason.clone());
+                chains.insert(chain);
+            }
+        }
+
+        visiting.remove(node);
+        chains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_chains() {
+        let mut provenance = Provenance::default();
+        let libc = Node::Path(PathBuf::from("/usr/lib/libc.so.6"));
+        let ls = Node::Path(PathBuf::from("/usr/bin/ls"));
+
+        provenance.record(Node::Module("base".to_string()), "module base".to_string());
+        provenance.push(Node::Module("base".to_string()));
+        provenance.record(ls.clone(), "binary ls".to_string());
+        provenance.push(ls.clone());
+        assert_eq!(provenance.module(), Some("base"));
+        provenance.record(libc.clone(), "DT_NEEDED libc.so.6".to_string());
+        provenance.pop();
+        provenance.record(libc.clone(), "binary libc.so.6".to_string());
+        provenance.pop();
+        assert_eq!(provenance.module(), None);
+
+        // cycle between the library and the loader
+        let loader = Node::Path(PathBuf::from("/usr/lib/ld-linux-x86-64.so.2"));
+        provenance.push(libc.clone());
+        provenance.record(loader.clone(), "PT_INTERP ld-linux-x86-64.so.2".to_string());
+        provenance.pop();
+        provenance.push(loader.clone());
+        provenance.record(libc.clone(), "DT_NEEDED libc.so.6".to_string());
+        provenance.pop();
+
+        let chains: Vec<_> = provenance
+            .chains(&libc)
+            .into_iter()
+            .map(|chain| chain.join(CHAIN_SEPARATOR))
+            .collect();
+
+        assert_eq!(
+            chains,
+            [
+                "module base → binary libc.so.6",
+                "module base → binary ls → DT_NEEDED libc.so.6",
+            ]
+        );
+        assert!(!provenance.contains(&Node::Path(PathBuf::from("/etc"))));
+
+        // recorded again from another module, still owned by the first one
+        provenance.push(Node::Module("network".to_string()));
+        provenance.record(ls.clone(), "binary ls".to_string());
+        provenance.pop();
+        assert_eq!(
+            provenance.owners().get(Path::new("/usr/bin/ls")),
+            Some(&"base".to_string())
+        );
+        assert_eq!(
+            provenance.origin(Path::new("/usr/bin/ls")),
+            Some(("base", "binary ls"))
+        );
+        assert_eq!(
+            provenance
+                .owners()
+                .get(Path::new("/usr/lib/ld-linux-x86-64.so.2")),
+            None
+        );
+    }
+}
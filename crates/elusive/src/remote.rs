@@ -0,0 +1,259 @@
+//! Files downloaded from remote sources.
+//!
+//! Remote sources are pinned with the SHA-256 digest of their content, which
+//! is checked before anything reaches the initramfs. Downloads are kept in a
+//! cache directory under their digest, so rebuilds do not need the network.
+//!
+//! `file://` URLs are always supported. `http://` and `https://` URLs need
+//! elusive built with the `remote-sources` feature, they honor the usual
+//! proxy environment variables (`HTTPS_PROXY`, `ALL_PROXY`, ...).
+
+use crate::config::Remote;
+use crate::measurement;
+
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Timeout of downloads when none is configured.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Custom error type for remote sources.
+#[derive(thiserror::Error, Debug)]
+pub enum RemoteError {
+    #[error("i/o error: {0}")]
+    InputOutput(io::Error),
+    #[error("unsupported url for remote source: {0}")]
+    UnsupportedUrl(String),
+    #[error("downloading {0} requires elusive built with the remote-sources feature")]
+    Disabled(String),
+    #[error("failed to download {url}: {reason}")]
+    Network { url: String, reason: String },
+    #[error("checksum mismatch for {url}: expected sha256 {expected}, got {actual}")]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl From<io::Error> for RemoteError {
+    fn from(err: io::Error) -> Self {
+        Self::InputOutput(err)
+    }
+}
+
+/// Downloads remote sources into a cache directory.
+#[derive(Clone, Debug)]
+pub struct Fetcher {
+    cache_dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Fetcher {
+    /// Create a fetcher keeping downloads in the provided directory, created
+    /// on the first download.
+    pub fn new(cache_dir: PathBuf) -> Self {
+        Fetcher {
+            cache_dir,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Set the timeout of downloads.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Get the path of the remote source in the cache, downloading it first
+    /// if it is not there yet. Nothing is written to the cache unless the
+    /// content matches the digest.
+    pub fn fetch(&self, remote: &Remote) -> Result<PathBuf, RemoteError> {
+        let path = self.cache_dir.join(&remote.sha256);
+
+        match fs::read(&path) {
+            Ok(data) if digest(&data) == remote.sha256 => {
+                debug!(url = remote.url.as_str(); "Using cached remote source: {}", remote.url);
+                return Ok(path);
+            }
+            Ok(_) => debug!("Replacing corrupted cache entry: {}", path.display()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        debug!(url = remote.url.as_str(); "Downloading remote source: {}", remote.url);
+        let data = download(&remote.url, self.timeout)?;
+
+        let actual = digest(&data);
+        if actual != remote.sha256 {
+            return Err(RemoteError::ChecksumMismatch {
+                url: remote.url.clone(),
+                expected: remote.sha256.clone(),
+                actual,
+            });
+        }
+
+        fs::create_dir_all(&self.cache_dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.cache_dir)?;
+        file.write_all(&data)?;
+
+        // downloads may be binaries, and their modification time would
+        // otherwise depend on when they were downloaded
+        file.as_file()
+            .set_permissions(fs::Permissions::from_mode(0o755))?;
+        file.as_file().set_modified(SystemTime::UNIX_EPOCH)?;
+
+        file.persist(&path).map_err(|err| err.error)?;
+        Ok(path)
+    }
+}
+
+/// Get the default cache directory: `$XDG_CACHE_HOME/elusive`, or
+/// `~/.cache/elusive` when it is not set.
+pub fn default_cache_dir() -> PathBuf {
+    let base = match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+        (Some(cache), _) if !cache.is_empty() => PathBuf::from(cache),
+        (_, Some(home)) => Path::new(&home).join(".cache"),
+        _ => env::temp_dir(),
+    };
+
+    base.join("elusive")
+}
+
+fn digest(data: &[u8]) -> String {
+    measurement::hex(&Sha256::digest(data))
+}
+
+fn download(url: &str, timeout: Duration) -> Result<Vec<u8>, RemoteError> {
+    if let Some(path) = url.strip_prefix("file://") {
+        let network = |err: io::Error| RemoteError::Network {
+            url: url.to_string(),
+            reason: err.to_string(),
+        };
+
+        let mut data = Vec::new();
+        io::copy(&mut File::open(path).map_err(network)?, &mut data).map_err(network)?;
+
+        return Ok(data);
+    }
+
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return http_get(url, timeout);
+    }
+
+    Err(RemoteError::UnsupportedUrl(url.to_string()))
+}
+
+#[cfg(feature = "remote-sources")]
+fn http_get(url: &str, timeout: Duration) -> Result<Vec<u8>, RemoteError> {
+    use std::io::Read;
+
+    let network = |reason: String| RemoteError::Network {
+        url: url.to_string(),
+        reason,
+    };
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .try_proxy_from_env(true)
+        .build();
+
+    let response = agent
+        .get(url)
+        .call()
+        .map_err(|err| network(err.to_string()))?;
+
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .map_err(|err| network(err.to_string()))?;
+
+    Ok(data)
+}
+
+#[cfg(not(feature = "remote-sources"))]
+fn http_get(url: &str, _timeout: Duration) -> Result<Vec<u8>, RemoteError> {
+    Err(RemoteError::Disabled(url.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(url: String, data: &[u8]) -> Remote {
+        Remote {
+            url,
+            sha256: digest(data),
+        }
+    }
+
+    #[test]
+    fn test_fetch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("rescue"), b"rescue").unwrap();
+
+        let fetcher = Fetcher::new(dir.join("cache"));
+        let url = format!("file://{}", dir.join("rescue").display());
+
+        let mismatch = fetcher.fetch(&remote(url.clone(), b"other"));
+        let fetched = fetcher.fetch(&remote(url.clone(), b"rescue"));
+
+        // served from the cache once the source is gone
+        fs::remove_file(dir.join("rescue")).unwrap();
+        let cached = fetcher.fetch(&remote(url.clone(), b"rescue"));
+        let missing = fetcher.fetch(&remote(url, b"other"));
+        let unsupported = fetcher.fetch(&remote("ftp://example.com/a".to_string(), b""));
+        let cache_entries = fs::read_dir(dir.join("cache")).unwrap().count();
+
+        assert!(matches!(
+            mismatch,
+            Err(RemoteError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(fetched.unwrap(), dir.join("cache").join(digest(b"rescue")));
+        assert_eq!(cached.unwrap(), dir.join("cache").join(digest(b"rescue")));
+        assert!(matches!(missing, Err(RemoteError::Network { .. })));
+        assert!(matches!(unsupported, Err(RemoteError::UnsupportedUrl(_))));
+        assert_eq!(cache_entries, 1);
+    }
+
+    #[cfg(feature = "remote-sources")]
+    #[test]
+    fn test_http() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rescue", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            let response =
+                "HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nrescue";
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        });
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let fetcher = Fetcher::new(dir.to_path_buf());
+        let fetched = fetcher.fetch(&remote(url, b"rescue"));
+        server.join().unwrap();
+
+        let data = fs::read(fetched.unwrap()).unwrap();
+        assert_eq!(data, b"rescue");
+    }
+}
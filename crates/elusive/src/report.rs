@@ -0,0 +1,502 @@
+//! Size of the initramfs per configuration module.
+//!
+//! Every entry of the archive is attributed to the configuration module that
+//! first added it, entries added outside of modules (init, skeleton, host-only
+//! detection, parent directories...) are grouped under [`OTHER`] along with
+//! the trailer and padding of the archive. Uncompressed sizes are exact and add
+//! up to the size of the serialized archive.
+//!
+//! Compression works on the whole archive, so compressed sizes are estimates:
+//! the compressed size of the archive is split between modules in proportion
+//! to their uncompressed size, or with [`Attribution::Modules`] in proportion
+//! to the size of each module compressed on its own, which accounts for
+//! content that compresses better or worse than the rest. Either way, the
+//! estimates add up to the compressed size of the archive.
+
+use crate::encoder::{Encoder, EncoderError};
+use crate::io::CountingWriter;
+use crate::library_policy::FilteredLibrary;
+use crate::newc::{Archive, NewcError};
+use crate::payload::PayloadModules;
+use crate::size::Size;
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Name of the group of entries that no configuration module added.
+pub const OTHER: &str = "(other)";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReportError {
+    #[error("unknown size report attribution: {0}")]
+    UnknownAttribution(String),
+    #[error("unknown size report format: {0}")]
+    UnknownFormat(String),
+    #[error("i/o error: {0}")]
+    InputOutput(io::Error),
+    #[error("encoder error: {0}")]
+    Encoder(EncoderError),
+    #[error("cpio error: {0}")]
+    Newc(NewcError),
+}
+
+impl From<io::Error> for ReportError {
+    fn from(err: io::Error) -> Self {
+        ReportError::InputOutput(err)
+    }
+}
+
+impl From<NewcError> for ReportError {
+    fn from(err: NewcError) -> Self {
+        ReportError::Newc(err)
+    }
+}
+
+impl From<EncoderError> for ReportError {
+    fn from(err: EncoderError) -> Self {
+        ReportError::Encoder(err)
+    }
+}
+
+impl ReportError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReportError::UnknownAttribution(_) => "report_unknown_attribution",
+            ReportError::UnknownFormat(_) => "report_unknown_format",
+            ReportError::InputOutput(_) => "io",
+            ReportError::Encoder(_) => "encoder",
+            ReportError::Newc(err) => err.code(),
+        }
+    }
+}
+
+/// How the compressed size of the archive is split between modules.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub enum Attribution {
+    /// In proportion to the uncompressed size of each module.
+    #[default]
+    Proportional,
+    /// In proportion to the size of each module compressed on its own, which
+    /// compresses the archive once more per module.
+    Modules,
+}
+
+impl FromStr for Attribution {
+    type Err = ReportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proportional" => Ok(Attribution::Proportional),
+            "modules" => Ok(Attribution::Modules),
+            other => Err(ReportError::UnknownAttribution(other.to_string())),
+        }
+    }
+}
+
+/// Format of printed size reports.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub enum ReportFormat {
+    /// Aligned columns for humans.
+    #[default]
+    Table,
+    /// JSON document for tooling.
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = ReportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(ReportFormat::Table),
+            "json" => Ok(ReportFormat::Json),
+            other => Err(ReportError::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+/// Size of the entries of a configuration module.
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct ModuleSize {
+    /// Name of the module, [`OTHER`] for entries added outside of modules.
+    pub module: String,
+    /// Number of entries first added by the module.
+    pub entries: usize,
+    /// Bytes taken by the entries in the uncompressed archive.
+    pub uncompressed: u64,
+    /// Estimated bytes taken by the entries in the compressed archive.
+    pub compressed: u64,
+}
+
+/// Size of an archive per configuration module.
+#[derive(Serialize, Debug)]
+pub struct SizeReport {
+    /// Modules sorted by decreasing compressed size.
+    pub modules: Vec<ModuleSize>,
+    /// Size of the uncompressed archive.
+    pub uncompressed: u64,
+    /// Size of the compressed archive.
+    pub compressed: u64,
+    /// Encoder compressing the archive.
+    pub encoder: String,
+    /// Libraries left out of the dependencies of ELF files, see
+    /// [`crate::library_policy`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filtered_libraries: Vec<FilteredLibrary>,
+    /// Payloads and the kernel modules mounting them, see [`crate::payload`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub payloads: Vec<PayloadModules>,
+    /// Size of each module compressed on its own, with [`Attribution::Modules`].
+    #[serde(skip)]
+    weights: Option<Vec<u64>>,
+}
+
+impl SizeReport {
+    /// Start the report of an archive from the module owning each entry (see
+    /// [`Initramfs::module_owners`](crate::initramfs::Initramfs::module_owners)),
+    /// sizes are filled in by [`SizeReport::set_totals`] once the archive is
+    /// written.
+    pub fn new(
+        archive: &Archive,
+        owners: &BTreeMap<PathBuf, String>,
+        attribution: Attribution,
+        encoder: &Encoder,
+    ) -> Result<Self, ReportError> {
+        let sizes = archive.entry_sizes();
+        let mut groups: BTreeMap<&str, BTreeSet<&PathBuf>> = BTreeMap::new();
+
+        for path in sizes.keys() {
+            let module = owners.get(path).map_or(OTHER, String::as_str);
+            groups.entry(module).or_default().insert(path);
+        }
+
+        // the trailer and padding are added to the other entries with the totals
+        groups.entry(OTHER).or_default();
+
+        let modules: Vec<_> = groups
+            .iter()
+            .map(|(module, paths)| ModuleSize {
+                module: module.to_string(),
+                entries: paths.len(),
+                uncompressed: paths.iter().map(|path| sizes[*path]).sum(),
+                compressed: 0,
+            })
+            .collect();
+
+        let weights = match attribution {
+            Attribution::Proportional => None,
+            Attribution::Modules => {
+                let mut weights = Vec::with_capacity(groups.len());
+
+                for paths in groups.values() {
+                    let entries = archive
+                        .entries()
+                        .iter()
+                        .filter(|(path, _)| paths.contains(path))
+                        .cloned();
+
+                    weights.push(compressed_len(Archive::from(entries), encoder)?);
+                }
+
+                Some(weights)
+            }
+        };
+
+        Ok(SizeReport {
+            modules,
+            uncompressed: 0,
+            compressed: 0,
+            encoder: encoder.to_string(),
+            filtered_libraries: Vec::new(),
+            payloads: Vec::new(),
+            weights,
+        })
+    }
+
+    /// Set the size of the archive once serialized and once compressed, and
+    /// split them between modules.
+    pub fn set_totals(&mut self, uncompressed: u64, compressed: u64) {
+        self.modules.sort_by(|l, r| l.module.cmp(&r.module));
+
+        let attributed: u64 = self
+            .modules
+            .iter()
+            .filter(|module| module.module != OTHER)
+            .map(|module| module.uncompressed)
+            .sum();
+        for module in &mut self.modules {
+            if module.module == OTHER {
+                module.uncompressed = uncompressed.saturating_sub(attributed);
+            }
+        }
+
+        let shares = match &self.weights {
+            Some(weights) => apportion(compressed, weights),
+            None => {
+                let weights: Vec<_> = self.modules.iter().map(|m| m.uncompressed).collect();
+                apportion(compressed, &weights)
+            }
+        };
+
+        for (module, share) in self.modules.iter_mut().zip(shares) {
+            module.compressed = share;
+        }
+
+        self.modules.sort_by(|l, r| {
+            r.compressed
+                .cmp(&l.compressed)
+                .then_with(|| l.module.cmp(&r.module))
+        });
+
+        self.uncompressed = uncompressed;
+        self.compressed = compressed;
+    }
+
+    /// Write the report in the given format.
+    pub fn write<W: Write>(&self, format: ReportFormat, mut out: W) -> Result<(), ReportError> {
+        match format {
+            ReportFormat::Table => self.write_table(out)?,
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self).map_err(io::Error::from)?;
+                writeln!(out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_table<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let width = self
+            .modules
+            .iter()
+            .map(|module| module.module.len())
+            .chain(["MODULE".len(), "total".len()])
+            .max()
+            .unwrap_or_default();
+
+        writeln!(
+            out,
+            "{:<width$}  {:>8}  {:>12}  {:>12}",
+            "MODULE", "ENTRIES", "UNCOMPRESSED", "COMPRESSED"
+        )?;
+
+        for module in &self.modules {
+            writeln!(
+                out,
+                "{:<width$}  {:>8}  {:>12}  {:>12}",
+                module.module,
+                module.entries,
+                Size(module.uncompressed).to_string(),
+                Size(module.compressed).to_string(),
+            )?;
+        }
+
+        let entries: usize = self.modules.iter().map(|module| module.entries).sum();
+        writeln!(
+            out,
+            "{:<width$}  {:>8}  {:>12}  {:>12}",
+            "total",
+            entries,
+            Size(self.uncompressed).to_string(),
+            Size(self.compressed).to_string(),
+        )?;
+
+        if !self.filtered_libraries.is_empty() {
+            writeln!(out, "\nLIBRARIES LEFT OUT")?;
+        }
+
+        for filtered in &self.filtered_libraries {
+            writeln!(
+                out,
+                "{} ({}), needed by {}",
+                filtered.name, filtered.pattern, filtered.requested_by
+            )?;
+        }
+
+        if !self.payloads.is_empty() {
+            writeln!(out, "\nPAYLOADS")?;
+        }
+
+        for payload in &self.payloads {
+            let mut modules = payload.modules.clone();
+            modules.extend(
+                payload
+                    .builtin
+                    .iter()
+                    .map(|name| format!("{name} (builtin)")),
+            );
+            modules.extend(
+                payload
+                    .missing
+                    .iter()
+                    .map(|name| format!("{name} (missing)")),
+            );
+
+            writeln!(
+                out,
+                "{} ({}): {}",
+                payload.destination,
+                payload.filesystem,
+                modules.join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// size of the archive once serialized and compressed on its own
+fn compressed_len(archive: Archive, encoder: &Encoder) -> Result<u64, ReportError> {
+    let data = archive.serialize()?;
+    let mut out = CountingWriter::new(io::sink());
+    encoder.encode(&data, &mut out)?;
+
+    Ok(out.count())
+}
+
+// split the total in proportion to the weights, rounding with the largest
+// remainder method so shares add up to the total
+fn apportion(total: u64, weights: &[u64]) -> Vec<u64> {
+    let sum: u128 = weights.iter().map(|&weight| u128::from(weight)).sum();
+
+    if sum == 0 {
+        let mut shares = vec![0; weights.len()];
+        if let Some(first) = shares.first_mut() {
+            *first = total;
+        }
+
+        return shares;
+    }
+
+    let products: Vec<u128> = weights
+        .iter()
+        .map(|&weight| u128::from(total) * u128::from(weight))
+        .collect();
+    let mut shares: Vec<u64> = products
+        .iter()
+        .map(|product| u64::try_from(product / sum).expect("share is at most the total"))
+        .collect();
+
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(products[index] % sum));
+
+    let left = total - shares.iter().sum::<u64>();
+    for index in order
+        .into_iter()
+        .take(usize::try_from(left).unwrap_or(usize::MAX))
+    {
+        shares[index] += 1;
+    }
+
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::Entry;
+
+    #[test]
+    fn test_size_report() {
+        // text compresses well, pseudo-random data does not
+        let text = b"elusive initramfs generator\n".repeat(512);
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..8192)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state.to_le_bytes()[0]
+            })
+            .collect();
+
+        let entries = [
+            (PathBuf::from("/"), Entry::directory()),
+            (PathBuf::from("/etc"), Entry::directory()),
+            (PathBuf::from("/etc/motd"), Entry::file(text)),
+            (PathBuf::from("/usr"), Entry::directory()),
+            (PathBuf::from("/usr/random"), Entry::file(noise)),
+        ];
+        let owners = BTreeMap::from([
+            (PathBuf::from("/etc/motd"), "motd".to_string()),
+            (PathBuf::from("/usr/random"), "random".to_string()),
+        ]);
+
+        let encoder = Encoder::Gzip;
+        let archive = Archive::from(entries.clone());
+        let uncompressed = Archive::from(entries.clone()).serialize().unwrap().len() as u64;
+        let compressed = compressed_len(Archive::from(entries), &encoder).unwrap();
+
+        for attribution in [Attribution::Proportional, Attribution::Modules] {
+            let mut report = SizeReport::new(&archive, &owners, attribution, &encoder).unwrap();
+            report.set_totals(uncompressed, compressed);
+
+            let names: Vec<_> = report.modules.iter().map(|m| m.module.as_str()).collect();
+            assert_eq!(names.len(), 3);
+            assert!(names.contains(&"motd") && names.contains(&"random") && names.contains(&OTHER));
+
+            let sum: u64 = report.modules.iter().map(|m| m.uncompressed).sum();
+            assert_eq!(sum, uncompressed);
+            let sum: u64 = report.modules.iter().map(|m| m.compressed).sum();
+            assert_eq!(sum, compressed);
+
+            let other = report.modules.iter().find(|m| m.module == OTHER).unwrap();
+            assert_eq!(other.entries, 2);
+        }
+
+        // compressed on their own, the random data takes most of the archive
+        let mut report =
+            SizeReport::new(&archive, &owners, Attribution::Modules, &encoder).unwrap();
+        report.set_totals(uncompressed, compressed);
+        assert_eq!(report.modules[0].module, "random");
+        assert_eq!(report.encoder, "gzip");
+
+        let mut table = Vec::new();
+        report.write(ReportFormat::Table, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.starts_with("MODULE"));
+        assert!(table.lines().last().unwrap().starts_with("total"));
+
+        report.filtered_libraries.push(FilteredLibrary {
+            name: "liblz4.so.1".to_string(),
+            pattern: "liblz4*".to_string(),
+            requested_by: "/usr/lib/libsystemd.so.0".to_string(),
+        });
+
+        let mut table = Vec::new();
+        report.write(ReportFormat::Table, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.ends_with(
+            "LIBRARIES LEFT OUT\nliblz4.so.1 (liblz4*), needed by /usr/lib/libsystemd.so.0\n"
+        ));
+
+        report.payloads.push(PayloadModules {
+            destination: "/root.squashfs".to_string(),
+            filesystem: crate::payload::PayloadType::Squashfs,
+            modules: vec!["squashfs".to_string(), "loop".to_string()],
+            builtin: vec!["overlay".to_string()],
+            missing: Vec::new(),
+        });
+
+        let mut table = Vec::new();
+        report.write(ReportFormat::Table, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table
+            .ends_with("PAYLOADS\n/root.squashfs (squashfs): squashfs, loop, overlay (builtin)\n"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["filtered_libraries"][0]["name"], "liblz4.so.1");
+        assert_eq!(json["payloads"][0]["filesystem"], "squashfs");
+    }
+
+    #[test]
+    fn test_apportion() {
+        assert_eq!(apportion(10, &[1, 1, 1]), [4, 3, 3]);
+        assert_eq!(apportion(7, &[0, 0]), [7, 0]);
+        assert_eq!(apportion(100, &[3, 1]), [75, 25]);
+    }
+}
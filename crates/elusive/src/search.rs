@@ -8,3 +8,13 @@ where
     let mut list = paths.iter().map(|path| path.as_ref().join(&name));
     list.find(|path| path.exists())
 }
+
+/// Find every existing path for the name, in the order of search paths.
+pub fn search_all_paths<P, S>(name: P, paths: &[S]) -> Vec<PathBuf>
+where
+    P: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    let list = paths.iter().map(|path| path.as_ref().join(&name));
+    list.filter(|path| path.exists()).collect()
+}
@@ -0,0 +1,268 @@
+//! Detached signatures for generated images.
+//!
+//! Images are signed with Ed25519ph: the output is hashed with SHA-512 while
+//! it is written, and the digest is signed once everything has been written.
+//! Signatures are stored as 64 raw bytes next to the image (`<output>.sig`).
+//!
+//! Keys are read either as PEM (PKCS#8 for private keys, SubjectPublicKeyInfo
+//! for public keys, as produced by `openssl genpkey -algorithm ed25519`) or as
+//! 32 raw bytes.
+
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Extension appended to output paths for detached signatures.
+const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Beginning of PEM encoded keys.
+const PEM_PREFIX: &[u8] = b"-----BEGIN";
+
+/// Custom error type for signing and verification.
+#[derive(thiserror::Error, Debug)]
+pub enum SigningError {
+    #[error("i/o error: {0}")]
+    InputOutput(io::Error),
+    #[error("invalid key {0}: {1}")]
+    InvalidKey(PathBuf, String),
+    #[error("invalid signature {0}: expected {len} bytes", len = Signature::BYTE_SIZE)]
+    InvalidSignature(PathBuf),
+    #[error("signature verification failed")]
+    VerificationFailed,
+}
+
+impl SigningError {
+    /// Get a stable identifier for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SigningError::InputOutput(_) => "signing_io",
+            SigningError::InvalidKey(..) => "signing_invalid_key",
+            SigningError::InvalidSignature(_) => "signing_invalid_signature",
+            SigningError::VerificationFailed => "signing_verification_failed",
+        }
+    }
+}
+
+impl From<io::Error> for SigningError {
+    fn from(err: io::Error) -> Self {
+        Self::InputOutput(err)
+    }
+}
+
+/// Read an ed25519 private key, PEM encoded or raw.
+pub fn read_signing_key<P>(path: P) -> Result<SigningKey, SigningError>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    let invalid = |message: String| SigningError::InvalidKey(path.to_path_buf(), message);
+
+    if data.starts_with(PEM_PREFIX) {
+        let pem = String::from_utf8_lossy(&data);
+        return SigningKey::from_pkcs8_pem(&pem).map_err(|err| invalid(err.to_string()));
+    }
+
+    let bytes = raw_key(&data).ok_or_else(|| invalid(raw_key_message(&data)))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Read an ed25519 public key, PEM encoded or raw.
+pub fn read_verifying_key<P>(path: P) -> Result<VerifyingKey, SigningError>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    let invalid = |message: String| SigningError::InvalidKey(path.to_path_buf(), message);
+
+    if data.starts_with(PEM_PREFIX) {
+        let pem = String::from_utf8_lossy(&data);
+        return VerifyingKey::from_public_key_pem(&pem).map_err(|err| invalid(err.to_string()));
+    }
+
+    let bytes = raw_key(&data).ok_or_else(|| invalid(raw_key_message(&data)))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|err| invalid(err.to_string()))
+}
+
+fn raw_key(data: &[u8]) -> Option<[u8; 32]> {
+    data.try_into().ok()
+}
+
+fn raw_key_message(data: &[u8]) -> String {
+    format!("expected PEM or 32 raw bytes, found {} bytes", data.len())
+}
+
+/// Read a detached signature.
+pub fn read_signature<P>(path: P) -> Result<Signature, SigningError>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+
+    Signature::from_slice(&data).map_err(|_| SigningError::InvalidSignature(path.to_path_buf()))
+}
+
+/// Get the path of the detached signature for an output path.
+pub fn signature_path<P>(path: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    let mut path = OsString::from(path.as_ref());
+    path.push(".");
+    path.push(SIGNATURE_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Sign the digest of the data written through a [`DigestWriter`].
+pub fn sign(key: &SigningKey, digest: Sha512) -> Signature {
+    key.sign_prehashed(digest, None)
+        .expect("signing without context cannot fail")
+}
+
+/// Verify a signature over everything read from the provided reader.
+pub fn verify<R>(
+    key: &VerifyingKey,
+    mut reader: R,
+    signature: &Signature,
+) -> Result<(), SigningError>
+where
+    R: Read,
+{
+    let mut writer = DigestWriter::<_, Sha512>::new(io::sink());
+    io::copy(&mut reader, &mut writer)?;
+
+    let (_, digest) = writer.into_parts();
+    let digest = digest.expect("digest is enabled");
+
+    key.verify_prehashed(digest, None, signature)
+        .map_err(|_| SigningError::VerificationFailed)
+}
+
+/// Compute the digest (SHA-512 unless specified) of data written to the inner
+/// writer.
+pub struct DigestWriter<W, D = Sha512> {
+    inner: W,
+    digest: Option<D>,
+}
+
+impl<W, D> DigestWriter<W, D>
+where
+    D: Digest,
+{
+    /// Wrap a writer, starting from an empty digest.
+    pub fn new(inner: W) -> Self {
+        DigestWriter {
+            inner,
+            digest: Some(D::new()),
+        }
+    }
+
+    /// Wrap a writer without computing anything, when no signature is needed.
+    pub fn disabled(inner: W) -> Self {
+        DigestWriter {
+            inner,
+            digest: None,
+        }
+    }
+
+    /// Get a mutable reference to the inner writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Get back the inner writer and the digest of what was written, if enabled.
+    pub fn into_parts(self) -> (W, Option<D>) {
+        (self.inner, self.digest)
+    }
+}
+
+impl<W, D> Write for DigestWriter<W, D>
+where
+    W: Write,
+    D: Digest,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let written = self.inner.write(buf)?;
+
+        if let Some(digest) = &mut self.digest {
+            digest.update(&buf[..written]);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey, EncodePublicKey};
+
+    #[test]
+    fn test_sign_verify() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        // keys as generated by openssl and as raw bytes
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let private = dir.join("key.pem");
+        let public = dir.join("key.pub");
+        let raw = dir.join("key.raw");
+        fs::write(&private, key.to_pkcs8_pem(LineEnding::LF).unwrap()).unwrap();
+        fs::write(&raw, key.verifying_key().as_bytes()).unwrap();
+        fs::write(
+            &public,
+            key.verifying_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        )
+        .unwrap();
+
+        let key = read_signing_key(&private).unwrap();
+        let public = read_verifying_key(&public).unwrap();
+        assert_eq!(read_verifying_key(&raw).unwrap(), public);
+
+        let image = b"07070100000000000041ed00000000000000000000000000000000".repeat(100);
+        let mut writer = DigestWriter::new(Vec::new());
+        writer.write_all(&image).unwrap();
+
+        let (written, digest) = writer.into_parts();
+        let signature = sign(&key, digest.unwrap());
+        assert_eq!(written, image);
+
+        let path = signature_path(dir.join("initramfs.img"));
+        assert_eq!(path, dir.join("initramfs.img.sig"));
+        fs::write(&path, signature.to_bytes()).unwrap();
+
+        let signature = read_signature(&path).unwrap();
+        assert!(verify(&public, image.as_slice(), &signature).is_ok());
+
+        let mut corrupted = image.clone();
+        corrupted[42] ^= 1;
+        assert!(matches!(
+            verify(&public, corrupted.as_slice(), &signature),
+            Err(SigningError::VerificationFailed)
+        ));
+
+        fs::write(&path, b"short").unwrap();
+        assert!(matches!(
+            read_signature(&path),
+            Err(SigningError::InvalidSignature(_))
+        ));
+        assert!(matches!(
+            read_signing_key(&path),
+            Err(SigningError::InvalidKey(..))
+        ));
+    }
+}
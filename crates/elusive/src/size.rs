@@ -0,0 +1,171 @@
+//! Human readable sizes.
+//!
+//! Sizes can be written as a plain number of bytes or with a unit suffix,
+//! either decimal (`kB`, `MB`, `GB`) or binary (`KiB`, `MiB`, `GiB`).
+
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Units accepted when parsing, matched case insensitively.
+const UNITS: &[(&str, u64)] = &[
+    ("b", 1),
+    ("k", 1 << 10),
+    ("kb", 1000),
+    ("kib", 1 << 10),
+    ("m", 1 << 20),
+    ("mb", 1000 * 1000),
+    ("mib", 1 << 20),
+    ("g", 1 << 30),
+    ("gb", 1000 * 1000 * 1000),
+    ("gib", 1 << 30),
+];
+
+/// Binary units used for display.
+const DISPLAY_UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Custom error type for size parsing.
+#[derive(thiserror::Error, Debug)]
+pub enum SizeError {
+    #[error("invalid size: {0}")]
+    Invalid(String),
+    #[error("unknown size unit '{1}' in: {0}")]
+    UnknownUnit(String, String),
+    #[error("size is too large: {0}")]
+    Overflow(String),
+}
+
+/// A size in bytes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Size(pub u64);
+
+impl Size {
+    /// Get the size in bytes.
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Size {
+    type Err = SizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+
+        let (number, unit) = trimmed.split_at(split);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| SizeError::Invalid(s.to_string()))?;
+
+        let unit = unit.trim();
+        let multiplier = if unit.is_empty() {
+            1
+        } else {
+            UNITS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+                .map(|(_, multiplier)| *multiplier)
+                .ok_or_else(|| SizeError::UnknownUnit(s.to_string(), unit.to_string()))?
+        };
+
+        number
+            .checked_mul(multiplier)
+            .map(Size)
+            .ok_or_else(|| SizeError::Overflow(s.to_string()))
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut unit = 0;
+        let mut divisor = 1;
+
+        while unit + 1 < DISPLAY_UNITS.len() && self.0 >= divisor << 10 {
+            unit += 1;
+            divisor <<= 10;
+        }
+
+        if self.0.is_multiple_of(divisor) {
+            write!(f, "{} {}", self.0 / divisor, DISPLAY_UNITS[unit])
+        } else {
+            #[allow(clippy::cast_precision_loss)]
+            let value = self.0 as f64 / divisor as f64;
+            write!(f, "{value:.1} {}", DISPLAY_UNITS[unit])
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::{Error, Visitor};
+
+        struct SizeVisitor;
+
+        impl Visitor<'_> for SizeVisitor {
+            type Value = Size;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "a number of bytes or a string such as '80MiB'")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                Ok(Size(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                v.parse().map_err(Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fromstr() {
+        assert_eq!("1234".parse::<Size>().unwrap(), Size(1234));
+        assert_eq!("80MiB".parse::<Size>().unwrap(), Size(80 << 20));
+        assert_eq!("80 mib".parse::<Size>().unwrap(), Size(80 << 20));
+        assert_eq!("2M".parse::<Size>().unwrap(), Size(2 << 20));
+        assert_eq!("3kB".parse::<Size>().unwrap(), Size(3000));
+        assert_eq!("1GB".parse::<Size>().unwrap(), Size(1_000_000_000));
+
+        assert!(matches!("".parse::<Size>(), Err(SizeError::Invalid(_))));
+        assert!(matches!("MiB".parse::<Size>(), Err(SizeError::Invalid(_))));
+        assert!(matches!(
+            "1.5MiB".parse::<Size>(),
+            Err(SizeError::UnknownUnit(..))
+        ));
+        assert!(matches!(
+            "80 parsecs".parse::<Size>(),
+            Err(SizeError::UnknownUnit(..))
+        ));
+        assert!(matches!(
+            "99999999999999GiB".parse::<Size>(),
+            Err(SizeError::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Size(512).to_string(), "512 B");
+        assert_eq!(Size(80 << 20).to_string(), "80 MiB");
+        assert_eq!(Size(1536).to_string(), "1.5 KiB");
+    }
+}
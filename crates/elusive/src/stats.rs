@@ -0,0 +1,204 @@
+//! Timing of the phases of a build, to find out what makes it slow.
+//!
+//! The builder records the phases it runs (skeleton, init, configuration
+//! modules, checks), with the time spent in each configuration module split
+//! between ELF files, other files, kernel modules and units. The caller adds
+//! the phases happening once the archive is built (serialization, compression,
+//! output). Times are wall clock and only measured around existing loops, so
+//! recording them is cheap.
+
+use crate::report::{ReportError, ReportFormat};
+use crate::size::Size;
+
+use serde::{Serialize, Serializer};
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Time taken by a phase of the build, with what it produced.
+#[derive(Serialize, Clone, Debug)]
+pub struct PhaseStats {
+    /// Name of the phase.
+    pub name: String,
+    /// Wall time spent in the phase.
+    #[serde(serialize_with = "milliseconds")]
+    pub time: Duration,
+    /// Number of entries added, or written to the archive.
+    pub entries: usize,
+    /// Number of bytes added, or written.
+    pub bytes: u64,
+}
+
+/// Time taken by a configuration module.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct ModuleStats {
+    /// Name of the module.
+    pub module: String,
+    /// Wall time spent in the module.
+    #[serde(serialize_with = "milliseconds")]
+    pub time: Duration,
+    /// Time spent adding binaries and their dependencies.
+    #[serde(serialize_with = "milliseconds")]
+    pub elf: Duration,
+    /// Time spent adding files, symlinks and templates.
+    #[serde(serialize_with = "milliseconds")]
+    pub files: Duration,
+    /// Time spent adding kernel modules and their dependencies.
+    #[serde(serialize_with = "milliseconds")]
+    pub kmod: Duration,
+    /// Time spent adding systemd units and their dependencies.
+    #[serde(serialize_with = "milliseconds")]
+    pub units: Duration,
+    /// Number of entries added.
+    pub entries: usize,
+    /// Number of bytes added.
+    pub bytes: u64,
+}
+
+/// Timing of a build, see the module documentation.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct BuildStats {
+    /// Phases in the order they ran.
+    pub phases: Vec<PhaseStats>,
+    /// Configuration modules in the order they were processed.
+    pub modules: Vec<ModuleStats>,
+}
+
+impl BuildStats {
+    /// Record a phase that just ran.
+    pub fn add_phase(&mut self, name: &str, time: Duration, entries: usize, bytes: u64) {
+        self.phases.push(PhaseStats {
+            name: name.to_string(),
+            time,
+            entries,
+            bytes,
+        });
+    }
+
+    /// Get a phase by name.
+    pub fn phase(&self, name: &str) -> Option<&PhaseStats> {
+        self.phases.iter().find(|phase| phase.name == name)
+    }
+
+    /// Get the time spent in all phases.
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.time).sum()
+    }
+
+    /// Write the statistics in the given format.
+    pub fn write<W: Write>(&self, format: ReportFormat, mut out: W) -> Result<(), ReportError> {
+        match format {
+            ReportFormat::Table => self.write_table(out)?,
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self).map_err(io::Error::from)?;
+                writeln!(out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_table<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let width = self
+            .phases
+            .iter()
+            .map(|phase| phase.name.len())
+            .chain(self.modules.iter().map(|module| module.module.len()))
+            .chain(["PHASE".len(), "MODULE".len()])
+            .max()
+            .unwrap_or_default();
+
+        writeln!(
+            out,
+            "{:<width$}  {:>10}  {:>8}  {:>12}",
+            "PHASE", "TIME", "ENTRIES", "BYTES"
+        )?;
+
+        for phase in &self.phases {
+            writeln!(
+                out,
+                "{:<width$}  {:>10}  {:>8}  {:>12}",
+                phase.name,
+                format_time(phase.time),
+                phase.entries,
+                Size(phase.bytes).to_string()
+            )?;
+        }
+
+        writeln!(
+            out,
+            "{:<width$}  {:>10}",
+            "total",
+            format_time(self.total())
+        )?;
+
+        if self.modules.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<width$}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+            "MODULE", "TIME", "ELF", "FILES", "KMOD", "UNITS"
+        )?;
+
+        for module in &self.modules {
+            writeln!(
+                out,
+                "{:<width$}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+                module.module,
+                format_time(module.time),
+                format_time(module.elf),
+                format_time(module.files),
+                format_time(module.kmod),
+                format_time(module.units)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    format!("{:.1}ms", time.as_secs_f64() * 1000.0)
+}
+
+fn milliseconds<S: Serializer>(time: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(time.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut stats = BuildStats::default();
+        stats.add_phase("skeleton", Duration::from_micros(1500), 12, 0);
+        stats.add_phase("modules", Duration::from_millis(20), 3, 2048);
+        stats.modules.push(ModuleStats {
+            module: "base".to_string(),
+            time: Duration::from_millis(20),
+            elf: Duration::from_millis(15),
+            ..ModuleStats::default()
+        });
+
+        assert_eq!(stats.total(), Duration::from_micros(21500));
+
+        let mut table = Vec::new();
+        stats.write(ReportFormat::Table, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.contains("skeleton       1.5ms"), "{table}");
+        assert!(table.contains("total         21.5ms"), "{table}");
+        assert!(
+            table.contains("base          20.0ms      15.0ms"),
+            "{table}"
+        );
+
+        let mut json = Vec::new();
+        stats.write(ReportFormat::Json, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["phases"][1]["time"], 20.0);
+        assert_eq!(json["modules"][0]["elf"], 15.0);
+    }
+}
@@ -3,23 +3,57 @@
 //! This module is helpful to get dependencies of a unit file, required binaries
 //! executed by services and installation paths for symlink creation.
 
-use crate::search::search_paths;
+use crate::elf::Elf;
+use crate::search::{search_all_paths, search_paths};
 
 use pest::Parser;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{OsStr, OsString};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 use self::parser::{Rule, UnitParser};
 
-const UNIT_SEARCH_PATHS: &[&str] = &[
-    "/usr/lib/systemd/system/",
-    "/etc/systemd/system/",
+/// Directories searched for units, from highest to lowest precedence as done
+/// by systemd: local configuration overrides units shipped by packages.
+pub const UNIT_SEARCH_PATHS: &[&str] = &[
     "/etc/systemd/system.control/",
+    "/etc/systemd/system/",
     "/etc/systemd/system.attached/",
+    "/usr/lib/systemd/system/",
+];
+
+/// Directory systemd runs generators from in the initramfs.
+pub const GENERATOR_DIR: &str = "/usr/lib/systemd/system-generators";
+
+/// Directories searched for generators on the host.
+pub const GENERATOR_SEARCH_PATHS: &[&str] = &[
+    "/usr/lib/systemd/system-generators/",
+    "/lib/systemd/system-generators/",
+];
+
+/// Generators added with `settings.auto_generators` when they exist: without
+/// them, the root filesystem from `/etc/fstab` or the GPT partition types is
+/// never mounted.
+pub const DEFAULT_GENERATORS: &[&str] = &[
+    "systemd-fstab-generator",
+    "systemd-gpt-auto-generator",
+    "systemd-debug-generator",
 ];
 
+/// Keys of the `[Unit]` section whose units are added along with a unit when
+/// no other set is configured: the unit fails to start without them.
+pub const DEFAULT_DEPENDENCY_KEYS: &[&str] = &["Requires", "Requisite", "BindsTo"];
+
+/// Suffixes of dependencies never added: slices are created by systemd, devices
+/// by udev, mounts and swaps by generators from `/etc/fstab` and the kernel
+/// command line.
+const IGNORED_DEPENDENCY_SUFFIXES: &[&str] = &[".slice", ".device", ".mount", ".swap"];
+
+/// Units symlinked to this path are masked.
+const MASKED_TARGET: &str = "/dev/null";
+
 const UNIT_INSTALL_PATHS: &[&str] = &[
     "/usr/lib/systemd/system/initrd.target.wants/",
     "/usr/lib/systemd/system/initrd-root-device.target.wants/",
@@ -29,6 +63,12 @@ const UNIT_INSTALL_PATHS: &[&str] = &[
 
 const SOCKET_INSTALL_PATHS: &[&str] = &["/usr/lib/systemd/system/sockets.target.wants/"];
 
+/// Sections of units running processes, which can read environment files.
+const EXEC_SECTIONS: &[&str] = &["Service", "Socket", "Mount", "Swap"];
+
+/// Keys of the `[Service]` section loading credentials as `ID:PATH`.
+const CREDENTIAL_KEYS: &[&str] = &["LoadCredential", "LoadCredentialEncrypted"];
+
 mod parser {
     use pest_derive::Parser;
 
@@ -46,6 +86,14 @@ pub enum UnitError {
     Parse(Box<pest::error::Error<Rule>>),
     #[error("could not find systemd unit: {0:?}")]
     UnitNotFound(OsString),
+    #[error("systemd unit is masked: {0}")]
+    Masked(PathBuf),
+    #[error("could not find systemd generator: {0}")]
+    GeneratorNotFound(String),
+    #[error("invalid unit pattern {0:?}: {1}")]
+    Pattern(String, glob::PatternError),
+    #[error("no systemd unit found for {0}")]
+    NoMatch(String),
 }
 
 impl From<io::Error> for UnitError {
@@ -65,11 +113,19 @@ impl From<pest::error::Error<Rule>> for UnitError {
 pub struct Unit {
     /// The path of the unit in the filesystem.
     pub path: PathBuf,
+    /// Units with the same name in lower precedence search paths, overridden
+    /// by this one.
+    pub overrides: Vec<PathBuf>,
     /// The raw bytes of the unit file.
     pub data: Vec<u8>,
     /// The binaries required by this unit (non-empty if service, empty otherwise).
     pub binaries: Vec<String>,
-    /// The dependencies of this unit file (Requires=).
+    /// Other files read by the unit at their absolute path (environment
+    /// files, credentials, `ExecStartPre=` scripts), each flagged when the
+    /// unit starts without it.
+    pub required_files: Vec<(PathBuf, bool)>,
+    /// The dependencies of this unit file (Requires=, Requisite= and BindsTo=
+    /// unless configured otherwise), without duplicates.
     pub dependencies: Vec<String>,
     /// The path where a symlink should be created for installation.
     pub install_path: Option<PathBuf>,
@@ -80,9 +136,35 @@ impl Unit {
     pub fn from_name<T>(name: T) -> Result<Self, UnitError>
     where
         T: AsRef<str>,
+    {
+        Self::from_name_in(name, UNIT_SEARCH_PATHS)
+    }
+
+    /// Search and parse a unit file with the given name in the provided
+    /// directories, ordered from highest to lowest precedence.
+    pub fn from_name_in<T, S>(name: T, search_paths: &[S]) -> Result<Self, UnitError>
+    where
+        T: AsRef<str>,
+        S: AsRef<Path>,
+    {
+        Self::from_name_with_keys(name, search_paths, DEFAULT_DEPENDENCY_KEYS)
+    }
+
+    /// Search and parse a unit file with the given name in the provided
+    /// directories, taking its dependencies from the provided keys of the
+    /// `[Unit]` section (e.g. `Requires` or `PartOf`).
+    pub fn from_name_with_keys<T, S, K>(
+        name: T,
+        search_paths: &[S],
+        dependency_keys: &[K],
+    ) -> Result<Self, UnitError>
+    where
+        T: AsRef<str>,
+        S: AsRef<Path>,
+        K: AsRef<str>,
     {
         let name = name.as_ref();
-        let path = Self::find_unit(name)?;
+        let (path, overrides) = Self::find_unit_in(name, search_paths)?;
         let data = fs::read_to_string(&path)?;
 
         let unit = UnitParser::parse(Rule::unit, &data)?
@@ -106,7 +188,13 @@ impl Unit {
 
                     let section = properties.entry(current_section).or_default();
                     let list = section.entry(name).or_default();
-                    list.push(value);
+
+                    // an empty assignment resets the values accumulated so far
+                    if value.is_empty() {
+                        list.clear();
+                    } else {
+                        list.push(value);
+                    }
                 }
                 Rule::EOI => (),
                 other => unreachable!("{other:?}"),
@@ -125,16 +213,61 @@ impl Unit {
             }
         }
 
-        let mut dependencies = Vec::new();
-        let section = properties.get("Unit").expect("Unit section is declared");
+        let mut required_files = Vec::new();
+
+        // a leading dash marks an environment file as optional
+        let environment_files = EXEC_SECTIONS
+            .iter()
+            .filter_map(|section| properties.get(section)?.get("EnvironmentFile"))
+            .flatten();
+        for file in environment_files {
+            match file.strip_prefix('-') {
+                Some(file) => required_files.push((PathBuf::from(file), true)),
+                None => required_files.push((PathBuf::from(file), false)),
+            }
+        }
+
+        if let Some(service) = properties.get("Service") {
+            // credentials without a path or with a relative one come from the
+            // credential store instead
+            let credentials = CREDENTIAL_KEYS
+                .iter()
+                .filter_map(|key| service.get(key))
+                .flatten()
+                .filter_map(|credential| credential.split_once(':'))
+                .map(|(_, path)| Path::new(path))
+                .filter(|path| path.is_absolute());
+            for path in credentials {
+                required_files.push((path.to_path_buf(), false));
+            }
 
-        if let Some(required) = section.get("Requires") {
-            let iter = required[0]
-                .split(' ')
-                .filter(|dep| !dep.ends_with(".slice"))
-                .map(String::from);
+            // scripts are not resolved like ELF binaries, they are copied as is
+            for command in service.get("ExecStartPre").into_iter().flatten() {
+                let path = PathBuf::from(cmd_exec_path(command));
 
-            dependencies.extend(iter);
+                if path.is_absolute() && !is_elf(&path) {
+                    required_files.push((path, false));
+                }
+            }
+        }
+
+        let mut dependencies: Vec<String> = Vec::new();
+
+        if let Some(section) = properties.get("Unit") {
+            let values = dependency_keys
+                .iter()
+                .filter_map(|key| section.get(key.as_ref()))
+                .flatten();
+
+            for dependency in values.flat_map(|value| value.split_whitespace()) {
+                let ignored = IGNORED_DEPENDENCY_SUFFIXES
+                    .iter()
+                    .any(|suffix| dependency.ends_with(suffix));
+
+                if !ignored && !dependencies.iter().any(|dep| dep == dependency) {
+                    dependencies.push(dependency.to_string());
+                }
+            }
         }
 
         let static_paths = match path.extension().and_then(OsStr::to_str) {
@@ -150,8 +283,10 @@ impl Unit {
 
         Ok(Unit {
             path,
+            overrides,
             data: data.into_bytes(),
             binaries,
+            required_files,
             dependencies,
             install_path,
         })
@@ -163,9 +298,137 @@ impl Unit {
     where
         P: AsRef<Path>,
     {
-        search_paths(&name, UNIT_SEARCH_PATHS)
-            .ok_or_else(|| UnitError::UnitNotFound(name.as_ref().into()))
+        Self::find_unit_in(name, UNIT_SEARCH_PATHS).map(|(path, _)| path)
+    }
+
+    /// Search for a unit with the given name in the provided directories and
+    /// return its path along with the paths it overrides. Fails with
+    /// [`UnitError::Masked`] when the unit found first is a symlink to
+    /// `/dev/null`.
+    pub fn find_unit_in<P, S>(
+        name: P,
+        search_paths: &[S],
+    ) -> Result<(PathBuf, Vec<PathBuf>), UnitError>
+    where
+        P: AsRef<Path>,
+        S: AsRef<Path>,
+    {
+        let mut paths = search_all_paths(&name, search_paths).into_iter();
+        let path = paths
+            .next()
+            .ok_or_else(|| UnitError::UnitNotFound(name.as_ref().into()))?;
+
+        if fs::read_link(&path).is_ok_and(|target| target == Path::new(MASKED_TARGET)) {
+            return Err(UnitError::Masked(path));
+        }
+
+        Ok((path, paths.collect()))
+    }
+}
+
+/// Find the names of the units matching a glob pattern in the provided
+/// directories, sorted. Patterns may hold alternatives in braces, e.g.
+/// `systemd-udevd*.{service,socket}`.
+pub fn match_units<S>(pattern: &str, search_paths: &[S]) -> Result<Vec<String>, UnitError>
+where
+    S: AsRef<Path>,
+{
+    let patterns = expand_braces(pattern)
+        .iter()
+        .map(|expanded| glob::Pattern::new(expanded))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| UnitError::Pattern(pattern.to_string(), err))?;
+
+    let names = unit_names(search_paths.iter().map(AsRef::as_ref))
+        .filter(|name| patterns.iter().any(|pattern| pattern.matches(name)))
+        .collect();
+
+    Ok(names)
+}
+
+/// Find the names of the units listed in the `.wants` and `.requires`
+/// directories of a target in the provided directories, sorted.
+pub fn wanted_units<S>(target: &str, search_paths: &[S]) -> Vec<String>
+where
+    S: AsRef<Path>,
+{
+    let dirs = search_paths.iter().flat_map(|path| {
+        let path = path.as_ref();
+        [".wants", ".requires"].map(|suffix| path.join(format!("{target}{suffix}")))
+    });
+
+    unit_names(dirs).collect()
+}
+
+/// Check whether a unit name is a glob pattern rather than a single unit.
+pub fn is_unit_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '[', '{'])
+}
+
+// names of the unit files in the directories, without duplicates, skipping
+// directories such as .wants ones
+fn unit_names<I, P>(dirs: I) -> impl Iterator<Item = String>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let mut names = BTreeSet::new();
+
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            // units in .wants directories are symlinks, .wants directories
+            // themselves are not units
+            if path.is_dir() {
+                continue;
+            }
+
+            if let Some(name) = entry.file_name().to_str() {
+                names.insert(name.to_string());
+            }
+        }
     }
+
+    names.into_iter()
+}
+
+// expand alternatives in braces, which glob patterns do not support
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else {
+        return vec![pattern.to_string()];
+    };
+    let Some(close) = pattern[open..].find('}').map(|index| open + index) else {
+        return vec![pattern.to_string()];
+    };
+
+    let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+    pattern[open + 1..close]
+        .split(',')
+        .flat_map(|alternative| expand_braces(&format!("{prefix}{alternative}{suffix}")))
+        .collect()
+}
+
+/// Find a generator with the given name in the provided directories, ordered
+/// from highest to lowest precedence.
+pub fn find_generator<S>(name: &str, paths: &[S]) -> Result<PathBuf, UnitError>
+where
+    S: AsRef<Path>,
+{
+    search_paths(name, paths).ok_or_else(|| UnitError::GeneratorNotFound(name.to_string()))
+}
+
+// missing or unreadable files are not ELF files
+fn is_elf(path: &Path) -> bool {
+    let mut magic = [0; 4];
+
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|()| Elf::is_elf(&magic))
 }
 
 fn cmd_exec_path<T>(command: T) -> String
@@ -195,4 +458,217 @@ mod tests {
             UnitParser::parse(Rule::unit, &data).unwrap();
         }
     }
+
+    #[test]
+    fn test_precedence() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let local = dir.join("etc");
+        let vendor = dir.join("usr");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(&vendor).unwrap();
+
+        let unit = |description: &str| {
+            format!("[Unit]\nDescription={description}\n\n[Service]\nExecStart=/usr/bin/true\n")
+        };
+
+        fs::write(vendor.join("sample.service"), unit("vendor")).unwrap();
+        fs::write(local.join("sample.service"), unit("local")).unwrap();
+        fs::write(vendor.join("vendor.service"), unit("vendor only")).unwrap();
+        fs::write(vendor.join("masked.service"), unit("masked")).unwrap();
+        symlink(MASKED_TARGET, local.join("masked.service")).unwrap();
+
+        let search_paths = [&local, &vendor];
+        let sample = Unit::from_name_in("sample.service", &search_paths);
+        let only = Unit::from_name_in("vendor.service", &search_paths);
+        let masked = Unit::from_name_in("masked.service", &search_paths);
+        let missing = Unit::from_name_in("missing.service", &search_paths);
+
+        let sample = sample.unwrap();
+        assert_eq!(sample.path, local.join("sample.service"));
+        assert_eq!(sample.overrides, [vendor.join("sample.service")]);
+        assert!(String::from_utf8(sample.data).unwrap().contains("=local"));
+
+        let only = only.unwrap();
+        assert_eq!(only.path, vendor.join("vendor.service"));
+        assert!(only.overrides.is_empty());
+
+        assert!(
+            matches!(masked, Err(UnitError::Masked(path)) if path == local.join("masked.service"))
+        );
+        assert!(matches!(missing, Err(UnitError::UnitNotFound(_))));
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let repeated = "[Unit]\nRequires=a.service  b.service\nRequires=c.service\n\n\
+            [Service]\nExecStart=/usr/bin/true\n\n\
+            [Unit]\nRequires=b.service d.service\n";
+        let reset = "[Unit]\nRequires=a.service\nRequires=\nRequires=e.service\n\
+            BindsTo=f.service\nBindsTo=\n";
+        let directives = "[Unit]\nRequisite=g.service\nBindsTo=h.service dev-sda2.device\n\
+            PartOf=i.target\nRequires=-.slice sysroot.mount dev-sda2.swap g.service\n";
+
+        fs::write(dir.join("repeated.service"), repeated).unwrap();
+        fs::write(dir.join("reset.service"), reset).unwrap();
+        fs::write(dir.join("directives.target"), directives).unwrap();
+
+        let search_paths = [&dir];
+        let dependencies = |name: &str, keys: &[&str]| {
+            Unit::from_name_with_keys(name, &search_paths, keys).map(|unit| unit.dependencies)
+        };
+
+        let repeated = Unit::from_name_in("repeated.service", &search_paths);
+        let reset = Unit::from_name_in("reset.service", &search_paths);
+        let directives = Unit::from_name_in("directives.target", &search_paths);
+        let part_of = dependencies("directives.target", &["PartOf", "Requires"]);
+
+        let repeated = repeated.unwrap();
+        assert_eq!(
+            repeated.dependencies,
+            ["a.service", "b.service", "c.service", "d.service"]
+        );
+        assert_eq!(repeated.binaries, ["/usr/bin/true"]);
+
+        assert_eq!(reset.unwrap().dependencies, ["e.service"]);
+        assert_eq!(directives.unwrap().dependencies, ["g.service", "h.service"]);
+        assert_eq!(part_of.unwrap(), ["i.target", "g.service"]);
+    }
+
+    #[test]
+    fn test_required_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let script = dir.join("prepare.sh");
+        let elf = dir.join("prepare");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::write(&elf, b"\x7fELF\x02\x01\x01").unwrap();
+
+        let service = format!(
+            "[Service]\n\
+             ExecStartPre=-{script}\n\
+             ExecStartPre={elf}\n\
+             ExecStartPre=true\n\
+             ExecStart=/usr/bin/true\n\
+             EnvironmentFile=/etc/default/sample\n\
+             EnvironmentFile=-/etc/default/sample.local\n\
+             LoadCredential=key:/etc/sample/key\n\
+             LoadCredential=store\n\
+             LoadCredentialEncrypted=secret:secret.cred\n\
+             SetCredential=token:value\n",
+            script = script.display(),
+            elf = elf.display(),
+        );
+        fs::write(dir.join("sample.service"), service).unwrap();
+        fs::write(
+            dir.join("sample.socket"),
+            "[Socket]\nListenStream=/run/sample.sock\nEnvironmentFile=/etc/default/socket\n",
+        )
+        .unwrap();
+
+        let service = Unit::from_name_in("sample.service", &[&dir]);
+        let socket = Unit::from_name_in("sample.socket", &[&dir]);
+
+        assert_eq!(
+            service.unwrap().required_files,
+            [
+                (PathBuf::from("/etc/default/sample"), false),
+                (PathBuf::from("/etc/default/sample.local"), true),
+                (PathBuf::from("/etc/sample/key"), false),
+                (script, false),
+            ]
+        );
+        assert_eq!(
+            socket.unwrap().required_files,
+            [(PathBuf::from("/etc/default/socket"), false)]
+        );
+    }
+
+    #[test]
+    fn test_match_units() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let local = dir.join("etc");
+        let vendor = dir.join("usr");
+        fs::create_dir_all(local.join("sysinit.target.requires")).unwrap();
+        fs::create_dir_all(vendor.join("sysinit.target.wants")).unwrap();
+
+        for name in [
+            "systemd-udevd.service",
+            "systemd-udevd-control.socket",
+            "systemd-udevd-kernel.socket",
+            "systemd-udev-trigger.service",
+            "systemd-journald.service",
+        ] {
+            fs::write(vendor.join(name), "[Unit]\n").unwrap();
+        }
+        fs::write(local.join("systemd-udevd.service"), "[Unit]\n").unwrap();
+
+        symlink(
+            "../systemd-udevd.service",
+            vendor.join("sysinit.target.wants/systemd-udevd.service"),
+        )
+        .unwrap();
+        symlink(
+            "../systemd-journald.service",
+            vendor.join("sysinit.target.wants/systemd-journald.service"),
+        )
+        .unwrap();
+        symlink(
+            "/usr/lib/systemd/system/cryptsetup.target",
+            local.join("sysinit.target.requires/cryptsetup.target"),
+        )
+        .unwrap();
+
+        let search_paths = [&local, &vendor];
+        let udevd = match_units("systemd-udevd*.{service,socket}", &search_paths);
+        let sockets = match_units("*.socket", &search_paths);
+        let none = match_units("plymouth*", &search_paths);
+        let invalid = match_units("systemd-[.service", &search_paths);
+        let wanted = wanted_units("sysinit.target", &search_paths);
+        let unwanted = wanted_units("multi-user.target", &search_paths);
+
+        assert_eq!(
+            udevd.unwrap(),
+            [
+                "systemd-udevd-control.socket",
+                "systemd-udevd-kernel.socket",
+                "systemd-udevd.service",
+            ]
+        );
+        assert_eq!(
+            sockets.unwrap(),
+            [
+                "systemd-udevd-control.socket",
+                "systemd-udevd-kernel.socket"
+            ]
+        );
+        assert!(none.unwrap().is_empty());
+        assert!(matches!(invalid, Err(UnitError::Pattern(..))));
+
+        assert_eq!(
+            wanted,
+            [
+                "cryptsetup.target",
+                "systemd-journald.service",
+                "systemd-udevd.service",
+            ]
+        );
+        assert!(unwanted.is_empty());
+
+        assert!(is_unit_pattern("systemd-udevd*"));
+        assert!(!is_unit_pattern("getty@tty1.service"));
+        assert_eq!(
+            expand_braces("a.{b,c}.{d,e}"),
+            ["a.b.d", "a.b.e", "a.c.d", "a.c.e"]
+        );
+    }
 }
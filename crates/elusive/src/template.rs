@@ -0,0 +1,153 @@
+//! Minimal template rendering for small files generated at build time.
+//!
+//! Templates only support `{{name}}` substitution, there are no conditionals
+//! or loops. Variable values are used as is, unless they are prefixed with
+//! `env:` to read an environment variable or `cmd:` to use the output of a
+//! shell command, which has to be explicitly allowed.
+
+use std::collections::BTreeMap;
+use std::process::{Command, ExitStatus};
+use std::{env, io};
+
+/// Prefix for values read from the environment.
+const ENV_PREFIX: &str = "env:";
+/// Prefix for values produced by a shell command.
+const CMD_PREFIX: &str = "cmd:";
+
+/// Custom error type for template rendering.
+#[derive(thiserror::Error, Debug)]
+pub enum TemplateError {
+    #[error("unknown template variable: {0}")]
+    UnknownVariable(String),
+    #[error("unterminated template variable at offset {0}")]
+    Unterminated(usize),
+    #[error("environment variable is not set: {0}")]
+    MissingEnv(String),
+    #[error("commands are not allowed in template variables: {0}")]
+    CommandsDisabled(String),
+    #[error("failed to run command '{0}': {1}")]
+    Command(String, io::Error),
+    #[error("command '{0}' failed: {1}")]
+    CommandFailed(String, ExitStatus),
+}
+
+/// Resolve the value of each variable, see [`resolve`].
+pub fn resolve_vars(
+    vars: &BTreeMap<String, String>,
+    allow_commands: bool,
+) -> Result<BTreeMap<String, String>, TemplateError> {
+    vars.iter()
+        .map(|(name, value)| Ok((name.clone(), resolve(value, allow_commands)?)))
+        .collect()
+}
+
+/// Resolve the value of a variable, reading the environment for `env:VAR` and
+/// running `cmd:...` with `sh -c` when commands are allowed. Trailing newlines
+/// are removed from command output.
+pub fn resolve(value: &str, allow_commands: bool) -> Result<String, TemplateError> {
+    if let Some(name) = value.strip_prefix(ENV_PREFIX) {
+        return env::var(name).map_err(|_| TemplateError::MissingEnv(name.to_string()));
+    }
+
+    if let Some(command) = value.strip_prefix(CMD_PREFIX) {
+        if !allow_commands {
+            return Err(TemplateError::CommandsDisabled(command.to_string()));
+        }
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .output()
+            .map_err(|err| TemplateError::Command(command.to_string(), err))?;
+
+        if !output.status.success() {
+            return Err(TemplateError::CommandFailed(
+                command.to_string(),
+                output.status,
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        return Ok(stdout.trim_end_matches('\n').to_string());
+    }
+
+    Ok(value.to_string())
+}
+
+/// Render a template, replacing every `{{name}}` with the value of the
+/// corresponding variable.
+pub fn render(content: &str, vars: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+    let mut rendered = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+
+        let offset = content.len() - rest.len() + start;
+        let end = rest[start..]
+            .find("}}")
+            .ok_or(TemplateError::Unterminated(offset))?;
+
+        let name = rest[start + 2..start + end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| TemplateError::UnknownVariable(name.to_string()))?;
+
+        rendered.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let vars = BTreeMap::from([
+            ("root_uuid".to_string(), "1234".to_string()),
+            ("name".to_string(), "root".to_string()),
+        ]);
+
+        assert_eq!(
+            render("{{name}} UUID={{ root_uuid }} none luks\n", &vars).unwrap(),
+            "root UUID=1234 none luks\n"
+        );
+        assert_eq!(render("no variables", &vars).unwrap(), "no variables");
+
+        assert!(matches!(
+            render("{{missing}}", &vars),
+            Err(TemplateError::UnknownVariable(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            render("a {{name", &vars),
+            Err(TemplateError::Unterminated(2))
+        ));
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("literal", false).unwrap(), "literal");
+        assert_eq!(
+            resolve("env:PATH", false).unwrap(),
+            env::var("PATH").unwrap()
+        );
+        assert!(matches!(
+            resolve("env:ELUSIVE_TEMPLATE_UNSET", false),
+            Err(TemplateError::MissingEnv(_))
+        ));
+
+        assert!(matches!(
+            resolve("cmd:echo hello", false),
+            Err(TemplateError::CommandsDisabled(_))
+        ));
+        assert_eq!(resolve("cmd:echo hello", true).unwrap(), "hello");
+        assert!(matches!(
+            resolve("cmd:false", true),
+            Err(TemplateError::CommandFailed(..))
+        ));
+    }
+}
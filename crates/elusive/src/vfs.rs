@@ -1,29 +1,67 @@
 //! Simple / naive implementation of a virtual filesystem.
 //!
 //! This VFS is used to back initramfs and microcode archive generation to avoid
-//! copying files on disk or in tmpfs.
+//! copying files on disk or in tmpfs. Entries are created at [`ImagePath`]s,
+//! which cannot be relative or contain `..`.
 
-use std::collections::btree_map::IntoIter;
-use std::collections::BTreeMap;
-use std::io;
+use crate::paths::{escaped, ImagePath};
+use crate::size::Size;
+
+use log::debug;
+
+use std::collections::btree_map::{IntoIter, Iter};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fmt;
 use std::io::Read;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::{fs, io, iter};
+
+/// Number of entries listed when the total size limit is exceeded.
+const LARGEST_ENTRIES: usize = 5;
 
 const DIRECTORY_MODE: u32 = 0o040_755;
 const FILE_MODE: u32 = 0o100_644;
 const SYMLINK_MODE: u32 = 0o120_000;
 
+/// Bits of the mode holding the file type.
+const TYPE_MASK: u32 = 0o170_000;
+
+/// Maximum number of symlinks followed when resolving a path.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
 /// Error returned by VFS.
 #[derive(thiserror::Error, Debug)]
 pub enum VfsError {
-    #[error("no such file or directory: {0}")]
+    #[error("no such file or directory: {}", escaped(.0))]
     NoSuchFileOrDirectory(PathBuf),
-    #[error("not a directory: {0}")]
+    #[error("not a directory: {}", escaped(.0))]
     NotADirectory(PathBuf),
-    #[error("file already exists: {0}")]
+    #[error("file already exists: {}", escaped(.0))]
     FileExists(PathBuf),
+    #[error("path escapes the target directory: {}", escaped(.0))]
+    OutsideRoot(PathBuf),
+    #[error(
+        "adding {} would bring the content to {total}, over max_total_size ({limit}); largest entries so far: {}",
+        escaped(.path),
+        largest_entries(.largest)
+    )]
+    TotalSizeExceeded {
+        path: PathBuf,
+        total: Size,
+        limit: Size,
+        largest: Vec<(PathBuf, Size)>,
+    },
+    #[error("i/o error: {0}")]
+    InputOutput(io::Error),
+}
+
+impl From<io::Error> for VfsError {
+    fn from(err: io::Error) -> Self {
+        Self::InputOutput(err)
+    }
 }
 
 /// Representation for VFS entry metadata.
@@ -49,6 +87,23 @@ pub struct Metadata {
     pub rdev_minor: u64,
 }
 
+impl Metadata {
+    /// Get all metadata fields along with their names.
+    pub fn fields(&self) -> [(&'static str, u64); 9] {
+        [
+            ("mode", u64::from(self.mode)),
+            ("uid", self.uid),
+            ("gid", self.gid),
+            ("nlink", self.nlink),
+            ("mtime", self.mtime),
+            ("dev_major", self.dev_major),
+            ("dev_minor", self.dev_minor),
+            ("rdev_major", self.rdev_major),
+            ("rdev_minor", self.rdev_minor),
+        ]
+    }
+}
+
 /// A VFS entry.
 #[derive(Clone, PartialEq, Default, Debug)]
 pub struct Entry {
@@ -64,6 +119,7 @@ impl Entry {
         Entry {
             metadata: Metadata {
                 mode: DIRECTORY_MODE,
+                nlink: 2,
                 ..Default::default()
             },
             data: None,
@@ -75,6 +131,7 @@ impl Entry {
         Entry {
             metadata: Metadata {
                 mode: FILE_MODE,
+                nlink: 1,
                 ..Default::default()
             },
             data: Some(data),
@@ -91,6 +148,7 @@ impl Entry {
         Entry {
             metadata: Metadata {
                 mode: SYMLINK_MODE,
+                nlink: 1,
                 ..Default::default()
             },
             data: Some(data),
@@ -99,17 +157,17 @@ impl Entry {
 
     /// Check if the entry is a directory.
     pub fn is_dir(&self) -> bool {
-        self.metadata.mode == DIRECTORY_MODE
+        self.metadata.mode & TYPE_MASK == DIRECTORY_MODE & TYPE_MASK
     }
 
     /// Check if the entry is a normal file.
     pub fn is_file(&self) -> bool {
-        self.metadata.mode == FILE_MODE
+        self.metadata.mode & TYPE_MASK == FILE_MODE & TYPE_MASK
     }
 
     /// Check if the entry is a symlink.
     pub fn is_symlink(&self) -> bool {
-        self.metadata.mode == SYMLINK_MODE
+        self.metadata.mode & TYPE_MASK == SYMLINK_MODE & TYPE_MASK
     }
 }
 
@@ -122,10 +180,7 @@ impl TryFrom<std::fs::File> for Entry {
         let mut entry = Entry {
             metadata: Metadata {
                 mode: metadata.mode(),
-                mtime: metadata
-                    .mtime()
-                    .try_into()
-                    .expect("timetstamp does not fit in a u64"),
+                mtime: clamp_mtime(metadata.mtime()),
                 rdev_major: major(metadata.rdev()),
                 rdev_minor: minor(metadata.rdev()),
                 ..Default::default()
@@ -144,9 +199,185 @@ impl TryFrom<std::fs::File> for Entry {
     }
 }
 
+/// A difference between two sets of entries.
+#[derive(Clone, PartialEq, Debug)]
+pub enum DiffEntry {
+    /// The path only exists on the left side.
+    MissingRight(PathBuf),
+    /// The path only exists on the right side.
+    MissingLeft(PathBuf),
+    /// A metadata field differs.
+    Metadata {
+        path: PathBuf,
+        field: &'static str,
+        left: u64,
+        right: u64,
+    },
+    /// The content differs, lengths may be equal.
+    Content {
+        path: PathBuf,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffEntry::MissingRight(path) => write!(f, "{}: missing on right side", path.display()),
+            DiffEntry::MissingLeft(path) => write!(f, "{}: missing on left side", path.display()),
+            DiffEntry::Metadata {
+                path,
+                field: "mode",
+                left,
+                right,
+            } => write!(
+                f,
+                "{}: mode differs ({left:o} != {right:o})",
+                path.display()
+            ),
+            DiffEntry::Metadata {
+                path,
+                field,
+                left,
+                right,
+            } => write!(f, "{}: {field} differs ({left} != {right})", path.display()),
+            DiffEntry::Content { path, left, right } if left == right => {
+                write!(f, "{}: content differs", path.display())
+            }
+            DiffEntry::Content { path, left, right } => write!(
+                f,
+                "{}: content length differs ({left} != {right})",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Compute the differences between two sets of entries, sorted by path.
+pub(crate) fn diff_entries<'a, L, R>(left: L, right: R) -> Vec<DiffEntry>
+where
+    L: IntoIterator<Item = (&'a PathBuf, &'a Entry)>,
+    R: IntoIterator<Item = (&'a PathBuf, &'a Entry)>,
+{
+    let left: BTreeMap<_, _> = left.into_iter().collect();
+    let mut right: BTreeMap<_, _> = right.into_iter().collect();
+    let mut diff = Vec::new();
+
+    for (path, l) in left {
+        let Some(r) = right.remove(path) else {
+            diff.push(DiffEntry::MissingRight(path.clone()));
+            continue;
+        };
+
+        let fields = l.metadata.fields().into_iter();
+        for ((field, left), (_, right)) in fields.zip(r.metadata.fields()) {
+            if left != right {
+                diff.push(DiffEntry::Metadata {
+                    path: path.clone(),
+                    field,
+                    left,
+                    right,
+                });
+            }
+        }
+
+        if l.data != r.data {
+            diff.push(DiffEntry::Content {
+                path: path.clone(),
+                left: l.data.as_ref().map_or(0, Vec::len),
+                right: r.data.as_ref().map_or(0, Vec::len),
+            });
+        }
+    }
+
+    diff.extend(
+        right
+            .into_keys()
+            .map(|path| DiffEntry::MissingLeft(path.clone())),
+    );
+    diff.sort_by(|l, r| l.path().cmp(r.path()));
+    diff
+}
+
+impl DiffEntry {
+    /// Get the path this difference applies to.
+    pub fn path(&self) -> &Path {
+        match self {
+            DiffEntry::MissingRight(path) | DiffEntry::MissingLeft(path) => path,
+            DiffEntry::Metadata { path, .. } | DiffEntry::Content { path, .. } => path,
+        }
+    }
+}
+
+/// A symlink whose final target is not in the VFS.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DanglingSymlink {
+    /// Path of the symlink.
+    pub path: PathBuf,
+    /// Target of the symlink, as stored.
+    pub target: PathBuf,
+    /// Path the symlink resolves to, `None` if resolution loops.
+    pub resolved: Option<PathBuf>,
+}
+
+impl fmt::Display for DanglingSymlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (path, target) = (self.path.display(), self.target.display());
+
+        match &self.resolved {
+            Some(resolved) => write!(
+                f,
+                "dangling symlink {path} -> {target}: {} does not exist",
+                resolved.display()
+            ),
+            None => write!(
+                f,
+                "dangling symlink {path} -> {target}: too many levels of symbolic links"
+            ),
+        }
+    }
+}
+
+/// Why a path does not resolve to an entry of the VFS, see [`Vfs::lookup`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Unresolved {
+    /// The path resolves to a missing entry, `missing` being the first of its
+    /// ancestors, or itself, not in the VFS.
+    Missing { resolved: PathBuf, missing: PathBuf },
+    /// Symlinks are left after too many hops.
+    Loop(PathBuf),
+}
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unresolved::Missing { resolved, missing } if resolved == missing => {
+                write!(f, "{} does not exist", escaped(resolved))
+            }
+            Unresolved::Missing { resolved, missing } => write!(
+                f,
+                "{} does not exist, {} is missing",
+                escaped(resolved),
+                escaped(missing)
+            ),
+            Unresolved::Loop(path) => {
+                write!(f, "too many levels of symbolic links at {}", escaped(path))
+            }
+        }
+    }
+}
+
 /// Virtual filesystem.
+#[derive(Clone)]
 pub struct Vfs {
     inner: BTreeMap<PathBuf, Entry>,
+    // directories created with the default mode, replaced by the first entry
+    // with real metadata
+    placeholders: HashSet<PathBuf>,
+    // sum of the data lengths of all entries
+    data_size: u64,
+    max_data_size: Option<u64>,
 }
 
 impl Vfs {
@@ -155,7 +386,33 @@ impl Vfs {
         let mut map = BTreeMap::new();
         map.insert(PathBuf::from("/"), Entry::directory());
 
-        Vfs { inner: map }
+        Vfs {
+            inner: map,
+            placeholders: HashSet::from([PathBuf::from("/")]),
+            data_size: 0,
+            max_data_size: None,
+        }
+    }
+
+    /// Set the limit of the total size of the data of all entries, checked
+    /// when entries are created. Metadata is not counted.
+    pub fn set_max_data_size(&mut self, limit: Option<Size>) {
+        self.max_data_size = limit.map(Size::bytes);
+    }
+
+    /// Get the total size of the data of all entries.
+    pub fn data_size(&self) -> Size {
+        Size(self.data_size)
+    }
+
+    /// Get the number of entries.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Check whether the VFS has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
     }
 
     /// Check the VFS has an entry at the given path.
@@ -166,6 +423,14 @@ impl Vfs {
         self.inner.contains_key(path.as_ref())
     }
 
+    /// Get the entry at the given path, symlinks are not followed.
+    pub fn get<P>(&self, path: P) -> Option<&Entry>
+    where
+        P: AsRef<Path>,
+    {
+        self.inner.get(path.as_ref())
+    }
+
     /// Check the VFS contains a directory at given path.
     pub fn contains_dir<P>(&self, path: P) -> bool
     where
@@ -178,6 +443,15 @@ impl Vfs {
         false
     }
 
+    /// Check the VFS contains a directory at given path that was created
+    /// implicitly, with the default mode.
+    pub fn is_placeholder<P>(&self, path: P) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        self.placeholders.contains(path.as_ref()) && self.contains_dir(path)
+    }
+
     /// Check the VFS contains a file at given path.
     pub fn contains_file<P>(&self, path: P) -> bool
     where
@@ -190,12 +464,138 @@ impl Vfs {
         false
     }
 
-    /// Create a directory entry in the VFS.
-    pub fn create_dir<P>(&mut self, path: P) -> Result<(), VfsError>
+    /// Resolve symlinks found in the parent directories of the provided path, the
+    /// same way they would be once the archive is unpacked by the kernel.
+    pub fn resolve_parent<P>(&self, path: P) -> PathBuf
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
+
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => self.resolve_dir(parent, 0).join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Resolve symlinks found in every component of the provided path, including
+    /// the last one. A path that is still a symlink was left unresolved after
+    /// too many hops.
+    pub fn resolve<P>(&self, path: P) -> PathBuf
+    where
+        P: AsRef<Path>,
+    {
+        self.resolve_dir(path.as_ref(), 0)
+    }
+
+    /// Get the entry a path resolves to once every symlink is followed, along
+    /// with the resolved path, the way the kernel would once the archive is
+    /// unpacked.
+    pub fn lookup<P>(&self, path: P) -> Result<(PathBuf, &Entry), Unresolved>
+    where
+        P: AsRef<Path>,
+    {
+        let resolved = self.resolve(path);
+
+        match self.inner.get(&resolved) {
+            Some(entry) if entry.is_symlink() => Err(Unresolved::Loop(resolved)),
+            Some(entry) => Ok((resolved, entry)),
+            None => {
+                let missing = resolved
+                    .ancestors()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .find(|ancestor| !self.inner.contains_key(*ancestor))
+                    .unwrap_or(&resolved)
+                    .to_path_buf();
+
+                Err(Unresolved::Missing { resolved, missing })
+            }
+        }
+    }
+
+    /// Find symlinks that do not resolve to an existing entry.
+    pub fn dangling_symlinks(&self) -> Vec<DanglingSymlink> {
+        let mut dangling = Vec::new();
+
+        for (path, entry) in &self.inner {
+            if !entry.is_symlink() {
+                continue;
+            }
+
+            let resolved = match self.lookup(path) {
+                Ok(_) => continue,
+                Err(Unresolved::Missing { resolved, .. }) => Some(resolved),
+                Err(Unresolved::Loop(_)) => None,
+            };
+
+            let target = OsStr::from_bytes(entry.data.as_deref().unwrap_or_default());
+            dangling.push(DanglingSymlink {
+                path: path.clone(),
+                target: PathBuf::from(target),
+                resolved,
+            });
+        }
+
+        dangling
+    }
+
+    fn resolve_dir(&self, path: &Path, depth: usize) -> PathBuf {
+        let mut resolved = PathBuf::from("/");
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => continue,
+            }
+
+            if depth >= MAX_SYMLINK_DEPTH {
+                continue;
+            }
+
+            let target = match self.inner.get(&resolved) {
+                Some(entry) if entry.is_symlink() => entry.data.as_deref().unwrap_or_default(),
+                _ => continue,
+            };
+
+            resolved.pop();
+            let target = resolved.join(OsStr::from_bytes(target));
+            resolved = self.resolve_dir(&target, depth + 1);
+        }
+
+        resolved
+    }
+
+    /// Compute the differences between this VFS and another one.
+    pub fn diff(&self, other: &Vfs) -> Vec<DiffEntry> {
+        diff_entries(&self.inner, &other.inner)
+    }
+
+    /// Iterate over all entries in the VFS, sorted by path.
+    pub fn iter(&self) -> Iter<'_, PathBuf, Entry> {
+        self.inner.iter()
+    }
+
+    /// Create a directory entry in the VFS.
+    pub fn create_dir(&mut self, path: &ImagePath) -> Result<(), VfsError> {
+        self.create_dir_with_mode(path, None)
+    }
+
+    /// Create a directory entry in the VFS, see [`Vfs::create_dir`]. Without a
+    /// mode, the directory is a placeholder with the default mode until an
+    /// entry with real metadata replaces it. A mode given for an existing
+    /// placeholder upgrades it.
+    pub fn create_dir_with_mode(
+        &mut self,
+        path: &ImagePath,
+        mode: Option<u32>,
+    ) -> Result<(), VfsError> {
+        let path = path.as_path();
         if let Some(parent) = path.parent() {
             if !self.contains(parent) {
                 return Err(VfsError::NoSuchFileOrDirectory(parent.into()));
@@ -207,55 +607,257 @@ impl Vfs {
             }
         }
 
-        if let Some(entry) = self.inner.get(path) {
-            // should check symlink target, for now be lazy
-            if entry.is_dir() || entry.is_symlink() {
-                return Ok(());
-            }
-
+        if let Some(entry) = self.inner.get_mut(path) {
             if entry.is_file() {
                 return Err(VfsError::FileExists(path.into()));
             }
+
+            if let Some(mode) = mode.filter(|_| self.placeholders.remove(path)) {
+                entry.metadata.mode = directory_mode(mode);
+            }
+
+            // should check symlink target, for now be lazy
+            return Ok(());
+        }
+
+        let mut entry = Entry::directory();
+        match mode {
+            Some(mode) => entry.metadata.mode = directory_mode(mode),
+            None => {
+                self.placeholders.insert(path.into());
+            }
         }
 
-        self.inner.insert(path.into(), Entry::directory());
+        self.inner.insert(path.into(), entry);
         Ok(())
     }
 
     /// Recursively create directories in the VFS.
-    pub fn create_dir_all<P>(&mut self, path: P) -> Result<(), VfsError>
-    where
-        P: AsRef<Path>,
-    {
-        let path = path.as_ref();
-        if self.contains_dir(path) {
+    pub fn create_dir_all(&mut self, path: &ImagePath) -> Result<(), VfsError> {
+        self.create_dir_all_with_mode(path, None)
+    }
+
+    /// Recursively create directories in the VFS, see
+    /// [`Vfs::create_dir_with_mode`]. The mode only applies to the last
+    /// directory, missing parents are placeholders.
+    pub fn create_dir_all_with_mode(
+        &mut self,
+        path: &ImagePath,
+        mode: Option<u32>,
+    ) -> Result<(), VfsError> {
+        if self.contains_dir(path) && (mode.is_none() || !self.is_placeholder(path)) {
             return Ok(());
         }
 
-        let ancestors: Vec<&Path> = path.ancestors().collect();
+        let ancestors: Vec<ImagePath> =
+            iter::successors(path.parent(), ImagePath::parent).collect();
         for dir in ancestors.iter().rev() {
             self.create_dir(dir)?;
         }
 
+        self.create_dir_with_mode(path, mode)
+    }
+
+    /// Create an entry in the VFS. An entry with real metadata replaces a
+    /// placeholder directory.
+    ///
+    /// Fails if the data of the entry would bring the total over the limit set
+    /// with [`Vfs::set_max_data_size`].
+    pub fn create_entry(&mut self, path: &ImagePath, entry: Entry) -> Result<(), VfsError> {
+        let path = path.as_path();
+
+        if self.contains_file(path) {
+            return Err(VfsError::FileExists(path.into()));
+        }
+
+        let replaced = self.inner.get(path).map_or(0, data_len);
+        let total = self.data_size - replaced + data_len(&entry);
+
+        if let Some(limit) = self.max_data_size.filter(|&limit| total > limit) {
+            return Err(VfsError::TotalSizeExceeded {
+                path: path.into(),
+                total: Size(total),
+                limit: Size(limit),
+                largest: self.largest_entries(),
+            });
+        }
+
+        self.data_size = total;
+        self.placeholders.remove(path);
+        self.inner.insert(path.into(), entry);
         Ok(())
     }
 
-    /// Create an entry in the VFS.
-    pub fn create_entry<P>(&mut self, path: P, entry: Entry) -> Result<(), VfsError>
+    // entries with the most data, largest first
+    fn largest_entries(&self) -> Vec<(PathBuf, Size)> {
+        let mut entries: Vec<_> = self
+            .inner
+            .iter()
+            .filter(|(_, entry)| data_len(entry) > 0)
+            .map(|(path, entry)| (path.clone(), Size(data_len(entry))))
+            .collect();
+
+        entries.sort_by(|l, r| r.1.cmp(&l.1).then_with(|| l.0.cmp(&r.0)));
+        entries.truncate(LARGEST_ENTRIES);
+        entries
+    }
+
+    /// Remove the entry at the given path, without its descendants.
+    pub fn remove_entry<P>(&mut self, path: P) -> Option<Entry>
     where
         P: AsRef<Path>,
     {
-        let path = path.as_ref();
+        self.placeholders.remove(path.as_ref());
 
-        if self.contains_file(path) {
-            return Err(VfsError::FileExists(path.into()));
+        let entry = self.inner.remove(path.as_ref())?;
+        self.data_size -= data_len(&entry);
+        Some(entry)
+    }
+
+    /// Remove directories without any descendant other than empty directories,
+    /// except the root and the protected paths, returning the number of
+    /// directories removed.
+    pub fn prune_empty_dirs<P>(&mut self, protected: &[P]) -> usize
+    where
+        P: AsRef<Path>,
+    {
+        let protected: HashSet<&Path> = protected.iter().map(AsRef::as_ref).collect();
+        let mut non_empty: HashSet<PathBuf> = HashSet::new();
+        let mut empty = Vec::new();
+
+        // descendants sort after their parent, walking backwards visits them first
+        for (path, entry) in self.inner.iter().rev() {
+            let keep = !entry.is_dir()
+                || path == Path::new("/")
+                || protected.contains(path.as_path())
+                || non_empty.contains(path);
+
+            if !keep {
+                empty.push(path.clone());
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                non_empty.insert(parent.to_path_buf());
+            }
+        }
+
+        for path in &empty {
+            self.inner.remove(path);
+            self.placeholders.remove(path);
+        }
+
+        empty.len()
+    }
+}
+
+impl Vfs {
+    /// Write the content of the VFS to the provided directory on disk, applying
+    /// modes and modification times. Special files are created with mknod(2),
+    /// which usually requires elevated privileges.
+    ///
+    /// Entries already on disk are replaced, except directories which are
+    /// kept. Symlinks on disk are never followed when replacing them.
+    pub fn write_to_dir<P>(&self, root: P) -> Result<(), VfsError>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref();
+        fs::create_dir_all(root)?;
+        let root = root.canonicalize()?;
+
+        for (path, entry) in &self.inner {
+            let relative = path.strip_prefix("/").unwrap_or(path);
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+
+            if relative
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                return Err(VfsError::OutsideRoot(path.clone()));
+            }
+
+            // parent directories may be symlinks, make sure they resolve under root
+            let dest = root.join(relative);
+            let parent = dest.parent().expect("path is under root").canonicalize()?;
+            if !parent.starts_with(&root) {
+                return Err(VfsError::OutsideRoot(path.clone()));
+            }
+
+            let dest = parent.join(dest.file_name().expect("path has a file name"));
+            let mode = entry.metadata.mode;
+            let data = entry.data.as_deref().unwrap_or_default();
+
+            // lstat so that a symlink left on disk is replaced, not written through
+            let existing = match fs::symlink_metadata(&dest) {
+                Ok(metadata) => Some(metadata.file_type()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+
+            let keep =
+                existing.is_some_and(|ty| ty.is_dir()) && mode & libc::S_IFMT == libc::S_IFDIR;
+            match existing {
+                Some(_) if keep => (),
+                Some(ty) if ty.is_dir() => fs::remove_dir_all(&dest)?,
+                Some(_) => fs::remove_file(&dest)?,
+                None => (),
+            }
+
+            match mode & libc::S_IFMT {
+                libc::S_IFDIR => {
+                    if !keep {
+                        fs::create_dir(&dest)?;
+                    }
+                }
+                libc::S_IFREG => fs::write(&dest, data)?,
+                libc::S_IFLNK => symlink(OsStr::from_bytes(data), &dest)?,
+                _ => {
+                    let dev = libc::makedev(
+                        entry.metadata.rdev_major as u32,
+                        entry.metadata.rdev_minor as u32,
+                    );
+
+                    let cstring =
+                        CString::new(dest.as_os_str().as_bytes()).map_err(io::Error::from)?;
+                    let ret = unsafe { libc::mknod(cstring.as_ptr(), mode, dev) };
+
+                    if ret < 0 {
+                        return Err(io::Error::last_os_error().into());
+                    }
+                }
+            }
+
+            if mode & libc::S_IFMT != libc::S_IFLNK {
+                fs::set_permissions(&dest, fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+
+        // set times last, as creating children updates directory mtimes
+        for (path, entry) in self.inner.iter().rev() {
+            let relative = path.strip_prefix("/").unwrap_or(path);
+            set_mtime(&root.join(relative), entry.metadata.mtime)?;
         }
 
-        self.inner.insert(path.into(), entry);
         Ok(())
     }
 }
 
+fn data_len(entry: &Entry) -> u64 {
+    entry.data.as_ref().map_or(0, |data| data.len() as u64)
+}
+
+fn largest_entries(entries: &[(PathBuf, Size)]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|(path, size)| format!("{} ({size})", escaped(path)))
+        .collect();
+
+    entries.join(", ")
+}
+
 impl Default for Vfs {
     fn default() -> Self {
         Self::new()
@@ -271,7 +873,44 @@ impl IntoIterator for Vfs {
     }
 }
 
+// modification times before the epoch are not representable in an archive
+fn clamp_mtime(mtime: i64) -> u64 {
+    u64::try_from(mtime).unwrap_or_else(|_| {
+        debug!("Negative modification time {}, using 0", mtime);
+        0
+    })
+}
+
+fn set_mtime(path: &Path, mtime: u64) -> Result<(), io::Error> {
+    let cstring = CString::new(path.as_os_str().as_bytes())?;
+    let time = libc::timespec {
+        tv_sec: mtime.try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: 0,
+    };
+
+    let times = [time, time];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            cstring.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 // shamelessly taken from the `nix` crate !
+// keep the permission bits of a mode read from the host, as a directory
+const fn directory_mode(mode: u32) -> u32 {
+    (DIRECTORY_MODE & TYPE_MASK) | (mode & !TYPE_MASK)
+}
+
 const fn major(dev: u64) -> u64 {
     ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff)
 }
@@ -280,3 +919,372 @@ const fn major(dev: u64) -> u64 {
 const fn minor(dev: u64) -> u64 {
     ((dev >> 12) & 0xffff_ff00) | ((dev) & 0x0000_00ff)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newc::Archive;
+
+    use walkdir::WalkDir;
+
+    fn image(path: &str) -> ImagePath {
+        ImagePath::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_clamp_mtime() {
+        assert_eq!(clamp_mtime(-1), 0);
+        assert_eq!(clamp_mtime(i64::MIN), 0);
+        assert_eq!(clamp_mtime(0), 0);
+        assert_eq!(clamp_mtime(i64::MAX), i64::MAX as u64);
+    }
+
+    #[test]
+    fn test_write_to_dir() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/usr/bin")).unwrap();
+
+        let mut hello = Entry::file(b"hello".to_vec());
+        hello.metadata.mode = 0o100_750;
+        hello.metadata.mtime = 1_000_000;
+        vfs.create_entry(&image("/usr/bin/hello"), hello).unwrap();
+        vfs.create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        vfs.write_to_dir(root).unwrap();
+
+        let mut unpacked = Vfs::new();
+        for entry in WalkDir::new(root).min_depth(1) {
+            let entry = entry.unwrap();
+            let path = Path::new("/").join(entry.path().strip_prefix(root).unwrap());
+
+            let entry = if entry.path_is_symlink() {
+                // permissions of a symlink are meaningless, keep only its mtime
+                let metadata = fs::symlink_metadata(entry.path()).unwrap();
+                let mut link = Entry::symlink(fs::read_link(entry.path()).unwrap());
+                link.metadata.mtime = clamp_mtime(metadata.mtime());
+                link
+            } else {
+                Entry::try_from(fs::File::open(entry.path()).unwrap()).unwrap()
+            };
+
+            let path = ImagePath::new(path).unwrap();
+            unpacked.create_entry(&path, entry).unwrap();
+        }
+
+        // the archive built from the same tree is what the directory stands for
+        let data = Archive::from(vfs.clone()).serialize().unwrap();
+        let archive = Archive::deserialize(&data).unwrap();
+
+        let expected: Vec<_> = archive
+            .entries()
+            .iter()
+            .map(|(path, entry)| {
+                let metadata = &entry.metadata;
+                (path, metadata.mode, metadata.mtime, &entry.data)
+            })
+            .collect();
+        let actual: Vec<_> = unpacked
+            .iter()
+            .filter(|(path, _)| *path != Path::new("/"))
+            .map(|(path, entry)| {
+                let metadata = &entry.metadata;
+                (path, metadata.mode, metadata.mtime, &entry.data)
+            })
+            .collect();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_write_to_dir_replace() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/etc")).unwrap();
+        vfs.create_dir_all(&image("/usr/bin")).unwrap();
+
+        let mut script = Entry::file(b"#!/bin/sh\n".to_vec());
+        script.metadata.mode = 0o100_750;
+        script.metadata.mtime = 1_000_000;
+        vfs.create_entry(&image("/usr/bin/hello"), script).unwrap();
+        vfs.create_entry(&image("/etc/passwd"), Entry::file(b"root".to_vec()))
+            .unwrap();
+        vfs.create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let root = dir.join("root");
+        let outside = dir.join("outside");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+
+        // leftovers of a previous run, one of them pointing out of the root
+        symlink(outside.join("passwd"), root.join("etc/passwd")).unwrap();
+        fs::create_dir_all(root.join("bin")).unwrap();
+
+        vfs.write_to_dir(&root).unwrap();
+
+        let passwd = fs::symlink_metadata(root.join("etc/passwd")).unwrap();
+        let hello = fs::metadata(root.join("usr/bin/hello")).unwrap();
+        let bin = fs::read_link(root.join("bin")).unwrap();
+        let escaped = outside.join("passwd").exists();
+
+        assert!(passwd.is_file());
+        assert_eq!(passwd.permissions().mode() & 0o7777, 0o644);
+        assert_eq!(hello.permissions().mode(), 0o100_750);
+        assert_eq!(hello.mtime(), 1_000_000);
+        assert_eq!(bin, Path::new("usr/bin"));
+        assert!(!escaped);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut left = Vfs::new();
+        left.create_dir_all(&image("/etc")).unwrap();
+        left.create_entry(&image("/etc/hosts"), Entry::file(b"localhost".to_vec()))
+            .unwrap();
+        left.create_entry(&image("/etc/motd"), Entry::file(b"hi".to_vec()))
+            .unwrap();
+
+        let mut right = Vfs::new();
+        right.create_dir_all(&image("/etc")).unwrap();
+        right
+            .create_entry(&image("/etc/hosts"), Entry::file(b"127.0.0.1".to_vec()))
+            .unwrap();
+        right
+            .create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+            .unwrap();
+
+        let mut motd = Entry::file(b"hello".to_vec());
+        motd.metadata.uid = 1000;
+        right.create_entry(&image("/etc/motd"), motd).unwrap();
+
+        assert!(left.diff(&left).is_empty());
+
+        let diff = left.diff(&right);
+        let lines: Vec<_> = diff.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "/bin: missing on left side",
+                "/etc/hosts: content differs",
+                "/etc/motd: uid differs (0 != 1000)",
+                "/etc/motd: content length differs (2 != 5)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_dir_with_mode() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/etc/ssl/private")).unwrap();
+        assert!(vfs.is_placeholder("/etc/ssl"));
+
+        // a mode upgrades a placeholder, once
+        vfs.create_dir_all_with_mode(&image("/etc/ssl/private"), Some(0o040_700))
+            .unwrap();
+        vfs.create_dir_with_mode(&image("/etc/ssl/private"), Some(0o040_755))
+            .unwrap();
+        vfs.create_dir_all_with_mode(&image("/run/lock"), Some(0o1777))
+            .unwrap();
+
+        let mut ssl = Entry::directory();
+        ssl.metadata.mode = 0o040_750;
+        vfs.create_entry(&image("/etc/ssl"), ssl).unwrap();
+
+        let modes: BTreeMap<_, _> = vfs
+            .iter()
+            .map(|(path, entry)| (path.to_str().unwrap(), entry.metadata.mode))
+            .collect();
+        assert_eq!(modes["/etc/ssl"], 0o040_750);
+        assert_eq!(modes["/etc/ssl/private"], 0o040_700);
+        assert_eq!(modes["/run/lock"], 0o041_777);
+        assert!(vfs.is_placeholder("/run"));
+        assert!(!vfs.is_placeholder("/etc/ssl"));
+        assert!(!vfs.is_placeholder("/etc/ssl/private"));
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/usr/lib/modules/6.1.0/kernel"))
+            .unwrap();
+        vfs.create_dir_all(&image("/usr/lib/firmware")).unwrap();
+        vfs.create_dir_all(&image("/usr/bin")).unwrap();
+        vfs.create_entry(&image("/usr/bin/sh"), Entry::file(Vec::new()))
+            .unwrap();
+        vfs.create_dir_all(&image("/etc")).unwrap();
+        vfs.create_dir_all(&image("/tmp")).unwrap();
+        vfs.create_entry(&image("/lib"), Entry::symlink("usr/lib"))
+            .unwrap();
+
+        assert_eq!(vfs.prune_empty_dirs(&["/etc", "/tmp"]), 5);
+
+        let paths: Vec<_> = vfs.iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            paths,
+            [
+                "/",
+                "/etc",
+                "/lib",
+                "/tmp",
+                "/usr",
+                "/usr/bin",
+                "/usr/bin/sh"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_parent() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/usr/lib")).unwrap();
+        vfs.create_entry(&image("/lib64"), Entry::symlink("usr/lib"))
+            .unwrap();
+        vfs.create_entry(&image("/usr/lib64"), Entry::symlink("lib"))
+            .unwrap();
+        vfs.create_entry(&image("/loop"), Entry::symlink("loop"))
+            .unwrap();
+
+        assert_eq!(
+            vfs.resolve_parent("/lib64/ld-linux-x86-64.so.2"),
+            Path::new("/usr/lib/ld-linux-x86-64.so.2")
+        );
+        assert_eq!(
+            vfs.resolve_parent("/usr/lib64/libc.so.6"),
+            Path::new("/usr/lib/libc.so.6")
+        );
+        assert_eq!(vfs.resolve_parent("/lib64"), Path::new("/lib64"));
+        assert_eq!(vfs.resolve_parent("/loop/file"), Path::new("/loop/file"));
+    }
+
+    #[test]
+    fn test_dangling_symlinks() {
+        let mut vfs = Vfs::new();
+        vfs.create_dir_all(&image("/usr/bin")).unwrap();
+        vfs.create_entry(&image("/usr/bin/busybox"), Entry::file(Vec::new()))
+            .unwrap();
+        vfs.create_entry(&image("/bin"), Entry::symlink("usr/bin"))
+            .unwrap();
+
+        // relative and absolute, through a symlinked directory
+        vfs.create_entry(&image("/usr/bin/sh"), Entry::symlink("busybox"))
+            .unwrap();
+        vfs.create_entry(&image("/usr/bin/ash"), Entry::symlink("/bin/busybox"))
+            .unwrap();
+        vfs.create_entry(&image("/usr/bin/bash"), Entry::symlink("../share/bash"))
+            .unwrap();
+
+        // chained
+        vfs.create_entry(&image("/first"), Entry::symlink("second"))
+            .unwrap();
+        vfs.create_entry(&image("/second"), Entry::symlink("/bin/sh"))
+            .unwrap();
+        vfs.create_entry(&image("/broken"), Entry::symlink("usr/bin/bash"))
+            .unwrap();
+
+        // looping
+        vfs.create_entry(&image("/ping"), Entry::symlink("pong"))
+            .unwrap();
+        vfs.create_entry(&image("/pong"), Entry::symlink("/ping"))
+            .unwrap();
+
+        assert_eq!(vfs.resolve("/first"), Path::new("/usr/bin/busybox"));
+        assert_eq!(
+            vfs.lookup("/first").unwrap().0,
+            Path::new("/usr/bin/busybox")
+        );
+        assert_eq!(
+            vfs.lookup("/bin/share/bash").unwrap_err(),
+            Unresolved::Missing {
+                resolved: PathBuf::from("/usr/bin/share/bash"),
+                missing: PathBuf::from("/usr/bin/share"),
+            }
+        );
+        assert_eq!(
+            vfs.lookup("/broken").unwrap_err().to_string(),
+            "/usr/share/bash does not exist, /usr/share is missing"
+        );
+        assert_eq!(
+            vfs.lookup("/usr/bin/zsh").unwrap_err().to_string(),
+            "/usr/bin/zsh does not exist"
+        );
+        assert_eq!(
+            vfs.lookup("/ping").unwrap_err(),
+            Unresolved::Loop(PathBuf::from("/ping"))
+        );
+        assert_eq!(
+            vfs.dangling_symlinks(),
+            [
+                DanglingSymlink {
+                    path: PathBuf::from("/broken"),
+                    target: PathBuf::from("usr/bin/bash"),
+                    resolved: Some(PathBuf::from("/usr/share/bash")),
+                },
+                DanglingSymlink {
+                    path: PathBuf::from("/ping"),
+                    target: PathBuf::from("pong"),
+                    resolved: None,
+                },
+                DanglingSymlink {
+                    path: PathBuf::from("/pong"),
+                    target: PathBuf::from("/ping"),
+                    resolved: None,
+                },
+                DanglingSymlink {
+                    path: PathBuf::from("/usr/bin/bash"),
+                    target: PathBuf::from("../share/bash"),
+                    resolved: Some(PathBuf::from("/usr/share/bash")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_outside_root() {
+        let mut vfs = Vfs::new();
+        vfs.create_entry(&image("/escape"), Entry::symlink("/tmp"))
+            .unwrap();
+        vfs.create_entry(&image("/escape/file"), Entry::file(Vec::new()))
+            .unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let result = vfs.write_to_dir(root);
+
+        assert!(matches!(result, Err(VfsError::OutsideRoot(_))));
+    }
+
+    #[test]
+    fn test_max_data_size() {
+        let mut vfs = Vfs::new();
+        vfs.set_max_data_size(Some(Size(10)));
+        vfs.create_entry(&image("/small"), Entry::file(vec![0; 4]))
+            .unwrap();
+        vfs.create_entry(&image("/large"), Entry::file(vec![0; 6]))
+            .unwrap();
+        assert_eq!(vfs.data_size(), Size(10));
+
+        let err = vfs
+            .create_entry(&image("/extra"), Entry::file(vec![0; 1]))
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(matches!(
+            err,
+            VfsError::TotalSizeExceeded {
+                total: Size(11),
+                limit: Size(10),
+                ..
+            }
+        ));
+        assert!(message.contains("/extra"), "{message}");
+        assert!(message.contains("/large (6 B), /small (4 B)"), "{message}");
+
+        vfs.remove_entry("/large");
+        vfs.create_entry(&image("/extra"), Entry::file(vec![0; 1]))
+            .unwrap();
+        assert_eq!(vfs.data_size(), Size(5));
+    }
+}
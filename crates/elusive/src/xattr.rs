@@ -0,0 +1,276 @@
+//! Extended attributes of files added to the initramfs.
+//!
+//! The newc format cannot carry extended attributes, so file capabilities,
+//! SELinux labels and IMA signatures of the host files are lost once the
+//! archive is extracted. When configured, they are collected as files are
+//! added and written out as a shell script restoring them, meant to be run
+//! early by the init:
+//!
+//! - `security.selinux` labels are restored with `chcon`,
+//! - everything else with `setfattr`, values being base64 encoded so binary
+//!   data such as `security.capability` is restored byte for byte (`setcap`
+//!   cannot express the root id of namespaced capabilities).
+
+use crate::paths::ImagePath;
+
+use base64ct::{Base64, Encoding};
+
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+/// Attribute restored with `chcon` rather than `setfattr`.
+pub const SELINUX: &str = "security.selinux";
+
+/// Extended attributes of files, keyed by their path in the initramfs.
+#[derive(Clone, Default, Debug)]
+pub struct Xattrs {
+    files: BTreeMap<ImagePath, BTreeMap<OsString, Vec<u8>>>,
+}
+
+impl Xattrs {
+    /// Read the extended attributes of a host file, without following
+    /// symlinks, and record them for its path in the initramfs.
+    pub fn collect(&mut self, source: &Path, dest: &ImagePath) -> io::Result<()> {
+        let mut attributes = BTreeMap::new();
+
+        for name in list(source)? {
+            // the attribute may have been removed since it was listed
+            if let Some(value) = get(source, &name)? {
+                attributes.insert(name, value);
+            }
+        }
+
+        if attributes.is_empty() {
+            self.files.remove(dest);
+        } else {
+            self.files.insert(dest.clone(), attributes);
+        }
+
+        Ok(())
+    }
+
+    /// Record an attribute of a file in the initramfs.
+    pub fn insert(&mut self, dest: ImagePath, name: impl Into<OsString>, value: Vec<u8>) {
+        self.files
+            .entry(dest)
+            .or_default()
+            .insert(name.into(), value);
+    }
+
+    /// Whether no attributes were collected.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Generate the POSIX shell script restoring the collected attributes,
+    /// sorted by path then by attribute name.
+    pub fn script(&self) -> Vec<u8> {
+        let mut script =
+            b"#!/bin/sh\n# restore extended attributes lost in the newc archive\n".to_vec();
+
+        for (path, attributes) in &self.files {
+            for (name, value) in attributes {
+                if name.as_bytes() == SELINUX.as_bytes() {
+                    // labels are NUL terminated strings
+                    let label = value.strip_suffix(b"\0").unwrap_or(value);
+                    script.extend_from_slice(b"chcon ");
+                    script.extend_from_slice(&quote(label));
+                } else {
+                    script.extend_from_slice(b"setfattr -h -n ");
+                    script.extend_from_slice(&quote(name.as_bytes()));
+                    script.extend_from_slice(b" -v 0s");
+                    script.extend_from_slice(Base64::encode_string(value).as_bytes());
+                }
+
+                script.push(b' ');
+                script.extend_from_slice(&quote(path.as_os_str().as_bytes()));
+                script.push(b'\n');
+            }
+        }
+
+        script
+    }
+}
+
+/// Quote a word for a POSIX shell. Single quotes leave everything verbatim
+/// but single quotes themselves, which are closed, escaped and reopened.
+pub fn quote(word: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(word.len() + 2);
+    quoted.push(b'\'');
+
+    for &byte in word {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+
+    quoted.push(b'\'');
+    quoted
+}
+
+/// List the names of the extended attributes of a file, without following
+/// symlinks. Filesystems without support for them have none.
+pub fn list(path: &Path) -> io::Result<Vec<OsString>> {
+    let path = c_path(path)?;
+
+    loop {
+        let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        let size = match size {
+            0 => return Ok(Vec::new()),
+            size if size < 0 => return unsupported_or(io::Error::last_os_error(), Vec::new()),
+            size => size as usize,
+        };
+
+        let mut buffer = vec![0u8; size];
+        let ret = unsafe { libc::llistxattr(path.as_ptr(), buffer.as_mut_ptr().cast(), size) };
+
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+
+            // attributes were added in between the two calls
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+
+            return unsupported_or(err, Vec::new());
+        }
+
+        buffer.truncate(ret as usize);
+        return Ok(buffer
+            .split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| OsString::from_vec(name.to_vec()))
+            .collect());
+    }
+}
+
+/// Read the value of an extended attribute of a file, without following
+/// symlinks, or `None` if it does not have it.
+pub fn get(path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+    let path = c_path(path)?;
+    let name = CString::new(name.as_bytes()).map_err(io::Error::from)?;
+
+    loop {
+        let size =
+            unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return unsupported_or(io::Error::last_os_error(), None);
+        }
+
+        let mut buffer = vec![0u8; size as usize];
+        let ret = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        };
+
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+
+            // the value grew in between the two calls
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+
+            return unsupported_or(err, None);
+        }
+
+        buffer.truncate(ret as usize);
+        return Ok(Some(buffer));
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::from)
+}
+
+// missing attributes and filesystems without support are not errors
+fn unsupported_or<T>(err: io::Error, value: T) -> io::Result<T> {
+    match err.raw_os_error() {
+        Some(libc::ENOTSUP | libc::ENODATA) => Ok(value),
+        _ => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn image(path: &str) -> ImagePath {
+        ImagePath::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_script() {
+        let mut xattrs = Xattrs::default();
+        xattrs.insert(
+            image("/usr/bin/ping"),
+            "security.capability",
+            vec![0, 0, 0, 2, 0, 0x20, 0, 0],
+        );
+        xattrs.insert(
+            image("/usr/bin/ping"),
+            SELINUX,
+            b"system_u:object_r:ping_exec_t:s0\0".to_vec(),
+        );
+        xattrs.insert(image("/etc/it's a file"), "security.ima", vec![3, 2, 0xff]);
+
+        assert_eq!(
+            String::from_utf8(xattrs.script()).unwrap(),
+            "#!/bin/sh\n\
+             # restore extended attributes lost in the newc archive\n\
+             setfattr -h -n 'security.ima' -v 0sAwL/ '/etc/it'\\''s a file'\n\
+             setfattr -h -n 'security.capability' -v 0sAAAAAgAgAAA= '/usr/bin/ping'\n\
+             chcon 'system_u:object_r:ping_exec_t:s0' '/usr/bin/ping'\n"
+        );
+    }
+
+    #[test]
+    fn test_collect() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let path = dir.join("a \"quoted\" 'file'; rm -rf");
+        fs::write(&path, b"").unwrap();
+
+        let name = CString::new("user.elusive").unwrap();
+        let c_path = c_path(&path).unwrap();
+        let ret = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                b"\0\x01".as_ptr().cast(),
+                2,
+                0,
+            )
+        };
+        let supported = ret == 0;
+
+        let mut xattrs = Xattrs::default();
+        let dest = image("/etc/a \"quoted\" 'file'; rm -rf");
+        let collected = xattrs.collect(&path, &dest);
+
+        collected.unwrap();
+        if !supported {
+            // user attributes are not supported by this filesystem
+            assert!(xattrs.is_empty());
+            return;
+        }
+
+        let script = String::from_utf8(xattrs.script()).unwrap();
+        assert_eq!(
+            script.lines().last().unwrap(),
+            r#"setfattr -h -n 'user.elusive' -v 0sAAE= '/etc/a "quoted" '\''file'\''; rm -rf'"#
+        );
+    }
+}
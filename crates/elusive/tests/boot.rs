@@ -0,0 +1,62 @@
+//! Boot generated images in QEMU, see `common` for requirements.
+
+mod common;
+
+use elusive::elf::Elf;
+use elusive::encoder::Encoder;
+use elusive::newc::Archive;
+
+use std::path::Path;
+
+fn boot_with(encoder: Encoder) {
+    if !common::enabled() {
+        eprintln!("ELUSIVE_QEMU_TESTS is not set, skipping boot test");
+        return;
+    }
+
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    let initrd = dir.join("initramfs.img");
+    common::write_image(
+        common::initramfs(common::shim(common::SHIM_OK)),
+        &encoder,
+        &initrd,
+    );
+
+    let status = common::boot(&common::kernel(), &initrd, common::BOOT_TIMEOUT);
+
+    let status = status.expect("qemu should exit before the timeout");
+    assert_eq!(status.code(), Some(common::exit_status(common::SHIM_OK)));
+}
+
+#[test]
+fn test_shim_image() {
+    let shim = common::shim(common::SHIM_OK);
+    assert!(Elf::is_elf(&shim));
+    assert_eq!(Elf::interpreter_from_data(&shim).unwrap(), None);
+
+    let data = common::initramfs(shim.clone())
+        .into_archive()
+        .serialize()
+        .unwrap();
+    let archive = Archive::deserialize(&data).unwrap();
+
+    let (_, init) = archive
+        .entries()
+        .iter()
+        .find(|(path, _)| path == Path::new("/init"))
+        .unwrap();
+    assert_eq!(init.data.as_deref(), Some(shim.as_slice()));
+    assert_eq!(init.metadata.mode, 0o100_755);
+}
+
+#[test]
+fn test_boot_gzip() {
+    boot_with(Encoder::Gzip);
+}
+
+#[test]
+fn test_boot_zstd() {
+    boot_with(Encoder::Zstd);
+}
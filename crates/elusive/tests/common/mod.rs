@@ -0,0 +1,158 @@
+//! Helpers to assemble initramfs images and boot them in QEMU.
+//!
+//! Boot tests only run when `ELUSIVE_QEMU_TESTS=1` is set, they need
+//! `qemu-system-x86_64` and a kernel image whose path is given by
+//! `ELUSIVE_QEMU_KERNEL`. The kernel needs `CONFIG_X86_IOPL_IOPERM` and the
+//! decompressor of the tested encoder built in.
+//!
+//! The image `/init` is a tiny static executable (the shim) that writes its
+//! status code to the `isa-debug-exit` device, QEMU then exits with
+//! `(code << 1) | 1`.
+
+#![allow(dead_code)]
+
+use elusive::config::Mode;
+use elusive::encoder::Encoder;
+use elusive::initramfs::Initramfs;
+use elusive::paths::ImagePath;
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+/// Status code written by the shim when it runs.
+pub const SHIM_OK: u8 = 0x10;
+
+/// Port of the `isa-debug-exit` device.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+/// Address the shim is loaded at.
+const SHIM_BASE: u64 = 0x40_0000;
+/// Size of the ELF header followed by a single program header.
+const SHIM_HEADERS: u64 = 64 + 56;
+
+/// Default time given to QEMU to boot the image and run init.
+pub const BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Check boot tests were requested.
+pub fn enabled() -> bool {
+    env::var("ELUSIVE_QEMU_TESTS").is_ok_and(|value| value == "1")
+}
+
+/// Get the kernel image to boot.
+pub fn kernel() -> PathBuf {
+    env::var_os("ELUSIVE_QEMU_KERNEL")
+        .map(PathBuf::from)
+        .expect("ELUSIVE_QEMU_KERNEL should point to a kernel image")
+}
+
+/// Exit status of QEMU once the guest wrote the provided code.
+pub fn exit_status(code: u8) -> i32 {
+    (i32::from(code) << 1) | 1
+}
+
+/// Build the shim: a static x86_64 executable that gets access to the debug
+/// exit port, writes the status code to it, then spins.
+pub fn shim(code: u8) -> Vec<u8> {
+    let [port_low, port_high] = DEBUG_EXIT_PORT.to_le_bytes();
+
+    #[rustfmt::skip]
+    let text = [
+        0xb8, 0xad, 0x00, 0x00, 0x00,          // mov eax, 173 (ioperm)
+        0xbf, port_low, port_high, 0x00, 0x00, // mov edi, port
+        0xbe, 0x04, 0x00, 0x00, 0x00,          // mov esi, 4
+        0xba, 0x01, 0x00, 0x00, 0x00,          // mov edx, 1
+        0x0f, 0x05,                            // syscall
+        0x66, 0xba, port_low, port_high,       // mov dx, port
+        0xb0, code,                            // mov al, code
+        0xee,                                  // out dx, al
+        0xeb, 0xfe,                            // jmp .
+    ];
+
+    let size = SHIM_HEADERS + text.len() as u64;
+    let mut elf = Vec::new();
+
+    // ELF header: 64-bit, little endian, executable for x86_64
+    elf.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes());
+    elf.extend_from_slice(&0x3eu16.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&(SHIM_BASE + SHIM_HEADERS).to_le_bytes());
+    elf.extend_from_slice(&64u64.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    for half in [64u16, 56, 1, 0, 0, 0] {
+        elf.extend_from_slice(&half.to_le_bytes());
+    }
+
+    // program header: the whole file loaded readable and executable
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&5u32.to_le_bytes());
+    for word in [0, SHIM_BASE, SHIM_BASE, size, size, 0x1000] {
+        elf.extend_from_slice(&word.to_le_bytes());
+    }
+
+    elf.extend_from_slice(&text);
+    elf
+}
+
+/// Assemble an initramfs holding the provided init, without reading any
+/// configuration file.
+pub fn initramfs(init: Vec<u8>) -> Initramfs {
+    let mut initramfs = Initramfs::new().unwrap();
+    initramfs
+        .add_content_file(&ImagePath::new("/init").unwrap(), init, Mode(0o755))
+        .unwrap();
+
+    initramfs
+}
+
+/// Serialize and encode the initramfs, then write it to the provided path.
+pub fn write_image(initramfs: Initramfs, encoder: &Encoder, path: &Path) {
+    let archive = initramfs.into_archive().serialize().unwrap();
+
+    let mut image = Vec::new();
+    encoder.encode(&archive, &mut image).unwrap();
+    fs::write(path, image).unwrap();
+}
+
+/// Boot the kernel with the provided initrd, killing QEMU once the timeout
+/// expires. Returns `None` on timeout.
+pub fn boot(kernel: &Path, initrd: &Path, timeout: Duration) -> Option<ExitStatus> {
+    let machine = if Path::new("/dev/kvm").exists() {
+        "q35,accel=kvm"
+    } else {
+        "q35"
+    };
+
+    let mut qemu = Command::new("qemu-system-x86_64")
+        .args(["-machine", machine, "-m", "256", "-nographic", "-no-reboot"])
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+        .arg("-kernel")
+        .arg(kernel)
+        .arg("-initrd")
+        .arg(initrd)
+        .args(["-append", "console=ttyS0 panic=-1"])
+        .stdin(Stdio::null())
+        .spawn()
+        .expect("qemu-system-x86_64 should be installed");
+
+    wait_timeout(&mut qemu, timeout)
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    None
+}
@@ -0,0 +1,71 @@
+//! Run the binary with JSON logs and parse what it writes on stderr.
+
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn test_json_log() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let confdir = dir.join("elusive.d");
+    fs::create_dir_all(&confdir).unwrap();
+
+    let init = dir.join("init");
+    fs::write(&init, "#!/bin/sh\n").unwrap();
+
+    let motd = dir.join("motd");
+    fs::write(&motd, "hello\n").unwrap();
+    fs::write(
+        confdir.join("fixture.yaml"),
+        format!(
+            "name: fixture\nfiles:\n  - sources:\n      - {}\n    destination: /etc\n",
+            motd.display()
+        ),
+    )
+    .unwrap();
+
+    let config = dir.join("elusive.yaml");
+    fs::write(
+        &config,
+        format!("init: {}\nmodules: [fixture]\n", init.display()),
+    )
+    .unwrap();
+
+    let image = dir.join("initramfs.img");
+    let output = Command::new(env!("CARGO_BIN_EXE_elusive"))
+        .args(["--log-format", "json", "--skip-default-paths", "-c"])
+        .arg(&config)
+        .arg("-C")
+        .arg(&confdir)
+        .args(["initramfs", "-o"])
+        .arg(&image)
+        .env("RUST_LOG", "debug")
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    let written = lines
+        .iter()
+        .find(|line| line["message"] == format!("Writing initramfs to: {}", image.display()))
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(!lines.is_empty());
+    for line in &lines {
+        for key in ["level", "target", "message"] {
+            assert!(line.get(key).is_some());
+        }
+    }
+
+    assert_eq!(written["level"], "INFO");
+    assert_eq!(written["target"], "elusive::cli");
+    assert_eq!(written["path"], image.to_str().unwrap());
+    assert!(written["bytes"].as_u64().is_some_and(|bytes| bytes > 0));
+    assert!(lines.iter().any(|line| line["module"] == "fixture"));
+    assert!(lines.iter().any(|line| line["path"] == "/etc"));
+}
@@ -0,0 +1,21 @@
+#!/bin/bash
+
+set -e
+
+FUZZ_DIR="$(git rev-parse --show-toplevel)/crates/elusive/fuzz"
+TARGETS="newc_serialize newc_deserialize"
+SECONDS_PER_TARGET="${1:-30}"
+
+for target in ${TARGETS}; do
+    corpus="${FUZZ_DIR}/corpus/${target}"
+    regressions="${FUZZ_DIR}/regressions/${target}"
+    mkdir -p "${corpus}"
+
+    if [ -d "${regressions}" ]; then
+        cargo +nightly fuzz run --fuzz-dir "${FUZZ_DIR}" "${target}" "${corpus}" "${regressions}" \
+            -- -max_total_time="${SECONDS_PER_TARGET}"
+    else
+        cargo +nightly fuzz run --fuzz-dir "${FUZZ_DIR}" "${target}" "${corpus}" \
+            -- -max_total_time="${SECONDS_PER_TARGET}"
+    fi
+done